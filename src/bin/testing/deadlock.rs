@@ -0,0 +1,68 @@
+//
+//   Copyright 2026 Delphix
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+//
+
+use std::fs::File;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+// pthread mutexes rather than std::sync::Mutex, since pdeadlock relies on the pthread mutex layout
+// to find the owner of a lock.
+struct PthreadMutex(Box<libc::pthread_mutex_t>);
+
+unsafe impl Send for PthreadMutex {}
+unsafe impl Sync for PthreadMutex {}
+
+impl PthreadMutex {
+    fn new() -> Self {
+        PthreadMutex(Box::new(libc::PTHREAD_MUTEX_INITIALIZER))
+    }
+
+    fn lock(&self) {
+        let ptr = &*self.0 as *const libc::pthread_mutex_t as *mut libc::pthread_mutex_t;
+        assert_eq!(unsafe { libc::pthread_mutex_lock(ptr) }, 0);
+    }
+}
+
+fn main() {
+    let a = Arc::new(PthreadMutex::new());
+    let b = Arc::new(PthreadMutex::new());
+    let barrier = Arc::new(Barrier::new(3));
+
+    // Each thread takes one lock, waits until the other thread has taken the other lock, and then
+    // tries to take it, deadlocking the two threads.
+    for &(first, second) in [(&a, &b), (&b, &a)].iter() {
+        let (first, second, barrier) = (first.clone(), second.clone(), barrier.clone());
+        thread::spawn(move || {
+            first.lock();
+            barrier.wait();
+            second.lock();
+        });
+    }
+
+    // Give both threads time to block trying to take their second lock.
+    barrier.wait();
+    thread::sleep(Duration::from_millis(200));
+
+    // Signal parent process (the test process) that this process is ready to be observed by the
+    // ptool being tested.
+    File::create(std::env::var("PTOOLS_TEST_READY_FILE").unwrap()).unwrap();
+
+    // Wait for the parent finish running the ptool and then kill us.
+    loop {
+        thread::park();
+    }
+}
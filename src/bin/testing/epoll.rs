@@ -16,9 +16,11 @@ fn main() {
 
     // Signal parent process (the test process) that this process is ready to be observed by the
     // ptool being tested.
-    File::create("/tmp/ptools-test-ready").unwrap();
+    File::create(std::env::var("PTOOLS_TEST_READY_FILE").unwrap()).unwrap();
 
     // Wait for the parent finish running the ptool and then kill us.
-    loop {}
+    loop {
+        std::thread::park();
+    }
 }
 
@@ -21,21 +21,33 @@ use std::alloc::System;
 static ALLOCATOR: System = System;
 
 extern crate getopts;
+extern crate libc;
 extern crate nix;
 
-use getopts::{Options, ParsingStyle};
+use getopts::{Matches, Options, ParsingStyle};
 
 use nix::fcntl::OFlag;
+use nix::sys::signal::Signal;
 use nix::sys::socket::{AddressFamily, SockType};
-use nix::sys::stat::{major, minor, stat, SFlag};
+use nix::sys::stat::{major, minor, stat, umask, FileStat, Mode, SFlag};
+use nix::sys::uio::{process_vm_readv, IoVec, RemoteIoVec};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::env;
 use std::error::Error;
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader, Read};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::Path;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::io::ErrorKind;
 use std::process::exit;
 
@@ -70,6 +82,49 @@ use std::process::exit;
 // purely internal invariant holds, and panic if it doesn't.
 //
 
+//
+// Everything is normally read from /proc, but with --from-capture the tools read an archive written
+// by pcapture instead. The archive is unpacked into a temporary directory laid out like /proc, so
+// code only needs to build its paths with proc_path!() rather than hardcoding /proc. The few things
+// which can't be stored as files are handled by read_proc_link() and fd_stat().
+//
+static CAPTURE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+// With --task, the thread (as its process's pid and its tid) whose files are read instead of its
+// process's. The paths proc_path!() builds for the process are redirected to /proc/PID/task/TID/.
+static TASK: OnceLock<(u64, u64)> = OnceLock::new();
+
+// The short usage of the tool, for the usage errors found once the options have been handled
+static USAGE: OnceLock<String> = OnceLock::new();
+
+fn is_capture() -> bool {
+    CAPTURE_DIR.get().is_some()
+}
+
+fn proc_root() -> String {
+    match CAPTURE_DIR.get() {
+        Some(dir) => format!("{}/proc", dir.display()),
+        None => "/proc".to_string(),
+    }
+}
+
+// Like format!(), for a path relative to /proc
+macro_rules! proc_path {
+    ($($arg:tt)*) => {
+        proc_file_path(format!($($arg)*))
+    }
+}
+
+fn proc_file_path(path: String) -> String {
+    if let Some(&(pid, tid)) = TASK.get() {
+        let prefix = format!("{}/", pid);
+        if path.starts_with(&prefix) && !path[prefix.len()..].starts_with("task/") {
+            return format!("{}/{}task/{}/{}", proc_root(), prefix, tid, &path[prefix.len()..]);
+        }
+    }
+    format!("{}/{}", proc_root(), path)
+}
+
 fn usage(program: &str, opts: Options) -> ! {
     usage_impl(program, opts, false);
 }
@@ -79,933 +134,19486 @@ fn usage_err(program: &str, opts: Options) -> ! {
 }
 
 fn usage_impl(program: &str, opts: Options, error: bool) -> ! {
-    print!("{}\n", opts.short_usage(program));
+    println!("{}", opts.short_usage(program));
     std::process::exit(if error { 1 } else { 0 });
 }
 
-fn open_or_exit(filename: &str) -> File {
-    match File::open(filename) {
-        Ok(f) => f,
-        Err(e) => {
-            eprint!("{} {}\n", filename, e);
-            std::process::exit(1);
+// Options accepted by every tool
+fn add_common_opts(opts: &mut Options) {
+    opts.optflag("", "read-only", "refuse to use any feature which changes the state of a process");
+    opts.optopt("", "from-capture", "read processes from an archive written by pcapture, instead of /proc", "FILE");
+    opts.optflag("", "generate-man", "print a man page for this command, in roff");
+    opts.optflag("", "no-progress", "don't show progress on stderr while going through many processes");
+    opts.optopt("", "time-format", "show times as local (the default, e.g. '2024-03-01 18:04:05 +0100'), iso \
+                                    (RFC 3339) or epoch (seconds since 1970)", "FORMAT");
+    opts.optflag("", "utc", "show times in UTC rather than the local timezone");
+}
+
+fn apply_common_opts(program: &str, opts: &Options, matches: &Matches) {
+    let _ = USAGE.set(opts.short_usage(program));
+    if matches.opt_present("generate-man") {
+        print!("{}", man_page(program, opts));
+        exit(0);
+    }
+    // The environment variable allows read-only mode to be imposed on every invocation of the
+    // tools, e.g. from a login profile on production systems, without relying on each caller to
+    // pass the flag.
+    if matches.opt_present("read-only") || env::var_os("PTOOLS_READ_ONLY").is_some() {
+        set_read_only();
+    }
+    if matches.opt_present("no-progress") {
+        NO_PROGRESS.store(true, Ordering::SeqCst);
+    }
+    if matches.opt_present("time-format") || matches.opt_present("utc") {
+        let style = match matches.opt_str("time-format").as_deref() {
+            None | Some("local") => TimeStyle::Local,
+            Some("iso") => TimeStyle::Iso,
+            Some("epoch") => TimeStyle::Epoch,
+            Some(style) => {
+                eprintln!("unknown time format '{}', expected local, iso or epoch", style);
+                println!("{}", opts.short_usage(program));
+                exit(1);
+            }
+        };
+        let _ = TIME_FORMAT.set(TimeFormat { style, utc: matches.opt_present("utc") });
+    }
+    if let Some(path) = matches.opt_str("from-capture") {
+        if let Err(e) = load_capture(&path) {
+            eprintln!("Unable to read capture {}: {}", path, e);
+            exit(1);
         }
     }
 }
 
-fn print_args(pid: u64) {
-    let file = open_or_exit(&format!("/proc/{}/cmdline", pid));
-    print_proc_summary(pid);
+// What each tool does and the operands it takes, for its man page. Everything else on the page
+// comes from the tool's options, so it can't drift from what the tool accepts.
+const TOOL_DESCRIPTIONS: &[(&str, &str, &str)] = &[
+    ("pargs", "[PID|CORE]...", "print the arguments, environment or auxiliary vector of processes"),
+    ("penv", "[PID]...", "print the environment of processes"),
+    ("penvdiff", "[PID]...", "save the environment of a process, or compare processes' environments with it"),
+    ("pfiles", "[PID]...", "print the open files and sockets of processes"),
+    ("ptree", "[PID]...", "print the process trees containing processes"),
+    ("psyscall", "[PID]...", "print the system call processes are blocked in"),
+    ("pmap", "[PID]...", "print the address space mappings of processes"),
+    ("pdeadlock", "[PID]...", "look for threads deadlocked on mutexes in processes"),
+    ("ptoolsd", "", "serve what the other tools show over a UNIX socket"),
+    ("pcompare", "PID PID", "compare the state of two processes"),
+    ("pwdx", "[PID]...", "print the working directories of processes"),
+    ("pstack", "[PID]...", "print the stack traces of the threads of processes"),
+    ("pnamespaces", "[PID]...", "print the namespaces of processes"),
+    ("pcapture", "PID", "archive the state of a process, for the other tools to read later"),
+    ("pstopwatch", "[PID]...", "measure how long processes spend running and waiting"),
+    ("pcore", "PID", "write a core file of a running process without killing it"),
+    ("pshm", "[PID]...", "list the System V and POSIX shared memory processes use, and who else uses it"),
+    ("pmem", "[PID]...", "print the memory usage of processes, or add it up by user, cgroup or command"),
+    ("pstop", "[PID]...", "stop processes, optionally at their threads' next system calls"),
+    ("prun", "[PID]...", "set stopped processes running again"),
+    ("pwait", "[PID]...", "wait for processes to exit, reporting each exit as it happens"),
+    ("pionice", "[PID]...", "print or set the I/O scheduling class and level of processes"),
+    ("pdoctor", "", "check which features of the tools the running kernel and its configuration allow"),
+    ("pcaps", "[PID]...", "print the capabilities of processes, or list every process holding dangerous ones"),
+    ("pboot", "[PID]...", "print the kernel and how it was booted, and processes' personality and LSM attributes"),
+    ("pstartup", "[PID]...", "print how processes were started: by whom, from where, with what"),
+    ("plock", "[PID]...", "print the file locks processes hold and wait for, and who is in their way"),
+];
 
-    for (i, bytes) in BufReader::new(file).split('\0' as u8).enumerate() {
-        let bytes = &bytes.unwrap();
-        let arg = from_utf8(bytes).unwrap();
-        println!("argv[{}]: {}", i, arg);
+// The options in a usage message, as (option, description) pairs, e.g. ("-o, --output FILE",
+// "write ..."). getopts doesn't tell us the options it was given any other way. Each row is the
+// option, as '-o, --output FILE', padded to 24 columns before the description, or on a line of its
+// own if it doesn't fit, followed by the rest of the description on lines indented by 24 columns.
+fn usage_rows(opts: &Options) -> Vec<(String, String)> {
+    opts.usage_with_format(|rows| {
+        rows.map(|row| {
+            let first = row.lines().next().unwrap_or("");
+            let bytes = first.as_bytes();
+            let mut end = first.len() - first.trim_start().len();
+            // The short option is followed by ', ' before a long option, and ' ' otherwise
+            if bytes.get(end) == Some(&b'-') && bytes.get(end + 1) != Some(&b'-') {
+                end += 2;
+                end += if first[end..].starts_with(", ") { 2 } else { (bytes.get(end) == Some(&b' ')) as usize };
+            }
+            // The long option is always followed by a space
+            if first[end..].starts_with("--") {
+                end += first[end..].find(' ').map_or(first.len() - end, |i| i + 1);
+            }
+            // Anything other than padding straight after the names is the argument
+            if bytes.get(end).is_some_and(|&b| b != b' ') {
+                end += first[end..].find(' ').unwrap_or(first.len() - end);
+            }
+            let desc = std::iter::once(&first[end..]).chain(row.lines().skip(1))
+                .map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>().join(" ");
+            format!("{}\0{}", first[..end].trim(), desc)
+        }).collect::<Vec<_>>().join("\n")
+    }).lines().filter_map(|row| row.split_once('\0')).map(|(opt, desc)| (opt.to_string(), desc.to_string())).collect()
+}
+
+// Escape text for roff, where a '.' or ''' at the start of a line starts a request, and '-' is a
+// hyphen rather than a minus sign unless escaped.
+fn roff_escape(s: &str) -> String {
+    let s = s.replace('\\', "\\e").replace('-', "\\-");
+    if s.starts_with('.') || s.starts_with('\'') {
+        format!("\\&{}", s)
+    } else {
+        s
     }
 }
 
-fn print_env(pid: u64) {
-    // This contains the environ as it was when the proc was started. To get the current
-    // environment, we need to inspect its memory to find out how it has change. POSIX defines a
-    // char **__environ symbol that we will need to find. Unfortunately, inspecting the memory of
-    // another process is not typically permitted, even if the process owned by the same user. See
-    // /etc/sysctl.d/10-ptrace.conf for details.
-    //
-    // Long term, we might want to print the current environment if we can, and print a warning
-    // + the contents of /proc/[pid]/environ if we can't
-    let file = open_or_exit(&format!("/proc/{}/environ", pid));
-    print_proc_summary(pid);
+// An option as shown in a man page: names in bold, and its argument in italics
+fn roff_option(opt: &str) -> String {
+    opt.split(' ').map(|word| {
+        let (word, sep) = match word.strip_suffix(',') {
+            Some(word) => (word, ","),
+            None => (word, ""),
+        };
+        let font = if word.starts_with('-') { "B" } else { "I" };
+        format!("\\f{}{}\\fR{}", font, roff_escape(word), sep)
+    }).collect::<Vec<_>>().join(" ")
+}
+
+fn man_page(program: &str, opts: &Options) -> String {
+    let name = Path::new(program).file_name().map_or(program.to_string(), |name| name.to_string_lossy().into_owned());
+    let tool = name.strip_suffix('2').unwrap_or(&name);
+    let (operands, description) = TOOL_DESCRIPTIONS.iter().find(|&&(t, _, _)| t == tool)
+        .map_or(("", "inspect processes"), |&(_, operands, description)| (operands, description));
 
-    for (i, bytes) in BufReader::new(file).split('\0' as u8).enumerate() {
-        let bytes = &bytes.unwrap();
-        let arg = from_utf8(bytes).unwrap();
-        println!("envp[{}]: {}", i, arg);
+    let mut page = vec![
+        format!(".TH {} 1 \"\" \"ptools2 {}\" \"User Commands\"", name.to_uppercase(), env!("CARGO_PKG_VERSION")),
+        ".SH NAME".to_string(),
+        format!("{} \\- {}", roff_escape(&name), description),
+        ".SH SYNOPSIS".to_string(),
+        format!(".B {}", roff_escape(&name)),
+        format!("[\\fIOPTION\\fR]... {}", roff_escape(operands)).trim_end().to_string(),
+        ".SH OPTIONS".to_string(),
+    ];
+    let rows = usage_rows(opts);
+    for (opt, desc) in &rows {
+        page.push(".TP".to_string());
+        page.push(roff_option(opt));
+        page.push(roff_escape(desc));
+    }
+    if rows.iter().any(|(opt, _)| opt == "--read-only") {
+        page.push(".SH ENVIRONMENT".to_string());
+        page.push(".TP".to_string());
+        page.push(".B PTOOLS_READ_ONLY".to_string());
+        page.push("If set, behave as if \\fB\\-\\-read\\-only\\fR was given.".to_string());
     }
+    page.push(".SH SEE ALSO".to_string());
+    let others = TOOL_DESCRIPTIONS.iter().filter(|&&(t, _, _)| t != tool)
+        .map(|&(t, _, _)| format!(".BR {}2 (1)", t)).collect::<Vec<_>>();
+    page.push(others.join(" ,\n"));
+    page.join("\n") + "\n"
 }
 
-// Print the pid and a summary of command line arguments on a single line.
-fn print_proc_summary(pid: u64) {
-    print!("{:8}", format!("{}:", pid));
-    print_cmd_summary(pid);
+// Options for narrowing down which processes a tool operates on, accepted by every tool which takes
+// pids. Without any pids, they select from all processes on the system, so e.g. 'pfiles
+// --younger-than 5m' shows the files of everything started in the last five minutes.
+fn add_selection_opts(opts: &mut Options) {
+    opts.optopt("", "younger-than", "only operate on processes started less than AGE ago (e.g. 30s, 5m, 2d)", "AGE");
+    opts.optopt("", "older-than", "only operate on processes started more than AGE ago", "AGE");
+    opts.optmulti("", "exe", "only operate on processes running the executable at PATH (even if it has since \
+                              been replaced or deleted), or the file with device and inode MAJOR:MINOR:INODE",
+                  "PATH");
+    opts.optmulti("u", "user", "only operate on processes whose real user is USER (a name or uid). Processes in \
+                                user namespaces match by their uid outside, as shown by ps.", "USER");
+    opts.optmulti("", "container", "only operate on processes in the container with the given name (as its runtime \
+                                    knows it) or id, which may be shortened to a prefix", "NAME");
+    opts.optmulti("", "pod", "only operate on processes in containers of the Kubernetes pod with the given name \
+                              or UID", "NAME");
+    opts.optmulti("", "port", "only operate on processes with a TCP socket listening on PORT, or a UDP socket \
+                               bound to it", "PORT");
+    opts.optmulti("", "match", "only operate on processes whose command line matches the shell PATTERN (see \
+                                fnmatch(3)), e.g. 'ssh*', or which starts with it if it begins with '^', or \
+                                ends with it if it ends with '$', e.g. '^ssh'", "PATTERN");
+    opts.optmulti("", "cgroup", "only operate on processes in the (v2) cgroup PATH or below it", "PATH");
+    opts.optflagmulti("", "not", "only operate on processes which don't match the selection option after this");
+    opts.optflagmulti("", "or", "also operate on processes which match the selection options after this, rather \
+                                 than only on those which match all of them");
 }
 
-#[derive(Debug)]
-struct ParseError {
-    reason: String,
+// The selection options given to a tool, which exits with a usage error if they don't make sense
+fn apply_selection_opts(program: &str, opts: &Options, args: &[String], matches: &Matches) -> ProcessSelection {
+    ProcessSelection::from_matches(opts, args, matches).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        println!("{}", opts.short_usage(program));
+        exit(1);
+    })
 }
 
-impl ParseError {
-    fn new(item: &str, reason: &str) -> Self {
-        ParseError {
-            reason: format!("Error parsing {}: {}", item, reason),
+// A --match pattern may be anchored like a regular expression, '^ssh' matching what starts with
+// ssh and 'ssh$' what ends with it, which is the shell pattern 'ssh*' or '*ssh'. Otherwise it's a
+// shell pattern, which has to match all of the command line.
+fn anchored_pattern(pattern: &str) -> String {
+    let (start, end) = (pattern.starts_with('^'), pattern.ends_with('$') && !pattern.ends_with("\\$"));
+    if !start && !end {
+        return pattern.to_string();
+    }
+    let inner = &pattern[start as usize..pattern.len() - end as usize];
+    format!("{}{}{}", if start { "" } else { "*" }, inner, if end { "" } else { "*" })
+}
+
+// The selection options which take a value, by their long names
+const SELECTION_OPTS: &[&str] = &["younger-than", "older-than", "exe", "user", "container", "pod", "port", "match",
+                                  "cgroup"];
+
+// Not in the libc crate we build against
+extern "C" {
+    fn fnmatch(pattern: *const libc::c_char, string: *const libc::c_char, flags: libc::c_int) -> libc::c_int;
+}
+
+// A selection option, or --not or --or, in the order they were given
+#[derive(Debug, PartialEq)]
+enum SelectionTerm {
+    Not,
+    Or,
+    Filter(String, String),
+}
+
+// The selection options, --not and --or in the order they were given. getopts only keeps the
+// values of each option in order, so parse every prefix of the arguments to see which option each
+// one adds, in case something like '--label --not' makes an option's value look like another.
+fn selection_terms(opts: &Options, args: &[String]) -> Vec<SelectionTerm> {
+    let names = SELECTION_OPTS.iter().chain(&["not", "or"]).collect::<Vec<_>>();
+    let mut counts = vec![0; names.len()];
+    let mut terms = vec![];
+    for end in 1..=args.len() {
+        // A prefix ending in an option which needs a value doesn't parse until the next one
+        let matches = match opts.parse(&args[..end]) {
+            Ok(matches) => matches,
+            Err(_) => continue,
+        };
+        for (&&name, count) in names.iter().zip(counts.iter_mut()) {
+            let values = matches.opt_strs(name);
+            while *count < matches.opt_count(name) {
+                terms.push(match name {
+                    "not" => SelectionTerm::Not,
+                    "or" => SelectionTerm::Or,
+                    _ => SelectionTerm::Filter(name.to_string(), values[*count].clone()),
+                });
+                *count += 1;
+            }
         }
     }
-    fn in_file(file: &str, reason: &str) -> Self {
-        ParseError {
-            reason: format!("Error parsing /proc/[pid]/{}: {}", file, reason),
+    terms
+}
+
+// The selection options given to a tool
+#[derive(Default)]
+struct ProcessSelection {
+    younger_than: Option<Duration>,
+    older_than: Option<Duration>,
+    exes: Vec<ExeFilter>,
+    users: Vec<libc::uid_t>,
+    containers: Vec<String>,
+    pods: Vec<String>,
+    // What the runtimes told us about each container seen so far, by id, since every process in
+    // a container has the same
+    container_names: Mutex<HashMap<String, ContainerNames>>,
+    ports: Vec<u16>,
+    // The inodes of the sockets on those ports in each network namespace seen so far, by the
+    // target of /proc/[pid]/ns/net
+    port_sockets: Mutex<HashMap<String, std::collections::HashSet<u64>>>,
+    // Shell patterns for the command line
+    cmdlines: Vec<String>,
+    cgroups: Vec<String>,
+    // From --not, selections of one option each, which processes must not match
+    excluded: Vec<ProcessSelection>,
+    // From --or, the selection processes which don't match this one may match instead
+    or: Option<Box<ProcessSelection>>,
+}
+
+// An executable to select processes by. Processes match if their executable has the path, which
+// still works once it has been replaced by an upgrade or deleted, or if it is the same file, which
+// still works once it has been renamed.
+#[derive(Debug, PartialEq)]
+struct ExeFilter {
+    path: Option<PathBuf>,
+    // The major and minor device numbers and the inode
+    file: Option<(u64, u64, u64)>,
+}
+
+impl ExeFilter {
+    fn parse(arg: &str) -> ExeFilter {
+        let numbers = arg.split(':').map(|n| n.parse::<u64>()).collect::<Result<Vec<_>, _>>();
+        if let Ok(&[major, minor, ino]) = numbers.as_deref() {
+            return ExeFilter { path: None, file: Some((major, minor, ino)) };
         }
+        // Processes' executables are shown with symlinks resolved, e.g. /usr/bin/python3.11 for
+        // /usr/bin/python3
+        let path = fs::canonicalize(arg).unwrap_or_else(|_| PathBuf::from(arg));
+        let file = stat(&path).ok().map(|st| (major(st.st_dev), minor(st.st_dev), st.st_ino));
+        ExeFilter { path: Some(path), file }
+    }
+
+    // 'link' is where /proc/[pid]/exe points, and 'file' is the same as for ExeFilter
+    fn matches(&self, link: Option<&Path>, file: Option<(u64, u64, u64)>) -> bool {
+        let link = link.map(|link| {
+            let link = link.to_string_lossy();
+            PathBuf::from(link.strip_suffix(" (deleted)").unwrap_or(&link))
+        });
+        (self.path.is_some() && self.path == link) || (self.file.is_some() && self.file == file)
     }
 }
 
-impl Error for ParseError {
-    fn description(&self) -> &str {
-        &self.reason
+// Parse an age such as '90', '30s', '5m', '12h', '2d' or '1w'. Plain numbers are seconds.
+fn parse_age(age: &str) -> Result<Duration, Box<dyn Error>> {
+    let split = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (count, unit) = age.split_at(split);
+    let count = count.parse::<u64>().map_err(|_| format!("Invalid age '{}'", age))?;
+    let unit_secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(From::from(format!("Invalid unit in age '{}': expected s, m, h, d or w", age))),
+    };
+    let secs = count.checked_mul(unit_secs).ok_or_else(|| format!("Age '{}' is too large", age))?;
+    Ok(Duration::from_secs(secs))
+}
+
+// The reverse of parse_age, in the largest unit which fits
+fn age_str(age: Duration) -> String {
+    let units = [("w", 7 * 24 * 60 * 60), ("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60)];
+    let age = age.as_secs();
+    match units.iter().find(|&&(_, secs)| age >= secs) {
+        Some(&(unit, secs)) => format!("{}{}", age / secs, unit),
+        None => format!("{}s", age),
     }
 }
 
-impl std::fmt::Display for ParseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.description())
+fn ago_str(secs_ago: u64) -> String {
+    format!("{} ago", age_str(Duration::from_secs(secs_ago)))
+}
+
+// How times are shown, so that they can be lined up with logs from elsewhere: local times are for
+// people, iso for tools, and epoch for arithmetic. With utc, local and iso times are in UTC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TimeStyle {
+    Local,
+    Iso,
+    Epoch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct TimeFormat {
+    style: TimeStyle,
+    utc: bool,
+}
+
+// Set if --time-format or --utc was given
+static TIME_FORMAT: OnceLock<TimeFormat> = OnceLock::new();
+
+// Whether a time format was asked for. Times which are otherwise only shown as ages (e.g. '3h')
+// are then shown as times too.
+fn time_format_chosen() -> bool {
+    TIME_FORMAT.get().is_some()
+}
+
+// A time, as --time-format and --utc say
+fn time_str(time: std::time::SystemTime) -> String {
+    let format = TIME_FORMAT.get().copied().unwrap_or(TimeFormat { style: TimeStyle::Local, utc: false });
+    let offset = match format.utc {
+        true => 0,
+        false => {
+            let secs = time.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs()) as libc::time_t;
+            let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+            match unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+                true => 0,
+                false => tm.tm_gmtoff as i64,
+            }
+        }
+    };
+    format_time(time, format, offset)
+}
+
+// A time in a format, offset from UTC by this many seconds unless format.utc is set
+fn format_time(time: std::time::SystemTime, format: TimeFormat, offset: i64) -> String {
+    let since_epoch = time.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    let millis = since_epoch.subsec_millis();
+    if format.style == TimeStyle::Epoch {
+        return format!("{}.{:03}", since_epoch.as_secs(), millis);
+    }
+    let offset = if format.utc { 0 } else { offset };
+    let secs = (since_epoch.as_secs() as i64 + offset).max(0) as u64;
+    // The civil date of a day count, from Howard Hinnant's chrono-compatible algorithms, with eras
+    // of 400 years starting on March 1st so that leap days come last
+    let days = secs / 86400 + 719_468;
+    let (era, day_of_era) = (days / 146_097, days % 146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    let date = format!("{:04}-{:02}-{:02}", year, month, day);
+    let time_of_day = format!("{:02}:{:02}:{:02}", secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    let sign = if offset < 0 { '-' } else { '+' };
+    let (hours, minutes) = (offset.abs() / 3600, offset.abs() / 60 % 60);
+    match (format.style, format.utc) {
+        (TimeStyle::Iso, true) => format!("{}T{}.{:03}Z", date, time_of_day, millis),
+        (TimeStyle::Iso, false) => format!("{}T{}.{:03}{}{:02}:{:02}", date, time_of_day, millis, sign, hours, minutes),
+        (_, true) => format!("{} {} UTC", date, time_of_day),
+        (_, false) => format!("{} {} {}{:02}{:02}", date, time_of_day, sign, hours, minutes),
     }
 }
 
-// Info parsed from /proc/[pid]/status
-struct ProcStat {
+// Return field 'n' (counting from 1, as in proc(5)) of a /proc/[pid]/stat file. Count from the end
+// of the command, which is the only field which can contain spaces, and is followed by field 3.
+fn stat_field(stat: &str, n: usize) -> Option<&str> {
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(n.checked_sub(3)?)
+}
+
+// The unit of the times in /proc/[pid]/stat
+fn clock_ticks_per_sec() -> u64 {
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as u64,
+        _ => 100,
+    }
+}
+
+// How long ago a process was started
+fn process_age(pid: u64) -> Result<Duration, Box<dyn Error>> {
+    let stat = fs::read_to_string(proc_path!("{}/stat", pid))?;
+    // The start time is measured in clock ticks since boot
+    let starttime = stat_field(&stat, 22).and_then(|t| t.parse::<u64>().ok())
+        .ok_or_else(|| ParseError::in_file("stat", "missing start time"))?;
+    let ticks_per_sec = clock_ticks_per_sec();
+
+    let uptime_path = proc_path!("uptime");
+    let uptime = fs::read_to_string(&uptime_path)?;
+    let uptime = uptime.split_whitespace().next().and_then(|t| t.parse::<f64>().ok())
+        .ok_or_else(|| ParseError::new(&uptime_path, "missing uptime"))?;
+
+    let started = Duration::from_millis(starttime * 1000 / ticks_per_sec);
+    Ok(Duration::from_millis((uptime * 1000.0) as u64).checked_sub(started).unwrap_or_default())
+}
+
+// Identifies a process even when its pid is reused: a pid can only be reused once the process
+// using it has exited, and the next process to get it will have started later. Shown as
+// 'pid:starttime', with the start time in clock ticks since boot, as in /proc/[pid]/stat.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+struct ProcessId {
     pid: u64,
-    fields: HashMap<String, String>,
+    starttime: u64,
 }
 
-impl ProcStat {
-    // What is the stability of 'status' vs 'stat'? Is parsing this more likely to break? Overall,
-    // 'stat' seems better designed for parsing, _except_ ...
-    //
-    // The fields in /proc/[pid]/stat are separated by spaces. Unfortunately, the second field is
-    // the command, which can contain spaces. Without knowing what the command is beforehand, we
-    // can't parse this file reliably. We can read the command from /proc/[pid]/comm,
-    // so we know exactly what to expect, but that would be a pain.
-    //
-    fn read(pid: u64) -> Result<Self, Box<dyn Error>> {
-        // /proc/[pid]/status contains lines of the form
-        //
-        //    Name:   bash
-        //    Umask:  0022
-        //    State:  S (sleeping)
-        //    ...
+impl ProcessId {
+    fn read(pid: u64) -> Option<ProcessId> {
+        ProcessId::from_stat(pid, &fs::read_to_string(proc_path!("{}/stat", pid)).ok()?)
+    }
 
-        let status_file = ProcStat::status_file(pid);
-        let fields = BufReader::new(File::open(&status_file)?)
-            .lines()
-            .map(|s| {
-                let s: String = s?;
-                let substrs = s.splitn(2, ":").collect::<Vec<&str>>();
-                if substrs.len() < 2 {
-                    Err(ParseError::in_file(
-                        "status",
-                        &format!(
-                            "Fewer fields than expected in line '{}' of file {}",
-                            s, status_file
-                        ),
-                    ))?;
-                }
-                let key = substrs[0].to_string();
-                let value = substrs[1].trim().to_string();
-                Ok((key, value))
-            }).collect::<Result<HashMap<String, String>, Box<dyn Error>>>()?;
+    fn from_stat(pid: u64, stat: &str) -> Option<ProcessId> {
+        let starttime = stat_field(stat, 22)?.parse().ok()?;
+        Some(ProcessId { pid, starttime })
+    }
+}
 
-        Ok(ProcStat {
-            pid: pid,
-            fields: fields,
-        })
+impl std::fmt::Display for ProcessId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.pid, self.starttime)
     }
+}
 
-    fn status_file(pid: u64) -> String {
-        format!("/proc/{}/status", pid)
+impl ProcessSelection {
+    fn from_matches(opts: &Options, args: &[String], matches: &Matches) -> Result<Self, Box<dyn Error>> {
+        // Without --not or --or, each kind of option narrows the selection down, and giving one
+        // again adds to what it lets through, so the order doesn't matter
+        if !["not", "or"].iter().any(|&o| matches.opt_present(o)) {
+            let terms = SELECTION_OPTS.iter()
+                .flat_map(|&name| matches.opt_strs(name).into_iter()
+                          .map(move |value| SelectionTerm::Filter(name.to_string(), value)))
+                .collect::<Vec<_>>();
+            return ProcessSelection::from_terms(&terms);
+        }
+        ProcessSelection::from_terms(&selection_terms(opts, args))
     }
 
-    fn get_field(&self, field: &str) -> Result<&str, Box<dyn Error>> {
-        match self.fields.get(field) {
-            Some(val) => Ok(val),
-            None => Err(From::from(ParseError::in_file(
-                "status",
-                &format!(
-                    "Missing expected field '{}' in file {}",
-                    field,
-                    ProcStat::status_file(self.pid)
-                ),
-            ))),
+    // Options up to an --or must all match, each with a --not before it not matching
+    fn from_terms(terms: &[SelectionTerm]) -> Result<Self, Box<dyn Error>> {
+        let mut groups = vec![ProcessSelection::default()];
+        let mut negate = false;
+        for term in terms {
+            let group = groups.last_mut().unwrap();
+            match term {
+                SelectionTerm::Not if negate => return Err("--not can't be given twice in a row".into()),
+                SelectionTerm::Not => negate = true,
+                SelectionTerm::Or if negate || !group.has_filters() => {
+                    return Err("--or needs selection options on both sides of it".into());
+                }
+                SelectionTerm::Or => groups.push(ProcessSelection::default()),
+                SelectionTerm::Filter(name, value) if negate => {
+                    let mut excluded = ProcessSelection::default();
+                    excluded.add(name, value)?;
+                    group.excluded.push(excluded);
+                    negate = false;
+                }
+                SelectionTerm::Filter(name, value) => group.add(name, value)?,
+            }
+        }
+        if negate {
+            return Err("--not must be followed by a selection option".into());
         }
+        let mut selection = groups.pop().unwrap();
+        if !groups.is_empty() && !selection.has_filters() {
+            return Err("--or needs selection options on both sides of it".into());
+        }
+        while let Some(mut group) = groups.pop() {
+            group.or = Some(Box::new(selection));
+            selection = group;
+        }
+        Ok(selection)
     }
 
-    fn ppid(&self) -> Result<u64, Box<dyn Error>> {
-        Ok(self.get_field("PPid")?.parse()?)
+    fn add(&mut self, name: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        match name {
+            "younger-than" => self.younger_than = Some(parse_age(value)?),
+            "older-than" => self.older_than = Some(parse_age(value)?),
+            "exe" => self.exes.push(ExeFilter::parse(value)),
+            "user" => self.users.push(parse_user(value)?),
+            // An empty name would be a prefix of every container's id
+            "container" | "pod" if value.is_empty() => return Err(format!("--{} needs a name", name).into()),
+            "container" => self.containers.push(value.to_string()),
+            "pod" => self.pods.push(value.to_string()),
+            "port" => {
+                self.ports.push(value.parse().map_err(|_| format!("--port takes a port number, not '{}'", value))?)
+            }
+            "match" => self.cmdlines.push(anchored_pattern(value)),
+            // The root is written as '/', and the others without a slash at the end
+            "cgroup" => self.cgroups.push(format!("/{}", value.trim_matches('/'))),
+            _ => return Err(format!("unknown selection option --{}", name).into()),
+        }
+        Ok(())
     }
-}
 
-fn print_tree(pid_of_interest: u64) -> Result<(), Box<dyn Error>> {
-    let mut child_map = HashMap::new(); // Map of pid to pids of children
-    let mut parent_map = HashMap::new(); // Map of pid to pid of parent
+    fn has_filters(&self) -> bool {
+        self.younger_than.is_some() || self.older_than.is_some() || !self.exes.is_empty() || !self.users.is_empty()
+            || self.by_container() || !self.ports.is_empty() || !self.cmdlines.is_empty() || !self.cgroups.is_empty()
+            || !self.excluded.is_empty()
+    }
 
-    // Loop over all the processes listed in /proc/, find the parent of each one, and build a map
-    // from parent to children. There doesn't seem to be a more efficient way of doing this
-    // reliably.
-    for entry in fs::read_dir("/proc")? {
-        let entry = entry?;
-        let filename = entry.file_name();
-        let filename = filename.to_str().unwrap();
-        if let Ok(pid) = filename.parse::<u64>() {
-            let ppid = match ProcStat::read(pid) {
-                Ok(proc_stat) => match proc_stat.ppid() {
-                    Ok(ppid) => ppid,
-                    Err(e) => {
-                        eprintln!("{}", e.to_string());
-                        continue
-                    }
-                },
-                // Proc probably exited before we could read its status
-                Err(_) => continue,
-            };
-            child_map.entry(ppid).or_insert(vec![]).push(pid);
-            parent_map.insert(pid, ppid);
+    // Whether any selection options were given, so that no pids means all processes. A capture
+    // only holds the processes of interest, so there it always does.
+    fn is_active(&self) -> bool {
+        self.has_filters() || is_capture()
+    }
+
+    // Whether processes are selected by the container or pod they are in
+    fn by_container(&self) -> bool {
+        !self.containers.is_empty() || !self.pods.is_empty()
+    }
+
+    fn matches(&self, pid: u64) -> bool {
+        let matches = self.matches_age(pid) && self.matches_exe(pid) && self.matches_user(pid)
+            && self.matches_container(pid) && self.matches_port(pid) && self.matches_cmdline(pid)
+            && self.matches_cgroup(pid) && !self.excluded.iter().any(|excluded| excluded.matches(pid));
+        matches || self.or.as_ref().is_some_and(|or| or.matches(pid))
+    }
+
+    // Kernel threads have no command line, so they go by their name in brackets, as ps shows them
+    fn matches_cmdline(&self, pid: u64) -> bool {
+        if self.cmdlines.is_empty() {
+            return true;
         }
+        let cmdline = match read_nul_strings(&proc_path!("{}/cmdline", pid)) {
+            Ok(args) if !args.is_empty() => args.join(" "),
+            Ok(_) => format!("[{}]", fs::read_to_string(proc_path!("{}/comm", pid)).unwrap_or_default().trim_end()),
+            Err(_) => return false,
+        };
+        let cmdline = std::ffi::CString::new(cmdline).unwrap_or_default();
+        self.cmdlines.iter().any(|pattern| {
+            let pattern = std::ffi::CString::new(pattern.as_str()).unwrap_or_default();
+            unsafe { fnmatch(pattern.as_ptr(), cmdline.as_ptr(), 0) == 0 }
+        })
     }
 
-    let indent_level = if pid_of_interest == 1 {
-        0
-    } else {
-        if !parent_map.contains_key(&pid_of_interest) {
-            eprintln!("No such pid {}", pid_of_interest);
-            exit(1);
+    fn matches_cgroup(&self, pid: u64) -> bool {
+        if self.cgroups.is_empty() {
+            return true;
         }
-        print_parents(&parent_map, pid_of_interest)
-    };
-    print_children(&child_map, pid_of_interest, indent_level);
+        let cgroup = match fs::read_to_string(proc_path!("{}/cgroup", pid)).ok()
+            .and_then(|contents| parse_cgroup(&contents, &CgroupHierarchy::Unified)) {
+            Some(cgroup) => cgroup,
+            None => return false,
+        };
+        self.cgroups.iter().any(|path| {
+            path == "/" || cgroup == *path || cgroup.strip_prefix(path.as_str()).is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
 
-    Ok(())
-}
+    // Sockets are found by their inodes, from the tables of the process' network namespace, so
+    // that this works for processes in containers too
+    fn matches_port(&self, pid: u64) -> bool {
+        if self.ports.is_empty() {
+            return true;
+        }
+        let netns = read_proc_link(proc_path!("{}/ns/net", pid)).ok()
+            .map_or_else(String::new, |link| link.to_string_lossy().into_owned());
+        let mut cache = self.port_sockets.lock().unwrap();
+        let sockets = cache.entry(netns).or_insert_with(|| {
+            ["tcp", "tcp6", "udp", "udp6"].iter()
+                .flat_map(|&table| port_sockets(&read_net_table(pid, table, 10).unwrap_or_default(),
+                                                &self.ports, table.starts_with("tcp")))
+                .collect()
+        });
+        !sockets.is_empty() && read_fds(pid).unwrap_or_default().into_iter().any(|fd| {
+            read_proc_link(proc_path!("{}/fd/{}", pid, fd)).ok()
+                .and_then(|link| link.to_str()?.strip_prefix("socket:[")?.strip_suffix(']')?.parse::<u64>().ok())
+                .is_some_and(|inode| sockets.contains(&inode))
+        })
+    }
 
-// Print a summary of command line arguments on a single line.
-fn print_cmd_summary(pid: u64) {
-    match File::open(format!("/proc/{}/cmdline", pid)) {
-        Ok(file) => {
-            for arg in BufReader::new(file).take(80).split('\0' as u8) {
-                print!("{} ", from_utf8(&arg.unwrap()).unwrap());
-            }
-            print!("\n");
+    fn matches_container(&self, pid: u64) -> bool {
+        if !self.by_container() {
+            return true;
         }
-        Err(ref e) if e.kind() == ErrorKind::NotFound => {
-            println!("<exited>");
+        let container = match read_container(pid) {
+            Some(container) => container,
+            None => return false,
+        };
+        let mut cache = self.container_names.lock().unwrap();
+        let names = cache.entry(container.id.clone()).or_insert_with(|| container_names(&container));
+        let is = |name: &Option<String>, arg: &String| name.as_ref() == Some(arg);
+        let in_container = self.containers.is_empty()
+            || self.containers.iter().any(|arg| container.id.starts_with(arg.as_str()) || is(&names.name, arg));
+        let in_pod = self.pods.is_empty()
+            || self.pods.iter().any(|arg| container.pod_uid.as_ref() == Some(arg) || is(&names.pod, arg));
+        in_container && in_pod
+    }
+
+    fn matches_user(&self, pid: u64) -> bool {
+        if self.users.is_empty() {
+            return true;
         }
-        Err(e) => {
-            println!("<error reading cmdline>");
-            eprintln!("{}", e.to_string());
+        let uid = ProcStat::read(pid).ok()
+            .and_then(|status| status.get_field("Uid").ok()?.split_whitespace().next()?.parse::<libc::uid_t>().ok());
+        uid.is_some_and(|uid| self.users.contains(&uid))
+    }
+
+    fn matches_age(&self, pid: u64) -> bool {
+        if self.younger_than.is_none() && self.older_than.is_none() {
+            return true;
         }
+        let age = match process_age(pid) {
+            Ok(age) => age,
+            // Process probably exited
+            Err(_) => return false,
+        };
+        self.younger_than.is_none_or(|max| age < max) && self.older_than.is_none_or(|min| age > min)
     }
-}
 
-// Returns the current indentation level
-fn print_parents(parent_map: &HashMap<u64, u64>, pid: u64) -> u64 {
-    let ppid = match parent_map.get(&pid) {
-        Some(ppid) => *ppid,
-        // Some child process listed 'pid' as its parent, but 'pid' exited before we could read its
-        // parent. The child of 'pid' will have been re-parented, and the new parent will be 'init'.
-        // It's actually a bit more complicated (see find_new_reaper() in the kernel), and there is
-        // one case we might want to handle better: when a child is re-parented to another thread in
-        // the thread group.
-        None => 1
-    };
+    // stat() follows /proc/[pid]/exe to the file even if it was deleted, but a capture only has
+    // the link
+    fn matches_exe(&self, pid: u64) -> bool {
+        if self.exes.is_empty() {
+            return true;
+        }
+        let exe = proc_path!("{}/exe", pid);
+        let link = read_proc_link(&exe).ok();
+        let file = match is_capture() {
+            true => None,
+            false => stat(exe.as_str()).ok().map(|st| (major(st.st_dev), minor(st.st_dev), st.st_ino)),
+        };
+        self.exes.iter().any(|filter| filter.matches(link.as_deref(), file))
+    }
 
-    // We've reached the top of the process tree. Don't bother printing the parent if the parent
-    // is pid 1. Typically pid 1 didn't really start the process in question.
-    if ppid == 1 {
-        return 0;
+    // The pids to operate on: those given on the command line, or every process if there are none,
+    // narrowed down by the selection options.
+    fn pids(&self, args: &[String]) -> Vec<u64> {
+        let pids = if args.is_empty() {
+            all_pids()
+        } else {
+            args.iter().map(|arg| arg.parse::<u64>().unwrap_or_else(|_| {
+                eprintln!("'{}' isn't a pid", arg);
+                println!("{}", USAGE.get().map_or("", |usage| usage));
+                exit(1);
+            })).collect()
+        };
+        pids.into_iter().filter(|&pid| self.matches(pid)).collect()
     }
+}
+
+// The state of listening TCP sockets in /proc/[pid]/net/tcp
+const TCP_LISTEN: &str = "0A";
 
-    let indent_level = print_parents(parent_map, ppid);
-    print_ptree_line(ppid, indent_level);
-    return indent_level + 1;
+// The inodes of the sockets in rows of /proc/[pid]/net/{tcp,udp}[6] whose local port is one of
+// 'ports', and which are listening if 'listening' is set. The local address is e.g. '0100007F:1F90'
+// (127.0.0.1:8080), with 32 hex digits before the port for IPv6.
+fn port_sockets(rows: &[Vec<String>], ports: &[u16], listening: bool) -> Vec<u64> {
+    rows.iter()
+        .filter(|fields| !listening || fields[3] == TCP_LISTEN)
+        .filter(|fields| fields[1].rsplit(':').next().and_then(|port| u16::from_str_radix(port, 16).ok())
+                .is_some_and(|port| ports.contains(&port)))
+        .filter_map(|fields| fields[9].parse().ok())
+        .collect()
 }
 
-fn print_children(child_map: &HashMap<u64, Vec<u64>>, pid: u64, indent_level: u64) {
-    print_ptree_line(pid, indent_level);
-    if let Some(children) = child_map.get(&pid) {
-        for child in children.iter() {
-            print_children(child_map, *child, indent_level + 1);
+fn all_pids() -> Vec<u64> {
+    let mut pids = match fs::read_dir(proc_root()) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u64>().ok())
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", proc_root(), e);
+            vec![]
         }
-    }
+    };
+    pids.sort();
+    pids
 }
 
-fn print_ptree_line(pid: u64, indent_level: u64) {
-    for _ in 0..indent_level {
-        print!("  ");
+static NO_PROGRESS: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+// The signal which interrupted us, and the write end of the pipe note_interrupt() wakes up poll()
+// through, since a signal arriving just before poll() is called would otherwise go unnoticed
+static INTERRUPT_SIGNAL: AtomicI32 = AtomicI32::new(0);
+static INTERRUPT_WAKEUP: AtomicI32 = AtomicI32::new(-1);
+static INTERRUPT_PIPE: OnceLock<libc::c_int> = OnceLock::new();
+
+// Only does what is async-signal-safe: atomic stores and write(2)
+extern "C" fn note_interrupt(signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    INTERRUPT_SIGNAL.store(signal, Ordering::SeqCst);
+    let fd = INTERRUPT_WAKEUP.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe { libc::write(fd, b"!".as_ptr() as *const libc::c_void, 1) };
     }
-    print!("{}  ", pid);
-    print_cmd_summary(pid);
 }
 
-// As defined by the file type bits of the st_mode field returned by stat
-#[derive(PartialEq)]
-enum PosixFileType {
-    Regular,
-    Directory,
-    Socket,
-    SymLink,
-    BlockDevice,
-    CharDevice,
-    Fifo,
-    Unknown(u32),
+// Loops which wait for something (pwait, --watch, --follow and --follow-forks), and scans of many
+// processes, stop at their next wakeup on SIGINT or SIGTERM, instead of being killed in the middle
+// of a line, so that they can say where they had got to and a pager is left with complete output.
+// Returns an fd which becomes readable once one of them has arrived, to poll() along with whatever
+// else is waited for. The handlers are installed once, and a second signal kills us as usual.
+fn catch_interrupts() -> libc::c_int {
+    *INTERRUPT_PIPE.get_or_init(|| {
+        let mut fds = [-1; 2];
+        unsafe {
+            libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK);
+            INTERRUPT_WAKEUP.store(fds[1], Ordering::SeqCst);
+            for &signal in &[libc::SIGINT, libc::SIGTERM] {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = note_interrupt as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESETHAND;
+                libc::sigaction(signal, &action, std::ptr::null_mut());
+            }
+        }
+        fds[0]
+    })
 }
 
-// As defined by contents of the symlink for the file descriptor in /proc/[pid]/fd/, which has the
-// form 'anon_inode:[eventpoll]' TODO better comment
-#[derive(PartialEq)]
-enum AnonFileType {
-    Epoll,
-    Unknown(String),
+fn interrupt_signal() -> Option<libc::c_int> {
+    match INTERRUPTED.load(Ordering::SeqCst) {
+        true => Some(INTERRUPT_SIGNAL.load(Ordering::SeqCst)),
+        false => None,
+    }
 }
 
-#[derive(PartialEq)]
-enum FileType {
-    Posix(PosixFileType),
-    Anon(AnonFileType),
-    Unknown,
+// Sleep for 'duration', or until we are interrupted, in which case we exit with the status a
+// shell gives a command killed by the signal, once what was printed so far is out
+fn sleep_unless_interrupted(duration: Duration) {
+    let mut fds = [libc::pollfd { fd: catch_interrupts(), events: libc::POLLIN, revents: 0 }];
+    let deadline = Instant::now() + duration;
+    loop {
+        if let Some(signal) = interrupt_signal() {
+            let _ = std::io::stdout().flush();
+            exit(128 + signal);
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return;
+        }
+        // An EINTR is dealt with like any other wakeup
+        let millis = left.as_micros().div_ceil(1000).min(libc::c_int::MAX as u128) as libc::c_int;
+        unsafe { libc::poll(fds.as_mut_ptr(), 1, millis) };
+    }
 }
 
-// Some common types of files have their type described by the st_mode returned by stat. For certain
-// types of files, though, st_mode is zero. In this case we can try to get more info from the text
-// in /proc/[pid]/fd/[fd]
-fn file_type(mode: u32, link_path: &Path) -> FileType {
-    let mode = mode & SFlag::S_IFMT.bits();
-    if mode != 0 {
-        let posix_file_type = match SFlag::from_bits_truncate(mode) {
-            SFlag::S_IFSOCK => PosixFileType::Socket,
-            SFlag::S_IFLNK => PosixFileType::SymLink,
-            SFlag::S_IFREG => PosixFileType::Regular,
-            SFlag::S_IFBLK => PosixFileType::BlockDevice,
-            SFlag::S_IFDIR => PosixFileType::Directory,
-            SFlag::S_IFCHR => PosixFileType::CharDevice,
-            SFlag::S_IFIFO => PosixFileType::Fifo,
-            _ => PosixFileType::Unknown(mode),
-        };
-        FileType::Posix(posix_file_type)
-    } else {
-        // Symlinks normally contain name of another file, but the contents of /proc/[pid]/fd/[fd]
-        // is in this case just text. fs::read_link converts this arbitrary text to a path, and then
-        // we convert it back to a String here. We are assuming this conversion is lossless.
-        let faux_path = match fs::read_link(link_path) {
-           Ok(faux_path) => faux_path,
-            Err(e) => {
-                eprintln!("Failed to read {:?}: {}", link_path, e);
-                return FileType::Unknown
-            }
-        };
-        let fd_info = match faux_path.to_str() {
-            Some(fd_info) => fd_info,
-            None => {
-                eprintln!("Failed to convert path to string: {:?}", faux_path);
-                return FileType::Unknown
-            }
-        };
-        // For anonymous inodes, this text has the format 'anon_inode:[<type>]' or
-        // 'anon_inode:<type>'.
-        if fd_info.starts_with("anon_inode:") {
-            let fd_type_str = fd_info.trim_start_matches("anon_inode:").trim_start_matches("[").trim_end_matches("]");
-            let anon_file_type = match fd_type_str {
-                "eventpoll" => AnonFileType::Epoll,
-                x => AnonFileType::Unknown(x.to_string()),
-            };
-            FileType::Anon(anon_file_type)
-        } else {
-            FileType::Unknown
+// Going through thousands of processes can take minutes, so ScanProgress hands out the pids with a
+// line on stderr saying how far it has got, once it has taken more than a second. It can only share
+// the terminal with the output if the loop prints nothing, or calls clear() before it prints, so a
+// loop which prints as it goes only gets one when its output goes elsewhere.
+//
+// An interrupt (SIGINT or SIGTERM) stops the scan at the next process rather than killing us
+// mid-line, so what was printed so far is complete, then exits. A second one kills us as usual.
+struct ScanProgress {
+    pids: std::vec::IntoIter<u64>,
+    total: usize,
+    done: usize,
+    started: Instant,
+    // When the line was last drawn, if it is showing
+    drawn: Option<Instant>,
+    enabled: bool,
+}
+
+// How far a scan has got, e.g. '1200 of 4000 processes (30%), 12s'
+fn progress_str(done: usize, total: usize, elapsed: Duration) -> String {
+    format!("{} of {} processes ({}%), {}s", done, total, done * 100 / total.max(1), elapsed.as_secs())
+}
+
+impl ScanProgress {
+    // quiet says the loop prints nothing without calling clear() first
+    fn new(pids: Vec<u64>, quiet: bool) -> ScanProgress {
+        let is_tty = |fd| unsafe { libc::isatty(fd) } == 1;
+        let pager = PAGER.lock().is_ok_and(|pager| pager.is_some());
+        let enabled = !NO_PROGRESS.load(Ordering::SeqCst) && is_tty(libc::STDERR_FILENO)
+            && (quiet || (!is_tty(libc::STDOUT_FILENO) && !pager));
+        catch_interrupts();
+        ScanProgress { total: pids.len(), pids: pids.into_iter(), done: 0, started: Instant::now(), drawn: None,
+                       enabled }
+    }
+
+    fn clear(&mut self) {
+        if self.drawn.take().is_some() {
+            eprint!("\r\x1b[K");
         }
     }
 }
 
-fn print_file_type(file_type: &FileType) -> String {
-    match file_type {
-        // For now we print the Posix file types using the somewhat cryptic macro identifiers used
-        // by the st_mode field returned by stat to match what is printed on Solaris. However, given
-        // that we already have more file types than we do on Solaris (because of Linux specific
-        // things like epoll, for example), and given that these additional file types can't be
-        // printed using S_ names (since they don't exist for these file types, since they aren't
-        // understood by stat), we are printing names that are sort of inconsistent. Maybe we should
-        // just be consistent, print better names, and just break compatibility with Solaris pfiles.
-        FileType::Posix(PosixFileType::Regular) => "S_IFREG".into(),
-        FileType::Posix(PosixFileType::Directory) => "S_IFDIR".into(),
-        FileType::Posix(PosixFileType::Socket) => "S_IFSOCK".into(),
-        FileType::Posix(PosixFileType::SymLink) => "S_IFLNK".into(),
-        FileType::Posix(PosixFileType::BlockDevice) => "S_IFBLK".into(),
-        FileType::Posix(PosixFileType::CharDevice) => "S_IFCHR".into(),
-        FileType::Posix(PosixFileType::Fifo) => "S_IFIFO".into(),
-        FileType::Posix(PosixFileType::Unknown(x)) => format!("UNKNOWN_TYPE(mode={})", x),
-        FileType::Anon(AnonFileType::Epoll) => "anon_inode(epoll)".into(),
-        FileType::Anon(AnonFileType::Unknown(s)) => format!("anon_inode({})", s),
-        FileType::Unknown => "UNKNOWN_TYPE".into(),
+impl Iterator for ScanProgress {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if let Some(signal) = interrupt_signal() {
+            self.clear();
+            let _ = std::io::stdout().flush();
+            eprintln!("Interrupted after {} of {} processes", self.done, self.total);
+            exit(128 + signal);
+        }
+        let redraw = self.drawn.is_none_or(|drawn| drawn.elapsed() >= Duration::from_millis(200));
+        if self.enabled && redraw && self.started.elapsed() >= Duration::from_secs(1) {
+            eprint!("\r\x1b[K{}", progress_str(self.done, self.total, self.started.elapsed()));
+            self.drawn = Some(Instant::now());
+        }
+        let pid = self.pids.next()?;
+        self.done += 1;
+        Some(pid)
     }
 }
 
-fn print_open_flags(flags: u64) {
-    let open_flags = vec![
-        (OFlag::O_APPEND, "O_APPEND"),
-        (OFlag::O_ASYNC, "O_ASYNC"),
-        (OFlag::O_CLOEXEC, "O_CLOEXEC"),
-        (OFlag::O_CREAT, "O_CREAT"),
-        (OFlag::O_DIRECT, "O_DIRECT"),
-        (OFlag::O_DIRECTORY, "O_DIRECTORY"),
-        (OFlag::O_DSYNC, "O_DSYNC"),
-        (OFlag::O_EXCL, "O_EXCL"),
-        (OFlag::O_LARGEFILE, "O_LARGEFILE"),
-        (OFlag::O_NOATIME, "O_NOATIME"),
-        (OFlag::O_NOCTTY, "O_NOCTTY"),
-        (OFlag::O_NOFOLLOW, "O_NOFOLLOW"),
-        (OFlag::O_NONBLOCK, "O_NONBLOCK"),
-        (OFlag::O_PATH, "O_PATH"),
-        (OFlag::O_SYNC, "O_SYNC"),
-        (OFlag::O_TMPFILE, "O_TMPFILE"),
-        (OFlag::O_TRUNC, "O_TRUNC"),
-    ];
+impl Drop for ScanProgress {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
 
-    print!(
-        "{}",
-        match OFlag::from_bits_truncate(flags as i32 & OFlag::O_ACCMODE.bits()) {
-            OFlag::O_RDONLY => "O_RDONLY".to_string(),
-            OFlag::O_WRONLY => "O_WRONLY".to_string(),
-            OFlag::O_RDWR => "O_RDWR".to_string(),
-            _ => format!("Unexpected mode {:o}", flags),
+// pargs, penv and pfiles can keep running after printing the processes they were given, and print
+// each process that one of them forks (or that execs something new), and so on down, so that a
+// pool of workers stays covered as it grows. New processes are printed if they pass the selection
+// options, but are followed either way.
+fn add_follow_forks_opt(opts: &mut Options) {
+    opts.optflag("", "follow-forks", "keep running, and also print processes forked by the ones printed, \
+                                      as they are forked or exec");
+}
+
+// Threads can have their own comm, and after unshare() their own fd table and working directory,
+// so pargs, penv and pfiles can look at one thread rather than its whole process
+fn add_task_opt(opts: &mut Options) {
+    opts.optopt("", "task", "look at thread TID of the process (which may then be left out), through \
+                             /proc/PID/task/TID/", "TID");
+}
+
+// Resolve --task to the process whose thread it is, checking that against the process the tool was
+// given, and from then on read the thread's files instead of the process's
+fn apply_task_opt(matches: &Matches, selection: &ProcessSelection, conflicts: &[&str]) -> Result<Option<u64>, String> {
+    let tid = match matches.opt_str("task") {
+        Some(tid) => tid.parse::<u64>().map_err(|_| format!("--task takes a thread id, not '{}'", tid))?,
+        None => return Ok(None),
+    };
+    if is_capture() {
+        return Err("--task can't be used with --from-capture, which doesn't have the files of each thread".to_string());
+    }
+    if selection.is_active() || matches.opts_present(&conflicts.iter().map(|c| c.to_string()).collect::<Vec<_>>()) {
+        let conflicts = conflicts.iter().map(|c| format!("--{}", c)).collect::<Vec<_>>();
+        return Err(format!("--task can't be combined with the selection options or {}", conflicts.join(", ")));
+    }
+    // Threads other than the main one aren't listed in /proc, but can still be looked up there
+    let tgid = ProcStat::read(tid).ok().and_then(|status| status.get_field("Tgid").ok()?.parse::<u64>().ok())
+        .ok_or_else(|| format!("No such thread {}", tid))?;
+    match matches.free.iter().map(|pid| pid.parse::<u64>()).collect::<Vec<_>>()[..] {
+        [] => {}
+        [Ok(pid)] if pid == tgid => {}
+        [Ok(pid)] => return Err(format!("{} isn't a thread of process {}, but of process {}", tid, pid, tgid)),
+        _ => return Err("--task takes a thread of one process".to_string()),
+    }
+    let _ = TASK.set((tgid, tid));
+    Ok(Some(tgid))
+}
+
+// pargs, penv and ptree can keep running and append a JSON snapshot of the processes to a log every
+// so often, as a record of how they changed over time without running an agent. Logs are rotated as
+// logrotate does, keeping LOG_GENERATIONS old ones: log.1 (the newest), log.2 and so on.
+const LOG_GENERATIONS: usize = 4;
+
+fn add_log_opts(opts: &mut Options) {
+    opts.optopt("", "log-to", "keep running, appending a JSON snapshot of the processes to the log TOOL.jsonl \
+                               in DIR every --interval seconds", "DIR");
+    opts.optopt("", "interval", "with --log-to, how often to take a snapshot, in seconds (default 60)", "SECS");
+    opts.optopt("", "rotate", "with --log-to, start a new log once the current one reaches SIZE (default 10M), \
+                               keeping the last 4", "SIZE");
+    opts.optflag("", "daemon", "with --log-to, detach from the terminal and run in the background");
+}
+
+struct SnapshotLog {
+    path: PathBuf,
+    tool: &'static str,
+    interval: Duration,
+    // Start a new log rather than let the current one grow past this many bytes
+    rotate: u64,
+    daemon: bool,
+}
+
+impl SnapshotLog {
+    // None without --log-to. The tools' other options change how they print, which doesn't apply.
+    fn from_matches(matches: &Matches, tool: &'static str, conflicts: &[&str]) -> Result<Option<Self>, String> {
+        let dir = match matches.opt_str("log-to") {
+            Some(dir) => dir,
+            None if ["interval", "rotate", "daemon"].iter().any(|&o| matches.opt_present(o)) => {
+                return Err("--interval, --rotate and --daemon only work with --log-to".to_string());
+            }
+            None => return Ok(None),
+        };
+        if conflicts.iter().any(|&o| matches.opt_present(o)) {
+            let conflicts = conflicts.iter().map(|c| format!("{}{}", if c.len() == 1 { "-" } else { "--" }, c))
+                .collect::<Vec<_>>();
+            return Err(format!("--log-to can't be combined with {}", conflicts.join(", ")));
         }
-    );
+        let interval = match matches.opt_str("interval").map(|secs| secs.parse::<f64>()) {
+            None => Duration::from_secs(60),
+            Some(Ok(secs)) if secs > 0.0 && secs.is_finite() => Duration::from_millis((secs * 1000.0) as u64),
+            Some(_) => return Err("--interval must be a positive number of seconds".to_string()),
+        };
+        let rotate = match matches.opt_str("rotate").map(|size| parse_size(&size)) {
+            None => 10 << 20,
+            Some(Ok(size)) if size > 0 => size,
+            Some(Ok(_)) => return Err("--rotate must be a size larger than 0".to_string()),
+            Some(Err(e)) => return Err(format!("--rotate: {}", e)),
+        };
+        fs::create_dir_all(&dir).map_err(|e| format!("Unable to create {}: {}", dir, e))?;
+        Ok(Some(SnapshotLog {
+            path: Path::new(&dir).join(format!("{}.jsonl", tool)),
+            tool,
+            interval,
+            rotate,
+            daemon: matches.opt_present("daemon"),
+        }))
+    }
 
-    // O_LARGEFILE == 0. Should that get printed everywhere?
-    // probably yes, if we want to match illumos
+    fn generation(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
 
-    for &(flag, _desc) in open_flags.iter() {
-        if (flags as i32 & flag.bits()) != 0 {
-            print!("|{:?}", flag); // TODO don't use debug
+    fn append(&self, line: &str) -> std::io::Result<()> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let size = fs::metadata(&self.path).map_or(0, |meta| meta.len());
+        if size > 0 && size + line.len() as u64 + 1 > self.rotate {
+            for n in (1..LOG_GENERATIONS).rev() {
+                match fs::rename(self.generation(n), self.generation(n + 1)) {
+                    Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                    result => result?,
+                }
+            }
+            fs::rename(&self.path, self.generation(1))?;
         }
+        // Environments can contain secrets, so only our own user may read the logs
+        let mut log = fs::OpenOptions::new().append(true).create(true).mode(0o600).open(&self.path)?;
+        log.write_all(format!("{}\n", line).as_bytes())
     }
 
-    // TODO why does illumos print close on exec separately?
+    fn line(&self, processes: Json) -> String {
+        let now = std::time::SystemTime::now();
+        let millis = now.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        Json::object(vec![
+            ("time", Json::Str(utc_time_str(now))),
+            ("unix_time_ms", Json::UInt(millis)),
+            ("tool", Json::Str(self.tool.to_string())),
+            ("processes", processes),
+        ]).to_string()
+    }
+
+    // The first snapshot is taken before detaching, so that if the log can't be written to, we
+    // can still say so. Later errors are reported (if there is anywhere to) and retried.
+    fn run(&self, snapshot: &mut dyn FnMut() -> Json) -> ! {
+        if let Err(e) = self.append(&self.line(snapshot())) {
+            eprintln!("Unable to write to {}: {}", self.path.display(), e);
+            exit(1);
+        }
+        if self.daemon {
+            if let Err(e) = nix::unistd::daemon(true, false) {
+                eprintln!("Unable to run in the background: {}", e);
+                exit(1);
+            }
+        }
+        let mut next = Instant::now() + self.interval;
+        loop {
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+            next += self.interval;
+            if let Err(e) = self.append(&self.line(snapshot())) {
+                eprintln!("Unable to write to {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
 
-    print!("\n");
+// What pargs and penv log for each process: its id, to tell it from a later process with the same
+// pid, and the strings in its cmdline or environ
+fn strings_snapshot(pids: &[u64], name: &str, file: &str) -> Json {
+    Json::Array(pids.iter().filter_map(|&pid| {
+        let strings = read_nul_strings(&proc_path!("{}/{}", pid, file)).ok()?;
+        Some(Json::object(vec![
+            ("pid", Json::UInt(pid)),
+            ("id", ProcessId::read(pid).map_or(Json::Null, |id| Json::Str(id.to_string()))),
+            (name, Json::Array(strings.into_iter().map(Json::Str).collect())),
+        ]))
+    }).collect())
 }
 
-fn get_flags(pid: u64, fd: u64) -> u64 {
-    let mut contents = String::new();
-    File::open(format!("/proc/{}/fdinfo/{}", pid, fd))
-        .unwrap()
-        .read_to_string(&mut contents)
-        .unwrap();
-    let line = contents
-        .lines()
-        .filter(|line| line.starts_with("flags:"))
-        .collect::<Vec<&str>>()
-        .pop()
-        .unwrap();
-    let str_flags = line.replace("flags:", "");
-    u64::from_str_radix(str_flags.trim(), 8).unwrap()
-}
-
-fn print_file(pid: u64, fd: u64, sockets: &HashMap<u64, SockInfo>) {
-    let link_path_str = format!("/proc/{}/fd/{}", pid, fd);
-    let link_path = Path::new(&link_path_str);
-    let stat_info = match stat(link_path) {
+fn follow_forks(pids: &[u64], selection: &ProcessSelection, print: &mut dyn FnMut(u64)) -> ! {
+    let _ = std::io::stdout().flush();
+    catch_interrupts();
+    let proc_events = Arc::new(Mutex::new(vec![]));
+    let listening = match listen_for_proc_events(proc_events.clone()) {
+        Ok(()) => true,
         Err(e) => {
-            eprintln!("failed to stat {}: {}", &link_path_str, e);
-            return;
-        },
-        Ok(stat_info) => stat_info,
+            eprintln!("Unable to listen for process events, so checking for new processes every second: {}", e);
+            false
+        }
     };
 
-    let file_type = file_type(stat_info.st_mode, &link_path);
+    let mut followed = pids.iter().cloned().collect::<std::collections::HashSet<_>>();
+    let mut print = |pid| {
+        if selection.matches(pid) {
+            print(pid);
+            let _ = std::io::stdout().flush();
+        }
+    };
+    // Most forked processes exec straight away, so they are only printed as they are once they
+    // have, or if they haven't by the next check
+    let mut forked = vec![];
+    while !followed.is_empty() {
+        if listening {
+            sleep_unless_interrupted(Duration::from_millis(100));
+            for pid in forked.drain(..) {
+                print(pid);
+            }
+            for event in proc_events.lock().unwrap().drain(..) {
+                match event {
+                    ProcEvent::Fork { parent, child } if followed.contains(&parent) => {
+                        followed.insert(child);
+                        forked.push(child);
+                    }
+                    ProcEvent::Exec { pid } if followed.contains(&pid) => {
+                        forked.retain(|&child| child != pid);
+                        print(pid);
+                    }
+                    ProcEvent::Exit { pid, .. } => {
+                        followed.remove(&pid);
+                        forked.retain(|&child| child != pid);
+                    }
+                    _ => {}
+                }
+            }
+        } else {
+            sleep_unless_interrupted(Duration::from_secs(1));
+            let tree = match read_process_tree() {
+                Ok(tree) => tree,
+                Err(e) => {
+                    eprintln!("Unable to read processes: {}", e);
+                    exit(1);
+                }
+            };
+            followed.retain(|pid| tree.parent_map.contains_key(pid));
+            for pid in followed.iter().cloned().collect::<Vec<_>>() {
+                walk_tree(&tree, pid, &mut |child, depth| {
+                    if depth > 0 && followed.insert(child) {
+                        print(child);
+                    }
+                    true
+                });
+            }
+        }
+    }
+    exit(0);
+}
 
-    print!(
-        " {: >4}: {} mode:{:o} dev:{},{} ino:{} uid:{} gid:{}",
-        fd,
-        print_file_type(&file_type),
-        stat_info.st_mode & 0o7777,
-        major(stat_info.st_dev),
-        minor(stat_info.st_dev),
-        stat_info.st_ino,
-        stat_info.st_uid,
-        stat_info.st_gid
-    );
+//
+// Tools with long output (pfiles, ptree, pmap) send it through a pager when writing to a terminal,
+// the way git does. This is done by pointing our stdout at the pager's stdin, so the rest of the
+// code can keep using println!(). Since the tools exit() as soon as they are done, waiting for the
+// pager to exit (so that the shell prompt doesn't come back while it is still running) is done from
+// an atexit() handler.
+//
+static PAGER: Mutex<Option<std::process::Child>> = Mutex::new(None);
 
-    let rdev_major = major(stat_info.st_rdev);
-    let rdev_minor = minor(stat_info.st_rdev);
-    if rdev_major == 0 && rdev_minor == 0 {
-        print!(" size:{}\n", stat_info.st_size)
+fn add_pager_opts(opts: &mut Options) {
+    opts.optflag("", "no-pager", "don't send output through a pager when writing to a terminal");
+}
+
+// Which pager to use, if any. An empty or 'cat' pager means not to use one.
+fn pager_command() -> Option<String> {
+    let pager = env::var("PTOOLS_PAGER").or_else(|_| env::var("PAGER")).unwrap_or_else(|_| "less".to_string());
+    if pager.trim().is_empty() || pager.trim() == "cat" {
+        None
     } else {
-        print!(" rdev:{},{}\n", rdev_major, rdev_minor);
+        Some(pager)
     }
+}
 
-    print!("       ");
-    print_open_flags(get_flags(pid, fd));
+extern "C" fn wait_for_pager() {
+    let _ = std::io::stdout().flush();
+    // The pager exits once it sees the end of its input, which needs every copy of the write end of
+    // the pipe closed.
+    unsafe {
+        libc::close(libc::STDOUT_FILENO);
+    }
+    if let Ok(mut pager) = PAGER.lock() {
+        if let Some(mut child) = pager.take() {
+            let _ = child.wait();
+        }
+    }
+}
 
-    // TODO we can print more specific information for epoll fds by looking at /proc/[pid]/fdinfo/[fd]
-    match file_type {
-        FileType::Posix(PosixFileType::Socket) => {
-            // TODO We should read the 'system.sockprotoname' xattr for /proc/[pid]/fd/[fd] for
-            // sockets. That way we can at least print the protocol even if we weren't able to find
-            // any info for the socket in procfs.
-            // TODO make sure we are displaying information that is for the correct namespace
-            // TODO handle IPv6
-            if let Some(sock_info) = sockets.get(&stat_info.st_ino) {
-                print_sock_type(sock_info.sock_type);
-                print_sock_address(&sock_info);
-            } else {
-                print!("       ERROR: failed to find info for socket with inode num {}\n",
-                       stat_info.st_ino);
-            }
-        },
-        _ => {
-            match fs::read_link(link_path) {
-                Ok(path) => println!("       {}", path.to_string_lossy()),
-                Err(e) => eprintln!("failed to readlink {}: {}", &link_path_str, e),
-            }
+fn start_pager(matches: &Matches) {
+    if matches.opt_present("no-pager") || unsafe { libc::isatty(libc::STDOUT_FILENO) } != 1 {
+        return;
+    }
+    let pager = match pager_command() {
+        Some(pager) => pager,
+        None => return,
+    };
+
+    let mut command = std::process::Command::new("/bin/sh");
+    command.arg("-c").arg(&pager).stdin(std::process::Stdio::piped());
+    // Like git: quit if everything fits on one screen, pass colors through, and don't clear the
+    // screen on exit. This is what makes short output behave as if there were no pager.
+    if env::var_os("LESS").is_none() {
+        command.env("LESS", "FRX");
+    }
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Unable to run pager '{}': {}", pager, e);
+            return;
+        }
+    };
+
+    let stdin = child.stdin.take().expect("pager was spawned with a piped stdin");
+    {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { libc::dup2(stdin.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+            eprintln!("Unable to redirect output to pager: {}", std::io::Error::last_os_error());
+            return;
         }
     }
+    drop(stdin);
+
+    // If the user quits the pager early, die quietly from SIGPIPE instead of panicking in
+    // println!() when the write fails.
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    }
+
+    *PAGER.lock().unwrap() = Some(child);
+    unsafe {
+        libc::atexit(wait_for_pager);
+    }
+}
+
+//
+// Most of what these tools do is read state from /proc. A few features need to change the state of
+// the process being inspected: sending it signals, attaching to it with ptrace, changing its
+// resource limits, and so on. We want to be able to guarantee that none of those happen, so that
+// the tools can be used on production systems where observing a process must not disturb it.
+//
+// To keep that guarantee enforceable, any function which changes the state of a process must take a
+// MutateCap. The only way to get one is MutateCap::acquire(), which fails if we are running in
+// read-only mode, so code which only gathers data can't accidentally call into a mutating path,
+// and read-only mode is enforced in exactly one place.
+//
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_read_only() {
+    READ_ONLY.store(true, Ordering::SeqCst);
+}
+
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::SeqCst)
 }
 
 #[derive(Debug)]
-struct SockInfo {
-    family: AddressFamily,
-    sock_type: SockType,
-    inode: u64,
-    local_addr: Option<SocketAddr>, // Doesn't apply to unix sockets
-    peer_addr: Option<SocketAddr>,  // Doesn't apply to unix sockets
-    peer_pid: Option<u64>,          // If the peer is another process on this system
-                                    // TODO state: Option<SockState>, // TCP only
+pub struct ReadOnlyError {
+    action: String,
 }
 
-fn print_sock_type(sock_type: SockType) {
-    println!(
-        "         {}",
-        match sock_type {
-            SockType::Stream => "SOCK_STREAM",
-            SockType::Datagram => "SOCK_DGRAM",
-            SockType::SeqPacket => "SOCK_SEQPACKET",
-            SockType::Raw => "SOCK_RAW",
-            SockType::Rdm => "SOCK_RDM",
+impl Error for ReadOnlyError {}
+
+impl std::fmt::Display for ReadOnlyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Refusing to {}: running in read-only mode", self.action)
+    }
+}
+
+pub struct MutateCap {
+    _private: (),
+}
+
+impl MutateCap {
+    // 'action' describes what the capability is needed for, e.g. "send signal to process 123",
+    // and is used in the error message if we are running in read-only mode.
+    pub fn acquire(action: &str) -> Result<MutateCap, ReadOnlyError> {
+        MutateCap::acquire_in_mode(is_read_only(), action)
+    }
+
+    fn acquire_in_mode(read_only: bool, action: &str) -> Result<MutateCap, ReadOnlyError> {
+        if read_only {
+            return Err(ReadOnlyError { action: action.to_string() });
         }
-    )
+        Ok(MutateCap { _private: () })
+    }
 }
 
-fn address_family_str(addr_fam: AddressFamily) -> &'static str {
-    match addr_fam {
-        AddressFamily::Unix => "AF_UNIX",
-        AddressFamily::Inet => "AF_INET",
-        AddressFamily::Inet6 => "AF_INET6",
-        AddressFamily::Netlink => "AF_NETLINK",
-        AddressFamily::Packet => "AF_PACKET",
-        AddressFamily::Ipx => "AF_IPX",
-        AddressFamily::X25 => "AF_X25",
-        AddressFamily::Ax25 => "AF_AX25",
-        AddressFamily::AtmPvc => "AF_ATMPVC",
-        AddressFamily::AppleTalk => "AF_APPLETALK",
-        AddressFamily::Alg => "AF_ALG",
-        AddressFamily::NetRom => "AF_NETROM",
-        AddressFamily::Bridge => "AF_BRIDGE",
-        AddressFamily::Rose => "AF_ROSE",
-        AddressFamily::Decnet => "AF_DECNET",
-        AddressFamily::NetBeui => "AF_NETBEUI",
-        AddressFamily::Security => "AF_SECURITY",
-        AddressFamily::Key => "AF_KEY",
-        AddressFamily::Ash => "AF_ASH",
-        AddressFamily::Econet => "AF_ECONET",
-        AddressFamily::AtmSvc => "AF_ATMSVC",
-        AddressFamily::Rds => "AF_RDS",
-        AddressFamily::Sna => "AF_SNA",
-        AddressFamily::Irda => "AF_IRDA",
-        AddressFamily::Pppox => "AF_PPPOX",
-        AddressFamily::Wanpipe => "AF_WANPIPE",
-        AddressFamily::Llc => "AF_LLC",
-        AddressFamily::Ib => "AF_IB",
-        AddressFamily::Mpls => "AF_MPLS",
-        AddressFamily::Can => "AF_CAN",
-        AddressFamily::Tipc => "AF_TIPC",
-        AddressFamily::Bluetooth => "AF_BLUETOOTH",
-        AddressFamily::Iucv => "AF_IUCV",
-        AddressFamily::RxRpc => "AF_RXRPC",
-        AddressFamily::Isdn => "AF_ISDN",
-        AddressFamily::Phonet => "AF_PHONET",
-        AddressFamily::Ieee802154 => "AF_IEEE802154",
-        AddressFamily::Caif => "AF_CAIF",
-        AddressFamily::Nfc => "AF_NFC",
-        AddressFamily::Vsock => "AF_VSOCK",
-        AddressFamily::Unspec => panic!("Need to handle this case"), // TODO
+fn open_or_exit(filename: &str) -> File {
+    match File::open(filename) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("{} {}", filename, e);
+            std::process::exit(1);
+        }
     }
 }
 
-fn inet_address_str(addr_fam: AddressFamily, addr: Option<SocketAddr>) -> String {
-    format!(
-        "{} {}",
-        address_family_str(addr_fam),
-        if let Some(addr) = addr {
-            format!("{}  port: {}", addr.ip(), addr.port())
+fn print_args(pid: u64, raw: bool, limits: ArgLimits, lineage: bool) {
+    let file = open_or_exit(&proc_path!("{}/cmdline", pid));
+    print_proc_summary(pid);
+    if lineage {
+        let comm = fs::read_to_string(proc_path!("{}/comm", pid)).map_or_else(|_| "?".to_string(),
+                                                                             |comm| comm.trim_end().to_string());
+        println!("lineage: {}", lineage_str(&process_ancestors(pid), &comm, utf8_locale()));
+    }
+
+    match read_args(BufReader::new(file), limits, utf8_locale()) {
+        Ok(args) => print_argv(pid, &args, "", raw),
+        Err(e) => eprintln!("Unable to read the arguments of process {}: {}", pid, e),
+    }
+}
+
+// The ancestors of a process, its parent first, with their comms. A parent can exit while we look,
+// which leaves the chain short.
+fn process_ancestors(pid: u64) -> Vec<(u64, String)> {
+    let mut ancestors = vec![];
+    let mut ppid = ProcStat::read(pid).and_then(|status| status.ppid()).unwrap_or(0);
+    while ppid != 0 && ancestors.len() < 4096 {
+        let comm = fs::read_to_string(proc_path!("{}/comm", ppid)).map_or_else(|_| "?".to_string(),
+                                                                               |comm| comm.trim_end().to_string());
+        ancestors.push((ppid, comm));
+        ppid = ProcStat::read(ppid).and_then(|status| status.ppid()).unwrap_or(0);
+    }
+    ancestors
+}
+
+// How a process came to run, from the top down, e.g. 'systemd → sshd → bash → make → cc1'
+fn lineage_str(ancestors: &[(u64, String)], comm: &str, utf8: bool) -> String {
+    let arrow = if utf8 { " \u{2192} " } else { " -> " };
+    ancestors.iter().rev().map(|(_, comm)| &comm[..]).chain(std::iter::once(comm)).collect::<Vec<_>>().join(arrow)
+}
+
+// How much of a command line pargs prints, as argv can run to megabytes
+#[derive(Clone, Copy, Debug, Default)]
+struct ArgLimits {
+    max_args: Option<usize>,
+    max_bytes: Option<u64>,
+}
+
+// The arguments kept from a command line, and how large the whole of it was
+#[derive(Debug)]
+struct ReadArgs {
+    args: Vec<String>,
+    // Bytes of the command line the kept arguments take, counting the NUL after each
+    kept_bytes: u64,
+    total_args: u64,
+    total_bytes: u64,
+    // Whether the last argument kept was cut short by max_bytes
+    cut: bool,
+}
+
+impl ReadArgs {
+    fn truncated(&self) -> bool {
+        self.cut || (self.args.len() as u64) < self.total_args
+    }
+}
+
+// Whether the locale can show UTF-8, so arguments which aren't ASCII can be printed as they are
+fn utf8_locale() -> bool {
+    static UTF8: OnceLock<bool> = OnceLock::new();
+    *UTF8.get_or_init(|| unsafe {
+        libc::setlocale(libc::LC_CTYPE, b"\0".as_ptr() as *const libc::c_char);
+        let codeset = libc::nl_langinfo(libc::CODESET);
+        !codeset.is_null() && matches!(std::ffi::CStr::from_ptr(codeset).to_bytes(), b"UTF-8" | b"utf8")
+    })
+}
+
+// An argument as text. Bytes which aren't UTF-8 are shown as \xNN rather than replaced, so nothing
+// is lost, and so is everything which isn't ASCII when the locale can't show it.
+fn arg_str(bytes: &[u8], utf8: bool) -> String {
+    let mut arg = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        if utf8 {
+            arg.push_str(chunk.valid());
         } else {
-            "".to_string()
+            for c in chunk.valid().chars() {
+                match c.is_ascii() {
+                    true => arg.push(c),
+                    false => c.encode_utf8(&mut [0; 4]).bytes().for_each(|b| arg.push_str(&format!("\\x{:02x}", b))),
+                }
+            }
         }
-    )
+        chunk.invalid().iter().for_each(|b| arg.push_str(&format!("\\x{:02x}", b)));
+    }
+    arg
 }
 
-fn print_sock_address(sock_info: &SockInfo) {
-    println!(
-        "         sockname: {}",
-        match sock_info.family {
-            AddressFamily::Inet => inet_address_str(sock_info.family, sock_info.local_addr),
-            AddressFamily::Inet6 => inet_address_str(sock_info.family, sock_info.local_addr),
-            addr_fam => address_family_str(addr_fam).to_string(),
+// Read the NUL separated arguments of a command line one at a time, keeping those within the limits
+// and only counting the rest. An argument can be at most 128k (MAX_ARG_STRLEN), so that's as much
+// as is held at once besides what's kept.
+fn read_args<R: BufRead>(mut reader: R, limits: ArgLimits, utf8: bool) -> std::io::Result<ReadArgs> {
+    let mut read = ReadArgs { args: vec![], kept_bytes: 0, total_args: 0, total_bytes: 0, cut: false };
+    let mut full = false;
+    let mut arg = vec![];
+    loop {
+        arg.clear();
+        let len = reader.read_until(b'\0', &mut arg)? as u64;
+        if len == 0 {
+            return Ok(read);
         }
-    );
+        read.total_args += 1;
+        read.total_bytes += len;
+        let room = limits.max_bytes.map_or(u64::MAX, |max| max.saturating_sub(read.kept_bytes));
+        full = full || room == 0 || limits.max_args.is_some_and(|max| read.args.len() >= max);
+        if full {
+            continue;
+        }
+        if arg.last() == Some(&0) {
+            arg.pop();
+        }
+        if arg.len() as u64 > room {
+            arg.truncate(room as usize);
+            read.cut = true;
+            full = true;
+        }
+        read.kept_bytes += len.min(room);
+        read.args.push(arg_str(&arg, utf8));
+    }
+}
+
+// The ways a process' argv[0], comm and executable can disagree, for pargs --check-names
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NameMismatch {
+    // argv[0] was overwritten with a title, like setproctitle() does
+    Retitled,
+    // argv[0] is another name for the executable, e.g. a link to a multi-call binary like busybox
+    Link,
+    // comm was changed with prctl(PR_SET_NAME)
+    Renamed,
+    // argv[0] names something other than the executable
+    Masquerade,
+    // The executable has been deleted or replaced since the process started
+    Deleted,
+    // The executable is a memfd, so there was never a file on disk
+    Memfd,
+}
+
+impl NameMismatch {
+    fn name(self) -> &'static str {
+        match self {
+            NameMismatch::Retitled => "retitled",
+            NameMismatch::Link => "link",
+            NameMismatch::Renamed => "renamed",
+            NameMismatch::Masquerade => "masquerade",
+            NameMismatch::Deleted => "deleted",
+            NameMismatch::Memfd => "memfd",
+        }
+    }
+
+    // Whether it is worth a look in a security sweep, rather than something programs do routinely
+    fn is_suspicious(self) -> bool {
+        matches!(self, NameMismatch::Masquerade | NameMismatch::Deleted | NameMismatch::Memfd)
+    }
+}
+
+// Compare the names a process goes by. exec() sets comm to the first 15 bytes of the name of the
+// file it was given, which for a script is the script rather than its interpreter (which is then
+// argv[0], with the script as argv[1]). is_link says whether a name for the executable found in
+// argv[0] leads to it.
+fn name_mismatches(argv: &[String], comm: &str, exe: &str, is_link: &dyn Fn(&str) -> bool)
+                   -> Vec<(NameMismatch, String)> {
+    let base = |path: &str| path.rsplit('/').next().unwrap_or(path).to_string();
+    let is_comm = |name: &str| name.bytes().take(15).eq(comm.bytes());
+    let mut mismatches = vec![];
+
+    let path = match exe.strip_suffix(" (deleted)") {
+        Some(path) if path.starts_with("/memfd:") => {
+            mismatches.push((NameMismatch::Memfd, format!("the executable is {}, which only exists in memory", exe)));
+            path
+        }
+        Some(path) => {
+            mismatches.push((NameMismatch::Deleted, format!("the executable {} has been deleted or replaced since \
+                                                             the process started", path)));
+            path
+        }
+        None => exe,
+    };
+    let exe_name = base(path);
+    let script = argv.get(1).is_some_and(|arg| is_comm(&base(arg))) && !is_comm(&exe_name);
+
+    // Login shells are started with a '-' in front of their name
+    let arg0 = argv.first().map_or("", |arg| arg.trim_start_matches('-'));
+    let arg0_name = base(arg0.split([' ', ':']).next().unwrap_or(""));
+    if arg0.starts_with('[') && arg0.ends_with(']') {
+        mismatches.push((NameMismatch::Masquerade, format!("argv[0] is '{}', like the name of a kernel thread", arg0)));
+    } else if arg0.contains(' ') {
+        mismatches.push((NameMismatch::Retitled, format!("argv[0] is '{}', rewritten since exec()", arg0)));
+    } else if arg0_name != exe_name && !script {
+        if is_link(argv.first().map_or("", |arg| arg.as_str())) {
+            mismatches.push((NameMismatch::Link, format!("argv[0] '{}' is another name for the executable {}",
+                                                         arg0, path)));
+        } else {
+            mismatches.push((NameMismatch::Masquerade, format!("argv[0] '{}' doesn't lead to the executable {}",
+                                                               arg0, path)));
+        }
+    }
+
+    if !is_comm(&exe_name) && !is_comm(&arg0_name) && !script {
+        mismatches.push((NameMismatch::Renamed, format!("comm is '{}', which is neither argv[0] nor the executable's \
+                                                         name, so was changed with prctl(PR_SET_NAME)", comm)));
+    }
+    mismatches
+}
+
+// Whether a name a process was started as leads to its executable: a path, from its root or
+// working directory, or a command looked up in its PATH
+fn leads_to_exe(pid: u64, name: &str) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let id = |path: &str| fs::metadata(path).ok().map(|meta| (meta.dev(), meta.ino()));
+    let exe = match id(&format!("/proc/{}/exe", pid)) {
+        Some(exe) => exe,
+        None => return false,
+    };
+    let name = name.trim_start_matches('-');
+    let candidates = if name.starts_with('/') {
+        vec![format!("/proc/{}/root{}", pid, name)]
+    } else if name.contains('/') {
+        vec![format!("/proc/{}/cwd/{}", pid, name)]
+    } else {
+        let env = read_nul_strings(&format!("/proc/{}/environ", pid)).unwrap_or_default();
+        let path = env.iter().find_map(|var| var.strip_prefix("PATH="))
+            .unwrap_or("/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin");
+        path.split(':').filter(|dir| dir.starts_with('/'))
+            .map(|dir| format!("/proc/{}/root{}/{}", pid, dir, name))
+            .collect()
+    };
+    candidates.iter().any(|path| id(path) == Some(exe))
+}
+
+// Print how the names of a process disagree, if they do, calling before_print() first. Returns
+// whether any of the mismatches are suspicious, or None if the process' names couldn't be read.
+fn print_name_mismatches(pid: u64, before_print: &mut dyn FnMut()) -> Option<bool> {
+    let argv = read_nul_strings(&proc_path!("{}/cmdline", pid)).ok()?;
+    let comm = fs::read_to_string(proc_path!("{}/comm", pid)).ok()?;
+    let exe = fs::read_link(proc_path!("{}/exe", pid)).ok()?;
+    // Kernel threads have no arguments
+    if argv.is_empty() {
+        return Some(false);
+    }
+
+    let mismatches = name_mismatches(&argv, comm.trim_end_matches('\n'), &exe.to_string_lossy(),
+                                     &|name| leads_to_exe(pid, name));
+    if !mismatches.is_empty() {
+        before_print();
+        print_proc_summary(pid);
+        for (kind, description) in &mismatches {
+            println!("  [{}] {}", kind.name(), description);
+        }
+    }
+    Some(mismatches.iter().any(|(kind, _)| kind.is_suspicious()))
+}
+
+// Programs which set their process title (e.g. with setproctitle()) write it over their argument
+// strings, usually as one string with spaces in it, so /proc/[pid]/cmdline has a single giant
+// argument. Split that back up on spaces, unless the process was really started with a single
+// argument: argc is how many it was started with, if we know, and is_path says whether the argument
+// is the path of a file, as it would be for a program run without arguments from a directory with
+// spaces in its name.
+fn resplit_args(args: &[String], argc: Option<u64>, is_path: &dyn Fn(&str) -> bool) -> Option<Vec<String>> {
+    if args.len() != 1 || !args[0].contains(' ') || argc == Some(1) || (argc.is_none() && is_path(&args[0])) {
+        return None;
+    }
+    Some(args[0].split(' ').filter(|arg| !arg.is_empty()).map(str::to_string).collect())
+}
+
+// How many arguments a process was started with. The kernel put argc at the start of the stack,
+// followed by pointers to the arguments, so check that the first points at the arguments.
+fn original_argc(pid: u64) -> Option<u64> {
+    // The memory of a captured process isn't there to read
+    if is_capture() {
+        return None;
+    }
+    let stat = fs::read_to_string(proc_path!("{}/stat", pid)).ok()?;
+    let start_stack = stat_field(&stat, 28)?.parse::<u64>().ok()?;
+    let arg_start = stat_field(&stat, 48)?.parse::<u64>().ok()?;
+    let argc = read_process_u64(pid, start_stack)?;
+    let argv0 = read_process_u64(pid, start_stack + 8)?;
+    if argv0 == arg_start && (1..=1 << 20).contains(&argc) {
+        Some(argc)
+    } else {
+        None
+    }
+}
+
+// The arguments of a process split back up, if it rewrote them as one string and raw isn't set
+fn resplit_process_args(pid: u64, args: &[String], raw: bool) -> Option<Vec<String>> {
+    let is_path = |arg: &str| arg.starts_with('/') && Path::new(&format!("/proc/{}/root{}", pid, arg)).exists();
+    match raw {
+        true => None,
+        false => resplit_args(args, original_argc(pid), &is_path),
+    }
+}
+
+fn print_argv(pid: u64, read: &ReadArgs, indent: &str, raw: bool) {
+    let args = &read.args;
+    // Only the whole of an argument can be told apart from one rewritten as one string
+    let split = match read.truncated() {
+        true => None,
+        false => resplit_process_args(pid, args, raw),
+    };
+    match split {
+        Some(split) => {
+            println!("{}argv: rewritten by the process as one string, so split on spaces here (--raw prints it as \
+                      is)", indent);
+            for (i, arg) in split.iter().enumerate() {
+                println!("{}argv[{}]: {}", indent, i, arg);
+            }
+        }
+        None => for (i, arg) in args.iter().enumerate() {
+            println!("{}argv[{}]: {}", indent, i, arg);
+        },
+    }
+    // Say so when arguments are left out, so that whatever reads the output doesn't take what's
+    // there for all of them
+    if read.truncated() {
+        println!("{}argv: truncated, showing {} of {} arguments ({} of {} bytes){}, see --max-args and \
+                  --max-bytes", indent, args.len(), read.total_args, read.kept_bytes, read.total_bytes,
+                 if read.cut { ", the last cut short" } else { "" });
+    }
+}
+
+// Show how an argument changed by marking the words which differ, like wdiff does:
+// '--mem=[-400m-]{+512m+}'. Words are runs of letters and digits, and everything else stands
+// alone, so the unchanged words around a change are kept apart from it.
+fn highlight_arg_change(old: &str, new: &str) -> String {
+    let words = |s: &str| {
+        let mut words: Vec<String> = vec![];
+        for c in s.chars() {
+            match words.last_mut() {
+                Some(word) if c.is_alphanumeric() && word.chars().last().is_some_and(|l| l.is_alphanumeric()) => {
+                    word.push(c)
+                }
+                _ => words.push(c.to_string()),
+            }
+        }
+        words
+    };
+    let (old, new) = (words(old), words(new));
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (old_mid, new_mid) = (old[prefix..old.len() - suffix].concat(), new[prefix..new.len() - suffix].concat());
+    let mut s = old[..prefix].concat();
+    if !old_mid.is_empty() {
+        s += &format!("[-{}-]", old_mid);
+    }
+    if !new_mid.is_empty() {
+        s += &format!("{{+{}+}}", new_mid);
+    }
+    s + &old[old.len() - suffix..].concat()
+}
+
+// Above this many pairs of differing arguments to compare, argv_diff() gives up on pairing them
+const ARGV_DIFF_MAX_CELLS: usize = 1 << 20;
+
+// How the arguments of one process differ from those of another, as lines like diff's: ' ' for
+// arguments both have, '-' and '+' for those only one has, and '~' for an argument changed in
+// place, with the words which changed marked. The indexes are those in the first process, except
+// for arguments only the second has.
+fn argv_diff(old: &[String], new: &[String]) -> Vec<String> {
+    // Most of the arguments are usually the same, so only what's between the arguments both start
+    // and end with needs comparing
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (old_end, new_end) = (old.len() - suffix, new.len() - suffix);
+
+    let mut lines = (0..prefix).map(|i| format!("  argv[{}]: {}", i, old[i])).collect::<Vec<_>>();
+    if (old_end - prefix).saturating_mul(new_end - prefix) > ARGV_DIFF_MAX_CELLS {
+        // Too many to compare in reasonable time and memory, so list them all as different
+        lines.extend((prefix..old_end).map(|i| format!("- argv[{}]: {}", i, old[i])));
+        lines.extend((prefix..new_end).map(|j| format!("+ argv[{}]: {}", j, new[j])));
+    } else {
+        argv_diff_lcs(old, new, (prefix, old_end), (prefix, new_end), &mut lines);
+    }
+    lines.extend((old_end..old.len()).map(|i| format!("  argv[{}]: {}", i, old[i])));
+    lines
+}
+
+// argv_diff() of the arguments old[start..end] and new[start..end], pairing them up by their
+// longest common subsequence
+fn argv_diff_lcs(old: &[String], new: &[String], (old_start, old_end): (usize, usize),
+                 (new_start, new_end): (usize, usize), lines: &mut Vec<String>) {
+    // The lengths of the longest common subsequences of the suffixes, by the usual table
+    let mut lcs = vec![vec![0u32; new_end - new_start + 1]; old_end - old_start + 1];
+    for i in (old_start..old_end).rev() {
+        for j in (new_start..new_end).rev() {
+            let (x, y) = (i - old_start, j - new_start);
+            lcs[x][y] = if old[i] == new[j] { lcs[x + 1][y + 1] + 1 } else { lcs[x + 1][y].max(lcs[x][y + 1]) };
+        }
+    }
+    let longer = |i: usize, j: usize| lcs[i + 1 - old_start][j - new_start] >= lcs[i - old_start][j + 1 - new_start];
+
+    let (mut i, mut j) = (old_start, new_start);
+    while i < old_end || j < new_end {
+        if i < old_end && j < new_end && old[i] == new[j] {
+            lines.push(format!("  argv[{}]: {}", i, old[i]));
+            i += 1;
+            j += 1;
+            continue;
+        }
+        // A run of arguments which differ: those removed and added are paired up as changes
+        let (mut removed, mut added) = (vec![], vec![]);
+        while (i < old_end || j < new_end) && !(i < old_end && j < new_end && old[i] == new[j]) {
+            if j == new_end || (i < old_end && longer(i, j)) {
+                removed.push(i);
+                i += 1;
+            } else {
+                added.push(j);
+                j += 1;
+            }
+        }
+        let changed = removed.len().min(added.len());
+        for (&r, &a) in removed.iter().zip(&added) {
+            lines.push(format!("~ argv[{}]: {}", r, highlight_arg_change(&old[r], &new[a])));
+        }
+        for &r in &removed[changed..] {
+            lines.push(format!("- argv[{}]: {}", r, old[r]));
+        }
+        for &a in &added[changed..] {
+            lines.push(format!("+ argv[{}]: {}", a, new[a]));
+        }
+    }
+}
+
+// Print how the arguments of the second process differ from those of the first, returning whether
+// they do
+fn print_argv_compare(pids: (u64, u64), raw: bool) -> Result<bool, Box<dyn Error>> {
+    let mut argvs = vec![];
+    for &pid in &[pids.0, pids.1] {
+        let args = read_nul_strings(&proc_path!("{}/cmdline", pid))
+            .map_err(|e| format!("Unable to read arguments of process {}: {}", pid, e))?;
+        argvs.push(resplit_process_args(pid, &args, raw).unwrap_or(args));
+    }
+    println!("--- {}: {}", pids.0, cmd_summary(pids.0).trim_end());
+    println!("+++ {}: {}", pids.1, cmd_summary(pids.1).trim_end());
+    let lines = argv_diff(&argvs[0], &argvs[1]);
+    for line in &lines {
+        println!("{}", line);
+    }
+    Ok(lines.iter().any(|line| !line.starts_with(' ')))
+}
+
+// Print the arguments of a process and of all its descendants, in tree order and indented like
+// ptree does.
+fn print_args_tree(tree: &ProcessTree, pid: u64, raw: bool, limits: ArgLimits) {
+    walk_tree(tree, pid, &mut |pid, depth| {
+        let indent = "  ".repeat(depth);
+        // Processes in the subtree can exit while we walk it, so just leave those out
+        let args = File::open(proc_path!("{}/cmdline", pid))
+            .and_then(|file| read_args(BufReader::new(file), limits, utf8_locale()));
+        if let Ok(args) = args {
+            print!("{}", indent);
+            print_proc_summary(pid);
+            print_argv(pid, &args, &indent, raw);
+        }
+        true
+    });
+}
+
+// Options controlling how penv prints the environment
+struct PenvOptions {
+    // Print variables in the order they appear in the environment block, rather than by name
+    original_order: bool,
+    // Warn about values longer than this, if set
+    max_value_len: Option<usize>,
+    // Only print the problems found, including hazards which are fine more often than not
+    lint: bool,
+    // Compare the environment with the settings of the process' systemd unit, which takes a couple
+    // of systemctl runs per process
+    unit_check: bool,
+}
+
+// Values longer than this are most likely a mistake, like a script appending to PATH in a loop
+const DEFAULT_MAX_ENV_VALUE_LEN: usize = 4096;
+
+fn env_name(var: &str) -> &str {
+    var.split('=').next().unwrap_or(var)
+}
+
+// Look for problems in an environment which are known to break programs. Variables are referred to
+// by their index in the environment block.
+fn env_warnings(env: &[String], max_value_len: Option<usize>) -> Vec<String> {
+    let mut warnings = vec![];
+
+    // The same name can appear more than once if whatever started the process built the
+    // environment by hand. getenv() returns the first, but programs which copy the environment
+    // into a map usually keep the last, so different parts of a program can disagree on the value.
+    let mut indices: Vec<(&str, Vec<usize>)> = vec![];
+    for (i, var) in env.iter().enumerate() {
+        if !var.contains('=') {
+            warnings.push(format!("envp[{}] has no '=', so isn't a variable", i));
+            continue;
+        }
+        match indices.iter_mut().find(|(name, _)| *name == env_name(var)) {
+            Some((_, list)) => list.push(i),
+            None => indices.push((env_name(var), vec![i])),
+        }
+    }
+    for (name, list) in indices.iter().filter(|(_, list)| list.len() > 1) {
+        let list = list.iter().map(|i| format!("envp[{}]", i)).collect::<Vec<_>>();
+        warnings.push(format!("{} is set {} times ({})", name, list.len(), list.join(", ")));
+    }
+
+    if let Some(max_len) = max_value_len {
+        for (i, var) in env.iter().enumerate() {
+            let len = var.len().saturating_sub(env_name(var).len() + 1);
+            if len > max_len {
+                warnings.push(format!("{} (envp[{}]) is {} bytes long", env_name(var), i, len));
+            }
+        }
+    }
+
+    warnings
+}
+
+// Settings which are sometimes deliberate but often behind "works in my shell, not in the service"
+// problems, for --lint. Whether directories exist is answered by dir_exists, which gets absolute
+// paths, or is None if we can't tell.
+fn env_hazards(env: &[String], dir_exists: Option<&dyn Fn(&str) -> bool>) -> Vec<String> {
+    let mut hazards = vec![];
+    let value = |name: &str| env.iter().find(|var| env_name(var) == name)
+        .map(|var| var.get(name.len() + 1..).unwrap_or(""));
+
+    if let Some(preload) = value("LD_PRELOAD") {
+        hazards.push(format!("LD_PRELOAD is set to '{}', which is loaded into every program run with \
+                              this environment", preload));
+    }
+    for name in &["LD_LIBRARY_PATH", "PATH"] {
+        let dirs = match value(name) {
+            Some(dirs) => dirs,
+            None => continue,
+        };
+        for dir in dirs.split(':') {
+            if !dir.starts_with('/') {
+                let dir = if dir.is_empty() { "an empty entry".to_string() } else { format!("'{}'", dir) };
+                hazards.push(format!("{} has {}, which is relative to the current directory", name, dir));
+            } else if dir_exists.is_some_and(|exists| !exists(dir)) {
+                hazards.push(format!("{} has '{}', which doesn't exist", name, dir));
+            }
+        }
+    }
+    if value("TZ").is_none() {
+        hazards.push("TZ isn't set, so the C library checks /etc/localtime whenever it converts a time, \
+                      and the process' idea of the time zone depends on that file".to_string());
+    }
+
+    hazards
+}
+
+// How an environment differs from a baseline saved by penvdiff --save, as lines like diff's: '-'
+// for variables which were removed or changed, followed by '+' for their new values, and '+' for
+// new variables. Only the first occurrence of a name counts, as for getenv(). Variables named in
+// ignore are left out.
+fn env_diff(baseline: &[String], env: &[String], ignore: &[String]) -> Vec<String> {
+    let vars = |env: &[String]| {
+        let mut vars: Vec<(String, String)> = vec![];
+        for var in env {
+            let name = env_name(var);
+            if !vars.iter().any(|(n, _)| n == name) && !ignore.iter().any(|n| n == name) {
+                vars.push((name.to_string(), var.get(name.len() + 1..).unwrap_or("").to_string()));
+            }
+        }
+        vars.sort();
+        vars
+    };
+    let (old, new) = (vars(baseline), vars(env));
+
+    let mut names = old.iter().chain(&new).map(|(name, _)| name).collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    let mut lines = vec![];
+    for name in names {
+        let value = |vars: &[(String, String)]| vars.iter().find(|(n, _)| n == name).map(|(_, value)| value.clone());
+        let (old, new) = (value(&old), value(&new));
+        if old == new {
+            continue;
+        }
+        lines.extend(old.map(|value| format!("-{}={}", name, value)));
+        lines.extend(new.map(|value| format!("+{}={}", name, value)));
+    }
+    lines
+}
+
+// Baselines are stored like /proc/[pid]/environ, as NUL terminated variables, so that values with
+// newlines survive. Environments often have secrets in them, so only the owner can read the file.
+fn save_env_baseline(path: &str, env: &[String]) -> std::io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    file.write_all(env.iter().map(|var| format!("{}\0", var)).collect::<String>().as_bytes())
+}
+
+// Variables systemd sets itself in the processes it starts, with values we can't predict (see
+// "Environment Variables in Spawned Processes" in systemd.exec(5)). Locale variables (LANG and LC_*)
+// come from locale.conf.
+const SYSTEMD_EXEC_VARS: &[&str] = &[
+    "PATH", "LANG", "USER", "LOGNAME", "HOME", "SHELL", "TERM", "INVOCATION_ID", "JOURNAL_STREAM",
+    "SYSTEMD_EXEC_PID", "MAINPID", "MANAGERPID", "NOTIFY_SOCKET", "WATCHDOG_PID", "WATCHDOG_USEC",
+    "LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES", "XDG_RUNTIME_DIR", "RUNTIME_DIRECTORY",
+    "STATE_DIRECTORY", "CACHE_DIRECTORY", "LOGS_DIRECTORY", "CONFIGURATION_DIRECTORY",
+    "CREDENTIALS_DIRECTORY", "FDSTORE", "LOG_NAMESPACE", "MEMORY_PRESSURE_WATCH",
+    "MEMORY_PRESSURE_WRITE", "SERVICE_RESULT", "EXIT_CODE", "EXIT_STATUS", "PIDFILE",
+];
+
+// The system service a process belongs to, from its cgroup (e.g. /system.slice/nginx.service, or
+// a cgroup below it if the service delegates). User services are run by another instance of
+// systemd, which we don't ask.
+fn systemd_service(cgroup: &str) -> Option<String> {
+    if cgroup.split('/').any(|c| c.starts_with("user@")) {
+        return None;
+    }
+    cgroup.split('/').rfind(|c| c.ends_with(".service") && c.len() > ".service".len()).map(str::to_string)
+}
+
+// The system service a process belongs to, from whichever of the unified and systemd's own cgroup
+// hierarchies it has
+fn process_service(pid: u64) -> Option<String> {
+    let contents = fs::read_to_string(proc_path!("{}/cgroup", pid)).ok()?;
+    parse_cgroup(&contents, &CgroupHierarchy::Unified)
+        .or_else(|| parse_cgroup(&contents, &CgroupHierarchy::Controller("name=systemd".to_string())))
+        .and_then(|cgroup| systemd_service(&cgroup))
+}
+
+// Split a list of words the way systemd quotes them, e.g. in 'systemctl show' output for
+// Environment=: separated by spaces, with single or double quotes around words containing spaces
+// and C-style escapes.
+fn split_unit_words(s: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return words;
+        }
+        let mut word = String::new();
+        let mut quote = None;
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('n') => word.push('\n'),
+                    Some('t') => word.push('\t'),
+                    Some('x') => {
+                        let hex = chars.by_ref().take(2).collect::<String>();
+                        if let Ok(b) = u8::from_str_radix(&hex, 16) {
+                            word.push(b as char);
+                        }
+                    }
+                    Some(c) => word.push(c),
+                    None => {}
+                },
+                '"' | '\'' if quote.is_none() => quote = Some(c),
+                c if quote == Some(c) => quote = None,
+                c if c.is_whitespace() && quote.is_none() => break,
+                c => word.push(c),
+            }
+        }
+        words.push(word);
+    }
+}
+
+// Parse an EnvironmentFile= file: KEY=VALUE lines, with '#' or ';' comments, optionally quoted
+// values, and lines continued with a trailing backslash.
+fn parse_env_file(contents: &str) -> Vec<(String, String)> {
+    let mut vars = vec![];
+    let mut pending = String::new();
+    for line in contents.lines() {
+        if let Some(line) = line.strip_suffix('\\') {
+            pending.push_str(line);
+            continue;
+        }
+        pending.push_str(line);
+        let line = std::mem::take(&mut pending);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some((name, value)) = line.split_once('=') {
+            let value = value.trim();
+            let value = match split_unit_words(value) {
+                ref words if value.starts_with('"') || value.starts_with('\'') => words.join(" "),
+                _ => value.to_string(),
+            };
+            vars.push((name.trim().to_string(), value));
+        }
+    }
+    vars
+}
+
+// What a service's processes are expected to have a variable set to, and what set it. Variables
+// which systemd sets itself, or passes on from its own environment, can have any value.
+struct ExpectedVar {
+    value: Option<String>,
+    source: String,
+}
+
+// The environment a service is configured to start its processes with: the service manager's
+// environment, then Environment=, then EnvironmentFile= in order, each overriding the last.
+// EnvironmentFile= files which have changed since the process started are pointed out, since
+// they won't match what it was started with.
+fn service_environment(unit: &str, started: Option<std::time::SystemTime>) -> Result<HashMap<String, ExpectedVar>, Box<dyn Error>> {
+    let systemctl = |args: &[&str]| -> Result<String, Box<dyn Error>> {
+        let output = std::process::Command::new("systemctl").args(args).output()?;
+        if !output.status.success() {
+            return Err(From::from(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+
+    let mut expected = HashMap::new();
+    for &name in SYSTEMD_EXEC_VARS {
+        expected.insert(name.to_string(), ExpectedVar { value: None, source: "systemd".to_string() });
+    }
+    for var in systemctl(&["show-environment"])?.lines() {
+        if let Some((name, value)) = var.split_once('=') {
+            let value = split_unit_words(value).join(" ");
+            expected.insert(name.to_string(), ExpectedVar { value: Some(value), source: "the service manager".to_string() });
+        }
+    }
+
+    let show = systemctl(&["show", "--property=Environment", "--property=EnvironmentFiles",
+                           "--property=PassEnvironment", "--property=UnsetEnvironment", "--", unit])?;
+    let property = |name: &str| show.lines().filter_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+        .map(str::to_string).collect::<Vec<_>>();
+    for name in property("PassEnvironment").iter().flat_map(|names| split_unit_words(names)) {
+        expected.insert(name, ExpectedVar { value: None, source: format!("PassEnvironment= of {}", unit) });
+    }
+    for var in property("Environment").iter().flat_map(|vars| split_unit_words(vars)) {
+        if let Some((name, value)) = var.split_once('=') {
+            expected.insert(name.to_string(), ExpectedVar { value: Some(value.to_string()), source: format!("Environment= of {}", unit) });
+        }
+    }
+    for file in property("EnvironmentFiles") {
+        // e.g. '/etc/default/ssh (ignore_errors=yes)'
+        let path = file.rsplit_once(" (").map_or(file.as_str(), |(path, _)| path);
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        let changed = started.is_some_and(|started| {
+            fs::metadata(path).and_then(|m| m.modified()).is_ok_and(|modified| modified > started)
+        });
+        let source = if changed { format!("{} (changed since the process started)", path) } else { path.to_string() };
+        for (name, value) in parse_env_file(&contents) {
+            expected.insert(name, ExpectedVar { value: Some(value), source: source.clone() });
+        }
+    }
+    for name in property("UnsetEnvironment").iter().flat_map(|names| split_unit_words(names)) {
+        expected.remove(env_name(&name));
+    }
+    Ok(expected)
+}
+
+// Compare an environment with what its service is configured to set, to find variables which were
+// added or changed after systemd started it, e.g. by a wrapper script.
+fn service_env_warnings(env: &[String], unit: &str, expected: &HashMap<String, ExpectedVar>) -> Vec<String> {
+    let mut warnings = vec![];
+    for (i, var) in env.iter().enumerate() {
+        let (name, value) = match var.split_once('=') {
+            Some(name_value) => name_value,
+            None => continue,
+        };
+        match expected.get(name) {
+            _ if name.starts_with("LC_") => {}
+            None => warnings.push(format!("{} (envp[{}]) isn't set by {} or the service manager, so was added after \
+                                           it was started", name, i, unit)),
+            Some(&ExpectedVar { value: Some(ref expected), ref source }) if expected != value =>
+                warnings.push(format!("{} (envp[{}]) is '{}', but {} sets it to '{}'", name, i, value, source, expected)),
+            Some(_) => {}
+        }
+    }
+
+    let mut missing = expected.iter()
+        .filter(|(name, var)| var.value.is_some() && var.source != "the service manager"
+                && !env.iter().any(|v| env_name(v) == name.as_str()))
+        .collect::<Vec<_>>();
+    missing.sort_by_key(|&(name, _)| name);
+    for (name, var) in missing {
+        warnings.push(format!("{} sets {}, but it isn't in the environment", var.source, name));
+    }
+    warnings
+}
+
+// The warnings about how the environment of a process differs from its service's configuration,
+// or nothing if it isn't part of a system service, or we can't ask systemd about it.
+fn unit_env_warnings(pid: u64, env: &[String]) -> Vec<String> {
+    if is_capture() {
+        return vec![];
+    }
+    let unit = match process_service(pid) {
+        Some(unit) => unit,
+        None => return vec![],
+    };
+    let started = process_age(pid).ok().and_then(|age| std::time::SystemTime::now().checked_sub(age));
+    match service_environment(&unit, started) {
+        Ok(expected) => service_env_warnings(env, &unit, &expected),
+        Err(_) => vec![],
+    }
+}
+
+// How much of the room exec() gives the argument and environment strings they take. Each string
+// takes its length and a NUL, plus a pointer to it.
+#[derive(Debug, PartialEq)]
+struct ExecSize {
+    env_bytes: u64,
+    env_count: usize,
+    argv_bytes: u64,
+    argv_count: usize,
+    // What exec() allows for both, which depends on the stack rlimit
+    limit: u64,
+}
+
+// exec() fails with E2BIG when argv and envp take more than a quarter of the stack rlimit, capped
+// at 6MiB but never less than ARG_MAX (128KiB), or when any one string is longer than 32 pages
+// (see bprm_stack_limits() in fs/exec.c).
+const ARG_MAX: u64 = 128 * 1024;
+
+fn exec_size(env: &[String], argv: &[String], stack_rlimit: Option<u64>) -> ExecSize {
+    let bytes = |strings: &[String]| strings.iter().map(|s| s.len() as u64 + 1 + 8).sum();
+    ExecSize {
+        env_bytes: bytes(env),
+        env_count: env.len(),
+        argv_bytes: bytes(argv),
+        argv_count: argv.len(),
+        limit: (stack_rlimit.unwrap_or(u64::MAX) / 4).clamp(ARG_MAX, 6 << 20),
+    }
+}
+
+// Children inherit the environment and stack rlimit, so an environment which takes up most of what
+// exec() allows leaves them little room for arguments, and CI runners and build systems which
+// keep adding to it eventually see every exec() fail with E2BIG.
+fn exec_size_warnings(env: &[String], size: &ExecSize, page_size: u64) -> Vec<String> {
+    let mut warnings = vec![];
+    if size.env_bytes >= size.limit {
+        warnings.push(format!("the environment takes {} bytes, more than the {} exec() allows with this stack \
+                               rlimit, so children can't exec() anything without shrinking it (E2BIG)",
+                              size.env_bytes, size.limit));
+    } else if size.env_bytes > size.limit / 2 {
+        warnings.push(format!("the environment takes {} bytes, {}% of the {} exec() allows with this stack \
+                               rlimit, so children's exec() fails with E2BIG for arguments over {} bytes",
+                              size.env_bytes, size.env_bytes * 100 / size.limit, size.limit,
+                              size.limit - size.env_bytes));
+    }
+    for (i, var) in env.iter().enumerate().filter(|(_, var)| var.len() as u64 >= 32 * page_size) {
+        warnings.push(format!("{} (envp[{}]) is {} bytes, more than the {} exec() allows for one string, so \
+                               children can't exec() anything while it is set (E2BIG)",
+                              env_name(var), i, var.len(), 32 * page_size));
+    }
+    warnings
+}
+
+// Returns whether there were any warnings
+fn print_env(pid: u64, penv_opts: &PenvOptions) -> bool {
+    // This contains the environ as it was when the proc was started. To get the current
+    // environment, we need to inspect its memory to find out how it has change. POSIX defines a
+    // char **__environ symbol that we will need to find. Unfortunately, inspecting the memory of
+    // another process is not typically permitted, even if the process owned by the same user. See
+    // /etc/sysctl.d/10-ptrace.conf for details.
+    //
+    // Long term, we might want to print the current environment if we can, and print a warning
+    // + the contents of /proc/[pid]/environ if we can't
+    let path = proc_path!("{}/environ", pid);
+    let env = match read_nul_strings(&path) {
+        Ok(env) => env,
+        Err(e) => {
+            eprintln!("{} {}", path, e);
+            exit(1);
+        }
+    };
+    print_proc_summary(pid);
+
+    // Keep the index into the environment block when sorting, so that it still identifies the
+    // variable. The sort is stable, so duplicates are listed in the order they appear.
+    let mut vars = env.iter().enumerate().collect::<Vec<_>>();
+    if !penv_opts.original_order {
+        vars.sort_by_key(|&(_, var)| env_name(var));
+    }
+    // What the process was started with is what it passes on, unless it changed its environment
+    let argv = read_nul_strings(&proc_path!("{}/cmdline", pid)).unwrap_or_default();
+    let stack_rlimit = fs::read_to_string(proc_path!("{}/limits", pid)).ok()
+        .and_then(|limits| parse_limit(&limits, "Max stack size"))
+        .and_then(|(soft, _)| soft);
+    let size = exec_size(&env, &argv, stack_rlimit);
+    if !penv_opts.lint {
+        for (i, var) in vars {
+            println!("envp[{}]: {}", i, var);
+        }
+        println!("size: envp {} bytes ({} variables), argv {} bytes ({} arguments), of {} bytes exec() allows",
+                 size.env_bytes, size.env_count, size.argv_bytes, size.argv_count, size.limit);
+    }
+
+    let mut warnings = env_warnings(&env, penv_opts.max_value_len);
+    warnings.extend(exec_size_warnings(&env, &size, page_size()));
+    if penv_opts.unit_check {
+        warnings.extend(unit_env_warnings(pid, &env));
+    }
+    if penv_opts.lint {
+        // Directories are looked up from the process' root, since it may be in a container. A
+        // capture has no filesystem to look in.
+        let dir_exists = |dir: &str| Path::new(&format!("/proc/{}/root{}", pid, dir)).is_dir();
+        let dir_exists: Option<&dyn Fn(&str) -> bool> = if is_capture() { None } else { Some(&dir_exists) };
+        warnings.extend(env_hazards(&env, dir_exists));
+    }
+    for warning in &warnings {
+        println!("WARNING: {}", warning);
+    }
+    !warnings.is_empty()
+}
+
+// With --common, the environment most of the processes share is printed once, then only how each
+// process' differs from it, so that a pool of identical workers doesn't print the same environment
+// hundreds of times. Returns false if some environments couldn't be read.
+fn print_common_env(pids: &[u64]) -> bool {
+    let mut ok = true;
+    let mut envs = vec![];
+    for &pid in pids {
+        match read_nul_strings(&proc_path!("{}/environ", pid)) {
+            Ok(env) => envs.push((pid, env)),
+            Err(e) => {
+                eprintln!("Unable to read the environment of process {}: {}", pid, e);
+                ok = false;
+            }
+        }
+    }
+    let common = common_env(&envs.iter().map(|(_, env)| env.as_slice()).collect::<Vec<_>>());
+    println!("common environment of {} processes ({} variables):", envs.len(), common.len());
+    for var in &common {
+        println!("  {}", var);
+    }
+    let mut same = vec![];
+    for (pid, env) in &envs {
+        let (added, missing) = env_differences(env, &common);
+        if added.is_empty() && missing.is_empty() {
+            same.push(pid.to_string());
+            continue;
+        }
+        print_proc_summary(*pid);
+        for var in added {
+            println!("  + {}", var);
+        }
+        for var in missing {
+            println!("  - {}", var);
+        }
+    }
+    if !same.is_empty() {
+        println!("same as the common environment: {}", same.join(" "));
+    }
+    ok
+}
+
+// The variables which more than half of the environments have, sorted by name. For two processes
+// that is what they have in common, and for a pool it is what it would be but for a few outliers.
+fn common_env(envs: &[&[String]]) -> Vec<String> {
+    let mut counts = HashMap::new();
+    for env in envs {
+        for var in env.iter().collect::<std::collections::HashSet<_>>() {
+            *counts.entry(var).or_insert(0) += 1;
+        }
+    }
+    let mut common = counts.into_iter().filter(|&(_, count)| count * 2 > envs.len()).map(|(var, _)| var.clone())
+        .collect::<Vec<_>>();
+    common.sort_by(|a, b| env_name(a).cmp(env_name(b)).then_with(|| a.cmp(b)));
+    common
+}
+
+// The variables an environment has which the common one doesn't, and those of the common one it
+// lacks, sorted by name
+fn env_differences<'a>(env: &'a [String], common: &'a [String]) -> (Vec<&'a String>, Vec<&'a String>) {
+    let mut added = env.iter().filter(|var| !common.contains(var)).collect::<Vec<_>>();
+    added.sort_by(|a, b| env_name(a).cmp(env_name(b)).then_with(|| a.cmp(b)));
+    let missing = common.iter().filter(|var| !env.contains(var)).collect();
+    (added, missing)
+}
+
+// Read a file of NUL-separated strings, like /proc/[pid]/cmdline or /proc/[pid]/environ
+fn read_nul_strings(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut strings = vec![];
+    for bytes in BufReader::new(File::open(path)?).split(b'\0') {
+        strings.push(String::from_utf8_lossy(&bytes?).into_owned());
+    }
+    Ok(strings)
+}
+
+// Print the pid and a summary of command line arguments on a single line.
+fn print_proc_summary(pid: u64) {
+    match TASK.get() {
+        // Like Solaris, name a thread as pid/lwp
+        Some(&(task_pid, tid)) if task_pid == pid => print!("{:8}", format!("{}/{}:", pid, tid)),
+        _ => print!("{:8}", format!("{}:", pid)),
+    }
+    print_cmd_summary(pid);
+}
+
+#[derive(Debug)]
+struct ParseError {
+    reason: String,
+}
+
+impl ParseError {
+    fn new(item: &str, reason: &str) -> Self {
+        ParseError {
+            reason: format!("Error parsing {}: {}", item, reason),
+        }
+    }
+    fn in_file(file: &str, reason: &str) -> Self {
+        ParseError {
+            reason: format!("Error parsing /proc/[pid]/{}: {}", file, reason),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+// A minimal JSON document model, so that tools can offer machine readable output without pulling in
+// a serialization crate. Objects keep their keys in insertion order so output is stable.
+enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    // Parse a complete JSON document. Only integral numbers are supported, since nothing we read
+    // needs fractions.
+    fn parse(s: &str) -> Result<Json, ParseError> {
+        let mut parser = JsonParser { input: s.as_bytes(), pos: 0 };
+        let value = parser.parse_value(0)?;
+        parser.skip_whitespace();
+        if parser.pos != parser.input.len() {
+            return Err(parser.err("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Json::UInt(n) => Some(n),
+            Json::Int(n) if n >= 0 => Some(n as u64),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::Str(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn write_str(f: &mut std::fmt::Formatter, s: &str) -> std::fmt::Result {
+        write!(f, "\"")?;
+        for c in s.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '\r' => write!(f, "\\r")?,
+                '\t' => write!(f, "\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(n) => write!(f, "{}", n),
+            Json::UInt(n) => write!(f, "{}", n),
+            Json::Str(ref s) => Json::write_str(f, s),
+            Json::Array(ref items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(ref fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    Json::write_str(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+// A recursive descent parser for the input side of Json
+struct JsonParser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    // Documents come from other processes, so don't let a deeply nested one exhaust the stack
+    const MAX_DEPTH: usize = 64;
+
+    fn err(&self, reason: &str) -> ParseError {
+        ParseError::new("JSON", &format!("{} at offset {}", reason, self.pos))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            return Err(self.err(&format!("expected '{}'", c as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Json, ParseError> {
+        if depth > Self::MAX_DEPTH {
+            return Err(self.err("document nested too deeply"));
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.parse_object(depth),
+            Some(b'[') => self.parse_array(depth),
+            Some(b'"') => Ok(Json::Str(self.parse_string()?)),
+            Some(b't') => self.parse_literal("true", Json::Bool(true)),
+            Some(b'f') => self.parse_literal("false", Json::Bool(false)),
+            Some(b'n') => self.parse_literal("null", Json::Null),
+            Some(c) if c == b'-' || c.is_ascii_digit() => self.parse_number(),
+            Some(_) => Err(self.err("unexpected character")),
+            None => Err(self.err("unexpected end of input")),
+        }
+    }
+
+    fn parse_literal(&mut self, literal: &str, value: Json) -> Result<Json, ParseError> {
+        if !self.input[self.pos..].starts_with(literal.as_bytes()) {
+            return Err(self.err("unexpected character"));
+        }
+        self.pos += literal.len();
+        Ok(value)
+    }
+
+    fn parse_number(&mut self) -> Result<Json, ParseError> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if let Some(b'.') | Some(b'e') | Some(b'E') = self.peek() {
+            return Err(self.err("fractional numbers are not supported"));
+        }
+
+        // The slice is all ASCII, so it is valid UTF-8
+        let digits = from_utf8(&self.input[start..self.pos]).unwrap();
+        let value = if digits.starts_with('-') {
+            digits.parse().map(Json::Int).ok()
+        } else {
+            digits.parse().map(Json::UInt).ok()
+        };
+        value.ok_or_else(|| self.err(&format!("invalid number '{}'", digits)))
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, ParseError> {
+        let hex = self.input.get(self.pos..self.pos + 4)
+            .and_then(|hex| from_utf8(hex).ok())
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| self.err("invalid \\u escape"))?;
+        self.pos += 4;
+        Ok(hex)
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParseError> {
+        self.expect(b'"')?;
+        let mut bytes = vec![];
+        loop {
+            let c = self.peek().ok_or_else(|| self.err("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = self.peek().ok_or_else(|| self.err("unterminated string"))?;
+                    self.pos += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.parse_hex4()?;
+                            // Characters outside the BMP are written as a UTF-16 surrogate pair
+                            if (0xd800..0xdc00).contains(&code) {
+                                if !self.input[self.pos..].starts_with(b"\\u") {
+                                    return Err(self.err("unpaired surrogate"));
+                                }
+                                self.pos += 2;
+                                let low = self.parse_hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(self.err("unpaired surrogate"));
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            std::char::from_u32(code).ok_or_else(|| self.err("unpaired surrogate"))?
+                        }
+                        _ => return Err(self.err("invalid escape")),
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                c if c < 0x20 => return Err(self.err("control character in string")),
+                c => bytes.push(c),
+            }
+        }
+        // We only split the (valid UTF-8) input at ASCII characters, so this can't fail
+        Ok(String::from_utf8(bytes).unwrap())
+    }
+
+    fn parse_array(&mut self, depth: usize) -> Result<Json, ParseError> {
+        self.expect(b'[')?;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.parse_value(depth + 1)?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(self.err("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn parse_object(&mut self, depth: usize) -> Result<Json, ParseError> {
+        self.expect(b'{')?;
+        let mut fields = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.expect(b':')?;
+            fields.push((key, self.parse_value(depth + 1)?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.err("expected ',' or '}'")),
+            }
+        }
+    }
+}
+
+// Info parsed from /proc/[pid]/status
+struct ProcStat {
+    status_file: String,
+    fields: HashMap<String, String>,
+}
+
+impl ProcStat {
+    // What is the stability of 'status' vs 'stat'? Is parsing this more likely to break? Overall,
+    // 'stat' seems better designed for parsing, _except_ ...
+    //
+    // The fields in /proc/[pid]/stat are separated by spaces. Unfortunately, the second field is
+    // the command, which can contain spaces. Without knowing what the command is beforehand, we
+    // can't parse this file reliably. We can read the command from /proc/[pid]/comm,
+    // so we know exactly what to expect, but that would be a pain.
+    //
+    fn read(pid: u64) -> Result<Self, Box<dyn Error>> {
+        ProcStat::read_file(proc_path!("{}/status", pid))
+    }
+
+    // Same as read(), but for an individual thread of the process
+    fn read_task(pid: u64, tid: u64) -> Result<Self, Box<dyn Error>> {
+        ProcStat::read_file(proc_path!("{}/task/{}/status", pid, tid))
+    }
+
+    fn read_file(status_file: String) -> Result<Self, Box<dyn Error>> {
+        // /proc/[pid]/status contains lines of the form
+        //
+        //    Name:   bash
+        //    Umask:  0022
+        //    State:  S (sleeping)
+        //    ...
+
+        let fields = BufReader::new(File::open(&status_file)?)
+            .lines()
+            .map(|s| {
+                let s: String = s?;
+                let substrs = s.splitn(2, ":").collect::<Vec<&str>>();
+                if substrs.len() < 2 {
+                    Err(ParseError::in_file(
+                        "status",
+                        &format!(
+                            "Fewer fields than expected in line '{}' of file {}",
+                            s, status_file
+                        ),
+                    ))?;
+                }
+                let key = substrs[0].to_string();
+                let value = substrs[1].trim().to_string();
+                Ok((key, value))
+            }).collect::<Result<HashMap<String, String>, Box<dyn Error>>>()?;
+
+        Ok(ProcStat {
+            status_file,
+            fields,
+        })
+    }
+
+    fn get_field(&self, field: &str) -> Result<&str, Box<dyn Error>> {
+        match self.fields.get(field) {
+            Some(val) => Ok(val),
+            None => Err(From::from(ParseError::in_file(
+                "status",
+                &format!(
+                    "Missing expected field '{}' in file {}",
+                    field,
+                    self.status_file
+                ),
+            ))),
+        }
+    }
+
+    fn ppid(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(self.get_field("PPid")?.parse()?)
+    }
+
+    // The pid of the process a thread is part of
+    fn tgid(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(self.get_field("Tgid")?.parse()?)
+    }
+
+    // The umask, which is only listed by Linux 4.7 and later
+    fn umask(&self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_str_radix(self.get_field("Umask")?, 8)?)
+    }
+}
+
+#[derive(Default)]
+struct ProcessTree {
+    child_map: HashMap<u64, Vec<u64>>, // Map of pid to pids of children
+    parent_map: HashMap<u64, u64>, // Map of pid to pid of parent
+    start_times: HashMap<u64, u64>, // Map of pid to start time, as in ProcessId
+}
+
+impl ProcessTree {
+    fn insert(&mut self, pid: u64, ppid: u64, starttime: Option<u64>) {
+        self.reparent(pid, ppid);
+        if let Some(starttime) = starttime {
+            self.start_times.insert(pid, starttime);
+        }
+    }
+
+    fn reparent(&mut self, pid: u64, ppid: u64) {
+        if let Some(old) = self.parent_map.insert(pid, ppid) {
+            if let Some(children) = self.child_map.get_mut(&old) {
+                children.retain(|&child| child != pid);
+            }
+        }
+        self.child_map.entry(ppid).or_default().push(pid);
+    }
+
+    fn children(&self, pid: u64) -> &[u64] {
+        self.child_map.get(&pid).map_or(&[], |children| &children[..])
+    }
+
+    // Whether a process listed as a child of another really is one. The processes are read one at
+    // a time, so if the parent exits while we do, its pid can be given to a new process before we
+    // get to it, making its old children look like children of the new one. A child can't have
+    // started before its parent, though, unless the parent is init, which never exits and adopts
+    // orphans whenever they started.
+    fn is_child(&self, pid: u64, child: u64) -> bool {
+        if pid == 1 {
+            return true;
+        }
+        match (self.start_times.get(&pid), self.start_times.get(&child)) {
+            (Some(start), Some(child_start)) => child_start >= start,
+            _ => true,
+        }
+    }
+
+    // The ancestors of a process, its parent first, up to but not including init. The chain also
+    // ends at a parent which isn't in the tree or isn't really the parent (see is_child()), and
+    // where it comes back round to a process already in it.
+    fn ancestors(&self, pid: u64) -> Vec<u64> {
+        let mut ancestors = vec![];
+        let mut seen = std::collections::HashSet::new();
+        seen.insert(pid);
+        let mut pid = pid;
+        while let Some(&ppid) = self.parent_map.get(&pid) {
+            if ppid <= 1 || !self.parent_map.contains_key(&ppid) || !self.is_child(ppid, pid) || !seen.insert(ppid) {
+                break;
+            }
+            ancestors.push(ppid);
+            pid = ppid;
+        }
+        ancestors
+    }
+
+    // Make every process reachable from the top of the tree. A process whose parent exited while
+    // we read the others may list a parent we didn't see, or one which got the parent's pid
+    // afterwards, and reused pids can even make processes look like each other's ancestors. The
+    // kernel gives the children of exited processes to init (or a subreaper, which we can't tell),
+    // so we do too, breaking cycles at the first process found in each.
+    fn adopt_orphans(&mut self) {
+        if !self.parent_map.contains_key(&1) {
+            return;
+        }
+        let mut orphans = self.parent_map.iter()
+            .filter(|&(&pid, &ppid)| pid != 1 && ppid != 0
+                    && (!self.parent_map.contains_key(&ppid) || !self.is_child(ppid, pid)))
+            .map(|(&pid, _)| pid)
+            .collect::<Vec<_>>();
+        orphans.sort();
+        for pid in orphans {
+            self.reparent(pid, 1);
+        }
+
+        let mut pids = self.parent_map.keys().cloned().collect::<Vec<_>>();
+        pids.sort();
+        let mut reached = std::collections::HashSet::new();
+        for &pid in pids.iter().filter(|pid| self.parent_map[pid] == 0) {
+            walk_tree(self, pid, &mut |pid, _| reached.insert(pid));
+        }
+        for pid in pids {
+            if reached.contains(&pid) {
+                continue;
+            }
+            // It is in a cycle, or below one. Its ancestors go round the cycle forever, so the
+            // first one they come back to is in it.
+            let mut seen = std::collections::HashSet::new();
+            let mut member = pid;
+            while seen.insert(member) {
+                member = match self.parent_map.get(&member) {
+                    Some(&ppid) => ppid,
+                    None => break,
+                };
+            }
+            self.reparent(member, 1);
+            walk_tree(self, member, &mut |pid, _| reached.insert(pid));
+        }
+    }
+}
+
+fn read_process_tree() -> Result<ProcessTree, Box<dyn Error>> {
+    // Loop over all the processes listed in /proc/, find the parent of each one, and build a map
+    // from parent to children. There doesn't seem to be a more efficient way of doing this
+    // reliably.
+    let mut stats = vec![];
+    for entry in fs::read_dir(proc_root())? {
+        let entry = entry?;
+        if let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u64>().ok()) {
+            match fs::read_to_string(proc_path!("{}/stat", pid)) {
+                Ok(stat) => stats.push((pid, stat)),
+                // Proc probably exited before we could read its stat
+                Err(_) => continue,
+            }
+        }
+    }
+    let mut tree = process_tree_from_stats(stats);
+    // A capture only has some of the processes, so those it lacks the parents of aren't orphans
+    if !is_capture() {
+        tree.adopt_orphans();
+    }
+    Ok(tree)
+}
+
+// Build the tree from the pids and /proc/[pid]/stat contents of processes
+fn process_tree_from_stats(stats: Vec<(u64, String)>) -> ProcessTree {
+    let mut tree = ProcessTree::default();
+    for (pid, stat) in stats {
+        let ppid = match stat_field(&stat, 4).and_then(|ppid| ppid.parse().ok()) {
+            Some(ppid) => ppid,
+            None => {
+                eprintln!("Unable to parse /proc/{}/stat", pid);
+                continue
+            }
+        };
+        tree.insert(pid, ppid, ProcessId::from_stat(pid, &stat).map(|id| id.starttime));
+    }
+    tree
+}
+
+// What walk_tree calls for each process in a subtree. depth is 0 for the process at the root.
+trait TreeVisitor {
+    // Called before the descendants of the process are visited. Returning false leaves out the
+    // whole subtree under it, and the call to post() for it.
+    fn pre(&mut self, pid: u64, depth: usize) -> bool;
+
+    // Called once all the descendants of the process have been visited
+    fn post(&mut self, _pid: u64, _depth: usize) {}
+}
+
+// Most walks only need to look at each process on the way down
+impl<F: FnMut(u64, usize) -> bool> TreeVisitor for F {
+    fn pre(&mut self, pid: u64, depth: usize) -> bool {
+        self(pid, depth)
+    }
+}
+
+// Visit a process and its descendants depth first, children in the order they were read, as ptree
+// prints them. Each process is visited at most once, even if pid reuse made the tree look like it
+// has a cycle, and processes which only look like children because their parent's pid was reused
+// are left out. The walk doesn't recurse, so deep trees can't overflow the stack.
+fn walk_tree(tree: &ProcessTree, root: u64, visitor: &mut dyn TreeVisitor) {
+    let mut visited = std::collections::HashSet::new();
+    visited.insert(root);
+    if !visitor.pre(root, 0) {
+        return;
+    }
+
+    // The processes on the way from the root to the current one, and how many of the children of
+    // each have been looked at so far
+    let mut path = vec![(root, 0)];
+    while let Some(&mut (pid, ref mut next)) = path.last_mut() {
+        let children = tree.children(pid);
+        if *next == children.len() {
+            path.pop();
+            visitor.post(pid, path.len());
+            continue;
+        }
+        let child = children[*next];
+        *next += 1;
+        if tree.is_child(pid, child) && visited.insert(child) && visitor.pre(child, path.len()) {
+            path.push((child, 0));
+        }
+    }
+}
+
+// A cgroup hierarchy, as listed in /proc/[pid]/cgroup
+enum CgroupHierarchy {
+    // The cgroup v2 hierarchy
+    Unified,
+    // The cgroup v1 hierarchy which the named controller (e.g. 'memory' or 'name=systemd') is
+    // attached to
+    Controller(String),
+}
+
+// Options controlling what ptree prints for each process
+struct PtreeOptions {
+    // Show which cgroup in this hierarchy each process belongs to
+    cgroup: Option<CgroupHierarchy>,
+    // Mark processes running setuid or setgid executables, or with capabilities their parent lacks
+    privileges: bool,
+    // Print totals after the tree
+    summary: bool,
+    // Show the values of these environment variables
+    env: Vec<String>,
+    // Group the processes by cgroup, in the hierarchy cgroup names, rather than by parent
+    by_cgroup: bool,
+    // Show what each process's stdin is
+    stdin: bool,
+    // Columns to show after each process' command, like ps -o
+    fields: Vec<PtreeField>,
+    // With fields, the lines of the tree being printed, and the process on each (if it is still
+    // running), held back until the width of the longest is known
+    lines: std::cell::RefCell<Vec<(String, Option<u64>)>>,
+    // In --watch mode, processes which exited recently
+    exited: HashMap<ProcessId, ExitedProcess>,
+    // What to show for each process instead of its pid and command
+    label: Option<PtreeLabel>,
+}
+
+// The columns ptree --fields can add
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PtreeField {
+    User,
+    Rss,
+    Cpu,
+    Start,
+    Cgroup,
+    Nlwp,
+}
+
+impl PtreeField {
+    fn parse(field: &str) -> Option<PtreeField> {
+        match field {
+            "user" => Some(PtreeField::User),
+            "rss" => Some(PtreeField::Rss),
+            "cpu" => Some(PtreeField::Cpu),
+            "start" => Some(PtreeField::Start),
+            "cgroup" => Some(PtreeField::Cgroup),
+            "nlwp" => Some(PtreeField::Nlwp),
+            _ => None,
+        }
+    }
+
+    // Its heading, as ps has it, and whether it is a number, to be right aligned
+    fn header(self) -> (&'static str, bool) {
+        match self {
+            PtreeField::User => ("USER", false),
+            PtreeField::Rss => ("RSS", true),
+            PtreeField::Cpu => ("%CPU", true),
+            PtreeField::Start => ("START", false),
+            PtreeField::Cgroup => ("CGROUP", false),
+            PtreeField::Nlwp => ("NLWP", true),
+        }
+    }
+
+    // %CPU is, as in ps, the CPU time the process has used over the time since it started, and
+    // RSS is in kB
+    fn value(self, pid: u64) -> String {
+        let status = || ProcStat::read(pid).ok();
+        let value = match self {
+            PtreeField::User => Some(process_user(pid)),
+            PtreeField::Rss => {
+                status().and_then(|s| Some(s.get_field("VmRSS").ok()?.trim_end_matches(" kB").to_string()))
+            }
+            PtreeField::Cpu => process_cpu_ticks(pid).zip(process_age(pid).ok()).map(|(ticks, age)| {
+                let secs = ticks as f64 / clock_ticks_per_sec() as f64;
+                format!("{:.1}", secs * 100.0 / age.as_secs_f64().max(0.01))
+            }),
+            PtreeField::Start => process_age(pid).ok().map(|age| time_str(std::time::SystemTime::now() - age)),
+            PtreeField::Cgroup => Some(cgroup_summary(pid, &CgroupHierarchy::Unified)),
+            PtreeField::Nlwp => status().and_then(|s| Some(s.get_field("Threads").ok()?.to_string())),
+        };
+        value.unwrap_or_else(|| "-".to_string())
+    }
+}
+
+// A piece of a ptree --label template
+#[derive(Debug, PartialEq)]
+enum LabelPart {
+    Text(String),
+    Pid,
+    Ppid,
+    Comm,
+    // The command line, as ptree shows it by default
+    Cmd,
+    Field(PtreeField),
+}
+
+// What ptree --label shows for each process, from a template such as '{pid} {user} {comm}', which
+// may use {pid}, {ppid}, {comm}, {cmd} and the names of the --fields columns. '{{' and '}}' are
+// literal braces.
+#[derive(Debug, PartialEq)]
+struct PtreeLabel(Vec<LabelPart>);
+
+impl PtreeLabel {
+    fn parse(template: &str) -> Result<PtreeLabel, String> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let (name, rest) = chars.as_str().split_once('}')
+                        .ok_or_else(|| format!("unclosed '{{' in label '{}'", template))?;
+                    let part = match name {
+                        "pid" => LabelPart::Pid,
+                        "ppid" => LabelPart::Ppid,
+                        "comm" => LabelPart::Comm,
+                        "cmd" => LabelPart::Cmd,
+                        _ => LabelPart::Field(PtreeField::parse(name).ok_or_else(|| {
+                            format!("unknown field '{{{}}}' in label, expected pid, ppid, comm, cmd, user, rss, cpu, \
+                                     start, cgroup or nlwp", name)
+                        })?),
+                    };
+                    if !text.is_empty() {
+                        parts.push(LabelPart::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(part);
+                    chars = rest.chars();
+                }
+                '}' => return Err(format!("unmatched '}}' in label '{}', use '}}}}' for a brace", template)),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(LabelPart::Text(text));
+        }
+        Ok(PtreeLabel(parts))
+    }
+
+    fn format(&self, pid: u64) -> String {
+        self.0.iter().map(|part| match part {
+            LabelPart::Text(text) => text.clone(),
+            LabelPart::Pid => pid.to_string(),
+            LabelPart::Ppid => ProcStat::read(pid).and_then(|stat| stat.ppid()).map_or("-".to_string(), |ppid| {
+                ppid.to_string()
+            }),
+            LabelPart::Comm => fs::read_to_string(proc_path!("{}/comm", pid))
+                .map_or("-".to_string(), |comm| comm.trim_end().to_string()),
+            LabelPart::Cmd => cmd_summary(pid).trim_end().to_string(),
+            LabelPart::Field(field) => field.value(pid),
+        }).collect()
+    }
+}
+
+// Lay out the lines of a tree with the fields of each process after it, each field in a column as
+// wide as its widest entry, under a line of headings
+fn ptree_field_lines(lines: &[(String, Option<Vec<String>>)], fields: &[PtreeField]) -> Vec<String> {
+    let headers = fields.iter().map(|field| field.header().0.to_string()).collect::<Vec<_>>();
+    // Lines of processes which have exited have no fields, so may stick out
+    let width = lines.iter().filter(|(_, values)| values.is_some()).map(|(line, _)| line.trim_end().chars().count())
+        .max().unwrap_or(0);
+    let mut widths = headers.iter().map(|header| header.len()).collect::<Vec<_>>();
+    for values in lines.iter().filter_map(|(_, values)| values.as_ref()) {
+        for (width, value) in widths.iter_mut().zip(values) {
+            *width = (*width).max(value.chars().count());
+        }
+    }
+    let row = |line: &str, values: &[String]| {
+        let columns = values.iter().zip(fields).zip(&widths).map(|((value, field), &width)| match field.header().1 {
+            true => format!("{:>1$}", value, width),
+            false => format!("{:<1$}", value, width),
+        });
+        let columns = columns.collect::<Vec<_>>().join("  ");
+        format!("{:<w$}  {}", line.trim_end(), columns, w = width).trim_end().to_string()
+    };
+    let blank = vec![String::new(); fields.len()];
+    std::iter::once(row("", &headers))
+        .chain(lines.iter().map(|(line, values)| row(line, values.as_ref().unwrap_or(&blank))))
+        .collect()
+}
+
+// Find the path of the cgroup in a hierarchy from the contents of /proc/[pid]/cgroup, which has
+// lines of the form
+//
+//    4:memory:/user.slice
+//    1:name=systemd:/user.slice/user-1000.slice/session-2.scope
+//    0::/user.slice/user-1000.slice/session-2.scope
+//
+// where the v2 hierarchy always has id 0 and no controllers.
+fn parse_cgroup(contents: &str, hierarchy: &CgroupHierarchy) -> Option<String> {
+    for line in contents.lines() {
+        let fields = line.splitn(3, ':').collect::<Vec<&str>>();
+        if fields.len() != 3 {
+            continue;
+        }
+        let matches = match *hierarchy {
+            CgroupHierarchy::Unified => fields[0] == "0" && fields[1].is_empty(),
+            CgroupHierarchy::Controller(ref name) => fields[1].split(',').any(|c| c == name),
+        };
+        if matches {
+            return Some(fields[2].to_string());
+        }
+    }
+    None
+}
+
+fn cgroup_summary(pid: u64, hierarchy: &CgroupHierarchy) -> String {
+    fs::read_to_string(proc_path!("{}/cgroup", pid)).ok()
+        .and_then(|contents| parse_cgroup(&contents, hierarchy))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+// The container a process is in, as worked out from the name of its cgroup
+#[derive(Debug, PartialEq)]
+struct Container {
+    // The runtime which made the cgroup, e.g. 'docker' or 'cri-containerd'
+    runtime: String,
+    id: String,
+    // The UID of the Kubernetes pod the container is part of
+    pod_uid: Option<String>,
+}
+
+// Runtimes name each container's cgroup after its id, which is 64 hex digits. With the systemd
+// cgroup driver it is a scope like 'docker-<id>.scope' or 'cri-containerd-<id>.scope', under a
+// slice like 'kubepods-besteffort-pod<uid>.slice' (with the dashes in the pod UID turned into
+// underscores) for Kubernetes. With the cgroupfs driver it is a directory named after the id, in
+// one named after the runtime or in '/kubepods/<class>/pod<uid>'.
+fn parse_container_cgroup(path: &str) -> Option<Container> {
+    let is_id = |s: &str| s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit());
+    let components = path.split('/').filter(|c| !c.is_empty()).collect::<Vec<_>>();
+    let pod_uid = components.iter().find_map(|c| {
+        let uid = match c.strip_suffix(".slice") {
+            Some(slice) => slice.rsplit('-').next()?.strip_prefix("pod")?.replace('_', "-"),
+            None if components.first() == Some(&"kubepods") => c.strip_prefix("pod")?.to_string(),
+            None => return None,
+        };
+        Some(uid).filter(|uid| !uid.is_empty())
+    });
+    // A process can be in a cgroup below the container's, e.g. systemd running in a container puts
+    // its services in their own
+    components.iter().enumerate().find_map(|(i, c)| {
+        let (runtime, id) = match c.strip_suffix(".scope").and_then(|scope| scope.rsplit_once('-')) {
+            Some((runtime, id)) => (runtime.to_string(), id),
+            None if i > 0 && pod_uid.is_some() => ("kubernetes".to_string(), *c),
+            None if i > 0 => (components[i - 1].to_string(), *c),
+            None => return None,
+        };
+        Some(Container { runtime, id: id.to_string(), pod_uid: pod_uid.clone() }).filter(|_| is_id(id))
+    })
+}
+
+fn read_container(pid: u64) -> Option<Container> {
+    let contents = fs::read_to_string(proc_path!("{}/cgroup", pid)).ok()?;
+    contents.lines().filter_map(|line| line.splitn(3, ':').nth(2)).find_map(parse_container_cgroup)
+}
+
+// What a container's runtime calls it and its pod, if we could find out
+#[derive(Debug, Default, PartialEq)]
+struct ContainerNames {
+    name: Option<String>,
+    pod: Option<String>,
+}
+
+// Find the names in a container's OCI runtime spec, which Kubernetes runtimes annotate with them,
+// or in Docker's own config
+fn parse_container_config(config: &Json) -> ContainerNames {
+    let string = |json: Option<&Json>| json.and_then(Json::as_str).map(|s| s.to_string());
+    let annotations = config.get("annotations");
+    let labels = config.get("Config").and_then(|c| c.get("Labels"));
+    let any = |keys: &[&str]| keys.iter().find_map(|key| {
+        string(annotations.and_then(|a| a.get(key))).or_else(|| string(labels.and_then(|l| l.get(key))))
+    });
+    ContainerNames {
+        name: any(&["io.kubernetes.cri.container-name", "io.kubernetes.container.name", "nerdctl/name"])
+            // Docker's names start with a slash
+            .or_else(|| string(config.get("Name")).map(|name| name.trim_start_matches('/').to_string())),
+        pod: any(&["io.kubernetes.cri.sandbox-name", "io.kubernetes.pod.name"]),
+    }
+}
+
+// Where each runtime keeps the config of its containers
+fn container_config_paths(container: &Container) -> Vec<PathBuf> {
+    let id = &container.id;
+    let mut paths = vec![
+        PathBuf::from(format!("/var/lib/docker/containers/{}/config.v2.json", id)),
+        PathBuf::from(format!("/run/containers/storage/overlay-containers/{}/userdata/config.json", id)),
+        PathBuf::from(format!("/var/lib/containers/storage/overlay-containers/{}/userdata/config.json", id)),
+    ];
+    // containerd keeps its containers in namespaces, e.g. 'k8s.io' or 'moby'
+    let tasks = "/run/containerd/io.containerd.runtime.v2.task";
+    if let Ok(namespaces) = fs::read_dir(tasks) {
+        paths.extend(namespaces.filter_map(|ns| Some(ns.ok()?.path().join(id).join("config.json"))));
+    }
+    paths
+}
+
+fn container_names(container: &Container) -> ContainerNames {
+    // The runtimes' state is on the live system
+    if is_capture() {
+        return ContainerNames::default();
+    }
+    container_config_paths(container).iter()
+        .filter_map(|path| Json::parse(&fs::read_to_string(path).ok()?).ok())
+        .map(|config| parse_container_config(&config))
+        .next()
+        .unwrap_or_default()
+}
+
+fn print_tree(pid_of_interest: u64, opts: &PtreeOptions) -> Result<(), Box<dyn Error>> {
+    let tree = read_process_tree()?;
+    let parent_map = &tree.parent_map;
+
+    let indent_level = if pid_of_interest == 1 {
+        0
+    } else {
+        if !parent_map.contains_key(&pid_of_interest) {
+            eprintln!("No such pid {}", pid_of_interest);
+            exit(1);
+        }
+        print_parents(&tree, pid_of_interest, opts)
+    };
+    walk_tree(&tree, pid_of_interest, &mut PtreePrinter { indent_level, opts });
+    if !opts.fields.is_empty() {
+        let lines = opts.lines.borrow_mut().drain(..)
+            .map(|(line, pid)| (line, pid.map(|pid| opts.fields.iter().map(|field| field.value(pid)).collect())))
+            .collect::<Vec<_>>();
+        for line in ptree_field_lines(&lines, &opts.fields) {
+            println!("{}", line);
+        }
+    }
+
+    if opts.summary {
+        let mut ancestors = tree.ancestors(pid_of_interest);
+        if pid_of_interest != 1 {
+            ancestors.push(1);
+        }
+        ancestors.reverse();
+        print_tree_summary(&tree_summary(&tree, ancestors, pid_of_interest));
+    }
+
+    Ok(())
+}
+
+// Arrange processes by cgroup for ptree --by-cgroup, given the path of each process' cgroup. Each
+// cgroup comes with the processes in it, in the order given, and after its parent, which is listed
+// even if it has no processes of its own. Cgroups are identified by the components of their paths,
+// so the root is empty.
+fn cgroup_tree(cgroups: &[(u64, String)]) -> Vec<(Vec<String>, Vec<u64>)> {
+    let mut tree = std::collections::BTreeMap::<Vec<String>, Vec<u64>>::new();
+    for (pid, path) in cgroups {
+        let components = path.split('/').filter(|c| !c.is_empty()).map(String::from).collect::<Vec<_>>();
+        for len in 0..components.len() {
+            tree.entry(components[..len].to_vec()).or_default();
+        }
+        tree.entry(components).or_default().push(*pid);
+    }
+    tree.into_iter().collect()
+}
+
+// Print the processes in the subtrees under some processes grouped by cgroup, like systemd-cgls:
+// each cgroup indented below its parent, and the processes in it below it, in the order ptree would
+// print them. Processes whose cgroup can't be read come last.
+fn print_cgroup_tree(roots: &[u64], opts: &PtreeOptions) -> Result<(), Box<dyn Error>> {
+    let tree = read_process_tree()?;
+    let hierarchy = opts.cgroup.as_ref().unwrap_or(&CgroupHierarchy::Unified);
+
+    let mut seen = std::collections::HashSet::new();
+    let mut cgroups = vec![];
+    let mut unknown = vec![];
+    for &root in roots {
+        if root != 1 && !tree.parent_map.contains_key(&root) {
+            eprintln!("No such pid {}", root);
+            exit(1);
+        }
+        for pid in subtree_pids(&tree, root).into_iter().filter(|&pid| seen.insert(pid)) {
+            match fs::read_to_string(proc_path!("{}/cgroup", pid)).ok()
+                .and_then(|contents| parse_cgroup(&contents, hierarchy)) {
+                Some(path) => cgroups.push((pid, path)),
+                None => unknown.push(pid),
+            }
+        }
+    }
+
+    for (cgroup, pids) in cgroup_tree(&cgroups) {
+        println!("{}{}", "  ".repeat(cgroup.len()), cgroup.last().map_or("/", |name| name.as_str()));
+        for pid in pids {
+            print_ptree_line(pid, cgroup.len() as u64 + 1, opts);
+        }
+    }
+    if !unknown.is_empty() {
+        println!("(unknown cgroup)");
+        for pid in unknown {
+            print_ptree_line(pid, 1, opts);
+        }
+    }
+    Ok(())
+}
+
+// What ptree --summary prints after a tree, for attaching to tickets
+#[derive(Debug, PartialEq)]
+struct TreeSummary {
+    // Every process in the tree, ancestors first
+    pids: Vec<u64>,
+    // How many levels the tree has, and the process at the bottom of the longest chain
+    depth: usize,
+    deepest: u64,
+    // The child of the process of interest with the most descendants, and how many processes are
+    // in its subtree
+    largest: Option<(u64, usize)>,
+}
+
+// The processes in the subtree under a process, in the order ptree prints them
+fn subtree_pids(tree: &ProcessTree, pid: u64) -> Vec<u64> {
+    let mut pids = vec![];
+    walk_tree(tree, pid, &mut |pid, _| {
+        pids.push(pid);
+        true
+    });
+    pids
+}
+
+// Some processes and all their descendants, each process once, parents before their children
+fn with_descendants(pids: &[u64]) -> Result<Vec<u64>, Box<dyn Error>> {
+    let tree = read_process_tree()?;
+    let mut seen = std::collections::HashSet::new();
+    let mut all = vec![];
+    for &pid in pids {
+        walk_tree(&tree, pid, &mut |pid, _| {
+            if seen.insert(pid) {
+                all.push(pid);
+            }
+            true
+        });
+    }
+    Ok(all)
+}
+
+// The number of levels of the subtree under a process, and the first process on the bottom level
+fn subtree_depth(tree: &ProcessTree, pid: u64) -> (usize, u64) {
+    let mut deepest = (1, pid);
+    walk_tree(tree, pid, &mut |pid, depth| {
+        if depth + 1 > deepest.0 {
+            deepest = (depth + 1, pid);
+        }
+        true
+    });
+    deepest
+}
+
+fn tree_summary(tree: &ProcessTree, ancestors: Vec<u64>, pid: u64) -> TreeSummary {
+    let (depth, deepest) = subtree_depth(tree, pid);
+    let mut largest = None;
+    for &child in tree.children(pid) {
+        let size = subtree_pids(tree, child).len();
+        if largest.is_none_or(|(_, largest)| size > largest) {
+            largest = Some((child, size));
+        }
+    }
+    TreeSummary { depth: ancestors.len() + depth, pids: [ancestors, subtree_pids(tree, pid)].concat(), deepest,
+                  largest }
+}
+
+fn print_tree_summary(summary: &TreeSummary) {
+    let threads = summary.pids.iter()
+        .filter_map(|&pid| ProcStat::read(pid).ok()?.get_field("Threads").ok()?.parse::<u64>().ok())
+        .sum::<u64>();
+    let mut users = summary.pids.iter().map(|&pid| process_user(pid)).collect::<Vec<_>>();
+    users.sort();
+    users.dedup();
+    let count = |count, one, many| format!("{} {}", count, if count == 1 { one } else { many });
+
+    println!();
+    println!("{}, {}, {}", count(summary.pids.len() as u64, "process", "processes"),
+             count(threads, "thread", "threads"), count(users.len() as u64, "user", "users"));
+    println!("deepest chain: {}, down to {}  {}", count(summary.depth as u64, "level", "levels"), summary.deepest,
+             cmd_summary(summary.deepest).trim_end());
+    if let Some((pid, size)) = summary.largest {
+        println!("largest subtree: {}, under {}  {}", count(size as u64, "process", "processes"), pid,
+                 cmd_summary(pid).trim_end());
+    }
+}
+
+// A process as ptree --count-only counts it
+struct CountedProcess {
+    pid: u64,
+    children: usize,
+    threads: u64,
+    zombie: bool,
+    user: String,
+    service: Option<String>,
+}
+
+impl CountedProcess {
+    fn read(tree: &ProcessTree, pid: u64) -> Option<Self> {
+        let status = ProcStat::read(pid).ok()?;
+        Some(CountedProcess {
+            pid,
+            children: tree.children(pid).len(),
+            threads: status.get_field("Threads").ok().and_then(|n| n.parse().ok()).unwrap_or(0),
+            zombie: status.get_field("State").is_ok_and(|state| state.starts_with('Z')),
+            user: process_user(pid),
+            service: process_service(pid),
+        })
+    }
+}
+
+// The counts ptree --count-only prints instead of a tree
+#[derive(Debug, PartialEq)]
+struct TreeCounts {
+    processes: u64,
+    threads: u64,
+    zombies: u64,
+    // Most processes first
+    users: Vec<(String, u64)>,
+    services: Vec<(String, u64)>,
+    // The process with the most children, and how many it has
+    widest: Option<(u64, usize)>,
+}
+
+fn tree_counts(processes: &[CountedProcess]) -> TreeCounts {
+    let tally = |keys: Vec<String>| {
+        let mut counts = HashMap::new();
+        for key in keys {
+            *counts.entry(key).or_insert(0) += 1;
+        }
+        let mut counts = counts.into_iter().collect::<Vec<(String, u64)>>();
+        counts.sort_by(|(a, m), (b, n)| n.cmp(m).then_with(|| a.cmp(b)));
+        counts
+    };
+    TreeCounts {
+        processes: processes.len() as u64,
+        threads: processes.iter().map(|p| p.threads).sum(),
+        zombies: processes.iter().filter(|p| p.zombie).count() as u64,
+        users: tally(processes.iter().map(|p| p.user.clone()).collect()),
+        services: tally(processes.iter().filter_map(|p| p.service.clone()).collect()),
+        widest: processes.iter().filter(|p| p.children > 0)
+            .fold(None, |widest: Option<&CountedProcess>, p| match widest {
+                Some(w) if w.children >= p.children => Some(w),
+                _ => Some(p),
+            })
+            .map(|p| (p.pid, p.children)),
+    }
+}
+
+fn print_tree_counts(counts: &TreeCounts, json: bool) {
+    let tallies = |tally: &[(String, u64)]| {
+        Json::object(tally.iter().map(|(key, n)| (key.as_str(), Json::UInt(*n))).collect())
+    };
+    if json {
+        println!("{}", Json::object(vec![
+            ("processes", Json::UInt(counts.processes)),
+            ("threads", Json::UInt(counts.threads)),
+            ("zombies", Json::UInt(counts.zombies)),
+            ("users", tallies(&counts.users)),
+            ("services", tallies(&counts.services)),
+            ("widest", counts.widest.map_or(Json::Null, |(pid, children)| Json::object(vec![
+                ("pid", Json::UInt(pid)),
+                ("children", Json::UInt(children as u64)),
+            ]))),
+        ]));
+        return;
+    }
+    println!("processes: {}", counts.processes);
+    println!("threads: {}", counts.threads);
+    println!("zombies: {}", counts.zombies);
+    if let Some((pid, children)) = counts.widest {
+        println!("widest: {} children of {}  {}", children, pid, cmd_summary(pid).trim_end());
+    }
+    for (user, n) in &counts.users {
+        println!("user {}: {}", user, n);
+    }
+    for (service, n) in &counts.services {
+        println!("service {}: {}", service, n);
+    }
+}
+
+// Print a summary of command line arguments on a single line.
+fn print_cmd_summary(pid: u64) {
+    println!("{}", cmd_summary(pid));
+}
+
+fn cmd_summary(pid: u64) -> String {
+    match File::open(proc_path!("{}/cmdline", pid)) {
+        Ok(file) => {
+            let mut summary = String::new();
+            for arg in BufReader::new(file).take(80).split(b'\0') {
+                match arg {
+                    Ok(arg) => {
+                        summary.push_str(&String::from_utf8_lossy(&arg));
+                        summary.push(' ');
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        break;
+                    }
+                }
+            }
+            summary
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            "<exited>".to_string()
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            "<error reading cmdline>".to_string()
+        }
+    }
+}
+
+// Print the ancestors of a process from the top down, and return the indentation level for it. Don't
+// bother printing pid 1: typically it didn't really start the process in question. A capture only
+// has the parents which were captured too.
+fn print_parents(tree: &ProcessTree, pid: u64, opts: &PtreeOptions) -> u64 {
+    let ancestors = tree.ancestors(pid);
+    for (indent_level, &ppid) in ancestors.iter().rev().enumerate() {
+        print_ptree_line(ppid, indent_level as u64, opts);
+    }
+    ancestors.len() as u64
+}
+
+// Prints the subtree under the process of interest, indented below its parents. Processes which
+// exited recently are shown after their parent's live children.
+struct PtreePrinter<'a> {
+    indent_level: u64,
+    opts: &'a PtreeOptions,
+}
+
+impl<'a> TreeVisitor for PtreePrinter<'a> {
+    fn pre(&mut self, pid: u64, depth: usize) -> bool {
+        print_ptree_line(pid, self.indent_level + depth as u64, self.opts);
+        true
+    }
+
+    fn post(&mut self, pid: u64, depth: usize) {
+        let mut exited = self.opts.exited.iter().filter(|(_, exited)| exited.ppid == pid).collect::<Vec<_>>();
+        exited.sort_by_key(|&(id, _)| *id);
+        let indent = "  ".repeat((self.indent_level + depth as u64) as usize + 1);
+        for (child, exited) in exited {
+            let line = format!("{}{}  {}{}", indent, child.pid, exited.summary, exited.annotation());
+            match self.opts.fields.is_empty() {
+                true => println!("{}", line),
+                false => self.opts.lines.borrow_mut().push((line, None)),
+            }
+        }
+    }
+}
+
+fn print_ptree_line(pid: u64, indent_level: u64, opts: &PtreeOptions) {
+    let mut line = "  ".repeat(indent_level as usize);
+    if let Some(ref label) = opts.label {
+        line += &label.format(pid);
+        match opts.fields.is_empty() {
+            true => println!("{}", line),
+            false => opts.lines.borrow_mut().push((line, Some(pid))),
+        }
+        return;
+    }
+    line += &format!("{}  ", pid);
+    // With --by-cgroup, the cgroup is the one the process is listed under
+    if let Some(hierarchy) = opts.cgroup.as_ref().filter(|_| !opts.by_cgroup) {
+        line += &format!("{}  ", cgroup_summary(pid, hierarchy));
+    }
+    if !opts.env.is_empty() {
+        line += &env_values_summary(pid, &opts.env);
+    }
+    if opts.privileges {
+        let privileges = read_privileges(pid);
+        let parent = ProcStat::read(pid).and_then(|stat| stat.ppid()).ok().and_then(read_privileges);
+        if let Some(annotation) = privileges.and_then(|p| privileges_annotation(&p, parent.as_ref())) {
+            line += &format!("[{}]  ", annotation);
+        }
+    }
+    if opts.stdin {
+        line += &format!("[stdin: {}]  ", stdin_summary(pid));
+    }
+    line += &cmd_summary(pid);
+    match opts.fields.is_empty() {
+        true => println!("{}", line),
+        false => opts.lines.borrow_mut().push((line, Some(pid))),
+    }
+}
+
+// What fd 0 of a process is, for ptree --stdin: a tty for something started interactively,
+// /dev/null for a daemon, or a socket for one started by inetd or systemd socket activation
+fn stdin_summary(pid: u64) -> String {
+    let link = match read_proc_link(proc_path!("{}/fd/0", pid)) {
+        Ok(link) => link.to_string_lossy().into_owned(),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound && Path::new(&proc_path!("{}", pid)).exists() => {
+            return "closed".to_string();
+        }
+        Err(_) => return "?".to_string(),
+    };
+    let socket = link.strip_prefix("socket:[").and_then(|inode| inode.trim_end_matches(']').parse::<u64>().ok())
+        .and_then(|inode| fetch_sock_info(pid).ok()?.remove(&inode));
+    stdin_kind(&link, socket.as_ref())
+}
+
+fn stdin_kind(link: &str, socket: Option<&SockInfo>) -> String {
+    let unspecified = |addr: &SocketAddr| addr.ip().is_unspecified() && addr.port() == 0;
+    if link.starts_with("socket:") {
+        match socket {
+            // inetd starts a process for each connection...
+            Some(SockInfo { peer_addr: Some(peer), .. }) if !unspecified(peer) => format!("socket from {}", peer),
+            // ...systemd hands one the socket to accept() connections on
+            Some(SockInfo { local_addr: Some(local), .. }) => format!("socket on {}", local),
+            Some(SockInfo { family: AddressFamily::Unix, .. }) => "unix socket".to_string(),
+            _ => "socket".to_string(),
+        }
+    } else if link.starts_with("pipe:") {
+        "pipe".to_string()
+    } else if link == "/dev/null" {
+        link.to_string()
+    } else if ["/dev/pts/", "/dev/tty", "/dev/console"].iter().any(|prefix| link.starts_with(prefix)) {
+        format!("tty {}", link)
+    } else {
+        format!("file {}", link)
+    }
+}
+
+// The values of some environment variables of a process for ptree --env, as 'NAME=value  ' for each
+// which is set. Processes whose environment we can't read show 'NAME=?'.
+fn env_values_summary(pid: u64, names: &[String]) -> String {
+    let env = read_nul_strings(&proc_path!("{}/environ", pid)).ok();
+    let mut summary = String::new();
+    for name in names {
+        match env {
+            // getenv() returns the first
+            Some(ref env) => if let Some(var) = env.iter().find(|var| env_name(var) == name) {
+                summary += &format!("{}  ", var);
+            },
+            None => summary += &format!("{}=?  ", name),
+        }
+    }
+    summary
+}
+
+// The names of the capabilities, by number (see include/uapi/linux/capability.h)
+const CAPABILITY_NAMES: &[&str] = &[
+    "cap_chown", "cap_dac_override", "cap_dac_read_search", "cap_fowner", "cap_fsetid", "cap_kill",
+    "cap_setgid", "cap_setuid", "cap_setpcap", "cap_linux_immutable", "cap_net_bind_service",
+    "cap_net_broadcast", "cap_net_admin", "cap_net_raw", "cap_ipc_lock", "cap_ipc_owner", "cap_sys_module",
+    "cap_sys_rawio", "cap_sys_chroot", "cap_sys_ptrace", "cap_sys_pacct", "cap_sys_admin", "cap_sys_boot",
+    "cap_sys_nice", "cap_sys_resource", "cap_sys_time", "cap_sys_tty_config", "cap_mknod", "cap_lease",
+    "cap_audit_write", "cap_audit_control", "cap_setfcap", "cap_mac_override", "cap_mac_admin", "cap_syslog",
+    "cap_wake_alarm", "cap_block_suspend", "cap_audit_read", "cap_perfmon", "cap_bpf", "cap_checkpoint_restore",
+];
+
+// List a capability set. Root's usually has every capability we know of, or all but a few which a
+// container runtime dropped, so those are listed by what they lack.
+fn capabilities_str(caps: u64) -> String {
+    let all = (1u64 << CAPABILITY_NAMES.len()) - 1;
+    let names = |caps: u64| (0..64).filter(|bit| caps & (1 << bit) != 0)
+        .map(|bit| CAPABILITY_NAMES.get(bit).map_or_else(|| format!("cap_{}", bit), |name| name.to_string()))
+        .collect::<Vec<_>>()
+        .join(",");
+    let missing = all & !caps;
+    if missing == 0 {
+        "all".to_string()
+    } else if missing.count_ones() < (caps & all).count_ones() {
+        format!("all except {}", names(missing))
+    } else {
+        names(caps)
+    }
+}
+
+// What makes a process privileged: the owners of its executable if it is setuid or setgid, and its
+// effective and ambient capabilities
+#[derive(Debug, PartialEq)]
+struct Privileges {
+    setuid: Option<libc::uid_t>,
+    setgid: Option<libc::gid_t>,
+    effective: u64,
+    ambient: u64,
+}
+
+fn read_privileges(pid: u64) -> Option<Privileges> {
+    use std::os::unix::fs::MetadataExt;
+    let status = ProcStat::read(pid).ok()?;
+    let caps = |field| status.get_field(field).ok().and_then(|caps| u64::from_str_radix(caps, 16).ok());
+    // A capture doesn't have the executable
+    let exe = if is_capture() { None } else { fs::metadata(proc_path!("{}/exe", pid)).ok() };
+    Some(Privileges {
+        setuid: exe.as_ref().filter(|exe| exe.mode() & libc::S_ISUID as u32 != 0).map(|exe| exe.uid()),
+        setgid: exe.as_ref().filter(|exe| exe.mode() & libc::S_ISGID as u32 != 0).map(|exe| exe.gid()),
+        effective: caps("CapEff")?,
+        ambient: caps("CapAmb").unwrap_or(0),
+    })
+}
+
+// Describe what is privileged about a process. Capabilities are only mentioned where they differ
+// from its parent's, so that the descendants of root daemons don't all stand out.
+fn privileges_annotation(privileges: &Privileges, parent: Option<&Privileges>) -> Option<String> {
+    let mut parts = vec![];
+    if let Some(uid) = privileges.setuid {
+        parts.push(format!("setuid {}", user_name(uid)));
+    }
+    if let Some(gid) = privileges.setgid {
+        parts.push(format!("setgid {}", group_name(gid)));
+    }
+    let differs = |caps: u64, parent_caps: fn(&Privileges) -> u64| caps != 0 && parent.map(parent_caps) != Some(caps);
+    if differs(privileges.effective, |p| p.effective) {
+        parts.push(format!("caps {}", capabilities_str(privileges.effective)));
+    }
+    if differs(privileges.ambient, |p| p.ambient) {
+        parts.push(format!("ambient caps {}", capabilities_str(privileges.ambient)));
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(", "))
+    }
+}
+
+// A process which exited since an earlier refresh of ptree --watch. It is shown where it used to be
+// for a few refreshes, so that processes don't just silently vanish from the tree.
+struct ExitedProcess {
+    ppid: u64,
+    // Its command line, from when it was still running
+    summary: String,
+    // How it exited, if the proc connector told us
+    status: Option<String>,
+    // When we noticed it had gone, which is at most a refresh after it exited
+    noticed: std::time::SystemTime,
+    refreshes_left: u32,
+}
+
+impl ExitedProcess {
+    fn annotation(&self) -> String {
+        let status = self.status.as_ref().map(|s| format!(": {}", s)).unwrap_or_default();
+        format!("[exited by {}{}]", time_str(self.noticed), status)
+    }
+}
+
+// How many refreshes exited processes stay in the tree for
+const EXITED_REFRESHES: u32 = 3;
+
+// Describe a wait status, as reported by wait(2) and the proc connector
+fn wait_status_str(status: u32) -> String {
+    let signo = (status & 0x7f) as i32;
+    if signo == 0 {
+        format!("exit status {}", (status >> 8) & 0xff)
+    } else if status & 0x80 != 0 {
+        format!("killed by {}, core dumped", signal_str(signo))
+    } else {
+        format!("killed by {}", signal_str(signo))
+    }
+}
+
+// The proc connector (see include/uapi/linux/cn_proc.h) sends a netlink message for every fork,
+// exec and exit on the system, which is the only way to find out how a process we aren't the parent
+// of exited. Listening needs CAP_NET_ADMIN.
+const NETLINK_CONNECTOR: i32 = 11;
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const PROC_EVENT_FORK: u32 = 0x0000_0001;
+const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+// struct nlmsghdr, then struct cn_msg, then struct proc_event
+const NLMSG_HDR_LEN: usize = 16;
+const CN_MSG_LEN: usize = 20;
+
+// What happened to a process (rather than a thread), according to the proc connector
+#[derive(Debug, PartialEq)]
+enum ProcEvent {
+    Fork { parent: u64, child: u64 },
+    Exec { pid: u64 },
+    Exit { pid: u64, status: u32 },
+}
+
+// Parse the fork, exec and exit events of processes in a message from the proc connector
+fn parse_proc_events(buf: &[u8]) -> Vec<ProcEvent> {
+    let mut events = vec![];
+    let mut offset = 0;
+    while offset + NLMSG_HDR_LEN <= buf.len() {
+        let len = read_u32(buf, offset) as usize;
+        if len < NLMSG_HDR_LEN || offset + len > buf.len() {
+            break;
+        }
+        // proc_event: what, cpu, timestamp and then for forks: parent pid and tgid, child pid and
+        // tgid; for execs: pid, tgid; for exits: pid, tgid, exit code, exit signal
+        let event = offset + NLMSG_HDR_LEN + CN_MSG_LEN;
+        if event + 32 <= offset + len {
+            let field = |i: usize| u64::from(read_u32(buf, event + 16 + 4 * i));
+            match read_u32(buf, event) {
+                PROC_EVENT_FORK if field(2) == field(3) => events.push(ProcEvent::Fork { parent: field(1), child: field(2) }),
+                PROC_EVENT_EXEC => events.push(ProcEvent::Exec { pid: field(1) }),
+                PROC_EVENT_EXIT if field(0) == field(1) => {
+                    events.push(ProcEvent::Exit { pid: field(0), status: field(2) as u32 });
+                }
+                _ => {}
+            }
+        }
+        // Messages are padded to 4 bytes
+        offset += (len + 3) & !3;
+    }
+    events
+}
+
+// Subscribe to events from the proc connector, and record those of processes in 'events' from a
+// background thread.
+fn listen_for_proc_events(events: Arc<Mutex<Vec<ProcEvent>>>) -> Result<(), Box<dyn Error>> {
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, NETLINK_CONNECTOR) };
+    nix::errno::Errno::result(fd)?;
+    // Close the socket if anything fails before the thread takes it over
+    let socket = unsafe { <File as std::os::unix::io::FromRawFd>::from_raw_fd(fd) };
+
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = CN_IDX_PROC;
+    let res = unsafe {
+        libc::bind(fd, &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                   std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+    };
+    nix::errno::Errno::result(res)?;
+
+    let mut msg = vec![];
+    let len = NLMSG_HDR_LEN + CN_MSG_LEN + 4;
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+    msg.extend_from_slice(&[0; 10]); // flags, seq, pid
+    msg.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+    msg.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+    msg.extend_from_slice(&[0; 8]); // seq, ack
+    msg.extend_from_slice(&4u16.to_ne_bytes());
+    msg.extend_from_slice(&[0; 2]); // flags
+    msg.extend_from_slice(&PROC_CN_MCAST_LISTEN.to_ne_bytes());
+    let res = unsafe { libc::send(fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+    nix::errno::Errno::result(res)?;
+
+    thread::spawn(move || {
+        use std::os::unix::io::AsRawFd;
+        let mut buf = [0u8; 4096];
+        loop {
+            let res = unsafe { libc::recv(socket.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            match nix::errno::Errno::result(res) {
+                Ok(len) => {
+                    events.lock().unwrap().extend(parse_proc_events(&buf[..len as usize]));
+                }
+                // ENOBUFS means we lost some events while busy, which is OK
+                Err(nix::Error::Sys(nix::errno::Errno::ENOBUFS)) | Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => {}
+                Err(_) => return,
+            }
+        }
+    });
+    Ok(())
+}
+
+// Exit statuses from the proc connector, by pid, which haven't been matched with a process yet. Each
+// is kept for a few refreshes, as it may arrive just before or after we notice that a process has
+// gone. A pid can be used by several processes in turn, so these are in the order they exited.
+type ExitStatuses = HashMap<u64, std::collections::VecDeque<(u32, u32)>>;
+
+// Give each exited process without a status the oldest unclaimed status for its pid. Processes
+// sharing a pid exited in the order they started, so the earlier one gets the earlier status.
+fn assign_exit_statuses(exited: &mut HashMap<ProcessId, ExitedProcess>, statuses: &mut ExitStatuses) {
+    let mut ids = exited.iter().filter(|(_, exited)| exited.status.is_none()).map(|(&id, _)| id).collect::<Vec<_>>();
+    ids.sort();
+    for id in ids {
+        if let Some((status, _)) = statuses.get_mut(&id.pid).and_then(|queue| queue.pop_front()) {
+            exited.get_mut(&id).unwrap().status = Some(wait_status_str(status));
+        }
+    }
+    statuses.retain(|_, queue| !queue.is_empty());
+}
+
+// Reprint the tree every 'interval', showing processes which exited since an earlier refresh, and
+// how they exited. Processes are told apart by ProcessId, so that one which exited isn't mistaken
+// for a new process which was given its pid.
+fn watch_tree(pids: &[u64], interval: Duration, opts: &mut PtreeOptions) -> ! {
+    catch_interrupts();
+    let proc_events = Arc::new(Mutex::new(vec![]));
+    if let Err(e) = listen_for_proc_events(proc_events.clone()) {
+        eprintln!("Unable to listen for process exits, so exit statuses won't be shown: {}", e);
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    let watched = pids.iter().map(|&pid| (pid, ProcessId::read(pid))).collect::<Vec<_>>();
+    let is_tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+    let mut statuses = ExitStatuses::new();
+    let mut previous: HashMap<ProcessId, (u64, String)> = HashMap::new();
+    loop {
+        let tree = match read_process_tree() {
+            Ok(tree) => tree,
+            Err(e) => {
+                eprintln!("Unable to read processes: {}", e);
+                exit(1);
+            }
+        };
+        let current = tree.parent_map.iter()
+            .filter_map(|(&pid, &ppid)| Some((ProcessId::read(pid)?, ppid)))
+            .collect::<HashMap<_, _>>();
+
+        for event in proc_events.lock().unwrap().drain(..) {
+            if let ProcEvent::Exit { pid, status } = event {
+                statuses.entry(pid).or_default().push_back((status, EXITED_REFRESHES + 1));
+            }
+        }
+        let noticed = std::time::SystemTime::now();
+        for (id, (ppid, summary)) in previous.drain() {
+            if !current.contains_key(&id) {
+                opts.exited.insert(id, ExitedProcess { ppid, summary, status: None, noticed,
+                                                       refreshes_left: EXITED_REFRESHES });
+            }
+        }
+        assign_exit_statuses(&mut opts.exited, &mut statuses);
+
+        if is_tty {
+            print!("\x1b[H\x1b[2J");
+        }
+        for &(pid, id) in &watched {
+            let alive = match id {
+                Some(id) => current.contains_key(&id),
+                None => tree.parent_map.contains_key(&pid),
+            };
+            if pid == 1 || alive {
+                print_tree(pid, opts).unwrap();
+            } else {
+                match id.and_then(|id| opts.exited.get(&id)) {
+                    Some(exited) => println!("{}  {} {}", pid, exited.summary, exited.annotation()),
+                    None => println!("{}  [exited]", pid),
+                }
+            }
+        }
+        if !is_tty {
+            println!();
+        }
+        let _ = std::io::stdout().flush();
+
+        opts.exited.retain(|_, exited| {
+            exited.refreshes_left -= 1;
+            exited.refreshes_left > 0
+        });
+        for queue in statuses.values_mut() {
+            queue.retain_mut(|&mut (_, ref mut left)| {
+                *left -= 1;
+                *left > 0
+            });
+        }
+        statuses.retain(|_, queue| !queue.is_empty());
+        for (&id, &ppid) in &current {
+            previous.insert(id, (ppid, cmd_summary(id.pid)));
+        }
+
+        sleep_unless_interrupted(interval);
+    }
+}
+
+// As defined by the file type bits of the st_mode field returned by stat
+#[derive(PartialEq)]
+enum PosixFileType {
+    Regular,
+    Directory,
+    Socket,
+    SymLink,
+    BlockDevice,
+    CharDevice,
+    Fifo,
+    Unknown(u32),
+}
+
+// As defined by contents of the symlink for the file descriptor in /proc/[pid]/fd/, which has the
+// form 'anon_inode:[eventpoll]' TODO better comment
+#[derive(PartialEq)]
+enum AnonFileType {
+    Epoll,
+    Inotify,
+    Fanotify,
+    Signalfd,
+    Pidfd,
+    Unknown(String),
+}
+
+#[derive(PartialEq)]
+enum FileType {
+    Posix(PosixFileType),
+    Anon(AnonFileType),
+    Unknown,
+}
+
+// Some common types of files have their type described by the st_mode returned by stat. For certain
+// types of files, though, st_mode is zero. In this case we can try to get more info from the text
+// in /proc/[pid]/fd/[fd]
+fn file_type(mode: u32, link_path: &Path) -> FileType {
+    let mode = mode & SFlag::S_IFMT.bits();
+    if mode != 0 {
+        let posix_file_type = match SFlag::from_bits_truncate(mode) {
+            SFlag::S_IFSOCK => PosixFileType::Socket,
+            SFlag::S_IFLNK => PosixFileType::SymLink,
+            SFlag::S_IFREG => PosixFileType::Regular,
+            SFlag::S_IFBLK => PosixFileType::BlockDevice,
+            SFlag::S_IFDIR => PosixFileType::Directory,
+            SFlag::S_IFCHR => PosixFileType::CharDevice,
+            SFlag::S_IFIFO => PosixFileType::Fifo,
+            _ => PosixFileType::Unknown(mode),
+        };
+        FileType::Posix(posix_file_type)
+    } else {
+        // Symlinks normally contain name of another file, but the contents of /proc/[pid]/fd/[fd]
+        // is in this case just text. fs::read_link converts this arbitrary text to a path, and then
+        // we convert it back to a String here. We are assuming this conversion is lossless.
+        let faux_path = match read_proc_link(link_path) {
+           Ok(faux_path) => faux_path,
+            Err(e) => {
+                eprintln!("Failed to read {:?}: {}", link_path, e);
+                return FileType::Unknown
+            }
+        };
+        let fd_info = match faux_path.to_str() {
+            Some(fd_info) => fd_info,
+            None => {
+                eprintln!("Failed to convert path to string: {:?}", faux_path);
+                return FileType::Unknown
+            }
+        };
+        // For anonymous inodes, this text has the format 'anon_inode:[<type>]' or
+        // 'anon_inode:<type>'.
+        if fd_info.starts_with("anon_inode:") {
+            let fd_type_str = fd_info.trim_start_matches("anon_inode:").trim_start_matches("[").trim_end_matches("]");
+            let anon_file_type = match fd_type_str {
+                "eventpoll" => AnonFileType::Epoll,
+                "inotify" => AnonFileType::Inotify,
+                "fanotify" => AnonFileType::Fanotify,
+                "signalfd" => AnonFileType::Signalfd,
+                "pidfd" => AnonFileType::Pidfd,
+                x => AnonFileType::Unknown(x.to_string()),
+            };
+            FileType::Anon(anon_file_type)
+        } else {
+            FileType::Unknown
+        }
+    }
+}
+
+fn print_file_type(file_type: &FileType) -> String {
+    match file_type {
+        // For now we print the Posix file types using the somewhat cryptic macro identifiers used
+        // by the st_mode field returned by stat to match what is printed on Solaris. However, given
+        // that we already have more file types than we do on Solaris (because of Linux specific
+        // things like epoll, for example), and given that these additional file types can't be
+        // printed using S_ names (since they don't exist for these file types, since they aren't
+        // understood by stat), we are printing names that are sort of inconsistent. Maybe we should
+        // just be consistent, print better names, and just break compatibility with Solaris pfiles.
+        FileType::Posix(PosixFileType::Regular) => "S_IFREG".into(),
+        FileType::Posix(PosixFileType::Directory) => "S_IFDIR".into(),
+        FileType::Posix(PosixFileType::Socket) => "S_IFSOCK".into(),
+        FileType::Posix(PosixFileType::SymLink) => "S_IFLNK".into(),
+        FileType::Posix(PosixFileType::BlockDevice) => "S_IFBLK".into(),
+        FileType::Posix(PosixFileType::CharDevice) => "S_IFCHR".into(),
+        FileType::Posix(PosixFileType::Fifo) => "S_IFIFO".into(),
+        FileType::Posix(PosixFileType::Unknown(x)) => format!("UNKNOWN_TYPE(mode={})", x),
+        FileType::Anon(AnonFileType::Epoll) => "anon_inode(epoll)".into(),
+        FileType::Anon(AnonFileType::Inotify) => "anon_inode(inotify)".into(),
+        FileType::Anon(AnonFileType::Fanotify) => "anon_inode(fanotify)".into(),
+        FileType::Anon(AnonFileType::Signalfd) => "anon_inode(signalfd)".into(),
+        FileType::Anon(AnonFileType::Pidfd) => "anon_inode(pidfd)".into(),
+        FileType::Anon(AnonFileType::Unknown(s)) => format!("anon_inode({})", s),
+        FileType::Unknown => "UNKNOWN_TYPE".into(),
+    }
+}
+
+fn open_flags_strs(flags: u64) -> Vec<String> {
+    let open_flags = vec![
+        (OFlag::O_APPEND, "O_APPEND"),
+        (OFlag::O_ASYNC, "O_ASYNC"),
+        (OFlag::O_CLOEXEC, "O_CLOEXEC"),
+        (OFlag::O_CREAT, "O_CREAT"),
+        (OFlag::O_DIRECT, "O_DIRECT"),
+        (OFlag::O_DIRECTORY, "O_DIRECTORY"),
+        (OFlag::O_DSYNC, "O_DSYNC"),
+        (OFlag::O_EXCL, "O_EXCL"),
+        (OFlag::O_LARGEFILE, "O_LARGEFILE"),
+        (OFlag::O_NOATIME, "O_NOATIME"),
+        (OFlag::O_NOCTTY, "O_NOCTTY"),
+        (OFlag::O_NOFOLLOW, "O_NOFOLLOW"),
+        (OFlag::O_NONBLOCK, "O_NONBLOCK"),
+        (OFlag::O_PATH, "O_PATH"),
+        (OFlag::O_SYNC, "O_SYNC"),
+        (OFlag::O_TMPFILE, "O_TMPFILE"),
+        (OFlag::O_TRUNC, "O_TRUNC"),
+    ];
+
+    let mut strs = vec![
+        match OFlag::from_bits_truncate(flags as i32 & OFlag::O_ACCMODE.bits()) {
+            OFlag::O_RDONLY => "O_RDONLY".to_string(),
+            OFlag::O_WRONLY => "O_WRONLY".to_string(),
+            OFlag::O_RDWR => "O_RDWR".to_string(),
+            _ => format!("Unexpected mode {:o}", flags),
+        }
+    ];
+
+    // O_LARGEFILE == 0. Should that get printed everywhere?
+    // probably yes, if we want to match illumos
+
+    // Some flags are made up of more than one bit (O_TMPFILE includes the bit for O_DIRECTORY, for
+    // instance), so check that all of the bits of a flag are set rather than any of them.
+    for &(flag, desc) in open_flags.iter() {
+        if (flags as i32 & flag.bits()) == flag.bits() && flag.bits() != 0 {
+            strs.push(desc.to_string());
+        }
+    }
+
+    // TODO why does illumos print close on exec separately?
+
+    strs
+}
+
+// Returns the file offset and the flags of an fd, from /proc/[pid]/fdinfo/[fd]
+fn get_pos_and_flags(pid: u64, fd: u64) -> Result<(u64, u64), Box<dyn Error>> {
+    let mut contents = String::new();
+    File::open(proc_path!("{}/fdinfo/{}", pid, fd))?.read_to_string(&mut contents)?;
+    let field = |name: &str| {
+        contents
+            .lines()
+            .find(|line| line.starts_with(name))
+            .map(|line| line[name.len()..].trim())
+            .ok_or_else(|| ParseError::in_file("fdinfo", &format!("missing '{}' field", name)))
+    };
+    let pos = field("pos:")?.parse()?;
+    let flags = u64::from_str_radix(field("flags:")?, 8)?;
+    Ok((pos, flags))
+}
+
+// A file watched by an epoll instance, as listed in /proc/[pid]/fdinfo/[epoll fd]
+#[derive(Debug, PartialEq)]
+struct EpollWatch {
+    // The fd the file was added with. Epoll tracks what it watches by open file description, so
+    // this fd may since have been closed, or reused for another file.
+    tfd: u64,
+    events: u32,
+    // The data returned to epoll_wait() with events for this file
+    data: u64,
+    // The inode of the watched file
+    ino: u64,
+}
+
+// Split a line of fdinfo made of 'name:value' fields, where the value may be padded, as in
+// 'tfd:        9 events:       19' or 'wd:3 ino:9e7e'
+fn fdinfo_line_fields(line: &str) -> HashMap<&str, &str> {
+    let mut fields = HashMap::new();
+    let mut tokens = line.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token.find(':') {
+            Some(i) if i + 1 == token.len() => {
+                if let Some(value) = tokens.next() {
+                    fields.insert(&token[..i], value);
+                }
+            }
+            Some(i) => {
+                fields.insert(&token[..i], &token[i + 1..]);
+            }
+            None => {}
+        }
+    }
+    fields
+}
+
+fn parse_epoll_fdinfo(contents: &str) -> Vec<EpollWatch> {
+    // Each watched file has a line like
+    // 'tfd:        9 events:       19 data:                9  pos:0 ino:2a sdev:e'
+    contents.lines().filter(|line| line.starts_with("tfd:")).filter_map(|line| {
+        let fields = fdinfo_line_fields(line);
+        let num = |name, radix| fields.get(name).and_then(|value| u64::from_str_radix(value, radix).ok());
+        Some(EpollWatch {
+            tfd: num("tfd", 10)?,
+            events: num("events", 16)? as u32,
+            data: num("data", 16)?,
+            ino: num("ino", 16).unwrap_or(0),
+        })
+    }).collect()
+}
+
+fn read_epoll_watches(pid: u64, fd: u64) -> Result<Vec<EpollWatch>, Box<dyn Error>> {
+    Ok(parse_epoll_fdinfo(&fs::read_to_string(proc_path!("{}/fdinfo/{}", pid, fd))?))
+}
+
+fn epoll_events_strs(events: u32) -> Vec<&'static str> {
+    let names = [
+        (libc::EPOLLIN, "EPOLLIN"),
+        (libc::EPOLLPRI, "EPOLLPRI"),
+        (libc::EPOLLOUT, "EPOLLOUT"),
+        (libc::EPOLLERR, "EPOLLERR"),
+        (libc::EPOLLHUP, "EPOLLHUP"),
+        (libc::EPOLLRDNORM, "EPOLLRDNORM"),
+        (libc::EPOLLRDBAND, "EPOLLRDBAND"),
+        (libc::EPOLLWRNORM, "EPOLLWRNORM"),
+        (libc::EPOLLWRBAND, "EPOLLWRBAND"),
+        (libc::EPOLLMSG, "EPOLLMSG"),
+        (libc::EPOLLRDHUP, "EPOLLRDHUP"),
+        (libc::EPOLLEXCLUSIVE, "EPOLLEXCLUSIVE"),
+        (libc::EPOLLONESHOT, "EPOLLONESHOT"),
+        (libc::EPOLLET, "EPOLLET"),
+    ];
+    names.iter().filter(|&&(bit, _)| events & bit as u32 != 0).map(|&(_, name)| name).collect()
+}
+
+// A watch of an inotify instance, as listed in /proc/[pid]/fdinfo/[inotify fd]
+#[derive(Debug, PartialEq)]
+struct InotifyWatch {
+    // The watch descriptor inotify_add_watch() returned
+    wd: i32,
+    // The watched inode, and the major and minor numbers of the filesystem it is on
+    ino: u64,
+    dev: (u64, u64),
+    mask: u32,
+    // The file handle of the inode, as from name_to_handle_at(), if the kernel could make one
+    handle: Option<(i32, Vec<u8>)>,
+    // Where the watched file is, as the process sees it, if we could find out
+    path: Option<String>,
+}
+
+fn parse_inotify_fdinfo(contents: &str) -> Vec<InotifyWatch> {
+    // Each watch has a line like 'inotify wd:1 ino:83 sdev:800013 mask:fce ignored_mask:0
+    // fhandle-bytes:8 fhandle-type:1 f_handle:8300000039a206fd'
+    contents.lines().filter(|line| line.starts_with("inotify ")).filter_map(|line| {
+        let fields = fdinfo_line_fields(line);
+        let hex = |name| fields.get(name).and_then(|value| u64::from_str_radix(value, 16).ok());
+        // The kernel's own dev_t, with a 20 bit minor number
+        let sdev = hex("sdev")?;
+        let handle = fields.get("f_handle").and_then(|bytes| {
+            let handle_type = fields.get("fhandle-type")?.parse::<i32>().ok()?;
+            let bytes = (0..bytes.len() / 2).map(|i| u8::from_str_radix(bytes.get(i * 2..i * 2 + 2)?, 16).ok())
+                .collect::<Option<Vec<_>>>()?;
+            Some((handle_type, bytes))
+        });
+        Some(InotifyWatch {
+            wd: fields.get("wd")?.parse().ok()?,
+            ino: hex("ino")?,
+            dev: (sdev >> 20, sdev & 0xf_ffff),
+            mask: hex("mask")? as u32,
+            handle,
+            path: None,
+        })
+    }).collect()
+}
+
+fn read_inotify_watches(pid: u64, fd: u64) -> Result<Vec<InotifyWatch>, Box<dyn Error>> {
+    Ok(parse_inotify_fdinfo(&fs::read_to_string(proc_path!("{}/fdinfo/{}", pid, fd))?))
+}
+
+// See include/uapi/linux/inotify.h
+fn inotify_mask_strs(mask: u32) -> Vec<&'static str> {
+    let names = [
+        (0x0000_0001, "IN_ACCESS"),
+        (0x0000_0002, "IN_MODIFY"),
+        (0x0000_0004, "IN_ATTRIB"),
+        (0x0000_0008, "IN_CLOSE_WRITE"),
+        (0x0000_0010, "IN_CLOSE_NOWRITE"),
+        (0x0000_0020, "IN_OPEN"),
+        (0x0000_0040, "IN_MOVED_FROM"),
+        (0x0000_0080, "IN_MOVED_TO"),
+        (0x0000_0100, "IN_CREATE"),
+        (0x0000_0200, "IN_DELETE"),
+        (0x0000_0400, "IN_DELETE_SELF"),
+        (0x0000_0800, "IN_MOVE_SELF"),
+        (0x0100_0000, "IN_ONLYDIR"),
+        (0x0200_0000, "IN_DONT_FOLLOW"),
+        (0x0400_0000, "IN_EXCL_UNLINK"),
+        (0x1000_0000, "IN_MASK_CREATE"),
+        (0x2000_0000, "IN_MASK_ADD"),
+        (0x8000_0000, "IN_ONESHOT"),
+    ];
+    names.iter().filter(|&&(bit, _)| mask & bit != 0).map(|&(_, name)| name).collect()
+}
+
+// open_by_handle_at(2) isn't in our libc crate, and has a different number on aarch64
+#[cfg(not(target_arch = "aarch64"))]
+const SYS_OPEN_BY_HANDLE_AT: libc::c_long = 304;
+#[cfg(target_arch = "aarch64")]
+const SYS_OPEN_BY_HANDLE_AT: libc::c_long = 265;
+
+// How many directory entries to look through for watched inodes on a filesystem, when the file
+// handles can't be opened
+const INOTIFY_SEARCH_LIMIT: usize = 100_000;
+
+// Find the paths, relative to 'dir', of the given inodes, which are on filesystem 'dev'. The
+// search is breadth-first, since watches are mostly on directories near the top (/etc, a spool
+// directory), and doesn't cross into other filesystems.
+fn find_inodes(dir: &Path, dev: u64, inodes: &[u64], limit: usize) -> HashMap<u64, String> {
+    use std::os::unix::fs::MetadataExt;
+    let mut found = HashMap::new();
+    if let Ok(meta) = fs::symlink_metadata(dir) {
+        if inodes.contains(&meta.ino()) {
+            found.insert(meta.ino(), String::new());
+        }
+    }
+    let mut queue = std::collections::VecDeque::new();
+    queue.push_back(String::new());
+    let mut seen = 0;
+    while let Some(rel) = queue.pop_front() {
+        let entries = match fs::read_dir(dir.join(rel.trim_start_matches('/'))) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            seen += 1;
+            if seen > limit || found.len() == inodes.len() {
+                return found;
+            }
+            let meta = match entry.metadata() {
+                Ok(meta) if meta.dev() == dev => meta,
+                _ => continue,
+            };
+            let path = format!("{}/{}", rel, entry.file_name().to_string_lossy());
+            if inodes.contains(&meta.ino()) {
+                found.entry(meta.ino()).or_insert_with(|| path.clone());
+            }
+            if meta.is_dir() {
+                queue.push_back(path);
+            }
+        }
+    }
+    found
+}
+
+// Open the watched inode from its file handle, and return its path relative to the directory
+// 'mount_fd' is open on. This needs CAP_DAC_READ_SEARCH, and the path is only known if the inode's
+// dentry is connected to the mount's, as it always is for directories.
+fn handle_path(mount_fd: &File, mount_dir: &str, handle: &(i32, Vec<u8>)) -> Option<String> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    // struct file_handle: the length and type of the handle, then the handle itself
+    let mut file_handle = (handle.1.len() as u32).to_ne_bytes().to_vec();
+    file_handle.extend_from_slice(&handle.0.to_ne_bytes());
+    file_handle.extend_from_slice(&handle.1);
+    let fd = unsafe { libc::syscall(SYS_OPEN_BY_HANDLE_AT, mount_fd.as_raw_fd(), file_handle.as_ptr(), libc::O_PATH) };
+    if fd < 0 {
+        return None;
+    }
+    let file = unsafe { File::from_raw_fd(fd as libc::c_int) };
+    let path = fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).ok()?;
+    strip_dir_prefix(&path.to_string_lossy(), mount_dir).map(|rest| rest.to_string())
+}
+
+// Work out the paths of the files the process' inotify fds watch, as the process sees them. The
+// watches only record inodes, so we look in the mounts of their filesystems, through the process'
+// root in case it is in another mount namespace.
+fn resolve_inotify_paths(pid: u64, watches: &mut [&mut InotifyWatch], mounts: &[MountInfo]) {
+    let mut devs = watches.iter().map(|watch| watch.dev).collect::<Vec<_>>();
+    devs.sort();
+    devs.dedup();
+    for (major, minor) in devs {
+        // Bind mounts of part of the filesystem show less of it than the mount of its root
+        let dev = format!("{}:{}", major, minor);
+        let mount = match mounts.iter().filter(|m| m.dev == dev).min_by_key(|m| m.root.len()) {
+            Some(mount) => mount,
+            None => continue,
+        };
+        let mount_dir = format!("/proc/{}/root{}", pid, mount.mount_point.trim_end_matches('/'));
+        let in_mount = |rel: &str| match format!("{}{}", mount.mount_point.trim_end_matches('/'), rel) {
+            ref path if path.is_empty() => "/".to_string(),
+            path => path,
+        };
+
+        let mount_fd = File::open(&mount_dir).ok();
+        let our_mount_dir = mount_fd.as_ref()
+            .and_then(|file| fs::read_link(format!("/proc/self/fd/{}", std::os::unix::io::AsRawFd::as_raw_fd(file))).ok())
+            .map(|path| path.to_string_lossy().into_owned());
+        let mut missing = vec![];
+        for watch in watches.iter_mut().filter(|watch| watch.dev == (major, minor)) {
+            let path = match (&mount_fd, &our_mount_dir, &watch.handle) {
+                (Some(file), Some(dir), Some(handle)) => handle_path(file, dir, handle),
+                _ => None,
+            };
+            match path {
+                Some(path) => watch.path = Some(in_mount(&path)),
+                None => missing.push(watch.ino),
+            }
+        }
+        if missing.is_empty() {
+            continue;
+        }
+
+        let dev_t = match fs::metadata(&mount_dir) {
+            Ok(meta) => std::os::unix::fs::MetadataExt::dev(&meta),
+            Err(_) => continue,
+        };
+        let found = find_inodes(Path::new(&mount_dir), dev_t, &missing, INOTIFY_SEARCH_LIMIT);
+        for watch in watches.iter_mut().filter(|watch| watch.dev == (major, minor) && watch.path.is_none()) {
+            watch.path = found.get(&watch.ino).map(|rel| in_mount(rel));
+        }
+    }
+}
+
+fn inotify_watch_str(watch: &InotifyWatch) -> String {
+    let target = match watch.path {
+        Some(ref path) => path.clone(),
+        None => format!("inode {} on {}:{}", watch.ino, watch.dev.0, watch.dev.1),
+    };
+    format!("watch {}: {} {}", watch.wd, target, inotify_mask_strs(watch.mask).join("|"))
+}
+
+// What a fanotify mark is on
+#[derive(Debug, PartialEq)]
+enum FanotifyObject {
+    // An inode, and the major and minor numbers of the filesystem it is on
+    Inode(u64, (u64, u64)),
+    // A mount, by its id in mountinfo
+    Mount(u64),
+    // A whole filesystem, by its major and minor numbers
+    Filesystem((u64, u64)),
+}
+
+#[derive(Debug, PartialEq)]
+struct FanotifyMark {
+    object: FanotifyObject,
+    mask: u32,
+    ignored_mask: u32,
+    // Where the marked mount or filesystem is mounted (or the inode's filesystem is), as the
+    // process sees it, if we could find out
+    mount_point: Option<String>,
+}
+
+// A fanotify group, as listed in /proc/[pid]/fdinfo/[fanotify fd]
+#[derive(Debug, PartialEq)]
+struct Fanotify {
+    // The flags and event_f_flags fanotify_init() was called with
+    flags: u32,
+    event_flags: u32,
+    marks: Vec<FanotifyMark>,
+}
+
+// Events which the kernel holds the accessing process back for, until the listener allows or
+// denies them
+const FAN_PERMISSION_EVENTS: u32 = 0x0001_0000 | 0x0002_0000 | 0x0004_0000 | 0x0010_0000;
+
+impl Fanotify {
+    // Whether any of the group's marks ask for permission events. Then files it marks can't be
+    // opened or read until the listener answers, so if it hangs, so does everything using them.
+    fn permission_events(&self) -> bool {
+        self.marks.iter().any(|mark| mark.mask & FAN_PERMISSION_EVENTS != 0)
+    }
+}
+
+fn parse_fanotify_fdinfo(contents: &str) -> Option<Fanotify> {
+    // The group has a line like 'fanotify flags:10 event-flags:0', and each mark one like
+    // 'fanotify mnt_id:27 mflags:0 mask:10000 ignored_mask:0', with 'ino:83 sdev:800013' for
+    // inodes or just 'sdev:800013' for filesystems instead of the mount id. Everything is in hex.
+    let mut lines = contents.lines().filter(|line| line.starts_with("fanotify ")).map(fdinfo_line_fields);
+    let group = lines.next()?;
+    let hex = |fields: &HashMap<&str, &str>, name| fields.get(name).and_then(|value| u64::from_str_radix(value, 16).ok());
+    let marks = lines.filter_map(|fields| {
+        // The kernel's own dev_t, with a 20 bit minor number
+        let dev = hex(&fields, "sdev").map(|sdev| (sdev >> 20, sdev & 0xf_ffff));
+        let object = match (hex(&fields, "ino"), hex(&fields, "mnt_id"), dev) {
+            (Some(ino), _, Some(dev)) => FanotifyObject::Inode(ino, dev),
+            (None, Some(mnt_id), _) => FanotifyObject::Mount(mnt_id),
+            (None, None, Some(dev)) => FanotifyObject::Filesystem(dev),
+            _ => return None,
+        };
+        Some(FanotifyMark {
+            object,
+            mask: hex(&fields, "mask")? as u32,
+            ignored_mask: hex(&fields, "ignored_mask").unwrap_or(0) as u32,
+            mount_point: None,
+        })
+    }).collect();
+    Some(Fanotify {
+        flags: hex(&group, "flags")? as u32,
+        event_flags: hex(&group, "event-flags").unwrap_or(0) as u32,
+        marks,
+    })
+}
+
+fn read_fanotify(pid: u64, fd: u64) -> Result<Fanotify, Box<dyn Error>> {
+    let fdinfo = fs::read_to_string(proc_path!("{}/fdinfo/{}", pid, fd))?;
+    parse_fanotify_fdinfo(&fdinfo).ok_or_else(|| From::from("no fanotify group in fdinfo"))
+}
+
+// Find where the objects of fanotify marks are mounted, in the process' mounts
+fn resolve_fanotify_mounts(fanotify: &mut Fanotify, mounts: &[MountInfo]) {
+    for mark in &mut fanotify.marks {
+        // Bind mounts of part of the filesystem show less of it than the mount of its root
+        let of_dev = |(major, minor)| {
+            let dev = format!("{}:{}", major, minor);
+            mounts.iter().filter(|m| m.dev == dev).min_by_key(|m| m.root.len())
+        };
+        let mount = match mark.object {
+            FanotifyObject::Mount(id) => mounts.iter().find(|m| m.id == id),
+            FanotifyObject::Inode(_, dev) | FanotifyObject::Filesystem(dev) => of_dev(dev),
+        };
+        mark.mount_point = mount.map(|m| m.mount_point.clone());
+    }
+}
+
+// See include/uapi/linux/fanotify.h. The notification class is a two bit field rather than a flag.
+fn fanotify_flags_strs(flags: u32) -> Vec<&'static str> {
+    let class = match flags & 0xc {
+        0x0 => "FAN_CLASS_NOTIF",
+        0x4 => "FAN_CLASS_CONTENT",
+        0x8 => "FAN_CLASS_PRE_CONTENT",
+        _ => "FAN_CLASS_UNKNOWN",
+    };
+    let names = [
+        (0x0001, "FAN_CLOEXEC"),
+        (0x0002, "FAN_NONBLOCK"),
+        (0x0010, "FAN_UNLIMITED_QUEUE"),
+        (0x0020, "FAN_UNLIMITED_MARKS"),
+        (0x0040, "FAN_ENABLE_AUDIT"),
+        (0x0080, "FAN_REPORT_PIDFD"),
+        (0x0100, "FAN_REPORT_TID"),
+        (0x0200, "FAN_REPORT_FID"),
+        (0x0400, "FAN_REPORT_DIR_FID"),
+        (0x0800, "FAN_REPORT_NAME"),
+        (0x1000, "FAN_REPORT_TARGET_FID"),
+        (0x2000, "FAN_REPORT_FD_ERROR"),
+        (0x4000, "FAN_REPORT_MNT"),
+    ];
+    let mut strs = vec![class];
+    strs.extend(names.iter().filter(|&&(bit, _)| flags & bit != 0).map(|&(_, name)| name));
+    strs
+}
+
+fn fanotify_mask_strs(mask: u32) -> Vec<&'static str> {
+    let names = [
+        (0x0000_0001, "FAN_ACCESS"),
+        (0x0000_0002, "FAN_MODIFY"),
+        (0x0000_0004, "FAN_ATTRIB"),
+        (0x0000_0008, "FAN_CLOSE_WRITE"),
+        (0x0000_0010, "FAN_CLOSE_NOWRITE"),
+        (0x0000_0020, "FAN_OPEN"),
+        (0x0000_0040, "FAN_MOVED_FROM"),
+        (0x0000_0080, "FAN_MOVED_TO"),
+        (0x0000_0100, "FAN_CREATE"),
+        (0x0000_0200, "FAN_DELETE"),
+        (0x0000_0400, "FAN_DELETE_SELF"),
+        (0x0000_0800, "FAN_MOVE_SELF"),
+        (0x0000_1000, "FAN_OPEN_EXEC"),
+        (0x0000_4000, "FAN_Q_OVERFLOW"),
+        (0x0000_8000, "FAN_FS_ERROR"),
+        (0x0001_0000, "FAN_OPEN_PERM"),
+        (0x0002_0000, "FAN_ACCESS_PERM"),
+        (0x0004_0000, "FAN_OPEN_EXEC_PERM"),
+        (0x0010_0000, "FAN_PRE_ACCESS"),
+        (0x0100_0000, "FAN_MNT_ATTACH"),
+        (0x0200_0000, "FAN_MNT_DETACH"),
+        (0x0800_0000, "FAN_EVENT_ON_CHILD"),
+        (0x1000_0000, "FAN_RENAME"),
+        (0x4000_0000, "FAN_ONDIR"),
+    ];
+    names.iter().filter(|&&(bit, _)| mask & bit != 0).map(|&(_, name)| name).collect()
+}
+
+fn fanotify_object_str(mark: &FanotifyMark) -> String {
+    let object = match mark.object {
+        FanotifyObject::Inode(ino, (major, minor)) => format!("inode {} on {}:{}", ino, major, minor),
+        FanotifyObject::Mount(id) => format!("mount {}", id),
+        FanotifyObject::Filesystem((major, minor)) => format!("filesystem {}:{}", major, minor),
+    };
+    match mark.mount_point {
+        Some(ref mount_point) => format!("{} ({})", object, mount_point),
+        None => object,
+    }
+}
+
+fn fanotify_mark_str(mark: &FanotifyMark) -> String {
+    let mut s = format!("mark on {}: {}", fanotify_object_str(mark), fanotify_mask_strs(mark.mask).join("|"));
+    if mark.ignored_mask != 0 {
+        s += &format!(" ignoring {}", fanotify_mask_strs(mark.ignored_mask).join("|"));
+    }
+    s
+}
+
+// The value of a 'Name:\tvalue' line of fdinfo
+fn fdinfo_field(fdinfo: &str, name: &str) -> Option<String> {
+    fdinfo.lines()
+        .find(|line| line.split(':').next() == Some(name))
+        .map(|line| line[name.len() + 1..].trim().to_string())
+}
+
+// Name the signals in a mask like those of signalfds and /proc/[pid]/status, where bit N-1 is for
+// signal N. glibc keeps the first two real-time signals for itself, so SIGRTMIN is 34.
+fn signal_mask_names(mask: u64) -> Vec<String> {
+    (1..=64).filter(|signo| mask & (1 << (signo - 1)) != 0).map(|signo| match Signal::from_c_int(signo) {
+        Ok(signal) => format!("{:?}", signal),
+        Err(_) if signo >= 34 => format!("SIGRTMIN+{}", signo - 34),
+        Err(_) => format!("SIG{}", signo),
+    }).collect()
+}
+
+// The process a pidfd refers to
+#[derive(Debug, PartialEq)]
+enum PidfdTarget {
+    Running(u64),
+    // Exited, but not waited for by its parent yet
+    Zombie(u64),
+    // The kernel shows a pid of -1 once the process has been reaped
+    Exited,
+    // In a pid namespace we can't see, since it is outside ours
+    Hidden,
+    // In a capture, we can only say which process it was
+    Captured(u64),
+}
+
+impl PidfdTarget {
+    fn state(&self) -> &'static str {
+        match self {
+            PidfdTarget::Running(_) => "running",
+            PidfdTarget::Zombie(_) => "zombie",
+            PidfdTarget::Exited => "exited",
+            PidfdTarget::Hidden => "hidden",
+            PidfdTarget::Captured(_) => "unknown",
+        }
+    }
+}
+
+fn pidfd_target(pid: i64) -> PidfdTarget {
+    match pid {
+        pid if pid < 0 => PidfdTarget::Exited,
+        0 => PidfdTarget::Hidden,
+        _ if is_capture() => PidfdTarget::Captured(pid as u64),
+        _ => match fs::read_to_string(format!("/proc/{}/stat", pid)).ok()
+            .and_then(|stat| stat_field(&stat, 3).map(String::from)) {
+            Some(ref state) if state == "Z" => PidfdTarget::Zombie(pid as u64),
+            Some(_) => PidfdTarget::Running(pid as u64),
+            // It is still reported until it is reaped, so this is a race with it going
+            None => PidfdTarget::Exited,
+        },
+    }
+}
+
+fn pidfd_target_str(pid: i64) -> String {
+    match pidfd_target(pid) {
+        PidfdTarget::Running(pid) => format!("pid {} (running): {}", pid, cmd_summary(pid).trim_end()),
+        PidfdTarget::Zombie(pid) => format!("pid {} (exited, but not yet waited for)", pid),
+        PidfdTarget::Exited => "a process which has exited".to_string(),
+        PidfdTarget::Hidden => "a process outside our pid namespace".to_string(),
+        PidfdTarget::Captured(pid) => format!("pid {}", pid),
+    }
+}
+
+// What sysfs tells us about the device a block or character device fd refers to
+#[derive(Debug, Default, PartialEq)]
+struct DeviceInfo {
+    // The name of the device, as under /dev (e.g. 'nvme0n1' or 'dri/renderD128')
+    name: Option<String>,
+    // Further details for the kinds of device we know about
+    details: Vec<String>,
+}
+
+fn read_sysfs_str(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string()).filter(|s| !s.is_empty())
+}
+
+// Look up a device in sysfs, which is mounted at 'sysfs' (only tests don't use /sys). 'fdinfo' is
+// the contents of /proc/[pid]/fdinfo/[fd], which DRM drivers add usage statistics to.
+fn device_info(sysfs: &Path, block: bool, rdev: u64, fdinfo: &str) -> DeviceInfo {
+    let dev_dir = sysfs.join(format!("dev/{}/{}:{}", if block { "block" } else { "char" }, major(rdev), minor(rdev)));
+    // DEVNAME is the path under /dev, which is more useful than the sysfs name for e.g. DRM nodes
+    let name = read_sysfs_str(&dev_dir.join("uevent"))
+        .and_then(|uevent| uevent.lines().find(|l| l.starts_with("DEVNAME=")).map(|l| l[8..].to_string()))
+        .or_else(|| fs::read_link(&dev_dir).ok()
+                 .and_then(|target| target.file_name().map(|name| name.to_string_lossy().into_owned())));
+    let mut info = DeviceInfo { name, details: vec![] };
+    let name = match info.name {
+        Some(ref name) => name.clone(),
+        None => return info,
+    };
+
+    if block {
+        if let Some(backing) = read_sysfs_str(&dev_dir.join("loop/backing_file")) {
+            info.details.push(format!("loop device backed by {}", backing));
+        }
+        if let Some(dm_name) = read_sysfs_str(&dev_dir.join("dm/name")) {
+            let slaves = fs::read_dir(dev_dir.join("slaves")).map(|entries| {
+                let mut slaves = entries.filter_map(|e| Some(e.ok()?.file_name().to_string_lossy().into_owned()))
+                    .collect::<Vec<_>>();
+                slaves.sort();
+                slaves
+            }).unwrap_or_default();
+            let on = if slaves.is_empty() { String::new() } else { format!(" on {}", slaves.join(", ")) };
+            info.details.push(format!("device mapper device {}{}", dm_name, on));
+        }
+        if name.starts_with("nbd") {
+            match read_sysfs_str(&dev_dir.join("pid")) {
+                Some(pid) => info.details.push(format!("nbd device served by pid {}", pid)),
+                None => info.details.push("nbd device, not connected".to_string()),
+            }
+        }
+    } else if name.starts_with("dri/") {
+        let node = if name.starts_with("dri/renderD") { "render node" } else { "primary node" };
+        let driver = fs::read_link(dev_dir.join("device/driver")).ok()
+            .and_then(|driver| driver.file_name().map(|name| name.to_string_lossy().into_owned()));
+        info.details.push(format!("DRM {}{}", node, driver.map(|d| format!(", driver {}", d)).unwrap_or_default()));
+        if let Some(usage) = drm_usage(fdinfo) {
+            info.details.push(usage);
+        }
+    }
+
+    info
+}
+
+// Summarize the usage statistics which DRM drivers put in fdinfo (see
+// Documentation/gpu/drm-usage-stats.rst): time spent on each engine, and memory used.
+fn drm_usage(fdinfo: &str) -> Option<String> {
+    let mut client = None;
+    let mut engines = vec![];
+    let mut memory = vec![];
+    for line in fdinfo.lines() {
+        let mut parts = line.splitn(2, ':');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim()),
+            _ => continue,
+        };
+        if key == "drm-client-id" {
+            client = Some(value.to_string());
+        } else if key.starts_with("drm-engine-") && !key.starts_with("drm-engine-capacity-") {
+            if let Some(ns) = value.strip_suffix(" ns").and_then(|ns| ns.parse::<u64>().ok()) {
+                engines.push(format!("{} {}ms", &key[11..], ns / 1_000_000));
+            }
+        } else if key.starts_with("drm-total-") || key.starts_with("drm-memory-") {
+            let region = key.trim_start_matches("drm-total-").trim_start_matches("drm-memory-");
+            memory.push(format!("{} {}", region, value));
+        }
+    }
+
+    let mut parts = vec![];
+    if let Some(client) = client {
+        parts.push(format!("client {}", client));
+    }
+    if !engines.is_empty() {
+        parts.push(format!("busy: {}", engines.join(", ")));
+    }
+    if !memory.is_empty() {
+        parts.push(format!("memory: {}", memory.join(", ")));
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("; "))
+    }
+}
+
+// Everything we know about a single file descriptor of a process, independent of how it ends up
+// being displayed.
+struct FileInfo {
+    fd: u64,
+    file_type: FileType,
+    stat: FileStat,
+    pos: u64,
+    flags: u64,
+    // The target of the /proc/[pid]/fd/[fd] symlink
+    link: Option<String>,
+    // For epoll fds, the files they watch
+    epoll: Vec<EpollWatch>,
+    // For inotify fds, the files they watch
+    inotify: Vec<InotifyWatch>,
+    // For fanotify fds, the group and what it marks
+    fanotify: Option<Fanotify>,
+    // For signalfds, the mask of signals they accept
+    signalfd: Option<u64>,
+    // For pidfds, the pid of the process they refer to, as /proc/[pid]/fdinfo gives it
+    pidfd: Option<i64>,
+    // For UNIX sockets, how many fds sent over them with SCM_RIGHTS are queued, not yet received.
+    // Only kernels from 5.8 on say.
+    scm_fds: Option<u64>,
+    // For block and character device fds
+    device: Option<DeviceInfo>,
+    // For files on overlay filesystems, with --overlay
+    overlay: Option<OverlayOrigin>,
+    // For files in a project, on filesystems with project quotas
+    project: Option<ProjectQuota>,
+    // With --age
+    age: Option<FdAge>,
+    // For processes in another user namespace, the file's owner and group as the process sees them
+    owner_inside: Option<(String, String)>,
+    // With --acl, for regular files and directories
+    acl: Option<FileAcl>,
+    // For fds referring to a namespace, or to a socket which isn't in the process' network
+    // namespace, which namespace that is
+    namespace: Option<String>,
+    // With --enter-ns, what such a socket is, from the tables of its own network namespace
+    foreign_socket: Option<SockInfo>,
+    // With --hash, for small regular files
+    hash: Option<FileHash>,
+    // With --join-ns, what its path (or for a UNIX socket, the path it's bound to) names for the
+    // process
+    ns_path: Option<NsPath>,
+}
+
+// How long an fd has been open, as far as can be told. Nothing records when an fd was opened, so
+// it is bounded from both sides. It can't be older than the process, or than the socket it refers
+// to, since sockets' inodes are timestamped when they are made. /proc/[pid]/fd/[fd] gets its inode
+// the first time the fd is listed or looked up, so the fd has been open at least since then,
+// unless it was closed and the number reused without anyone looking in between.
+#[derive(Debug, PartialEq)]
+struct FdAge {
+    at_least: Option<Duration>,
+    at_most: Option<Duration>,
+    // Whether at_most is the age of the socket, rather than of the process
+    socket: bool,
+}
+
+// The ages are of the fd's /proc inode, if it was made before we started looking, of the process
+// and of the socket the fd refers to
+fn fd_age(listed: Option<Duration>, process: Option<Duration>, socket: Option<Duration>) -> FdAge {
+    let socket_limit = socket.is_some() && (process.is_none() || socket < process);
+    let at_most = if socket_limit { socket } else { process };
+    // An inode older than the fd itself means that the number was reused
+    let at_least = listed.filter(|&listed| at_most.is_none_or(|at_most| listed <= at_most + Duration::from_secs(1)));
+    FdAge { at_least, at_most, socket: socket_limit }
+}
+
+// With now, also say when the fd was opened, as times
+fn fd_age_str(age: &FdAge, now: Option<std::time::SystemTime>) -> Option<String> {
+    let when = |age: Duration, what: &str| now.and_then(|now| now.checked_sub(age))
+        .map(|time| format!("opened {} {}", what, time_str(time)));
+    let mut parts = vec![];
+    if let Some(at_least) = age.at_least {
+        match when(at_least, "before") {
+            Some(before) => parts.push(format!("at least {} ({})", age_str(at_least), before)),
+            None => parts.push(format!("at least {}", age_str(at_least))),
+        }
+    }
+    if let Some(at_most) = age.at_most {
+        let what = if age.socket { "the socket's age" } else { "the process' age" };
+        match when(at_most, "after") {
+            Some(after) => parts.push(format!("at most {} ({}; {})", age_str(at_most), what, after)),
+            None => parts.push(format!("at most {} ({})", age_str(at_most), what)),
+        }
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(format!("open for {}", parts.join(", ")))
+    }
+}
+
+// How long ago a file's inode was changed. Some kernels leave sockets' inode times at 0.
+fn inode_age(st: &FileStat, now: std::time::SystemTime) -> Option<Duration> {
+    if st.st_ctime <= 0 {
+        return None;
+    }
+    let ctime = std::time::UNIX_EPOCH + Duration::new(st.st_ctime as u64, st.st_ctime_nsec as u32);
+    now.duration_since(ctime).ok()
+}
+
+fn read_fd_age(pid: u64, info: &FileInfo, process: Option<Duration>, looked: std::time::SystemTime) -> FdAge {
+    let now = std::time::SystemTime::now();
+    // Allow for the coarse clock inode times come from
+    let listed = nix::sys::stat::lstat(proc_path!("{}/fd/{}", pid, info.fd).as_str()).ok()
+        .and_then(|st| inode_age(&st, now))
+        .filter(|&age| age > now.duration_since(looked).unwrap_or_default() + Duration::from_secs(1));
+    let socket = match info.file_type {
+        FileType::Posix(PosixFileType::Socket) => inode_age(&info.stat, now),
+        _ => None,
+    };
+    fd_age(listed, process, socket)
+}
+
+fn file_info(pid: u64, fd: u64) -> Option<FileInfo> {
+    let link_path_str = proc_path!("{}/fd/{}", pid, fd);
+    let link_path = Path::new(&link_path_str);
+    let stat_info = match fd_stat(pid, fd) {
+        Err(e) => {
+            eprintln!("failed to stat {}: {}", &link_path_str, e);
+            return None;
+        },
+        Ok(stat_info) => stat_info,
+    };
+
+    let (pos, flags) = match get_pos_and_flags(pid, fd) {
+        Ok(pos_and_flags) => pos_and_flags,
+        Err(e) => {
+            eprintln!("failed to read flags for /proc/{}/fdinfo/{}: {}", pid, fd, e);
+            return None;
+        }
+    };
+
+    let link = match read_proc_link(link_path) {
+        Ok(path) => Some(path.to_string_lossy().into_owned()),
+        Err(e) => {
+            eprintln!("failed to readlink {}: {}", &link_path_str, e);
+            None
+        }
+    };
+
+    let file_type = file_type(stat_info.st_mode, link_path);
+    let epoll = match file_type {
+        FileType::Anon(AnonFileType::Epoll) => read_epoll_watches(pid, fd).unwrap_or_else(|e| {
+            eprintln!("failed to read epoll info for /proc/{}/fdinfo/{}: {}", pid, fd, e);
+            vec![]
+        }),
+        _ => vec![],
+    };
+    let inotify = match file_type {
+        FileType::Anon(AnonFileType::Inotify) => read_inotify_watches(pid, fd).unwrap_or_else(|e| {
+            eprintln!("failed to read inotify info for /proc/{}/fdinfo/{}: {}", pid, fd, e);
+            vec![]
+        }),
+        _ => vec![],
+    };
+    let fanotify = match file_type {
+        FileType::Anon(AnonFileType::Fanotify) => read_fanotify(pid, fd).map_err(|e| {
+            eprintln!("failed to read fanotify info for /proc/{}/fdinfo/{}: {}", pid, fd, e);
+        }).ok(),
+        _ => None,
+    };
+
+    let fdinfo = || fs::read_to_string(proc_path!("{}/fdinfo/{}", pid, fd)).unwrap_or_default();
+    let signalfd = match file_type {
+        FileType::Anon(AnonFileType::Signalfd) => {
+            fdinfo_field(&fdinfo(), "sigmask").and_then(|mask| u64::from_str_radix(&mask, 16).ok())
+        }
+        _ => None,
+    };
+    let pidfd = match file_type {
+        FileType::Anon(AnonFileType::Pidfd) => fdinfo_field(&fdinfo(), "Pid").and_then(|pid| pid.parse().ok()),
+        _ => None,
+    };
+    let scm_fds = match file_type {
+        FileType::Posix(PosixFileType::Socket) => fdinfo_field(&fdinfo(), "scm_fds").and_then(|n| n.parse().ok()),
+        _ => None,
+    };
+
+    let device = match file_type {
+        // Device numbers in a capture may mean something else in our sysfs
+        _ if is_capture() => None,
+        FileType::Posix(PosixFileType::BlockDevice) | FileType::Posix(PosixFileType::CharDevice) => {
+            let fdinfo = fs::read_to_string(proc_path!("{}/fdinfo/{}", pid, fd)).unwrap_or_default();
+            let block = file_type == FileType::Posix(PosixFileType::BlockDevice);
+            Some(device_info(Path::new("/sys"), block, stat_info.st_rdev, &fdinfo))
+        }
+        _ => None,
+    };
+
+    Some(FileInfo {
+        fd,
+        file_type,
+        stat: stat_info,
+        pos,
+        flags,
+        link,
+        epoll,
+        inotify,
+        fanotify,
+        signalfd,
+        pidfd,
+        scm_fds,
+        device,
+        overlay: None,
+        project: None,
+        age: None,
+        owner_inside: None,
+        acl: None,
+        namespace: None,
+        foreign_socket: None,
+        hash: None,
+        ns_path: None,
+    })
+}
+
+fn print_file(info: &FileInfo, sockets: &HashMap<u64, SockInfo>) {
+    let stat_info = &info.stat;
+
+    print!(
+        " {: >4}: {} mode:{:o} dev:{},{} ino:{} uid:{} gid:{}",
+        info.fd,
+        print_file_type(&info.file_type),
+        stat_info.st_mode & 0o7777,
+        major(stat_info.st_dev),
+        minor(stat_info.st_dev),
+        stat_info.st_ino,
+        stat_info.st_uid,
+        stat_info.st_gid
+    );
+
+    let rdev_major = major(stat_info.st_rdev);
+    let rdev_minor = minor(stat_info.st_rdev);
+    let owner_inside = info.owner_inside.as_ref()
+        .map(|(uid, gid)| format!(" ns-uid:{} ns-gid:{}", uid, gid))
+        .unwrap_or_default();
+    if rdev_major == 0 && rdev_minor == 0 {
+        println!(" size:{}{}", stat_info.st_size, owner_inside)
+    } else {
+        println!(" rdev:{},{}{}", rdev_major, rdev_minor, owner_inside);
+    }
+
+    println!("       {}", open_flags_strs(info.flags).join("|"));
+
+    match info.file_type {
+        FileType::Posix(PosixFileType::Socket) => {
+            // TODO We should read the 'system.sockprotoname' xattr for /proc/[pid]/fd/[fd] for
+            // sockets. That way we can at least print the protocol even if we weren't able to find
+            // any info for the socket in procfs.
+            // TODO make sure we are displaying information that is for the correct namespace
+            // TODO handle IPv6
+            if let Some(ref namespace) = info.namespace {
+                println!("       socket {}: {}", stat_info.st_ino, namespace);
+            }
+            if let Some(sock_info) = sockets.get(&stat_info.st_ino).or(info.foreign_socket.as_ref()) {
+                print_sock_type(sock_info.sock_type);
+                print_sock_address(sock_info);
+                match info.ns_path {
+                    Some(NsPath::Missing) => println!("       WARNING: the process doesn't see anything at this path \
+                                                       (it was removed or is hidden by a mount), so nothing can \
+                                                       connect to it by name"),
+                    Some(NsPath::Other) => println!("       WARNING: the process sees something other than a socket \
+                                                     at this path, so nothing can connect to it by name"),
+                    _ => {}
+                }
+            } else if info.namespace.is_none() {
+                println!("       ERROR: failed to find info for socket with inode num {}",
+                       stat_info.st_ino);
+            }
+            // These keep their files open (even deleted ones) without being in any process' fds,
+            // so they can be what holds on to space a leak hunt can't find
+            if let Some(n) = info.scm_fds.filter(|&n| n > 0) {
+                let (fds, keep) = if n == 1 { ("fd", "keeps its file") } else { ("fds", "keep their files") };
+                println!("       WARNING: {} {} in flight, sent with SCM_RIGHTS and not yet received, which {} open",
+                         n, fds, keep);
+            }
+        },
+        _ => {
+            if let Some(ref link) = info.link {
+                println!("       {}", link);
+            }
+            match info.ns_path {
+                Some(NsPath::Missing) => println!("       the process sees nothing at this path, which is only where \
+                                                   pfiles sees the file"),
+                Some(NsPath::Other) => println!("       the process sees another file at this path, which is only \
+                                                 where pfiles sees this one"),
+                _ => {}
+            }
+            if let Some(ref namespace) = info.namespace {
+                println!("       {}", namespace);
+            }
+        }
+    }
+
+    if let Some(ref device) = info.device {
+        // The link already names the device node, unless it was opened somewhere other than /dev
+        if let Some(ref name) = device.name {
+            if info.link.as_ref().is_none_or(|link| *link != format!("/dev/{}", name)) {
+                println!("         device {}", name);
+            }
+        }
+        for detail in &device.details {
+            println!("         {}", detail);
+        }
+    }
+    if let Some(ref origin) = info.overlay {
+        println!("         overlay: {}", overlay_origin_str(origin));
+    }
+    if let Some(ref project) = info.project {
+        println!("         {}", project_quota_str(project));
+    }
+    if let Some(ref acl) = info.acl {
+        if !acl.entries.is_empty() {
+            println!("         acl: {}", acl_str(&acl.entries));
+        }
+        println!("         access: {} {}", acl_perm_str(acl.access), acl.reason);
+    }
+    if let Some(ref hash) = info.hash {
+        println!("         sha256: {}", hash.sha256);
+        match hash.on_disk {
+            OnDisk::Same => {}
+            OnDisk::Replaced(Some(ref current)) if *current == hash.sha256 => {
+                println!("         the file now at its path is another one, with the same contents");
+            }
+            OnDisk::Replaced(Some(ref current)) => {
+                println!("         the file now at its path differs: sha256 {}", current);
+            }
+            OnDisk::Replaced(None) => println!("         the file now at its path is another one"),
+            OnDisk::Gone => println!("         no file is at its path any more"),
+        }
+    }
+    for watch in &info.epoll {
+        println!("         watching fd {}: {} data:0x{:x}", watch.tfd, epoll_events_strs(watch.events).join("|"),
+                 watch.data);
+    }
+    for watch in &info.inotify {
+        println!("         {}", inotify_watch_str(watch));
+    }
+    if let Some(ref fanotify) = info.fanotify {
+        println!("         fanotify: {}, event fds {}", fanotify_flags_strs(fanotify.flags).join("|"),
+                 open_flags_strs(fanotify.event_flags as u64).join("|"));
+        for mark in &fanotify.marks {
+            println!("         {}", fanotify_mark_str(mark));
+        }
+        if fanotify.permission_events() {
+            println!("         WARNING: permission events: opening or reading the marked files waits until this \
+                      process answers, so if it hangs, so does everything using them");
+        }
+    }
+    if let Some(mask) = info.signalfd {
+        println!("         signals: {}", signal_mask_names(mask).join("|"));
+    }
+    if let Some(pid) = info.pidfd {
+        println!("         pidfd: {}", pidfd_target_str(pid));
+    }
+    let now = Some(std::time::SystemTime::now()).filter(|_| time_format_chosen());
+    if let Some(age) = info.age.as_ref().and_then(|age| fd_age_str(age, now)) {
+        println!("         {}", age);
+    }
+}
+
+fn file_json(info: &FileInfo, sockets: &HashMap<u64, SockInfo>) -> Json {
+    let stat_info = &info.stat;
+    let mut fields = vec![
+        ("fd", Json::UInt(info.fd)),
+        ("type", Json::Str(print_file_type(&info.file_type))),
+        ("mode", Json::Str(format!("{:o}", stat_info.st_mode & 0o7777))),
+        ("dev", Json::Str(format!("{},{}", major(stat_info.st_dev), minor(stat_info.st_dev)))),
+        ("ino", Json::UInt(stat_info.st_ino)),
+        ("uid", Json::UInt(stat_info.st_uid as u64)),
+        ("gid", Json::UInt(stat_info.st_gid as u64)),
+    ];
+    if let Some((ref uid, ref gid)) = info.owner_inside {
+        fields.push(("ns_uid", uid.parse::<u64>().map_or(Json::Null, Json::UInt)));
+        fields.push(("ns_gid", gid.parse::<u64>().map_or(Json::Null, Json::UInt)));
+    }
+
+    let rdev_major = major(stat_info.st_rdev);
+    let rdev_minor = minor(stat_info.st_rdev);
+    if rdev_major == 0 && rdev_minor == 0 {
+        fields.push(("size", Json::Int(stat_info.st_size)));
+    } else {
+        fields.push(("rdev", Json::Str(format!("{},{}", rdev_major, rdev_minor))));
+    }
+
+    fields.push(("flags", Json::Array(open_flags_strs(info.flags).into_iter().map(Json::Str).collect())));
+
+    if let Some(ref link) = info.link {
+        fields.push(("path", Json::Str(link.clone())));
+    }
+
+    if info.file_type == FileType::Posix(PosixFileType::Socket) {
+        if let Some(sock_info) = sockets.get(&stat_info.st_ino).or(info.foreign_socket.as_ref()) {
+            fields.push(("socket", sock_json(sock_info)));
+        }
+        if let Some(n) = info.scm_fds {
+            fields.push(("fds_in_flight", Json::UInt(n)));
+        }
+    }
+    if let Some(ns_path) = info.ns_path {
+        fields.push(("path_in_ns", Json::Str(ns_path.name().to_string())));
+    }
+    if let Some(ref namespace) = info.namespace {
+        fields.push(("namespace", Json::Str(namespace.clone())));
+    }
+
+    if let Some(ref device) = info.device {
+        let mut device_fields = vec![];
+        if let Some(ref name) = device.name {
+            device_fields.push(("name", Json::Str(name.clone())));
+        }
+        device_fields.push(("details", Json::Array(device.details.iter().map(|d| Json::Str(d.clone())).collect())));
+        fields.push(("device", Json::object(device_fields)));
+    }
+
+    if let Some(ref origin) = info.overlay {
+        let mut overlay_fields = vec![
+            ("layer", Json::Str(if origin.lower.is_some() { "lower" } else { "upper" }.to_string())),
+            ("dir", Json::Str(origin.dir.clone())),
+        ];
+        if let Some(i) = origin.lower {
+            overlay_fields.push(("index", Json::UInt(i as u64)));
+        }
+        if let Some(from) = origin.copied_from {
+            overlay_fields.push(("copied_up_from", Json::UInt(from as u64)));
+        }
+        fields.push(("overlay", Json::object(overlay_fields)));
+    }
+
+    if let Some(ref project) = info.project {
+        let mut project_fields = vec![("id", Json::UInt(project.id as u64))];
+        if let Some(ref usage) = project.usage {
+            project_fields.extend(vec![
+                ("bytes", Json::UInt(usage.bytes)),
+                ("bytes_soft_limit", Json::UInt(usage.bytes_soft)),
+                ("bytes_hard_limit", Json::UInt(usage.bytes_hard)),
+                ("inodes", Json::UInt(usage.inodes)),
+                ("inodes_soft_limit", Json::UInt(usage.inodes_soft)),
+                ("inodes_hard_limit", Json::UInt(usage.inodes_hard)),
+            ]);
+        }
+        fields.push(("project", Json::object(project_fields)));
+    }
+
+    if let Some(ref acl) = info.acl {
+        fields.push(("acl", Json::object(vec![
+            ("entries", if acl.entries.is_empty() { Json::Null } else { Json::Str(acl_str(&acl.entries)) }),
+            ("access", Json::Str(acl_perm_str(acl.access))),
+            ("reason", Json::Str(acl.reason.clone())),
+        ])));
+    }
+
+    if let Some(ref hash) = info.hash {
+        let (on_disk, current) = match hash.on_disk {
+            OnDisk::Same => ("same", None),
+            OnDisk::Replaced(ref current) => ("replaced", current.clone()),
+            OnDisk::Gone => ("gone", None),
+        };
+        fields.push(("sha256", Json::Str(hash.sha256.clone())));
+        fields.push(("on_disk", Json::Str(on_disk.to_string())));
+        fields.push(("on_disk_sha256", current.map_or(Json::Null, Json::Str)));
+    }
+
+    if info.file_type == FileType::Anon(AnonFileType::Epoll) {
+        fields.push(("epoll", Json::Array(info.epoll.iter().map(|watch| Json::object(vec![
+            ("tfd", Json::UInt(watch.tfd)),
+            ("events", Json::Array(epoll_events_strs(watch.events).into_iter().map(|e| Json::Str(e.to_string())).collect())),
+            ("data", Json::UInt(watch.data)),
+        ])).collect())));
+    }
+    if info.file_type == FileType::Anon(AnonFileType::Inotify) {
+        fields.push(("inotify", Json::Array(info.inotify.iter().map(|watch| Json::object(vec![
+            ("wd", Json::Int(watch.wd.into())),
+            ("mask", Json::Array(inotify_mask_strs(watch.mask).into_iter().map(|m| Json::Str(m.to_string())).collect())),
+            ("dev", Json::Str(format!("{},{}", watch.dev.0, watch.dev.1))),
+            ("ino", Json::UInt(watch.ino)),
+            ("path", watch.path.clone().map_or(Json::Null, Json::Str)),
+        ])).collect())));
+    }
+    if let Some(ref fanotify) = info.fanotify {
+        let strs = |strs: Vec<&str>| Json::Array(strs.into_iter().map(|s| Json::Str(s.to_string())).collect());
+        fields.push(("fanotify", Json::object(vec![
+            ("flags", strs(fanotify_flags_strs(fanotify.flags))),
+            ("event_flags", Json::Array(open_flags_strs(fanotify.event_flags as u64).into_iter().map(Json::Str).collect())),
+            ("permission_events", Json::Bool(fanotify.permission_events())),
+            ("marks", Json::Array(fanotify.marks.iter().map(|mark| {
+                let mut mark_fields = match mark.object {
+                    FanotifyObject::Inode(ino, dev) => vec![
+                        ("type", Json::Str("inode".to_string())),
+                        ("ino", Json::UInt(ino)),
+                        ("dev", Json::Str(format!("{},{}", dev.0, dev.1))),
+                    ],
+                    FanotifyObject::Mount(id) => vec![
+                        ("type", Json::Str("mount".to_string())),
+                        ("mnt_id", Json::UInt(id)),
+                    ],
+                    FanotifyObject::Filesystem(dev) => vec![
+                        ("type", Json::Str("filesystem".to_string())),
+                        ("dev", Json::Str(format!("{},{}", dev.0, dev.1))),
+                    ],
+                };
+                mark_fields.extend(vec![
+                    ("mount_point", mark.mount_point.clone().map_or(Json::Null, Json::Str)),
+                    ("mask", strs(fanotify_mask_strs(mark.mask))),
+                    ("ignored_mask", strs(fanotify_mask_strs(mark.ignored_mask))),
+                ]);
+                Json::object(mark_fields)
+            }).collect())),
+        ])));
+    }
+    if let Some(mask) = info.signalfd {
+        fields.push(("signals", Json::Array(signal_mask_names(mask).into_iter().map(Json::Str).collect())));
+    }
+    if let Some(pid) = info.pidfd {
+        let target = pidfd_target(pid);
+        fields.push(("pidfd", Json::object(vec![
+            ("pid", if pid > 0 { Json::UInt(pid as u64) } else { Json::Null }),
+            ("state", Json::Str(target.state().to_string())),
+        ])));
+    }
+    if let Some(ref age) = info.age {
+        let secs = |age: Option<Duration>| age.map_or(Json::Null, |age| Json::UInt(age.as_secs()));
+        fields.push(("age", Json::object(vec![
+            ("at_least_secs", secs(age.at_least)),
+            ("at_most_secs", secs(age.at_most)),
+        ])));
+    }
+
+    Json::object(fields)
+}
+
+#[derive(Debug)]
+struct SockInfo {
+    family: AddressFamily,
+    sock_type: SockType,
+    #[allow(dead_code)]
+    inode: u64,
+    local_addr: Option<SocketAddr>, // Doesn't apply to unix sockets
+    peer_addr: Option<SocketAddr>,  // Doesn't apply to unix sockets
+    peer_process: Option<(u64, String)>, // Pid and comm of the peer, if it's a process on this system
+    protocol: Option<&'static str>, // Guessed from the ports, TCP and UDP only
+                                    // TODO state: Option<SockState>, // TCP only
+    ulp: Option<Ulp>,               // TCP only, and only if we're allowed to ask sock_diag
+    netns: Option<u64>,             // Inode of the network namespace whose tables it was found in
+    path: Option<String>,           // UNIX only, the path (or @name, for abstract ones) it's bound to
+    bound_if: Option<BoundIf>,      // SO_BINDTODEVICE, TCP and UDP only, and only if sock_diag says
+}
+
+// The interface a socket was bound to with SO_BINDTODEVICE, and its name in the socket's namespace
+#[derive(Clone, Debug, PartialEq)]
+struct BoundIf {
+    index: u32,
+    name: Option<String>,
+}
+
+// Which addresses an address a socket is bound to is reachable from, roughly
+fn addr_scope(ip: IpAddr) -> &'static str {
+    match ip {
+        ip if ip.is_unspecified() => "wildcard",
+        ip if ip.is_loopback() => "loopback",
+        ip if ip.is_multicast() => "multicast",
+        IpAddr::V4(v4) if v4.is_link_local() => "link-local",
+        IpAddr::V4(v4) if v4.is_private() => "private",
+        IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80 => "link-local",
+        // Unique local addresses, fc00::/7
+        IpAddr::V6(v6) if v6.segments()[0] & 0xfe00 == 0xfc00 => "private",
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => addr_scope(IpAddr::V4(v4)),
+            None => "global",
+        },
+        IpAddr::V4(_) => "global",
+    }
+}
+
+fn bound_if_str(bound_if: &BoundIf) -> String {
+    match bound_if.name {
+        Some(ref name) => format!("{} (ifindex {})", name, bound_if.index),
+        None => format!("ifindex {}", bound_if.index),
+    }
+}
+
+// The upper layer protocol attached to a TCP socket, such as in-kernel TLS
+#[derive(Debug, PartialEq)]
+enum Ulp {
+    // The TLS_CONF_* values say where records are encrypted (tx) and decrypted (rx)
+    Tls { version: Option<u16>, cipher: Option<u16>, tx: u16, rx: u16 },
+    Other(String),
+}
+
+fn sock_type_str(sock_type: SockType) -> &'static str {
+    match sock_type {
+        SockType::Stream => "SOCK_STREAM",
+        SockType::Datagram => "SOCK_DGRAM",
+        SockType::SeqPacket => "SOCK_SEQPACKET",
+        SockType::Raw => "SOCK_RAW",
+        SockType::Rdm => "SOCK_RDM",
+    }
+}
+
+fn print_sock_type(sock_type: SockType) {
+    println!("         {}", sock_type_str(sock_type))
+}
+
+fn address_family_str(addr_fam: AddressFamily) -> &'static str {
+    match addr_fam {
+        AddressFamily::Unix => "AF_UNIX",
+        AddressFamily::Inet => "AF_INET",
+        AddressFamily::Inet6 => "AF_INET6",
+        AddressFamily::Netlink => "AF_NETLINK",
+        AddressFamily::Packet => "AF_PACKET",
+        AddressFamily::Ipx => "AF_IPX",
+        AddressFamily::X25 => "AF_X25",
+        AddressFamily::Ax25 => "AF_AX25",
+        AddressFamily::AtmPvc => "AF_ATMPVC",
+        AddressFamily::AppleTalk => "AF_APPLETALK",
+        AddressFamily::Alg => "AF_ALG",
+        AddressFamily::NetRom => "AF_NETROM",
+        AddressFamily::Bridge => "AF_BRIDGE",
+        AddressFamily::Rose => "AF_ROSE",
+        AddressFamily::Decnet => "AF_DECNET",
+        AddressFamily::NetBeui => "AF_NETBEUI",
+        AddressFamily::Security => "AF_SECURITY",
+        AddressFamily::Key => "AF_KEY",
+        AddressFamily::Ash => "AF_ASH",
+        AddressFamily::Econet => "AF_ECONET",
+        AddressFamily::AtmSvc => "AF_ATMSVC",
+        AddressFamily::Rds => "AF_RDS",
+        AddressFamily::Sna => "AF_SNA",
+        AddressFamily::Irda => "AF_IRDA",
+        AddressFamily::Pppox => "AF_PPPOX",
+        AddressFamily::Wanpipe => "AF_WANPIPE",
+        AddressFamily::Llc => "AF_LLC",
+        AddressFamily::Ib => "AF_IB",
+        AddressFamily::Mpls => "AF_MPLS",
+        AddressFamily::Can => "AF_CAN",
+        AddressFamily::Tipc => "AF_TIPC",
+        AddressFamily::Bluetooth => "AF_BLUETOOTH",
+        AddressFamily::Iucv => "AF_IUCV",
+        AddressFamily::RxRpc => "AF_RXRPC",
+        AddressFamily::Isdn => "AF_ISDN",
+        AddressFamily::Phonet => "AF_PHONET",
+        AddressFamily::Ieee802154 => "AF_IEEE802154",
+        AddressFamily::Caif => "AF_CAIF",
+        AddressFamily::Nfc => "AF_NFC",
+        AddressFamily::Vsock => "AF_VSOCK",
+        AddressFamily::Unspec => panic!("Need to handle this case"), // TODO
+    }
+}
+
+fn inet_address_str(addr_fam: AddressFamily, addr: Option<SocketAddr>) -> String {
+    format!(
+        "{} {}",
+        address_family_str(addr_fam),
+        if let Some(addr) = addr {
+            format!("{}  port: {}", addr.ip(), addr.port())
+        } else {
+            "".to_string()
+        }
+    )
+}
+
+fn print_sock_address(sock_info: &SockInfo) {
+    println!(
+        "         sockname: {}",
+        match sock_info.family {
+            AddressFamily::Inet => inet_address_str(sock_info.family, sock_info.local_addr),
+            AddressFamily::Inet6 => inet_address_str(sock_info.family, sock_info.local_addr),
+            AddressFamily::Unix if sock_info.path.is_some() =>
+                format!("AF_UNIX {}", sock_info.path.as_deref().unwrap_or_default()),
+            addr_fam => address_family_str(addr_fam).to_string(),
+        }
+    );
+
+    // If we have some additional info to print about the remote side of this socket, print it here
+
+    // TODO check that addr is not 0.0.0.0 or :: (Actually, should we make it such that sockaddrs
+    // are none in these cases)?
+    if let Some(addr) = sock_info.peer_addr {
+        if addr.ip() != IpAddr::from([0, 0, 0, 0])
+            && addr.ip() != IpAddr::from([0, 0, 0, 0, 0, 0, 0, 0])
+        {
+            println!(
+                "         peername: {} ",
+                inet_address_str(sock_info.family, Some(addr))
+            );
+        }
+    }
+    // TODO for unix sockets, see if we can find and print the pid/comm of the other process
+    if let Some(peer) = peer_str(sock_info) {
+        println!("         peer: {}", peer);
+    }
+    if let Some(ref ulp) = sock_info.ulp {
+        println!("         {}", ulp_str(ulp));
+    }
+    if let Some(netns) = sock_info.netns {
+        println!("         netns: net:[{}]", netns);
+    }
+    if let Some(addr) = sock_info.local_addr.filter(|_| sock_info.family != AddressFamily::Unix) {
+        println!("         bound: {} address{}", addr_scope(addr.ip()),
+                 sock_info.bound_if.as_ref().map(|bound_if| format!(", to device {}", bound_if_str(bound_if)))
+                     .unwrap_or_default());
+    }
+}
+
+fn sock_json(sock_info: &SockInfo) -> Json {
+    let mut fields = vec![
+        ("type", Json::Str(sock_type_str(sock_info.sock_type).to_string())),
+        ("family", Json::Str(address_family_str(sock_info.family).to_string())),
+    ];
+    if let Some(addr) = sock_info.local_addr {
+        fields.push(("sockname", Json::Str(addr.to_string())));
+    }
+    if let Some(ref path) = sock_info.path {
+        fields.push(("sockname", Json::Str(path.clone())));
+    }
+    if let Some(addr) = sock_info.peer_addr {
+        if !addr.ip().is_unspecified() {
+            fields.push(("peername", Json::Str(addr.to_string())));
+        }
+    }
+    if let Some(protocol) = sock_info.protocol {
+        fields.push(("protocol", Json::Str(protocol.to_string())));
+    }
+    if let Some((pid, ref comm)) = sock_info.peer_process {
+        fields.push(("peer_pid", Json::UInt(pid)));
+        fields.push(("peer_comm", Json::Str(comm.clone())));
+    }
+    if let Some(ref ulp) = sock_info.ulp {
+        fields.push(("ulp", ulp_json(ulp)));
+    }
+    if let Some(netns) = sock_info.netns {
+        fields.push(("netns", Json::UInt(netns)));
+    }
+    if let Some(addr) = sock_info.local_addr {
+        fields.push(("scope", Json::Str(addr_scope(addr.ip()).to_string())));
+    }
+    if let Some(ref bound_if) = sock_info.bound_if {
+        fields.push(("bound_ifindex", Json::UInt(u64::from(bound_if.index))));
+        fields.push(("bound_device", bound_if.name.clone().map_or(Json::Null, Json::Str)));
+    }
+    Json::object(fields)
+}
+
+// Well-known ports, for guessing what a connection is for. Only those that are likely to be seen on
+// a server, and so worth pointing out, as /etc/services has hundreds.
+const WELL_KNOWN_PORTS: &[(u16, &str)] = &[
+    (21, "ftp"), (22, "ssh"), (23, "telnet"), (25, "smtp"), (53, "dns"), (80, "http"), (88, "kerberos"),
+    (110, "pop3"), (111, "rpcbind"), (123, "ntp"), (143, "imap"), (161, "snmp"), (389, "ldap"), (443, "https"),
+    (445, "smb"), (465, "smtps"), (514, "syslog"), (587, "submission"), (636, "ldaps"), (993, "imaps"),
+    (995, "pop3s"), (1433, "mssql"), (1521, "oracle"), (2049, "nfs"), (2181, "zookeeper"), (2379, "etcd"),
+    (3306, "mysql"), (3389, "rdp"), (4222, "nats"), (5432, "postgresql"), (5672, "amqp"), (6379, "redis"),
+    (6443, "kubernetes"), (8080, "http-alt"), (8443, "https-alt"), (9042, "cassandra"), (9092, "kafka"),
+    (9200, "elasticsearch"), (10250, "kubelet"), (11211, "memcached"), (27017, "mongodb"),
+];
+
+// Guess the protocol spoken over a connection from its ports: the peer's if it's a service we
+// connected to, or failing that, our own if we are the service
+fn guess_protocol(local: SocketAddr, peer: SocketAddr) -> Option<&'static str> {
+    let service = |port| WELL_KNOWN_PORTS.iter().find(|&&(p, _)| p == port).map(|&(_, name)| name);
+    service(peer.port()).or_else(|| service(local.port()))
+}
+
+// The other end of a connection, for pfiles --peers, as 'tcp -> 127.0.0.1:5432 (postgresql;
+// postgres, pid 1234)'
+fn peer_str(sock_info: &SockInfo) -> Option<String> {
+    if sock_info.protocol.is_none() && sock_info.peer_process.is_none() {
+        return None;
+    }
+    let peer = sock_info.peer_addr?;
+    let transport = if sock_info.sock_type == SockType::Stream { "tcp" } else { "udp" };
+    let mut about = sock_info.protocol.map(|p| p.to_string()).into_iter().collect::<Vec<_>>();
+    if let Some((pid, ref comm)) = sock_info.peer_process {
+        about.push(format!("{}, pid {}", comm, pid));
+    }
+    Some(format!("{} -> {} ({})", transport, peer, about.join("; ")))
+}
+
+// Which process has each socket open, by inode. Finding out means looking through every process'
+// fds, so it's done once per run, the first time a peer is looked up, and sockets opened after
+// that have no owner.
+#[derive(Default)]
+struct SocketOwners(OnceLock<HashMap<u64, u64>>);
+
+impl SocketOwners {
+    fn get(&self) -> &HashMap<u64, u64> {
+        self.0.get_or_init(|| {
+            let mut owners = HashMap::new();
+            for pid in all_pids() {
+                for fd in read_fds(pid).unwrap_or_default() {
+                    let inode = read_proc_link(proc_path!("{}/fd/{}", pid, fd)).ok()
+                        .and_then(|link| link.to_str()?.strip_prefix("socket:[")?.strip_suffix(']')?.parse::<u64>().ok());
+                    if let Some(inode) = inode {
+                        owners.entry(inode).or_insert(pid);
+                    }
+                }
+            }
+            owners
+        })
+    }
+}
+
+// Work out what the connections among these sockets (all those of a network namespace) are for,
+// and which processes are at the other end of those between processes on this system. The
+// sockets are matched by their addresses, each one's local address being the other's peer, and
+// their owners then found among every process' fds.
+fn resolve_socket_peers(sockets: &mut HashMap<u64, SockInfo>, owners: &SocketOwners) {
+    let connected = |sock: &SockInfo| match (sock.local_addr, sock.peer_addr) {
+        (Some(local), Some(peer)) if sock.family == AddressFamily::Inet && !peer.ip().is_unspecified() &&
+            peer.port() != 0 && sock.sock_type != SockType::Raw => Some((local, peer)),
+        _ => None,
+    };
+    let by_addrs = sockets.values().filter_map(|sock| Some(((connected(sock)?, sock.sock_type == SockType::Stream), sock.inode)))
+        .collect::<HashMap<_, _>>();
+    let mut peers = HashMap::new();
+    for sock in sockets.values_mut() {
+        if let Some((local, peer)) = connected(sock) {
+            sock.protocol = guess_protocol(local, peer);
+            if let Some(&inode) = by_addrs.get(&((peer, local), sock.sock_type == SockType::Stream)) {
+                peers.insert(sock.inode, inode);
+            }
+        }
+    }
+    if peers.is_empty() {
+        return;
+    }
+
+    let owners = owners.get();
+    for (inode, peer) in peers {
+        let owner = owners.get(&peer).map(|&pid| {
+            (pid, read_task_state(pid, pid).map_or_else(|| "?".to_string(), |task| task.comm))
+        });
+        if let Some(sock) = sockets.get_mut(&inode) {
+            sock.peer_process = owner;
+        }
+    }
+}
+
+fn parse_sock_type(type_code: &str) -> Result<SockType, ParseError> {
+    match type_code.parse::<u64>() {
+        Ok(1) => Ok(SockType::Stream),
+        Ok(2) => Ok(SockType::Datagram),
+        Ok(5) => Ok(SockType::SeqPacket),
+        _ => Err(ParseError::new("socket type", &format!("unknown type '{}'", type_code))),
+    }
+}
+
+// Parse a socket address of the form "0100007F:1538" (i.e. 127.0.0.1:5432)
+fn parse_ipv4_sock_addr(s: &str) -> Result<SocketAddr, ParseError> {
+    let mk_err = || {
+        ParseError::new(
+            "IPv4 address",
+            &format!("expected address in form '0100007F:1538', got {}", s),
+        )
+    };
+
+    let fields = s.split(':').collect::<Vec<_>>();
+    if fields.len() != 2 {
+        return Err(mk_err());
+    }
+
+    // Port is always printed with most-significant byte first.
+    let port = u16::from_str_radix(fields[1], 16).map_err(|_| mk_err())?;
+
+    // Address is printed with most-significant byte first on big-endian systems and vice-versa on
+    // little-endian systems.
+    let addr_native_endian = u32::from_str_radix(fields[0], 16).map_err(|_| mk_err())?;
+    let addr = Ipv4Addr::from(addr_native_endian.to_be());
+
+    Ok(SocketAddr::new(IpAddr::V4(addr), port))
+}
+
+// Parse a socket address of the form "00000000000000000000000001000000:1538" (i.e. [::1]:5432), where
+// the address is printed as four 32 bit words, each in native byte order
+fn parse_ipv6_sock_addr(s: &str) -> Result<SocketAddr, ParseError> {
+    let mk_err = || {
+        ParseError::new(
+            "IPv6 address",
+            &format!("expected address in form '00000000000000000000000001000000:1538', got {}", s),
+        )
+    };
+
+    let (addr, port) = s.split_once(':').ok_or_else(mk_err)?;
+    let port = u16::from_str_radix(port, 16).map_err(|_| mk_err())?;
+    if addr.len() != 32 || !addr.is_ascii() {
+        return Err(mk_err());
+    }
+    let mut octets = [0u8; 16];
+    for (i, word) in octets.chunks_mut(4).enumerate() {
+        let native = u32::from_str_radix(&addr[i * 8..i * 8 + 8], 16).map_err(|_| mk_err())?;
+        word.copy_from_slice(&native.to_be().to_be_bytes());
+    }
+
+    Ok(SocketAddr::new(IpAddr::V6(std::net::Ipv6Addr::from(octets)), port))
+}
+
+// Read one of the tables in /proc/[pid]/net/, returning the whitespace separated fields on each
+// line after the header. Each line must have at least min_fields fields.
+fn read_net_table(pid: u64, table: &str, min_fields: usize) -> Result<Vec<Vec<String>>, Box<dyn Error>> {
+    let file = File::open(proc_path!("{}/net/{}", pid, table))?;
+    let mut rows = vec![];
+    for line in BufReader::new(file).lines().skip(1) {
+        let line = line?;
+        let fields = line.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+        if fields.len() < min_fields {
+            return Err(From::from(ParseError::in_file(
+                &format!("net/{}", table),
+                &format!("Fewer fields than expected in line '{}'", line),
+            )));
+        }
+        rows.push(fields);
+    }
+    Ok(rows)
+}
+
+fn fetch_sock_info(pid: u64) -> Result<HashMap<u64, SockInfo>, Box<dyn Error>> {
+    let mut sockets = HashMap::new();
+    let netns = process_namespace(pid, "net");
+
+    // The path is the rest of the line, since it may contain spaces
+    let unix = fs::read_to_string(proc_path!("{}/net/unix", pid))?;
+    for line in unix.lines().skip(1) {
+        let (fields, path) = split_fields(line, 7).ok_or_else(|| {
+            ParseError::in_file("net/unix", &format!("Fewer fields than expected in line '{}'", line))
+        })?;
+        let inode = fields[6].parse()?;
+        sockets.insert(inode, SockInfo {
+            family: AddressFamily::Unix,
+            sock_type: parse_sock_type(fields[4])?,
+            inode,
+            local_addr: None,
+            peer_addr: None,
+            peer_process: None,
+            protocol: None,
+            ulp: None,
+            netns,
+            path: Some(path.to_string()).filter(|path| !path.is_empty()),
+            bound_if: None,
+        });
+    }
+
+    for fields in read_net_table(pid, "netlink", 10)? {
+        let inode = fields[9].parse()?;
+        sockets.insert(inode, SockInfo {
+            family: AddressFamily::Netlink,
+            sock_type: SockType::Datagram,
+            inode,
+            local_addr: None,
+            peer_addr: None,
+            peer_process: None,
+            protocol: None,
+            ulp: None,
+            netns,
+            path: None,
+            bound_if: None,
+        });
+    }
+
+    // procfs entries for tcp, udp, and raw sockets all use same format, for IPv4 and IPv6. The
+    // IPv6 tables are missing if the kernel was booted with ipv6.disable=1.
+    let inet_tables = [
+        ("tcp", SockType::Stream, AddressFamily::Inet), ("udp", SockType::Datagram, AddressFamily::Inet),
+        ("raw", SockType::Raw, AddressFamily::Inet), ("tcp6", SockType::Stream, AddressFamily::Inet6),
+        ("udp6", SockType::Datagram, AddressFamily::Inet6), ("raw6", SockType::Raw, AddressFamily::Inet6),
+    ];
+    for &(table, sock_type, family) in &inet_tables {
+        let rows = match read_net_table(pid, table, 10) {
+            Err(e) if family == AddressFamily::Inet6
+                && e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == ErrorKind::NotFound) => continue,
+            rows => rows?,
+        };
+        let parse_addr = match family {
+            AddressFamily::Inet6 => parse_ipv6_sock_addr,
+            _ => parse_ipv4_sock_addr,
+        };
+        for fields in rows {
+            let inode = fields[9].parse()?;
+            sockets.insert(inode, SockInfo {
+                family,
+                sock_type,
+                local_addr: Some(parse_addr(&fields[1])?),
+                peer_addr: Some(parse_addr(&fields[2])?),
+                peer_process: None,
+                protocol: None,
+                //state: u64::from_str_radix(fields[3], 16).unwrap(),
+                inode,
+                ulp: None,
+                netns,
+                path: None,
+                bound_if: None,
+            });
+        }
+    }
+
+    // Only the kernel's socket diagnostics know about ULPs and the devices sockets are bound to, and
+    // only tell about ULPs to those with CAP_NET_ADMIN, so this is best effort.
+    if sockets.values().any(|sock| {
+        sock.sock_type != SockType::Raw && matches!(sock.family, AddressFamily::Inet | AddressFamily::Inet6)
+    }) {
+        for (inode, diag) in inet_diags(pid).unwrap_or_default() {
+            if let Some(sock) = sockets.get_mut(&inode) {
+                sock.ulp = diag.ulp;
+                sock.bound_if = diag.bound_if;
+            }
+        }
+    }
+
+    Ok(sockets)
+}
+
+// See include/uapi/linux/inet_diag.h and tls.h
+const NETLINK_SOCK_DIAG: i32 = 4;
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const INET_DIAG_REQ_V2_LEN: usize = 56;
+const INET_DIAG_MSG_LEN: usize = 72;
+const INET_DIAG_INFO: u8 = 2;
+const INET_DIAG_ULP_INFO: u16 = 19;
+const INET_ULP_INFO_NAME: u16 = 1;
+const INET_ULP_INFO_TLS: u16 = 2;
+const TLS_INFO_VERSION: u16 = 1;
+const TLS_INFO_CIPHER: u16 = 2;
+const TLS_INFO_TXCONF: u16 = 3;
+const TLS_INFO_RXCONF: u16 = 4;
+
+// Parse the ULP of a socket from the attributes following its inet_diag_msg
+fn parse_ulp_info(attrs: &[u8]) -> Option<Ulp> {
+    let info = netlink_attrs(attrs).into_iter().find(|&(attr_type, _)| attr_type == INET_DIAG_ULP_INFO)?.1;
+    let info = netlink_attrs(info);
+    let name = info.iter().find(|&&(attr_type, _)| attr_type == INET_ULP_INFO_NAME)
+        .map(|(_, name)| String::from_utf8_lossy(name).trim_end_matches('\0').to_string())?;
+    match info.iter().find(|&&(attr_type, _)| attr_type == INET_ULP_INFO_TLS) {
+        Some(&(_, tls)) if name == "tls" => {
+            let tls = netlink_attrs(tls);
+            let field = |wanted| tls.iter().find(|&&(attr_type, payload)| attr_type == wanted && payload.len() >= 2)
+                .map(|&(_, payload)| read_u16(payload, 0));
+            Some(Ulp::Tls {
+                version: field(TLS_INFO_VERSION),
+                cipher: field(TLS_INFO_CIPHER),
+                tx: field(TLS_INFO_TXCONF).unwrap_or(0),
+                rx: field(TLS_INFO_RXCONF).unwrap_or(0),
+            })
+        }
+        _ => Some(Ulp::Other(name)),
+    }
+}
+
+// What sock_diag says about a TCP or UDP socket which its table in /proc/[pid]/net doesn't
+#[derive(Debug, Default, PartialEq)]
+struct InetDiag {
+    ulp: Option<Ulp>,
+    bound_if: Option<BoundIf>,
+}
+
+// Parse a dump of inet_diag_msgs into what they say about the sockets with a ULP or bound to a
+// device, by inode
+fn parse_inet_diag_dump(buf: &[u8], diags: &mut HashMap<u64, InetDiag>) -> bool {
+    let mut offset = 0;
+    while offset + NLMSG_HDR_LEN <= buf.len() {
+        let len = read_u32(buf, offset) as usize;
+        if len < NLMSG_HDR_LEN || offset + len > buf.len() {
+            return true;
+        }
+        match read_u16(buf, offset + 4) {
+            msg_type if msg_type == libc::NLMSG_DONE as u16 || msg_type == libc::NLMSG_ERROR as u16 => return true,
+            SOCK_DIAG_BY_FAMILY if len >= NLMSG_HDR_LEN + INET_DIAG_MSG_LEN => {
+                let msg = &buf[offset + NLMSG_HDR_LEN..offset + len];
+                // idiag_if, in the inet_diag_sockid after the family, state, timer and retrans
+                let index = read_u32(msg, 40);
+                let diag = InetDiag {
+                    ulp: parse_ulp_info(&msg[INET_DIAG_MSG_LEN..]),
+                    bound_if: Some(BoundIf { index, name: None }).filter(|_| index != 0),
+                };
+                if diag != InetDiag::default() {
+                    diags.insert(u64::from(read_u32(msg, 68)), diag);
+                }
+            }
+            _ => {}
+        }
+        offset += (len + 3) & !3;
+    }
+    false
+}
+
+// Ask sock_diag about every TCP and UDP socket in our network namespace, naming the devices they
+// are bound to while we're in it
+fn dump_inet_diags() -> Result<HashMap<u64, InetDiag>, Box<dyn Error>> {
+    use std::os::unix::io::AsRawFd;
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, NETLINK_SOCK_DIAG) };
+    nix::errno::Errno::result(fd)?;
+    let socket = unsafe { <File as std::os::unix::io::FromRawFd>::from_raw_fd(fd) };
+    let mut diags = HashMap::new();
+    let queries = [(libc::AF_INET, libc::IPPROTO_TCP), (libc::AF_INET6, libc::IPPROTO_TCP),
+                   (libc::AF_INET, libc::IPPROTO_UDP), (libc::AF_INET6, libc::IPPROTO_UDP)];
+    for &(family, protocol) in &queries {
+        let len = NLMSG_HDR_LEN + INET_DIAG_REQ_V2_LEN;
+        let mut msg = vec![];
+        msg.extend_from_slice(&(len as u32).to_ne_bytes());
+        msg.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+        msg.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+        msg.extend_from_slice(&[0; 8]); // seq, pid
+        // family, protocol, extensions, pad, then every state
+        msg.extend_from_slice(&[family as u8, protocol as u8, 1 << (INET_DIAG_INFO - 1), 0]);
+        msg.extend_from_slice(&(!0u32).to_ne_bytes());
+        msg.resize(len, 0); // Any socket id
+        let res = unsafe { libc::send(socket.as_raw_fd(), msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        nix::errno::Errno::result(res)?;
+
+        let mut buf = vec![0u8; 32768];
+        loop {
+            let res = unsafe { libc::recv(socket.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            let received = nix::errno::Errno::result(res)? as usize;
+            if received == 0 || parse_inet_diag_dump(&buf[..received], &mut diags) {
+                break;
+            }
+        }
+    }
+    for bound_if in diags.values_mut().filter_map(|diag| diag.bound_if.as_mut()) {
+        let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+        if !unsafe { libc::if_indextoname(bound_if.index, name.as_mut_ptr()) }.is_null() {
+            bound_if.name = Some(unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned());
+        }
+    }
+    Ok(diags)
+}
+
+// What sock_diag says about the TCP and UDP sockets in pid's network namespace, which we have to
+// join to ask about them
+fn inet_diags(pid: u64) -> Result<HashMap<u64, InetDiag>, Box<dyn Error>> {
+    use std::os::unix::io::AsRawFd;
+    let netns = proc_path!("{}/ns/net", pid);
+    if fs::read_link(&netns)? == fs::read_link("/proc/self/ns/net")? {
+        return dump_inet_diags();
+    }
+    let netns = File::open(netns)?;
+    // Namespaces are joined per thread, so do it in one we throw away
+    std::thread::spawn(move || {
+        let res = unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) };
+        nix::errno::Errno::result(res).map_err(|e| e.to_string())?;
+        dump_inet_diags().map_err(|e| e.to_string())
+    }).join().map_err(|_| ParseError::new("sock_diag", "query panicked"))?.map_err(From::from)
+}
+
+// Parse a link to a namespace, such as those in /proc/[pid]/ns/, or an fd referring to one
+fn parse_ns_link(link: &str) -> Option<(&'static str, u64)> {
+    let (kind, inode) = link.split_once(":[")?;
+    let inode = inode.strip_suffix(']')?.parse().ok()?;
+    NAMESPACE_TYPES.iter().find(|&&(k, _)| k == kind).map(|&(k, _)| (k, inode))
+}
+
+fn process_namespace(pid: u64, kind: &str) -> Option<u64> {
+    let link = read_proc_link(proc_path!("{}/ns/{}", pid, kind)).ok()?;
+    parse_ns_link(link.to_str()?).map(|(_, inode)| inode)
+}
+
+// Some process other than pid which is in a namespace, preferably not a kernel thread, since those
+// are all in the initial namespaces
+fn namespace_member(kind: &str, inode: u64, pid: u64) -> Option<u64> {
+    let members = all_pids().into_iter().filter(|&member| member != pid && process_namespace(member, kind) == Some(inode))
+        .collect::<Vec<_>>();
+    members.iter().find(|&&member| !is_kernel_thread(member)).or(members.first()).copied()
+}
+
+// Which namespace an fd of a process refers to: the process' own, that of some other process, or
+// one which no process is in, which only fds and bind mounts are keeping alive
+fn namespace_str(pid: u64, kind: &str, inode: u64) -> String {
+    let name = NAMESPACE_TYPES.iter().find(|&&(k, _)| k == kind).map_or(kind, |&(_, name)| name);
+    let whose = if process_namespace(pid, kind) == Some(inode) {
+        "the process' own".to_string()
+    } else {
+        match namespace_member(kind, inode, pid) {
+            Some(member) => format!("that of process {} ({})", member,
+                                    read_task_state(member, member).map_or_else(|| "?".to_string(), |task| task.comm)),
+            None => "which no process is in".to_string(),
+        }
+    };
+    format!("{} namespace {}:[{}], {}", name, kind, inode, whose)
+}
+
+// pidfd_getfd(2) and SIOCGSKNS aren't in our libc crate
+const SYS_PIDFD_GETFD: libc::c_long = 438;
+const SIOCGSKNS: libc::c_ulong = 0x894c;
+
+// What a path names in the mount namespace of a process, for pfiles --join-ns
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NsPath {
+    // The file it was expected to name, or for a UNIX socket's path, a socket
+    Same,
+    Other,
+    Missing,
+    // Looking it up failed some other way
+    Unknown,
+}
+
+impl NsPath {
+    fn name(self) -> &'static str {
+        match self {
+            NsPath::Same => "same",
+            NsPath::Other => "other",
+            NsPath::Missing => "missing",
+            NsPath::Unknown => "unknown",
+        }
+    }
+}
+
+// Look up paths as a process would, from inside its mount namespace and under its root, where
+// absolute symlinks lead where they do for it (through /proc/[pid]/root, they are resolved against
+// our root). Each path is expected to be the file with the (dev, inode) given, or a socket.
+//
+// setns() only lets a single threaded process into a mount namespace, so a child is forked to
+// join it, which sends a byte back over a pipe for each path. The progress thread may hold locks
+// fork() leaves held, so the child only makes system calls, on what was allocated before. None if
+// the child couldn't join it, e.g. without CAP_SYS_ADMIN, so the caller can carry on without.
+fn lookup_in_namespace(pid: u64, paths: &[(String, Option<(u64, u64)>)]) -> Option<Vec<NsPath>> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    let open = |name| File::open(proc_path!("{}/{}", pid, name)).ok();
+    let (mnt, root) = (open("ns/mnt")?, open("root")?);
+    let cpaths = paths.iter().map(|(path, _)| std::ffi::CString::new(path.as_str()).ok()).collect::<Vec<_>>();
+    let mut answers = vec![3u8; paths.len()];
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    let mut pipe = [0; 2];
+    if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return None;
+    }
+    let child = unsafe { libc::fork() };
+    if child == 0 {
+        unsafe {
+            if libc::setns(mnt.as_raw_fd(), libc::CLONE_NEWNS) != 0 || libc::fchdir(root.as_raw_fd()) != 0
+                || libc::chroot(b".\0".as_ptr() as *const libc::c_char) != 0 {
+                libc::_exit(1);
+            }
+            for (i, cpath) in cpaths.iter().enumerate() {
+                let cpath = match *cpath {
+                    Some(ref cpath) => cpath,
+                    None => continue,
+                };
+                answers[i] = if libc::stat(cpath.as_ptr(), &mut st) != 0 {
+                    match *libc::__errno_location() {
+                        libc::ENOENT | libc::ENOTDIR => 2,
+                        _ => 3,
+                    }
+                } else {
+                    let same = match paths[i].1 {
+                        Some((dev, ino)) => st.st_dev == dev && st.st_ino == ino,
+                        None => st.st_mode & libc::S_IFMT == libc::S_IFSOCK,
+                    };
+                    !same as u8
+                };
+            }
+            let mut written = 0;
+            while written < answers.len() {
+                let res = libc::write(pipe[1], answers[written..].as_ptr() as *const libc::c_void,
+                                      answers.len() - written);
+                if res <= 0 {
+                    libc::_exit(1);
+                }
+                written += res as usize;
+            }
+            libc::_exit(0);
+        }
+    }
+    unsafe { libc::close(pipe[1]) };
+    let mut reader = unsafe { File::from_raw_fd(pipe[0]) };
+    if child < 0 {
+        return None;
+    }
+    let mut buf = vec![];
+    let read = reader.read_to_end(&mut buf);
+    let mut status = 0;
+    unsafe { libc::waitpid(child, &mut status, 0) };
+    if read.is_err() || buf.len() != paths.len() {
+        return None;
+    }
+    Some(buf.into_iter().map(|answer| match answer {
+        0 => NsPath::Same,
+        1 => NsPath::Other,
+        2 => NsPath::Missing,
+        _ => NsPath::Unknown,
+    }).collect())
+}
+
+// The network namespace a socket of a process is in. Only the socket itself can tell, so it is
+// copied from the process, which takes the same permission as ptracing it.
+fn socket_netns(pid: u64, fd: u64) -> Result<File, Box<dyn Error>> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    let pidfd = nix::errno::Errno::result(unsafe { libc::syscall(SYS_PIDFD_OPEN, pid as libc::pid_t, 0) })?;
+    let pidfd = unsafe { File::from_raw_fd(pidfd as i32) };
+    let socket = unsafe { libc::syscall(SYS_PIDFD_GETFD, pidfd.as_raw_fd(), fd as libc::c_int, 0) };
+    let socket = unsafe { File::from_raw_fd(nix::errno::Errno::result(socket)? as i32) };
+    let netns = nix::errno::Errno::result(unsafe { libc::ioctl(socket.as_raw_fd(), SIOCGSKNS) })?;
+    Ok(unsafe { File::from_raw_fd(netns) })
+}
+
+// For a socket which isn't in the tables of its process' network namespace, where it is. It may
+// have been made in another namespace before the process moved (or passed from a process in one),
+// or just be of a kind we don't know about. With enter, find out which, reading the socket tables of
+// its namespace through a process in it, or by joining it if there is none.
+fn foreign_socket(pid: u64, fd: u64, inode: u64, enter: bool) -> (String, Option<SockInfo>) {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    let own = process_namespace(pid, "net");
+    let own_str = own.map_or_else(|| "network namespace".to_string(), |own| format!("network namespace net:[{}]", own));
+    if !enter {
+        return (format!("not in the process' {}, or of a kind pfiles doesn't know (--enter-ns looks for it)",
+                        own_str), None);
+    }
+    let (netns, ns_inode) = match socket_netns(pid, fd).and_then(|netns| Ok((netns.metadata()?.ino(), netns))) {
+        Ok((ns_inode, netns)) => (netns, ns_inode),
+        Err(e) => return (format!("unable to find its network namespace: {}", e), None),
+    };
+    if own == Some(ns_inode) {
+        return (format!("in the process' {}, but of a kind pfiles doesn't know", own_str), None);
+    }
+    let sockets = match namespace_member("net", ns_inode, pid) {
+        Some(member) => fetch_sock_info(member).map_err(|e| e.to_string()),
+        // Namespaces are joined per thread, so do it in one we throw away, which can then read its
+        // own tables
+        None => std::thread::spawn(move || {
+            let res = unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) };
+            nix::errno::Errno::result(res).map_err(|e| format!("unable to join it: {}", e))?;
+            let tid = unsafe { libc::syscall(libc::SYS_gettid) } as u64;
+            fetch_sock_info(tid).map_err(|e| e.to_string())
+        }).join().unwrap_or_else(|_| Err("joining it panicked".to_string())),
+    };
+    let description = format!("in {}", namespace_str(pid, "net", ns_inode));
+    match sockets {
+        Ok(mut sockets) => (description, sockets.remove(&inode)),
+        Err(e) => (format!("{}, whose sockets can't be read: {}", description, e), None),
+    }
+}
+
+fn tls_version_str(version: u16) -> String {
+    match version {
+        0x0303 => "TLS 1.2".to_string(),
+        0x0304 => "TLS 1.3".to_string(),
+        v => format!("TLS version {:#06x}", v),
+    }
+}
+
+fn tls_cipher_str(cipher: u16) -> String {
+    match cipher {
+        51 => "AES_GCM_128".to_string(),
+        52 => "AES_GCM_256".to_string(),
+        53 => "AES_CCM_128".to_string(),
+        54 => "CHACHA20_POLY1305".to_string(),
+        55 => "SM4_GCM".to_string(),
+        56 => "SM4_CCM".to_string(),
+        57 => "ARIA_GCM_128".to_string(),
+        58 => "ARIA_GCM_256".to_string(),
+        c => format!("cipher {}", c),
+    }
+}
+
+// Where a kTLS socket encrypts or decrypts its records
+fn tls_conf_str(conf: u16) -> &'static str {
+    match conf {
+        1 => "not configured",
+        2 => "in kernel software",
+        3 => "offloaded to the NIC",
+        4 => "offloaded to the NIC (full records)",
+        _ => "unknown",
+    }
+}
+
+fn tls_conf_name(conf: u16) -> &'static str {
+    match conf {
+        1 => "base",
+        2 => "sw",
+        3 => "hw",
+        4 => "hw_record",
+        _ => "unknown",
+    }
+}
+
+fn ulp_str(ulp: &Ulp) -> String {
+    match *ulp {
+        Ulp::Tls { version, cipher, tx, rx } => {
+            let mut s = "kTLS:".to_string();
+            if let Some(version) = version {
+                s += &format!(" {}", tls_version_str(version));
+            }
+            if let Some(cipher) = cipher {
+                s += &format!(" {}", tls_cipher_str(cipher));
+            }
+            if version.is_some() || cipher.is_some() {
+                s.push(',');
+            }
+            format!("{} tx: {}, rx: {}", s, tls_conf_str(tx), tls_conf_str(rx))
+        }
+        Ulp::Other(ref name) => format!("ULP: {}", name),
+    }
+}
+
+fn ulp_json(ulp: &Ulp) -> Json {
+    match *ulp {
+        Ulp::Tls { version, cipher, tx, rx } => {
+            let mut fields = vec![("name", Json::Str("tls".to_string()))];
+            if let Some(version) = version {
+                fields.push(("version", Json::Str(tls_version_str(version))));
+            }
+            if let Some(cipher) = cipher {
+                fields.push(("cipher", Json::Str(tls_cipher_str(cipher))));
+            }
+            fields.push(("tx", Json::Str(tls_conf_name(tx).to_string())));
+            fields.push(("rx", Json::Str(tls_conf_name(rx).to_string())));
+            Json::object(fields)
+        }
+        Ulp::Other(ref name) => Json::object(vec![("name", Json::Str(name.clone()))]),
+    }
+}
+
+/*
+ * Some things about Illumos pfiles output seem less than ideal. For instance, would
+ * printing 'TCP' be preferrable to 'SOCK_STREAM'? Could we add somewhere in output the
+ * psuedo file for the socket? That could be very useful for manually inspecting or
+ * draining the output.
+ *
+ *    435: S_IFSOCK mode:0666 dev:556,0 ino:38252 uid:0 gid:0 rdev:0,0
+ *         O_RDWR
+ *           SOCK_STREAM
+ *           SO_SNDBUF(16384),SO_RCVBUF(5120)
+ *           sockname: AF_UNIX
+ *           peer: java[1053] zone: global[0]
+ *
+ * Another example: we can guess by the way that there is no peer address that this socket
+ * is listening. Could we make this more explicit? Even for sockets that aren't listening, it
+ * might be really useful to know the state of the connection
+ *
+ *    436: S_IFSOCK mode:0666 dev:556,0 ino:37604 uid:0 gid:0 rdev:0,0
+ *         O_RDWR|O_NONBLOCK
+ *           SOCK_STREAM
+ *           SO_REUSEADDR,SO_SNDBUF(16777216),SO_RCVBUF(4194304)
+ *           sockname: AF_INET6 ::  port: 8341
+ */
+
+// Options controlling what pfiles prints for each process
+#[derive(Default)]
+struct PfilesOptions {
+    // Only print these (inclusive) ranges of fds. Print all fds if empty.
+    fd_ranges: Vec<(u64, u64)>,
+    json: bool,
+    // Sample the files watched by epoll fds twice, this far apart
+    epoll_sample: Option<Duration>,
+    // Find out which overlayfs layer backs each file
+    overlay: bool,
+    // Find out which project each regular file and directory is in, which means opening it again
+    quota: bool,
+    // Work out how long each fd has been open
+    age: bool,
+    // Show the ACLs of files, and the access the process has to them
+    acl: bool,
+    // Guess what connections are for, and find the processes at the other end of local ones
+    peers: bool,
+    // Look up sockets in other network namespaces there
+    enter_ns: bool,
+    // Look up the paths of files and UNIX sockets in the process' mount namespace
+    join_ns: bool,
+    // Hash the contents of regular files of at most this many bytes
+    hash: Option<u64>,
+    // Only count the fds of each type
+    counts: bool,
+    // Print the details of at most this many fds, and only count the rest
+    limit: Option<usize>,
+    // Leave out the fds on filesystems which don't answer a statfs() this quickly
+    mount_timeout: Option<Duration>,
+    // Warn about fds open for writing on filesystems which are at least this many percent full
+    full_threshold: u64,
+}
+
+impl PfilesOptions {
+    fn want_fd(&self, fd: u64) -> bool {
+        self.fd_ranges.is_empty() || self.fd_ranges.iter().any(|&(lo, hi)| lo <= fd && fd <= hi)
+    }
+}
+
+// Parse an fd specification of the form '5' or '100-200' into an inclusive range
+fn parse_fd_range(s: &str) -> Result<(u64, u64), ParseError> {
+    let mk_err = || ParseError::new("fd range", &format!("expected fd in form '5' or '100-200', got '{}'", s));
+    let parse = |s: &str| s.trim().parse::<u64>().map_err(|_| mk_err());
+
+    let (lo, hi) = match s.find('-') {
+        Some(i) => (parse(&s[..i])?, parse(&s[i + 1..])?),
+        None => {
+            let fd = parse(s)?;
+            (fd, fd)
+        }
+    };
+
+    if lo > hi {
+        return Err(mk_err());
+    }
+    Ok((lo, hi))
+}
+
+// See include/uapi/linux/ioprio.h
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: i32 = 13;
+
+// Describe an I/O priority the way ionice(1) does. Processes which never set one get a best-effort
+// priority based on their nice value.
+fn ioprio_str(ioprio: i32, nice: Option<i64>) -> String {
+    let level = ioprio & ((1 << IOPRIO_CLASS_SHIFT) - 1);
+    match ioprio >> IOPRIO_CLASS_SHIFT {
+        0 => match nice {
+            Some(nice) => format!("none (best-effort/{} from nice {})", (nice + 20) / 5, nice),
+            None => "none".to_string(),
+        },
+        1 => format!("realtime/{}", level),
+        2 => format!("best-effort/{}", level),
+        3 => "idle".to_string(),
+        class => format!("class {}/{}", class, level),
+    }
+}
+
+// I/O priorities belong to threads. Given a pid, ioprio_get() and ioprio_set() only look at or
+// change its main thread.
+fn thread_ioprio(tid: u64) -> nix::Result<i32> {
+    let ioprio = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, tid as libc::pid_t) };
+    nix::errno::Errno::result(ioprio).map(|ioprio| ioprio as i32)
+}
+
+fn set_thread_ioprio(tid: u64, ioprio: i32) -> nix::Result<()> {
+    let res = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, tid as libc::pid_t, ioprio) };
+    nix::errno::Errno::result(res).map(drop)
+}
+
+fn thread_nice(pid: u64, tid: u64) -> Option<i64> {
+    read_task_file(pid, tid, "stat").ok().and_then(|stat| stat_field(&stat, 19)?.parse().ok())
+}
+
+fn io_priority(pid: u64) -> Result<String, Box<dyn Error>> {
+    let ioprio = thread_ioprio(pid)?;
+    let nice = fs::read_to_string(proc_path!("{}/stat", pid)).ok()
+        .and_then(|stat| stat_field(&stat, 19)?.parse().ok());
+    Ok(ioprio_str(ioprio, nice))
+}
+
+// Parse an I/O scheduling class, by name or by number as ionice(1) takes them
+fn parse_ioprio_class(class: &str) -> Option<i32> {
+    match class {
+        "0" | "none" => Some(0),
+        "1" | "realtime" => Some(1),
+        "2" | "best-effort" => Some(2),
+        "3" | "idle" => Some(3),
+        _ => None,
+    }
+}
+
+// The I/O priority to set for pionice -c and -n. Only the realtime and best-effort classes have
+// levels, from 0 (highest) to 7, and -n alone means best-effort, as with ionice(1).
+fn ioprio_from_opts(class: Option<&str>, level: Option<&str>) -> Result<i32, String> {
+    let class = match class {
+        Some(name) => parse_ioprio_class(name).ok_or_else(|| format!("Unknown I/O scheduling class '{}'", name))?,
+        None => 2,
+    };
+    let level = match level {
+        Some(_) if class == 0 || class == 3 => return Err("-n only applies to the realtime and best-effort \
+                                                           classes".to_string()),
+        Some(level) => match level.parse::<i32>() {
+            Ok(level) if (0..8).contains(&level) => level,
+            _ => return Err(format!("-n must be a level from 0 to 7, not '{}'", level)),
+        },
+        // The default level of best-effort is what a nice of 0 gives
+        None if class == 1 || class == 2 => 4,
+        None => 0,
+    };
+    Ok((class << IOPRIO_CLASS_SHIFT) | level)
+}
+
+// Print the I/O priority of a process's threads, listing them separately only if they differ
+fn print_io_priorities(pid: u64) -> bool {
+    let tids = match task_ids(pid) {
+        Ok(tids) => tids,
+        Err(e) => {
+            eprintln!("Unable to read /proc/{}/task/: {}", pid, e);
+            return false;
+        }
+    };
+    let mut priorities = vec![];
+    for tid in tids {
+        match thread_ioprio(tid) {
+            Ok(ioprio) => priorities.push((tid, ioprio_str(ioprio, thread_nice(pid, tid)))),
+            // The thread exited
+            Err(nix::Error::Sys(nix::errno::Errno::ESRCH)) => {}
+            Err(e) => {
+                eprintln!("Unable to get the I/O priority of process {}: {}", pid, e);
+                return false;
+            }
+        }
+    }
+
+    print_proc_summary(pid);
+    if priorities.iter().all(|(_, priority)| *priority == priorities[0].1) {
+        if let Some((_, priority)) = priorities.first() {
+            println!("  I/O priority: {}", priority);
+        }
+    } else {
+        for (tid, priority) in priorities {
+            let comm = read_task_file(pid, tid, "comm").map(|comm| comm.trim().to_string()).unwrap_or_default();
+            println!("  thread {} ({}): {}", tid, comm, priority);
+        }
+    }
+    true
+}
+
+// Give every thread of a process the I/O priority, printing what it was before. Threads created
+// meanwhile inherit it from the thread which created them, which may or may not have been changed.
+fn set_io_priority(_cap: &MutateCap, pid: u64, ioprio: i32) -> bool {
+    let old = io_priority(pid).ok();
+    let tids = match task_ids(pid) {
+        Ok(tids) => tids,
+        Err(e) => {
+            eprintln!("Unable to read /proc/{}/task/: {}", pid, e);
+            return false;
+        }
+    };
+    for tid in tids {
+        match set_thread_ioprio(tid, ioprio) {
+            Ok(()) | Err(nix::Error::Sys(nix::errno::Errno::ESRCH)) => {}
+            Err(e) => {
+                eprintln!("Unable to set the I/O priority of process {}: {}", pid, e);
+                return false;
+            }
+        }
+    }
+    let new = ioprio_str(ioprio, thread_nice(pid, pid));
+    println!("{}: I/O priority {} -> {}", pid, old.unwrap_or_else(|| "unknown".to_string()), new);
+    true
+}
+
+// The settings of a process which decide the permissions and I/O priority of the files it works
+// with, as the umask in octal and the I/O priority. Each is None if we can't find it out. ioprio_get()
+// asks about a live process, so captures don't have an I/O priority.
+fn file_settings(pid: u64) -> (Option<String>, Option<String>) {
+    let umask = ProcStat::read(pid).and_then(|status| status.umask()).ok().map(|umask| format!("{:04o}", umask));
+    let ioprio = if is_capture() { None } else { io_priority(pid).ok() };
+    (umask, ioprio)
+}
+
+// See include/uapi/linux/fs.h: _IOR('X', 31, struct fsxattr). struct fsxattr has the flags, the
+// extent size hint and the number of extents before the project ID.
+const FS_IOC_FSGETXATTR: libc::c_ulong = 0x801c_581f;
+const FSXATTR_LEN: usize = 28;
+// See include/uapi/linux/quota.h
+const Q_GETQUOTA: libc::c_int = 0x80_0007;
+const PRJQUOTA: libc::c_int = 2;
+// struct if_dqblk: block limits (in 1KiB blocks), space used (in bytes), inode limits and inodes
+// used, grace periods and which fields are valid
+const IF_DQBLK_LEN: usize = 72;
+const SYS_QUOTACTL_FD: libc::c_long = 443;
+
+// Filesystems which have project quotas
+const PROJECT_QUOTA_FS_TYPES: &[&str] = &["xfs", "ext4", "f2fs"];
+
+// How much of a project quota is used, with limits of 0 meaning there is none
+#[derive(Debug, PartialEq)]
+struct QuotaUsage {
+    bytes: u64,
+    bytes_soft: u64,
+    bytes_hard: u64,
+    inodes: u64,
+    inodes_soft: u64,
+    inodes_hard: u64,
+}
+
+// The project a file belongs to, and the usage of the project's quota if quotas are enforced
+#[derive(Debug, PartialEq)]
+struct ProjectQuota {
+    id: u32,
+    usage: Option<QuotaUsage>,
+}
+
+fn parse_if_dqblk(buf: &[u8]) -> QuotaUsage {
+    QuotaUsage {
+        bytes_hard: read_u64(buf, 0).saturating_mul(1024),
+        bytes_soft: read_u64(buf, 8).saturating_mul(1024),
+        bytes: read_u64(buf, 16),
+        inodes_hard: read_u64(buf, 24),
+        inodes_soft: read_u64(buf, 32),
+        inodes: read_u64(buf, 40),
+    }
+}
+
+// Whether writes are likely to fail with EDQUOT soon: more than 90% of the lower limit is used
+fn quota_nearly_full(usage: &QuotaUsage) -> bool {
+    let nearly = |used: u64, soft: u64, hard: u64| {
+        let limit = [soft, hard].iter().cloned().filter(|&l| l > 0).min();
+        limit.is_some_and(|limit| used as f64 >= limit as f64 * 0.9)
+    };
+    nearly(usage.bytes, usage.bytes_soft, usage.bytes_hard) || nearly(usage.inodes, usage.inodes_soft, usage.inodes_hard)
+}
+
+fn size_str(bytes: u64) -> String {
+    let units = ["K", "M", "G", "T", "P"];
+    let mut size = bytes as f64;
+    if size < 1024.0 {
+        return format!("{}B", bytes);
+    }
+    for unit in &units {
+        size /= 1024.0;
+        if size < 1024.0 || *unit == "P" {
+            return format!("{:.1}{}", size, unit);
+        }
+    }
+    unreachable!()
+}
+
+// Parse a size like those size_str prints: '512', '64K', '10M', '1.5G' or '2GiB'. Plain numbers
+// are bytes, and the units are powers of 1024.
+fn parse_size(size: &str) -> Result<u64, Box<dyn Error>> {
+    let split = size.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number = number.parse::<f64>().map_err(|_| format!("Invalid size '{}'", size))?;
+    let unit = unit.trim_end_matches("iB").trim_end_matches('B');
+    let power = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return Err(From::from(format!("Invalid unit in size '{}': expected K, M, G or T", size))),
+    };
+    Ok((number * 1024f64.powi(power)) as u64)
+}
+
+fn project_quota_str(project: &ProjectQuota) -> String {
+    let usage = match project.usage {
+        Some(ref usage) => usage,
+        None => return format!("project {}", project.id),
+    };
+    let of = |used: String, soft: u64, hard: u64, fmt: &dyn Fn(u64) -> String| match (soft, hard) {
+        (0, 0) => used,
+        (soft, 0) => format!("{} of {} (soft)", used, fmt(soft)),
+        (0, hard) => format!("{} of {}", used, fmt(hard)),
+        (soft, hard) => format!("{} of {} (soft) / {}", used, fmt(soft), fmt(hard)),
+    };
+    format!("project {} quota: {} used, {} inodes", project.id,
+            of(size_str(usage.bytes), usage.bytes_soft, usage.bytes_hard, &size_str),
+            of(usage.inodes.to_string(), usage.inodes_soft, usage.inodes_hard, &|n| n.to_string()))
+}
+
+// Find the project ID of an open regular file or directory, and its quota. We open it again
+// through /proc, so only do so on local filesystems with project quotas, where that has no side
+// effects. Project 0 is the default for files outside any project, so isn't shown.
+fn project_quota(pid: u64, fd: u64, mount: &MountInfo) -> Option<ProjectQuota> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    if !PROJECT_QUOTA_FS_TYPES.contains(&mount.fs_type.as_str()) {
+        return None;
+    }
+    let file = fs::OpenOptions::new().read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY)
+        .open(proc_path!("{}/fd/{}", pid, fd)).ok()?;
+    let mut fsxattr = [0u8; FSXATTR_LEN];
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSGETXATTR, fsxattr.as_mut_ptr()) };
+    if res != 0 {
+        return None;
+    }
+    let id = read_u32(&fsxattr, 12);
+    if id == 0 {
+        return None;
+    }
+
+    // quotactl_fd() is new in Linux 5.14, so fall back to naming the filesystem's device
+    let mut dqblk = [0u8; IF_DQBLK_LEN];
+    let cmd = (Q_GETQUOTA << 8) | PRJQUOTA;
+    let mut res = unsafe { libc::syscall(SYS_QUOTACTL_FD, file.as_raw_fd(), cmd, id, dqblk.as_mut_ptr()) };
+    if res != 0 {
+        res = match std::ffi::CString::new(mount.source.clone()) {
+            Ok(device) => unsafe {
+                libc::quotactl(cmd, device.as_ptr(), id as libc::c_int, dqblk.as_mut_ptr() as *mut libc::c_char)
+            }.into(),
+            Err(_) => -1,
+        };
+    }
+    Some(ProjectQuota { id, usage: if res == 0 { Some(parse_if_dqblk(&dqblk)) } else { None } })
+}
+
+// An entry of a POSIX ACL, as stored in the system.posix_acl_access xattr: after a 4 byte version
+// (2), 8 byte entries of a tag, permissions and the uid or gid of named users and groups, all little
+// endian
+#[derive(Debug, PartialEq)]
+struct AclEntry {
+    tag: u16,
+    perm: u16,
+    id: u32,
+}
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+// A file's ACL (empty if it has none, and only its mode applies), and what the process may do to it
+struct FileAcl {
+    entries: Vec<AclEntry>,
+    // As rwx bits
+    access: u16,
+    reason: String,
+}
+
+fn parse_posix_acl(xattr: &[u8]) -> Option<Vec<AclEntry>> {
+    let le16 = |b: &[u8]| u16::from_le_bytes([b[0], b[1]]);
+    let le32 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    if xattr.len() < 4 || le32(xattr) != 2 || !(xattr.len() - 4).is_multiple_of(8) {
+        return None;
+    }
+    Some(xattr[4..].chunks(8).map(|e| AclEntry { tag: le16(e), perm: le16(&e[2..]), id: le32(&e[4..]) }).collect())
+}
+
+fn read_posix_acl(path: &str) -> Option<Vec<AclEntry>> {
+    let path = std::ffi::CString::new(path).ok()?;
+    let name = b"system.posix_acl_access\0".as_ptr() as *const libc::c_char;
+    // Ask how big it is first. Files without an ACL have no xattr.
+    let len = unsafe { libc::getxattr(path.as_ptr(), name, std::ptr::null_mut(), 0) };
+    if len <= 0 {
+        return None;
+    }
+    let mut buf = vec![0u8; len as usize];
+    let len = unsafe { libc::getxattr(path.as_ptr(), name, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    if len < 0 {
+        return None;
+    }
+    parse_posix_acl(&buf[..len as usize])
+}
+
+fn acl_perm_str(perm: u16) -> String {
+    [(4, 'r'), (2, 'w'), (1, 'x')].iter().map(|&(bit, c)| if perm & bit != 0 { c } else { '-' }).collect()
+}
+
+// In the form getfacl and setfacl use, e.g. 'user::rw-,user:alice:r--,group::r--,mask::r--,other::---'
+fn acl_str(entries: &[AclEntry]) -> String {
+    entries.iter()
+        .map(|entry| {
+            let (kind, qualifier) = match entry.tag {
+                ACL_USER_OBJ => ("user", String::new()),
+                ACL_USER => ("user", user_name(entry.id)),
+                ACL_GROUP_OBJ => ("group", String::new()),
+                ACL_GROUP => ("group", group_name(entry.id)),
+                ACL_MASK => ("mask", String::new()),
+                ACL_OTHER => ("other", String::new()),
+                tag => ("unknown", format!("{}/{}", tag, entry.id)),
+            };
+            format!("{}:{}:{}", kind, qualifier, acl_perm_str(entry.perm))
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// The credentials the kernel checks a process' access to files against
+#[derive(Debug, Default)]
+struct FileCredentials {
+    fsuid: libc::uid_t,
+    fsgid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+    // Effective capabilities
+    caps: u64,
+}
+
+fn read_file_credentials(pid: u64) -> Option<FileCredentials> {
+    let status = ProcStat::read(pid).ok()?;
+    // The Uid and Gid lines have the real, effective, saved and filesystem ids
+    let fs_id = |field| status.get_field(field).ok()?.split_whitespace().nth(3)?.parse::<u32>().ok();
+    Some(FileCredentials {
+        fsuid: fs_id("Uid")?,
+        fsgid: fs_id("Gid")?,
+        groups: status.get_field("Groups").map(|groups| groups.split_whitespace().filter_map(|g| g.parse().ok())
+                                                   .collect()).unwrap_or_default(),
+        caps: status.get_field("CapEff").ok().and_then(|caps| u64::from_str_radix(caps, 16).ok()).unwrap_or(0),
+    })
+}
+
+// What a process may do to a file, as rwx bits, and why. The owner gets the owner entry, a user with
+// a named entry that, members of the owning group or named groups what any of those entries allow,
+// and anyone else the other entry, with the mask limiting all but the owner and other entries (see
+// posix_acl_permission()). Without an ACL, the mode's bits are those entries. CAP_DAC_OVERRIDE
+// allows reading and writing anything, and executing if anyone may, and CAP_DAC_READ_SEARCH allows
+// reading.
+fn file_access(creds: &FileCredentials, uid: u32, gid: u32, mode: u32, acl: &[AclEntry]) -> (u16, String) {
+    let entry = |tag| acl.iter().find(|e| e.tag == tag).map(|e| e.perm);
+    let mode_bits = |shift: u32| ((mode >> shift) & 7) as u16;
+    let (owner, group, other) = match acl.is_empty() {
+        true => (mode_bits(6), mode_bits(3), mode_bits(0)),
+        false => (entry(ACL_USER_OBJ).unwrap_or(0), entry(ACL_GROUP_OBJ).unwrap_or(0), entry(ACL_OTHER).unwrap_or(0)),
+    };
+    let mask = entry(ACL_MASK).unwrap_or(7);
+    let masked = |perm: u16, why: String| match perm & mask != perm {
+        true => (perm & mask, format!("{} (limited by the mask)", why)),
+        false => (perm, why),
+    };
+
+    let in_group = |gid| gid == creds.fsgid || creds.groups.contains(&gid);
+    let groups = std::iter::once((gid, group)).filter(|&(gid, _)| in_group(gid))
+        .chain(acl.iter().filter(|e| e.tag == ACL_GROUP && in_group(e.id)).map(|e| (e.id, e.perm)))
+        .collect::<Vec<_>>();
+    let (mut access, mut reason) = if creds.fsuid == uid {
+        (owner, "as its owner".to_string())
+    } else if let Some(user) = acl.iter().find(|e| e.tag == ACL_USER && e.id == creds.fsuid) {
+        masked(user.perm, format!("through the entry for user {}", user_name(user.id)))
+    } else if !groups.is_empty() {
+        let names = groups.iter().map(|&(gid, _)| group_name(gid)).collect::<Vec<_>>();
+        masked(groups.iter().fold(0, |perm, &(_, group)| perm | group),
+               format!("as a member of {}", names.join(", ")))
+    } else {
+        (other, "as anyone else".to_string())
+    };
+
+    let is_dir = mode & libc::S_IFMT == libc::S_IFDIR;
+    let execute = if is_dir || mode & 0o111 != 0 { 1 } else { 0 };
+    if has_capability(creds.caps, CAP_DAC_OVERRIDE) && access != access | 6 | execute {
+        access |= 6 | execute;
+        reason += ", and CAP_DAC_OVERRIDE";
+    } else if has_capability(creds.caps, CAP_DAC_READ_SEARCH) && access & 4 == 0 {
+        access |= 4 | if is_dir { 1 } else { 0 };
+        reason += ", and CAP_DAC_READ_SEARCH";
+    }
+    (access, reason)
+}
+
+fn file_acl(pid: u64, fd: u64, st: &FileStat, creds: &FileCredentials) -> FileAcl {
+    let entries = read_posix_acl(&proc_path!("{}/fd/{}", pid, fd)).unwrap_or_default();
+    let (access, reason) = file_access(creds, st.st_uid, st.st_gid, st.st_mode, &entries);
+    FileAcl { entries, access, reason }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// SHA-256, as in FIPS 180-4, as a hex string. The files pfiles --hash reads are small, so they are
+// hashed in one go.
+fn sha256_hex(data: &[u8]) -> String {
+    let mut state: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                               0x5be0cd19];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (word, bytes) in w.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for (&k, &w) in SHA256_K.iter().zip(w.iter()) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(k).wrapping_add(w);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(s0.wrapping_add(maj));
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+    state.iter().map(|word| format!("{:08x}", word)).collect()
+}
+
+// What is at the path a hashed file was opened by now
+#[derive(Debug, PartialEq)]
+enum OnDisk {
+    // The file the process has open
+    Same,
+    // Another file, e.g. after an editor or a package upgrade replaced it, with its hash if it is
+    // small enough and we could read it
+    Replaced(Option<String>),
+    Gone,
+}
+
+// Which version of a config file a long-running daemon loaded, compared to what is there now
+struct FileHash {
+    sha256: String,
+    on_disk: OnDisk,
+}
+
+// The magic numbers statfs() gives for filesystems whose files are made up as they are read, like
+// /proc/kmsg or tracefs' trace_pipe, which block, or take the data from whoever else reads them
+const PSEUDO_FS_MAGICS: &[i64] = &[
+    0x9fa0,     // proc
+    0x62656572, // sysfs
+    0x64626720, // debugfs
+    0x74726163, // tracefs
+];
+
+// The hash of a file's contents, if it has at most 'limit' bytes
+fn hash_file(path: &str, limit: u64) -> Option<String> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut contents = vec![];
+    // A FIFO which replaced the file mustn't hang us opening or reading it
+    fs::OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(path).ok()?
+        .take(limit + 1).read_to_end(&mut contents).ok()?;
+    if contents.len() as u64 > limit {
+        return None;
+    }
+    Some(sha256_hex(&contents))
+}
+
+// The contents are read through /proc/[pid]/fd/[fd], so they are those of the file the process
+// has open even if it has since been deleted or replaced, and the path is looked up in the
+// process' mount namespace, through /proc/[pid]/root
+fn file_hash(pid: u64, info: &FileInfo, limit: u64) -> Option<FileHash> {
+    // Pseudo files have no size, so those are left out with the rest of their filesystems
+    if info.file_type != FileType::Posix(PosixFileType::Regular) || info.stat.st_size == 0
+        || info.stat.st_size as u64 > limit {
+        return None;
+    }
+    let fd_path = proc_path!("{}/fd/{}", pid, info.fd);
+    let mut st: libc::statfs = unsafe { std::mem::zeroed() };
+    nix::sys::statfs::statfs(fd_path.as_str(), &mut st).ok()?;
+    if PSEUDO_FS_MAGICS.contains(&(st.f_type as i64)) {
+        return None;
+    }
+    let sha256 = hash_file(&fd_path, limit)?;
+    let path = info.link.as_deref().filter(|link| link.starts_with('/'))?;
+    let current = proc_path!("{}/root{}", pid, path.trim_end_matches(" (deleted)"));
+    let on_disk = match stat(current.as_str()) {
+        Ok(st) if (st.st_dev, st.st_ino) == (info.stat.st_dev, info.stat.st_ino) => OnDisk::Same,
+        Ok(st) if st.st_mode & SFlag::S_IFMT.bits() == SFlag::S_IFREG.bits() => {
+            OnDisk::Replaced(hash_file(&current, limit))
+        }
+        Ok(_) => OnDisk::Replaced(None),
+        Err(_) => OnDisk::Gone,
+    };
+    Some(FileHash { sha256, on_disk })
+}
+
+// The kinds of fd pfiles -c counts
+const FD_COUNT_TYPES: &[&str] = &[
+    "regular", "dir", "socket-tcp", "socket-udp", "socket-unix", "socket-other", "pipe", "anon", "device", "other",
+];
+
+// Which of FD_COUNT_TYPES an fd is, given its st_mode and, for sockets, what we know about them
+fn fd_count_type(mode: u32, socket: Option<&SockInfo>) -> &'static str {
+    match SFlag::from_bits_truncate(mode & SFlag::S_IFMT.bits()) {
+        // Anonymous inodes, like epoll or eventfd fds, have no file type
+        _ if mode & SFlag::S_IFMT.bits() == 0 => "anon",
+        SFlag::S_IFREG => "regular",
+        SFlag::S_IFDIR => "dir",
+        SFlag::S_IFIFO => "pipe",
+        SFlag::S_IFCHR | SFlag::S_IFBLK => "device",
+        SFlag::S_IFSOCK => match socket.map(|s| (s.family, s.sock_type)) {
+            Some((AddressFamily::Inet, SockType::Stream)) | Some((AddressFamily::Inet6, SockType::Stream)) => {
+                "socket-tcp"
+            }
+            Some((AddressFamily::Inet, SockType::Datagram)) | Some((AddressFamily::Inet6, SockType::Datagram)) => {
+                "socket-udp"
+            }
+            Some((AddressFamily::Unix, _)) => "socket-unix",
+            _ => "socket-other",
+        },
+        _ => "other",
+    }
+}
+
+// The soft and hard values of a limit from /proc/[pid]/limits, which has lines like
+// 'Max open files            1024                 524288               files'. None is unlimited.
+fn parse_limit(limits: &str, name: &str) -> Option<(Option<u64>, Option<u64>)> {
+    let line = limits.lines().find(|line| line.starts_with(name))?;
+    let mut fields = line[name.len()..].split_whitespace()
+        .map(|limit| if limit == "unlimited" { Ok(None) } else { limit.parse::<u64>().map(Some) });
+    Some((fields.next()?.ok()?, fields.next()?.ok()?))
+}
+
+// How many of the fds are of each of FD_COUNT_TYPES, and in all
+fn count_fds(pid: u64, fds: &[u64], sockets: &HashMap<u64, SockInfo>) -> (Vec<(&'static str, u64)>, u64) {
+    let mut counts = FD_COUNT_TYPES.iter().map(|&name| (name, 0)).collect::<Vec<_>>();
+    let mut total = 0;
+    for &fd in fds {
+        // The fd may have been closed since we listed them
+        if let Ok(st) = fd_stat(pid, fd) {
+            let kind = fd_count_type(st.st_mode, sockets.get(&st.st_ino));
+            counts.iter_mut().filter(|(name, _)| *name == kind).for_each(|(_, count)| *count += 1);
+            total += 1;
+        }
+    }
+    (counts, total)
+}
+
+fn fd_counts_str(counts: &[(&str, u64)], total: u64) -> String {
+    let counts = counts.iter().map(|(name, count)| format!("{}:{}", name, count)).collect::<Vec<_>>();
+    format!("{} total:{}", counts.join(" "), total)
+}
+
+fn fd_counts_json(counts: &[(&str, u64)]) -> Json {
+    Json::object(counts.iter().map(|&(name, count)| (name, Json::UInt(count))).collect())
+}
+
+// pfiles -c, for health checks: how many fds of each type a process has, and how close it is to
+// running out. Only the fds are stat()ed, so this is quick even with many of them.
+fn print_fd_counts(pid: u64, opts: &PfilesOptions) -> bool {
+    let fds = match read_fds(pid) {
+        Ok(fds) => fds,
+        Err(e) => {
+            eprintln!("Unable to read /proc/{}/fd/: {}", pid, e);
+            return false;
+        }
+    };
+    let fds = fds.into_iter().filter(|&fd| opts.want_fd(fd)).collect::<Vec<_>>();
+    let mounts = fs::read_to_string(proc_path!("{}/mountinfo", pid)).map(|contents| parse_mountinfo(&contents))
+        .unwrap_or_default();
+    let (fds, _) = skip_unresponsive_mounts(pid, fds, &mounts, opts.mount_timeout);
+    let (counts, total) = count_fds(pid, &fds, &fetch_sock_info(pid).unwrap_or_default());
+    let (soft, hard) = fs::read_to_string(proc_path!("{}/limits", pid)).ok()
+        .and_then(|limits| parse_limit(&limits, "Max open files"))
+        .unwrap_or((None, None));
+
+    if opts.json {
+        let limit = |limit: Option<u64>| limit.map_or(Json::Null, Json::UInt);
+        println!("{}", Json::object(vec![
+            ("pid", Json::UInt(pid)),
+            ("cmdline", Json::Str(cmd_summary(pid).trim_end().to_string())),
+            ("counts", fd_counts_json(&counts)),
+            ("total", Json::UInt(total)),
+            ("limit", Json::object(vec![("soft", limit(soft)), ("hard", limit(hard))])),
+        ]));
+        return true;
+    }
+
+    print_proc_summary(pid);
+    println!("  {}", fd_counts_str(&counts, total));
+    match soft {
+        Some(soft) if soft > 0 => println!("  {} of {} fds open ({}%), hard limit {}", total, soft, total * 100 / soft,
+                                          hard.map_or("unlimited".to_string(), |hard| hard.to_string())),
+        _ => println!("  {} fds open, no limit", total),
+    }
+    true
+}
+
+// A filesystem which didn't answer in time, such as an NFS mount whose server is gone, and the fds
+// on it, which are left alone rather than have pfiles hang on them
+struct UnresponsiveMount {
+    mount_point: String,
+    fs_type: String,
+    source: String,
+    fds: Vec<u64>,
+}
+
+impl UnresponsiveMount {
+    fn json(&self) -> Json {
+        Json::object(vec![
+            ("mount_point", Json::Str(self.mount_point.clone())),
+            ("fs_type", Json::Str(self.fs_type.clone())),
+            ("source", Json::Str(self.source.clone())),
+            ("fds", Json::Array(self.fds.iter().map(|&fd| Json::UInt(fd)).collect())),
+        ])
+    }
+}
+
+// Which mount each fd is on, by its id in mountinfo. fdinfo has it without touching the file.
+fn fd_mount_ids(pid: u64, fds: &[u64]) -> Vec<(u64, u64)> {
+    fds.iter().filter_map(|&fd| {
+        let fdinfo = fs::read_to_string(proc_path!("{}/fdinfo/{}", pid, fd)).ok()?;
+        Some((fd, fdinfo_field(&fdinfo, "mnt_id")?.parse().ok()?))
+    }).collect()
+}
+
+// The mounts, of (mount id, an fd on it), which don't answer a statfs() through the fd in time.
+// They are all asked at once, so this takes at most the timeout. A thread which is still waiting
+// is left to it: hung NFS and FUSE requests can be killed, so they go away when we exit.
+fn probe_mounts(pid: u64, probes: &[(u64, u64)], timeout: Duration) -> Vec<u64> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for &(id, fd) in probes {
+        let (sender, path) = (sender.clone(), proc_path!("{}/fd/{}", pid, fd));
+        thread::spawn(move || {
+            let mut st: libc::statfs = unsafe { std::mem::zeroed() };
+            // Failing is an answer too
+            let _ = nix::sys::statfs::statfs(path.as_str(), &mut st);
+            let _ = sender.send(id);
+        });
+    }
+    let deadline = Instant::now() + timeout;
+    let mut answered = vec![];
+    while answered.len() < probes.len() {
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(id) => answered.push(id),
+            Err(_) => break,
+        }
+    }
+    probes.iter().map(|&(id, _)| id).filter(|id| !answered.contains(id)).collect()
+}
+
+// Before looking at the files, check that the filesystems they are on answer, and leave out the
+// fds on those which don't, so that one dead server doesn't hang pfiles. Only mounts in the
+// process' mountinfo are asked: the kernel's own, for pipes, sockets and so on, can't hang.
+fn skip_unresponsive_mounts(pid: u64, fds: Vec<u64>, mounts: &[MountInfo], timeout: Option<Duration>)
+                            -> (Vec<u64>, Vec<UnresponsiveMount>) {
+    let timeout = match timeout {
+        Some(timeout) if !is_capture() => timeout,
+        _ => return (fds, vec![]),
+    };
+    let on = fd_mount_ids(pid, &fds);
+    let mut probes: Vec<(u64, u64)> = vec![];
+    for &(fd, id) in &on {
+        if mounts.iter().any(|mount| mount.id == id) && !probes.iter().any(|&(probed, _)| probed == id) {
+            probes.push((id, fd));
+        }
+    }
+    let unresponsive = probe_mounts(pid, &probes, timeout).into_iter()
+        .filter_map(|id| {
+            let mount = mounts.iter().find(|mount| mount.id == id)?;
+            Some(UnresponsiveMount {
+                mount_point: mount.mount_point.clone(),
+                fs_type: mount.fs_type.clone(),
+                source: mount.source.clone(),
+                fds: on.iter().filter(|&&(_, on_id)| on_id == id).map(|&(fd, _)| fd).collect(),
+            })
+        })
+        .collect::<Vec<_>>();
+    for mount in &unresponsive {
+        let fds = mount.fds.iter().map(|fd| fd.to_string()).collect::<Vec<_>>();
+        eprintln!("WARNING: the {} filesystem on {} ({}) didn't answer within {}s, so process {}'s fds on it \
+                   ({}) are left out", mount.fs_type, mount.mount_point, mount.source, timeout.as_secs_f64(), pid,
+                  fds.join(", "));
+    }
+    let fds = fds.into_iter().filter(|fd| !unresponsive.iter().any(|mount| mount.fds.contains(fd))).collect();
+    (fds, unresponsive)
+}
+
+// How much of a filesystem is used, in percent, as df shows it: of the blocks ordinary users may
+// use, leaving out those reserved for root. Rounded up, so that only a filesystem which is really
+// full is at 100%.
+fn fs_used_percent(blocks: u64, free: u64, available: u64) -> Option<u64> {
+    let used = blocks.saturating_sub(free);
+    match used + available {
+        0 => None,
+        usable => Some((used * 100).div_ceil(usable)),
+    }
+}
+
+// A filesystem which is nearly full, and the fds open for writing to files on it
+struct FullFilesystem {
+    mount_point: String,
+    used_percent: u64,
+    fds: Vec<u64>,
+}
+
+impl FullFilesystem {
+    fn json(&self) -> Json {
+        Json::object(vec![
+            ("mount_point", Json::Str(self.mount_point.clone())),
+            ("used_percent", Json::UInt(self.used_percent)),
+            ("fds", Json::Array(self.fds.iter().map(|&fd| Json::UInt(fd)).collect())),
+        ])
+    }
+}
+
+// The filesystems regular files open for writing are on which are at least threshold percent full,
+// to explain writes failing with ENOSPC. Each is asked once, through the first fd on it.
+fn full_filesystems(pid: u64, files: &[FileInfo], mounts: &[MountInfo], threshold: u64) -> Vec<FullFilesystem> {
+    let mut by_dev: Vec<(u64, Vec<u64>)> = vec![];
+    let writable = files.iter().filter(|info| info.file_type == FileType::Posix(PosixFileType::Regular)
+                                       && info.flags & libc::O_ACCMODE as u64 != libc::O_RDONLY as u64);
+    for info in writable {
+        match by_dev.iter_mut().find(|(dev, _)| *dev == info.stat.st_dev) {
+            Some((_, fds)) => fds.push(info.fd),
+            None => by_dev.push((info.stat.st_dev, vec![info.fd])),
+        }
+    }
+    by_dev.into_iter().filter_map(|(dev, fds)| {
+        let mut st: libc::statfs = unsafe { std::mem::zeroed() };
+        nix::sys::statfs::statfs(proc_path!("{}/fd/{}", pid, fds[0]).as_str(), &mut st).ok()?;
+        let used_percent = fs_used_percent(st.f_blocks as u64, st.f_bfree as u64, st.f_bavail as u64)
+            .filter(|&used| used >= threshold)?;
+        let dev = format!("{}:{}", major(dev), minor(dev));
+        let mount_point = mounts.iter().find(|mount| mount.dev == dev)
+            .map_or_else(|| format!("device {}", dev), |mount| mount.mount_point.clone());
+        Some(FullFilesystem { mount_point, used_percent, fds })
+    }).collect()
+}
+
+fn print_files(pid: u64, opts: &PfilesOptions, socket_owners: &SocketOwners) -> bool {
+
+    let proc_dir = proc_path!("{}/", pid);
+    if !Path::new(&proc_dir).exists() {
+        eprintln!("No such directory {}", &proc_dir);
+        return false;
+    }
+    if opts.counts {
+        return print_fd_counts(pid, opts);
+    }
+
+    if !opts.json {
+        print_proc_summary(pid);
+        // Files being created with the wrong permissions is usually down to the umask
+        let (umask, ioprio) = file_settings(pid);
+        let unknown = || "unknown".to_string();
+        println!("  umask: {}  I/O priority: {}", umask.unwrap_or_else(unknown), ioprio.unwrap_or_else(unknown));
+        // The fds can be no older than the process
+        if let Some(age) = process_age(pid).ok().filter(|_| opts.age) {
+            let started = std::time::SystemTime::now().checked_sub(age).map(time_str).unwrap_or_default();
+            println!("  started: {} ({})", started, ago_str(age.as_secs()));
+        }
+    }
+    // Files' owners are shown as we see them, which for containers with id mappings isn't who the
+    // process thinks owns them
+    let id_maps = read_id_maps(pid);
+    if let Some(ids) = id_maps.as_ref().and_then(|maps| user_namespace_ids_str(pid, maps)).filter(|_| !opts.json) {
+        println!("  user namespace: {}", ids);
+    }
+
+    // TODO print current rlimit
+
+    let mut sockets = match fetch_sock_info(pid) {
+        Ok(sockets) => sockets,
+        Err(e) => {
+            eprintln!("Unable to read sockets of process {}: {}", pid, e);
+            HashMap::new()
+        }
+    };
+    if opts.peers {
+        resolve_socket_peers(&mut sockets, socket_owners);
+    }
+
+    // Listing the fds makes their inodes, so note the time first
+    let looked = std::time::SystemTime::now();
+    let fds = match read_fds(pid) {
+        Ok(fds) => fds,
+        Err(e) => {
+            eprintln!("Unable to read /proc/{}/fd/: {}", pid, e);
+            return false;
+        }
+    };
+    let process_age = process_age(pid).ok();
+
+    let mounts = fs::read_to_string(proc_path!("{}/mountinfo", pid)).map(|contents| parse_mountinfo(&contents))
+        .unwrap_or_default();
+    // Layer directories in the mount options are as the filesystem's creator saw them, which is
+    // usually from the host, like us
+    let overlay_mounts = if opts.overlay { &mounts[..] } else { &[] };
+
+    // Which filesystem each file is on, from our point of view, since that's where we open it. A
+    // capture's files aren't there to open.
+    let our_mounts = match is_capture() {
+        true => vec![],
+        false => fs::read_to_string("/proc/self/mountinfo").map(|contents| parse_mountinfo(&contents))
+            .unwrap_or_default(),
+    };
+
+    // Collectors can't cope with the output for hundreds of thousands of fds, so with --limit, the
+    // rest are only counted
+    let fds = fds.into_iter().filter(|&fd| opts.want_fd(fd)).collect::<Vec<_>>();
+    let (fds, unresponsive) = skip_unresponsive_mounts(pid, fds, &mounts, opts.mount_timeout);
+    let truncated = match opts.limit {
+        Some(limit) if fds.len() > limit => Some(count_fds(pid, &fds, &sockets)),
+        _ => None,
+    };
+    let shown = opts.limit.unwrap_or(fds.len());
+    let creds = if opts.acl { read_file_credentials(pid) } else { None };
+
+    let files = fds.into_iter()
+        .take(shown)
+        .filter_map(|fd| file_info(pid, fd))
+        .map(|mut info| {
+            let dev = format!("{}:{}", major(info.stat.st_dev), minor(info.stat.st_dev));
+            if let Some(ref link) = info.link {
+                info.overlay = overlay_origin(link, &dev, info.stat.st_ino, overlay_mounts, &overlay_layer_ino);
+            }
+            let on_disk = matches!(info.file_type,
+                                   FileType::Posix(PosixFileType::Regular) | FileType::Posix(PosixFileType::Directory));
+            if let Some(mount) = our_mounts.iter().find(|m| m.dev == dev).filter(|_| on_disk && opts.quota) {
+                info.project = project_quota(pid, info.fd, mount);
+            }
+            if let Some(creds) = creds.as_ref().filter(|_| on_disk) {
+                info.acl = Some(file_acl(pid, info.fd, &info.stat, creds));
+            }
+            if let Some(limit) = opts.hash {
+                info.hash = file_hash(pid, &info, limit);
+            }
+            // A capture's files aren't there to look for
+            if !is_capture() {
+                resolve_inotify_paths(pid, &mut info.inotify.iter_mut().collect::<Vec<_>>(), &mounts);
+            }
+            if let Some(ref mut fanotify) = info.fanotify {
+                resolve_fanotify_mounts(fanotify, &mounts);
+            }
+            if opts.age {
+                info.age = Some(read_fd_age(pid, &info, process_age, looked));
+            }
+            if let Some((kind, inode)) = info.link.as_deref().and_then(parse_ns_link) {
+                info.namespace = Some(namespace_str(pid, kind, inode));
+            } else if info.file_type == FileType::Posix(PosixFileType::Socket) &&
+                !sockets.contains_key(&info.stat.st_ino) {
+                let (namespace, socket) = foreign_socket(pid, info.fd, info.stat.st_ino, opts.enter_ns);
+                info.namespace = Some(namespace);
+                info.foreign_socket = socket;
+            }
+            if let Some((ref uid_map, ref gid_map)) = id_maps {
+                info.owner_inside = Some((id_inside_str(uid_map, info.stat.st_uid),
+                                          id_inside_str(gid_map, info.stat.st_gid)));
+            }
+            info
+        });
+    let mut files = files.collect::<Vec<_>>();
+    if opts.join_ns {
+        resolve_ns_paths(pid, &mut files, &sockets);
+    }
+    // A capture's filesystems aren't there to ask
+    let full_filesystems = match is_capture() {
+        true => vec![],
+        false => full_filesystems(pid, &files, &our_mounts, opts.full_threshold),
+    };
+
+    if opts.json {
+        let mut json = files_json(pid, files.into_iter(), &sockets);
+        if let (Json::Object(ref mut fields), Some((counts, total))) = (&mut json, truncated) {
+            fields.push(("total".to_string(), Json::UInt(total)));
+            fields.push(("counts".to_string(), fd_counts_json(&counts)));
+        }
+        if let (Json::Object(ref mut fields), false) = (&mut json, unresponsive.is_empty()) {
+            fields.push(("unresponsive_mounts".to_string(),
+                         Json::Array(unresponsive.iter().map(UnresponsiveMount::json).collect())));
+        }
+        if let (Json::Object(ref mut fields), false) = (&mut json, full_filesystems.is_empty()) {
+            fields.push(("full_filesystems".to_string(),
+                         Json::Array(full_filesystems.iter().map(FullFilesystem::json).collect())));
+        }
+        println!("{}", json);
+    } else {
+        for info in &files {
+            print_file(info, &sockets);
+        }
+        if let Some((ref counts, total)) = truncated {
+            println!(" ... {} more fds not shown (--limit {})", total.saturating_sub(files.len() as u64), shown);
+            println!("  {}", fd_counts_str(counts, total));
+        }
+        print_shared_files(pid, &files);
+        for info in &files {
+            for warning in epoll_warnings(pid, info.fd, &info.epoll) {
+                println!(" WARNING: {}", warning);
+            }
+        }
+        // EDQUOT errors don't say which quota was hit, so point out the projects which are close
+        let mut full = files.iter()
+            .filter_map(|info| info.project.as_ref())
+            .filter(|project| project.usage.as_ref().is_some_and(quota_nearly_full))
+            .map(|project| project.id)
+            .collect::<Vec<_>>();
+        full.sort();
+        full.dedup();
+        for id in full {
+            let fds = files.iter().filter(|info| info.project.as_ref().is_some_and(|p| p.id == id))
+                .map(|info| info.fd.to_string()).collect::<Vec<_>>();
+            println!(" WARNING: project {} (fds {}) has used more than 90% of its quota, so writes may fail with \
+                      EDQUOT", id, fds.join(", "));
+        }
+        for fs in &full_filesystems {
+            let fds = fs.fds.iter().map(|fd| fd.to_string()).collect::<Vec<_>>();
+            println!(" WARNING: {} is {}% full, so writes through fds {} may fail with ENOSPC", fs.mount_point,
+                     fs.used_percent, fds.join(", "));
+        }
+        if let Some(interval) = opts.epoll_sample {
+            print_epoll_sample(pid, &files, interval);
+        }
+    }
+
+    true
+}
+
+// With --join-ns, look up the paths of files (other than deleted ones) and of UNIX sockets bound to
+// one as the process sees them, all at once
+fn resolve_ns_paths(pid: u64, files: &mut [FileInfo], sockets: &HashMap<u64, SockInfo>) {
+    let path = |info: &FileInfo| match info.file_type {
+        FileType::Posix(PosixFileType::Socket) => sockets.get(&info.stat.st_ino)
+            .and_then(|sock| sock.path.clone()).filter(|path| path.starts_with('/')).map(|path| (path, None)),
+        _ => info.link.clone().filter(|link| link.starts_with('/') && !link.ends_with(" (deleted)"))
+            .map(|link| (link, Some((info.stat.st_dev, info.stat.st_ino)))),
+    };
+    let wanted = files.iter().enumerate().filter_map(|(i, info)| Some((i, path(info)?))).collect::<Vec<_>>();
+    let paths = wanted.iter().map(|(_, path)| path.clone()).collect::<Vec<_>>();
+    if paths.is_empty() {
+        return;
+    }
+    if let Some(answers) = lookup_in_namespace(pid, &paths) {
+        for ((i, _), answer) in wanted.into_iter().zip(answers) {
+            files[i].ns_path = Some(answer);
+        }
+    }
+}
+
+// A row of lsof-style output: COMMAND, PID, USER, FD, TYPE, DEVICE, SIZE/OFF, NODE and NAME
+type LsofRow = [String; 9];
+
+// What pfiles --format prints
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PfilesFormat {
+    Pfiles,
+    // The columns of lsof -p
+    Lsof,
+    Csv,
+    Tsv,
+}
+
+const LSOF_HEADER: [&str; 9] = ["COMMAND", "PID", "USER", "FD", "TYPE", "DEVICE", "SIZE/OFF", "NODE", "NAME"];
+
+// lsof's FD column: the fd, followed by whether it was opened for reading (r), writing (w) or both (u)
+fn lsof_fd_str(fd: u64, flags: u64) -> String {
+    let mode = match flags & libc::O_ACCMODE as u64 {
+        0 => "r",
+        1 => "w",
+        _ => "u",
+    };
+    format!("{}{}", fd, mode)
+}
+
+fn lsof_dev_str(dev: u64) -> String {
+    format!("{},{}", major(dev), minor(dev))
+}
+
+// How lsof names an inet socket, such as '10.0.0.1:22->10.0.0.2:50312', or '*:8080' when listening
+fn lsof_inet_name(local: Option<SocketAddr>, peer: Option<SocketAddr>) -> String {
+    let addr_str = |addr: SocketAddr| {
+        let host = match addr.ip() {
+            ip if ip.is_unspecified() => "*".to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+            ip => ip.to_string(),
+        };
+        match addr.port() {
+            0 => format!("{}:*", host),
+            port => format!("{}:{}", host, port),
+        }
+    };
+    let mut name = local.map_or_else(|| "*:*".to_string(), addr_str);
+    if let Some(peer) = peer.filter(|peer| !peer.ip().is_unspecified() || peer.port() != 0) {
+        name += &format!("->{}", addr_str(peer));
+    }
+    name
+}
+
+// The TYPE, DEVICE, SIZE/OFF, NODE and NAME columns for an fd. Like lsof, regular files and
+// directories show their size, and everything else the fd's offset.
+fn lsof_file_columns(info: &FileInfo, sockets: &HashMap<u64, SockInfo>) -> [String; 5] {
+    let st = &info.stat;
+    let (dev, ino, size, offset) =
+        (lsof_dev_str(st.st_dev), st.st_ino.to_string(), st.st_size.to_string(), format!("0t{}", info.pos));
+    let link = info.link.clone().unwrap_or_default();
+    let (file_type, device, size_off, node, name) = match info.file_type {
+        FileType::Posix(PosixFileType::Regular) => ("REG", dev, size, ino, link),
+        FileType::Posix(PosixFileType::Directory) => ("DIR", dev, size, ino, link),
+        FileType::Posix(PosixFileType::SymLink) => ("LINK", dev, size, ino, link),
+        FileType::Posix(PosixFileType::CharDevice) => ("CHR", lsof_dev_str(st.st_rdev), offset, ino, link),
+        FileType::Posix(PosixFileType::BlockDevice) => ("BLK", lsof_dev_str(st.st_rdev), offset, ino, link),
+        FileType::Posix(PosixFileType::Fifo) if link.starts_with("pipe:") => ("FIFO", dev, offset, ino, "pipe".into()),
+        FileType::Posix(PosixFileType::Fifo) => ("FIFO", dev, offset, ino, link),
+        FileType::Posix(PosixFileType::Socket) => match sockets.get(&st.st_ino) {
+            // lsof puts the protocol where the inode would go, and the inode under DEVICE
+            Some(sock) if matches!(sock.family, AddressFamily::Inet | AddressFamily::Inet6) => {
+                let protocol = match sock.sock_type {
+                    SockType::Stream => "TCP",
+                    SockType::Datagram => "UDP",
+                    _ => "raw",
+                };
+                let family = if matches!(sock.family, AddressFamily::Inet) { "IPv4" } else { "IPv6" };
+                (family, ino, offset, protocol.to_string(), lsof_inet_name(sock.local_addr, sock.peer_addr))
+            }
+            Some(sock) if matches!(sock.family, AddressFamily::Unix) => {
+                let name = format!("type={}", sock_type_str(sock.sock_type).trim_start_matches("SOCK_"));
+                ("unix", dev, offset, ino, name)
+            }
+            Some(sock) if matches!(sock.family, AddressFamily::Netlink) => ("netlink", dev, offset, ino, "netlink".into()),
+            Some(sock) => {
+                let name = format!("protocol: {}", address_family_str(sock.family).trim_start_matches("AF_"));
+                ("sock", dev, offset, ino, name)
+            }
+            None => ("sock", dev, offset, ino, "can't identify protocol".into()),
+        },
+        FileType::Anon(_) => ("a_inode", dev, offset, ino, link.trim_start_matches("anon_inode:").to_string()),
+        FileType::Posix(PosixFileType::Unknown(_)) | FileType::Unknown => ("unknown", dev, offset, ino, link),
+    };
+    [file_type.to_string(), device, size_off, node, name]
+}
+
+// The rows lsof -p would print for a process: its working directory, root and executable, the
+// other files it has mapped, and its fds. With --fd, only the fds.
+fn lsof_rows(pid: u64, opts: &PfilesOptions) -> Option<Vec<LsofRow>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let proc_dir = proc_path!("{}/", pid);
+    if !Path::new(&proc_dir).exists() {
+        eprintln!("No such directory {}", &proc_dir);
+        return None;
+    }
+
+    // Like lsof, only the first 9 characters of the command
+    let command = fs::read_to_string(proc_path!("{}/comm", pid))
+        .map(|comm| comm.trim_end_matches('\n').chars().take(9).collect::<String>())
+        .unwrap_or_else(|_| "?".to_string());
+    let user = ProcStat::read(pid).ok()
+        .and_then(|status| status.get_field("Uid").ok()?.split_whitespace().next()?.parse::<u32>().ok())
+        .map_or_else(|| "?".to_string(), user_name);
+    let row = |fd: &str, [file_type, device, size_off, node, name]: [String; 5]| -> LsofRow {
+        [command.clone(), pid.to_string(), user.clone(), fd.to_string(), file_type, device, size_off, node, name]
+    };
+
+    let mut rows = vec![];
+    if opts.fd_ranges.is_empty() {
+        let mut exe = None;
+        for &(fd, link) in &[("cwd", "cwd"), ("rtd", "root"), ("txt", "exe")] {
+            let path = proc_path!("{}/{}", pid, link);
+            let name = match read_proc_link(&path) {
+                Ok(name) => name.to_string_lossy().into_owned(),
+                Err(e) => {
+                    let blank = String::new;
+                    rows.push(row(fd, ["unknown".into(), blank(), blank(), blank(),
+                                       format!("/proc/{}/{} (readlink: {})", pid, link, e)]));
+                    continue;
+                }
+            };
+            // A capture only has the link's target
+            let meta = fs::metadata(&path).ok().filter(|_| !is_capture());
+            let file_type = if meta.as_ref().is_some_and(|meta| meta.is_dir()) { "DIR" } else { "REG" };
+            let field = |f: &dyn Fn(&fs::Metadata) -> String| meta.as_ref().map(f).unwrap_or_default();
+            rows.push(row(fd, [file_type.into(), field(&|meta| lsof_dev_str(meta.dev())),
+                               field(&|meta| meta.size().to_string()), field(&|meta| meta.ino().to_string()),
+                               name.clone()]));
+            if link == "exe" {
+                exe = Some(name);
+            }
+        }
+
+        let mappings = File::open(proc_path!("{}/maps", pid)).ok()
+            .and_then(|maps| parse_smaps(BufReader::new(maps)).ok())
+            .unwrap_or_default();
+        let mut seen = vec![];
+        for mapping in mappings.iter().filter(|m| m.path.starts_with('/') && Some(&m.path) != exe.as_ref()) {
+            if seen.contains(&(mapping.dev, mapping.inode)) {
+                continue;
+            }
+            seen.push((mapping.dev, mapping.inode));
+            // lsof marks mapped files which have been deleted, and so can't be looked at, as DEL
+            let (fd, path) = match mapping.path.strip_suffix(" (deleted)") {
+                Some(path) => ("DEL", path),
+                None => ("mem", &mapping.path[..]),
+            };
+            let size = match fd == "DEL" || is_capture() {
+                true => String::new(),
+                false => fs::metadata(path).ok().filter(|meta| meta.ino() == mapping.inode)
+                    .map(|meta| meta.size().to_string()).unwrap_or_default(),
+            };
+            rows.push(row(fd, ["REG".into(), format!("{},{}", mapping.dev.0, mapping.dev.1), size,
+                               mapping.inode.to_string(), path.to_string()]));
+        }
+    }
+
+    let sockets = match fetch_sock_info(pid) {
+        Ok(sockets) => sockets,
+        Err(e) => {
+            eprintln!("Unable to read sockets of process {}: {}", pid, e);
+            HashMap::new()
+        }
+    };
+    let fds = match read_fds(pid) {
+        Ok(fds) => fds,
+        Err(e) => {
+            eprintln!("Unable to read /proc/{}/fd/: {}", pid, e);
+            return None;
+        }
+    };
+    let mounts = fs::read_to_string(proc_path!("{}/mountinfo", pid)).map(|contents| parse_mountinfo(&contents))
+        .unwrap_or_default();
+    let fds = fds.into_iter().filter(|&fd| opts.want_fd(fd)).collect();
+    let (fds, _) = skip_unresponsive_mounts(pid, fds, &mounts, opts.mount_timeout);
+    for info in fds.into_iter().filter_map(|fd| file_info(pid, fd)) {
+        rows.push(row(&lsof_fd_str(info.fd, info.flags), lsof_file_columns(&info, &sockets)));
+    }
+    Some(rows)
+}
+
+// The columns pfiles --format csv and tsv print, one row per fd, named like the fields of --json.
+// Spreadsheets and scripts go by these, so new ones only go at the end.
+const CSV_HEADER: [&str; 15] = ["pid", "comm", "fd", "type", "flags", "path", "size", "offset", "dev", "ino", "mode",
+                                "uid", "gid", "rdev", "socket"];
+
+// A field quoted as RFC 4180 says, when it has to be
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// TSV can't quote, so tabs and line breaks are escaped as databases' text formats do
+fn tsv_field(field: &str) -> String {
+    field.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn delimited_line(row: &[String], format: PfilesFormat) -> String {
+    match format {
+        PfilesFormat::Tsv => row.iter().map(|field| tsv_field(field)).collect::<Vec<_>>().join("\t"),
+        _ => row.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(","),
+    }
+}
+
+// A row of CSV_HEADER for each fd of a process
+fn csv_rows(pid: u64, opts: &PfilesOptions) -> Option<Vec<Vec<String>>> {
+    let comm = match fs::read_to_string(proc_path!("{}/comm", pid)) {
+        Ok(comm) => comm.trim_end_matches('\n').to_string(),
+        Err(e) => {
+            eprintln!("Unable to read /proc/{}/comm: {}", pid, e);
+            return None;
+        }
+    };
+    let sockets = match fetch_sock_info(pid) {
+        Ok(sockets) => sockets,
+        Err(e) => {
+            eprintln!("Unable to read sockets of process {}: {}", pid, e);
+            HashMap::new()
+        }
+    };
+    let fds = match read_fds(pid) {
+        Ok(fds) => fds,
+        Err(e) => {
+            eprintln!("Unable to read /proc/{}/fd/: {}", pid, e);
+            return None;
+        }
+    };
+    let mounts = fs::read_to_string(proc_path!("{}/mountinfo", pid)).map(|contents| parse_mountinfo(&contents))
+        .unwrap_or_default();
+    let fds = fds.into_iter().filter(|&fd| opts.want_fd(fd)).collect();
+    let (fds, _) = skip_unresponsive_mounts(pid, fds, &mounts, opts.mount_timeout);
+    let rows = fds.into_iter().filter_map(|fd| file_info(pid, fd)).map(|info| {
+        let st = &info.stat;
+        // Devices have no size, but the device they are instead
+        let (size, rdev) = match st.st_rdev {
+            0 => (st.st_size.to_string(), String::new()),
+            rdev => (String::new(), lsof_dev_str(rdev)),
+        };
+        // What lsof names a socket by, e.g. its addresses
+        let socket = match info.file_type {
+            FileType::Posix(PosixFileType::Socket) => lsof_file_columns(&info, &sockets)[4].clone(),
+            _ => String::new(),
+        };
+        vec![pid.to_string(), comm.clone(), info.fd.to_string(), print_file_type(&info.file_type),
+             open_flags_strs(info.flags).join("|"), info.link.clone().unwrap_or_default(), size, info.pos.to_string(),
+             lsof_dev_str(st.st_dev), st.st_ino.to_string(), format!("{:o}", st.st_mode & 0o7777),
+             st.st_uid.to_string(), st.st_gid.to_string(), rdev, socket]
+    }).collect();
+    Some(rows)
+}
+
+// Lay rows out in columns as lsof does, each as wide as its widest entry, with the numbers right
+// aligned
+fn lsof_table(rows: &[LsofRow], header: bool) -> String {
+    let header_row = LSOF_HEADER.map(String::from);
+    let all = header.then_some(&header_row).into_iter().chain(rows).collect::<Vec<_>>();
+    let mut widths = [0; 9];
+    for row in &all {
+        for (width, col) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(col.chars().count());
+        }
+    }
+
+    let right_aligned = [1, 3, 5, 6, 7];
+    let mut table = String::new();
+    for row in all {
+        let line = row.iter().zip(widths.iter()).enumerate()
+            .map(|(i, (col, &width))| match i {
+                8 => col.clone(),
+                _ if right_aligned.contains(&i) => format!("{:>1$}", col, width),
+                _ => format!("{:<1$}", col, width),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        table += line.trim_end();
+        table.push('\n');
+    }
+    table
+}
+
+// The kinds of kernel objects kcmp(2) can compare (see include/uapi/linux/kcmp.h). KCMP_FILE
+// compares open file descriptions; the rest compare per-task state which clone() can share.
+const KCMP_FILE: i32 = 0;
+const KCMP_VM: i32 = 1;
+const KCMP_FILES: i32 = 2;
+const KCMP_FS: i32 = 3;
+const KCMP_SIGHAND: i32 = 4;
+const KCMP_IO: i32 = 5;
+const KCMP_SYSVSEM: i32 = 6;
+
+// Use kcmp(2) to check whether a kernel object used by two processes is the same. Which object is
+// compared depends on kcmp_type; idx1 and idx2 pick e.g. an fd of each process.
+fn kcmp(pid1: u64, pid2: u64, kcmp_type: i32, idx1: u64, idx2: u64) -> nix::Result<bool> {
+    let res = unsafe {
+        libc::syscall(libc::SYS_kcmp, pid1 as libc::pid_t, pid2 as libc::pid_t, kcmp_type,
+                      idx1 as libc::c_ulong, idx2 as libc::c_ulong)
+    };
+    // kcmp() orders objects so that they can be sorted; 0 means they are the same
+    nix::errno::Errno::result(res).map(|res| res == 0)
+}
+
+// Groups of fds which were dup'd from one another, i.e. which share an open file description, and
+// so also share the file offset and flags like O_NONBLOCK and O_APPEND.
+struct SharedFiles {
+    groups: Vec<Vec<u64>>,
+    // False if kcmp() wasn't permitted, and the groups are a guess based on inode, offset and flags
+    exact: bool,
+}
+
+// Only fds referring to the same file can share an open file description, so only those need to be
+// compared.
+fn find_shared_files(pid: u64, files: &[FileInfo]) -> SharedFiles {
+    let mut by_inode: HashMap<(u64, u64), Vec<&FileInfo>> = HashMap::new();
+    for info in files {
+        by_inode.entry((info.stat.st_dev, info.stat.st_ino)).or_default().push(info);
+    }
+
+    let mut exact = true;
+    let mut groups = vec![];
+    for candidates in by_inode.values().filter(|c| c.len() > 1) {
+        let mut inode_groups: Vec<Vec<&FileInfo>> = vec![];
+        for &info in candidates {
+            let group = inode_groups.iter_mut().find(|group| {
+                let other = group[0];
+                if exact {
+                    match kcmp(pid, pid, KCMP_FILE, other.fd, info.fd) {
+                        Ok(same) => return same,
+                        // Not permitted (we need to be able to ptrace the process) or not supported
+                        // by the kernel. Fall back to guessing for this and all remaining fds.
+                        Err(_) => exact = false,
+                    }
+                }
+                other.pos == info.pos && other.flags == info.flags
+            });
+            match group {
+                Some(group) => group.push(info),
+                None => inode_groups.push(vec![info]),
+            }
+        }
+        groups.extend(inode_groups.into_iter()
+            .filter(|group| group.len() > 1)
+            .map(|group| group.iter().map(|info| info.fd).collect::<Vec<_>>()));
+    }
+    for group in groups.iter_mut() {
+        group.sort();
+    }
+    groups.sort();
+
+    SharedFiles { groups, exact }
+}
+
+fn is_stdio(fd: u64) -> bool {
+    fd <= 2
+}
+
+fn fd_list(fds: &[u64]) -> String {
+    fds.iter().map(|fd| fd.to_string()).collect::<Vec<_>>().join(",")
+}
+
+// Print the groups of dup'd fds, and warn about ones which commonly cause confusion
+fn print_shared_files(pid: u64, files: &[FileInfo]) {
+    let shared = find_shared_files(pid, files);
+    let info_of = |fd: u64| files.iter().find(|info| info.fd == fd).unwrap();
+    let path_of = |info: &FileInfo| info.link.clone().unwrap_or_else(|| "<unknown>".to_string());
+
+    if !shared.groups.is_empty() {
+        if shared.exact {
+            println!(" shared open file descriptions:");
+        } else {
+            println!(" shared open file descriptions (guessed from inode, offset and flags):");
+        }
+        for group in &shared.groups {
+            println!("   {}: {}", fd_list(group), path_of(info_of(group[0])));
+        }
+    }
+
+    // A regular file shared between one of stdin/stdout/stderr and some other fd usually means that
+    // output was redirected to a log file which the program also has open, e.g. with dup2(). They
+    // share a single offset, so data written through one lands wherever the other left off.
+    for group in &shared.groups {
+        let info = info_of(group[0]);
+        let stdio = group.iter().cloned().filter(|&fd| is_stdio(fd)).collect::<Vec<_>>();
+        let others = group.iter().cloned().filter(|&fd| !is_stdio(fd)).collect::<Vec<_>>();
+        if !stdio.is_empty() && !others.is_empty() &&
+            SFlag::from_bits_truncate(info.stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFREG {
+            println!(" WARNING: fds {} and {} share an open file description of {}, so they share \
+                      a file offset", fd_list(&stdio), fd_list(&others), path_of(info));
+        }
+    }
+
+    // O_NONBLOCK belongs to the open file description. Separate opens of the same device or pipe
+    // can disagree about it, which is easy to miss since setting it on one fd also changes every fd
+    // dup'd from it.
+    let mut by_inode: HashMap<(u64, u64), Vec<&FileInfo>> = HashMap::new();
+    for info in files {
+        let file_type = SFlag::from_bits_truncate(info.stat.st_mode) & SFlag::S_IFMT;
+        if file_type != SFlag::S_IFREG && file_type != SFlag::S_IFDIR {
+            by_inode.entry((info.stat.st_dev, info.stat.st_ino)).or_default().push(info);
+        }
+    }
+    let is_nonblock = |info: &FileInfo| info.flags & OFlag::O_NONBLOCK.bits() as u64 != 0;
+    let mut mixed = by_inode.values()
+        .map(|infos| {
+            let (nonblock, block): (Vec<&FileInfo>, Vec<&FileInfo>) =
+                infos.iter().partition(|info| is_nonblock(info));
+            let fds = |infos: Vec<&FileInfo>| infos.iter().map(|info| info.fd).collect::<Vec<_>>();
+            (infos[0], fds(nonblock), fds(block))
+        })
+        .filter(|(_, nonblock, block)| !nonblock.is_empty() && !block.is_empty())
+        .collect::<Vec<_>>();
+    mixed.sort_by_key(|&(info, _, _)| info.fd);
+    for (info, nonblock, block) in mixed {
+        println!(" WARNING: {} is open both non-blocking (fds {}) and blocking (fds {})",
+                 path_of(info), fd_list(&nonblock), fd_list(&block));
+    }
+}
+
+// Look for the usual causes of an event loop spinning: watching a file which will always be ready,
+// and watching a file through an fd which has since been closed. The latter happens when the file
+// is still open through a dup'd fd (or in a child process), so epoll still reports its events,
+// but the program can no longer epoll_ctl() it away.
+fn epoll_warnings(pid: u64, epoll_fd: u64, watches: &[EpollWatch]) -> Vec<String> {
+    let mut warnings = vec![];
+    for watch in watches {
+        let current = fd_stat(pid, watch.tfd).ok();
+        match current {
+            Some(ref st) if watch.ino == 0 || st.st_ino == watch.ino => {}
+            _ => warnings.push(format!(
+                "epoll fd {} still watches a file added as fd {}, which has since been {}; its events can't be \
+                 removed with epoll_ctl()", epoll_fd, watch.tfd,
+                if current.is_some() { "reused for another file" } else { "closed" })),
+        }
+
+        // Pipes and sockets with room in their buffers are writable almost all the time
+        let always_ready = libc::EPOLLOUT as u32;
+        if watch.events & always_ready != 0 && watch.events & libc::EPOLLET as u32 == 0
+            && watch.events & libc::EPOLLONESHOT as u32 == 0 {
+            warnings.push(format!(
+                "epoll fd {} watches fd {} for EPOLLOUT level-triggered, so epoll_wait() returns \
+                 immediately for as long as it is writable", epoll_fd, watch.tfd));
+        }
+    }
+    warnings
+}
+
+// What we can tell about how active a watched file is from /proc/[pid]/fdinfo. There are no
+// counters of wakeups, but eventfds and timerfds show events waiting to be read, and regular files
+// and ttys show how far they have been read.
+#[derive(Debug, Default, PartialEq)]
+struct WatchActivity {
+    pos: u64,
+    // Events which have happened and not been read yet, for eventfds and timerfds
+    pending: Option<u64>,
+}
+
+fn parse_watch_activity(fdinfo: &str) -> WatchActivity {
+    let field = |name: &str| fdinfo.lines().find(|line| line.starts_with(name)).map(|line| line[name.len()..].trim());
+    WatchActivity {
+        pos: field("pos:").and_then(|pos| pos.parse().ok()).unwrap_or(0),
+        pending: field("eventfd-count:").and_then(|count| u64::from_str_radix(count, 16).ok())
+            .or_else(|| field("ticks:").and_then(|ticks| ticks.parse().ok())),
+    }
+}
+
+// Time spent on CPU by all threads of a process, in clock ticks
+fn process_cpu_ticks(pid: u64) -> Option<u64> {
+    let stat = fs::read_to_string(proc_path!("{}/stat", pid)).ok()?;
+    let utime = stat_field(&stat, 14)?.parse::<u64>().ok()?;
+    let stime = stat_field(&stat, 15)?.parse::<u64>().ok()?;
+    Some(utime + stime)
+}
+
+// Sample the files watched by each epoll fd twice, and report which of them show signs of the
+// activity which would keep waking up an event loop.
+fn print_epoll_sample(pid: u64, files: &[FileInfo], interval: Duration) {
+    let watched = files.iter()
+        .flat_map(|info| info.epoll.iter().map(move |watch| (info.fd, watch.tfd)))
+        .collect::<Vec<_>>();
+    if watched.is_empty() {
+        return;
+    }
+
+    let sample = || watched.iter()
+        .map(|&(_, tfd)| fs::read_to_string(proc_path!("{}/fdinfo/{}", pid, tfd)).ok()
+             .map(|fdinfo| parse_watch_activity(&fdinfo)))
+        .collect::<Vec<_>>();
+    let cpu_before = process_cpu_ticks(pid);
+    let before = sample();
+    thread::sleep(interval);
+    let after = sample();
+    let cpu_after = process_cpu_ticks(pid);
+
+    let ticks_per_sec = clock_ticks_per_sec();
+    let cpu = match (cpu_before, cpu_after) {
+        (Some(before), Some(after)) if interval.as_millis() > 0 => format!(
+            ", process used {}% of a CPU",
+            after.saturating_sub(before) * 1000 * 100 / ticks_per_sec / interval.as_millis() as u64),
+        _ => String::new(),
+    };
+    println!(" epoll activity over {}ms{}:", interval.as_millis(), cpu);
+
+    // Most active first: files read from the most, then ones with events pending the whole time
+    let mut activity = watched.iter().zip(before.iter().zip(after.iter()))
+        .filter_map(|(&(epoll_fd, tfd), (before, after))| match (before, after) {
+            (Some(before), Some(after)) => Some((epoll_fd, tfd, before, after)),
+            _ => None,
+        })
+        .map(|(epoll_fd, tfd, before, after)| {
+            let read = after.pos.saturating_sub(before.pos);
+            let pending = before.pending.unwrap_or(0) > 0 && after.pending.unwrap_or(0) > 0;
+            (read, pending, epoll_fd, tfd, after.pending)
+        })
+        .collect::<Vec<_>>();
+    activity.sort_by(|a, b| (b.0, b.1).cmp(&(a.0, a.1)).then(a.3.cmp(&b.3)));
+
+    for (read, pending, epoll_fd, tfd, count) in activity {
+        let mut notes = vec![];
+        if read > 0 {
+            notes.push(format!("read {} bytes", read));
+        }
+        if pending {
+            notes.push(format!("{} events waiting to be read at both samples", count.unwrap_or(0)));
+        }
+        if notes.is_empty() {
+            notes.push("no activity visible in fdinfo".to_string());
+        }
+        println!("   fd {} (epoll fd {}): {}", tfd, epoll_fd, notes.join(", "));
+    }
+}
+
+// Returns the open fds of a process, in ascending order
+fn read_fds(pid: u64) -> Result<Vec<u64>, Box<dyn Error>> {
+    let mut fds = vec![];
+    for entry in fs::read_dir(proc_path!("{}/fd/", pid))? {
+        let entry = entry?;
+        let filename = entry.file_name();
+        let filename = filename.to_string_lossy();
+        if let Ok(fd) = filename.parse::<u64>() {
+            fds.push(fd);
+        } else {
+            eprintln!("Unexpected file /proc/[pid]/fd/{} found", &filename);
+        }
+    }
+    fds.sort();
+    Ok(fds)
+}
+
+fn files_json<I: Iterator<Item = FileInfo>>(pid: u64, files: I, sockets: &HashMap<u64, SockInfo>) -> Json {
+    let files = files.map(|info| file_json(&info, sockets)).collect();
+    let (umask, ioprio) = file_settings(pid);
+    let opt_str = |s: Option<String>| s.map_or(Json::Null, Json::Str);
+    Json::object(vec![
+        ("pid", Json::UInt(pid)),
+        ("cmdline", Json::Str(cmd_summary(pid).trim_end().to_string())),
+        ("umask", opt_str(umask)),
+        ("ioprio", opt_str(ioprio)),
+        ("files", Json::Array(files)),
+    ])
+}
+
+// Names of system calls, indexed by syscall number. These differ between architectures; the tables
+// here are generated from the kernel's asm/unistd_64.h (for x86_64) and asm-generic/unistd.h (for
+// aarch64, which uses the generic syscall numbering). Empty strings are unassigned numbers, and on
+// aarch64 also the time64 calls (403-423), which only 32-bit ABIs have.
+#[cfg(target_arch = "x86_64")]
+const SYSCALL_NAMES: &[&str] = &[
+    /*   0 */ "read", "write", "open", "close",
+    /*   4 */ "stat", "fstat", "lstat", "poll",
+    /*   8 */ "lseek", "mmap", "mprotect", "munmap",
+    /*  12 */ "brk", "rt_sigaction", "rt_sigprocmask", "rt_sigreturn",
+    /*  16 */ "ioctl", "pread64", "pwrite64", "readv",
+    /*  20 */ "writev", "access", "pipe", "select",
+    /*  24 */ "sched_yield", "mremap", "msync", "mincore",
+    /*  28 */ "madvise", "shmget", "shmat", "shmctl",
+    /*  32 */ "dup", "dup2", "pause", "nanosleep",
+    /*  36 */ "getitimer", "alarm", "setitimer", "getpid",
+    /*  40 */ "sendfile", "socket", "connect", "accept",
+    /*  44 */ "sendto", "recvfrom", "sendmsg", "recvmsg",
+    /*  48 */ "shutdown", "bind", "listen", "getsockname",
+    /*  52 */ "getpeername", "socketpair", "setsockopt", "getsockopt",
+    /*  56 */ "clone", "fork", "vfork", "execve",
+    /*  60 */ "exit", "wait4", "kill", "uname",
+    /*  64 */ "semget", "semop", "semctl", "shmdt",
+    /*  68 */ "msgget", "msgsnd", "msgrcv", "msgctl",
+    /*  72 */ "fcntl", "flock", "fsync", "fdatasync",
+    /*  76 */ "truncate", "ftruncate", "getdents", "getcwd",
+    /*  80 */ "chdir", "fchdir", "rename", "mkdir",
+    /*  84 */ "rmdir", "creat", "link", "unlink",
+    /*  88 */ "symlink", "readlink", "chmod", "fchmod",
+    /*  92 */ "chown", "fchown", "lchown", "umask",
+    /*  96 */ "gettimeofday", "getrlimit", "getrusage", "sysinfo",
+    /* 100 */ "times", "ptrace", "getuid", "syslog",
+    /* 104 */ "getgid", "setuid", "setgid", "geteuid",
+    /* 108 */ "getegid", "setpgid", "getppid", "getpgrp",
+    /* 112 */ "setsid", "setreuid", "setregid", "getgroups",
+    /* 116 */ "setgroups", "setresuid", "getresuid", "setresgid",
+    /* 120 */ "getresgid", "getpgid", "setfsuid", "setfsgid",
+    /* 124 */ "getsid", "capget", "capset", "rt_sigpending",
+    /* 128 */ "rt_sigtimedwait", "rt_sigqueueinfo", "rt_sigsuspend", "sigaltstack",
+    /* 132 */ "utime", "mknod", "uselib", "personality",
+    /* 136 */ "ustat", "statfs", "fstatfs", "sysfs",
+    /* 140 */ "getpriority", "setpriority", "sched_setparam", "sched_getparam",
+    /* 144 */ "sched_setscheduler", "sched_getscheduler", "sched_get_priority_max", "sched_get_priority_min",
+    /* 148 */ "sched_rr_get_interval", "mlock", "munlock", "mlockall",
+    /* 152 */ "munlockall", "vhangup", "modify_ldt", "pivot_root",
+    /* 156 */ "_sysctl", "prctl", "arch_prctl", "adjtimex",
+    /* 160 */ "setrlimit", "chroot", "sync", "acct",
+    /* 164 */ "settimeofday", "mount", "umount2", "swapon",
+    /* 168 */ "swapoff", "reboot", "sethostname", "setdomainname",
+    /* 172 */ "iopl", "ioperm", "create_module", "init_module",
+    /* 176 */ "delete_module", "get_kernel_syms", "query_module", "quotactl",
+    /* 180 */ "nfsservctl", "getpmsg", "putpmsg", "afs_syscall",
+    /* 184 */ "tuxcall", "security", "gettid", "readahead",
+    /* 188 */ "setxattr", "lsetxattr", "fsetxattr", "getxattr",
+    /* 192 */ "lgetxattr", "fgetxattr", "listxattr", "llistxattr",
+    /* 196 */ "flistxattr", "removexattr", "lremovexattr", "fremovexattr",
+    /* 200 */ "tkill", "time", "futex", "sched_setaffinity",
+    /* 204 */ "sched_getaffinity", "set_thread_area", "io_setup", "io_destroy",
+    /* 208 */ "io_getevents", "io_submit", "io_cancel", "get_thread_area",
+    /* 212 */ "lookup_dcookie", "epoll_create", "epoll_ctl_old", "epoll_wait_old",
+    /* 216 */ "remap_file_pages", "getdents64", "set_tid_address", "restart_syscall",
+    /* 220 */ "semtimedop", "fadvise64", "timer_create", "timer_settime",
+    /* 224 */ "timer_gettime", "timer_getoverrun", "timer_delete", "clock_settime",
+    /* 228 */ "clock_gettime", "clock_getres", "clock_nanosleep", "exit_group",
+    /* 232 */ "epoll_wait", "epoll_ctl", "tgkill", "utimes",
+    /* 236 */ "vserver", "mbind", "set_mempolicy", "get_mempolicy",
+    /* 240 */ "mq_open", "mq_unlink", "mq_timedsend", "mq_timedreceive",
+    /* 244 */ "mq_notify", "mq_getsetattr", "kexec_load", "waitid",
+    /* 248 */ "add_key", "request_key", "keyctl", "ioprio_set",
+    /* 252 */ "ioprio_get", "inotify_init", "inotify_add_watch", "inotify_rm_watch",
+    /* 256 */ "migrate_pages", "openat", "mkdirat", "mknodat",
+    /* 260 */ "fchownat", "futimesat", "newfstatat", "unlinkat",
+    /* 264 */ "renameat", "linkat", "symlinkat", "readlinkat",
+    /* 268 */ "fchmodat", "faccessat", "pselect6", "ppoll",
+    /* 272 */ "unshare", "set_robust_list", "get_robust_list", "splice",
+    /* 276 */ "tee", "sync_file_range", "vmsplice", "move_pages",
+    /* 280 */ "utimensat", "epoll_pwait", "signalfd", "timerfd_create",
+    /* 284 */ "eventfd", "fallocate", "timerfd_settime", "timerfd_gettime",
+    /* 288 */ "accept4", "signalfd4", "eventfd2", "epoll_create1",
+    /* 292 */ "dup3", "pipe2", "inotify_init1", "preadv",
+    /* 296 */ "pwritev", "rt_tgsigqueueinfo", "perf_event_open", "recvmmsg",
+    /* 300 */ "fanotify_init", "fanotify_mark", "prlimit64", "name_to_handle_at",
+    /* 304 */ "open_by_handle_at", "clock_adjtime", "syncfs", "sendmmsg",
+    /* 308 */ "setns", "getcpu", "process_vm_readv", "process_vm_writev",
+    /* 312 */ "kcmp", "finit_module", "sched_setattr", "sched_getattr",
+    /* 316 */ "renameat2", "seccomp", "getrandom", "memfd_create",
+    /* 320 */ "kexec_file_load", "bpf", "execveat", "userfaultfd",
+    /* 324 */ "membarrier", "mlock2", "copy_file_range", "preadv2",
+    /* 328 */ "pwritev2", "pkey_mprotect", "pkey_alloc", "pkey_free",
+    /* 332 */ "statx", "io_pgetevents", "rseq", "",
+    /* 336 */ "", "", "", "",
+    /* 340 */ "", "", "", "",
+    /* 344 */ "", "", "", "",
+    /* 348 */ "", "", "", "",
+    /* 352 */ "", "", "", "",
+    /* 356 */ "", "", "", "",
+    /* 360 */ "", "", "", "",
+    /* 364 */ "", "", "", "",
+    /* 368 */ "", "", "", "",
+    /* 372 */ "", "", "", "",
+    /* 376 */ "", "", "", "",
+    /* 380 */ "", "", "", "",
+    /* 384 */ "", "", "", "",
+    /* 388 */ "", "", "", "",
+    /* 392 */ "", "", "", "",
+    /* 396 */ "", "", "", "",
+    /* 400 */ "", "", "", "",
+    /* 404 */ "", "", "", "",
+    /* 408 */ "", "", "", "",
+    /* 412 */ "", "", "", "",
+    /* 416 */ "", "", "", "",
+    /* 420 */ "", "", "", "",
+    /* 424 */ "pidfd_send_signal", "io_uring_setup", "io_uring_enter", "io_uring_register",
+    /* 428 */ "open_tree", "move_mount", "fsopen", "fsconfig",
+    /* 432 */ "fsmount", "fspick", "pidfd_open", "clone3",
+    /* 436 */ "close_range", "openat2", "pidfd_getfd", "faccessat2",
+    /* 440 */ "process_madvise", "epoll_pwait2", "mount_setattr", "quotactl_fd",
+    /* 444 */ "landlock_create_ruleset", "landlock_add_rule", "landlock_restrict_self", "memfd_secret",
+    /* 448 */ "process_mrelease", "futex_waitv", "set_mempolicy_home_node",
+];
+
+#[cfg(target_arch = "aarch64")]
+const SYSCALL_NAMES: &[&str] = &[
+    /*   0 */ "io_setup", "io_destroy", "io_submit", "io_cancel",
+    /*   4 */ "io_getevents", "setxattr", "lsetxattr", "fsetxattr",
+    /*   8 */ "getxattr", "lgetxattr", "fgetxattr", "listxattr",
+    /*  12 */ "llistxattr", "flistxattr", "removexattr", "lremovexattr",
+    /*  16 */ "fremovexattr", "getcwd", "lookup_dcookie", "eventfd2",
+    /*  20 */ "epoll_create1", "epoll_ctl", "epoll_pwait", "dup",
+    /*  24 */ "dup3", "fcntl", "inotify_init1", "inotify_add_watch",
+    /*  28 */ "inotify_rm_watch", "ioctl", "ioprio_set", "ioprio_get",
+    /*  32 */ "flock", "mknodat", "mkdirat", "unlinkat",
+    /*  36 */ "symlinkat", "linkat", "renameat", "umount2",
+    /*  40 */ "mount", "pivot_root", "nfsservctl", "statfs",
+    /*  44 */ "fstatfs", "truncate", "ftruncate", "fallocate",
+    /*  48 */ "faccessat", "chdir", "fchdir", "chroot",
+    /*  52 */ "fchmod", "fchmodat", "fchownat", "fchown",
+    /*  56 */ "openat", "close", "vhangup", "pipe2",
+    /*  60 */ "quotactl", "getdents64", "lseek", "read",
+    /*  64 */ "write", "readv", "writev", "pread64",
+    /*  68 */ "pwrite64", "preadv", "pwritev", "sendfile",
+    /*  72 */ "pselect6", "ppoll", "signalfd4", "vmsplice",
+    /*  76 */ "splice", "tee", "readlinkat", "fstatat",
+    /*  80 */ "fstat", "sync", "fsync", "fdatasync",
+    /*  84 */ "sync_file_range", "timerfd_create", "timerfd_settime", "timerfd_gettime",
+    /*  88 */ "utimensat", "acct", "capget", "capset",
+    /*  92 */ "personality", "exit", "exit_group", "waitid",
+    /*  96 */ "set_tid_address", "unshare", "futex", "set_robust_list",
+    /* 100 */ "get_robust_list", "nanosleep", "getitimer", "setitimer",
+    /* 104 */ "kexec_load", "init_module", "delete_module", "timer_create",
+    /* 108 */ "timer_gettime", "timer_getoverrun", "timer_settime", "timer_delete",
+    /* 112 */ "clock_settime", "clock_gettime", "clock_getres", "clock_nanosleep",
+    /* 116 */ "syslog", "ptrace", "sched_setparam", "sched_setscheduler",
+    /* 120 */ "sched_getscheduler", "sched_getparam", "sched_setaffinity", "sched_getaffinity",
+    /* 124 */ "sched_yield", "sched_get_priority_max", "sched_get_priority_min", "sched_rr_get_interval",
+    /* 128 */ "restart_syscall", "kill", "tkill", "tgkill",
+    /* 132 */ "sigaltstack", "rt_sigsuspend", "rt_sigaction", "rt_sigprocmask",
+    /* 136 */ "rt_sigpending", "rt_sigtimedwait", "rt_sigqueueinfo", "rt_sigreturn",
+    /* 140 */ "setpriority", "getpriority", "reboot", "setregid",
+    /* 144 */ "setgid", "setreuid", "setuid", "setresuid",
+    /* 148 */ "getresuid", "setresgid", "getresgid", "setfsuid",
+    /* 152 */ "setfsgid", "times", "setpgid", "getpgid",
+    /* 156 */ "getsid", "setsid", "getgroups", "setgroups",
+    /* 160 */ "uname", "sethostname", "setdomainname", "getrlimit",
+    /* 164 */ "setrlimit", "getrusage", "umask", "prctl",
+    /* 168 */ "getcpu", "gettimeofday", "settimeofday", "adjtimex",
+    /* 172 */ "getpid", "getppid", "getuid", "geteuid",
+    /* 176 */ "getgid", "getegid", "gettid", "sysinfo",
+    /* 180 */ "mq_open", "mq_unlink", "mq_timedsend", "mq_timedreceive",
+    /* 184 */ "mq_notify", "mq_getsetattr", "msgget", "msgctl",
+    /* 188 */ "msgrcv", "msgsnd", "semget", "semctl",
+    /* 192 */ "semtimedop", "semop", "shmget", "shmctl",
+    /* 196 */ "shmat", "shmdt", "socket", "socketpair",
+    /* 200 */ "bind", "listen", "accept", "connect",
+    /* 204 */ "getsockname", "getpeername", "sendto", "recvfrom",
+    /* 208 */ "setsockopt", "getsockopt", "shutdown", "sendmsg",
+    /* 212 */ "recvmsg", "readahead", "brk", "munmap",
+    /* 216 */ "mremap", "add_key", "request_key", "keyctl",
+    /* 220 */ "clone", "execve", "mmap", "fadvise64",
+    /* 224 */ "swapon", "swapoff", "mprotect", "msync",
+    /* 228 */ "mlock", "munlock", "mlockall", "munlockall",
+    /* 232 */ "mincore", "madvise", "remap_file_pages", "mbind",
+    /* 236 */ "get_mempolicy", "set_mempolicy", "migrate_pages", "move_pages",
+    /* 240 */ "rt_tgsigqueueinfo", "perf_event_open", "accept4", "recvmmsg",
+    /* 244 */ "", "", "", "",
+    /* 248 */ "", "", "", "",
+    /* 252 */ "", "", "", "",
+    /* 256 */ "", "", "", "",
+    /* 260 */ "wait4", "prlimit64", "fanotify_init", "fanotify_mark",
+    /* 264 */ "name_to_handle_at", "open_by_handle_at", "clock_adjtime", "syncfs",
+    /* 268 */ "setns", "sendmmsg", "process_vm_readv", "process_vm_writev",
+    /* 272 */ "kcmp", "finit_module", "sched_setattr", "sched_getattr",
+    /* 276 */ "renameat2", "seccomp", "getrandom", "memfd_create",
+    /* 280 */ "bpf", "execveat", "userfaultfd", "membarrier",
+    /* 284 */ "mlock2", "copy_file_range", "preadv2", "pwritev2",
+    /* 288 */ "pkey_mprotect", "pkey_alloc", "pkey_free", "statx",
+    /* 292 */ "io_pgetevents", "rseq", "kexec_file_load", "",
+    /* 296 */ "", "", "", "",
+    /* 300 */ "", "", "", "",
+    /* 304 */ "", "", "", "",
+    /* 308 */ "", "", "", "",
+    /* 312 */ "", "", "", "",
+    /* 316 */ "", "", "", "",
+    /* 320 */ "", "", "", "",
+    /* 324 */ "", "", "", "",
+    /* 328 */ "", "", "", "",
+    /* 332 */ "", "", "", "",
+    /* 336 */ "", "", "", "",
+    /* 340 */ "", "", "", "",
+    /* 344 */ "", "", "", "",
+    /* 348 */ "", "", "", "",
+    /* 352 */ "", "", "", "",
+    /* 356 */ "", "", "", "",
+    /* 360 */ "", "", "", "",
+    /* 364 */ "", "", "", "",
+    /* 368 */ "", "", "", "",
+    /* 372 */ "", "", "", "",
+    /* 376 */ "", "", "", "",
+    /* 380 */ "", "", "", "",
+    /* 384 */ "", "", "", "",
+    /* 388 */ "", "", "", "",
+    /* 392 */ "", "", "", "",
+    /* 396 */ "", "", "", "",
+    /* 400 */ "", "", "", "",
+    /* 404 */ "", "", "", "",
+    /* 408 */ "", "", "", "",
+    /* 412 */ "", "", "", "",
+    /* 416 */ "", "", "", "",
+    /* 420 */ "", "", "", "",
+    /* 424 */ "pidfd_send_signal", "io_uring_setup", "io_uring_enter", "io_uring_register",
+    /* 428 */ "open_tree", "move_mount", "fsopen", "fsconfig",
+    /* 432 */ "fsmount", "fspick", "pidfd_open", "clone3",
+    /* 436 */ "close_range", "openat2", "pidfd_getfd", "faccessat2",
+    /* 440 */ "process_madvise", "epoll_pwait2", "mount_setattr", "quotactl_fd",
+    /* 444 */ "landlock_create_ruleset", "landlock_add_rule", "landlock_restrict_self", "memfd_secret",
+    /* 448 */ "process_mrelease", "futex_waitv", "set_mempolicy_home_node",
+];
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SYSCALL_NAMES: &[&str] = &[];
+
+fn syscall_name(nr: u64) -> String {
+    match SYSCALL_NAMES.get(nr as usize) {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => format!("syscall_{}", nr),
+    }
+}
+
+// For the syscalls we are most likely to find a thread blocked in, the number of arguments the
+// syscall takes and which of those arguments are file descriptors. For any other syscall we just
+// print all six argument registers, since we have no way of knowing how many are meaningful.
+fn syscall_args(name: &str) -> Option<(usize, &'static [usize])> {
+    let args: (usize, &'static [usize]) = match name {
+        "read" | "write" | "readv" | "writev" | "getdents64" => (3, &[0]),
+        "pread64" | "pwrite64" | "preadv" | "pwritev" => (4, &[0]),
+        "ioctl" | "fcntl" | "lseek" => (3, &[0]),
+        "close" | "fsync" | "fdatasync" => (1, &[0]),
+        "flock" | "listen" | "shutdown" => (2, &[0]),
+        "accept" | "connect" | "bind" => (3, &[0]),
+        "accept4" => (4, &[0]),
+        "recvfrom" | "sendto" => (6, &[0]),
+        "recvmsg" | "sendmsg" => (3, &[0]),
+        "recvmmsg" => (5, &[0]),
+        "sendmmsg" => (4, &[0]),
+        "epoll_wait" => (4, &[0]),
+        "epoll_pwait" => (5, &[0]),
+        "epoll_ctl" => (4, &[0, 2]),
+        "sendfile" => (4, &[0, 1]),
+        "splice" => (6, &[0, 2]),
+        "tee" => (4, &[0, 1]),
+        "copy_file_range" => (6, &[0, 2]),
+        "io_uring_enter" => (6, &[0]),
+        "fallocate" => (4, &[0]),
+        "ftruncate" => (2, &[0]),
+        "poll" | "ppoll" => (3, &[]),
+        "select" | "pselect6" => (5, &[]),
+        "nanosleep" => (2, &[]),
+        "clock_nanosleep" => (4, &[]),
+        "futex" => (6, &[]),
+        "wait4" => (4, &[]),
+        "waitid" => (5, &[]),
+        "pause" => (0, &[]),
+        "rt_sigsuspend" => (2, &[]),
+        "rt_sigtimedwait" => (4, &[]),
+        _ => return None,
+    };
+    Some(args)
+}
+
+// Print a syscall and its arguments, e.g. 'read(3</var/log/syslog>, 0x7ffc4c1d6a10, 0x2000)'.
+// File descriptor arguments are resolved through the process's fd table, in the same style as
+// 'strace -y'.
+fn format_syscall(pid: u64, nr: u64, args: &[u64]) -> String {
+    let name = syscall_name(nr);
+    let (nargs, fd_args) = syscall_args(&name).unwrap_or((args.len(), &[]));
+
+    let args = args.iter().take(nargs).enumerate().map(|(i, &arg)| {
+        if fd_args.contains(&i) {
+            // 'int' arguments are passed in a 64 bit register, but only the low 32 bits matter
+            let fd = arg as i32;
+            match read_proc_link(proc_path!("{}/fd/{}", pid, fd)) {
+                Ok(path) => format!("{}<{}>", fd, path.to_string_lossy()),
+                Err(_) => fd.to_string(),
+            }
+        } else {
+            format!("{:#x}", arg)
+        }
+    }).collect::<Vec<_>>();
+
+    format!("{}({})", name, args.join(", "))
+}
+
+// What a thread is doing, as reported by /proc/[pid]/task/[tid]/syscall
+#[derive(Debug, PartialEq)]
+enum TaskSyscall {
+    // The thread is running, so what syscall it is in (if any) isn't stable
+    Running,
+    // The thread is blocked, but not in a syscall
+    NotInSyscall,
+    Syscall { nr: u64, args: [u64; 6] },
+}
+
+// Parse the contents of /proc/[pid]/task/[tid]/syscall, which have one of the following forms:
+//
+//    running
+//    -1 <sp> <pc>
+//    <nr> <arg1> ... <arg6> <sp> <pc>
+//
+fn parse_syscall(contents: &str) -> Result<TaskSyscall, ParseError> {
+    let fields = contents.split_whitespace().collect::<Vec<_>>();
+    match fields.first() {
+        Some(&"running") => return Ok(TaskSyscall::Running),
+        Some(&"-1") => return Ok(TaskSyscall::NotInSyscall),
+        _ => {}
+    }
+
+    let parse_hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16);
+    let values = fields.iter().map(|f| parse_hex(f)).collect::<Result<Vec<_>, _>>();
+    match values {
+        Ok(ref values) if values.len() == 9 => {
+            // The syscall number is printed in decimal, the remaining fields in hex
+            let nr = fields[0].parse::<u64>().map_err(|_| {
+                ParseError::in_file("syscall", &format!("invalid syscall number '{}'", fields[0]))
+            })?;
+            let mut args = [0; 6];
+            args.copy_from_slice(&values[1..7]);
+            Ok(TaskSyscall::Syscall { nr, args })
+        },
+        _ => Err(ParseError::in_file("syscall", &format!("unexpected contents '{}'", contents.trim()))),
+    }
+}
+
+fn read_task_syscall(pid: u64, tid: u64) -> Result<TaskSyscall, Box<dyn Error>> {
+    Ok(parse_syscall(&read_task_file(pid, tid, "syscall")?)?)
+}
+
+fn describe_syscall(pid: u64, syscall: &TaskSyscall) -> String {
+    match *syscall {
+        TaskSyscall::Running => "<running>".to_string(),
+        TaskSyscall::NotInSyscall => "<blocked, not in a syscall>".to_string(),
+        TaskSyscall::Syscall { nr, ref args } => format_syscall(pid, nr, args),
+    }
+}
+
+fn task_ids(pid: u64) -> std::io::Result<Vec<u64>> {
+    let mut tids = fs::read_dir(proc_path!("{}/task/", pid))?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_string_lossy().parse::<u64>().ok())
+        .collect::<Vec<_>>();
+    tids.sort();
+    Ok(tids)
+}
+
+fn read_task_file(pid: u64, tid: u64, file: &str) -> Result<String, Box<dyn Error>> {
+    let mut contents = String::new();
+    File::open(proc_path!("{}/task/{}/{}", pid, tid, file))?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+// What a thread is doing, as far as the scheduler is concerned
+struct TaskState {
+    comm: String,
+    // e.g. 'S (sleeping)'
+    state: String,
+    // The kernel function the thread is sleeping in, if it is sleeping
+    wchan: Option<String>,
+    // The CPU the thread last ran on
+    cpu: Option<u64>,
+}
+
+impl TaskState {
+    // Threads in uninterruptible sleep are usually the ones we care about: they can't be killed,
+    // and are typically stuck waiting on I/O.
+    fn warning(&self) -> &'static str {
+        if self.state.starts_with('D') { "  ** UNINTERRUPTIBLE **" } else { "" }
+    }
+
+    fn wchan_str(&self) -> String {
+        match self.wchan {
+            Some(ref wchan) => format!("  wchan: {}", wchan),
+            None => "".to_string(),
+        }
+    }
+}
+
+// Returns None if the thread has exited
+fn read_task_state(pid: u64, tid: u64) -> Option<TaskState> {
+    let comm = match read_task_file(pid, tid, "comm") {
+        Ok(comm) => comm.trim_end().to_string(),
+        // Thread probably exited after we read the task directory
+        Err(_) => return None,
+    };
+
+    let state = match ProcStat::read_task(pid, tid).and_then(|s| Ok(s.get_field("State")?.to_string())) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("{}", e);
+            "?".to_string()
+        }
+    };
+
+    // wchan is the kernel function the thread is sleeping in, or '0' if it isn't sleeping
+    let wchan = match read_task_file(pid, tid, "wchan") {
+        Ok(wchan) if wchan != "0" && !wchan.is_empty() => Some(wchan),
+        _ => None,
+    };
+
+    // The processor is field 39 of 'stat'
+    let cpu = read_task_file(pid, tid, "stat").ok()
+        .and_then(|stat| stat_field(&stat, 39).and_then(|cpu| cpu.parse().ok()));
+
+    Some(TaskState { comm, state, wchan, cpu })
+}
+
+fn print_task_syscall(pid: u64, tid: u64) {
+    let task = match read_task_state(pid, tid) {
+        Some(task) => task,
+        None => return,
+    };
+
+    println!(" {: >6}: {}  {}{}{}", tid, task.comm, task.state, task.wchan_str(), task.warning());
+
+    // Reading 'syscall' requires the same permissions as ptrace-ing the process.
+    let syscall = match read_task_file(pid, tid, "syscall") {
+        Ok(contents) => match parse_syscall(&contents) {
+            Ok(syscall) => describe_syscall(pid, &syscall),
+            Err(e) => {
+                eprintln!("{}", e);
+                "<unknown>".to_string()
+            }
+        },
+        Err(e) => format!("<unable to read syscall: {}>", e),
+    };
+    println!("         {}", syscall);
+}
+
+fn print_syscalls(pid: u64) -> bool {
+    let tids = match task_ids(pid) {
+        Ok(tids) => tids,
+        Err(e) => {
+            eprintln!("Unable to read /proc/{}/task/: {}", pid, e);
+            return false;
+        }
+    };
+
+    print_proc_summary(pid);
+    for tid in tids {
+        print_task_syscall(pid, tid);
+    }
+
+    true
+}
+
+// A single mapping of a process's address space, as described by /proc/[pid]/smaps
+struct Mapping {
+    start: u64,
+    end: u64,
+    perms: String,
+    // Offset of the start of the mapping in the file backing it
+    offset: u64,
+    // The major and minor numbers of the filesystem of the file backing it, and its inode. System
+    // V shared memory segments have their shmid as the inode.
+    dev: (u64, u64),
+    inode: u64,
+    // File backing the mapping, or a pseudo-name such as '[heap]'. Empty for anonymous mappings.
+    path: String,
+    // The 'Key: N kB' lines following the mapping's header line, in kB
+    sizes: HashMap<String, u64>,
+    vm_flags: Vec<String>,
+}
+
+impl Mapping {
+    fn size(&self, field: &str) -> u64 {
+        *self.sizes.get(field).unwrap_or(&0)
+    }
+
+    fn kbytes(&self) -> u64 {
+        (self.end - self.start) / 1024
+    }
+
+    fn has_vm_flag(&self, flag: &str) -> bool {
+        self.vm_flags.iter().any(|f| f == flag)
+    }
+
+    fn is_hugetlb(&self) -> bool {
+        self.has_vm_flag("ht")
+    }
+
+    fn name(&self) -> &str {
+        if self.path.is_empty() {
+            "[ anon ]"
+        } else {
+            &self.path
+        }
+    }
+}
+
+// Split the first n space separated fields off a line, and return them along with the rest of it,
+// which is a path that may itself contain spaces. The kernel pads the fields before it with spaces.
+fn split_fields(line: &str, n: usize) -> Option<(Vec<&str>, &str)> {
+    let mut fields = vec![];
+    let mut rest = line;
+    for _ in 0..n {
+        rest = rest.trim_start_matches(' ');
+        let end = rest.find(' ').unwrap_or(rest.len());
+        if end == 0 {
+            return None;
+        }
+        fields.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    Some((fields, rest.trim_start_matches(' ')))
+}
+
+// Parse the contents of /proc/[pid]/smaps, which consists of a header line for each mapping, of the
+// form
+//
+//    7f2a4c1d6000-7f2a4c1f8000 r-xp 00000000 fe:00 280542     /usr/lib/libc.so.6
+//
+// followed by lines of the form 'Rss:   8 kB', and a final 'VmFlags: rd mr mw me' line.
+fn parse_smaps<R: BufRead>(reader: R) -> Result<Vec<Mapping>, Box<dyn Error>> {
+    let mut mappings: Vec<Mapping> = vec![];
+
+    for line in reader.lines() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        let first = match fields.next() {
+            Some(first) => first,
+            None => continue,
+        };
+
+        if first.ends_with(':') {
+            let mapping = mappings.last_mut().ok_or_else(|| {
+                ParseError::in_file("smaps", &format!("'{}' before first mapping", line))
+            })?;
+            let key = first.trim_end_matches(':');
+            if key == "VmFlags" {
+                mapping.vm_flags = fields.map(|s| s.to_string()).collect();
+            } else if let Some(Ok(value)) = fields.next().map(|v| v.parse::<u64>()) {
+                mapping.sizes.insert(key.to_string(), value);
+            }
+            continue;
+        }
+
+        let mk_err = || ParseError::in_file("smaps", &format!("unexpected mapping line '{}'", line));
+        let range = first.split('-').collect::<Vec<_>>();
+        if range.len() != 2 {
+            return Err(From::from(mk_err()));
+        }
+        let perms = fields.next().ok_or_else(mk_err)?;
+        let offset = fields.next().ok_or_else(mk_err)?;
+        let dev = fields.next().ok_or_else(mk_err)?.split(':')
+            .map(|n| u64::from_str_radix(n, 16).map_err(|_| mk_err()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if dev.len() != 2 {
+            return Err(From::from(mk_err()));
+        }
+        let inode = fields.next().ok_or_else(mk_err)?.parse::<u64>().map_err(|_| mk_err())?;
+        // The path may itself contain spaces, even several in a row, so take the rest of the line
+        let path = split_fields(&line, 5).map_or("", |(_, path)| path).to_string();
+
+        mappings.push(Mapping {
+            start: u64::from_str_radix(range[0], 16).map_err(|_| mk_err())?,
+            end: u64::from_str_radix(range[1], 16).map_err(|_| mk_err())?,
+            perms: perms.to_string(),
+            offset: u64::from_str_radix(offset, 16).map_err(|_| mk_err())?,
+            dev: (dev[0], dev[1]),
+            inode,
+            path,
+            sizes: HashMap::new(),
+            vm_flags: vec![],
+        });
+    }
+
+    Ok(mappings)
+}
+
+// Options controlling what pmap prints for each process
+struct PmapOptions {
+    extended: bool,
+    hugepages: bool,
+    // Only print mappings of these kinds, or all of them if empty
+    kinds: Vec<MappingKind>,
+    // Only print mappings with at least this much resident, in kB
+    min_rss: u64,
+    sort: MappingSort,
+    stacks: bool,
+}
+
+// The kinds of mapping pmap --filter selects
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MappingKind {
+    Anon,
+    File,
+    Stack,
+    Heap,
+}
+
+impl MappingKind {
+    fn parse(kind: &str) -> Option<MappingKind> {
+        match kind {
+            "anon" => Some(MappingKind::Anon),
+            "file" => Some(MappingKind::File),
+            "stack" => Some(MappingKind::Stack),
+            "heap" => Some(MappingKind::Heap),
+            _ => None,
+        }
+    }
+
+    // Anonymous mappings may be named with prctl(PR_SET_VMA_ANON_NAME), which shows as
+    // '[anon:NAME]'. Kernels before 4.5 listed threads' stacks as '[stack:TID]'.
+    fn of(mapping: &Mapping) -> Option<MappingKind> {
+        let path = &mapping.path;
+        if path.is_empty() || path.starts_with("[anon:") {
+            Some(MappingKind::Anon)
+        } else if path.starts_with('/') {
+            Some(MappingKind::File)
+        } else if path.starts_with("[stack") {
+            Some(MappingKind::Stack)
+        } else if path == "[heap]" {
+            Some(MappingKind::Heap)
+        } else {
+            None
+        }
+    }
+}
+
+// The order pmap prints mappings in: by address, as they are in /proc/[pid]/smaps, or biggest first
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MappingSort {
+    Address,
+    Size,
+    Rss,
+    Anon,
+}
+
+impl MappingSort {
+    fn parse(sort: &str) -> Option<MappingSort> {
+        match sort {
+            "address" => Some(MappingSort::Address),
+            "size" => Some(MappingSort::Size),
+            "rss" => Some(MappingSort::Rss),
+            "anon" => Some(MappingSort::Anon),
+            _ => None,
+        }
+    }
+}
+
+fn select_mappings<'a>(mappings: &'a [Mapping], opts: &PmapOptions) -> Vec<&'a Mapping> {
+    let mut selected = mappings.iter()
+        .filter(|m| opts.kinds.is_empty() || MappingKind::of(m).is_some_and(|kind| opts.kinds.contains(&kind)))
+        .filter(|m| m.size("Rss") >= opts.min_rss)
+        .collect::<Vec<_>>();
+    // The sort is stable, so equal mappings stay in address order
+    match opts.sort {
+        MappingSort::Address => {}
+        MappingSort::Size => selected.sort_by_key(|m| std::cmp::Reverse(m.kbytes())),
+        MappingSort::Rss => selected.sort_by_key(|m| std::cmp::Reverse(m.size("Rss"))),
+        MappingSort::Anon => selected.sort_by_key(|m| std::cmp::Reverse(m.size("Anonymous"))),
+    }
+    selected
+}
+
+// Read the current setting of one of the files in /sys/kernel/mm/transparent_hugepage/, which list
+// all of the possible values with the current one in brackets, e.g. 'always [madvise] never'.
+fn thp_setting(file: &str) -> Option<String> {
+    let mut contents = String::new();
+    File::open(format!("/sys/kernel/mm/transparent_hugepage/{}", file))
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .ok()?;
+    let start = contents.find('[')?;
+    let end = contents.find(']')?;
+    contents.get(start + 1..end).map(|s| s.to_string())
+}
+
+fn print_hugepage_summary(mappings: &[Mapping]) {
+    let total = |field: &str| mappings.iter().map(|m| m.size(field)).sum::<u64>();
+
+    let hugetlb = mappings.iter().filter(|m| m.is_hugetlb()).collect::<Vec<_>>();
+    let shared_hugetlb = hugetlb.iter().map(|m| m.size("Shared_Hugetlb")).sum::<u64>();
+    let private_hugetlb = hugetlb.iter().map(|m| m.size("Private_Hugetlb")).sum::<u64>();
+
+    // Only anonymous memory in regions where THP is allowed can be backed by transparent huge
+    // pages. Older kernels don't report THPeligible, in which case assume all anonymous memory is.
+    let eligible = mappings.iter()
+        .filter(|m| !m.is_hugetlb() && m.sizes.get("THPeligible").cloned().unwrap_or(1) == 1)
+        .collect::<Vec<_>>();
+    let eligible_anon = eligible.iter().map(|m| m.size("Anonymous")).sum::<u64>();
+    let anon_huge = total("AnonHugePages");
+
+    println!();
+    println!("Huge pages:");
+    print!("  AnonHugePages:  {: >10} kB", anon_huge);
+    if eligible_anon > 0 {
+        print!("  ({:.1}% of {} kB THP eligible anonymous memory)",
+            100.0 * anon_huge as f64 / eligible_anon as f64, eligible_anon);
+    }
+    println!();
+    println!("  ShmemPmdMapped: {: >10} kB", total("ShmemPmdMapped"));
+    println!("  FilePmdMapped:  {: >10} kB", total("FilePmdMapped"));
+    println!("  hugetlbfs:      {: >10} kB  ({} mappings, {} kB shared, {} kB private)",
+        shared_hugetlb + private_hugetlb, hugetlb.len(), shared_hugetlb, private_hugetlb);
+    println!("  THP enabled: {}  defrag: {}",
+        thp_setting("enabled").unwrap_or_else(|| "unknown".to_string()),
+        thp_setting("defrag").unwrap_or_else(|| "unknown".to_string()));
+}
+
+// The stack pointer of a thread which is blocked, from the last but one field of its syscall file
+// (see parse_syscall()). Where a running thread's stack pointer is isn't shown.
+fn syscall_stack_pointer(contents: &str) -> Option<u64> {
+    let fields = contents.split_whitespace().collect::<Vec<_>>();
+    if fields.len() != 3 && fields.len() != 9 {
+        return None;
+    }
+    u64::from_str_radix(fields[fields.len() - 2].trim_start_matches("0x"), 16).ok()
+}
+
+// The gap the kernel leaves between a stack which grows down and the accessible mapping below it
+// (see stack_guard_gap in mm/mmap.c), which is 256 pages unless set on the kernel command line.
+fn stack_guard_gap() -> u64 {
+    let pages = fs::read_to_string("/proc/cmdline").ok()
+        .and_then(|cmdline| cmdline.split_whitespace().find_map(|arg| arg.strip_prefix("stack_guard_gap=")
+            .and_then(|pages| pages.parse::<u64>().ok())))
+        .unwrap_or(256);
+    pages * page_size()
+}
+
+// How much room a thread has on its stack, in bytes
+#[derive(Debug, PartialEq)]
+struct StackRoom {
+    // The mapping the stack pointer is in
+    start: u64,
+    end: u64,
+    used: u64,
+    // How far the stack pointer can go down before the stack overflows
+    left: u64,
+    // The inaccessible guard pages right below the stack (or for a stack which grows, the gap the
+    // kernel keeps), which make an overflow crash instead of writing to another mapping
+    guard: u64,
+    // Whether the mapping grows down as it is used, like the main thread's
+    grows: bool,
+    // Whether another mapping is right below the stack, with no guard in between
+    unguarded: bool,
+}
+
+impl StackRoom {
+    fn is_nearly_full(&self) -> bool {
+        self.left < (self.used + self.left) / 10
+    }
+}
+
+// Work out the room on the stack with the given stack pointer. Mappings which grow down can do so
+// until they reach the stack size rlimit, or the guard gap above the next accessible mapping.
+fn stack_room(mappings: &[Mapping], sp: u64, rlimit: Option<u64>, guard_gap: u64) -> Option<StackRoom> {
+    let i = mappings.iter().position(|m| m.start <= sp && sp < m.end)?;
+    let stack = &mappings[i];
+    let grows = stack.has_vm_flag("gd");
+    let (guard, lowest) = if grows {
+        let below = mappings[..i].iter().rev().find(|m| !m.perms.starts_with("---")).map_or(0, |m| m.end);
+        let lowest = below.saturating_add(guard_gap).max(stack.end.saturating_sub(rlimit.unwrap_or(u64::MAX)));
+        (guard_gap, lowest.min(stack.start))
+    } else {
+        // Guard pages are mapped with no permissions, e.g. by pthread_create()
+        let mut guard_start = stack.start;
+        for m in mappings[..i].iter().rev().take_while(|m| m.perms.starts_with("---")) {
+            if m.end != guard_start {
+                break;
+            }
+            guard_start = m.start;
+        }
+        (stack.start - guard_start, stack.start)
+    };
+    Some(StackRoom {
+        start: stack.start,
+        end: stack.end,
+        used: stack.end - sp,
+        left: sp.saturating_sub(lowest),
+        guard,
+        grows,
+        unguarded: guard == 0 && i > 0 && mappings[i - 1].end == stack.start,
+    })
+}
+
+// Addresses below this are where dereferences of NULL pointers (to structs) land
+const NULL_PAGE_RANGE: u64 = 64 << 10;
+
+fn print_stack_summary(pid: u64, mappings: &[Mapping]) {
+    let rlimit = fs::read_to_string(proc_path!("{}/limits", pid)).ok()
+        .and_then(|limits| parse_limit(&limits, "Max stack size"))
+        .and_then(|(soft, _)| soft);
+    let guard_gap = stack_guard_gap();
+    let mut warnings = vec![];
+
+    println!();
+    println!("Thread stacks:");
+    println!("  {: >7} {: <16} {: >10} {: >10} {: >10} {: >10}", "TID", "Address", "Kbytes", "Used", "Left", "Guard");
+    for tid in task_ids(pid).unwrap_or_default() {
+        let comm = read_task_file(pid, tid, "comm").map(|comm| comm.trim().to_string()).unwrap_or_default();
+        let sp = match read_task_file(pid, tid, "syscall").ok().as_deref().and_then(syscall_stack_pointer) {
+            Some(sp) => sp,
+            None => {
+                println!("  {: >7} {: <16} (running, so where its stack is isn't known)  {}", tid, "?", comm);
+                continue;
+            }
+        };
+        let room = match stack_room(mappings, sp, rlimit, guard_gap) {
+            Some(room) => room,
+            None => {
+                println!("  {: >7} {: <16} (the stack pointer isn't in any mapping)  {}", tid, "?", comm);
+                continue;
+            }
+        };
+        let grows = if room.grows { ", grows down" } else { "" };
+        println!("  {: >7} {:016x} {: >10} {: >10} {: >10} {: >10}  {}{}", tid, room.start,
+                 (room.used + room.left) / 1024, room.used / 1024, room.left / 1024, room.guard / 1024, comm, grows);
+        if room.is_nearly_full() {
+            warnings.push(format!("thread {} has only {} kB of its {} kB stack left", tid, room.left / 1024,
+                                  (room.used + room.left) / 1024));
+        }
+        if room.unguarded {
+            warnings.push(format!("thread {}'s stack has no guard page, so overflowing it writes to the mapping \
+                                   below", tid));
+        }
+    }
+
+    let min_addr = fs::read_to_string("/proc/sys/vm/mmap_min_addr").ok().and_then(|addr| addr.trim().parse::<u64>().ok());
+    if let Some(min_addr) = min_addr {
+        println!("  vm.mmap_min_addr: {}", min_addr);
+        if min_addr == 0 {
+            warnings.push("vm.mmap_min_addr is 0, so the NULL page can be mapped".to_string());
+        }
+    }
+    for m in mappings.iter().filter(|m| m.start < NULL_PAGE_RANGE && !m.perms.starts_with("---")) {
+        warnings.push(format!("{} is mapped at {:016x}, so NULL pointer dereferences may not crash", m.name(), m.start));
+    }
+    for warning in warnings {
+        println!("  WARNING: {}", warning);
+    }
+}
+
+fn print_map(pid: u64, opts: &PmapOptions) -> bool {
+    let smaps_file = proc_path!("{}/smaps", pid);
+    let mappings = match File::open(&smaps_file).map_err(From::from).and_then(|f| parse_smaps(BufReader::new(f))) {
+        Ok(mappings) => mappings,
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", smaps_file, e);
+            return false;
+        }
+    };
+
+    print_proc_summary(pid);
+
+    let mut header = format!("{: <16} {: >10}", "Address", "Kbytes");
+    if opts.extended {
+        header += &format!(" {: >10} {: >10} {: >10}", "RSS", "Anon", "Locked");
+    }
+    if opts.hugepages {
+        header += &format!(" {: >10} {: >10} {: >10} {: >10}", "AnonHuge", "ShmemPmd", "FilePmd", "Hugetlb");
+    }
+    println!("{} {: <5} Mapping", header, "Mode");
+
+    // The totals are of the mappings printed, and the huge page summary of them all
+    let selected = select_mappings(&mappings, opts);
+    for m in &selected {
+        let mut line = format!("{:016x} {: >10}", m.start, m.kbytes());
+        if opts.extended {
+            line += &format!(" {: >10} {: >10} {: >10}", m.size("Rss"), m.size("Anonymous"), m.size("Locked"));
+        }
+        if opts.hugepages {
+            line += &format!(" {: >10} {: >10} {: >10} {: >10}",
+                m.size("AnonHugePages"), m.size("ShmemPmdMapped"), m.size("FilePmdMapped"),
+                m.size("Shared_Hugetlb") + m.size("Private_Hugetlb"));
+        }
+        println!("{} {: <5} {}", line, m.perms, m.name());
+    }
+
+    let total = |field: &str| selected.iter().map(|m| m.size(field)).sum::<u64>();
+    let mut line = format!("{: <16} {: >10}", "total kB", selected.iter().map(|m| m.kbytes()).sum::<u64>());
+    if opts.extended {
+        line += &format!(" {: >10} {: >10} {: >10}", total("Rss"), total("Anonymous"), total("Locked"));
+    }
+    if opts.hugepages {
+        line += &format!(" {: >10} {: >10} {: >10} {: >10}",
+            total("AnonHugePages"), total("ShmemPmdMapped"), total("FilePmdMapped"),
+            total("Shared_Hugetlb") + total("Private_Hugetlb"));
+    }
+    println!("{}", line);
+
+    if opts.hugepages {
+        print_hugepage_summary(&mappings);
+    }
+    if opts.stacks {
+        print_stack_summary(pid, &mappings);
+    }
+
+    true
+}
+
+//
+// pmap --visual draws the address space in proportion, so that the layout, and holes between
+// mappings, can be seen at a glance. Address spaces are mostly empty (libraries and stacks sit
+// terabytes above the heap), so gaps bigger than a row are left out and only say how big they are.
+//
+
+// What a stretch of the address space is used for, in pmap --visual
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Region {
+    Heap,
+    Stack,
+    // Executable mappings of files, i.e. programs and libraries
+    Code,
+    File,
+    Anon,
+    // [vdso], [vvar], [vsyscall] and the like
+    Kernel,
+    Unmapped,
+}
+
+impl Region {
+    fn of(mapping: &Mapping) -> Region {
+        match MappingKind::of(mapping) {
+            Some(MappingKind::Heap) => Region::Heap,
+            Some(MappingKind::Stack) => Region::Stack,
+            Some(MappingKind::File) if mapping.perms.contains('x') => Region::Code,
+            Some(MappingKind::File) => Region::File,
+            Some(MappingKind::Anon) => Region::Anon,
+            None => Region::Kernel,
+        }
+    }
+
+    // The character drawn for it, its name in the legend and its colour in HTML
+    fn style(self) -> (char, &'static str, &'static str) {
+        match self {
+            Region::Heap => ('H', "heap", "#e4572e"),
+            Region::Stack => ('S', "stack", "#f3a712"),
+            Region::Code => ('X', "code", "#1f77b4"),
+            Region::File => ('F', "file", "#76b7e5"),
+            Region::Anon => ('A', "anon", "#4daf4a"),
+            Region::Kernel => ('K', "kernel", "#984ea3"),
+            Region::Unmapped => ('.', "unmapped", "#eeeeee"),
+        }
+    }
+}
+
+const VISUAL_REGIONS: &[Region] = &[
+    Region::Heap, Region::Stack, Region::Code, Region::File, Region::Anon, Region::Kernel, Region::Unmapped,
+];
+
+// How many bytes each character of a row stands for: a power of two, and at least a page, so that
+// the mappings fill about 32 rows of 'width'
+fn visual_scale(mappings: &[Mapping], width: u64) -> u64 {
+    let mapped = mappings.iter().map(|m| m.end - m.start).sum::<u64>();
+    (mapped / (width * 32)).next_power_of_two().max(page_size())
+}
+
+// The mappings, split where the gap between two of them is bigger than 'max_gap' bytes
+fn address_runs(mappings: &[Mapping], max_gap: u64) -> Vec<&[Mapping]> {
+    let mut runs = vec![];
+    let mut first = 0;
+    for i in 1..=mappings.len() {
+        if i == mappings.len() || mappings[i].start - mappings[i - 1].end > max_gap {
+            runs.push(&mappings[first..i]);
+            first = i;
+        }
+    }
+    runs.retain(|run| !run.is_empty());
+    runs
+}
+
+// The rows of the ASCII drawing: the address each starts at, a character for each 'scale' bytes
+// (for whichever region has the most of them), and the names of the files mapped from that row
+fn visual_ascii(mappings: &[Mapping], width: u64, scale: u64) -> Vec<String> {
+    let mut rows = vec![];
+    let runs = address_runs(mappings, width * scale);
+    for (i, run) in runs.iter().enumerate() {
+        if i > 0 {
+            let gap = run[0].start - runs[i - 1][runs[i - 1].len() - 1].end;
+            rows.push(format!("{: <16}   ~ {} unmapped ~", "", size_str(gap)));
+        }
+        let end = run[run.len() - 1].end;
+        let mut row_start = run[0].start / scale * scale;
+        while row_start < end {
+            let mut cells = String::new();
+            let mut names: Vec<&str> = vec![];
+            for cell in 0..width {
+                let from = row_start + cell * scale;
+                if from >= end {
+                    break;
+                }
+                let to = from + scale;
+                let mut bytes = VISUAL_REGIONS.iter().map(|&region| (region, 0)).collect::<Vec<_>>();
+                let mut mapped = 0;
+                for m in run.iter().filter(|m| m.start < to && m.end > from) {
+                    let overlap = m.end.min(to) - m.start.max(from);
+                    mapped += overlap;
+                    if let Some(entry) = bytes.iter_mut().find(|&&mut (region, _)| region == Region::of(m)) {
+                        entry.1 += overlap;
+                    }
+                    let name = m.path.rsplit('/').next().unwrap_or("");
+                    if m.start >= from && m.path.starts_with('/') && !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+                bytes[VISUAL_REGIONS.len() - 1].1 = scale - mapped;
+                // The first region listed wins a tie
+                let region = bytes.iter().rev().max_by_key(|&&(_, bytes)| bytes).map_or(Region::Unmapped, |r| r.0);
+                cells.push(region.style().0);
+            }
+            rows.push(format!("{:016x} |{: <w$}| {}", row_start, cells, names.join(" "), w = width as usize)
+                .trim_end().to_string());
+            row_start += width * scale;
+        }
+    }
+    rows
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// A process' address space as HTML: a bar for each run of mappings, in which each mapping's width
+// is in proportion to its size, with its addresses, size and name shown on hovering over it
+fn visual_html(pid: u64, mappings: &[Mapping], max_gap: u64) -> Vec<String> {
+    let mut lines = vec![format!("<h2>{}: {}</h2>", pid, html_escape(cmd_summary(pid).trim_end()))];
+    let runs = address_runs(mappings, max_gap);
+    for (i, run) in runs.iter().enumerate() {
+        if i > 0 {
+            let gap = run[0].start - runs[i - 1][runs[i - 1].len() - 1].end;
+            lines.push(format!("<div class=\"gap\">~ {} unmapped ~</div>", size_str(gap)));
+        }
+        lines.push(format!("<div class=\"run\"><div class=\"addr\">{:016x}</div><div class=\"bar\">", run[0].start));
+        let mut last_end = run[0].start;
+        for m in run.iter() {
+            if m.start > last_end {
+                lines.push(format!("<div style=\"flex-grow:{};background:{}\" title=\"{} unmapped\"></div>",
+                                   (m.start - last_end) / 1024, Region::Unmapped.style().2,
+                                   size_str(m.start - last_end)));
+            }
+            let (_, name, colour) = Region::of(m).style();
+            lines.push(format!("<div style=\"flex-grow:{};background:{}\" title=\"{:x}-{:x} {} {} {} ({})\"></div>",
+                               m.kbytes(), colour, m.start, m.end, size_str(m.end - m.start), m.perms,
+                               html_escape(m.name()), name));
+            last_end = m.end;
+        }
+        lines.push(format!("</div><div class=\"addr\">{:016x}</div></div>", last_end));
+    }
+    lines
+}
+
+const VISUAL_HTML_HEAD: &str = "<!DOCTYPE html>
+<html><head><meta charset=\"utf-8\"><title>pmap</title><style>
+body { font-family: sans-serif; }
+.run { display: flex; align-items: center; margin: 4px 0; }
+.addr { font-family: monospace; font-size: small; padding: 0 8px; }
+.bar { display: flex; flex: 1; height: 32px; border: 1px solid #888; }
+.bar div { min-width: 1px; }
+.gap { font-size: small; color: #888; padding-left: 160px; }
+.legend span { display: inline-block; width: 12px; height: 12px; margin: 0 4px 0 12px; }
+</style></head><body>";
+
+fn print_visual_map(pid: u64, html: bool) -> bool {
+    let smaps_file = proc_path!("{}/smaps", pid);
+    let mappings = match File::open(&smaps_file).map_err(From::from).and_then(|f| parse_smaps(BufReader::new(f))) {
+        Ok(mappings) => mappings,
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", smaps_file, e);
+            return false;
+        }
+    };
+
+    const WIDTH: u64 = 64;
+    let scale = visual_scale(&mappings, WIDTH);
+    if html {
+        for line in visual_html(pid, &mappings, WIDTH * scale) {
+            println!("{}", line);
+        }
+        return true;
+    }
+    print_proc_summary(pid);
+    let legend = VISUAL_REGIONS.iter().map(|region| format!("{} {}", region.style().0, region.style().1))
+        .collect::<Vec<_>>();
+    println!("each character is {}: {}", size_str(scale), legend.join(", "));
+    for row in visual_ascii(&mappings, WIDTH, scale) {
+        println!("{}", row);
+    }
+    true
+}
+
+// Futex operations a thread can block in while waiting for a lock. The low bits of the futex 'op'
+// argument are the command; the remaining bits are flags such as FUTEX_PRIVATE_FLAG.
+const FUTEX_WAIT: u64 = 0;
+const FUTEX_LOCK_PI: u64 = 6;
+const FUTEX_WAIT_BITSET: u64 = 9;
+const FUTEX_WAIT_REQUEUE_PI: u64 = 11;
+const FUTEX_LOCK_PI2: u64 = 13;
+const FUTEX_CMD_MASK: u64 = !(128 | 256); // FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME
+const FUTEX_TID_MASK: u32 = 0x3fff_ffff;
+
+// Why a thread blocked in a futex is waiting on another thread
+#[derive(Debug, PartialEq)]
+enum FutexWait {
+    // A lock held by the given thread
+    Lock { addr: u64, owner: Option<u64> },
+    // The given thread to exit, i.e. pthread_join()
+    Join { addr: u64, tid: u64 },
+}
+
+impl FutexWait {
+    fn waits_for(&self) -> Option<u64> {
+        match *self {
+            FutexWait::Lock { owner, .. } => owner,
+            FutexWait::Join { tid, .. } => Some(tid),
+        }
+    }
+}
+
+// Read 'buf.len()' bytes at 'addr' in the address space of process 'pid'. This doesn't stop or
+// otherwise disturb the process, but does require permission to ptrace it.
+fn read_process_memory(pid: u64, addr: u64, buf: &mut [u8]) -> Result<(), Box<dyn Error>> {
+    let len = buf.len();
+    let remote = [RemoteIoVec { base: addr as usize, len }];
+    let read = process_vm_readv(Pid::from_raw(pid as i32), &[IoVec::from_mut_slice(buf)], &remote)?;
+    if read != len {
+        return Err(From::from(format!("short read of {} bytes at {:#x}", read, addr)));
+    }
+    Ok(())
+}
+
+fn read_process_u32s(pid: u64, addr: u64, count: usize) -> Result<Vec<u32>, Box<dyn Error>> {
+    let mut buf = vec![0; count * 4];
+    read_process_memory(pid, addr, &mut buf)?;
+    Ok(buf.chunks(4).map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]])).collect())
+}
+
+// Work out what a thread blocked in futex(uaddr, op, ...) is waiting for, given the futex word and
+// the two words following it. 'tids' are the threads of the process.
+//
+// For priority inheritance futexes, the kernel defines the futex word to contain the TID of the
+// owner. For everything else, the meaning of the word is up to userspace, so we have to rely on
+// knowledge of glibc:
+//  - pthread_join() waits for the tid field of the thread being joined to be cleared, so the word
+//    is the TID of a thread in the process.
+//  - A locked pthread_mutex_t has a non-zero word, followed by the mutex recursion count and then
+//    the TID of the owner.
+fn futex_wait(op: u64, addr: u64, words: &[u32], tids: &[u64]) -> Option<FutexWait> {
+    let is_tid = |tid: u32| tid != 0 && tids.contains(&(tid as u64));
+    match op & FUTEX_CMD_MASK {
+        FUTEX_LOCK_PI | FUTEX_LOCK_PI2 | FUTEX_WAIT_REQUEUE_PI => {
+            let owner = words[0] & FUTEX_TID_MASK;
+            Some(FutexWait::Lock { addr, owner: if is_tid(owner) { Some(owner as u64) } else { None } })
+        }
+        FUTEX_WAIT | FUTEX_WAIT_BITSET => {
+            if is_tid(words[0]) {
+                Some(FutexWait::Join { addr, tid: words[0] as u64 })
+            } else if words[0] != 0 && words.len() > 2 && is_tid(words[2]) {
+                Some(FutexWait::Lock { addr, owner: Some(words[2] as u64) })
+            } else {
+                Some(FutexWait::Lock { addr, owner: None })
+            }
+        }
+        _ => None,
+    }
+}
+
+// Find cycles in a wait-for graph where each thread waits for at most one other thread. Each cycle
+// is returned once, starting from its lowest TID.
+fn find_wait_cycles(waits_for: &HashMap<u64, u64>) -> Vec<Vec<u64>> {
+    let mut cycles = vec![];
+    let mut starts = waits_for.keys().cloned().collect::<Vec<_>>();
+    starts.sort();
+
+    for start in starts {
+        let mut path = vec![start];
+        let mut current = start;
+        while let Some(&next) = waits_for.get(&current) {
+            if next == start {
+                if path.iter().all(|&tid| tid >= start) {
+                    cycles.push(path);
+                }
+                break;
+            }
+            // Either a cycle which doesn't include 'start' (which we'll find when starting from one
+            // of its members), or we've walked more steps than there are threads.
+            if path.contains(&next) || path.len() > waits_for.len() {
+                break;
+            }
+            path.push(next);
+            current = next;
+        }
+    }
+
+    cycles
+}
+
+fn print_deadlocks(pid: u64) -> bool {
+    let tids = match task_ids(pid) {
+        Ok(tids) => tids,
+        Err(e) => {
+            eprintln!("Unable to read /proc/{}/task/: {}", pid, e);
+            return false;
+        }
+    };
+
+    let futex_nr = match SYSCALL_NAMES.iter().position(|&name| name == "futex") {
+        Some(nr) => nr as u64,
+        None => {
+            eprintln!("Don't know the futex syscall number on this architecture");
+            return false;
+        }
+    };
+
+    print_proc_summary(pid);
+
+    let comm = |tid: u64| match read_task_file(pid, tid, "comm") {
+        Ok(comm) => format!("{} ({})", tid, comm.trim_end()),
+        Err(_) => tid.to_string(),
+    };
+
+    let mut waits = vec![];
+    for &tid in &tids {
+        let (op, addr) = match read_task_syscall(pid, tid) {
+            Ok(TaskSyscall::Syscall { nr, args }) if nr == futex_nr => (args[1], args[0]),
+            Ok(_) => continue,
+            // The thread exited since we listed them, so it isn't waiting on anything
+            Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|e| {
+                e.kind() == ErrorKind::NotFound || e.raw_os_error() == Some(libc::ESRCH)
+            }) => continue,
+            Err(e) => {
+                eprintln!("Unable to read syscall of thread {}: {}", tid, e);
+                return false;
+            }
+        };
+
+        let wait = match read_process_u32s(pid, addr, 3).or_else(|_| read_process_u32s(pid, addr, 1)) {
+            Ok(words) => futex_wait(op, addr, &words, &tids),
+            Err(e) => {
+                eprintln!("Unable to read futex word of thread {} at {:#x}: {}", tid, addr, e);
+                Some(FutexWait::Lock { addr, owner: None })
+            }
+        };
+
+        if let Some(wait) = wait {
+            waits.push((tid, wait));
+        }
+    }
+
+    if waits.is_empty() {
+        println!("  no threads waiting on locks");
+        return true;
+    }
+
+    println!("  waiting threads:");
+    for &(tid, ref wait) in &waits {
+        match *wait {
+            FutexWait::Lock { addr, owner: Some(owner) } =>
+                println!("    {} waits for lock {:#x} held by {}", comm(tid), addr, comm(owner)),
+            FutexWait::Lock { addr, owner: None } =>
+                println!("    {} waits on futex {:#x} (owner unknown)", comm(tid), addr),
+            FutexWait::Join { tid: joined, .. } =>
+                println!("    {} waits for thread {} to exit", comm(tid), comm(joined)),
+        }
+    }
+
+    let waits_for = waits.iter()
+        .filter_map(|&(tid, ref wait)| wait.waits_for().map(|other| (tid, other)))
+        .collect::<HashMap<_, _>>();
+    let cycles = find_wait_cycles(&waits_for);
+    if cycles.is_empty() {
+        println!("  no deadlock cycles found");
+    }
+    for cycle in cycles {
+        let mut names = cycle.iter().map(|&tid| comm(tid)).collect::<Vec<_>>();
+        names.push(comm(cycle[0]));
+        println!("  DEADLOCK: {}", names.join(" -> "));
+    }
+
+    true
+}
+
+// Core files are ELF files with a PT_NOTE segment describing the process, and a PT_LOAD segment for
+// each mapping in its address space (see fill_note_info() in the kernel's fs/binfmt_elf.c). We only
+// handle ELF files of the same word size and byte order as ourselves, which covers cores and
+// binaries from the system being debugged.
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+#[cfg(target_endian = "little")]
+const ELFDATA_NATIVE: u8 = 1;
+#[cfg(target_endian = "big")]
+const ELFDATA_NATIVE: u8 = 2;
+const ET_CORE: u16 = 4;
+const PN_XNUM: u16 = 0xffff;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_PRPSINFO: u32 = 3;
+const NT_AUXV: u32 = 6;
+const NT_SIGINFO: u32 = 0x5349_4749;
+
+struct CoreNote {
+    name: String,
+    n_type: u32,
+    desc: Vec<u8>,
+}
+
+// A mapping of the process, and where its contents are in the core. Pages which weren't dumped
+// (e.g. read-only file mappings) have a smaller filesz than the mapping.
+struct CoreLoad {
+    vaddr: u64,
+    memsz: u64,
+    offset: u64,
+    filesz: u64,
+}
+
+struct CoreFile<R> {
+    reader: R,
+    notes: Vec<CoreNote>,
+    loads: Vec<CoreLoad>,
+}
+
+fn read_u16(buf: &[u8], off: usize) -> u16 {
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(&buf[off..off + 2]);
+    u16::from_ne_bytes(bytes)
+}
+
+fn read_u32(buf: &[u8], off: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[off..off + 4]);
+    u32::from_ne_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8], off: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[off..off + 8]);
+    u64::from_ne_bytes(bytes)
+}
+
+// Interpret a fixed size, NUL padded field as a string
+fn c_string(buf: &[u8]) -> String {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut buf = vec![0; len];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+// Like read_at(), for offsets and sizes which come from the file itself, so that a corrupt file
+// can't make us allocate more than the file holds
+fn read_extent<R: Read + Seek>(reader: &mut R, file_len: u64, offset: u64, len: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    match offset.checked_add(len) {
+        Some(end) if end <= file_len => read_at(reader, offset, len as usize),
+        _ => Err(From::from(format!("{} bytes at offset {} are past the end of the file ({} bytes)",
+                                    len, offset, file_len))),
+    }
+}
+
+// Read the ELF header at the start of a file, and check that we can parse the rest of it
+fn read_elf_header<R: Read + Seek>(reader: &mut R, what: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mk_err = |reason: &str| ParseError::new(what, reason);
+
+    let ehdr = read_at(reader, 0, 64).map_err(|_| mk_err("not an ELF file"))?;
+    if !ehdr.starts_with(ELF_MAGIC) {
+        return Err(From::from(mk_err("not an ELF file")));
+    }
+    if ehdr[4] != ELFCLASS64 || ehdr[5] != ELFDATA_NATIVE {
+        return Err(From::from(mk_err("only native 64-bit ELF files are supported")));
+    }
+    Ok(ehdr)
+}
+
+fn parse_notes(buf: &[u8]) -> Result<Vec<CoreNote>, ParseError> {
+    let mk_err = || ParseError::new("core file", "truncated note");
+    let align4 = |n: usize| (n + 3) & !3;
+
+    let mut notes = vec![];
+    let mut off = 0;
+    while off + 12 <= buf.len() {
+        let namesz = read_u32(buf, off) as usize;
+        let descsz = read_u32(buf, off + 4) as usize;
+        let n_type = read_u32(buf, off + 8);
+        let name_off = off + 12;
+        let desc_off = name_off + align4(namesz);
+        let desc = buf.get(desc_off..desc_off + descsz).ok_or_else(mk_err)?;
+        let name = buf.get(name_off..name_off + namesz).ok_or_else(mk_err)?;
+        notes.push(CoreNote { name: c_string(name), n_type, desc: desc.to_vec() });
+        off = desc_off + align4(descsz);
+    }
+    Ok(notes)
+}
+
+impl CoreFile<File> {
+    fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        CoreFile::parse(File::open(path)?)
+    }
+}
+
+impl<R: Read + Seek> CoreFile<R> {
+    fn parse(mut reader: R) -> Result<Self, Box<dyn Error>> {
+        let mk_err = |reason: &str| ParseError::new("core file", reason);
+
+        let ehdr = read_elf_header(&mut reader, "core file")?;
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        if read_u16(&ehdr, 16) != ET_CORE {
+            return Err(From::from(mk_err("not a core file")));
+        }
+        let phoff = read_u64(&ehdr, 32);
+        let shoff = read_u64(&ehdr, 40);
+        let phentsize = read_u16(&ehdr, 54) as usize;
+        if phentsize < 56 {
+            return Err(From::from(mk_err("program headers too small")));
+        }
+
+        // Cores with more mappings than fit in e_phnum store the real count in the first section
+        // header instead.
+        let phnum = match read_u16(&ehdr, 56) {
+            PN_XNUM => read_u32(&read_extent(&mut reader, file_len, shoff, 64)?, 44) as u64,
+            phnum => phnum as u64,
+        };
+
+        let phdrs = read_extent(&mut reader, file_len, phoff, phnum * phentsize as u64)?;
+        let mut notes = vec![];
+        let mut loads = vec![];
+        for phdr in phdrs.chunks(phentsize) {
+            let offset = read_u64(phdr, 8);
+            let filesz = read_u64(phdr, 32);
+            match read_u32(phdr, 0) {
+                PT_NOTE => {
+                    let buf = read_extent(&mut reader, file_len, offset, filesz)?;
+                    notes.extend(parse_notes(&buf)?);
+                }
+                PT_LOAD => loads.push(CoreLoad {
+                    vaddr: read_u64(phdr, 16),
+                    memsz: read_u64(phdr, 40),
+                    offset,
+                    filesz,
+                }),
+                _ => {}
+            }
+        }
+
+        Ok(CoreFile { reader, notes, loads })
+    }
+
+    // Returns the contents of the first note of the given type written by the kernel
+    fn note(&self, n_type: u32) -> Option<&[u8]> {
+        self.notes.iter()
+            .find(|note| note.name == "CORE" && note.n_type == n_type)
+            .map(|note| &note.desc[..])
+    }
+
+    // Read memory of the crashed process. Returns None if the address wasn't mapped or the page
+    // containing it wasn't included in the core.
+    fn read_memory(&mut self, addr: u64, len: usize) -> Option<Vec<u8>> {
+        let load = self.loads.iter().find(|l| l.vaddr <= addr && addr < l.vaddr + l.memsz)?;
+        let load_off = addr - load.vaddr;
+        if load_off + len as u64 > load.filesz {
+            return None;
+        }
+        read_at(&mut self.reader, load.offset + load_off, len).ok()
+    }
+
+    // Read a NUL terminated string from the memory of the crashed process
+    fn read_string(&mut self, addr: u64) -> Option<String> {
+        let mut bytes = vec![];
+        // Read a byte at a time, since the string may end right at the end of a mapping. These are
+        // short, and reads are buffered by the kernel anyway.
+        while bytes.len() < 4096 {
+            let b = self.read_memory(addr + bytes.len() as u64, 1)?[0];
+            if b == 0 {
+                return Some(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            bytes.push(b);
+        }
+        None
+    }
+}
+
+// Names of the entries in the auxiliary vector (see include/uapi/linux/auxvec.h), and whether the
+// value is a pointer to a string.
+fn auxv_name(a_type: u64) -> Option<(&'static str, bool)> {
+    Some(match a_type {
+        0 => ("AT_NULL", false),
+        1 => ("AT_IGNORE", false),
+        2 => ("AT_EXECFD", false),
+        3 => ("AT_PHDR", false),
+        4 => ("AT_PHENT", false),
+        5 => ("AT_PHNUM", false),
+        6 => ("AT_PAGESZ", false),
+        7 => ("AT_BASE", false),
+        8 => ("AT_FLAGS", false),
+        9 => ("AT_ENTRY", false),
+        10 => ("AT_NOTELF", false),
+        11 => ("AT_UID", false),
+        12 => ("AT_EUID", false),
+        13 => ("AT_GID", false),
+        14 => ("AT_EGID", false),
+        15 => ("AT_PLATFORM", true),
+        16 => ("AT_HWCAP", false),
+        17 => ("AT_CLKTCK", false),
+        23 => ("AT_SECURE", false),
+        24 => ("AT_BASE_PLATFORM", true),
+        25 => ("AT_RANDOM", false),
+        26 => ("AT_HWCAP2", false),
+        27 => ("AT_RSEQ_FEATURE_SIZE", false),
+        28 => ("AT_RSEQ_ALIGN", false),
+        29 => ("AT_HWCAP3", false),
+        30 => ("AT_HWCAP4", false),
+        31 => ("AT_EXECFN", true),
+        32 => ("AT_SYSINFO", false),
+        33 => ("AT_SYSINFO_EHDR", false),
+        51 => ("AT_MINSIGSTKSZ", false),
+        _ => return None,
+    })
+}
+
+fn parse_auxv(desc: &[u8]) -> Vec<(u64, u64)> {
+    desc.chunks(16)
+        .filter(|entry| entry.len() == 16)
+        .map(|entry| (read_u64(entry, 0), read_u64(entry, 8)))
+        .take_while(|&(a_type, _)| a_type != 0)
+        .collect()
+}
+
+// Describe si_code (see include/uapi/asm-generic/siginfo.h). Codes above zero are specific to the
+// signal; the rest say how a signal was sent.
+fn signal_code_str(signo: i32, code: i32) -> Option<&'static str> {
+    Some(match (signo, code) {
+        (_, 0) => "SI_USER",
+        (_, 0x80) => "SI_KERNEL",
+        (_, -1) => "SI_QUEUE",
+        (_, -2) => "SI_TIMER",
+        (_, -6) => "SI_TKILL",
+        (libc::SIGSEGV, 1) => "SEGV_MAPERR",
+        (libc::SIGSEGV, 2) => "SEGV_ACCERR",
+        (libc::SIGSEGV, 3) => "SEGV_BNDERR",
+        (libc::SIGSEGV, 4) => "SEGV_PKUERR",
+        (libc::SIGBUS, 1) => "BUS_ADRALN",
+        (libc::SIGBUS, 2) => "BUS_ADRERR",
+        (libc::SIGBUS, 3) => "BUS_OBJERR",
+        (libc::SIGBUS, 4) => "BUS_MCEERR_AR",
+        (libc::SIGBUS, 5) => "BUS_MCEERR_AO",
+        (libc::SIGILL, 1) => "ILL_ILLOPC",
+        (libc::SIGILL, 2) => "ILL_ILLOPN",
+        (libc::SIGILL, 3) => "ILL_ILLADR",
+        (libc::SIGILL, 4) => "ILL_ILLTRP",
+        (libc::SIGILL, 5) => "ILL_PRVOPC",
+        (libc::SIGILL, 6) => "ILL_PRVREG",
+        (libc::SIGILL, 7) => "ILL_COPROC",
+        (libc::SIGILL, 8) => "ILL_BADSTK",
+        (libc::SIGFPE, 1) => "FPE_INTDIV",
+        (libc::SIGFPE, 2) => "FPE_INTOVF",
+        (libc::SIGFPE, 3) => "FPE_FLTDIV",
+        (libc::SIGFPE, 4) => "FPE_FLTOVF",
+        (libc::SIGFPE, 5) => "FPE_FLTUND",
+        (libc::SIGFPE, 6) => "FPE_FLTRES",
+        (libc::SIGFPE, 7) => "FPE_FLTINV",
+        (libc::SIGFPE, 8) => "FPE_FLTSUB",
+        _ => return None,
+    })
+}
+
+fn signal_str(signo: i32) -> String {
+    match Signal::from_c_int(signo) {
+        Ok(signal) => format!("{} ({})", signal, signo),
+        Err(_) => format!("signal {}", signo),
+    }
+}
+
+// Describe the signal which killed the process, from NT_SIGINFO (a siginfo_t) if the kernel wrote
+// one, or else from the cursig field of NT_PRSTATUS.
+fn describe_core_signal<R: Read + Seek>(core: &CoreFile<R>) -> Option<String> {
+    if let Some(siginfo) = core.note(NT_SIGINFO).filter(|s| s.len() >= 24) {
+        let signo = read_u32(siginfo, 0) as i32;
+        let code = read_u32(siginfo, 8) as i32;
+        let mut desc = format!("{} code {}", signal_str(signo), code);
+        if let Some(code_str) = signal_code_str(signo, code) {
+            desc.push_str(&format!(" ({})", code_str));
+        }
+        match signo {
+            // The union following the first three fields is 8 byte aligned
+            libc::SIGSEGV | libc::SIGBUS | libc::SIGILL | libc::SIGFPE if code > 0 => {
+                desc.push_str(&format!(" addr 0x{:x}", read_u64(siginfo, 16)));
+            }
+            _ if code <= 0 => {
+                desc.push_str(&format!(" from pid {} uid {}", read_u32(siginfo, 16), read_u32(siginfo, 20)));
+            }
+            _ => {}
+        }
+        return Some(desc);
+    }
+    core.note(NT_PRSTATUS)
+        .filter(|s| s.len() >= 14)
+        .map(|prstatus| signal_str(read_u16(prstatus, 12) as i32))
+}
+
+// Print what pargs can tell from a core: the command, the signal which killed the process, and its
+// auxiliary vector.
+fn print_core_args(path: &str) -> bool {
+    let mut core = match CoreFile::open(path) {
+        Ok(core) => core,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return false;
+        }
+    };
+
+    // struct elf_prpsinfo, which has the pid at offset 24 and the first 80 bytes of the command
+    // line at offset 56.
+    match core.note(NT_PRPSINFO).filter(|p| p.len() >= 136) {
+        Some(prpsinfo) => println!("core '{}' of {}:\t{}", path, read_u32(prpsinfo, 24),
+                                   c_string(&prpsinfo[56..136]).trim_end()),
+        None => println!("core '{}':", path),
+    }
+
+    match describe_core_signal(&core) {
+        Some(signal) => println!("signal: {}", signal),
+        None => println!("signal: <unknown>"),
+    }
+
+    let auxv = core.note(NT_AUXV).map(parse_auxv).unwrap_or_default();
+    for (a_type, value) in auxv {
+        match auxv_name(a_type) {
+            Some((name, is_string)) => {
+                print!("{:<20} 0x{:016x}", name, value);
+                if is_string {
+                    if let Some(s) = core.read_string(value) {
+                        print!(" {}", s);
+                    }
+                }
+                println!();
+            }
+            None => println!("{:<20} 0x{:016x}", a_type, value),
+        }
+    }
+
+    true
+}
+
+// ptoolsd serves the data the other tools print as JSON-RPC 2.0 over a UNIX socket, so that
+// monitoring agents can poll it without paying for a fork/exec per query. Each request and response
+// is a single line of JSON. Results are cached for a short time, so that many agents asking about
+// the same process at once don't each rescan /proc, and so that they all see the same snapshot.
+
+const RPC_PARSE_ERROR: i64 = -32700;
+const RPC_INVALID_REQUEST: i64 = -32600;
+const RPC_METHOD_NOT_FOUND: i64 = -32601;
+const RPC_INVALID_PARAMS: i64 = -32602;
+// Start of the range JSON-RPC reserves for implementation defined errors
+const RPC_SERVER_ERROR: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> Self {
+        RpcError { code, message: message.to_string() }
+    }
+}
+
+impl From<Box<dyn Error>> for RpcError {
+    fn from(e: Box<dyn Error>) -> Self {
+        RpcError::new(RPC_SERVER_ERROR, &e.to_string())
+    }
+}
+
+// Rendered results of recent calls, keyed by method and params
+struct SnapshotCache {
+    ttl: Duration,
+    entries: HashMap<String, (Instant, String)>,
+}
+
+impl SnapshotCache {
+    fn get(&self, key: &str) -> Option<String> {
+        match self.entries.get(key) {
+            Some(&(time, ref result)) if time.elapsed() < self.ttl => Some(result.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, key: String, result: String) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, &mut (time, _)| time.elapsed() < ttl);
+        self.entries.insert(key, (Instant::now(), result));
+    }
+}
+
+fn rpc_opt_pid(params: Option<&Json>) -> Result<Option<u64>, RpcError> {
+    match params.and_then(|p| p.get("pid")) {
+        Some(pid) => match pid.as_u64() {
+            Some(pid) => Ok(Some(pid)),
+            None => Err(RpcError::new(RPC_INVALID_PARAMS, "'pid' must be a non-negative integer")),
+        },
+        None => Ok(None),
+    }
+}
+
+fn rpc_pid(params: Option<&Json>) -> Result<u64, RpcError> {
+    rpc_opt_pid(params)?.ok_or_else(|| RpcError::new(RPC_INVALID_PARAMS, "missing parameter 'pid'"))
+}
+
+fn rpc_check_pid(pid: u64) -> Result<(), RpcError> {
+    if !Path::new(&proc_path!("{}", pid)).exists() {
+        return Err(RpcError::new(RPC_SERVER_ERROR, &format!("No such process {}", pid)));
+    }
+    Ok(())
+}
+
+fn rpc_strings(pid: u64, file: &str) -> Result<Json, RpcError> {
+    rpc_check_pid(pid)?;
+    let strings = read_nul_strings(&proc_path!("{}/{}", pid, file))?;
+    Ok(Json::Array(strings.into_iter().map(Json::Str).collect()))
+}
+
+fn rpc_files(pid: u64) -> Result<Json, RpcError> {
+    rpc_check_pid(pid)?;
+    let sockets = fetch_sock_info(pid)?;
+    let files = read_fds(pid)?.into_iter().filter_map(|fd| file_info(pid, fd));
+    Ok(files_json(pid, files, &sockets))
+}
+
+// Returns every process, or only pid and its descendants
+fn rpc_tree(pid: Option<u64>) -> Result<Json, RpcError> {
+    let tree = read_process_tree()?;
+    let parent_map = &tree.parent_map;
+
+    let mut pids = match pid {
+        Some(pid) => {
+            if !parent_map.contains_key(&pid) {
+                return Err(RpcError::new(RPC_SERVER_ERROR, &format!("No such process {}", pid)));
+            }
+            subtree_pids(&tree, pid)
+        }
+        None => parent_map.keys().cloned().collect(),
+    };
+    pids.sort();
+    Ok(tree_json(&tree, pids))
+}
+
+// The processes of a tree, with their parents
+fn tree_json(tree: &ProcessTree, pids: Vec<u64>) -> Json {
+    let parent_map = &tree.parent_map;
+    // The ids let clients which poll tell a new process from an old one which had the same pid
+    let id = |pid| ProcessId::read(pid).map_or(Json::Null, |id| Json::Str(id.to_string()));
+    Json::Array(pids.into_iter().filter(|pid| parent_map.contains_key(pid)).map(|pid| Json::object(vec![
+        ("pid", Json::UInt(pid)),
+        ("ppid", Json::UInt(parent_map[&pid])),
+        ("id", id(pid)),
+        ("parent_id", id(parent_map[&pid])),
+        ("cmdline", Json::Str(cmd_summary(pid).trim_end().to_string())),
+    ])).collect())
+}
+
+fn rpc_call(method: &str, params: Option<&Json>) -> Result<Json, RpcError> {
+    match method {
+        "args" => rpc_strings(rpc_pid(params)?, "cmdline"),
+        "env" => rpc_strings(rpc_pid(params)?, "environ"),
+        "files" => rpc_files(rpc_pid(params)?),
+        "tree" => rpc_tree(rpc_opt_pid(params)?),
+        _ => Err(RpcError::new(RPC_METHOD_NOT_FOUND, &format!("unknown method '{}'", method))),
+    }
+}
+
+fn rpc_response(id: &Json, result: Result<String, RpcError>) -> String {
+    match result {
+        Ok(result) => format!("{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{}}}", id, result),
+        Err(e) => {
+            let error = Json::object(vec![
+                ("code", Json::Int(e.code)),
+                ("message", Json::Str(e.message)),
+            ]);
+            format!("{{\"jsonrpc\":\"2.0\",\"id\":{},\"error\":{}}}", id, error)
+        }
+    }
+}
+
+// Handle one line of input, returning the response to send, if any. Notifications (requests without
+// an id) get no response.
+fn rpc_handle(line: &str, cache: &Mutex<SnapshotCache>) -> Option<String> {
+    let request = match Json::parse(line) {
+        Ok(request) => request,
+        Err(e) => return Some(rpc_response(&Json::Null, Err(RpcError::new(RPC_PARSE_ERROR, &e.to_string())))),
+    };
+
+    let id = request.get("id");
+    let method = match request.get("method").and_then(Json::as_str) {
+        Some(method) => method,
+        None => {
+            let e = RpcError::new(RPC_INVALID_REQUEST, "missing 'method'");
+            return Some(rpc_response(id.unwrap_or(&Json::Null), Err(e)));
+        }
+    };
+    let params = request.get("params");
+
+    let key = match params {
+        Some(params) => format!("{} {}", method, params),
+        None => method.to_string(),
+    };
+    let cached = cache.lock().unwrap().get(&key);
+    let result = match cached {
+        Some(result) => Ok(result),
+        None => rpc_call(method, params).map(|result| {
+            let result = result.to_string();
+            cache.lock().unwrap().insert(key, result.clone());
+            result
+        }),
+    };
+
+    id.map(|id| rpc_response(id, result))
+}
+
+// How many connections ptoolsd serves at once
+const RPC_MAX_CONNECTIONS: usize = 64;
+
+fn rpc_serve_connection(stream: UnixStream, cache: &Mutex<SnapshotCache>) -> Result<(), Box<dyn Error>> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = rpc_handle(&line, cache) {
+            writeln!(writer, "{}", response)?;
+        }
+    }
+    Ok(())
+}
+
+fn bind_socket(path: &str) -> Result<UnixListener, Box<dyn Error>> {
+    // A socket left behind by an instance which didn't shut down cleanly would make bind() fail, so
+    // remove it, but only if nothing is listening on it anymore.
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(From::from(format!("{} exists and is not a socket", path)));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(From::from(format!("{} is already in use", path)));
+        }
+        fs::remove_file(path)?;
+    }
+
+    // The environment of a process can contain secrets, so only our own user may connect. Set the
+    // umask rather than changing the mode after bind() so there is no window where others can.
+    let old_umask = umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(path);
+    umask(old_umask);
+    Ok(listener?)
+}
+
+// What pcompare reports on, along with the clone() flag which causes it to be shared. The I/O
+// context and semaphore undo list are only allocated once a process needs them, and kcmp() also
+// reports them as the same when neither process has one.
+const KCMP_RESOURCES: &[(i32, &str, &str, bool)] = &[
+    (KCMP_VM, "address space", "CLONE_VM", false),
+    (KCMP_FILES, "fd table", "CLONE_FILES", false),
+    (KCMP_FS, "filesystem info (root, cwd, umask)", "CLONE_FS", false),
+    (KCMP_SIGHAND, "signal handlers", "CLONE_SIGHAND", false),
+    (KCMP_IO, "I/O context", "CLONE_IO", true),
+    (KCMP_SYSVSEM, "System V semaphore undo list", "CLONE_SYSVSEM", true),
+];
+
+fn print_comparison(pid1: u64, pid2: u64) -> bool {
+    for &pid in &[pid1, pid2] {
+        if !Path::new(&format!("/proc/{}", pid)).exists() {
+            eprintln!("No such process {}", pid);
+            return false;
+        }
+        print_proc_summary(pid);
+    }
+
+    let mut error = false;
+    for &(kcmp_type, resource, flag, may_be_unset) in KCMP_RESOURCES {
+        let desc = match kcmp(pid1, pid2, kcmp_type, 0, 0) {
+            Ok(true) if may_be_unset => format!("shared ({}), or unused by both", flag),
+            Ok(true) => format!("shared ({})", flag),
+            Ok(false) => "separate".to_string(),
+            Err(e) => {
+                error = true;
+                format!("<unknown: {}>", e)
+            }
+        };
+        println!("  {:<36} {}", format!("{}:", resource), desc);
+    }
+
+    // Only fds referring to the same file can share an open file description, so only compare those
+    let fds = |pid: u64| -> Vec<(u64, (u64, u64))> {
+        read_fds(pid).unwrap_or_else(|e| {
+            eprintln!("Unable to read /proc/{}/fd/: {}", pid, e);
+            vec![]
+        }).into_iter()
+            .filter_map(|fd| stat(format!("/proc/{}/fd/{}", pid, fd).as_str()).ok()
+                .map(|st| (fd, (st.st_dev, st.st_ino))))
+            .collect()
+    };
+    let fds2 = fds(pid2);
+    let mut shared = vec![];
+    for (fd1, inode1) in fds(pid1) {
+        for &(fd2, _) in fds2.iter().filter(|&&(_, inode2)| inode2 == inode1) {
+            match kcmp(pid1, pid2, KCMP_FILE, fd1, fd2) {
+                Ok(true) => shared.push((fd1, fd2)),
+                Ok(false) => {}
+                Err(e) => {
+                    eprintln!("Unable to compare fd {} of {} with fd {} of {}: {}", fd1, pid1, fd2, pid2, e);
+                    error = true;
+                }
+            }
+        }
+    }
+
+    if shared.is_empty() {
+        println!("  no shared open file descriptions");
+    } else {
+        println!("  shared open file descriptions:");
+        for (fd1, fd2) in shared {
+            let path = fs::read_link(format!("/proc/{}/fd/{}", pid1, fd1))
+                .map(|p| p.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| "<unknown>".to_string());
+            println!("    {: >4} = {: <4}  {}", fd1, fd2, path);
+        }
+    }
+
+    !error
+}
+
+// A mount, as listed in /proc/[pid]/mountinfo
+#[derive(Debug)]
+struct MountInfo {
+    // The mount's unique id, as fanotify and statx() give it
+    id: u64,
+    // major:minor of the filesystem
+    dev: String,
+    // The directory within the filesystem which is mounted, e.g. for bind mounts
+    root: String,
+    mount_point: String,
+    fs_type: String,
+    // e.g. the device the filesystem is on
+    source: String,
+    // The options of the filesystem (as opposed to those of the mount)
+    options: String,
+}
+
+// Paths in mountinfo have spaces, tabs, newlines and backslashes escaped as octal, e.g. '\040'
+fn unescape_mount_path(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(|b| (b'0'..=b'7').contains(b)) {
+            let octal = from_utf8(&bytes[i + 1..i + 4]).unwrap();
+            out.push(u8::from_str_radix(octal, 8).unwrap_or(b'?'));
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Lines look like
+//
+//    36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+//
+// where the fields after the mount point up to the '-' are optional.
+fn parse_mountinfo(contents: &str) -> Vec<MountInfo> {
+    contents.lines().filter_map(|line| {
+        let fields = line.split(' ').collect::<Vec<_>>();
+        if fields.len() < 5 {
+            return None;
+        }
+        let fs = fields.iter().position(|&f| f == "-").map_or(&[][..], |i| &fields[i + 1..]);
+        Some(MountInfo {
+            id: fields[0].parse().ok()?,
+            dev: fields[2].to_string(),
+            root: unescape_mount_path(fields[3]),
+            mount_point: unescape_mount_path(fields[4]),
+            fs_type: fs.first().map_or("", |f| f).to_string(),
+            source: fs.get(1).map_or(String::new(), |f| unescape_mount_path(f)),
+            options: fs.get(2).map_or(String::new(), |f| unescape_mount_path(f)),
+        })
+    }).collect()
+}
+
+// If path is prefix or is inside the directory prefix, returns the rest of path, with a leading '/'
+// unless it is empty.
+fn strip_dir_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    if !path.starts_with(prefix) {
+        return None;
+    }
+    let rest = &path[prefix.len()..];
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+// Given a path as seen through the mounts in 'from', find where the same file might be seen
+// through the mounts in 'to'. There can be several such paths, e.g. with bind mounts. None of them
+// are guaranteed to be right, since the mounts could hide one another, so callers should check.
+fn translate_path(path: &str, from: &[MountInfo], to: &[MountInfo]) -> Vec<String> {
+    // The mount the path is on is the one with the longest mount point containing it. Later mounts
+    // at the same point hide earlier ones.
+    let mount = from.iter()
+        .filter(|m| strip_dir_prefix(path, &m.mount_point).is_some())
+        .max_by_key(|m| m.mount_point.trim_end_matches('/').len());
+    let mount = match mount {
+        Some(mount) => mount,
+        None => return vec![],
+    };
+    let fs_path = format!("{}{}", mount.root.trim_end_matches('/'),
+                          strip_dir_prefix(path, &mount.mount_point).unwrap());
+
+    to.iter()
+        .filter(|m| m.dev == mount.dev)
+        .filter_map(|m| strip_dir_prefix(&fs_path, &m.root)
+            .map(|rest| format!("{}{}", m.mount_point.trim_end_matches('/'), rest)))
+        .map(|p| match p.trim_end_matches('/') {
+            "" => "/".to_string(),
+            p => p.to_string(),
+        })
+        .collect()
+}
+
+// Split overlayfs mount options, in which ',' and ':' inside paths are escaped with '\'. The
+// escapes are kept, since the parts may need splitting again.
+fn split_escaped(s: &str, sep: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => parts.last_mut().unwrap().extend(Some(c).into_iter().chain(chars.next())),
+            c if c == sep => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts
+}
+
+fn unescape_option(s: &str) -> String {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        out.extend(if c == '\\' { chars.next() } else { Some(c) });
+    }
+    out
+}
+
+// The layers of an overlay filesystem, from its mount options: the upper layer, if it is
+// writable, and the lower layers, from the top down. Newer kernels accept one
+// 'lowerdir+=' option per layer as well as a ':' separated 'lowerdir='.
+#[derive(Debug, PartialEq)]
+struct OverlayLayers {
+    upper: Option<String>,
+    lower: Vec<String>,
+}
+
+fn overlay_layers(options: &str) -> OverlayLayers {
+    let mut layers = OverlayLayers { upper: None, lower: vec![] };
+    for option in split_escaped(options, ',') {
+        if let Some(dirs) = option.strip_prefix("lowerdir=") {
+            // Data-only layers follow '::', see Documentation/filesystems/overlayfs.rst
+            layers.lower.extend(split_escaped(dirs, ':').iter().filter(|dir| !dir.is_empty()).map(|dir| unescape_option(dir)));
+        } else if let Some(dir) = option.strip_prefix("lowerdir+=") {
+            layers.lower.push(unescape_option(dir));
+        } else if let Some(dir) = option.strip_prefix("upperdir=") {
+            layers.upper = Some(unescape_option(dir));
+        }
+    }
+    layers
+}
+
+// Which layer of an overlay filesystem backs an open file
+#[derive(Debug, PartialEq)]
+struct OverlayOrigin {
+    // None for the upper layer, or which lower layer, counting from 1 at the top
+    lower: Option<usize>,
+    dir: String,
+    // For files in the upper layer, the lower layer they were copied up from
+    copied_from: Option<usize>,
+}
+
+// Find the layer backing the file at 'path' (as the process sees it) with inode number 'ino', on
+// the filesystem 'dev' (major:minor). 'lookup' gives the inode number of a file in a layer
+// directory, if it is there and isn't a whiteout. As with any lookup in the overlay, the topmost
+// layer with the file backs it; fds opened before a copy-up switch over to the copy. Files keep
+// the inode numbers of their lower layer files when copied up, so a lower layer with a matching
+// inode is the one it was copied from. Without a match (e.g. with 'xino', which adds high bits),
+// it is the topmost lower layer with the file.
+fn overlay_origin(path: &str, dev: &str, ino: u64, mounts: &[MountInfo],
+                  lookup: &dyn Fn(&str) -> Option<u64>) -> Option<OverlayOrigin> {
+    let mount = mounts.iter()
+        .filter(|m| m.fs_type == "overlay" && m.dev == dev && strip_dir_prefix(path, &m.mount_point).is_some())
+        .max_by_key(|m| m.mount_point.trim_end_matches('/').len())?;
+    let fs_path = format!("{}{}", mount.root.trim_end_matches('/'), strip_dir_prefix(path, &mount.mount_point)?);
+    let layers = overlay_layers(&mount.options);
+
+    let candidates = layers.upper.iter().map(|dir| (None, dir))
+        .chain(layers.lower.iter().enumerate().map(|(i, dir)| (Some(i + 1), dir)));
+    let found = candidates
+        .filter_map(|(lower, dir)| lookup(&format!("{}{}", dir.trim_end_matches('/'), fs_path)).map(|i| (lower, dir, i)))
+        .collect::<Vec<_>>();
+    let &(lower, dir, _) = found.first()?;
+    let copied_from = match lower {
+        None => found.iter().find(|&&(lower, _, i)| lower.is_some() && i == ino)
+            .or_else(|| found.get(1))
+            .and_then(|&(lower, _, _)| lower),
+        Some(_) => None,
+    };
+
+    Some(OverlayOrigin { lower, dir: dir.clone(), copied_from })
+}
+
+// The inode number of a file in an overlay layer directory. Whiteouts, which hide files in lower
+// layers, are character devices numbered 0:0.
+fn overlay_layer_ino(path: &str) -> Option<u64> {
+    let st = nix::sys::stat::lstat(path).ok()?;
+    if SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFCHR && st.st_rdev == 0 {
+        return None;
+    }
+    Some(st.st_ino)
+}
+
+fn overlay_layer_str(lower: Option<usize>) -> String {
+    match lower {
+        None => "upper layer".to_string(),
+        Some(i) => format!("lower layer {}", i),
+    }
+}
+
+fn overlay_origin_str(origin: &OverlayOrigin) -> String {
+    let layer = format!("{} {}", overlay_layer_str(origin.lower), origin.dir);
+    match (origin.lower, origin.copied_from) {
+        (None, Some(from)) => format!("{}, copied up from {}", layer, overlay_layer_str(Some(from))),
+        (None, None) => format!("{}, created there", layer),
+        (Some(_), _) => format!("{}, not copied up", layer),
+    }
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    match (stat(a), stat(b)) {
+        (Ok(a), Ok(b)) => (a.st_dev, a.st_ino) == (b.st_dev, b.st_ino),
+        _ => false,
+    }
+}
+
+// Print the current working directory of a process, or its root directory if 'root' is set. The
+// kernel shows the path relative to the process' root, which for a process in a container isn't
+// where we'd find the directory, so also show where it is from our point of view.
+fn print_dir(pid: u64, root: bool) -> bool {
+    let link = proc_path!("{}/{}", pid, if root { "root" } else { "cwd" });
+    let path = match read_proc_link(&link) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(e) => {
+            eprintln!("{}: {}", link, e);
+            return false;
+        }
+    };
+
+    // A capture doesn't record where the directory is from our point of view
+    let note = if is_capture() || same_file(&path, &link) {
+        None
+    } else {
+        let mountinfo = |pid: &str| fs::read_to_string(proc_path!("{}/mountinfo", pid))
+            .map(|contents| parse_mountinfo(&contents))
+            .unwrap_or_default();
+        let here = translate_path(&path, &mountinfo(&pid.to_string()), &mountinfo("self"))
+            .into_iter()
+            .find(|p| same_file(p, &link));
+        match here {
+            Some(here) => Some(format!("here: {}", here)),
+            None => Some(format!("not visible here, use {}", link)),
+        }
+    };
+
+    match note {
+        Some(note) => println!("{}: {} ({})", pid, path, note),
+        None => println!("{}: {}", pid, path),
+    }
+    true
+}
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_DYNSYM: u32 = 11;
+const STT_FUNC: u8 = 2;
+const STT_GNU_IFUNC: u8 = 10;
+
+// A cursor over the contents of an ELF section, for the variable length encodings used by DWARF.
+// Everything returns None when reading past the end, so that truncated or corrupt data just stops
+// unwinding.
+struct DwarfReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> DwarfReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| read_u16(b, 0))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| read_u32(b, 0))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8).map(|b| read_u64(b, 0))
+    }
+
+    fn uleb128(&mut self) -> Option<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb128(&mut self) -> Option<i64> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= i64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    fn c_str(&mut self) -> Option<&'a [u8]> {
+        let len = self.data.get(self.pos..)?.iter().position(|&b| b == 0)?;
+        let s = self.bytes(len)?;
+        self.pos += 1;
+        Some(s)
+    }
+
+    // Read a pointer in one of the DW_EH_PE_* encodings used by .eh_frame. 'addr' is the address
+    // the start of the data is loaded at, for pc-relative pointers.
+    fn pointer(&mut self, encoding: u8, addr: u64) -> Option<u64> {
+        let field_addr = addr.wrapping_add(self.pos as u64);
+        let value = match encoding & 0x0f {
+            0x00 | 0x04 | 0x0c => self.u64()?,
+            0x01 => self.uleb128()?,
+            0x02 => u64::from(self.u16()?),
+            0x03 => u64::from(self.u32()?),
+            0x09 => self.sleb128()? as u64,
+            0x0a => self.u16()? as i16 as u64,
+            0x0b => self.u32()? as i32 as u64,
+            _ => return None,
+        };
+        match encoding & 0x70 {
+            0x00 => Some(value),
+            0x10 => Some(field_addr.wrapping_add(value)),
+            _ => None,
+        }
+    }
+}
+
+// DWARF numbers the registers of each architecture. These are the ones unwinding needs to know
+// about; the return address column comes from the CIE.
+#[cfg(not(target_arch = "aarch64"))]
+const DWARF_REG_SP: usize = 7;
+#[cfg(not(target_arch = "aarch64"))]
+const DWARF_REG_FP: usize = 6;
+#[cfg(target_arch = "aarch64")]
+const DWARF_REG_SP: usize = 31;
+#[cfg(target_arch = "aarch64")]
+const DWARF_REG_FP: usize = 29;
+// Enough for x86_64's general purpose registers and return address column (16), and aarch64's
+// general purpose registers and stack pointer (0-31)
+const DWARF_REGS: usize = 32;
+
+// The registers of a thread, as of one of the frames on its stack. Registers which we can't recover
+// in callers' frames are None.
+#[derive(Clone)]
+struct Registers {
+    pc: u64,
+    regs: [Option<u64>; DWARF_REGS],
+}
+
+impl Registers {
+    fn sp(&self) -> Option<u64> {
+        self.regs[DWARF_REG_SP]
+    }
+}
+
+// Common Information Entry, holding what is shared by the FDEs of a file
+struct Cie {
+    code_align: u64,
+    data_align: i64,
+    ra_reg: usize,
+    fde_encoding: u8,
+    has_augmentation_data: bool,
+    // Frames of signal handlers, where the 'return address' is the interrupted instruction itself
+    signal_frame: bool,
+    instructions: std::ops::Range<usize>,
+}
+
+// Frame Description Entry, describing how to unwind a range of code
+struct Fde {
+    start: u64,
+    end: u64,
+    cie: usize,
+    instructions: std::ops::Range<usize>,
+}
+
+// The contents of a .eh_frame section, which is kept even in stripped binaries, since it is needed to
+// unwind for C++ exceptions.
+struct EhFrame {
+    // Where the section is loaded, relative to the start of the file's mappings
+    addr: u64,
+    data: Vec<u8>,
+    cies: HashMap<usize, Cie>,
+    // Sorted by start address
+    fdes: Vec<Fde>,
+}
+
+fn parse_cie(data: &[u8], offset: usize, end: usize, addr: u64) -> Option<Cie> {
+    let mut r = DwarfReader { data: &data[..end], pos: offset };
+    let version = r.u8()?;
+    let augmentation = r.c_str()?;
+    if version >= 4 {
+        // Address and segment selector size
+        r.bytes(2)?;
+    }
+    let code_align = r.uleb128()?;
+    let data_align = r.sleb128()?;
+    let ra_reg = if version == 1 { u64::from(r.u8()?) } else { r.uleb128()? } as usize;
+
+    let mut cie = Cie {
+        code_align,
+        data_align,
+        ra_reg,
+        fde_encoding: 0,
+        has_augmentation_data: false,
+        signal_frame: false,
+        instructions: 0..0,
+    };
+    if augmentation.first() == Some(&b'z') {
+        cie.has_augmentation_data = true;
+        let len = r.uleb128()? as usize;
+        let aug_end = r.pos + len;
+        for &c in &augmentation[1..] {
+            match c {
+                b'R' => cie.fde_encoding = r.u8()?,
+                b'L' => {
+                    r.u8()?;
+                }
+                b'P' => {
+                    let encoding = r.u8()?;
+                    r.pointer(encoding & 0x7f, addr)?;
+                }
+                b'S' => cie.signal_frame = true,
+                // Anything else can be skipped over using the length
+                _ => break,
+            }
+        }
+        r.pos = aug_end;
+    } else if !augmentation.is_empty() {
+        return None;
+    }
+    cie.instructions = r.pos..end;
+    Some(cie)
+}
+
+impl EhFrame {
+    fn parse(data: Vec<u8>, addr: u64) -> EhFrame {
+        let mut cies = HashMap::new();
+        let mut fdes = vec![];
+
+        let mut r = DwarfReader { data: &data, pos: 0 };
+        while let Some(len) = r.u32() {
+            let len = match len {
+                0 => break,
+                0xffff_ffff => match r.u64() {
+                    Some(len) => len as usize,
+                    None => break,
+                },
+                len => len as usize,
+            };
+            let id_pos = r.pos;
+            let end = match id_pos.checked_add(len) {
+                Some(end) if end <= data.len() => end,
+                _ => break,
+            };
+            let id = match r.u32() {
+                Some(id) => id as usize,
+                None => break,
+            };
+
+            // The id of a CIE is 0; for an FDE it is the distance back to the start of its CIE
+            if id != 0 && id <= id_pos {
+                let cie_pos = id_pos - id;
+                if let Entry::Vacant(entry) = cies.entry(cie_pos) {
+                    let cie_len = DwarfReader { data: &data, pos: cie_pos }.u32().unwrap_or(0) as usize;
+                    let cie_end = (cie_pos + 4 + cie_len).min(data.len());
+                    if let Some(cie) = parse_cie(&data, cie_pos + 8, cie_end, addr) {
+                        entry.insert(cie);
+                    }
+                }
+                if let Some(cie) = cies.get(&cie_pos) {
+                    let start = r.pointer(cie.fde_encoding, addr);
+                    let range = r.pointer(cie.fde_encoding & 0x0f, addr);
+                    let aug_len = if cie.has_augmentation_data { r.uleb128() } else { Some(0) };
+                    if let (Some(start), Some(range), Some(aug_len)) = (start, range, aug_len) {
+                        let instructions_start = r.pos + aug_len as usize;
+                        if instructions_start <= end {
+                            fdes.push(Fde { start, end: start.wrapping_add(range), cie: cie_pos,
+                                            instructions: instructions_start..end });
+                        }
+                    }
+                }
+            }
+            r.pos = end;
+        }
+
+        fdes.sort_by_key(|fde| fde.start);
+        EhFrame { addr, data, cies, fdes }
+    }
+
+    fn find_fde(&self, addr: u64) -> Option<&Fde> {
+        let i = match self.fdes.binary_search_by(|fde| fde.start.cmp(&addr)) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        Some(&self.fdes[i]).filter(|fde| addr < fde.end)
+    }
+}
+
+// How to find the value a register had in the caller's frame
+#[derive(Clone)]
+enum RegRule {
+    Undefined,
+    SameValue,
+    // Saved at CFA+N
+    Offset(i64),
+    // Is CFA+N
+    ValOffset(i64),
+    // Is in another register
+    Register(usize),
+    // Saved at the address computed by the expression
+    Expression(Vec<u8>),
+    // Is the value computed by the expression
+    ValExpression(Vec<u8>),
+}
+
+// The Canonical Frame Address is the value of the stack pointer in the caller's frame
+#[derive(Clone)]
+enum CfaRule {
+    RegOffset(usize, i64),
+    Expression(Vec<u8>),
+}
+
+#[derive(Clone)]
+struct UnwindRow {
+    cfa: CfaRule,
+    regs: Vec<RegRule>,
+}
+
+// Everything needed to unwind one frame, independent of the file it came from
+struct UnwindStep {
+    row: UnwindRow,
+    ra_reg: usize,
+    signal_frame: bool,
+}
+
+// Run the call frame instructions of a CIE or FDE, stopping once they describe code past 'addr'.
+// Returns false if the instructions couldn't be parsed.
+fn run_cfa_program(eh: &EhFrame, cie: &Cie, program: std::ops::Range<usize>, addr: u64, mut loc: u64,
+                   row: &mut UnwindRow, initial: &UnwindRow) -> Option<()> {
+    let mut r = DwarfReader { data: &eh.data[..program.end], pos: program.start };
+    let mut saved_rows = vec![];
+    let set_reg = |row: &mut UnwindRow, reg: u64, rule: RegRule| {
+        if let Some(r) = row.regs.get_mut(reg as usize) {
+            *r = rule;
+        }
+    };
+    let initial_rule = |reg: u64| initial.regs.get(reg as usize).cloned().unwrap_or(RegRule::SameValue);
+    let expression = |r: &mut DwarfReader| -> Option<Vec<u8>> {
+        let len = r.uleb128()? as usize;
+        Some(r.bytes(len)?.to_vec())
+    };
+
+    while r.pos < program.end {
+        let op = r.u8()?;
+        let advance = match op >> 6 {
+            1 => Some(u64::from(op & 0x3f)),
+            2 => {
+                let offset = r.uleb128()? as i64 * cie.data_align;
+                set_reg(row, u64::from(op & 0x3f), RegRule::Offset(offset));
+                None
+            }
+            3 => {
+                set_reg(row, u64::from(op & 0x3f), initial_rule(u64::from(op & 0x3f)));
+                None
+            }
+            _ => match op {
+                0x00 => None,
+                0x01 => {
+                    loc = r.pointer(cie.fde_encoding, eh.addr)?;
+                    if loc > addr {
+                        return Some(());
+                    }
+                    None
+                }
+                0x02 => Some(u64::from(r.u8()?)),
+                0x03 => Some(u64::from(r.u16()?)),
+                0x04 => Some(u64::from(r.u32()?)),
+                0x05 => {
+                    let reg = r.uleb128()?;
+                    let offset = r.uleb128()? as i64 * cie.data_align;
+                    set_reg(row, reg, RegRule::Offset(offset));
+                    None
+                }
+                0x06 => {
+                    let reg = r.uleb128()?;
+                    set_reg(row, reg, initial_rule(reg));
+                    None
+                }
+                0x07 => {
+                    let reg = r.uleb128()?;
+                    set_reg(row, reg, RegRule::Undefined);
+                    None
+                }
+                0x08 => {
+                    let reg = r.uleb128()?;
+                    set_reg(row, reg, RegRule::SameValue);
+                    None
+                }
+                0x09 => {
+                    let reg = r.uleb128()?;
+                    let other = r.uleb128()? as usize;
+                    set_reg(row, reg, RegRule::Register(other));
+                    None
+                }
+                0x0a => {
+                    saved_rows.push(row.clone());
+                    None
+                }
+                0x0b => {
+                    *row = saved_rows.pop()?;
+                    None
+                }
+                0x0c => {
+                    let reg = r.uleb128()? as usize;
+                    let offset = r.uleb128()? as i64;
+                    row.cfa = CfaRule::RegOffset(reg, offset);
+                    None
+                }
+                0x0d => {
+                    let reg = r.uleb128()? as usize;
+                    if let CfaRule::RegOffset(_, offset) = row.cfa {
+                        row.cfa = CfaRule::RegOffset(reg, offset);
+                    }
+                    None
+                }
+                0x0e | 0x13 => {
+                    let offset = if op == 0x0e { r.uleb128()? as i64 } else { r.sleb128()? * cie.data_align };
+                    if let CfaRule::RegOffset(reg, _) = row.cfa {
+                        row.cfa = CfaRule::RegOffset(reg, offset);
+                    }
+                    None
+                }
+                0x0f => {
+                    row.cfa = CfaRule::Expression(expression(&mut r)?);
+                    None
+                }
+                0x10 => {
+                    let reg = r.uleb128()?;
+                    let expr = expression(&mut r)?;
+                    set_reg(row, reg, RegRule::Expression(expr));
+                    None
+                }
+                0x11 => {
+                    let reg = r.uleb128()?;
+                    let offset = r.sleb128()? * cie.data_align;
+                    set_reg(row, reg, RegRule::Offset(offset));
+                    None
+                }
+                0x12 => {
+                    let reg = r.uleb128()? as usize;
+                    let offset = r.sleb128()? * cie.data_align;
+                    row.cfa = CfaRule::RegOffset(reg, offset);
+                    None
+                }
+                0x14 | 0x15 => {
+                    let reg = r.uleb128()?;
+                    let offset = if op == 0x14 { r.uleb128()? as i64 } else { r.sleb128()? } * cie.data_align;
+                    set_reg(row, reg, RegRule::ValOffset(offset));
+                    None
+                }
+                0x16 => {
+                    let reg = r.uleb128()?;
+                    let expr = expression(&mut r)?;
+                    set_reg(row, reg, RegRule::ValExpression(expr));
+                    None
+                }
+                // DW_CFA_GNU_args_size
+                0x2e => {
+                    r.uleb128()?;
+                    None
+                }
+                // DW_CFA_GNU_negative_offset_extended
+                0x2f => {
+                    let reg = r.uleb128()?;
+                    let offset = -(r.uleb128()? as i64) * cie.data_align;
+                    set_reg(row, reg, RegRule::Offset(offset));
+                    None
+                }
+                // DW_CFA_AARCH64_negate_ra_state (return address signing). We strip the signature
+                // from return addresses instead of tracking it.
+                0x2d => None,
+                _ => return None,
+            },
+        };
+
+        if let Some(advance) = advance {
+            loc = loc.wrapping_add(advance * cie.code_align);
+            if loc > addr {
+                return Some(());
+            }
+        }
+    }
+    Some(())
+}
+
+// Evaluate a DWARF expression as used in CFI. Only the operations compilers emit for unwinding
+// (mostly register plus offset, and some arithmetic in PLT entries) are supported.
+fn eval_dwarf_expr(pid: u64, expr: &[u8], regs: &Registers, initial: Option<u64>) -> Option<u64> {
+    let mut stack: Vec<u64> = initial.into_iter().collect();
+    let mut r = DwarfReader { data: expr, pos: 0 };
+    while r.pos < expr.len() {
+        let op = r.u8()?;
+        match op {
+            0x06 => {
+                let addr = stack.pop()?;
+                stack.push(read_process_u64(pid, addr)?);
+            }
+            0x08 => stack.push(u64::from(r.u8()?)),
+            0x09 => stack.push(r.u8()? as i8 as u64),
+            0x0a => stack.push(u64::from(r.u16()?)),
+            0x0b => stack.push(r.u16()? as i16 as u64),
+            0x0c => stack.push(u64::from(r.u32()?)),
+            0x0d => stack.push(r.u32()? as i32 as u64),
+            0x0e | 0x0f => stack.push(r.u64()?),
+            0x10 => stack.push(r.uleb128()?),
+            0x11 => stack.push(r.sleb128()? as u64),
+            0x12 => stack.push(*stack.last()?),
+            0x13 => {
+                stack.pop()?;
+            }
+            0x16 => {
+                let (a, b) = (stack.pop()?, stack.pop()?);
+                stack.push(a);
+                stack.push(b);
+            }
+            0x23 => {
+                let value = stack.pop()?.wrapping_add(r.uleb128()?);
+                stack.push(value);
+            }
+            0x1a | 0x1c | 0x1e | 0x21 | 0x22 | 0x24 | 0x25 | 0x27 | 0x29..=0x2e => {
+                let b = stack.pop()?;
+                let a = stack.pop()?;
+                stack.push(match op {
+                    0x1a => a & b,
+                    0x1c => a.wrapping_sub(b),
+                    0x1e => a.wrapping_mul(b),
+                    0x21 => a | b,
+                    0x22 => a.wrapping_add(b),
+                    0x24 => a.checked_shl(b as u32).unwrap_or(0),
+                    0x25 => a.checked_shr(b as u32).unwrap_or(0),
+                    0x27 => a ^ b,
+                    0x29 => (a == b) as u64,
+                    0x2a => (a as i64 >= b as i64) as u64,
+                    0x2b => (a as i64 > b as i64) as u64,
+                    0x2c => (a as i64 <= b as i64) as u64,
+                    0x2d => ((a as i64) < b as i64) as u64,
+                    _ => (a != b) as u64,
+                });
+            }
+            0x30..=0x4f => stack.push(u64::from(op - 0x30)),
+            0x70..=0x8f => {
+                let value = (*regs.regs.get((op - 0x70) as usize)?)?;
+                stack.push(value.wrapping_add(r.sleb128()? as u64));
+            }
+            0x92 => {
+                let reg = r.uleb128()? as usize;
+                let value = (*regs.regs.get(reg)?)?;
+                stack.push(value.wrapping_add(r.sleb128()? as u64));
+            }
+            _ => return None,
+        }
+    }
+    stack.pop()
+}
+
+fn read_process_u64(pid: u64, addr: u64) -> Option<u64> {
+    let mut buf = [0; 8];
+    read_process_memory(pid, addr, &mut buf).ok()?;
+    Some(read_u64(&buf, 0))
+}
+
+// Return addresses signed with pointer authentication have the signature in their top bits
+#[cfg(target_arch = "aarch64")]
+fn strip_return_address(addr: u64) -> u64 {
+    addr & 0x0000_ffff_ffff_ffff
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn strip_return_address(addr: u64) -> u64 {
+    addr
+}
+
+impl UnwindStep {
+    // Compute the registers of the caller's frame
+    fn apply(&self, pid: u64, regs: &Registers) -> Option<Registers> {
+        let cfa = match self.row.cfa {
+            CfaRule::RegOffset(reg, offset) => (*regs.regs.get(reg)?)?.wrapping_add(offset as u64),
+            CfaRule::Expression(ref expr) => eval_dwarf_expr(pid, expr, regs, None)?,
+        };
+
+        let mut caller = Registers { pc: 0, regs: [None; DWARF_REGS] };
+        for (reg, rule) in self.row.regs.iter().enumerate() {
+            caller.regs[reg] = match *rule {
+                RegRule::Undefined => None,
+                RegRule::SameValue => regs.regs[reg],
+                RegRule::Offset(offset) => read_process_u64(pid, cfa.wrapping_add(offset as u64)),
+                RegRule::ValOffset(offset) => Some(cfa.wrapping_add(offset as u64)),
+                RegRule::Register(other) => *regs.regs.get(other)?,
+                RegRule::Expression(ref expr) => {
+                    eval_dwarf_expr(pid, expr, regs, Some(cfa)).and_then(|addr| read_process_u64(pid, addr))
+                }
+                RegRule::ValExpression(ref expr) => eval_dwarf_expr(pid, expr, regs, Some(cfa)),
+            };
+        }
+        caller.regs[DWARF_REG_SP] = Some(cfa);
+
+        // An undefined return address marks the outermost frame
+        caller.pc = strip_return_address((*caller.regs.get(self.ra_reg)?)?);
+        Some(caller)
+    }
+}
+
+// Without unwind info, see if the frame was set up with a frame pointer. The frame record it points
+// to holds the caller's frame pointer followed by the return address, on both x86_64 and aarch64.
+fn frame_pointer_step(pid: u64, regs: &Registers) -> Option<Registers> {
+    let fp = regs.regs[DWARF_REG_FP]?;
+    if fp == 0 || fp % 8 != 0 || fp < regs.sp()? {
+        return None;
+    }
+    let mut caller = Registers { pc: 0, regs: [None; DWARF_REGS] };
+    caller.regs[DWARF_REG_FP] = Some(read_process_u64(pid, fp)?);
+    caller.regs[DWARF_REG_SP] = Some(fp + 16);
+    caller.pc = strip_return_address(read_process_u64(pid, fp + 8)?);
+    Some(caller)
+}
+
+// What we need from an ELF file mapped by a process: its function symbols, its unwind info, and
+// enough of its layout to map offsets in the file (which is what /proc/[pid]/maps tells us) to the
+// addresses these use.
+struct ElfInfo {
+    // (file offset, address, size) of each PT_LOAD segment
+    segments: Vec<(u64, u64, u64)>,
+    // (address, size, name), sorted by address
+    functions: Vec<(u64, u64, String)>,
+    eh_frame: Option<EhFrame>,
+}
+
+impl ElfInfo {
+    fn read(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let ehdr = read_elf_header(&mut file, path)?;
+
+        let phoff = read_u64(&ehdr, 32);
+        let phentsize = read_u16(&ehdr, 54) as usize;
+        let phnum = read_u16(&ehdr, 56) as usize;
+        let mut segments = vec![];
+        if phentsize >= 56 {
+            for phdr in read_extent(&mut file, file_len, phoff, (phnum * phentsize) as u64)?.chunks(phentsize) {
+                if read_u32(phdr, 0) == PT_LOAD {
+                    segments.push((read_u64(phdr, 8), read_u64(phdr, 16), read_u64(phdr, 32)));
+                }
+            }
+        }
+
+        let shoff = read_u64(&ehdr, 40);
+        let shentsize = read_u16(&ehdr, 58) as usize;
+        let shnum = read_u16(&ehdr, 60) as usize;
+        let shstrndx = read_u16(&ehdr, 62) as usize;
+        let mut functions = vec![];
+        let mut eh_frame = None;
+        if shentsize >= 64 && shoff != 0 {
+            let shdrs = read_extent(&mut file, file_len, shoff, (shnum * shentsize) as u64)?;
+            let shdrs = shdrs.chunks(shentsize).collect::<Vec<_>>();
+            let mut section = |shdr: &[u8]| read_extent(&mut file, file_len, read_u64(shdr, 24), read_u64(shdr, 32));
+
+            let shstrtab = match shdrs.get(shstrndx) {
+                Some(shdr) => section(shdr)?,
+                None => vec![],
+            };
+            let section_name = |shdr: &[u8]| shstrtab.get(read_u32(shdr, 0) as usize..).map(c_string);
+            if let Some(shdr) = shdrs.iter().find(|shdr| section_name(shdr).as_ref().map(|n| &n[..]) == Some(".eh_frame")) {
+                eh_frame = Some(EhFrame::parse(section(shdr)?, read_u64(shdr, 16)));
+            }
+
+            // Use the full symbol table if the file wasn't stripped, and the dynamic symbol table,
+            // which has only exported functions, otherwise.
+            let has_symtab = shdrs.iter().any(|shdr| read_u32(shdr, 4) == SHT_SYMTAB);
+            let wanted = if has_symtab { SHT_SYMTAB } else { SHT_DYNSYM };
+            for shdr in shdrs.iter().filter(|shdr| read_u32(shdr, 4) == wanted) {
+                let strtab = match shdrs.get(read_u32(shdr, 40) as usize) {
+                    Some(strtab) => section(strtab)?,
+                    None => continue,
+                };
+                for sym in section(shdr)?.chunks(24).filter(|sym| sym.len() == 24) {
+                    let sym_type = sym[4] & 0xf;
+                    let value = read_u64(sym, 8);
+                    if (sym_type != STT_FUNC && sym_type != STT_GNU_IFUNC) || value == 0 {
+                        continue;
+                    }
+                    let name = strtab.get(read_u32(sym, 0) as usize..).map(c_string).unwrap_or_default();
+                    functions.push((value, read_u64(sym, 16), name));
+                }
+            }
+        }
+        functions.sort();
+
+        Ok(ElfInfo { segments, functions, eh_frame })
+    }
+
+    fn address_of(&self, file_offset: u64) -> Option<u64> {
+        self.segments.iter()
+            .find(|&&(offset, _, size)| offset <= file_offset && file_offset < offset + size)
+            .map(|&(offset, addr, _)| addr + (file_offset - offset))
+    }
+
+    // Returns the function containing an address, and the offset of the address into it
+    fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let i = match self.functions.binary_search_by(|f| f.0.cmp(&addr)) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let (start, size, ref name) = self.functions[i];
+        // Hand written assembly often doesn't set a size
+        if addr < start + size || size == 0 {
+            Some((name, addr - start))
+        } else {
+            None
+        }
+    }
+
+    fn unwind_step(&self, addr: u64) -> Option<UnwindStep> {
+        let eh = self.eh_frame.as_ref()?;
+        let fde = eh.find_fde(addr)?;
+        let cie = eh.cies.get(&fde.cie)?;
+
+        let mut row = UnwindRow {
+            cfa: CfaRule::RegOffset(DWARF_REG_SP, 0),
+            regs: vec![RegRule::SameValue; DWARF_REGS],
+        };
+        let empty = row.clone();
+        run_cfa_program(eh, cie, cie.instructions.clone(), u64::MAX, 0, &mut row, &empty)?;
+        let initial = row.clone();
+        run_cfa_program(eh, cie, fde.instructions.clone(), addr, fde.start, &mut row, &initial)?;
+
+        Some(UnwindStep { row, ra_reg: cie.ra_reg, signal_frame: cie.signal_frame })
+    }
+}
+
+// An ELF file, and an address in it
+type FileAddress = (Arc<ElfInfo>, u64);
+
+// The mappings of a process, and what we know about the files they map
+struct AddressSpace {
+    pid: u64,
+    mappings: Vec<Mapping>,
+    // Indexed by path, or None if the file couldn't be read. Threads unwinding different stacks
+    // share it, so that each file is only read once.
+    files: Mutex<HashMap<String, Option<Arc<ElfInfo>>>>,
+}
+
+impl AddressSpace {
+    fn new(pid: u64) -> Result<Self, Box<dyn Error>> {
+        let maps = File::open(proc_path!("{}/maps", pid))?;
+        Ok(AddressSpace { pid, mappings: parse_smaps(BufReader::new(maps))?, files: Mutex::new(HashMap::new()) })
+    }
+
+    // Find the file mapped at an address, and the address within the file
+    fn resolve(&self, addr: u64) -> Option<(&Mapping, Option<FileAddress>)> {
+        let mapping = self.mappings.iter().find(|m| m.start <= addr && addr < m.end)?;
+        if !mapping.path.starts_with('/') {
+            return Some((mapping, None));
+        }
+
+        // Go through the process' root, in case it is in a container
+        let pid = self.pid;
+        let info = self.files.lock().unwrap().entry(mapping.path.clone())
+            .or_insert_with(|| ElfInfo::read(&format!("/proc/{}/root{}", pid, mapping.path)).ok().map(Arc::new))
+            .clone();
+        let file_addr = info.and_then(|info| {
+            let file_addr = info.address_of(addr - mapping.start + mapping.offset)?;
+            Some((info, file_addr))
+        });
+        Some((mapping, file_addr))
+    }
+
+    // Return addresses point after the call instruction, which may be the start of the next
+    // function, so callers look those up as the address of the call itself.
+    fn frame(&self, pc: u64, lookup_addr: u64) -> StackFrame {
+        let (symbol, module) = match self.resolve(lookup_addr) {
+            None => (None, None),
+            Some((mapping, None)) => (None, Some(mapping.name().to_string())),
+            Some((mapping, Some((info, file_addr)))) => {
+                let symbol = info.lookup(file_addr).map(|(name, offset)| (name.to_string(), offset + (pc - lookup_addr)));
+                (symbol, Some(mapping.path.clone()))
+            }
+        };
+        StackFrame { pc, symbol, module }
+    }
+
+    fn unwind_step(&self, lookup_addr: u64) -> Option<UnwindStep> {
+        let (_, file) = self.resolve(lookup_addr)?;
+        let (info, file_addr) = file?;
+        info.unwind_step(file_addr)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn get_registers(thread: &StoppedThread) -> Result<Registers, Box<dyn Error>> {
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    let res = unsafe {
+        libc::ptrace(libc::PTRACE_GETREGS, thread.tid as libc::pid_t, std::ptr::null_mut::<libc::c_void>(),
+                     &mut regs as *mut libc::user_regs_struct)
+    };
+    nix::errno::Errno::result(res)?;
+
+    let mut registers = Registers { pc: regs.rip, regs: [None; DWARF_REGS] };
+    let dwarf_order = [regs.rax, regs.rdx, regs.rcx, regs.rbx, regs.rsi, regs.rdi, regs.rbp, regs.rsp,
+                       regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15];
+    for (reg, value) in dwarf_order.iter().enumerate() {
+        registers.regs[reg] = Some(*value);
+    }
+    Ok(registers)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn get_registers(thread: &StoppedThread) -> Result<Registers, Box<dyn Error>> {
+    // struct user_pt_regs: x0-x30, then sp, pc and pstate. DWARF numbers these the same way.
+    let mut regs = [0u64; 34];
+    let mut iov = libc::iovec {
+        iov_base: regs.as_mut_ptr() as *mut libc::c_void,
+        iov_len: std::mem::size_of_val(&regs),
+    };
+    let res = unsafe {
+        libc::ptrace(libc::PTRACE_GETREGSET, thread.tid as libc::pid_t, NT_PRSTATUS as usize as *mut libc::c_void,
+                     &mut iov as *mut libc::iovec)
+    };
+    nix::errno::Errno::result(res)?;
+
+    let mut registers = Registers { pc: regs[32], regs: [None; DWARF_REGS] };
+    for reg in 0..32 {
+        registers.regs[reg] = Some(regs[reg]);
+    }
+    Ok(registers)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn get_registers(_thread: &StoppedThread) -> Result<Registers, Box<dyn Error>> {
+    Err(From::from("reading registers is not supported on this architecture"))
+}
+
+// A thread stopped with ptrace. It is resumed when this is dropped.
+struct StoppedThread {
+    tid: u64,
+    // A signal which was about to be delivered when we stopped the thread, and which needs to be
+    // passed on when we let it go.
+    pending_signal: i32,
+}
+
+impl StoppedThread {
+    fn stop(_cap: &MutateCap, tid: u64) -> Result<Self, Box<dyn Error>> {
+        let ptrace = |request| unsafe {
+            libc::ptrace(request, tid as libc::pid_t, std::ptr::null_mut::<libc::c_void>(),
+                         std::ptr::null_mut::<libc::c_void>())
+        };
+
+        // Unlike PTRACE_ATTACH, PTRACE_SEIZE doesn't send a SIGSTOP, which a parent waiting for the
+        // process could see.
+        nix::errno::Errno::result(ptrace(libc::PTRACE_SEIZE))?;
+        let mut thread = StoppedThread { tid, pending_signal: 0 };
+        nix::errno::Errno::result(ptrace(libc::PTRACE_INTERRUPT))?;
+        match waitpid(Pid::from_raw(tid as i32), Some(WaitPidFlag::__WALL))? {
+            WaitStatus::PtraceEvent(..) => {}
+            WaitStatus::Stopped(_, signal) => thread.pending_signal = signal as i32,
+            // It exited before it could stop, which is the same as not finding it
+            WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                return Err(From::from(nix::Error::Sys(nix::errno::Errno::ESRCH)));
+            }
+            status => return Err(From::from(format!("thread {} didn't stop: {:?}", tid, status))),
+        }
+        Ok(thread)
+    }
+}
+
+impl Drop for StoppedThread {
+    fn drop(&mut self) {
+        unsafe {
+            libc::ptrace(libc::PTRACE_DETACH, self.tid as libc::pid_t, std::ptr::null_mut::<libc::c_void>(),
+                         self.pending_signal as usize as *mut libc::c_void);
+        }
+    }
+}
+
+// How long pstop --at-syscall lets each thread run looking for a system call before stopping it
+// wherever it is
+const SYSCALL_STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Wait until every thread of a process to which we sent SIGSTOP has stopped. Threads in an
+// uninterruptible sleep only stop once it is over.
+fn wait_for_stop(pid: u64) -> Result<(), Box<dyn Error>> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let running = task_ids(pid)?.into_iter().filter(|&tid| {
+            read_task_file(pid, tid, "stat").ok().and_then(|stat| stat_field(&stat, 3).map(|s| s != "T" && s != "t"))
+                .unwrap_or(false)
+        }).collect::<Vec<_>>();
+        if running.is_empty() {
+            return Ok(());
+        }
+        if Instant::now() > deadline {
+            let running = running.iter().map(|tid| tid.to_string()).collect::<Vec<_>>().join(", ");
+            return Err(From::from(format!("threads {} of process {} haven't stopped yet", running, pid)));
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+// Stop a process, like kill -STOP
+fn stop_process(_cap: &MutateCap, pid: u64) -> Result<(), Box<dyn Error>> {
+    nix::sys::signal::kill(Pid::from_raw(pid as i32), Signal::SIGSTOP)?;
+    wait_for_stop(pid)
+}
+
+// Set a stopped process running again, like kill -CONT. This also resumes processes stopped with
+// pstop --at-syscall, which are in an ordinary group stop once pstop has detached.
+fn resume_process(_cap: &MutateCap, pid: u64) -> Result<(), Box<dyn Error>> {
+    nix::sys::signal::kill(Pid::from_raw(pid as i32), Signal::SIGCONT)?;
+    Ok(())
+}
+
+// Stop a process at system call boundaries, so that no thread is stopped halfway through user
+// code, e.g. while holding a lock between two instructions. Each thread is run under ptrace until
+// it makes its next system call, and will stop as soon as that returns. Returns the threads which
+// didn't make one within the timeout, and so were stopped wherever they were.
+fn stop_at_syscall(cap: &MutateCap, pid: u64, timeout: Duration) -> Result<Vec<u64>, Box<dyn Error>> {
+    let ptrace = |request, tid: u64, data: usize| unsafe {
+        nix::errno::Errno::result(libc::ptrace(request, tid as libc::pid_t, std::ptr::null_mut::<libc::c_void>(),
+                                               data as *mut libc::c_void))
+    };
+
+    let mut threads = vec![];
+    for tid in task_ids(pid)? {
+        threads.push(StoppedThread::stop(cap, tid)?);
+    }
+    for thread in &mut threads {
+        // Report system call stops as SIGTRAP|0x80, so they can't be mistaken for a real SIGTRAP
+        ptrace(libc::PTRACE_SETOPTIONS, thread.tid, libc::PTRACE_O_TRACESYSGOOD as usize)?;
+        ptrace(libc::PTRACE_SYSCALL, thread.tid, thread.pending_signal as usize)?;
+        thread.pending_signal = 0;
+    }
+
+    let mut running = threads.iter().map(|thread| thread.tid).collect::<Vec<_>>();
+    let mut exited = vec![];
+    let deadline = Instant::now() + timeout;
+    while !running.is_empty() && Instant::now() < deadline {
+        for tid in running.clone() {
+            match waitpid(Pid::from_raw(tid as i32), Some(WaitPidFlag::__WALL | WaitPidFlag::WNOHANG))? {
+                WaitStatus::PtraceSyscall(_) => running.retain(|&t| t != tid),
+                // The thread was sent a signal on the way: deliver it, and carry on to the next
+                // system call, which may well be in the signal handler
+                WaitStatus::Stopped(_, signal) => { ptrace(libc::PTRACE_SYSCALL, tid, signal as usize)?; }
+                WaitStatus::PtraceEvent(..) => { ptrace(libc::PTRACE_SYSCALL, tid, 0)?; }
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                    running.retain(|&t| t != tid);
+                    exited.push(tid);
+                }
+                _ => {}
+            }
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    // Threads busy computing are stopped wherever they are
+    let mut late = vec![];
+    for &tid in &running {
+        ptrace(libc::PTRACE_INTERRUPT, tid, 0)?;
+        match waitpid(Pid::from_raw(tid as i32), Some(WaitPidFlag::__WALL))? {
+            WaitStatus::PtraceSyscall(_) => {}
+            WaitStatus::PtraceEvent(..) => late.push(tid),
+            WaitStatus::Stopped(_, signal) => {
+                if let Some(thread) = threads.iter_mut().find(|thread| thread.tid == tid) {
+                    thread.pending_signal = signal as i32;
+                }
+                late.push(tid);
+            }
+            _ => exited.push(tid),
+        }
+    }
+
+    // While the threads are held by ptrace, a SIGSTOP is only acted on once we let them go, so each
+    // stops as soon as it gets back from the system call it is at, before running any user code
+    nix::sys::signal::kill(Pid::from_raw(pid as i32), Signal::SIGSTOP)?;
+    threads.retain(|thread| !exited.contains(&thread.tid));
+    drop(threads);
+    wait_for_stop(pid)?;
+    Ok(late)
+}
+
+// Deeper stacks than this are most likely a loop in a corrupt stack
+const MAX_STACK_FRAMES: usize = 256;
+
+// Walk the stack using the .eh_frame unwind info of each function, falling back to following frame
+// pointers for code without any (e.g. JIT compiled code). Returns the pc of each frame, along with
+// the address to look it up by.
+fn unwind_stack(space: &AddressSpace, regs: Registers) -> Vec<(u64, u64)> {
+    let mut frames = vec![];
+    let mut regs = regs;
+    // The pc of the innermost frame, and of frames interrupted by a signal, is the instruction
+    // which was about to run. In other frames it is a return address.
+    let mut is_return_addr = false;
+    loop {
+        let lookup_addr = if is_return_addr { regs.pc - 1 } else { regs.pc };
+        frames.push((regs.pc, lookup_addr));
+        if frames.len() >= MAX_STACK_FRAMES {
+            break;
+        }
+
+        let (caller, signal_frame) = match space.unwind_step(lookup_addr) {
+            Some(step) => (step.apply(space.pid, &regs), step.signal_frame),
+            None => (None, false),
+        };
+        let caller = match caller.or_else(|| frame_pointer_step(space.pid, &regs)) {
+            Some(caller) => caller,
+            None => break,
+        };
+
+        // The stack grows down, so callers' frames are at higher addresses. Signal handlers may run
+        // on a separate stack, though.
+        let progressed = match (caller.sp(), regs.sp()) {
+            (Some(caller_sp), Some(sp)) => caller_sp > sp || (signal_frame && caller_sp != sp),
+            _ => false,
+        };
+        if caller.pc == 0 || !progressed {
+            break;
+        }
+        is_return_addr = !signal_frame;
+        regs = caller;
+    }
+    frames
+}
+
+// A frame of a stack: its pc, the function it is in, with the offset into it, and the file mapped
+// there
+#[derive(Debug, PartialEq)]
+struct StackFrame {
+    pc: u64,
+    symbol: Option<(String, u64)>,
+    module: Option<String>,
+}
+
+// The stack of a thread, innermost frame first
+#[derive(Debug, PartialEq)]
+struct ThreadStack {
+    tid: u64,
+    // None if the thread exited before we could read its name
+    comm: Option<String>,
+    // The line pstack heads the stack with, which says what the scheduler thought the thread was
+    // doing
+    header: String,
+    frames: Vec<StackFrame>,
+}
+
+// Unwinding is mostly reading the process' memory a word at a time, so the stacks of processes with
+// many threads are unwound by a few threads at once, to let the process go sooner.
+const MAX_UNWIND_THREADS: usize = 8;
+
+// Unwind the stacks of stopped threads, given their registers. ptrace requests have to come from
+// the thread which stopped them, but their memory can be read from any thread.
+fn unwind_stacks(space: &AddressSpace, regs: &[Option<Registers>]) -> Vec<Option<Vec<(u64, u64)>>> {
+    let workers = thread::available_parallelism().map_or(1, |n| n.get()).min(MAX_UNWIND_THREADS).min(regs.len());
+    if workers <= 1 {
+        return regs.iter().map(|regs| regs.clone().map(|regs| unwind_stack(space, regs))).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let stacks = Mutex::new(vec![None; regs.len()]);
+    thread::scope(|scope| for _ in 0..workers {
+        scope.spawn(|| loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            if i >= regs.len() {
+                break;
+            }
+            let stack = regs[i].clone().map(|regs| unwind_stack(space, regs));
+            stacks.lock().unwrap()[i] = stack;
+        });
+    });
+    stacks.into_inner().unwrap()
+}
+
+// Why thread_stacks() couldn't gather the stacks of a process
+enum StacksError {
+    // We aren't allowed to ptrace it, e.g. because of Yama, so only the kernel's view of its
+    // threads is to be had
+    NotPermitted(String),
+    // Something else went wrong, which has been reported
+    Failed,
+}
+
+// The threads of a process, or with only, those of them which still exist. The flag is set if
+// some were missing.
+fn stack_tids(pid: u64, only: Option<&[u64]>) -> Option<(Vec<u64>, bool)> {
+    let mut tids = match task_ids(pid) {
+        Ok(tids) => tids,
+        Err(e) => {
+            eprintln!("Unable to read /proc/{}/task/: {}", pid, e);
+            return None;
+        }
+    };
+    let mut error = false;
+    if let Some(only) = only {
+        for tid in only.iter().filter(|tid| !tids.contains(tid)) {
+            eprintln!("Thread {} of process {} has exited", tid, pid);
+            error = true;
+        }
+        tids.retain(|tid| only.contains(tid));
+    }
+    Some((tids, error))
+}
+
+// The line pstack heads a thread's stack with, ending with a note if there is one
+fn stack_header(tid: u64, state: Option<&TaskState>, note: &str) -> String {
+    match state {
+        Some(task) => {
+            let cpu = task.cpu.map(|cpu| format!("  cpu {}", cpu)).unwrap_or_default();
+            format!("--- thread {} ({})  {}{}{}{}{} ---", tid, task.comm, task.state, cpu, task.wchan_str(),
+                    task.warning(), note)
+        }
+        None => format!("--- thread {}{} ---", tid, note),
+    }
+}
+
+// The stacks of every thread of a process, or with only, of those threads. The flag is set if some
+// threads' stacks are missing. Errors are reported as they are found, except not being allowed to
+// stop the threads, which callers can work around.
+fn thread_stacks(cap: &MutateCap, pid: u64, only: Option<&[u64]>) -> Result<(Vec<ThreadStack>, bool), StacksError> {
+    let (tids, mut error) = stack_tids(pid, only).ok_or(StacksError::Failed)?;
+
+    // Note what the scheduler thinks each thread is doing before we stop them, so that running and
+    // stuck threads stand out in processes with many threads.
+    let states = tids.iter().map(|&tid| read_task_state(pid, tid)).collect::<Vec<_>>();
+
+    // Stop every thread before looking at any of them, so that the stacks are consistent with one
+    // another. Threads which exit before we get to them are left out.
+    let mut threads = vec![];
+    let mut stopped = vec![];
+    for (tid, state) in tids.into_iter().zip(states) {
+        match StoppedThread::stop(cap, tid) {
+            Ok(thread) => {
+                threads.push(thread);
+                stopped.push((tid, state));
+            }
+            Err(e) if e.downcast_ref::<nix::Error>() == Some(&nix::Error::Sys(nix::errno::Errno::ESRCH)) => {}
+            Err(e) if e.downcast_ref::<nix::Error>() == Some(&nix::Error::Sys(nix::errno::Errno::EPERM)) => {
+                return Err(StacksError::NotPermitted(format!("ptrace of thread {}: {}", tid, e)));
+            }
+            Err(e) => {
+                eprintln!("Unable to stop thread {} of process {}: {}", tid, pid, e);
+                return Err(StacksError::Failed);
+            }
+        }
+    }
+
+    let space = match AddressSpace::new(pid) {
+        Ok(space) => space,
+        Err(e) => {
+            eprintln!("Unable to read mappings of process {}: {}", pid, e);
+            return Err(StacksError::Failed);
+        }
+    };
+
+    let regs = threads.iter().map(|thread| match get_registers(thread) {
+        Ok(regs) => Some(regs),
+        Err(e) => {
+            eprintln!("Unable to read registers of thread {}: {}", thread.tid, e);
+            error = true;
+            None
+        }
+    }).collect::<Vec<_>>();
+    let stacks = unwind_stacks(&space, &regs);
+    // Let the process go before printing, which is slow if the output goes to a terminal
+    drop(threads);
+
+    let mut threads = vec![];
+    for ((tid, state), stack) in stopped.into_iter().zip(stacks) {
+        let header = stack_header(tid, state.as_ref(), "");
+        let frames = stack.unwrap_or_default().into_iter()
+            .map(|(pc, lookup_addr)| space.frame(pc, lookup_addr))
+            .collect();
+        threads.push(ThreadStack { tid, comm: state.map(|task| task.comm), header, frames });
+    }
+    Ok((threads, error))
+}
+
+// Parse /proc/[pid]/task/[tid]/stack, which has lines like '[<0>] ep_poll+0x2a8/0x300', where the
+// address is only shown to root with kptr_restrict off
+fn parse_kernel_stack(contents: &str) -> Vec<StackFrame> {
+    contents.lines().filter_map(|line| {
+        let (addr, desc) = line.trim().strip_prefix("[<")?.split_once(">] ")?;
+        let (name, offset) = desc.split_once("+0x")
+            .map_or((desc, 0), |(name, offset)| {
+                (name, offset.split('/').next().and_then(|o| u64::from_str_radix(o, 16).ok()).unwrap_or(0))
+            });
+        Some(StackFrame { pc: u64::from_str_radix(addr, 16).unwrap_or(0), symbol: Some((name.to_string(), offset)),
+                          module: Some("kernel".to_string()) })
+    }).collect()
+}
+
+// When the threads can't be stopped, what the kernel says about them is still worth having in an
+// incident: their kernel stacks (which only root can read), or failing that, the kernel function
+// each is waiting in, which is in the header.
+fn kernel_thread_stacks(pid: u64, only: Option<&[u64]>) -> Option<(Vec<ThreadStack>, bool)> {
+    let (tids, error) = stack_tids(pid, only)?;
+    let threads = tids.into_iter().map(|tid| {
+        let state = read_task_state(pid, tid);
+        let (frames, note) = match read_task_file(pid, tid, "stack") {
+            Ok(stack) => (parse_kernel_stack(&stack), "  [kernel stack]"),
+            Err(_) => (vec![], "  [wait channel only]"),
+        };
+        ThreadStack { tid, header: stack_header(tid, state.as_ref(), note), comm: state.map(|task| task.comm), frames }
+    }).collect();
+    Some((threads, error))
+}
+
+// How pstack prints stacks. Besides its own format, it can mimic other tools, so that scripts
+// written for them work on its output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StackFormat {
+    Pstack,
+    // gdb's 'thread apply all bt'
+    Gdb,
+    EuStack,
+    // One line per distinct stack, with how many threads have it, as flame graph tools take
+    Folded,
+}
+
+impl StackFormat {
+    fn parse(s: &str) -> Option<StackFormat> {
+        match s {
+            "pstack" => Some(StackFormat::Pstack),
+            "gdb" => Some(StackFormat::Gdb),
+            "eu-stack" => Some(StackFormat::EuStack),
+            "folded" => Some(StackFormat::Folded),
+            _ => None,
+        }
+    }
+}
+
+// A frame as pstack prints it, e.g. ' 00007f3e5a2d1a3d poll+0x4d (/usr/lib/libc.so.6)'
+fn frame_line(frame: &StackFrame) -> String {
+    let module = frame.module.as_ref().map(|module| format!(" ({})", module)).unwrap_or_default();
+    match frame.symbol {
+        Some((ref name, offset)) => format!(" {:016x} {}+0x{:x}{}", frame.pc, name, offset, module),
+        None => format!(" {:016x} ???{}", frame.pc, module),
+    }
+}
+
+// The lines pstack prints for the stacks of a process
+fn stack_lines(stacks: &[ThreadStack]) -> Vec<String> {
+    let mut lines = vec![];
+    for stack in stacks {
+        lines.push(stack.header.clone());
+        lines.extend(stack.frames.iter().map(frame_line));
+    }
+    lines
+}
+
+// A list of tids with runs of consecutive ones shortened, e.g. '101-112, 120'
+fn tid_ranges(tids: &[u64]) -> String {
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    for &tid in tids {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == tid => *end = tid,
+            _ => ranges.push((tid, tid)),
+        }
+    }
+    ranges.iter().map(|&(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+        .collect::<Vec<_>>().join(", ")
+}
+
+// The lines pstack --dedup prints: threads with the same stack, like the idle workers of a pool,
+// are printed once, under a header saying how many there are and which. Threads we only have
+// the wait channel of are kept apart, as that is in their own headers.
+fn dedup_stack_lines(stacks: &[ThreadStack], utf8: bool) -> Vec<String> {
+    let mut groups: Vec<(&ThreadStack, Vec<u64>)> = vec![];
+    for stack in stacks {
+        match groups.iter_mut().find(|(first, _)| !stack.frames.is_empty() && first.frames == stack.frames) {
+            Some((_, tids)) => tids.push(stack.tid),
+            None => groups.push((stack, vec![stack.tid])),
+        }
+    }
+    let times = if utf8 { "\u{d7}" } else { "x" };
+    let mut lines = vec![];
+    for (stack, tids) in groups {
+        match tids.len() {
+            1 => lines.push(stack.header.clone()),
+            n => lines.push(format!("--- {} {} threads (tids {}) ---", times, n, tid_ranges(&tids))),
+        }
+        lines.extend(stack.frames.iter().map(frame_line));
+    }
+    lines
+}
+
+// What pstack prints for the stacks of a process
+fn stack_output(pid: u64, stacks: &[ThreadStack], format: StackFormat, dedup: bool) -> Vec<String> {
+    if dedup {
+        dedup_stack_lines(stacks, utf8_locale())
+    } else {
+        format_stacks(pid, stacks, format)
+    }
+}
+
+// Read back what stack_lines() printed, e.g. as saved by pcapture
+fn parse_stack_lines(text: &str) -> Vec<ThreadStack> {
+    let mut stacks: Vec<ThreadStack> = vec![];
+    for line in text.lines() {
+        if let Some(rest) = line.strip_prefix("--- thread ") {
+            let (tid, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+            // The name is followed by the state, which is always like 'S (sleeping)'
+            let comm = rest.strip_prefix('(').and_then(|rest| {
+                let end = rest.match_indices(")  ").map(|(i, _)| i)
+                    .find(|&i| rest[i + 3..].chars().nth(1) == Some(' '))?;
+                Some(rest[..end].to_string())
+            });
+            stacks.push(ThreadStack {
+                tid: tid.parse().unwrap_or(0),
+                comm,
+                header: line.to_string(),
+                frames: vec![],
+            });
+            continue;
+        }
+        let (stack, (pc, desc)) = match (stacks.last_mut(), line.trim_start().split_once(' ')) {
+            (Some(stack), Some(frame)) => (stack, frame),
+            _ => continue,
+        };
+        let (desc, module) = match desc.strip_suffix(')').and_then(|desc| desc.split_once(" (")) {
+            Some((desc, module)) => (desc, Some(module.to_string())),
+            None => (desc, None),
+        };
+        let symbol = desc.rsplit_once("+0x")
+            .and_then(|(name, offset)| Some((name.to_string(), u64::from_str_radix(offset, 16).ok()?)));
+        if let Ok(pc) = u64::from_str_radix(pc, 16) {
+            stack.frames.push(StackFrame { pc, symbol, module });
+        }
+    }
+    stacks
+}
+
+// The lines printed for the stacks of a process in formats other than pstack's own
+fn format_stacks(pid: u64, stacks: &[ThreadStack], format: StackFormat) -> Vec<String> {
+    let mut lines = vec![];
+    match format {
+        StackFormat::Pstack => lines = stack_lines(stacks),
+        // gdb numbers threads from 1 in the order it finds them, and lists the last one first
+        StackFormat::Gdb => for (i, stack) in stacks.iter().enumerate().rev() {
+            let comm = stack.comm.as_ref().map(|comm| format!(" \"{}\"", comm)).unwrap_or_default();
+            lines.push(String::new());
+            lines.push(format!("Thread {} (LWP {}{}):", i + 1, stack.tid, comm));
+            for (n, frame) in stack.frames.iter().enumerate() {
+                let name = frame.symbol.as_ref().map_or("??", |(name, _)| name);
+                let module = frame.module.as_ref().map(|module| format!(" from {}", module)).unwrap_or_default();
+                lines.push(format!("#{:<2} 0x{:016x} in {} (){}", n, frame.pc, name, module));
+            }
+        },
+        StackFormat::EuStack => {
+            lines.push(format!("PID {} - process", pid));
+            for stack in stacks {
+                lines.push(format!("TID {}:", stack.tid));
+                for (n, frame) in stack.frames.iter().enumerate() {
+                    match frame.symbol {
+                        Some((ref name, _)) => lines.push(format!("#{:<2} 0x{:016x} {}", n, frame.pc, name)),
+                        None => lines.push(format!("#{:<2} 0x{:016x}", n, frame.pc)),
+                    }
+                }
+            }
+        }
+        // Outermost frame first, below the name of the thread, as perf's stackcollapse does.
+        // Frames without a symbol are named after the file they are in.
+        StackFormat::Folded => {
+            let mut counts: Vec<(String, usize)> = vec![];
+            for stack in stacks {
+                let names = stack.comm.iter().cloned()
+                    .chain(stack.frames.iter().rev().map(|frame| match (&frame.symbol, &frame.module) {
+                        (Some((name, _)), _) => name.clone(),
+                        (None, Some(module)) => format!("[{}]", module.rsplit('/').next().unwrap_or(module)),
+                        (None, None) => "[unknown]".to_string(),
+                    }))
+                    .collect::<Vec<_>>()
+                    .join(";");
+                match counts.iter_mut().find(|(folded, _)| *folded == names) {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((names, 1)),
+                }
+            }
+            lines.extend(counts.into_iter().map(|(folded, count)| format!("{} {}", folded, count)));
+        }
+    }
+    lines
+}
+
+fn print_stacks(pid: u64, format: StackFormat, only: Option<&[u64]>, dedup: bool) -> bool {
+    if is_capture() {
+        return print_captured_stacks(pid, format, only, dedup);
+    }
+
+    let stacks = match MutateCap::acquire(&format!("stop process {} with ptrace", pid)) {
+        Ok(cap) => thread_stacks(&cap, pid, only),
+        Err(e) => Err(StacksError::NotPermitted(e.to_string())),
+    };
+    let (stacks, error, unavailable) = match stacks {
+        Ok((stacks, error)) => (stacks, error, None),
+        Err(StacksError::NotPermitted(reason)) => match kernel_thread_stacks(pid, only) {
+            Some((stacks, error)) => (stacks, error, Some(reason)),
+            None => return false,
+        },
+        Err(StacksError::Failed) => return false,
+    };
+    let unavailable = unavailable.map(|reason| {
+        format!("user stacks are unavailable ({}), so these are the threads' kernel stacks or wait channels", reason)
+    });
+    // The other formats are for scripts, which don't expect our summary, so the note goes to stderr
+    if format == StackFormat::Pstack {
+        print_proc_summary(pid);
+        if let Some(ref unavailable) = unavailable {
+            println!("  {}", unavailable);
+        }
+    } else if let Some(ref unavailable) = unavailable {
+        eprintln!("process {}: {}", pid, unavailable);
+    }
+    for line in stack_output(pid, &stacks, format, dedup) {
+        println!("{}", line);
+    }
+    !error
+}
+
+// Stacks can't be unwound from a capture, but pcapture --stack records what pstack would have printed
+fn print_captured_stacks(pid: u64, format: StackFormat, only: Option<&[u64]>, dedup: bool) -> bool {
+    match fs::read_to_string(proc_path!("{}/pstack", pid)) {
+        Ok(stacks) if only.is_some() => {
+            let only = only.unwrap_or_default();
+            let stacks = parse_stack_lines(&stacks).into_iter().filter(|stack| only.contains(&stack.tid))
+                .collect::<Vec<_>>();
+            if format == StackFormat::Pstack {
+                print_proc_summary(pid);
+            }
+            for line in stack_output(pid, &stacks, format, dedup) {
+                println!("{}", line);
+            }
+            let missing = only.iter().filter(|&tid| !stacks.iter().any(|stack| stack.tid == *tid)).collect::<Vec<_>>();
+            for tid in &missing {
+                eprintln!("No stack was captured for thread {} of process {}", tid, pid);
+            }
+            missing.is_empty()
+        }
+        Ok(stacks) if format == StackFormat::Pstack && !dedup => {
+            print_proc_summary(pid);
+            print!("{}", stacks);
+            true
+        }
+        Ok(stacks) => {
+            if format == StackFormat::Pstack {
+                print_proc_summary(pid);
+            }
+            for line in stack_output(pid, &parse_stack_lines(&stacks), format, dedup) {
+                println!("{}", line);
+            }
+            true
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            eprintln!("No stacks were captured for process {} (use pcapture --stack)", pid);
+            false
+        }
+        Err(e) => {
+            eprintln!("Unable to read captured stacks of process {}: {}", pid, e);
+            false
+        }
+    }
+}
+
+// The kinds of namespace listed in /proc/[pid]/ns/, and what they are called. Each is a symlink
+// like 'net:[4026531840]', where the number is the inode of the namespace.
+const NAMESPACE_TYPES: &[(&str, &str)] = &[
+    ("mnt", "mount"), ("pid", "PID"), ("net", "network"), ("ipc", "IPC"), ("uts", "UTS"), ("user", "user"),
+    ("cgroup", "cgroup"), ("time", "time"),
+];
+
+// The inode of each namespace of a process, indexed like NAMESPACE_TYPES, or None for kinds of
+// namespace which the kernel doesn't support.
+type Namespaces = Vec<Option<u64>>;
+
+fn read_namespaces(pid: u64) -> Result<Namespaces, Box<dyn Error>> {
+    read_namespaces_in(&proc_path!("{}", pid))
+}
+
+// Read the namespaces from the ns/ directory in 'dir', which is normally /proc/[pid]
+fn read_namespaces_in(dir: &str) -> Result<Namespaces, Box<dyn Error>> {
+    let mut namespaces = vec![];
+    for &(ns_type, _) in NAMESPACE_TYPES {
+        let path = format!("{}/ns/{}", dir, ns_type);
+        let link = match read_proc_link(&path) {
+            Ok(link) => link.to_string_lossy().into_owned(),
+            Err(ref e) if e.kind() == ErrorKind::NotFound && Path::new(dir).exists() => {
+                namespaces.push(None);
+                continue;
+            }
+            Err(e) => return Err(From::from(format!("Unable to read {}: {}", path, e))),
+        };
+        let inode = link.trim_end_matches(']').rsplit('[').next().and_then(|inode| inode.parse().ok());
+        match inode {
+            Some(inode) => namespaces.push(Some(inode)),
+            None => return Err(From::from(ParseError::new(&path, &format!("unexpected link '{}'", link)))),
+        }
+    }
+    Ok(namespaces)
+}
+
+// The pids of a process in each pid namespace it is in, from ours (the first) to its own (the last)
+fn ns_pids(pid: u64) -> Result<Vec<u64>, Box<dyn Error>> {
+    let status = ProcStat::read(pid)?;
+    let nspid = status.get_field("NSpid")?;
+    nspid.split_whitespace().map(|pid| pid.parse::<u64>()
+        .map_err(|_| From::from(ParseError::new("NSpid", &format!("unexpected value '{}'", nspid))))).collect()
+}
+
+// NS_GET_PARENT from linux/nsfs.h, which opens the parent of a pid (or user) namespace
+const NS_GET_PARENT: libc::c_ulong = 0xb702;
+
+// The inode of the pid namespace 'levels' above the one the process is in
+fn pid_ns_ancestor(pid: u64, levels: usize) -> Result<u64, Box<dyn Error>> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    let mut ns = File::open(proc_path!("{}/ns/pid", pid))?;
+    for _ in 0..levels {
+        let fd = unsafe { libc::ioctl(ns.as_raw_fd(), NS_GET_PARENT) };
+        if fd < 0 {
+            return Err(From::from(std::io::Error::last_os_error()));
+        }
+        ns = unsafe { File::from_raw_fd(fd) };
+    }
+    Ok(ns.metadata()?.ino())
+}
+
+// Find the process which has the given pid in the pid namespace of process 'context' (e.g. a pid
+// from the logs of a containerized program, with the container's init as context), and return
+// its pid in ours. The same pid can be in use in other namespaces at the same depth, so the
+// namespace a candidate has that pid in is compared with context's.
+fn translate_ns_pid(context: u64, pid: u64) -> Result<u64, Box<dyn Error>> {
+    let depth = ns_pids(context)?.len();
+    let context_ns = pid_ns_ancestor(context, 0)?;
+    for candidate in all_pids() {
+        let pids = match ns_pids(candidate) {
+            Ok(pids) => pids,
+            Err(_) => continue,
+        };
+        if pids.len() >= depth && pids[depth - 1] == pid
+            && pid_ns_ancestor(candidate, pids.len() - depth).ok() == Some(context_ns) {
+            return Ok(candidate);
+        }
+    }
+    Err(From::from(format!("No process has pid {} in the pid namespace of process {}", pid, context)))
+}
+
+// PF_KTHREAD, from the flags in field 9 of /proc/[pid]/stat
+const PF_KTHREAD: u64 = 0x0020_0000;
+
+fn is_kernel_thread(pid: u64) -> bool {
+    fs::read_to_string(proc_path!("{}/stat", pid)).ok()
+        .and_then(|stat| stat_field(&stat, 9)?.parse::<u64>().ok())
+        .is_some_and(|flags| flags & PF_KTHREAD != 0)
+}
+
+// The namespaces other processes are compared against: those of init if we can see them, and our
+// own otherwise (reading init's needs the same permissions as ptrace-ing it).
+struct ReferenceNamespaces {
+    name: &'static str,
+    namespaces: Namespaces,
+}
+
+fn reference_namespaces() -> Option<ReferenceNamespaces> {
+    if let Some(dir) = CAPTURE_DIR.get() {
+        // pcapture records init's namespaces separately, since init usually isn't captured
+        return match read_namespaces_in(&format!("{}/init", dir.display())) {
+            Ok(namespaces) => Some(ReferenceNamespaces { name: "init", namespaces }),
+            Err(_) => {
+                eprintln!("The namespaces of init weren't captured; not comparing with them");
+                None
+            }
+        };
+    }
+    match read_namespaces(1) {
+        Ok(namespaces) => Some(ReferenceNamespaces { name: "init", namespaces }),
+        Err(e) => {
+            eprintln!("{}; comparing with the namespaces of pnamespaces itself instead", e);
+            let namespaces = read_namespaces(std::process::id() as u64).ok()?;
+            Some(ReferenceNamespaces { name: "pnamespaces", namespaces })
+        }
+    }
+}
+
+fn print_namespaces(pid: u64, reference: Option<&ReferenceNamespaces>) -> bool {
+    let namespaces = match read_namespaces(pid) {
+        Ok(namespaces) => namespaces,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+
+    print_proc_summary(pid);
+    for (i, &(ns_type, _)) in NAMESPACE_TYPES.iter().enumerate() {
+        let inode = match namespaces[i] {
+            Some(inode) => inode,
+            None => {
+                println!("  {:<7} (not supported by this kernel)", ns_type);
+                continue;
+            }
+        };
+        let comparison = match reference {
+            Some(reference) if reference.namespaces[i] == Some(inode) => format!("same as {}", reference.name),
+            Some(reference) => format!("differs from {}", reference.name),
+            None => String::new(),
+        };
+        println!("  {:<7} {:<12} {}", ns_type, inode, comparison);
+    }
+
+    true
+}
+
+// Group processes by the namespaces they are in, so that e.g. each container shows up as a group,
+// headed by the namespaces which set it apart.
+fn print_namespace_groups(pids: &[u64], reference: Option<&ReferenceNamespaces>) {
+    let mut groups: Vec<(Namespaces, Vec<u64>)> = vec![];
+    let mut unreadable = 0;
+    for &pid in pids {
+        let namespaces = match read_namespaces(pid) {
+            Ok(namespaces) => namespaces,
+            Err(_) => {
+                unreadable += 1;
+                continue;
+            }
+        };
+        match groups.iter_mut().find(|(ns, _)| *ns == namespaces) {
+            Some((_, group)) => group.push(pid),
+            None => groups.push((namespaces, vec![pid])),
+        }
+    }
+
+    // The reference group (usually that of init) first, then in order of the lowest pid in each
+    let is_reference = |ns: &Namespaces| reference.is_some_and(|r| r.namespaces == *ns);
+    groups.sort_by_key(|(ns, group)| (!is_reference(ns), group[0]));
+
+    for (namespaces, group) in &groups {
+        let heading = match reference {
+            Some(reference) if is_reference(namespaces) => format!("same namespaces as {}", reference.name),
+            _ => NAMESPACE_TYPES.iter().zip(namespaces.iter()).enumerate()
+                .filter(|&(i, _)| reference.is_none_or(|r| r.namespaces[i] != namespaces[i]))
+                .filter_map(|(_, ((ns_type, _), inode))| Some(format!("{}:[{}]", ns_type, (*inode)?)))
+                .collect::<Vec<_>>().join(" "),
+        };
+        println!("{}", heading);
+        for &pid in group {
+            print!("  ");
+            print_proc_summary(pid);
+        }
+    }
+
+    if unreadable > 0 {
+        eprintln!("Unable to read the namespaces of {} processes", unreadable);
+    }
+}
+
+//
+// pcapture writes the state of a process (or a tree of them) to a tar archive, laid out like /proc
+// so that the other tools can read it back with --from-capture. Links in /proc (fd/*, ns/*, cwd and
+// so on) are stored as regular files holding the link's target, and the result of stat()-ing each
+// fd is stored in fdstat/[fd], since the files themselves can't be captured. The archive also holds
+// a MANIFEST.json describing where and when it was made, and init's namespaces in init/ns/, to
+// compare the captured processes' namespaces against.
+//
+const CAPTURE_VERSION: u64 = 1;
+const CAPTURE_MANIFEST: &str = "MANIFEST.json";
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// What is copied from /proc/[pid]/ for each process
+const CAPTURE_FILES: &[&str] = &[
+    "cmdline", "comm", "environ", "limits", "status", "stat", "statm", "maps", "smaps", "cgroup",
+    "mountinfo", "wchan", "syscall", "uid_map", "gid_map",
+];
+const CAPTURE_LINKS: &[&str] = &["cwd", "root", "exe"];
+const CAPTURE_TASK_FILES: &[&str] = &["comm", "stat", "status", "wchan", "syscall"];
+const CAPTURE_NET_TABLES: &[&str] = &["tcp", "tcp6", "udp", "udp6", "raw", "raw6", "unix", "netlink"];
+
+// Read a link in /proc, or the file standing in for it in a capture
+fn read_proc_link<P: AsRef<Path>>(path: P) -> std::io::Result<PathBuf> {
+    if is_capture() {
+        use std::os::unix::ffi::OsStringExt;
+        Ok(PathBuf::from(std::ffi::OsString::from_vec(fs::read(path)?)))
+    } else {
+        fs::read_link(path)
+    }
+}
+
+// stat() the file an fd refers to
+fn fd_stat(pid: u64, fd: u64) -> Result<FileStat, Box<dyn Error>> {
+    if is_capture() {
+        let path = proc_path!("{}/fdstat/{}", pid, fd);
+        Ok(parse_fd_stat(&fs::read_to_string(&path)?).map_err(|e| ParseError::new(&path, &e.to_string()))?)
+    } else {
+        Ok(stat(proc_path!("{}/fd/{}", pid, fd).as_str())?)
+    }
+}
+
+// The fields of a FileStat which the tools use, as 'name value' lines
+fn format_fd_stat(st: &FileStat) -> String {
+    format!("mode {}\ndev {}\nino {}\nrdev {}\nsize {}\nnlink {}\nuid {}\ngid {}\n",
+            st.st_mode, st.st_dev, st.st_ino, st.st_rdev, st.st_size, st.st_nlink, st.st_uid, st.st_gid)
+}
+
+fn parse_fd_stat(contents: &str) -> Result<FileStat, Box<dyn Error>> {
+    // FileStat is a plain C struct, so zeroes are a valid value for the fields we don't record
+    let mut st: FileStat = unsafe { std::mem::zeroed() };
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        let (name, value) = match (fields.next(), fields.next()) {
+            (Some(name), Some(value)) => (name, value),
+            _ => return Err(From::from(format!("unexpected line '{}'", line))),
+        };
+        match name {
+            "mode" => st.st_mode = value.parse()?,
+            "dev" => st.st_dev = value.parse()?,
+            "ino" => st.st_ino = value.parse()?,
+            "rdev" => st.st_rdev = value.parse()?,
+            "size" => st.st_size = value.parse()?,
+            "nlink" => st.st_nlink = value.parse()?,
+            "uid" => st.st_uid = value.parse()?,
+            "gid" => st.st_gid = value.parse()?,
+            // Ignore anything added by later versions
+            _ => {}
+        }
+    }
+    Ok(st)
+}
+
+// Writes tar archives in the ustar format. We only need regular files and directories with short
+// names, which every tar implementation can read.
+struct TarWriter<W: Write> {
+    out: W,
+    mtime: u64,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn new(out: W, mtime: u64) -> Self {
+        TarWriter { out, mtime }
+    }
+
+    fn add_dir(&mut self, path: &str) -> std::io::Result<()> {
+        let header = tar_header(&format!("{}/", path), b'5', 0, 0o755, self.mtime)?;
+        self.out.write_all(&header)
+    }
+
+    fn add_file(&mut self, path: &str, data: &[u8]) -> std::io::Result<()> {
+        let header = tar_header(path, b'0', data.len() as u64, 0o644, self.mtime)?;
+        self.out.write_all(&header)?;
+        self.out.write_all(data)?;
+        self.out.write_all(&vec![0; tar_padding(data.len() as u64)])
+    }
+
+    // An archive ends with two empty blocks
+    fn finish(mut self) -> std::io::Result<W> {
+        self.out.write_all(&[0; 1024])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+const TAR_BLOCK: usize = 512;
+
+fn tar_padding(size: u64) -> usize {
+    (TAR_BLOCK - (size as usize % TAR_BLOCK)) % TAR_BLOCK
+}
+
+// Write 'value' in octal into a header field, NUL terminated
+fn tar_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+fn tar_checksum(header: &[u8]) -> u64 {
+    // Computed with the checksum field itself taken to be spaces
+    header.iter().enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 })
+        .sum()
+}
+
+fn tar_header(path: &str, typeflag: u8, size: u64, mode: u32, mtime: u64) -> std::io::Result<[u8; TAR_BLOCK]> {
+    // Names longer than 100 bytes are split at a '/' into a prefix and the name
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        let split = path[..path.len().min(156)].rfind('/')
+            .filter(|&split| path.len() - split - 1 <= 100)
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, format!("path {} is too long", path)))?;
+        (&path[..split], &path[split + 1..])
+    };
+
+    let mut header = [0; TAR_BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    tar_octal(&mut header[100..108], mode as u64);
+    tar_octal(&mut header[108..116], 0);
+    tar_octal(&mut header[116..124], 0);
+    tar_octal(&mut header[124..136], size);
+    tar_octal(&mut header[136..148], mtime);
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    let checksum = format!("{:06o}\0 ", tar_checksum(&header));
+    header[148..156].copy_from_slice(checksum.as_bytes());
+    Ok(header)
+}
+
+fn tar_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn tar_parse_octal(field: &[u8]) -> Option<u64> {
+    u64::from_str_radix(tar_str(field).trim(), 8).ok()
+}
+
+// Unpack a tar archive into 'dest', which must be an empty directory. Only regular files and
+// directories are created, and only beneath 'dest': a capture may come from anywhere, and mustn't
+// be able to write elsewhere, so links and names containing '..' are rejected.
+fn unpack_tar<R: Read>(input: &mut R, dest: &Path) -> Result<(), Box<dyn Error>> {
+    loop {
+        let mut header = [0; TAR_BLOCK];
+        input.read_exact(&mut header).map_err(|_| "truncated archive")?;
+        if header.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        if tar_parse_octal(&header[148..156]) != Some(tar_checksum(&header)) {
+            return Err(From::from("corrupt archive: bad header checksum"));
+        }
+
+        let name = tar_str(&header[..100]);
+        let prefix = tar_str(&header[345..500]);
+        let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+        let size = tar_parse_octal(&header[124..136])
+            .ok_or_else(|| format!("corrupt archive: bad size for {}", path))?;
+
+        let relative = Path::new(&path);
+        let safe = relative.components().all(|c| match c {
+            std::path::Component::Normal(_) => true,
+            // Other tar implementations may start names with './'
+            std::path::Component::CurDir => true,
+            _ => false,
+        });
+        if !safe || relative.components().next().is_none() {
+            return Err(From::from(format!("unsafe path '{}' in archive", path)));
+        }
+
+        match header[156] {
+            b'0' | 0 => {
+                if let Some(parent) = relative.parent() {
+                    fs::create_dir_all(dest.join(parent))?;
+                }
+                let mut file = File::create(dest.join(relative))?;
+                let copied = std::io::copy(&mut input.by_ref().take(size), &mut file)?;
+                if copied != size {
+                    return Err(From::from("truncated archive"));
+                }
+            }
+            b'5' => fs::create_dir_all(dest.join(relative))?,
+            b'1' | b'2' => return Err(From::from(format!("unexpected link '{}' in archive", path))),
+            // Skip anything else, e.g. the extended headers of other tar implementations
+            _ => {
+                std::io::copy(&mut input.by_ref().take(size), &mut std::io::sink())?;
+            }
+        }
+        std::io::copy(&mut input.by_ref().take(tar_padding(size) as u64), &mut std::io::sink())?;
+    }
+}
+
+// The contents of a capture, gathered in memory so that the manifest, which lists everything we
+// failed to read, can come first in the archive.
+struct CaptureBuilder {
+    // Directories have no contents
+    entries: Vec<(String, Option<Vec<u8>>)>,
+    unreadable: Vec<String>,
+}
+
+impl CaptureBuilder {
+    fn dir(&mut self, path: String) {
+        self.entries.push((path, None));
+    }
+
+    // Copy 'src' from /proc. Processes can exit, and some files need privileges to read, so
+    // failures are only noted in the manifest.
+    fn copy_file(&mut self, src: &str, path: String) {
+        match fs::read(src) {
+            Ok(contents) => self.entries.push((path, Some(contents))),
+            Err(e) => self.unreadable.push(format!("{}: {}", src, e)),
+        }
+    }
+
+    fn copy_link(&mut self, src: &str, path: String) {
+        use std::os::unix::ffi::OsStrExt;
+        match fs::read_link(src) {
+            Ok(target) => self.entries.push((path, Some(target.as_os_str().as_bytes().to_vec()))),
+            Err(e) => self.unreadable.push(format!("{}: {}", src, e)),
+        }
+    }
+
+    fn add_process(&mut self, pid: u64, cap: Option<&MutateCap>) {
+        let src = format!("/proc/{}", pid);
+        let dest = format!("proc/{}", pid);
+        self.dir(dest.clone());
+        for file in CAPTURE_FILES {
+            self.copy_file(&format!("{}/{}", src, file), format!("{}/{}", dest, file));
+        }
+        for link in CAPTURE_LINKS {
+            self.copy_link(&format!("{}/{}", src, link), format!("{}/{}", dest, link));
+        }
+        self.dir(format!("{}/ns", dest));
+        for &(ns_type, _) in NAMESPACE_TYPES {
+            self.copy_link(&format!("{}/ns/{}", src, ns_type), format!("{}/ns/{}", dest, ns_type));
+        }
+        self.dir(format!("{}/net", dest));
+        for table in CAPTURE_NET_TABLES {
+            self.copy_file(&format!("{}/net/{}", src, table), format!("{}/net/{}", dest, table));
+        }
+
+        for subdir in &["fd", "fdinfo", "fdstat"] {
+            self.dir(format!("{}/{}", dest, subdir));
+        }
+        match read_fds(pid) {
+            Ok(fds) => for fd in fds {
+                self.copy_link(&format!("{}/fd/{}", src, fd), format!("{}/fd/{}", dest, fd));
+                self.copy_file(&format!("{}/fdinfo/{}", src, fd), format!("{}/fdinfo/{}", dest, fd));
+                match fd_stat(pid, fd) {
+                    Ok(st) => self.entries.push((format!("{}/fdstat/{}", dest, fd), Some(format_fd_stat(&st).into_bytes()))),
+                    Err(e) => self.unreadable.push(format!("{}/fd/{}: {}", src, fd, e)),
+                }
+            },
+            Err(e) => self.unreadable.push(format!("{}/fd: {}", src, e)),
+        }
+
+        self.dir(format!("{}/task", dest));
+        match task_ids(pid) {
+            Ok(tids) => for tid in tids {
+                self.dir(format!("{}/task/{}", dest, tid));
+                for file in CAPTURE_TASK_FILES {
+                    self.copy_file(&format!("{}/task/{}/{}", src, tid, file), format!("{}/task/{}/{}", dest, tid, file));
+                }
+            },
+            Err(e) => self.unreadable.push(format!("{}/task: {}", src, e)),
+        }
+
+        if let Some(cap) = cap {
+            match thread_stacks(cap, pid, None) {
+                Ok((stacks, _)) => {
+                    let stacks = stack_lines(&stacks).iter().map(|line| format!("{}\n", line)).collect::<String>();
+                    self.entries.push((format!("{}/pstack", dest), Some(stacks.into_bytes())));
+                }
+                Err(_) => self.unreadable.push(format!("stacks of process {}", pid)),
+            }
+        }
+    }
+}
+
+fn read_trimmed(path: &str) -> Json {
+    match fs::read_to_string(path) {
+        Ok(contents) => Json::Str(contents.trim().to_string()),
+        Err(_) => Json::Null,
+    }
+}
+
+// Write a capture of 'pids' to 'out', returning what couldn't be read
+fn write_capture<W: Write>(out: W, pids: &[u64], cap: Option<&MutateCap>) -> Result<(W, Vec<String>), Box<dyn Error>> {
+    let mut builder = CaptureBuilder { entries: vec![], unreadable: vec![] };
+    builder.dir("proc".to_string());
+    builder.copy_file("/proc/uptime", "proc/uptime".to_string());
+    for &(ns_type, _) in NAMESPACE_TYPES {
+        builder.copy_link(&format!("/proc/1/ns/{}", ns_type), format!("init/ns/{}", ns_type));
+    }
+    for &pid in pids {
+        builder.add_process(pid, cap);
+    }
+
+    let created = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs()).unwrap_or_default();
+    let manifest = Json::object(vec![
+        ("version", Json::UInt(CAPTURE_VERSION)),
+        ("created", Json::UInt(created)),
+        ("hostname", read_trimmed("/proc/sys/kernel/hostname")),
+        ("kernel", read_trimmed("/proc/sys/kernel/osrelease")),
+        ("pids", Json::Array(pids.iter().map(|&pid| Json::UInt(pid)).collect())),
+        ("stacks", Json::Bool(cap.is_some())),
+        ("unreadable", Json::Array(builder.unreadable.iter().cloned().map(Json::Str).collect())),
+    ]);
+
+    let mut tar = TarWriter::new(out, created);
+    tar.add_file(CAPTURE_MANIFEST, format!("{}\n", manifest).as_bytes())?;
+    for (path, contents) in &builder.entries {
+        match *contents {
+            Some(ref contents) => tar.add_file(path, contents)?,
+            None => tar.add_dir(path)?,
+        }
+    }
+    Ok((tar.finish()?, builder.unreadable))
+}
+
+fn create_capture_dir() -> std::io::Result<PathBuf> {
+    use std::os::unix::fs::DirBuilderExt;
+    for i in 0..100 {
+        let dir = env::temp_dir().join(format!("ptools-capture.{}.{}", std::process::id(), i));
+        match fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(std::io::Error::new(ErrorKind::AlreadyExists, "unable to create a temporary directory"))
+}
+
+extern "C" fn remove_capture_dir() {
+    if let Some(dir) = CAPTURE_DIR.get() {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+// Unpack a capture, and read everything from it from now on
+fn load_capture(path: &str) -> Result<(), Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut magic = [0; 2];
+    let compressed = file.read(&mut magic)? == magic.len() && magic == GZIP_MAGIC;
+    file.seek(SeekFrom::Start(0))?;
+
+    let dir = create_capture_dir()?;
+    CAPTURE_DIR.set(dir.clone()).expect("only one capture is loaded");
+    unsafe {
+        libc::atexit(remove_capture_dir);
+    }
+
+    if compressed {
+        let mut child = std::process::Command::new("gzip").arg("-dc")
+            .stdin(file)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("unable to run gzip: {}", e))?;
+        let result = unpack_tar(child.stdout.as_mut().expect("gzip was spawned with a piped stdout"), &dir);
+        // Stop gzip if we gave up part way through
+        drop(child.stdout.take());
+        let status = child.wait()?;
+        result?;
+        if !status.success() {
+            return Err(From::from(format!("gzip failed: {}", status)));
+        }
+    } else {
+        unpack_tar(&mut file, &dir)?;
+    }
+
+    let manifest = match fs::read_to_string(dir.join(CAPTURE_MANIFEST)) {
+        Ok(manifest) => Json::parse(&manifest)?,
+        Err(_) => return Err(From::from(format!("not a capture: no {}", CAPTURE_MANIFEST))),
+    };
+    match manifest.get("version").and_then(Json::as_u64) {
+        Some(CAPTURE_VERSION) => Ok(()),
+        version => Err(From::from(format!("unsupported capture version {:?}", version))),
+    }
+}
+
+// For tools which need the live process, e.g. to read its memory or compare kernel objects
+fn refuse_capture(program: &str, reason: &str) {
+    if is_capture() {
+        eprintln!("{} can't be used with --from-capture: {}", program, reason);
+        exit(1);
+    }
+}
+
+//
+// Delay accounting (see Documentation/accounting/delay-accounting.rst in the kernel) records how
+// long each thread spends waiting: for a CPU while runnable, for block I/O, for pages to be swapped
+// in, and so on. pstopwatch reads the totals for a process twice, with the TASKSTATS generic
+// netlink family, and shows how they grew. Without TASKSTATS (e.g. in a container, where it isn't
+// available) it falls back to /proc/[pid]/task/[tid]/schedstat, which only has the time spent
+// running and waiting for a CPU.
+//
+
+// The states delays are measured for, and for each, where its total is in struct taskstats (see
+// include/uapi/linux/taskstats.h), the version of the struct which added it, and whether it is only
+// measured with delay accounting turned on. Time spent running and waiting for a CPU is counted by
+// the scheduler regardless.
+const DELAY_STATES: &[(&str, usize, u16, bool)] = &[
+    ("running", 64, 1, false),
+    ("waiting for a CPU", 24, 1, false),
+    ("block I/O", 40, 1, true),
+    ("swapping in", 56, 1, true),
+    ("memory reclaim", 320, 5, true),
+    ("thrashing", 336, 7, true),
+    ("memory compaction", 360, 11, true),
+];
+
+// The total time the threads of a process have spent in each of DELAY_STATES, in nanoseconds, or
+// None for those we can't measure.
+type Delays = Vec<Option<u64>>;
+
+const TASKSTATS_FAMILY_NAME: &str = "TASKSTATS";
+const TASKSTATS_CMD_GET: u8 = 1;
+const TASKSTATS_CMD_ATTR_TGID: u16 = 2;
+const TASKSTATS_TYPE_STATS: u16 = 3;
+const TASKSTATS_TYPE_AGGR_TGID: u16 = 5;
+const GENL_HDR_LEN: usize = 4;
+const NLA_HDR_LEN: usize = 4;
+
+// Delay accounting is off by default since Linux 5.14, and turned on with this sysctl. Older
+// kernels don't have it, and always account delays.
+fn delay_accounting_enabled() -> bool {
+    fs::read_to_string("/proc/sys/kernel/task_delayacct").map_or(true, |enabled| enabled.trim() != "0")
+}
+
+// Parse a sequence of netlink attributes into their types and payloads
+fn netlink_attrs(buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = vec![];
+    let mut offset = 0;
+    while offset + NLA_HDR_LEN <= buf.len() {
+        let len = read_u16(buf, offset) as usize;
+        if len < NLA_HDR_LEN || offset + len > buf.len() {
+            break;
+        }
+        // The top bits of the type are flags
+        attrs.push((read_u16(buf, offset + 2) & 0x3fff, &buf[offset + NLA_HDR_LEN..offset + len]));
+        offset += (len + 3) & !3;
+    }
+    attrs
+}
+
+fn parse_taskstats(stats: &[u8], delay_accounting: bool) -> Delays {
+    let version = if stats.len() >= 2 { read_u16(stats, 0) } else { 0 };
+    DELAY_STATES.iter().map(|&(_, offset, min_version, needs_accounting)| {
+        if version >= min_version && offset + 8 <= stats.len() && (delay_accounting || !needs_accounting) {
+            Some(read_u64(stats, offset))
+        } else {
+            None
+        }
+    }).collect()
+}
+
+// A generic netlink socket for asking the kernel about tasks
+struct TaskstatsSocket {
+    socket: File,
+    family: u16,
+}
+
+impl TaskstatsSocket {
+    fn open() -> Result<Self, Box<dyn Error>> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_GENERIC) };
+        nix::errno::Errno::result(fd)?;
+        let socket = unsafe { <File as std::os::unix::io::FromRawFd>::from_raw_fd(fd) };
+
+        // Generic netlink families are numbered dynamically, so look TASKSTATS up by name
+        let mut name = TASKSTATS_FAMILY_NAME.as_bytes().to_vec();
+        name.push(0);
+        let mut control = TaskstatsSocket { socket, family: libc::GENL_ID_CTRL as u16 };
+        let reply = control.request(libc::CTRL_CMD_GETFAMILY as u8, libc::CTRL_ATTR_FAMILY_NAME as u16, &name)?;
+        let family = netlink_attrs(&reply).into_iter()
+            .find(|&(attr_type, payload)| attr_type == libc::CTRL_ATTR_FAMILY_ID as u16 && payload.len() >= 2)
+            .map(|(_, payload)| read_u16(payload, 0))
+            .ok_or_else(|| ParseError::new("generic netlink reply", "missing family id"))?;
+        control.family = family;
+        Ok(control)
+    }
+
+    // Send a request with a single attribute, and return the attributes of the reply
+    fn request(&self, cmd: u8, attr_type: u16, attr: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        use std::os::unix::io::AsRawFd;
+        let attr_len = NLA_HDR_LEN + attr.len();
+        let len = NLMSG_HDR_LEN + GENL_HDR_LEN + ((attr_len + 3) & !3);
+        let mut msg = vec![];
+        msg.extend_from_slice(&(len as u32).to_ne_bytes());
+        msg.extend_from_slice(&self.family.to_ne_bytes());
+        msg.extend_from_slice(&(libc::NLM_F_REQUEST as u16).to_ne_bytes());
+        msg.extend_from_slice(&[0; 8]); // seq, pid
+        msg.extend_from_slice(&[cmd, 1, 0, 0]); // cmd, version, reserved
+        msg.extend_from_slice(&(attr_len as u16).to_ne_bytes());
+        msg.extend_from_slice(&attr_type.to_ne_bytes());
+        msg.extend_from_slice(attr);
+        msg.resize(len, 0);
+        let fd = self.socket.as_raw_fd();
+        let res = unsafe { libc::send(fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        nix::errno::Errno::result(res)?;
+
+        let mut buf = vec![0u8; 16384];
+        let res = unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        let received = nix::errno::Errno::result(res)? as usize;
+        if received < NLMSG_HDR_LEN {
+            return Err(From::from(ParseError::new("netlink reply", "truncated message")));
+        }
+        let msg_len = (read_u32(&buf, 0) as usize).min(received);
+        if read_u16(&buf, 4) == libc::NLMSG_ERROR as u16 && msg_len >= NLMSG_HDR_LEN + 4 {
+            let errno = -(read_u32(&buf, NLMSG_HDR_LEN) as i32);
+            return Err(From::from(std::io::Error::from_raw_os_error(errno)));
+        }
+        if msg_len < NLMSG_HDR_LEN + GENL_HDR_LEN {
+            return Err(From::from(ParseError::new("netlink reply", "truncated message")));
+        }
+        Ok(buf[NLMSG_HDR_LEN + GENL_HDR_LEN..msg_len].to_vec())
+    }
+
+    // The delays of every thread of a process, including those which have exited
+    fn process_delays(&self, pid: u64) -> Result<Delays, Box<dyn Error>> {
+        let reply = self.request(TASKSTATS_CMD_GET, TASKSTATS_CMD_ATTR_TGID, &(pid as u32).to_ne_bytes())?;
+        let stats = netlink_attrs(&reply).into_iter()
+            .filter(|&(attr_type, _)| attr_type == TASKSTATS_TYPE_AGGR_TGID)
+            .flat_map(|(_, nested)| netlink_attrs(nested))
+            .find(|&(attr_type, _)| attr_type == TASKSTATS_TYPE_STATS)
+            .map(|(_, stats)| stats)
+            .ok_or_else(|| ParseError::new("taskstats reply", "missing stats"))?;
+        Ok(parse_taskstats(stats, delay_accounting_enabled()))
+    }
+}
+
+// The time the live threads of a process have spent running and waiting for a CPU, from their
+// schedstat files, which hold those two times in nanoseconds and the number of times they ran.
+fn schedstat_delays(pid: u64) -> Result<Delays, Box<dyn Error>> {
+    let mut running = 0;
+    let mut runnable = 0;
+    for tid in task_ids(pid)? {
+        let schedstat = match read_task_file(pid, tid, "schedstat") {
+            Ok(schedstat) => schedstat,
+            // The thread exited
+            Err(_) => continue,
+        };
+        let fields = schedstat.split_whitespace().map(|f| f.parse::<u64>()).collect::<Result<Vec<_>, _>>()?;
+        if fields.len() < 2 {
+            return Err(From::from(ParseError::in_file("schedstat", &format!("unexpected contents '{}'", schedstat.trim()))));
+        }
+        running += fields[0];
+        runnable += fields[1];
+    }
+    let mut delays = vec![None; DELAY_STATES.len()];
+    delays[0] = Some(running);
+    delays[1] = Some(runnable);
+    Ok(delays)
+}
+
+fn format_delay(ns: u64, interval: Duration) -> String {
+    let pct = ns as f64 * 100.0 / interval.as_nanos().max(1) as f64;
+    format!("{:>9.3}s  {:5.1}%", ns as f64 / 1e9, pct)
+}
+
+// Print how much each delay grew by over 'interval'. Percentages are of the interval, so with
+// several threads they can add up to more than 100%.
+fn print_delays(pid: u64, before: &Delays, after: &Delays, interval: Duration, source: &str) {
+    print_proc_summary(pid);
+    let threads = task_ids(pid).map(|tids| tids.len()).unwrap_or(0);
+    println!("  over {:.2}s, {} thread{} (from {}):", interval.as_secs_f64(), threads,
+             if threads == 1 { "" } else { "s" }, source);
+    let mut accounted = 0;
+    for (i, &(state, _, _, _)) in DELAY_STATES.iter().enumerate() {
+        match (before[i], after[i]) {
+            (Some(before), Some(after)) => {
+                let delta = after.saturating_sub(before);
+                // Reclaim and compaction happen while the thread is running
+                if i < 4 {
+                    accounted += delta;
+                }
+                println!("    {:<20}{}", state, format_delay(delta, interval));
+            }
+            _ if i < 4 => println!("    {:<20}{:>10}", state, "unknown"),
+            _ => {}
+        }
+    }
+    // Whatever is left of the threads' time was spent sleeping, or blocked on something other
+    // than I/O. This is approximate, since threads may have come and gone during the interval.
+    let total = interval.as_nanos() as u64 * threads as u64;
+    let other = if after[2].is_some() { "sleeping/other" } else { "sleeping/I/O/other" };
+    println!("    {:<20}{}", other, format_delay(total.saturating_sub(accounted), interval));
+    if after[2].is_none() && source == "taskstats" {
+        println!("  (block I/O and swap delays need delay accounting: sysctl kernel.task_delayacct=1)");
+    }
+}
+
+// Read the delays of a process, and where they came from
+fn sample_delays(taskstats: Option<&TaskstatsSocket>, pid: u64) -> Result<(Delays, String), Box<dyn Error>> {
+    if let Some(taskstats) = taskstats {
+        if let Ok(delays) = taskstats.process_delays(pid) {
+            return Ok((delays, "taskstats".to_string()));
+        }
+    }
+    Ok((schedstat_delays(pid)?, "schedstat".to_string()))
+}
+
+// Who ptree --follow is following. A process keeps its pid when it execs, but when it daemonizes,
+// the daemon is a child which carries on after it exits, so that is who we follow next.
+struct Follow {
+    pid: u64,
+    // The living processes forked by the followed process or its descendants, by their parent,
+    // oldest first. When a process exits, its children join its parent's. Processes which
+    // daemonize usually fork a child which forks again and exits, so the followed process' entry
+    // ends up with the daemon.
+    forked: HashMap<u64, Vec<u64>>,
+    // Set when the followed process exited without leaving anyone to follow
+    exited: bool,
+    // What happened, to show below the tree
+    history: Vec<String>,
+}
+
+// How many events of the history of ptree --follow to show
+const FOLLOW_HISTORY: usize = 10;
+
+impl Follow {
+    fn new(pid: u64) -> Self {
+        Follow { pid, forked: HashMap::new(), exited: false, history: vec![] }
+    }
+
+    fn is_tracked(&self, pid: u64) -> bool {
+        pid == self.pid || self.forked.values().any(|children| children.contains(&pid))
+    }
+
+    fn note(&mut self, note: String) {
+        self.history.push(note);
+        let excess = self.history.len().saturating_sub(FOLLOW_HISTORY);
+        self.history.drain(..excess);
+    }
+
+    // 'describe' gives the command line of a process, for execs
+    fn handle(&mut self, event: &ProcEvent, describe: &dyn Fn(u64) -> String) {
+        match *event {
+            ProcEvent::Fork { parent, child } if self.is_tracked(parent) => {
+                self.forked.entry(parent).or_default().push(child);
+            }
+            ProcEvent::Exec { pid } if pid == self.pid => self.note(format!("{} exec'd: {}", pid, describe(pid))),
+            ProcEvent::Exit { pid, status } if self.is_tracked(pid) => {
+                let orphans = self.forked.remove(&pid).unwrap_or_default();
+                if pid != self.pid {
+                    for children in self.forked.values_mut() {
+                        if let Some(i) = children.iter().position(|&child| child == pid) {
+                            children.splice(i..i + 1, orphans);
+                            break;
+                        }
+                    }
+                    return;
+                }
+                match orphans.last() {
+                    Some(&child) => {
+                        self.note(format!("{} exited ({}), following its child {}", pid, wait_status_str(status), child));
+                        self.pid = child;
+                        self.forked.insert(child, orphans[..orphans.len() - 1].to_vec());
+                    }
+                    None => {
+                        self.note(format!("{} exited ({})", pid, wait_status_str(status)));
+                        self.exited = true;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Reprint the tree containing a process every 'interval', following it when it execs, is
+// re-parented or daemonizes, until it exits without leaving a child behind. Without the proc
+// connector, we only see what changed between refreshes, so we follow the newest of its children
+// from the last refresh which is still running.
+fn follow_tree(pid: u64, interval: Duration, opts: &PtreeOptions) -> ! {
+    catch_interrupts();
+    let proc_events = Arc::new(Mutex::new(vec![]));
+    if let Err(e) = listen_for_proc_events(proc_events.clone()) {
+        eprintln!("Unable to listen for process events, so only processes still running at each refresh can \
+                   be followed: {}", e);
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    let mut follow = Follow::new(pid);
+    let mut id = ProcessId::read(pid);
+    // The parent and children of the followed process at the last refresh
+    let mut last: Option<(u64, u64, Vec<u64>)> = None;
+    let is_tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+    loop {
+        for event in proc_events.lock().unwrap().drain(..) {
+            follow.handle(&event, &cmd_summary);
+        }
+        let tree = match read_process_tree() {
+            Ok(tree) => tree,
+            Err(e) => {
+                eprintln!("Unable to read processes: {}", e);
+                exit(1);
+            }
+        };
+
+        if id.is_none_or(|id| id.pid != follow.pid) {
+            id = ProcessId::read(follow.pid);
+        }
+        let alive = id.is_some() && id == ProcessId::read(follow.pid) && tree.parent_map.contains_key(&follow.pid);
+        if !alive && !follow.exited {
+            let successor = last.as_ref().filter(|&&(pid, _, _)| pid == follow.pid)
+                .and_then(|(_, _, children)| children.iter().rev().find(|child| tree.parent_map.contains_key(child)));
+            match successor {
+                Some(&child) => {
+                    follow.note(format!("{} exited, following its child {}", follow.pid, child));
+                    follow.pid = child;
+                    id = ProcessId::read(child);
+                }
+                None => {
+                    follow.note(format!("{} exited", follow.pid));
+                    follow.exited = true;
+                }
+            }
+        }
+
+        if let (Some(&ppid), Some(&(pid, last_ppid, _))) = (tree.parent_map.get(&follow.pid), last.as_ref()) {
+            if pid == follow.pid && ppid != last_ppid && !follow.exited {
+                follow.note(format!("{} was re-parented from {} to {}", follow.pid, last_ppid, ppid));
+            }
+        }
+
+        if is_tty {
+            print!("\x1b[H\x1b[2J");
+        }
+        if !follow.exited && tree.parent_map.contains_key(&follow.pid) {
+            print_tree(follow.pid, opts).unwrap();
+        }
+        if !follow.history.is_empty() {
+            println!();
+            for note in &follow.history {
+                println!("{}", note);
+            }
+        }
+        if follow.exited {
+            exit(0);
+        }
+        if !is_tty {
+            println!();
+        }
+        let _ = std::io::stdout().flush();
+
+        last = tree.parent_map.get(&follow.pid)
+            .map(|&ppid| (follow.pid, ppid, tree.child_map.get(&follow.pid).cloned().unwrap_or_default()));
+        sleep_unless_interrupted(interval);
+    }
+}
+
+//
+// pcaps shows the capability sets of processes, from /proc/[pid]/status, or with --all, goes
+// through every process and lists those holding each dangerous capability, for hardening reviews.
+//
+
+// Capabilities which are as good as root, or give power over other processes, the kernel or the
+// network, and so are worth accounting for in an audit
+const DANGEROUS_CAPABILITIES: &[&str] = &[
+    "cap_sys_admin", "cap_sys_ptrace", "cap_sys_module", "cap_sys_rawio", "cap_sys_boot", "cap_dac_override",
+    "cap_dac_read_search", "cap_fowner", "cap_chown", "cap_setuid", "cap_setgid", "cap_setpcap", "cap_setfcap",
+    "cap_net_admin", "cap_net_raw", "cap_bpf", "cap_perfmon",
+];
+
+// The number of a capability given by name, with or without 'cap_' and in either case
+fn parse_capability(name: &str) -> Option<usize> {
+    let name = name.to_lowercase();
+    let name = if name.starts_with("cap_") { name } else { format!("cap_{}", name) };
+    CAPABILITY_NAMES.iter().position(|&cap| cap == name)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct CapabilitySets {
+    inheritable: u64,
+    permitted: u64,
+    effective: u64,
+    bounding: u64,
+    ambient: u64,
+}
+
+fn read_capability_sets(pid: u64) -> Result<CapabilitySets, Box<dyn Error>> {
+    let status = ProcStat::read(pid)?;
+    let caps = |field| -> Result<u64, Box<dyn Error>> {
+        u64::from_str_radix(status.get_field(field)?, 16)
+            .map_err(|_| From::from(ParseError::in_file("status", &format!("invalid {}", field))))
+    };
+    Ok(CapabilitySets {
+        inheritable: caps("CapInh")?,
+        permitted: caps("CapPrm")?,
+        effective: caps("CapEff")?,
+        bounding: caps("CapBnd")?,
+        // Linux 4.3 and later
+        ambient: caps("CapAmb").unwrap_or(0),
+    })
+}
+
+// The audit login uid and session of a process, which it inherits from whatever pam_loginuid set
+// them for at login, and keeps through su, sudo and setuid programs. Either is None if it was never
+// set, as for services started at boot.
+fn read_audit_ids(pid: u64) -> (Option<u32>, Option<u32>) {
+    let read = |name| fs::read_to_string(proc_path!("{}/{}", pid, name)).ok()
+        .and_then(|id| id.trim().parse::<u32>().ok()).filter(|&id| id != u32::MAX);
+    (read("loginuid"), read("sessionid"))
+}
+
+fn audit_ids_str(loginuid: Option<u32>, sessionid: Option<u32>) -> String {
+    match (loginuid, sessionid) {
+        (None, None) => "unset, it isn't part of a login".to_string(),
+        (loginuid, sessionid) => format!("{}, session {}",
+                                         loginuid.map_or_else(|| "unset".to_string(),
+                                                              |uid| format!("{} ({})", user_name(uid), uid)),
+                                         sessionid.map_or_else(|| "unset".to_string(), |id| id.to_string())),
+    }
+}
+
+fn print_capability_sets(pid: u64) -> bool {
+    let sets = match read_capability_sets(pid) {
+        Ok(sets) => sets,
+        Err(e) => {
+            eprintln!("Unable to read capabilities of process {}: {}", pid, e);
+            return false;
+        }
+    };
+    print_proc_summary(pid);
+    let caps = |caps| if caps == 0 { "none".to_string() } else { capabilities_str(caps) };
+    println!("  effective:   {}", caps(sets.effective));
+    println!("  permitted:   {}", caps(sets.permitted));
+    println!("  inheritable: {}", caps(sets.inheritable));
+    println!("  bounding:    {}", caps(sets.bounding));
+    println!("  ambient:     {}", caps(sets.ambient));
+    let (loginuid, sessionid) = read_audit_ids(pid);
+    println!("  login uid:   {}", audit_ids_str(loginuid, sessionid));
+    true
+}
+
+// For each capability, the processes which hold it, and whether it is in effect. Holding it
+// means having it in the permitted set, since a process can make any of those effective.
+fn capability_holders(processes: &[(u64, CapabilitySets)], caps: &[usize]) -> Vec<(usize, Vec<(u64, bool)>)> {
+    caps.iter().map(|&cap| {
+        let holders = processes.iter().filter(|(_, sets)| has_capability(sets.permitted, cap as u32))
+            .map(|&(pid, sets)| (pid, has_capability(sets.effective, cap as u32)))
+            .collect();
+        (cap, holders)
+    }).collect()
+}
+
+//
+// pboot prints what bug reports ask for about the system a process runs on: the kernel and the
+// command line it was booted with, when, and for each process the personality flags it runs with
+// (e.g. ADDR_NO_RANDOMIZE, set by 'setarch -R') and its LSM attributes from /proc/[pid]/attr.
+//
+
+// The flags of personality(2), above the execution domain in the low byte
+const PERSONALITY_FLAGS: &[(u32, &str)] = &[
+    (0x0020000, "UNAME26"),
+    (0x0040000, "ADDR_NO_RANDOMIZE"),
+    (0x0080000, "FDPIC_FUNCPTRS"),
+    (0x0100000, "MMAP_PAGE_ZERO"),
+    (0x0200000, "ADDR_COMPAT_LAYOUT"),
+    (0x0400000, "READ_IMPLIES_EXEC"),
+    (0x0800000, "ADDR_LIMIT_32BIT"),
+    (0x1000000, "SHORT_INODE"),
+    (0x2000000, "WHOLE_SECONDS"),
+    (0x4000000, "STICKY_TIMEOUTS"),
+    (0x8000000, "ADDR_LIMIT_3GB"),
+];
+
+// A personality as /proc/[pid]/personality gives it, e.g. 'PER_LINUX|ADDR_NO_RANDOMIZE'
+fn personality_str(personality: u32) -> String {
+    let mut names = vec![match personality & 0xff {
+        0x00 => "PER_LINUX".to_string(),
+        0x08 => "PER_LINUX32".to_string(),
+        domain => format!("domain 0x{:x}", domain),
+    }];
+    let mut rest = personality & !0xff;
+    for &(flag, name) in PERSONALITY_FLAGS {
+        if rest & flag != 0 {
+            names.push(name.to_string());
+            rest &= !flag;
+        }
+    }
+    if rest != 0 {
+        names.push(format!("0x{:x}", rest));
+    }
+    names.join("|")
+}
+
+// The files in /proc/[pid]/attr, of whichever LSM is first, and of those with their own directory
+const LSM_ATTRS: &[&str] = &[
+    "current", "exec", "fscreate", "keycreate", "prev", "sockcreate", "apparmor/current", "apparmor/exec",
+    "apparmor/prev", "smack/current", "selinux/current", "selinux/exec",
+];
+
+// The LSM attributes which are set, as (file, value) pairs. They can't be read without an LSM
+// which provides them.
+fn read_lsm_attrs(pid: u64) -> Vec<(&'static str, String)> {
+    LSM_ATTRS.iter().filter_map(|&name| {
+        let value = fs::read(proc_path!("{}/attr/{}", pid, name)).ok()?;
+        let value = String::from_utf8_lossy(&value).trim_end_matches(['\0', '\n']).to_string();
+        Some((name, value)).filter(|(_, value)| !value.is_empty())
+    }).collect()
+}
+
+// The boot time, from the btime line of /proc/stat
+fn parse_boot_time(stat: &str) -> Option<std::time::SystemTime> {
+    let secs = stat.lines().find_map(|line| line.strip_prefix("btime "))?.trim().parse::<u64>().ok()?;
+    Some(std::time::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// The personality of a process, which only those allowed to ptrace it can read
+fn read_personality(pid: u64) -> Result<u32, Box<dyn Error>> {
+    let personality = fs::read_to_string(proc_path!("{}/personality", pid))?;
+    Ok(u32::from_str_radix(personality.trim(), 16)?)
+}
+
+//
+// pmem reports how much memory processes use, from /proc/[pid]/smaps_rollup: RSS (what is
+// resident), PSS (with shared pages divided between the processes sharing them, so that totals add
+// up), USS (what would be freed if the process exited) and swap. On hosts running KSM (e.g. for
+// virtual machines), pages it merged are also shown, since RSS counts each copy in full.
+//
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct MemoryTotals {
+    // All in kB
+    rss: u64,
+    pss: u64,
+    uss: u64,
+    swap: u64,
+    // Pages merged by KSM, which are part of RSS
+    ksm: u64,
+    // Pages KSM merged into the zero page, which use no memory and are in none of the others
+    zero: u64,
+}
+
+impl std::ops::AddAssign for MemoryTotals {
+    fn add_assign(&mut self, other: MemoryTotals) {
+        self.rss += other.rss;
+        self.pss += other.pss;
+        self.uss += other.uss;
+        self.swap += other.swap;
+        self.ksm += other.ksm;
+        self.zero += other.zero;
+    }
+}
+
+// Sum the fields in smaps or smaps_rollup, which are lines like 'Pss:   1024 kB'. Summing works for
+// either, since smaps_rollup is formatted like smaps with a single mapping covering everything.
+fn parse_memory_totals(smaps: &str) -> MemoryTotals {
+    let mut totals = MemoryTotals::default();
+    for line in smaps.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some(field) => field,
+            None => continue,
+        };
+        let kb = match value.trim().strip_suffix(" kB").and_then(|kb| kb.trim().parse::<u64>().ok()) {
+            Some(kb) => kb,
+            None => continue,
+        };
+        match key {
+            "Rss" => totals.rss += kb,
+            "Pss" => totals.pss += kb,
+            "Private_Clean" | "Private_Dirty" => totals.uss += kb,
+            "Swap" => totals.swap += kb,
+            // Linux 6.8 and later
+            "KSM" => totals.ksm += kb,
+            _ => {}
+        }
+    }
+    totals
+}
+
+// How many of a process' pages KSM merged into the zero page, from /proc/[pid]/ksm_stat, which has
+// lines like 'ksm_zero_pages 42' (the count is new in Linux 6.10)
+fn parse_ksm_zero_pages(ksm_stat: &str) -> Option<u64> {
+    ksm_stat.lines().find_map(|line| line.strip_prefix("ksm_zero_pages ")?.trim().parse().ok())
+}
+
+// smaps_rollup is new in Linux 4.14, and isn't in captures, so fall back to smaps
+fn read_memory_totals(pid: u64) -> std::io::Result<MemoryTotals> {
+    let mut totals = fs::read_to_string(proc_path!("{}/smaps_rollup", pid))
+        .or_else(|_| fs::read_to_string(proc_path!("{}/smaps", pid)))
+        .map(|smaps| parse_memory_totals(&smaps))?;
+    if let Some(pages) = fs::read_to_string(proc_path!("{}/ksm_stat", pid)).ok().and_then(|s| parse_ksm_zero_pages(&s)) {
+        totals.zero = pages * page_size() / 1024;
+    }
+    Ok(totals)
+}
+
+// What pmem --group-by adds up processes by
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MemoryGroup {
+    User,
+    Cgroup,
+    Comm,
+}
+
+impl MemoryGroup {
+    fn parse(s: &str) -> Option<MemoryGroup> {
+        match s {
+            "user" => Some(MemoryGroup::User),
+            "cgroup" => Some(MemoryGroup::Cgroup),
+            "comm" => Some(MemoryGroup::Comm),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            MemoryGroup::User => "USER",
+            MemoryGroup::Cgroup => "CGROUP",
+            MemoryGroup::Comm => "COMMAND",
+        }
+    }
+
+    fn of(&self, pid: u64) -> String {
+        match *self {
+            MemoryGroup::User => process_user(pid),
+            MemoryGroup::Cgroup => cgroup_summary(pid, &CgroupHierarchy::Unified),
+            MemoryGroup::Comm => fs::read_to_string(proc_path!("{}/comm", pid))
+                .map(|comm| comm.trim_end().to_string())
+                .unwrap_or_else(|_| "-".to_string()),
+        }
+    }
+}
+
+// The name of the real user of a process, or its uid if it has no name
+fn process_user(pid: u64) -> String {
+    let uid = match ProcStat::read(pid).and_then(|status| Ok(status.get_field("Uid")?.to_string())) {
+        Ok(uids) => match uids.split_whitespace().next().and_then(|uid| uid.parse::<libc::uid_t>().ok()) {
+            Some(uid) => uid,
+            None => return "-".to_string(),
+        },
+        Err(_) => return "-".to_string(),
+    };
+    // A capture's users are from another system's passwd
+    if is_capture() {
+        return uid.to_string();
+    }
+    user_name(uid)
+}
+
+// The name of a user, or its uid if it has none
+fn user_name(uid: libc::uid_t) -> String {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    let res = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if res != 0 || result.is_null() {
+        return uid.to_string();
+    }
+    unsafe { std::ffi::CStr::from_ptr(pwd.pw_name) }.to_string_lossy().into_owned()
+}
+
+// The name of a group, or its gid if it has none
+fn group_name(gid: libc::gid_t) -> String {
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    let res = unsafe { libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if res != 0 || result.is_null() {
+        return gid.to_string();
+    }
+    unsafe { std::ffi::CStr::from_ptr(grp.gr_name) }.to_string_lossy().into_owned()
+}
+
+// The uid of a user given by name or number
+fn parse_user(user: &str) -> Result<libc::uid_t, Box<dyn Error>> {
+    if let Ok(uid) = user.parse::<libc::uid_t>() {
+        return Ok(uid);
+    }
+    let name = std::ffi::CString::new(user)?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    let res = unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if res != 0 || result.is_null() {
+        return Err(From::from(format!("No such user '{}'", user)));
+    }
+    Ok(pwd.pw_uid)
+}
+
+// The ranges of /proc/[pid]/uid_map or gid_map, which has lines of the form
+//
+//    0     100000      65536
+//
+// mapping 65536 ids from 0 inside the process' user namespace to 100000 outside it, where outside
+// is the user namespace of whoever reads the file.
+type IdMap = Vec<(u32, u32, u32)>;
+
+fn parse_id_map(contents: &str) -> IdMap {
+    contents.lines().filter_map(|line| {
+        let fields = line.split_whitespace().map(|f| f.parse::<u32>().ok()).collect::<Option<Vec<_>>>()?;
+        match fields[..] {
+            [inside, outside, count] => Some((inside, outside, count)),
+            _ => None,
+        }
+    }).collect()
+}
+
+// An outside id's id inside the namespace, or None if it isn't mapped (the process sees those as
+// the overflow id, usually 65534)
+fn id_inside(map: &IdMap, outside: u32) -> Option<u32> {
+    map.iter().find(|&&(_, start, count)| outside >= start && outside - start < count)
+        .map(|&(inside, start, _)| inside + (outside - start))
+}
+
+// The uid and gid maps of a process, or None if it is in the same user namespace as us, with
+// every id mapped to itself
+fn read_id_maps(pid: u64) -> Option<(IdMap, IdMap)> {
+    let read = |name| fs::read_to_string(proc_path!("{}/{}", pid, name)).ok().map(|map| parse_id_map(&map));
+    let (uid_map, gid_map) = (read("uid_map")?, read("gid_map")?);
+    let identity = vec![(0, 0, u32::MAX)];
+    if uid_map == identity && gid_map == identity {
+        None
+    } else {
+        Some((uid_map, gid_map))
+    }
+}
+
+// Ids as they are inside a user namespace, or '-' for ones it doesn't map
+fn id_inside_str(map: &IdMap, outside: u32) -> String {
+    id_inside(map, outside).map_or("-".to_string(), |id| id.to_string())
+}
+
+// The real uid and gid of a process, as seen from outside and inside its user namespace, e.g.
+// 'uid 100000 (0 inside), gid 100000 (0 inside)'
+fn user_namespace_ids_str(pid: u64, maps: &(IdMap, IdMap)) -> Option<String> {
+    let status = ProcStat::read(pid).ok()?;
+    let real = |field| status.get_field(field).ok()?.split_whitespace().next()?.parse::<u32>().ok();
+    let (uid, gid) = (real("Uid")?, real("Gid")?);
+    Some(format!("uid {} ({} inside), gid {} ({} inside)", uid, id_inside_str(&maps.0, uid), gid,
+                 id_inside_str(&maps.1, gid)))
+}
+
+// Lines of pmem's output start with the process or group, then for groups, how many processes are
+// in each. With ksm, they end with what KSM merged.
+fn print_memory_header(first: &str, count: bool, ksm: bool) {
+    let count = if count { format!(" {:>9}", "PROCESSES") } else { String::new() };
+    let ksm = if ksm { format!(" {:>10} {:>10}", "KSM kB", "ZERO kB") } else { String::new() };
+    println!("{:<24}{} {:>10} {:>10} {:>10} {:>10}{}", first, count, "USS kB", "PSS kB", "RSS kB", "SWAP kB", ksm);
+}
+
+fn print_memory_line(first: &str, count: Option<usize>, totals: &MemoryTotals, ksm: bool) {
+    let count = count.map(|count| format!(" {:>9}", count)).unwrap_or_default();
+    let ksm = if ksm { format!(" {:>10} {:>10}", totals.ksm, totals.zero) } else { String::new() };
+    println!("{:<24}{} {:>10} {:>10} {:>10} {:>10}{}", first, count, totals.uss, totals.pss, totals.rss, totals.swap,
+             ksm);
+}
+
+// Add up processes by group, largest PSS first. Each group has how many processes are in it.
+fn group_memory_totals(processes: &[(String, MemoryTotals)]) -> Vec<(String, usize, MemoryTotals)> {
+    let mut groups: Vec<(String, usize, MemoryTotals)> = vec![];
+    for (group, totals) in processes {
+        match groups.iter_mut().find(|(g, _, _)| g == group) {
+            Some((_, count, sum)) => {
+                *count += 1;
+                *sum += *totals;
+            }
+            None => groups.push((group.clone(), 1, *totals)),
+        }
+    }
+    groups.sort_by(|a, b| b.2.pss.cmp(&a.2.pss).then_with(|| a.0.cmp(&b.0)));
+    groups
+}
+
+//
+// pmem --wss estimates working sets with idle page tracking (see
+// Documentation/admin-guide/mm/idle_page_tracking.rst): the pages a process has resident are
+// marked idle through /sys/kernel/mm/page_idle/bitmap, and when the interval is over, those the
+// kernel has cleared the mark of are the ones which were used. Unlike RSS, this leaves out memory
+// which is resident but cold, such as caches nobody reads or code which only runs at startup.
+//
+
+const PAGE_IDLE_BITMAP: &str = "/sys/kernel/mm/page_idle/bitmap";
+
+// /proc/[pid]/pagemap has a u64 for each virtual page: bit 63 is set if the page is present, with
+// its page frame number in bits 0-54. Without CAP_SYS_ADMIN, the frame numbers read as 0.
+fn pagemap_pfn(entry: u64) -> Option<u64> {
+    match entry >> 63 {
+        1 => Some(entry & ((1 << 55) - 1)),
+        _ => None,
+    }
+}
+
+// The frames of the pages a process has resident, sorted, with those it shares with itself once.
+// Mappings with nothing resident, like the address space Go, JVMs and ASAN reserve, are skipped
+// rather than read a page at a time, as they can be terabytes.
+fn resident_pfns(pid: u64) -> Result<Vec<u64>, Box<dyn Error>> {
+    let page = page_size();
+    let mappings = parse_smaps(BufReader::new(File::open(proc_path!("{}/smaps", pid))?))?;
+    let mut pagemap = File::open(proc_path!("{}/pagemap", pid))?;
+    let mut entries = vec![0u8; 8 * 4096];
+    let mut pfns = vec![];
+    // The vsyscall page is outside of the range pagemap covers
+    for mapping in mappings.iter().filter(|mapping| mapping.path != "[vsyscall]" && mapping.size("Rss") > 0) {
+        let (mut vpage, end) = (mapping.start / page, mapping.end / page);
+        while vpage < end {
+            let len = ((end - vpage) as usize).min(entries.len() / 8) * 8;
+            pagemap.seek(SeekFrom::Start(vpage * 8))?;
+            pagemap.read_exact(&mut entries[..len])?;
+            pfns.extend(entries[..len].chunks(8)
+                .filter_map(|entry| pagemap_pfn(read_u64(entry, 0))));
+            vpage += len as u64 / 8;
+        }
+    }
+    pfns.sort_unstable();
+    pfns.dedup();
+    if pfns == [0] {
+        return Err("pagemap has no page frame numbers, reading them takes CAP_SYS_ADMIN".into());
+    }
+    Ok(pfns)
+}
+
+// The words of the idle page bitmap covering some frames (sorted), each a u64 with a bit for 64
+// frames, with the bits of the frames set
+fn bitmap_words(pfns: &[u64]) -> Vec<(u64, u64)> {
+    let mut words: Vec<(u64, u64)> = vec![];
+    for &pfn in pfns {
+        let (word, bit) = (pfn / 64, 1 << (pfn % 64));
+        match words.last_mut() {
+            Some(last) if last.0 == word => last.1 |= bit,
+            _ => words.push((word, bit)),
+        }
+    }
+    words
+}
+
+// Mark frames idle. Writing to the bitmap only sets bits (zeros are ignored), so there is no need
+// to read it first, and each run of consecutive words is written at once.
+fn set_idle(_cap: &MutateCap, bitmap: &File, words: &[(u64, u64)]) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    for run in words.chunk_by(|a, b| b.0 == a.0 + 1) {
+        let buf = run.iter().flat_map(|&(_, bits)| bits.to_ne_bytes()).collect::<Vec<_>>();
+        bitmap.write_all_at(&buf, run[0].0 * 8)?;
+    }
+    Ok(())
+}
+
+// Which of some frames are idle, as the words covering them
+fn read_idle(bitmap: &File, words: &[(u64, u64)]) -> std::io::Result<HashMap<u64, u64>> {
+    use std::os::unix::fs::FileExt;
+    let mut idle = HashMap::new();
+    for run in words.chunk_by(|a, b| b.0 == a.0 + 1) {
+        let mut buf = vec![0u8; run.len() * 8];
+        bitmap.read_exact_at(&mut buf, run[0].0 * 8)?;
+        for (&(word, bits), read) in run.iter().zip(buf.chunks(8)) {
+            idle.insert(word, bits & read_u64(read, 0));
+        }
+    }
+    Ok(idle)
+}
+
+// The frames a process had resident when they were marked idle, and those which took the mark: the
+// kernel only tracks pages on its LRU lists, which leaves out those of hugetlbfs, the tails of
+// transparent huge pages and kernel memory mapped into the process
+struct IdleMarks {
+    marked: Vec<u64>,
+    tracked: HashMap<u64, u64>,
+}
+
+impl IdleMarks {
+    fn set(cap: &MutateCap, bitmap: &File, pid: u64) -> Result<IdleMarks, Box<dyn Error>> {
+        let marked = resident_pfns(pid)?;
+        let words = bitmap_words(&marked);
+        set_idle(cap, bitmap, &words)?;
+        let tracked = read_idle(bitmap, &words)?;
+        Ok(IdleMarks { marked, tracked })
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct WorkingSet {
+    // Pages resident at the end of the interval
+    resident: u64,
+    // Those which were used during it, including those faulted in
+    accessed: u64,
+    // Those which were marked idle and still are
+    idle: u64,
+    // Those whose use can't be told, as they didn't take the idle mark
+    untracked: u64,
+}
+
+impl WorkingSet {
+    fn count(marks: &IdleMarks, resident: &[u64], idle: &HashMap<u64, u64>) -> WorkingSet {
+        let has = |words: &HashMap<u64, u64>, pfn: u64| words.get(&(pfn / 64)).is_some_and(|w| w & (1 << (pfn % 64)) != 0);
+        let mut set = WorkingSet { resident: resident.len() as u64, ..Default::default() };
+        for &pfn in resident {
+            if has(&marks.tracked, pfn) && has(idle, pfn) {
+                set.idle += 1;
+            } else if has(&marks.tracked, pfn) || marks.marked.binary_search(&pfn).is_err() {
+                set.accessed += 1;
+            } else {
+                set.untracked += 1;
+            }
+        }
+        set
+    }
+
+    fn read(bitmap: &File, pid: u64, marks: &IdleMarks) -> Result<WorkingSet, Box<dyn Error>> {
+        let resident = resident_pfns(pid)?;
+        let idle = read_idle(bitmap, &bitmap_words(&resident))?;
+        Ok(WorkingSet::count(marks, &resident, &idle))
+    }
+}
+
+fn print_working_sets(sets: &[(u64, WorkingSet)]) {
+    let kb = |pages: u64| pages * page_size() / 1024;
+    println!("{:<24} {:>10} {:>11} {:>10} {:>12}", "PID  COMMAND", "RSS kB", "ACCESSED kB", "IDLE kB",
+             "UNTRACKED kB");
+    for (pid, set) in sets {
+        println!("{:<24} {:>10} {:>11} {:>10} {:>12}", format!("{}  {}", pid, MemoryGroup::Comm.of(*pid)),
+                 kb(set.resident), kb(set.accessed), kb(set.idle), kb(set.untracked));
+    }
+}
+
+// Estimate the working sets of processes over an interval. Marking pages idle clears the accessed
+// bits of the processes' page table entries, which the kernel's reclaim and any other working set
+// estimates go by, so it isn't done in read-only mode.
+fn pmem_wss(pids: &[u64], interval: Duration) -> bool {
+    let pids_str = pids.iter().map(|pid| pid.to_string()).collect::<Vec<_>>().join(", ");
+    let cap = match MutateCap::acquire(&format!("mark the pages of process {} idle", pids_str)) {
+        Ok(cap) => cap,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let bitmap = match fs::OpenOptions::new().read(true).write(true).open(PAGE_IDLE_BITMAP) {
+        Ok(bitmap) => bitmap,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            eprintln!("{} doesn't exist, the kernel needs CONFIG_IDLE_PAGE_TRACKING for --wss", PAGE_IDLE_BITMAP);
+            return false;
+        }
+        Err(e) => {
+            eprintln!("Unable to open {}: {}", PAGE_IDLE_BITMAP, e);
+            return false;
+        }
+    };
+
+    let mut ok = true;
+    let mut marked = vec![];
+    for &pid in pids {
+        match IdleMarks::set(&cap, &bitmap, pid) {
+            Ok(marks) => marked.push((pid, marks)),
+            Err(e) => {
+                eprintln!("Unable to mark the pages of process {} idle: {}", pid, e);
+                ok = false;
+            }
+        }
+    }
+    if marked.is_empty() {
+        return false;
+    }
+    sleep_unless_interrupted(interval);
+
+    let mut sets = vec![];
+    for (pid, marks) in &marked {
+        match WorkingSet::read(&bitmap, *pid, marks) {
+            Ok(set) => sets.push((*pid, set)),
+            Err(e) => {
+                eprintln!("Unable to read the working set of process {}: {}", pid, e);
+                ok = false;
+            }
+        }
+    }
+    sets.sort_by(|a, b| b.1.accessed.cmp(&a.1.accessed).then(a.0.cmp(&b.0)));
+    print_working_sets(&sets);
+    ok
+}
+
+//
+// pcore writes a core file of a running process, like gdb's gcore, which debuggers (and pargs) can
+// read as if the process had crashed. Its threads are stopped with ptrace while the memory is
+// copied, then carry on. The layout follows the kernel's (see elf_core_dump() in
+// fs/binfmt_elf.c): the ELF header, program headers, a PT_NOTE segment with the process' state,
+// one for each of its threads, and a PT_LOAD segment for each mapping.
+//
+
+// Bits of /proc/[pid]/coredump_filter, which say which kinds of mapping are dumped (see core(5))
+const COREDUMP_ANON_PRIVATE: u32 = 1 << 0;
+const COREDUMP_ANON_SHARED: u32 = 1 << 1;
+const COREDUMP_MAPPED_PRIVATE: u32 = 1 << 2;
+const COREDUMP_MAPPED_SHARED: u32 = 1 << 3;
+const COREDUMP_ELF_HEADERS: u32 = 1 << 4;
+const COREDUMP_HUGETLB_PRIVATE: u32 = 1 << 5;
+const COREDUMP_HUGETLB_SHARED: u32 = 1 << 6;
+// The kernel's default, for when the process' filter can't be read
+const DEFAULT_COREDUMP_FILTER: u32 = 0x33;
+
+const NT_PRFPREG: u32 = 2;
+const NT_FILE: u32 = 0x4649_4c45;
+
+#[cfg(not(target_arch = "aarch64"))]
+const EM_NATIVE: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+const EM_NATIVE: u16 = 183;
+
+// struct elf_prstatus has the signal, pids and times before the registers, and whether the
+// floating point registers are in the core after them
+const PRSTATUS_REGS_OFFSET: usize = 112;
+const ELF_PHDR_LEN: usize = 56;
+const ELF_SHDR_LEN: usize = 64;
+
+fn page_size() -> u64 {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as u64,
+        _ => 4096,
+    }
+}
+
+// How much of a mapping goes into the core, as decided by vma_dump_size() in the kernel. smaps
+// doesn't tell us everything the kernel looks at: a private mapping which has had pages written
+// to has anonymous memory, and shared anonymous memory (including memfds and SysV shared memory)
+// is backed by files which aren't in any directory. 'is_elf' is asked whether the mapping starts
+// with an ELF header.
+fn core_dump_size(mapping: &Mapping, filter: u32, page_size: u64, is_elf: &mut dyn FnMut() -> bool) -> u64 {
+    let size = mapping.end - mapping.start;
+    let filtered = |bit| if filter & bit != 0 { size } else { 0 };
+    if mapping.has_vm_flag("dd") {
+        return 0;
+    }
+    if mapping.path == "[vdso]" || mapping.path == "[vsyscall]" {
+        return size;
+    }
+    if mapping.has_vm_flag("io") || mapping.has_vm_flag("pf") {
+        return 0;
+    }
+
+    let shared = mapping.has_vm_flag("sh");
+    if mapping.is_hugetlb() {
+        return filtered(if shared { COREDUMP_HUGETLB_SHARED } else { COREDUMP_HUGETLB_PRIVATE });
+    }
+    let file_backed = mapping.path.starts_with('/');
+    if shared {
+        let unlinked = !file_backed || ["/dev/zero", "/SYSV", "/memfd:"].iter().any(|p| mapping.path.starts_with(p));
+        return filtered(if unlinked { COREDUMP_ANON_SHARED } else { COREDUMP_MAPPED_SHARED });
+    }
+
+    let written = mapping.size("Anonymous") > 0 || mapping.size("Swap") > 0;
+    if written && filter & COREDUMP_ANON_PRIVATE != 0 {
+        return size;
+    }
+    if !file_backed {
+        return 0;
+    }
+    if filter & COREDUMP_MAPPED_PRIVATE != 0 {
+        return size;
+    }
+    // Without the ELF headers of the binaries, debuggers can't find their build IDs
+    if filter & COREDUMP_ELF_HEADERS != 0 && mapping.offset == 0 && mapping.perms.starts_with('r') && is_elf() {
+        return page_size;
+    }
+    0
+}
+
+// Append a note, named CORE as the kernel's are, to the contents of a PT_NOTE segment
+fn push_core_note(notes: &mut Vec<u8>, n_type: u32, desc: &[u8]) {
+    let align4 = |buf: &mut Vec<u8>| buf.resize((buf.len() + 3) & !3, 0);
+    notes.extend_from_slice(&5u32.to_ne_bytes());
+    notes.extend_from_slice(&(desc.len() as u32).to_ne_bytes());
+    notes.extend_from_slice(&n_type.to_ne_bytes());
+    notes.extend_from_slice(b"CORE\0");
+    align4(notes);
+    notes.extend_from_slice(desc);
+    align4(notes);
+}
+
+fn put_u32(buf: &mut [u8], off: usize, value: u32) {
+    buf[off..off + 4].copy_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u64(buf: &mut [u8], off: usize, value: u64) {
+    buf[off..off + 8].copy_from_slice(&value.to_ne_bytes());
+}
+
+// Read a register set of a stopped thread, as it is laid out in a core's notes
+fn get_regset(thread: &StoppedThread, n_type: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut regs = vec![0u8; 4096];
+    let mut iov = libc::iovec { iov_base: regs.as_mut_ptr() as *mut libc::c_void, iov_len: regs.len() };
+    let res = unsafe {
+        libc::ptrace(libc::PTRACE_GETREGSET, thread.tid as libc::pid_t, n_type as usize as *mut libc::c_void,
+                     &mut iov as *mut libc::iovec)
+    };
+    nix::errno::Errno::result(res)?;
+    regs.truncate(iov.iov_len);
+    Ok(regs)
+}
+
+// The fields of a task's /proc stat file which go into struct elf_prstatus and elf_prpsinfo
+struct CoreTaskStat {
+    state: char,
+    ppid: u32,
+    pgrp: u32,
+    sid: u32,
+    flags: u64,
+    nice: i8,
+    // utime, stime, cutime and cstime, in clock ticks
+    times: [u64; 4],
+}
+
+fn read_core_task_stat(path: &str) -> Result<CoreTaskStat, Box<dyn Error>> {
+    let stat = fs::read_to_string(path)?;
+    let field = |n| stat_field(&stat, n).ok_or_else(|| ParseError::in_file(path, "too few fields"));
+    let num = |n| -> Result<u64, Box<dyn Error>> { Ok(field(n)?.parse::<u64>()?) };
+    Ok(CoreTaskStat {
+        state: field(3)?.chars().next().unwrap_or('?'),
+        ppid: num(4)? as u32,
+        pgrp: num(5)? as u32,
+        sid: num(6)? as u32,
+        flags: num(9)?,
+        nice: field(19)?.parse::<i8>()?,
+        times: [num(14)?, num(15)?, num(16)?, num(17)?],
+    })
+}
+
+// struct elf_prstatus for a thread. Its registers are struct user_regs_struct (user_pt_regs on
+// aarch64), which is what PTRACE_GETREGSET returns for NT_PRSTATUS.
+fn core_prstatus(tid: u64, stat: &CoreTaskStat, regs: &[u8], fpvalid: bool) -> Vec<u8> {
+    let mut prstatus = vec![0u8; PRSTATUS_REGS_OFFSET + regs.len() + 8];
+    put_u32(&mut prstatus, 32, tid as u32);
+    put_u32(&mut prstatus, 36, stat.ppid);
+    put_u32(&mut prstatus, 40, stat.pgrp);
+    put_u32(&mut prstatus, 44, stat.sid);
+    let ticks = clock_ticks_per_sec();
+    for (i, &time) in stat.times.iter().enumerate() {
+        put_u64(&mut prstatus, 48 + i * 16, time / ticks);
+        put_u64(&mut prstatus, 56 + i * 16, time % ticks * 1_000_000 / ticks);
+    }
+    prstatus[PRSTATUS_REGS_OFFSET..PRSTATUS_REGS_OFFSET + regs.len()].copy_from_slice(regs);
+    put_u32(&mut prstatus, PRSTATUS_REGS_OFFSET + regs.len(), fpvalid as u32);
+    prstatus
+}
+
+// struct elf_prpsinfo, which describes the process: its state, ids, and the start of its command
+// line
+fn core_prpsinfo(pid: u64, stat: &CoreTaskStat, uid: u32, gid: u32, comm: &str, args: &str) -> Vec<u8> {
+    let mut prpsinfo = vec![0u8; 136];
+    prpsinfo[0] = "RSDTZW".find(stat.state).unwrap_or(0) as u8;
+    prpsinfo[1] = stat.state as u8;
+    prpsinfo[2] = (stat.state == 'Z') as u8;
+    prpsinfo[3] = stat.nice as u8;
+    put_u64(&mut prpsinfo, 8, stat.flags);
+    put_u32(&mut prpsinfo, 16, uid);
+    put_u32(&mut prpsinfo, 20, gid);
+    put_u32(&mut prpsinfo, 24, pid as u32);
+    put_u32(&mut prpsinfo, 28, stat.ppid);
+    put_u32(&mut prpsinfo, 32, stat.pgrp);
+    put_u32(&mut prpsinfo, 36, stat.sid);
+    // Both are NUL terminated if they fit, and cut short if they don't
+    let comm = &comm.as_bytes()[..comm.len().min(15)];
+    prpsinfo[40..40 + comm.len()].copy_from_slice(comm);
+    let args = &args.as_bytes()[..args.len().min(79)];
+    prpsinfo[56..56 + args.len()].copy_from_slice(args);
+    prpsinfo
+}
+
+// NT_FILE lists the files mapped into the process, which debuggers use to find its libraries:
+// the number of mappings and the page size, the range and offset (in pages) of each, then their
+// paths
+fn core_file_note(mappings: &[Mapping], page_size: u64) -> Vec<u8> {
+    let files = mappings.iter().filter(|m| m.path.starts_with('/')).collect::<Vec<_>>();
+    let mut note = vec![];
+    note.extend_from_slice(&(files.len() as u64).to_ne_bytes());
+    note.extend_from_slice(&page_size.to_ne_bytes());
+    for mapping in &files {
+        for value in &[mapping.start, mapping.end, mapping.offset / page_size] {
+            note.extend_from_slice(&value.to_ne_bytes());
+        }
+    }
+    for mapping in &files {
+        note.extend_from_slice(mapping.path.as_bytes());
+        note.push(0);
+    }
+    note
+}
+
+fn elf_perms(perms: &str) -> u32 {
+    let bits = perms.as_bytes();
+    let bit = |i: usize, c: u8, flag: u32| if bits.get(i) == Some(&c) { flag } else { 0 };
+    bit(0, b'r', 4) | bit(1, b'w', 2) | bit(2, b'x', 1)
+}
+
+// The headers of a core: the ELF header, the program headers (PT_NOTE first, then a PT_LOAD for
+// each of 'loads', which are (mapping, bytes to dump)), and if there are too many program headers
+// for e_phnum, a section header with the real count. Returns the headers and notes, padded to
+// where the memory of the first mapping goes.
+fn core_headers(notes: &[u8], loads: &[(&Mapping, u64)], page_size: u64) -> Vec<u8> {
+    let phnum = loads.len() + 1;
+    let shnum = if phnum >= PN_XNUM as usize { 1 } else { 0 };
+    let notes_offset = 64 + phnum * ELF_PHDR_LEN + shnum * ELF_SHDR_LEN;
+    let data_offset = (notes_offset + notes.len()) as u64;
+    let data_offset = data_offset.div_ceil(page_size) * page_size;
+
+    let mut headers = vec![0u8; data_offset as usize];
+    headers[..4].copy_from_slice(ELF_MAGIC);
+    headers[4] = ELFCLASS64;
+    headers[5] = ELFDATA_NATIVE;
+    headers[6] = 1;
+    headers[16..18].copy_from_slice(&ET_CORE.to_ne_bytes());
+    headers[18..20].copy_from_slice(&EM_NATIVE.to_ne_bytes());
+    put_u32(&mut headers, 20, 1);
+    put_u64(&mut headers, 32, 64);
+    headers[52..54].copy_from_slice(&64u16.to_ne_bytes());
+    headers[54..56].copy_from_slice(&(ELF_PHDR_LEN as u16).to_ne_bytes());
+    headers[56..58].copy_from_slice(&(phnum.min(PN_XNUM as usize) as u16).to_ne_bytes());
+    if shnum == 1 {
+        let shoff = 64 + phnum * ELF_PHDR_LEN;
+        put_u64(&mut headers, 40, shoff as u64);
+        headers[58..60].copy_from_slice(&(ELF_SHDR_LEN as u16).to_ne_bytes());
+        headers[60..62].copy_from_slice(&1u16.to_ne_bytes());
+        put_u32(&mut headers, shoff + 44, phnum as u32);
+    }
+
+    let mut phdr = |i: usize, p_type: u32, flags: u32, offset: u64, vaddr: u64, filesz: u64, memsz: u64, align: u64| {
+        let off = 64 + i * ELF_PHDR_LEN;
+        put_u32(&mut headers, off, p_type);
+        put_u32(&mut headers, off + 4, flags);
+        put_u64(&mut headers, off + 8, offset);
+        put_u64(&mut headers, off + 16, vaddr);
+        put_u64(&mut headers, off + 32, filesz);
+        put_u64(&mut headers, off + 40, memsz);
+        put_u64(&mut headers, off + 48, align);
+    };
+    phdr(0, PT_NOTE, 0, notes_offset as u64, 0, notes.len() as u64, 0, 4);
+    let mut offset = data_offset;
+    for (i, &(mapping, filesz)) in loads.iter().enumerate() {
+        phdr(i + 1, PT_LOAD, elf_perms(&mapping.perms), offset, mapping.start, filesz, mapping.end - mapping.start,
+             page_size);
+        offset += filesz;
+    }
+    headers[notes_offset..notes_offset + notes.len()].copy_from_slice(notes);
+    headers
+}
+
+// Copy 'len' bytes of the process' memory from 'addr' to 'out', a chunk at a time. Pages which
+// can't be read (e.g. beyond the end of a mapped file) are written as zeros, as the kernel does.
+// Returns how many bytes couldn't be read.
+fn copy_core_memory<W: Write>(mem: &File, addr: u64, len: u64, page_size: u64, out: &mut W,
+                              progress: &mut dyn FnMut(u64)) -> std::io::Result<u64> {
+    use std::os::unix::fs::FileExt;
+    const CHUNK: u64 = 1 << 20;
+    let mut buf = vec![0u8; CHUNK as usize];
+    let mut unreadable = 0;
+    let mut done = 0;
+    while done < len {
+        let chunk = (len - done).min(CHUNK) as usize;
+        let buf = &mut buf[..chunk];
+        if mem.read_exact_at(buf, addr + done).is_err() {
+            for (i, page) in buf.chunks_mut(page_size as usize).enumerate() {
+                if mem.read_exact_at(page, addr + done + i as u64 * page_size).is_err() {
+                    page.iter_mut().for_each(|b| *b = 0);
+                    unreadable += page.len() as u64;
+                }
+            }
+        }
+        out.write_all(buf)?;
+        done += chunk as u64;
+        progress(chunk as u64);
+    }
+    Ok(unreadable)
+}
+
+// What went into a core
+struct CoreSummary {
+    threads: usize,
+    mappings: usize,
+    bytes: u64,
+    // Bytes of mappings which were dumped but couldn't be read
+    unreadable: u64,
+}
+
+// Stop every thread of the process, and write a core of it to 'out'. Only the kinds of mapping in
+// 'filter' (a coredump_filter mask, or the process' own if None) are dumped. 'progress' is called
+// with how much memory has been copied so far, and how much there is to copy in all.
+fn write_core<W: Write>(cap: &MutateCap, pid: u64, filter: Option<u32>, mut out: W,
+                        progress: &mut dyn FnMut(u64, u64)) -> Result<(W, CoreSummary), Box<dyn Error>> {
+    // Stop every thread before reading anything, so that the memory and registers are consistent
+    // with one another. Threads started while we were stopping the others are stopped too.
+    let mut threads: Vec<StoppedThread> = vec![];
+    loop {
+        let tids = task_ids(pid)?.into_iter().filter(|tid| threads.iter().all(|t| t.tid != *tid)).collect::<Vec<_>>();
+        if tids.is_empty() {
+            break;
+        }
+        for tid in tids {
+            match StoppedThread::stop(cap, tid) {
+                Ok(thread) => threads.push(thread),
+                // It may have exited in the meantime
+                Err(_) if !Path::new(&format!("/proc/{}/task/{}", pid, tid)).exists() => {}
+                Err(e) => return Err(From::from(format!("unable to stop thread {}: {}", tid, e))),
+            }
+        }
+    }
+    // The main thread's registers come first, since debuggers start in the first thread
+    threads.sort_by_key(|thread| thread.tid != pid);
+
+    let filter = filter.unwrap_or_else(|| {
+        fs::read_to_string(format!("/proc/{}/coredump_filter", pid)).ok()
+            .and_then(|filter| u32::from_str_radix(filter.trim(), 16).ok())
+            .unwrap_or(DEFAULT_COREDUMP_FILTER)
+    });
+    let page_size = page_size();
+    let mappings = parse_smaps(BufReader::new(File::open(format!("/proc/{}/smaps", pid))?))?;
+    let mem = File::open(format!("/proc/{}/mem", pid))?;
+
+    let mut loads = vec![];
+    for mapping in &mappings {
+        let mut is_elf = || {
+            let mut magic = [0u8; 4];
+            std::os::unix::fs::FileExt::read_exact_at(&mem, &mut magic, mapping.start).is_ok() && magic == ELF_MAGIC
+        };
+        loads.push((mapping, core_dump_size(mapping, filter, page_size, &mut is_elf)));
+    }
+
+    let stat = read_core_task_stat(&format!("/proc/{}/stat", pid))?;
+    let status = ProcStat::read(pid)?;
+    let id = |field| -> Result<u32, Box<dyn Error>> {
+        Ok(status.get_field(field)?.split_whitespace().next().unwrap_or("").parse::<u32>()?)
+    };
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid))?;
+    let args = read_nul_strings(&format!("/proc/{}/cmdline", pid))?.join(" ");
+    let mut notes = vec![];
+    push_core_note(&mut notes, NT_PRPSINFO, &core_prpsinfo(pid, &stat, id("Uid")?, id("Gid")?, comm.trim_end(), args.trim_end()));
+    push_core_note(&mut notes, NT_AUXV, &fs::read(format!("/proc/{}/auxv", pid))?);
+    push_core_note(&mut notes, NT_FILE, &core_file_note(&mappings, page_size));
+    for thread in &threads {
+        let task_stat = read_core_task_stat(&format!("/proc/{}/task/{}/stat", pid, thread.tid))?;
+        let fpregs = get_regset(thread, NT_PRFPREG).ok();
+        let prstatus = core_prstatus(thread.tid, &task_stat, &get_regset(thread, NT_PRSTATUS)?, fpregs.is_some());
+        push_core_note(&mut notes, NT_PRSTATUS, &prstatus);
+        if let Some(fpregs) = fpregs {
+            push_core_note(&mut notes, NT_PRFPREG, &fpregs);
+        }
+    }
+
+    let headers = core_headers(&notes, &loads, page_size);
+    out.write_all(&headers)?;
+    let total = loads.iter().map(|&(_, size)| size).sum::<u64>();
+    let mut copied = 0;
+    let mut summary = CoreSummary { threads: threads.len(), mappings: 0, bytes: headers.len() as u64, unreadable: 0 };
+    for &(mapping, size) in loads.iter().filter(|&&(_, size)| size > 0) {
+        summary.unreadable += copy_core_memory(&mem, mapping.start, size, page_size, &mut out, &mut |len| {
+            copied += len;
+            progress(copied, total);
+        })?;
+        summary.mappings += 1;
+        summary.bytes += size;
+    }
+    out.flush()?;
+    Ok((out, summary))
+}
+
+//
+// pshm lists the shared memory a process uses: System V segments it has attached with shmat(),
+// which show up in its maps as '/SYSV<key> (deleted)' with the shmid as the inode, and POSIX shared
+// memory objects, which are files in /dev/shm it has mapped or open. The other processes using the
+// same memory are found by looking at everyone's maps and fds, and /proc/sysvipc/shm has what the
+// kernel knows about System V segments, since they outlive the processes which made them.
+//
+
+// A line of /proc/sysvipc/shm
+#[derive(Debug, PartialEq)]
+struct SysvSegment {
+    key: u32,
+    id: u64,
+    // The permissions, with SHM_DEST set once the segment was removed with IPC_RMID
+    mode: u32,
+    size: u64,
+    // The pids of the creator and of the last process to attach or detach
+    cpid: u64,
+    lpid: u64,
+    attached: u64,
+    uid: u32,
+    // When the last process detached, in seconds since the epoch
+    dtime: u64,
+}
+
+// struct ipc_perm's mode flag for segments which are freed as soon as nothing is attached
+const SHM_DEST: u32 = 0o1000;
+
+// The header line names the columns: 'key shmid perms size cpid lpid nattch uid gid cuid cgid
+// atime dtime ctime rss swap'
+fn parse_sysvipc_shm(contents: &str) -> Vec<SysvSegment> {
+    let mut lines = contents.lines();
+    let header = lines.next().unwrap_or("").split_whitespace().collect::<Vec<_>>();
+    lines.filter_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let field = |name| header.iter().position(|&h| h == name).and_then(|i| fields.get(i).cloned());
+        let num = |name| field(name)?.parse::<u64>().ok();
+        Some(SysvSegment {
+            key: field("key")?.parse::<i32>().ok()? as u32,
+            id: num("shmid")?,
+            mode: u32::from_str_radix(field("perms")?, 8).ok()?,
+            size: num("size")?,
+            cpid: num("cpid")?,
+            lpid: num("lpid")?,
+            attached: num("nattch")?,
+            uid: num("uid")? as u32,
+            dtime: num("dtime")?,
+        })
+    }).collect()
+}
+
+fn read_sysv_segments() -> Vec<SysvSegment> {
+    fs::read_to_string("/proc/sysvipc/shm").map(|contents| parse_sysvipc_shm(&contents)).unwrap_or_default()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum ShmObject {
+    // A System V segment, by shmid
+    Sysv(u64),
+    // A file in /dev/shm, by path. Paths of objects which have been unlinked end in ' (deleted)'.
+    Posix(String),
+}
+
+// Shared memory used by a process, identified by the major and minor numbers of its filesystem and
+// its inode
+#[derive(Debug, PartialEq)]
+struct ShmUse {
+    object: ShmObject,
+    file: (u64, u64, u64),
+    // How much of it is mapped, in bytes
+    mapped: u64,
+    // The fds it is open as
+    fds: Vec<u64>,
+}
+
+// Find the shared memory in a process' mappings and fds
+fn shm_uses(mappings: &[Mapping], fds: &[(u64, String, (u64, u64, u64))]) -> Vec<ShmUse> {
+    let mut uses: Vec<ShmUse> = vec![];
+    let mut add = |object: ShmObject, file, mapped, fd: Option<u64>| {
+        let index = match uses.iter().position(|u| u.file == file) {
+            Some(index) => index,
+            None => {
+                uses.push(ShmUse { object, file, mapped: 0, fds: vec![] });
+                uses.len() - 1
+            }
+        };
+        uses[index].mapped += mapped;
+        uses[index].fds.extend(fd);
+    };
+    for mapping in mappings {
+        let file = (mapping.dev.0, mapping.dev.1, mapping.inode);
+        if mapping.path.starts_with("/SYSV") {
+            add(ShmObject::Sysv(mapping.inode), file, mapping.end - mapping.start, None);
+        } else if mapping.path.starts_with("/dev/shm/") {
+            add(ShmObject::Posix(mapping.path.clone()), file, mapping.end - mapping.start, None);
+        }
+    }
+    for &(fd, ref link, file) in fds.iter().filter(|(_, link, _)| link.starts_with("/dev/shm/")) {
+        add(ShmObject::Posix(link.clone()), file, 0, Some(fd));
+    }
+    uses
+}
+
+fn read_shm_uses(pid: u64) -> Result<Vec<ShmUse>, Box<dyn Error>> {
+    use std::os::unix::fs::MetadataExt;
+    let mappings = parse_smaps(BufReader::new(File::open(format!("/proc/{}/maps", pid))?))?;
+    let mut fds = vec![];
+    for fd in read_fds(pid)? {
+        let path = format!("/proc/{}/fd/{}", pid, fd);
+        let link = match read_proc_link(&path) {
+            Ok(link) => link.to_string_lossy().into_owned(),
+            Err(_) => continue,
+        };
+        if link.starts_with("/dev/shm/") {
+            if let Ok(meta) = fs::metadata(&path) {
+                fds.push((fd, link, (major(meta.dev()), minor(meta.dev()), meta.ino())));
+            }
+        }
+    }
+    Ok(shm_uses(&mappings, &fds))
+}
+
+// Which processes use each piece of shared memory on the system
+fn shm_users() -> HashMap<(u64, u64, u64), Vec<u64>> {
+    let mut users: HashMap<_, Vec<u64>> = HashMap::new();
+    for pid in all_pids() {
+        for shm in read_shm_uses(pid).unwrap_or_default() {
+            users.entry(shm.file).or_default().push(pid);
+        }
+    }
+    users
+}
+
+fn pid_list(pids: &[u64]) -> String {
+    pids.iter().map(|pid| pid.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+fn print_shm(pid: u64, segments: &[SysvSegment], users: &HashMap<(u64, u64, u64), Vec<u64>>) -> bool {
+    let uses = match read_shm_uses(pid) {
+        Ok(uses) => uses,
+        Err(e) => {
+            eprintln!("Unable to read the shared memory of process {}: {}", pid, e);
+            return false;
+        }
+    };
+    print_proc_summary(pid);
+    if uses.is_empty() {
+        println!("  no shared memory");
+    }
+    for shm in &uses {
+        let mut details = vec![];
+        let name = match shm.object {
+            ShmObject::Sysv(id) => {
+                let segment = segments.iter().find(|s| s.id == id);
+                if let Some(segment) = segment {
+                    details.push(size_str(segment.size));
+                }
+                details.push(format!("{} mapped", size_str(shm.mapped)));
+                if let Some(segment) = segment {
+                    details.push(format!("created by {}", segment.cpid));
+                    if segment.mode & SHM_DEST != 0 {
+                        details.push("removed, so freed once nothing is attached".to_string());
+                    }
+                }
+                format!("SysV key 0x{:08x} id {}", segment.map_or(0, |s| s.key), id)
+            }
+            ShmObject::Posix(ref path) => {
+                let size = fs::metadata(format!("/proc/{}/root{}", pid, path.trim_end_matches(" (deleted)"))).ok()
+                    .filter(|_| !path.ends_with(" (deleted)"))
+                    .or_else(|| shm.fds.first().and_then(|fd| fs::metadata(format!("/proc/{}/fd/{}", pid, fd)).ok()));
+                if let Some(meta) = size {
+                    details.push(size_str(meta.len()));
+                }
+                if shm.mapped > 0 {
+                    details.push(format!("{} mapped", size_str(shm.mapped)));
+                }
+                if !shm.fds.is_empty() {
+                    let fds = shm.fds.iter().map(|fd| fd.to_string()).collect::<Vec<_>>();
+                    details.push(format!("open as fd {}", fds.join(", ")));
+                }
+                format!("POSIX {}", path)
+            }
+        };
+        let others = users.get(&shm.file).into_iter().flatten().cloned().filter(|&p| p != pid).collect::<Vec<_>>();
+        if !others.is_empty() {
+            details.push(format!("also used by {}", pid_list(&others)));
+        }
+        println!("  {}: {}", name, details.join(", "));
+    }
+    true
+}
+
+// Shared memory which nothing uses any more: System V segments with nothing attached whose
+// creator has exited, and files in /dev/shm which no process has mapped or open. Either stays
+// until it is removed, or the system reboots.
+fn print_shm_orphans(segments: &[SysvSegment], users: &HashMap<(u64, u64, u64), Vec<u64>>) {
+    use std::os::unix::fs::MetadataExt;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    for segment in segments.iter().filter(|s| s.attached == 0 && !Path::new(&format!("/proc/{}", s.cpid)).exists()) {
+        let detached = match segment.dtime {
+            0 => "never attached".to_string(),
+            dtime => format!("last detached {}", ago_str(now.saturating_sub(dtime))),
+        };
+        println!("SysV key 0x{:08x} id {}: {}, uid {}, created by {} which has exited, {}", segment.key, segment.id,
+                 size_str(segment.size), segment.uid, segment.cpid, detached);
+    }
+
+    let mut files = fs::read_dir("/dev/shm").map(|entries| entries.filter_map(|e| e.ok()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    files.sort_by_key(|entry| entry.file_name());
+    for entry in files {
+        let meta = match entry.metadata() {
+            Ok(meta) if meta.is_file() => meta,
+            _ => continue,
+        };
+        if users.contains_key(&(major(meta.dev()), minor(meta.dev()), meta.ino())) {
+            continue;
+        }
+        println!("POSIX {}: {}, uid {}, last modified {}", entry.path().display(), size_str(meta.len()), meta.uid(),
+                 ago_str(now.saturating_sub(meta.mtime().max(0) as u64)));
+    }
+}
+
+// A time as in RFC 3339, in UTC with milliseconds, e.g. '2024-03-01T17:04:05.123Z'. JSON output
+// always uses this, whatever --time-format says.
+fn utc_time_str(time: std::time::SystemTime) -> String {
+    format_time(time, TimeFormat { style: TimeStyle::Iso, utc: true }, 0)
+}
+
+// pidfd_open(2) isn't in our libc crate. It has the same number on every architecture.
+const SYS_PIDFD_OPEN: libc::c_long = 434;
+
+// A process pwait is waiting for. A pidfd becomes readable when the process exits. On kernels
+// without pidfds (before 5.3) we check whether a process with the pid and start time is still
+// running instead, so that another process given the pid later isn't mistaken for it.
+struct WaitedProcess {
+    pid: u64,
+    id: Option<ProcessId>,
+    pidfd: Option<File>,
+    // Worked out once, so that checking on the process doesn't allocate
+    stat_path: String,
+    // Its command line, for reporting once it has gone
+    summary: String,
+}
+
+impl WaitedProcess {
+    fn open(pid: u64) -> Result<Self, Box<dyn Error>> {
+        use std::os::unix::io::FromRawFd;
+        let id = ProcessId::read(pid);
+        if id.is_none() {
+            return Err(From::from(format!("No such pid {}", pid)));
+        }
+        let fd = unsafe { libc::syscall(SYS_PIDFD_OPEN, pid as libc::pid_t, 0) };
+        let pidfd = match nix::errno::Errno::result(fd) {
+            Ok(fd) => Some(unsafe { File::from_raw_fd(fd as i32) }),
+            Err(nix::Error::Sys(nix::errno::Errno::ENOSYS)) => None,
+            Err(nix::Error::Sys(nix::errno::Errno::ESRCH)) => return Err(From::from(format!("No such pid {}", pid))),
+            Err(e) => return Err(From::from(e)),
+        };
+        Ok(WaitedProcess { pid, id, pidfd, stat_path: proc_path!("{}/stat", pid),
+                           summary: cmd_summary(pid).trim_end().to_string() })
+    }
+
+    // 'stat' is a buffer kept from one check to the next
+    fn exited(&self, stat: &mut String) -> bool {
+        use std::os::unix::io::AsRawFd;
+        match self.pidfd {
+            Some(ref pidfd) => {
+                let mut fds = [libc::pollfd { fd: pidfd.as_raw_fd(), events: libc::POLLIN, revents: 0 }];
+                unsafe { libc::poll(fds.as_mut_ptr(), 1, 0) == 1 }
+            }
+            // Zombies have exited, even though they are still listed until they are waited for
+            None => {
+                stat.clear();
+                File::open(&self.stat_path).and_then(|mut file| file.read_to_string(stat)).is_err()
+                    || ProcessId::from_stat(self.pid, stat) != self.id || stat_field(stat, 3) == Some("Z")
+            }
+        }
+    }
+}
+
+// Block until one of the processes may have exited, we are interrupted, or a while has passed,
+// which is at most a second while listening to the proc connector, so that its events can be
+// cleared out. 'fds' is kept from one call to the next, so that this doesn't allocate.
+fn wait_for_exits(processes: &[WaitedProcess], fds: &mut Vec<libc::pollfd>, listening: bool) {
+    use std::os::unix::io::AsRawFd;
+    fds.clear();
+    fds.push(libc::pollfd { fd: catch_interrupts(), events: libc::POLLIN, revents: 0 });
+    fds.extend(processes.iter().filter_map(|p| p.pidfd.as_ref())
+        .map(|pidfd| libc::pollfd { fd: pidfd.as_raw_fd(), events: libc::POLLIN, revents: 0 }));
+    // Processes without a pidfd are checked on every wakeup. An EINTR is just another wakeup.
+    let timeout = match (fds.len() == processes.len() + 1, listening) {
+        (false, _) => 100,
+        (true, true) => 1000,
+        (true, false) => -1,
+    };
+    unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+}
+
+// Keep the exit statuses of the processes waited for out of the events the proc connector sent,
+// and drop the rest, which are about every process on the system
+fn take_exit_statuses(events: &Mutex<Vec<ProcEvent>>, waited: &std::collections::HashSet<u64>, statuses: &mut HashMap<u64, u32>) {
+    for event in events.lock().unwrap().drain(..) {
+        if let ProcEvent::Exit { pid, status } = event {
+            if waited.contains(&pid) {
+                statuses.insert(pid, status);
+            }
+        }
+    }
+}
+
+// The exit status of a process, from the proc connector, which may take a moment to tell us
+fn exit_status(events: &Mutex<Vec<ProcEvent>>, waited: &std::collections::HashSet<u64>, statuses: &mut HashMap<u64, u32>, pid: u64,
+               listening: bool) -> Option<u32> {
+    if !listening {
+        return None;
+    }
+    let deadline = Instant::now() + Duration::from_millis(100);
+    loop {
+        take_exit_statuses(events, waited, statuses);
+        let status = statuses.remove(&pid);
+        if status.is_some() || Instant::now() > deadline {
+            return status;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+fn exit_event_json(process: &WaitedProcess, time: std::time::SystemTime, status: Option<u32>, remaining: usize) -> Json {
+    let millis = time.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+    Json::object(vec![
+        ("event", Json::Str("exit".to_string())),
+        ("pid", Json::UInt(process.pid)),
+        ("cmdline", Json::Str(process.summary.clone())),
+        ("time", Json::Str(utc_time_str(time))),
+        ("unix_time_ms", Json::UInt(millis)),
+        ("status", status.map_or(Json::Null, |status| Json::Str(wait_status_str(status)))),
+        ("remaining", Json::UInt(remaining as u64)),
+    ])
+}
+
+fn exit_event_str(process: &WaitedProcess, time: std::time::SystemTime, status: Option<u32>) -> String {
+    let status = status.map(|status| format!(": {}", wait_status_str(status))).unwrap_or_default();
+    format!("{}: {} exited at {}{}", process.pid, process.summary, time_str(time), status)
+}
+
+// Something the tools need from the kernel or the way it is set up, for pdoctor to check
+struct DoctorCheck {
+    name: &'static str,
+    // Why the features in 'affects' won't work fully, or None if they will
+    problem: Option<String>,
+    affects: &'static str,
+}
+
+const CAP_DAC_OVERRIDE: u32 = 1;
+const CAP_DAC_READ_SEARCH: u32 = 2;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_SYS_PTRACE: u32 = 19;
+
+fn has_capability(caps: u64, cap: u32) -> bool {
+    caps & (1 << cap) != 0
+}
+
+// hidepid hides the processes of other users from everyone but members of the gid= group and those
+// allowed to ptrace them, and subset=pid hides everything but the processes.
+fn proc_mount_problem(mounts: &[MountInfo], caps: u64) -> Option<String> {
+    let proc_mount = match mounts.iter().rfind(|m| m.mount_point == "/proc") {
+        Some(m) if m.fs_type == "proc" => m,
+        _ => return Some("procfs isn't mounted at /proc".to_string()),
+    };
+    let mut problems = vec![];
+    for option in proc_mount.options.split(',') {
+        match option.split_once('=') {
+            Some(("hidepid", "0")) | Some(("hidepid", "off")) => {}
+            Some(("hidepid", value)) if !has_capability(caps, CAP_SYS_PTRACE) => {
+                let exempt = proc_mount.options.split(',').find(|o| o.starts_with("gid="))
+                    .map(|gid| format!(" (except to members of {})", gid)).unwrap_or_default();
+                problems.push(format!("/proc is mounted with hidepid={}, which hides other users' processes{}",
+                                      value, exempt));
+            }
+            Some(("subset", "pid")) => {
+                problems.push("/proc is mounted with subset=pid, which hides /proc/sys and the system-wide \
+                               files".to_string());
+            }
+            _ => {}
+        }
+    }
+    if problems.is_empty() {
+        None
+    } else {
+        Some(problems.join("; "))
+    }
+}
+
+// Yama restricts which processes can be ptraced (see Documentation/admin-guide/LSM/Yama.rst)
+fn ptrace_problem(ptrace_scope: Option<u32>, caps: u64) -> Option<String> {
+    match ptrace_scope {
+        None | Some(0) => None,
+        Some(1) | Some(2) if has_capability(caps, CAP_SYS_PTRACE) => None,
+        Some(1) => Some("kernel.yama.ptrace_scope is 1, so only descendants can be traced without \
+                         cap_sys_ptrace".to_string()),
+        Some(2) => Some("kernel.yama.ptrace_scope is 2, so nothing can be traced without cap_sys_ptrace".to_string()),
+        Some(scope) => Some(format!("kernel.yama.ptrace_scope is {}, so nothing can be traced at all", scope)),
+    }
+}
+
+// The tools show cgroup v2 paths unless told which v1 controller to look at
+fn cgroup_problem(mounts: &[MountInfo]) -> Option<String> {
+    let v2 = mounts.iter().any(|m| m.fs_type == "cgroup2");
+    let v1 = mounts.iter().any(|m| m.fs_type == "cgroup");
+    match (v1, v2) {
+        (false, true) => None,
+        (true, true) => Some("cgroups are in hybrid mode, so the v2 hierarchy has no controllers and v2 paths may \
+                              only reflect systemd's layout".to_string()),
+        (true, false) => Some("only cgroup v1 is mounted, so there are no v2 paths to show (ptree --controller \
+                               still works)".to_string()),
+        (false, false) => Some("no cgroup filesystem is mounted".to_string()),
+    }
+}
+
+fn doctor_checks() -> Vec<DoctorCheck> {
+    let caps = read_privileges(u64::from(std::process::id())).map_or(0, |privileges| privileges.effective);
+    let mounts = fs::read_to_string("/proc/self/mountinfo").map(|contents| parse_mountinfo(&contents))
+        .unwrap_or_default();
+    let ptrace_scope = fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope").ok()
+        .and_then(|scope| scope.trim().parse().ok());
+    let missing_cap = |cap: u32| if has_capability(caps, cap) {
+        None
+    } else {
+        Some(format!("we don't have {}", CAPABILITY_NAMES[cap as usize]))
+    };
+
+    let pidfd = unsafe { libc::syscall(SYS_PIDFD_OPEN, std::process::id() as libc::pid_t, 0) };
+    let pidfd_problem = match nix::errno::Errno::result(pidfd) {
+        Ok(fd) => {
+            unsafe { libc::close(fd as i32) };
+            None
+        }
+        Err(e) => Some(format!("pidfd_open() failed: {} (pidfds need Linux 5.3)", e)),
+    };
+    let sock_diag = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, NETLINK_SOCK_DIAG) };
+    let sock_diag_problem = match nix::errno::Errno::result(sock_diag) {
+        Ok(fd) => {
+            unsafe { libc::close(fd) };
+            missing_cap(CAP_NET_ADMIN).map(|problem| format!("sock_diag is available, but {}", problem))
+        }
+        Err(e) => Some(format!("unable to open a sock_diag socket: {}", e)),
+    };
+    let taskstats_problem = match TaskstatsSocket::open() {
+        Ok(_) if delay_accounting_enabled() => None,
+        Ok(_) => Some("delay accounting is off (set kernel.task_delayacct to 1)".to_string()),
+        Err(e) => Some(format!("unable to use taskstats: {}", e)),
+    };
+    let read_only_problem = if is_read_only() {
+        Some("read-only mode is on (PTOOLS_READ_ONLY is set, or --read-only was given)".to_string())
+    } else {
+        None
+    };
+
+    vec![
+        DoctorCheck {
+            name: "/proc",
+            problem: proc_mount_problem(&mounts, caps),
+            affects: "every tool, when given other users' processes or selecting processes",
+        },
+        DoctorCheck {
+            name: "ptrace",
+            problem: ptrace_problem(ptrace_scope, caps),
+            affects: "pstack, pcore, pdeadlock, psyscall, pstop --at-syscall, pcapture",
+        },
+        DoctorCheck {
+            name: "cap_sys_ptrace",
+            problem: missing_cap(CAP_SYS_PTRACE),
+            affects: "every tool, for processes of other users or with more privileges",
+        },
+        DoctorCheck {
+            name: "cap_dac_read_search",
+            problem: missing_cap(CAP_DAC_READ_SEARCH),
+            affects: "pfiles, which has to search filesystems for the inodes inotify watches",
+        },
+        DoctorCheck {
+            name: "proc connector",
+            problem: missing_cap(CAP_NET_ADMIN),
+            affects: "exit statuses in pwait -v and ptree --watch, and --follow-forks",
+        },
+        DoctorCheck {
+            name: "cgroups",
+            problem: cgroup_problem(&mounts),
+            affects: "ptree -c, pmem --group-by cgroup",
+        },
+        DoctorCheck {
+            name: "pidfd",
+            problem: pidfd_problem,
+            affects: "pwait, which has to poll processes instead",
+        },
+        DoctorCheck {
+            name: "sock_diag",
+            problem: sock_diag_problem,
+            affects: "kTLS and other ULPs of TCP sockets in pfiles",
+        },
+        DoctorCheck {
+            name: "taskstats",
+            problem: taskstats_problem,
+            affects: "pstopwatch, which only shows time running and runnable",
+        },
+        DoctorCheck {
+            name: "read-only",
+            problem: read_only_problem,
+            affects: "pstack, pcore, pdeadlock, pstop, prun, and anything else which stops or changes processes",
+        },
+    ]
+}
+
+fn doctor_check_json(check: &DoctorCheck) -> Json {
+    let mut fields = vec![
+        ("name", Json::Str(check.name.to_string())),
+        ("ok", Json::Bool(check.problem.is_none())),
+    ];
+    if let Some(ref problem) = check.problem {
+        fields.push(("problem", Json::Str(problem.clone())));
+        fields.push(("affects", Json::Str(check.affects.to_string())));
+    }
+    Json::object(fields)
+}
+
+fn print_doctor_check(check: &DoctorCheck) {
+    match check.problem {
+        None => println!("ok        {}", check.name),
+        Some(ref problem) => {
+            println!("DEGRADED  {}: {}", check.name, problem);
+            println!("          affects: {}", check.affects);
+        }
+    }
+}
+
+pub fn pargs_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("a", "", "Print command line args to process");
+        // We have a separate penv command, but keep this option for compatibility with Solaris
+        opts.optflag("e", "", "Print environement variables of process");
+        opts.optflag("", "tree", "Print command line args of the process and all of its descendants");
+        opts.optflag("", "raw", "Print the arguments as they are, even if the process seems to have rewritten \
+                                 them as one string");
+        opts.optflag("", "compare", "Show how the arguments of the second of two processes differ from those of \
+                                     the first, marking the words which changed. Exits with status 1 if they \
+                                     differ");
+        opts.optflag("", "check-names", "Only show processes whose argv[0], comm and executable disagree, and how. \
+                                         Exits with status 1 if any look like masquerading");
+        opts.optflag("", "all", "With --check-names, look at every process");
+        opts.optflag("", "common", "With -e, print the environment most of the processes share once, then only \
+                                    how each process' differs from it");
+        opts.optopt("", "max-args", "Print at most N arguments of each process, saying how many were left out",
+                    "N");
+        opts.optopt("", "max-bytes", "Print at most SIZE (e.g. 64k) of the arguments of each process, cutting \
+                                      the last one short", "SIZE");
+        opts.optflag("", "lineage", "Print the chain of processes which led to each one on a line above its \
+                                     arguments, e.g. systemd -> sshd -> bash -> make -> cc1");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        add_follow_forks_opt(&mut opts);
+        add_task_opt(&mut opts);
+        add_log_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    let log_conflicts = ["e", "tree", "raw", "compare", "check-names", "all", "common", "max-args", "max-bytes",
+                         "lineage", "follow-forks", "task"];
+    match SnapshotLog::from_matches(&matches, "pargs", &log_conflicts) {
+        Ok(Some(log)) => {
+            refuse_capture(program, "--log-to records the live processes");
+            if matches.free.is_empty() && !selection.is_active() {
+                usage_err(program, opts);
+            }
+            log.run(&mut || strings_snapshot(&selection.pids(&matches.free), "args", "cmdline"));
+        }
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    }
+
+    let all = matches.opt_present("all");
+    if (all && (!matches.free.is_empty() || !matches.opt_present("check-names")))
+        || (!all && matches.free.is_empty() && !selection.is_active() && !matches.opt_present("task")) {
+        usage_err(program, opts);
+    }
+    let task = apply_task_opt(&matches, &selection, &["tree", "compare", "follow-forks", "all"]).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+
+    let do_print_args = matches.opt_present("a");
+    let do_print_env = matches.opt_present("e");
+    let raw = matches.opt_present("raw");
+    // The tree shows it already, and the other modes aren't about single processes' arguments
+    let lineage = matches.opt_present("lineage");
+    if lineage && ["tree", "check-names", "compare"].iter().any(|&o| matches.opt_present(o)) {
+        eprintln!("--lineage can't be combined with --tree, --check-names or --compare");
+        usage_err(program, opts);
+    }
+
+    if ["max-args", "max-bytes"].iter().any(|&o| matches.opt_present(o))
+        && ["check-names", "compare"].iter().any(|&o| matches.opt_present(o)) {
+        eprintln!("--max-args and --max-bytes can't be combined with --check-names or --compare");
+        usage_err(program, opts);
+    }
+    let limits = ArgLimits {
+        max_args: match matches.opt_str("max-args").map(|n| n.parse::<usize>()) {
+            None => None,
+            Some(Ok(n)) => Some(n),
+            Some(Err(_)) => {
+                eprintln!("--max-args must be a number of arguments");
+                usage_err(program, opts);
+            }
+        },
+        max_bytes: match matches.opt_str("max-bytes").map(|size| parse_size(&size)) {
+            None => None,
+            Some(Ok(size)) => Some(size),
+            Some(Err(e)) => {
+                eprintln!("--max-bytes: {}", e);
+                usage_err(program, opts);
+            }
+        },
+    };
+
+    if matches.opt_present("check-names") {
+        refuse_capture(program, "--check-names looks for the files argv[0] names on the live system");
+        if ["e", "tree", "compare", "follow-forks"].iter().any(|&o| matches.opt_present(o)) {
+            eprintln!("--check-names can't be combined with -e, --tree, --compare or --follow-forks");
+            usage_err(program, opts);
+        }
+        let pids = task.map_or_else(|| selection.pids(&matches.free), |pid| vec![pid]);
+        let mut suspicious = false;
+        let mut scan = ScanProgress::new(pids, true);
+        while let Some(pid) = scan.next() {
+            match print_name_mismatches(pid, &mut || scan.clear()) {
+                Some(found) => suspicious |= found,
+                // Other users' processes can't be looked at without privileges, which a sweep of
+                // all processes shouldn't complain about
+                None if all || selection.is_active() => {}
+                None => {
+                    scan.clear();
+                    eprintln!("Unable to read the names of process {}", pid);
+                }
+            }
+        }
+        drop(scan);
+        exit(suspicious as i32);
+    }
+
+    if matches.opt_present("compare") {
+        let pids = matches.free.iter().map(|arg| arg.parse::<u64>()).collect::<Result<Vec<_>, _>>();
+        let pids = match pids {
+            Ok(ref pids) if pids.len() == 2 => (pids[0], pids[1]),
+            _ => {
+                eprintln!("--compare takes two pids");
+                usage_err(program, opts);
+            }
+        };
+        if do_print_env || matches.opt_present("tree") || matches.opt_present("follow-forks") {
+            eprintln!("--compare can't be combined with -e, --tree or --follow-forks");
+            usage_err(program, opts);
+        }
+        // Like diff(1), 1 means they differ and 2 that they couldn't be compared
+        match print_argv_compare(pids, raw) {
+            Ok(differ) => exit(differ as i32),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(2);
+            }
+        }
+    }
+
+    let targets = if let Some(pid) = task {
+        vec![pid.to_string()]
+    } else if matches.free.is_empty() {
+        selection.pids(&[]).iter().map(|pid| pid.to_string()).collect()
+    } else {
+        matches.free.clone()
+    };
+
+    if matches.opt_present("common") {
+        if !do_print_env || ["a", "tree", "follow-forks"].iter().any(|&o| matches.opt_present(o)) {
+            eprintln!("--common only works with -e, and can't be combined with -a, --tree or --follow-forks");
+            usage_err(program, opts);
+        }
+        let pids = targets.iter().filter_map(|arg| arg.parse::<u64>().ok()).filter(|&pid| selection.matches(pid))
+            .collect::<Vec<_>>();
+        if pids.len() < targets.len() {
+            eprintln!("--common can't be used with core files");
+            usage_err(program, opts);
+        }
+        if !print_common_env(&pids) {
+            exit(1);
+        }
+        return;
+    }
+
+    if matches.opt_present("follow-forks") {
+        refuse_capture(program, "--follow-forks follows the live processes");
+    }
+
+    let tree = if matches.opt_present("tree") {
+        if do_print_env {
+            eprintln!("--tree can't be combined with -e");
+            usage_err(program, opts);
+        }
+        match read_process_tree() {
+            Ok(tree) => Some(tree),
+            Err(e) => {
+                eprintln!("Unable to read processes: {}", e);
+                exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut error = false;
+    for arg in &targets {
+        // Anything which isn't a pid is the path of a core file. The selection options only apply
+        // to live processes.
+        let pid = match arg.parse::<u64>() {
+            Ok(pid) if selection.matches(pid) => pid,
+            Ok(_) => continue,
+            Err(_) if tree.is_some() => {
+                eprintln!("{}: --tree can't be used with core files", arg);
+                error = true;
+                continue;
+            }
+            Err(_) => {
+                if do_print_env {
+                    eprintln!("{}: the environment of a core file can't be printed", arg);
+                    error = true;
+                }
+                if do_print_args || !do_print_env {
+                    error = !print_core_args(arg) || error;
+                }
+                continue;
+            }
+        };
+
+        if let Some(ref tree) = tree {
+            if !tree.parent_map.contains_key(&pid) {
+                eprintln!("No such pid {}", pid);
+                error = true;
+                continue;
+            }
+            print_args_tree(tree, pid, raw, limits);
+            continue;
+        }
+
+        if do_print_args || !do_print_env {
+            print_args(pid, raw, limits, lineage);
+        }
+
+        if do_print_env {
+            // Solaris pargs lists the environment as is
+            print_env(pid, &PenvOptions { original_order: true, max_value_len: None, lint: false, unit_check: false });
+        }
+    }
+
+    if matches.opt_present("follow-forks") {
+        let pids = targets.iter().filter_map(|arg| arg.parse::<u64>().ok()).collect::<Vec<_>>();
+        follow_forks(&pids, &selection, &mut |pid| {
+            if do_print_args || !do_print_env {
+                print_args(pid, raw, limits, lineage);
+            }
+            if do_print_env {
+                print_env(pid, &PenvOptions { original_order: true, max_value_len: None, lint: false, unit_check: false });
+            }
+        });
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+pub fn penv_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        add_follow_forks_opt(&mut opts);
+        add_task_opt(&mut opts);
+        add_log_opts(&mut opts);
+        opts.optflag("", "original-order", "print variables in the order they are in the environment, \
+                                             instead of sorted by name");
+        opts.optopt("", "max-length", &format!("warn about values longer than LEN bytes (default {}, 0 to \
+                                                 disable)", DEFAULT_MAX_ENV_VALUE_LEN), "LEN");
+        opts.optflag("", "lint", "only print problems with the environment, also checking for hazards like \
+                                  LD_PRELOAD, missing PATH directories or TZ not being set, and exit with \
+                                  status 1 if there were any");
+        opts.optflag("", "common", "print the environment most of the processes share once, then only how each \
+                                    process' differs from it");
+        opts.optflag("", "unit-check", "warn about variables which don't match the Environment= and \
+                                        EnvironmentFile= settings of the process' systemd unit");
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    if matches.free.is_empty() && !selection.is_active() && !matches.opt_present("task") {
+        usage_err(program, opts);
+    }
+    match SnapshotLog::from_matches(&matches, "penv", &["original-order", "max-length", "lint", "common",
+                                                         "unit-check", "follow-forks", "task"]) {
+        Ok(Some(log)) => {
+            refuse_capture(program, "--log-to records the live processes");
+            log.run(&mut || strings_snapshot(&selection.pids(&matches.free), "env", "environ"));
+        }
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    }
+    let task = apply_task_opt(&matches, &selection, &["follow-forks"]).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+
+    let max_value_len = match matches.opt_str("max-length").map(|len| len.parse::<usize>()) {
+        None => Some(DEFAULT_MAX_ENV_VALUE_LEN),
+        Some(Ok(0)) => None,
+        Some(Ok(len)) => Some(len),
+        Some(Err(_)) => {
+            eprintln!("--max-length must be a number of bytes");
+            usage_err(program, opts);
+        }
+    };
+    let penv_opts = PenvOptions {
+        original_order: matches.opt_present("original-order"),
+        max_value_len,
+        lint: matches.opt_present("lint"),
+        unit_check: matches.opt_present("unit-check"),
+    };
+
+    if matches.opt_present("follow-forks") {
+        refuse_capture(program, "--follow-forks follows the live processes");
+    }
+
+    let pids = task.map_or_else(|| selection.pids(&matches.free), |pid| vec![pid]);
+    if matches.opt_present("common") {
+        if ["lint", "original-order", "unit-check", "follow-forks"].iter().any(|&o| matches.opt_present(o)) {
+            eprintln!("--common can't be used with --lint, --original-order, --unit-check or --follow-forks");
+            usage_err(program, opts);
+        }
+        if !print_common_env(&pids) {
+            exit(1);
+        }
+        return;
+    }
+
+    let mut found = false;
+    for &pid in &pids {
+        found |= print_env(pid, &penv_opts);
+    }
+    if matches.opt_present("follow-forks") {
+        follow_forks(&pids, &selection, &mut |pid| {
+            print_env(pid, &penv_opts);
+        });
+    }
+    if penv_opts.lint && found {
+        exit(1);
+    }
+}
+
+pub fn pfiles_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        add_follow_forks_opt(&mut opts);
+        add_task_opt(&mut opts);
+        add_pager_opts(&mut opts);
+        opts.optmulti("", "fd", "only print the given fd, or range of fds (e.g. 5, 100-200)", "FD");
+        opts.optflag("", "json", "print output as JSON, one object per process");
+        opts.optopt("", "format", "print output as FORMAT: pfiles (the default), lsof for the columns of \
+                                   lsof -p, or csv or tsv for a row per fd, under a header naming the columns",
+                    "FORMAT");
+        opts.optopt("", "epoll-sample", "sample the files watched by epoll fds twice, MS milliseconds apart, \
+                                         to see which keep an event loop busy", "MS");
+        opts.optflag("", "overlay", "show which overlayfs layer backs each file, and whether it was copied up");
+        opts.optflag("", "quota", "show the project quota of files on XFS, ext4 and f2fs which belong to a \
+                                   project. This opens them again, which file watchers see.");
+        opts.optopt("", "limit", "only print the first N fds of each process, followed by how many fds of each \
+                                  type it has in all", "N");
+        opts.optflag("c", "counts", "only print how many fds of each type each process has, and how many it may \
+                                     have open, for health checks");
+        opts.optflag("", "age", "show roughly how long each fd has been open. Running pfiles again later \
+                                 narrows this down for the fds it has seen before.");
+        opts.optflag("", "acl", "show the POSIX ACLs of regular files and directories, and what the process' \
+                                 credentials allow it to do to them");
+        opts.optflag("", "peers", "guess the protocol of each connection from its ports, and show which process \
+                                   is at the other end of connections on this system");
+        opts.optflag("", "enter-ns", "find out which network namespace sockets which aren't in the process' own \
+                                      are in, and show them from there (needs permission to ptrace the process, \
+                                      and to join namespaces no process is in)");
+        opts.optflag("", "join-ns", "look up the paths of files and UNIX sockets from inside the process' mount \
+                                     namespace and root, pointing out those which name something else or nothing \
+                                     for the process (needs CAP_SYS_ADMIN, and is quietly skipped without it)");
+        opts.optflag("", "hash", "show the SHA-256 of the contents of small regular files, e.g. to tell which \
+                                  version of a config file a daemon loaded, and whether the file now at its path \
+                                  differs");
+        opts.optopt("", "hash-max", "with --hash, hash files of up to SIZE (default 1M)", "SIZE");
+        opts.optflag("", "children", "also print the files of all descendants of the processes");
+        opts.optopt("", "mount-timeout", "leave out the fds on filesystems (e.g. NFS mounts whose server is down) \
+                                          which don't answer within SECS seconds (default 2, 0 to not check)",
+                    "SECS");
+        opts.optopt("", "full-threshold", "warn about files open for writing on filesystems which are at least \
+                                           PERCENT full (default 95)", "PERCENT");
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    if matches.free.is_empty() && !selection.is_active() && !matches.opt_present("task") {
+        usage_err(program, opts);
+    }
+    let task = apply_task_opt(&matches, &selection, &["children", "follow-forks"]).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+
+    let mut fd_ranges = vec![];
+    for spec in matches.opt_strs("fd") {
+        // Allow a comma separated list as well as multiple --fd options
+        for spec in spec.split(',') {
+            match parse_fd_range(spec) {
+                Ok(range) => fd_ranges.push(range),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    usage_err(program, opts);
+                }
+            }
+        }
+    }
+
+    let epoll_sample = match matches.opt_str("epoll-sample").map(|ms| ms.parse::<u64>()) {
+        None => None,
+        Some(Ok(ms)) => Some(Duration::from_millis(ms)),
+        Some(Err(_)) => {
+            eprintln!("--epoll-sample must be a number of milliseconds");
+            usage_err(program, opts);
+        }
+    };
+
+    if epoll_sample.is_some() && is_capture() {
+        eprintln!("--epoll-sample can't be used with --from-capture");
+        usage_err(program, opts);
+    }
+
+    // The layers are directories on the live system
+    if matches.opt_present("overlay") && is_capture() {
+        eprintln!("--overlay can't be used with --from-capture");
+        usage_err(program, opts);
+    }
+
+    // The times come from the live system's procfs
+    if matches.opt_present("age") && is_capture() {
+        eprintln!("--age can't be used with --from-capture");
+        usage_err(program, opts);
+    }
+
+    // ACLs are read from the live files
+    if matches.opt_present("acl") && is_capture() {
+        eprintln!("--acl can't be used with --from-capture");
+        usage_err(program, opts);
+    }
+
+    // The sockets are copied from the live process
+    if matches.opt_present("enter-ns") && is_capture() {
+        eprintln!("--enter-ns can't be used with --from-capture");
+        usage_err(program, opts);
+    }
+    if matches.opt_present("join-ns") && is_capture() {
+        eprintln!("--join-ns can't be used with --from-capture");
+        usage_err(program, opts);
+    }
+
+    // Captures don't have the files' contents
+    if matches.opt_present("hash") && is_capture() {
+        eprintln!("--hash can't be used with --from-capture");
+        usage_err(program, opts);
+    }
+    let hash = match matches.opt_str("hash-max").map(|size| parse_size(&size)) {
+        _ if !matches.opt_present("hash") => {
+            if matches.opt_present("hash-max") {
+                eprintln!("--hash-max only works with --hash");
+                usage_err(program, opts);
+            }
+            None
+        }
+        None => Some(1024 * 1024),
+        Some(Ok(size)) => Some(size),
+        Some(Err(e)) => {
+            eprintln!("--hash-max: {}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    let counts = matches.opt_present("counts");
+    if counts && ["epoll-sample", "overlay", "age", "acl", "hash", "join-ns"].iter().any(|&o| matches.opt_present(o)) {
+        eprintln!("-c only counts fds, so it can't be used with --epoll-sample, --overlay, --age, --acl, --hash or \
+                   --join-ns");
+        usage_err(program, opts);
+    }
+
+    let limit = match matches.opt_str("limit").map(|n| n.parse::<usize>()) {
+        None => None,
+        Some(Ok(n)) => Some(n),
+        Some(Err(_)) => {
+            eprintln!("--limit must be a number of fds");
+            usage_err(program, opts);
+        }
+    };
+
+    let full_threshold = match matches.opt_str("full-threshold").map(|percent| percent.parse::<u64>()) {
+        None => 95,
+        Some(Ok(percent)) if percent <= 100 => percent,
+        Some(_) => {
+            eprintln!("--full-threshold must be a percentage");
+            usage_err(program, opts);
+        }
+    };
+
+    let mount_timeout = match matches.opt_str("mount-timeout").map(|secs| secs.parse::<f64>()) {
+        None => Some(Duration::from_secs(2)),
+        Some(Ok(0.0)) => None,
+        Some(Ok(secs)) if secs > 0.0 && secs.is_finite() => Some(Duration::from_millis((secs * 1000.0) as u64)),
+        Some(_) => {
+            eprintln!("--mount-timeout must be a number of seconds");
+            usage_err(program, opts);
+        }
+    };
+
+    let format = match matches.opt_str("format").as_deref() {
+        None | Some("pfiles") => PfilesFormat::Pfiles,
+        Some("lsof") => PfilesFormat::Lsof,
+        Some("csv") => PfilesFormat::Csv,
+        Some("tsv") => PfilesFormat::Tsv,
+        Some(format) => {
+            eprintln!("unknown format '{}', expected pfiles, lsof, csv or tsv", format);
+            usage_err(program, opts);
+        }
+    };
+    // Only the columns each format has
+    let format_conflicts = ["json", "counts", "limit", "epoll-sample", "overlay", "quota", "age", "acl", "peers",
+                            "enter-ns", "join-ns", "hash"];
+    if format != PfilesFormat::Pfiles && format_conflicts.iter().any(|&o| matches.opt_present(o)) {
+        eprintln!("--format {} can't be used with --json, -c, --limit, --epoll-sample, --overlay, --quota, --age, \
+                   --acl, --peers, --enter-ns, --join-ns or --hash", matches.opt_str("format").unwrap_or_default());
+        usage_err(program, opts);
+    }
+
+    let pfiles_opts = PfilesOptions {
+        fd_ranges,
+        json: matches.opt_present("json"),
+        epoll_sample,
+        overlay: matches.opt_present("overlay"),
+        quota: matches.opt_present("quota"),
+        age: matches.opt_present("age"),
+        acl: matches.opt_present("acl"),
+        peers: matches.opt_present("peers"),
+        enter_ns: matches.opt_present("enter-ns"),
+        join_ns: matches.opt_present("join-ns"),
+        hash,
+        counts,
+        limit,
+        mount_timeout,
+        full_threshold,
+    };
+
+    // New processes are printed as they come, rather than for reading in a pager
+    let follow = matches.opt_present("follow-forks");
+    if follow {
+        refuse_capture(program, "--follow-forks follows the live processes");
+    } else {
+        start_pager(&matches);
+    }
+
+    let mut error = false;
+    let mut pids = task.map_or_else(|| selection.pids(&matches.free), |pid| vec![pid]);
+    if matches.opt_present("children") {
+        pids = match with_descendants(&pids) {
+            Ok(pids) => pids,
+            Err(e) => {
+                eprintln!("Unable to read processes: {}", e);
+                exit(1);
+            }
+        };
+    }
+    let print_rows = |pid| match csv_rows(pid, &pfiles_opts) {
+        Some(rows) => {
+            for row in rows {
+                println!("{}", delimited_line(&row, format));
+            }
+            true
+        }
+        None => false,
+    };
+    let socket_owners = SocketOwners::default();
+    match format {
+        PfilesFormat::Pfiles => for &pid in &pids {
+            error = error || !print_files(pid, &pfiles_opts, &socket_owners);
+        },
+        PfilesFormat::Lsof => {
+            // One table for all the processes, as lsof -p prints for a list of pids
+            let mut rows = vec![];
+            for &pid in &pids {
+                match lsof_rows(pid, &pfiles_opts) {
+                    Some(pid_rows) => rows.extend(pid_rows),
+                    None => error = true,
+                }
+            }
+            print!("{}", lsof_table(&rows, true));
+        }
+        PfilesFormat::Csv | PfilesFormat::Tsv => {
+            println!("{}", delimited_line(&CSV_HEADER.map(String::from), format));
+            for &pid in &pids {
+                error = !print_rows(pid) || error;
+            }
+        }
+    }
+    if follow {
+        follow_forks(&pids, &selection, &mut |pid| match format {
+            PfilesFormat::Pfiles => {
+                print_files(pid, &pfiles_opts, &socket_owners);
+            }
+            PfilesFormat::Lsof => print!("{}", lsof_table(&lsof_rows(pid, &pfiles_opts).unwrap_or_default(), false)),
+            PfilesFormat::Csv | PfilesFormat::Tsv => {
+                print_rows(pid);
+            }
+        });
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+pub fn ptree_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("c", "", "Show the cgroup v2 path of each process");
+        opts.optopt("", "controller", "Show the cgroup in the v1 hierarchy of the named controller \
+                                         instead (implies -c)", "NAME");
+        opts.optflag("", "by-cgroup", "Show the cgroup hierarchy (v2, or that of --controller) instead, with each \
+                                       process under its cgroup, like systemd-cgls");
+        opts.optopt("", "watch", "Reprint the tree every SECS seconds, showing how processes which \
+                                    exited in the meantime exited", "SECS");
+        opts.optopt("", "follow", "Keep reprinting the tree containing PID (every second, or as often as \
+                                     --watch says), following it when it execs, is re-parented or daemonizes",
+                    "PID");
+        opts.optflag("", "privileges", "Mark processes running setuid or setgid executables, or with \
+                                        effective or ambient capabilities which their parent doesn't have");
+        opts.optflag("", "stdin", "Show what each process's stdin is: a tty, /dev/null, a pipe, or a socket \
+                                   (as for inetd or socket-activated services)");
+        opts.optopt("", "ns", "Interpret the pids given as seen from the pid namespace of process CONTEXT, \
+                               e.g. a container's init", "CONTEXT");
+        opts.optmulti("", "env", "Show the value of environment variable NAME for each process which has it set \
+                                  (may be given more than once)", "NAME");
+        opts.optflag("", "summary", "After each tree, print how many processes, threads and users are in it, its \
+                                     deepest chain of processes and its largest subtree");
+        opts.optflag("p", "pids", "Only print the pids of the processes and their descendants (not their \
+                                   ancestors), one per line, parents first, e.g. for xargs kill");
+        opts.optflag("", "reverse", "With -p, print children before their parents");
+        opts.optflag("", "count-only", "Instead of the trees, print how many processes, threads and zombies are \
+                                        in the processes' subtrees, how many processes each user and service has, \
+                                        and which process has the most children, e.g. for monitoring checks");
+        opts.optflag("", "json", "With --count-only, print the counts as a JSON object");
+        opts.optopt("", "fields", "Show these columns after each process, like ps -o: a comma separated list of \
+                                   user, rss, cpu, start, cgroup and nlwp", "FIELDS");
+        opts.optopt("", "label", "Show each process as TEMPLATE instead of its pid and command, e.g. \
+                                  '{pid} {user} {comm}', with {ppid}, {cmd} (the command line) and the --fields \
+                                  names too, and {{ and }} for braces", "TEMPLATE");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        add_pager_opts(&mut opts);
+        add_log_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    let mut fields = vec![];
+    for name in matches.opt_str("fields").iter().flat_map(|fields| fields.split(',')) {
+        match PtreeField::parse(name.trim()) {
+            Some(field) => fields.push(field),
+            None => {
+                eprintln!("Unknown field '{}', expected user, rss, cpu, start, cgroup or nlwp", name);
+                usage_err(program, opts);
+            }
+        }
+    }
+
+    let label = match matches.opt_str("label").map(|template| PtreeLabel::parse(&template)) {
+        None => None,
+        Some(Ok(label)) => Some(label),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+    // The label replaces what these add to each process, and can show the same things
+    if label.is_some() && ["c", "controller", "privileges", "env", "stdin"].iter().any(|&o| matches.opt_present(o)) {
+        eprintln!("--label can't be used with -c, --controller, --privileges, --env or --stdin");
+        usage_err(program, opts);
+    }
+
+    let mut ptree_opts = PtreeOptions {
+        cgroup: match matches.opt_str("controller") {
+            Some(name) => Some(CgroupHierarchy::Controller(name)),
+            None if matches.opt_present("c") => Some(CgroupHierarchy::Unified),
+            None => None,
+        },
+        privileges: matches.opt_present("privileges"),
+        summary: matches.opt_present("summary"),
+        env: matches.opt_strs("env"),
+        by_cgroup: matches.opt_present("by-cgroup"),
+        stdin: matches.opt_present("stdin"),
+        fields,
+        lines: std::cell::RefCell::new(vec![]),
+        exited: HashMap::new(),
+        label,
+    };
+    if ptree_opts.by_cgroup && ["watch", "follow", "summary", "fields"].iter().any(|&o| matches.opt_present(o)) {
+        eprintln!("--by-cgroup can't be used with --watch, --follow, --summary or --fields");
+        usage_err(program, opts);
+    }
+    let pids_only = matches.opt_present("p");
+    let tree_opts = ["watch", "follow", "by-cgroup", "summary", "c", "controller", "privileges", "env", "stdin",
+                     "fields", "label"];
+    if pids_only && tree_opts.iter().any(|&o| matches.opt_present(o)) {
+        eprintln!("-p only prints pids, so it can't be used with --watch, --follow, --by-cgroup, --summary, -c, \
+                   --controller, --privileges, --env, --stdin, --fields or --label");
+        usage_err(program, opts);
+    }
+    if matches.opt_present("reverse") && !pids_only {
+        eprintln!("--reverse only works with -p");
+        usage_err(program, opts);
+    }
+    let count_only = matches.opt_present("count-only");
+    if count_only && (pids_only || tree_opts.iter().any(|&o| matches.opt_present(o))) {
+        eprintln!("--count-only only prints counts, so it can't be used with -p, --watch, --follow, --by-cgroup, \
+                   --summary, -c, --controller, --privileges, --env, --stdin, --fields or --label");
+        usage_err(program, opts);
+    }
+    if matches.opt_present("json") && !count_only {
+        eprintln!("--json only works with --count-only");
+        usage_err(program, opts);
+    }
+
+    let interval = match matches.opt_str("watch").map(|secs| secs.parse::<f64>()) {
+        None => None,
+        Some(Ok(secs)) if secs > 0.0 && secs.is_finite() => Some(Duration::from_millis((secs * 1000.0) as u64)),
+        Some(_) => {
+            eprintln!("--watch must be a positive number of seconds");
+            usage_err(program, opts);
+        }
+    };
+
+    // Pids from inside a container are translated to ours before anything else sees them
+    let mut free = matches.free.clone();
+    let mut follow = matches.opt_str("follow");
+    if let Some(context) = matches.opt_str("ns") {
+        refuse_capture(program, "--ns asks the kernel how pid namespaces are nested");
+        let context = match context.parse::<u64>() {
+            Ok(context) => context,
+            Err(_) => {
+                eprintln!("--ns must be given a pid");
+                usage_err(program, opts);
+            }
+        };
+        for pid in free.iter_mut().chain(follow.iter_mut()) {
+            let translated = match pid.parse::<u64>() {
+                Ok(inner) => translate_ns_pid(context, inner),
+                Err(_) => continue,
+            };
+            match translated {
+                Ok(translated) => *pid = translated.to_string(),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+    }
+
+    let log_conflicts = ["c", "controller", "by-cgroup", "watch", "follow", "privileges", "stdin", "env", "summary",
+                         "p", "reverse", "count-only", "json", "fields", "label"];
+    match SnapshotLog::from_matches(&matches, "ptree", &log_conflicts) {
+        // Like the trees, the subtrees of the processes, or every process
+        Ok(Some(log)) => {
+            refuse_capture(program, "--log-to records the live processes");
+            log.run(&mut || {
+                let tree = match read_process_tree() {
+                    Ok(tree) => tree,
+                    Err(e) => {
+                        eprintln!("Unable to read processes: {}", e);
+                        return Json::Array(vec![]);
+                    }
+                };
+                let mut pids = if free.is_empty() && !selection.is_active() {
+                    tree.parent_map.keys().cloned().collect()
+                } else {
+                    selection.pids(&free).into_iter().flat_map(|pid| subtree_pids(&tree, pid)).collect::<Vec<_>>()
+                };
+                pids.sort();
+                pids.dedup();
+                tree_json(&tree, pids)
+            });
+        }
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    }
+
+    if let Some(pid) = follow {
+        refuse_capture(program, "--follow follows the live process tree");
+        let pid = match pid.parse::<u64>() {
+            Ok(pid) if free.is_empty() && !selection.is_active() => pid,
+            Ok(_) => {
+                eprintln!("--follow takes the only process to show");
+                usage_err(program, opts);
+            }
+            Err(_) => {
+                eprintln!("--follow must be given a pid");
+                usage_err(program, opts);
+            }
+        };
+        if ProcessId::read(pid).is_none() {
+            eprintln!("No such pid {}", pid);
+            exit(1);
+        }
+        follow_tree(pid, interval.unwrap_or(Duration::from_secs(1)), &ptree_opts);
+    }
+
+    if let Some(interval) = interval {
+        refuse_capture(program, "--watch follows the live process tree");
+        let pids = if free.is_empty() && !selection.is_active() {
+            vec![1]
+        } else {
+            selection.pids(&free)
+        };
+        watch_tree(&pids, interval, &mut ptree_opts);
+    }
+
+    // Only the processes' subtrees, since the ancestors a tree shows aren't what anyone piping the
+    // pids into kill means
+    if pids_only {
+        let roots = if free.is_empty() && !selection.is_active() { vec![1] } else { selection.pids(&free) };
+        let mut pids = match with_descendants(&roots) {
+            Ok(pids) => pids,
+            Err(e) => {
+                eprintln!("Unable to read processes: {}", e);
+                exit(1);
+            }
+        };
+        // We are in the tree of the shell we were run from, but won't be around for long
+        let me = u64::from(std::process::id());
+        pids.retain(|&pid| pid != me);
+        if matches.opt_present("reverse") {
+            pids.reverse();
+        }
+        for pid in pids {
+            println!("{}", pid);
+        }
+        return;
+    }
+
+    // Like -p, the processes' subtrees
+    if count_only {
+        let roots = if free.is_empty() && !selection.is_active() { vec![1] } else { selection.pids(&free) };
+        let tree = match read_process_tree() {
+            Ok(tree) => tree,
+            Err(e) => {
+                eprintln!("Unable to read processes: {}", e);
+                exit(1);
+            }
+        };
+        let mut seen = std::collections::HashSet::new();
+        let mut processes = vec![];
+        for root in roots {
+            walk_tree(&tree, root, &mut |pid, _| {
+                if seen.insert(pid) {
+                    processes.extend(CountedProcess::read(&tree, pid));
+                }
+                true
+            });
+        }
+        print_tree_counts(&tree_counts(&processes), matches.opt_present("json"));
+        return;
+    }
+
+    start_pager(&matches);
+    if free.is_empty() && !selection.is_active() {
+        // Should we print all processes here, including kernel threads? Is there any way this
+        // could miss userspace processes?
+        if ptree_opts.by_cgroup {
+            print_cgroup_tree(&[1], &ptree_opts).unwrap();
+        } else {
+            print_tree(1, &ptree_opts).unwrap();
+        }
+    } else {
+        let mut pids = selection.pids(&free);
+        if free.is_empty() && (is_capture() || selection.by_container()) {
+            // Print each captured (or containerized) tree once, rather than once for every process in it
+            let captured = pids.clone();
+            pids.retain(|&pid| ProcStat::read(pid).and_then(|stat| stat.ppid())
+                        .map_or(true, |ppid| !captured.contains(&ppid)));
+        }
+        // All the processes go in one hierarchy of cgroups
+        if ptree_opts.by_cgroup {
+            print_cgroup_tree(&pids, &ptree_opts).unwrap();
+            return;
+        }
+        // This loop parses /proc/<pid>/status for each process in the system for each
+        // argument provided. Should rearrange it so it's only parsed once.
+        for pid in pids {
+            print_tree(pid, &ptree_opts).unwrap();
+        }
+    }
+}
+
+pub fn psyscall_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    if matches.free.is_empty() && !selection.is_active() {
+        usage_err(program, opts);
+    }
+
+    let mut error = false;
+    for pid in ScanProgress::new(selection.pids(&matches.free), false) {
+        error = error || !print_syscalls(pid);
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+pub fn pmap_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        add_pager_opts(&mut opts);
+        opts.optflag("x", "", "print resident, anonymous and locked memory for each mapping");
+        opts.optflag("", "hugepages", "print transparent and hugetlbfs huge page usage");
+        opts.optmulti("", "filter", "only print mappings of KIND: anon, file, stack or heap (may be given more than \
+                                     once)", "KIND");
+        opts.optopt("", "min-rss", "only print mappings with at least SIZE resident (e.g. 10M)", "SIZE");
+        opts.optopt("", "sort", "print mappings by address (the default), or biggest first by size, rss or anon",
+                    "FIELD");
+        opts.optflag("", "stacks", "print how much of each thread's stack is used and left, and the guard below it");
+        opts.optopt("", "visual", "draw the address space in proportion, as ascii, or as a standalone html page",
+                    "FORMAT");
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    if matches.free.is_empty() && !selection.is_active() {
+        usage_err(program, opts);
+    }
+
+    let mut kinds = vec![];
+    for kind in matches.opt_strs("filter") {
+        match MappingKind::parse(&kind) {
+            Some(kind) => kinds.push(kind),
+            None => {
+                eprintln!("Unknown kind of mapping '{}'", kind);
+                usage_err(program, opts);
+            }
+        }
+    }
+    let min_rss = match matches.opt_str("min-rss").map(|size| parse_size(&size)) {
+        None => 0,
+        Some(Ok(bytes)) => bytes.div_ceil(1024),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+    let sort = match matches.opt_str("sort").map(|sort| MappingSort::parse(&sort).ok_or(sort)) {
+        None => MappingSort::Address,
+        Some(Ok(sort)) => sort,
+        Some(Err(sort)) => {
+            eprintln!("Unknown sort order '{}'", sort);
+            usage_err(program, opts);
+        }
+    };
+
+    let visual = match matches.opt_str("visual").as_deref() {
+        None => None,
+        Some("ascii") => Some(false),
+        Some("html") => Some(true),
+        Some(format) => {
+            eprintln!("unknown format '{}', expected ascii or html", format);
+            usage_err(program, opts);
+        }
+    };
+    if let Some(html) = visual {
+        // The whole address space is drawn
+        if ["x", "hugepages", "filter", "min-rss", "sort", "stacks"].iter().any(|&o| matches.opt_present(o)) {
+            eprintln!("--visual can't be used with -x, --hugepages, --filter, --min-rss, --sort or --stacks");
+            usage_err(program, opts);
+        }
+        // A page to be saved, not read in a pager
+        if html {
+            println!("{}", VISUAL_HTML_HEAD);
+            let legend = VISUAL_REGIONS.iter()
+                .map(|region| format!("<span style=\"background:{}\"></span>{}", region.style().2, region.style().1))
+                .collect::<String>();
+            println!("<div class=\"legend\">{}</div>", legend);
+        } else {
+            start_pager(&matches);
+        }
+        let mut error = false;
+        for pid in ScanProgress::new(selection.pids(&matches.free), false) {
+            error = !print_visual_map(pid, html) || error;
+        }
+        if html {
+            println!("</body></html>");
+        }
+        if error {
+            exit(1);
+        }
+        return;
+    }
+
+    let pmap_opts = PmapOptions {
+        extended: matches.opt_present("x"),
+        hugepages: matches.opt_present("hugepages"),
+        kinds,
+        min_rss,
+        sort,
+        stacks: matches.opt_present("stacks"),
+    };
+
+    start_pager(&matches);
+
+    let mut error = false;
+    for pid in ScanProgress::new(selection.pids(&matches.free), false) {
+        error = error || !print_map(pid, &pmap_opts);
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+pub fn pdeadlock_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    if matches.free.is_empty() && !selection.is_active() {
+        usage_err(program, opts);
+    }
+
+    refuse_capture(program, "finding lock owners means reading the process' memory");
+
+    let mut error = false;
+    for pid in ScanProgress::new(selection.pids(&matches.free), false) {
+        error = error || !print_deadlocks(pid);
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+pub fn ptoolsd_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optopt("", "socket", "Path of the socket to listen on (default /run/ptoolsd.sock)", "PATH");
+        opts.optopt("", "cache-ttl", "How long to reuse results, in milliseconds (default 1000)", "MS");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    refuse_capture(program, "it serves the live state of processes");
+
+    if !matches.free.is_empty() {
+        usage_err(program, opts);
+    }
+
+    let socket_path = matches.opt_str("socket").unwrap_or_else(|| "/run/ptoolsd.sock".to_string());
+    let ttl = match matches.opt_str("cache-ttl").map(|ms| ms.parse::<u64>()) {
+        None => Duration::from_millis(1000),
+        Some(Ok(ms)) => Duration::from_millis(ms),
+        Some(Err(_)) => {
+            eprintln!("--cache-ttl must be a number of milliseconds");
+            usage_err(program, opts);
+        }
+    };
+
+    let listener = match bind_socket(&socket_path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Unable to listen on {}: {}", socket_path, e);
+            exit(1);
+        }
+    };
+
+    let cache = Arc::new(Mutex::new(SnapshotCache { ttl, entries: HashMap::new() }));
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Unable to accept connection: {}", e);
+                continue;
+            }
+        };
+        // Each connection has a thread, so a client which leaks them mustn't be able to make us
+        // run out. Closing the connection tells it to try again later.
+        if connections.load(Ordering::SeqCst) >= RPC_MAX_CONNECTIONS {
+            eprintln!("Refusing connection, already serving {}", RPC_MAX_CONNECTIONS);
+            continue;
+        }
+        connections.fetch_add(1, Ordering::SeqCst);
+        let (cache, connections) = (cache.clone(), connections.clone());
+        thread::spawn(move || {
+            if let Err(e) = rpc_serve_connection(stream, &cache) {
+                eprintln!("Error serving connection: {}", e);
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+pub fn pcompare_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    refuse_capture(program, "the kernel compares the live processes");
+
+    if matches.free.len() != 2 {
+        usage_err(program, opts);
+    }
+
+    let (pid1, pid2) = match (matches.free[0].parse::<u64>(), matches.free[1].parse::<u64>()) {
+        (Ok(pid1), Ok(pid2)) => (pid1, pid2),
+        _ => usage_err(program, opts),
+    };
+    if !print_comparison(pid1, pid2) {
+        exit(1);
+    }
+}
+
+pub fn pwdx_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("r", "root", "Print the root directory of each process instead");
+        opts.optflag("", "all", "Print the directory of every process");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    let root = matches.opt_present("r");
+    // With no pids, --all or a selection option picks from every process
+    let all = matches.opt_present("all");
+    if (all && !matches.free.is_empty()) || (!all && matches.free.is_empty() && !selection.is_active()) {
+        usage_err(program, opts);
+    }
+
+    let mut error = false;
+    for pid in ScanProgress::new(selection.pids(&matches.free), false) {
+        error = !print_dir(pid, root) || error;
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+pub fn pstack_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.optopt("", "format", "print stacks like pstack (the default), gdb ('thread apply all bt'), eu-stack, \
+                                   or folded for flame graphs", "FORMAT");
+        opts.optflag("", "all-threads", "print the stack of every thread (the default)");
+        opts.optmulti("", "thread", "only stop and print the stack of thread TID, which may be given more than \
+                                     once. Without PIDs, the processes the threads are part of are printed.", "TID");
+        opts.optflag("", "dedup", "print threads with the same stack once, saying how many there are");
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    let mut tids = vec![];
+    for tid in matches.opt_strs("thread") {
+        match tid.parse::<u64>() {
+            Ok(tid) => tids.push(tid),
+            Err(_) => {
+                eprintln!("Invalid thread id '{}'", tid);
+                usage_err(program, opts);
+            }
+        }
+    }
+    if !tids.is_empty() && matches.opt_present("all-threads") {
+        eprintln!("--all-threads and --thread can't be used together");
+        usage_err(program, opts);
+    }
+    if matches.free.is_empty() && !selection.is_active() && tids.is_empty() {
+        usage_err(program, opts);
+    }
+
+    let format = match matches.opt_str("format").map(|format| StackFormat::parse(&format).ok_or(format)) {
+        None => StackFormat::Pstack,
+        Some(Ok(format)) => format,
+        Some(Err(format)) => {
+            eprintln!("Unknown stack format '{}'", format);
+            usage_err(program, opts);
+        }
+    };
+    // The other formats are read by tools which expect every thread, and folded adds stacks up anyway
+    let dedup = matches.opt_present("dedup");
+    if dedup && format != StackFormat::Pstack {
+        eprintln!("--dedup can only be used with the pstack format");
+        usage_err(program, opts);
+    }
+
+    let mut error = false;
+    // The process each thread given with --thread is part of
+    let mut tgids = vec![];
+    for &tid in &tids {
+        match ProcStat::read(tid).and_then(|stat| stat.tgid()) {
+            Ok(tgid) => tgids.push((tid, tgid)),
+            Err(e) => {
+                eprintln!("Unable to find the process thread {} is part of: {}", tid, e);
+                error = true;
+            }
+        }
+    }
+    let pids = if matches.free.is_empty() && !selection.is_active() {
+        let mut pids = tgids.iter().map(|&(_, tgid)| tgid).collect::<Vec<_>>();
+        pids.sort();
+        pids.dedup();
+        pids
+    } else {
+        selection.pids(&matches.free)
+    };
+    for &(tid, tgid) in tgids.iter().filter(|&&(_, tgid)| !pids.contains(&tgid)) {
+        eprintln!("Thread {} is part of process {}, which wasn't selected", tid, tgid);
+        error = true;
+    }
+
+    for pid in pids {
+        if tids.is_empty() {
+            error = !print_stacks(pid, format, None, dedup) || error;
+            continue;
+        }
+        let only = tgids.iter().filter(|&&(_, tgid)| tgid == pid).map(|&(tid, _)| tid).collect::<Vec<_>>();
+        if !only.is_empty() {
+            error = !print_stacks(pid, format, Some(&only), dedup) || error;
+        }
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+pub fn pnamespaces_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("", "tree", "Group processes by the namespaces they share, instead of listing \
+                                  the namespaces of each");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    // With --tree and no pids, group every process on the system. Kernel threads are all in the
+    // initial namespaces, so leave them out.
+    let tree = matches.opt_present("tree");
+    if matches.free.is_empty() && !selection.is_active() && !tree {
+        usage_err(program, opts);
+    }
+    let pids = if matches.free.is_empty() {
+        all_pids().into_iter().filter(|&pid| !is_kernel_thread(pid) && selection.matches(pid)).collect()
+    } else {
+        selection.pids(&matches.free)
+    };
+
+    let reference = reference_namespaces();
+    if tree {
+        print_namespace_groups(&pids, reference.as_ref());
+        return;
+    }
+
+    let mut error = false;
+    for pid in pids {
+        error = !print_namespaces(pid, reference.as_ref()) || error;
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+pub fn pcapture_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optopt("o", "output", "Write the archive to FILE (default pcapture.PID.tar.gz)", "FILE");
+        opts.optflag("", "tree", "Also capture all descendants of the process");
+        opts.optflag("", "stack", "Also capture the stack of each thread (stops the process briefly, like pstack)");
+        opts.optflag("", "no-compress", "Write a plain tar archive instead of compressing it with gzip");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    if matches.opt_present("from-capture") {
+        eprintln!("{} can't be used with --from-capture", program);
+        usage_err(program, opts);
+    }
+    apply_common_opts(program, &opts, &matches);
+
+    if matches.free.len() != 1 {
+        usage_err(program, opts);
+    }
+    let pid = match matches.free[0].parse::<u64>() {
+        Ok(pid) => pid,
+        Err(_) => usage_err(program, opts),
+    };
+
+    let pids = if matches.opt_present("tree") {
+        let tree = match read_process_tree() {
+            Ok(tree) => tree,
+            Err(e) => {
+                eprintln!("Unable to read processes: {}", e);
+                exit(1);
+            }
+        };
+        if !tree.parent_map.contains_key(&pid) {
+            eprintln!("No such pid {}", pid);
+            exit(1);
+        }
+        let mut pids = subtree_pids(&tree, pid);
+        pids.sort();
+        pids
+    } else {
+        if !Path::new(&format!("/proc/{}", pid)).exists() {
+            eprintln!("No such pid {}", pid);
+            exit(1);
+        }
+        vec![pid]
+    };
+
+    let cap = if matches.opt_present("stack") {
+        match MutateCap::acquire(&format!("stop process {} with ptrace", pid)) {
+            Ok(cap) => Some(cap),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let compress = !matches.opt_present("no-compress");
+    let output = matches.opt_str("o").unwrap_or_else(|| {
+        format!("pcapture.{}.tar{}", pid, if compress { ".gz" } else { "" })
+    });
+    let file = match File::create(&output) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Unable to create {}: {}", output, e);
+            exit(1);
+        }
+    };
+
+    let result = if compress {
+        let mut gzip = match std::process::Command::new("gzip").arg("-c")
+            .stdin(std::process::Stdio::piped())
+            .stdout(file)
+            .spawn() {
+            Ok(gzip) => gzip,
+            Err(e) => {
+                eprintln!("Unable to run gzip: {} (use --no-compress to write a plain tar archive)", e);
+                let _ = fs::remove_file(&output);
+                exit(1);
+            }
+        };
+        let stdin = gzip.stdin.take().expect("gzip was spawned with a piped stdin");
+        let result = write_capture(stdin, &pids, cap.as_ref()).map(|(stdin, unreadable)| {
+            drop(stdin);
+            unreadable
+        });
+        match gzip.wait() {
+            Ok(status) if status.success() => result,
+            Ok(status) => Err(From::from(format!("gzip failed: {}", status))),
+            Err(e) => Err(From::from(e)),
+        }
+    } else {
+        write_capture(file, &pids, cap.as_ref()).map(|(_, unreadable)| unreadable)
+    };
+
+    match result {
+        Ok(unreadable) => {
+            println!("{}: captured {} process{}", output, pids.len(), if pids.len() == 1 { "" } else { "es" });
+            if !unreadable.is_empty() {
+                println!("  {} files couldn't be read, see {} in the archive", unreadable.len(), CAPTURE_MANIFEST);
+            }
+        }
+        Err(e) => {
+            eprintln!("Unable to write {}: {}", output, e);
+            let _ = fs::remove_file(&output);
+            exit(1);
+        }
+    }
+}
+
+pub fn pstopwatch_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optopt("i", "interval", "Measure over SECS seconds (default 1)", "SECS");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    refuse_capture(program, "delays are measured on the live process");
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    if matches.free.is_empty() && !selection.is_active() {
+        usage_err(program, opts);
+    }
+
+    let interval = match matches.opt_str("i").map(|secs| secs.parse::<f64>()) {
+        None => Duration::from_secs(1),
+        Some(Ok(secs)) if secs > 0.0 && secs.is_finite() => Duration::from_millis((secs * 1000.0) as u64),
+        Some(_) => {
+            eprintln!("--interval must be a positive number of seconds");
+            usage_err(program, opts);
+        }
+    };
+
+    // Without TASKSTATS, fall back to schedstat quietly; the output says where numbers came from
+    let taskstats = TaskstatsSocket::open().ok();
+    let pids = selection.pids(&matches.free);
+    let start = Instant::now();
+    let before = pids.iter().map(|&pid| sample_delays(taskstats.as_ref(), pid)).collect::<Vec<_>>();
+    thread::sleep(interval);
+    let after = pids.iter().map(|&pid| sample_delays(taskstats.as_ref(), pid)).collect::<Vec<_>>();
+    let elapsed = start.elapsed();
+
+    let mut error = false;
+    for ((&pid, before), after) in pids.iter().zip(before).zip(after) {
+        match (before, after) {
+            (Ok((before, source)), Ok((after, ref after_source))) if source == *after_source =>
+                print_delays(pid, &before, &after, elapsed, &source),
+            (Ok(_), Ok(_)) => {
+                eprintln!("Unable to measure the delays of process {} consistently", pid);
+                error = true;
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("Unable to read the delays of process {}: {}", pid, e);
+                error = true;
+            }
+        }
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+pub fn pmem_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("a", "all", "report on every process");
+        opts.optopt("g", "group-by", "add up the processes by user, cgroup (v2) or comm, showing how many are in \
+                                      each group", "KEY");
+        opts.optopt("", "wss", "estimate the working set, the memory used over SECS seconds, with idle page \
+                                tracking (needs CAP_SYS_ADMIN)", "SECS");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    let all = matches.opt_present("a");
+    if matches.free.is_empty() && !selection.is_active() && !all {
+        usage_err(program, opts);
+    }
+    if all && !matches.free.is_empty() {
+        eprintln!("--all can't be given pids");
+        usage_err(program, opts);
+    }
+
+    let group_by = match matches.opt_str("g").map(|key| MemoryGroup::parse(&key).ok_or(key)) {
+        None => None,
+        Some(Ok(group)) => Some(group),
+        Some(Err(key)) => {
+            eprintln!("Unknown --group-by key '{}', expected user, cgroup or comm", key);
+            usage_err(program, opts);
+        }
+    };
+
+    let wss = match matches.opt_str("wss").map(|secs| secs.parse::<f64>()) {
+        None => None,
+        Some(Ok(secs)) if secs > 0.0 && secs.is_finite() => Some(Duration::from_millis((secs * 1000.0) as u64)),
+        Some(_) => {
+            eprintln!("--wss must be a positive number of seconds");
+            usage_err(program, opts);
+        }
+    };
+    if let Some(interval) = wss {
+        if all || group_by.is_some() {
+            eprintln!("--wss can't be used with --all or --group-by");
+            usage_err(program, opts);
+        }
+        refuse_capture(program, "the working set is measured on the live process");
+        if !pmem_wss(&selection.pids(&matches.free), interval) {
+            exit(1);
+        }
+        return;
+    }
+
+    // When going through every process, those we aren't allowed to look at and kernel threads,
+    // which have no memory of their own, are left out quietly
+    let mut error = false;
+    // Nothing is printed until all the processes have been read
+    let mut processes = vec![];
+    let mut scan = ScanProgress::new(selection.pids(&matches.free), true);
+    while let Some(pid) = scan.next() {
+        match read_memory_totals(pid) {
+            Ok(totals) if matches.free.is_empty() && totals == MemoryTotals::default() => {}
+            Ok(totals) => processes.push((pid, totals)),
+            Err(_) if matches.free.is_empty() => {}
+            Err(e) => {
+                scan.clear();
+                eprintln!("Unable to read memory usage of process {}: {}", pid, e);
+                error = true;
+            }
+        }
+    }
+    drop(scan);
+    processes.sort_by(|a, b| b.1.pss.cmp(&a.1.pss).then(a.0.cmp(&b.0)));
+    // Most hosts don't run KSM, so the columns are only there if it merged something
+    let ksm = processes.iter().any(|(_, totals)| totals.ksm > 0 || totals.zero > 0);
+
+    match group_by {
+        Some(group_by) => {
+            let grouped = processes.iter().map(|&(pid, totals)| (group_by.of(pid), totals)).collect::<Vec<_>>();
+            print_memory_header(group_by.name(), true, ksm);
+            for (group, count, totals) in group_memory_totals(&grouped) {
+                print_memory_line(&group, Some(count), &totals, ksm);
+            }
+        }
+        None => {
+            print_memory_header("PID  COMMAND", false, ksm);
+            for &(pid, ref totals) in &processes {
+                print_memory_line(&format!("{}  {}", pid, MemoryGroup::Comm.of(pid)), None, totals, ksm);
+            }
+        }
+    }
+    if processes.len() > 1 {
+        let mut total = MemoryTotals::default();
+        for &(_, totals) in &processes {
+            total += totals;
+        }
+        print_memory_line("TOTAL", group_by.map(|_| processes.len()), &total, ksm);
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+pub fn pcore_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optopt("o", "output", "Write the core to FILE (default core.PID, or core.PID.gz with --compress)", "FILE");
+        opts.optflag("z", "compress", "Compress the core with gzip (debuggers need it uncompressed to read it)");
+        opts.optopt("", "filter", "Dump the kinds of mapping in MASK, a coredump_filter bitmask (see core(5)), \
+                                   instead of those in the process' /proc/PID/coredump_filter", "MASK");
+        opts.optflag("q", "quiet", "Don't show progress while writing the core");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    refuse_capture(program, "it copies the memory of the live process");
+
+    if matches.free.len() != 1 {
+        usage_err(program, opts);
+    }
+    let pid = match matches.free[0].parse::<u64>() {
+        Ok(pid) => pid,
+        Err(_) => usage_err(program, opts),
+    };
+    let filter = match matches.opt_str("filter").map(|mask| u32::from_str_radix(mask.trim_start_matches("0x"), 16)) {
+        None => None,
+        Some(Ok(mask)) => Some(mask),
+        Some(Err(_)) => {
+            eprintln!("--filter must be a hexadecimal bitmask, like the contents of /proc/PID/coredump_filter");
+            usage_err(program, opts);
+        }
+    };
+    if !Path::new(&format!("/proc/{}", pid)).exists() {
+        eprintln!("No such pid {}", pid);
+        exit(1);
+    }
+
+    let cap = match MutateCap::acquire(&format!("stop process {} with ptrace", pid)) {
+        Ok(cap) => cap,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
+    let compress = matches.opt_present("z");
+    let output = matches.opt_str("o").unwrap_or_else(|| format!("core.{}{}", pid, if compress { ".gz" } else { "" }));
+    let file = match File::create(&output) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Unable to create {}: {}", output, e);
+            exit(1);
+        }
+    };
+
+    // Progress goes on one line, rewritten as each percent is copied
+    let show_progress = !matches.opt_present("q") && unsafe { libc::isatty(libc::STDERR_FILENO) } == 1;
+    let mut percent = None;
+    let mut progress = |copied: u64, total: u64| {
+        let now = copied * 100 / total.max(1);
+        if show_progress && percent != Some(now) {
+            eprint!("\r{}: {}% ({} of {})", output, now, size_str(copied), size_str(total));
+            percent = Some(now);
+        }
+    };
+
+    let result = if compress {
+        let mut gzip = match std::process::Command::new("gzip").arg("-c")
+            .stdin(std::process::Stdio::piped())
+            .stdout(file)
+            .spawn() {
+            Ok(gzip) => gzip,
+            Err(e) => {
+                eprintln!("Unable to run gzip: {}", e);
+                let _ = fs::remove_file(&output);
+                exit(1);
+            }
+        };
+        let stdin = gzip.stdin.take().expect("gzip was spawned with a piped stdin");
+        let result = write_core(&cap, pid, filter, std::io::BufWriter::new(stdin), &mut progress)
+            .map(|(stdin, summary)| {
+                drop(stdin);
+                summary
+            });
+        match gzip.wait() {
+            Ok(status) if status.success() => result,
+            Ok(status) => Err(From::from(format!("gzip failed: {}", status))),
+            Err(e) => Err(From::from(e)),
+        }
+    } else {
+        write_core(&cap, pid, filter, std::io::BufWriter::new(file), &mut progress).map(|(_, summary)| summary)
+    };
+    if percent.is_some() {
+        eprintln!();
+    }
+
+    match result {
+        Ok(summary) => {
+            println!("{}: {} of {} mappings and {} thread{} of process {}", output, size_str(summary.bytes),
+                     summary.mappings, summary.threads, if summary.threads == 1 { "" } else { "s" }, pid);
+            if summary.unreadable > 0 {
+                println!("  {} couldn't be read, and is zeros in the core", size_str(summary.unreadable));
+            }
+        }
+        Err(e) => {
+            eprintln!("Unable to write a core of process {}: {}", pid, e);
+            let _ = fs::remove_file(&output);
+            exit(1);
+        }
+    }
+}
+
+pub fn pshm_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("", "orphans", "List shared memory which no process uses: System V segments with nothing \
+                                     attached whose creator has exited, and files in /dev/shm nothing has mapped or \
+                                     open");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    refuse_capture(program, "System V segments and the users of shared memory are looked up on the live system");
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    let orphans = matches.opt_present("orphans");
+    if matches.free.is_empty() && !selection.is_active() && !orphans {
+        usage_err(program, opts);
+    }
+
+    let segments = read_sysv_segments();
+    let users = shm_users();
+    if orphans {
+        print_shm_orphans(&segments, &users);
+        if matches.free.is_empty() && !selection.is_active() {
+            return;
+        }
+    }
+
+    let mut error = false;
+    for pid in ScanProgress::new(selection.pids(&matches.free), false) {
+        error = !print_shm(pid, &segments, &users) || error;
+    }
+    if error {
+        exit(1);
+    }
+}
+
+pub fn penvdiff_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optopt("", "save", "save the environment of the process to FILE, to compare against later", "FILE");
+        opts.optopt("", "baseline", "compare the environment of each process against the one saved in FILE, \
+                                     printing the differences and exiting with status 1 if there are any", "FILE");
+        opts.optmulti("", "ignore", "leave variable NAME out of the comparison, e.g. one which changes every \
+                                     time the process is restarted", "NAME");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    if matches.free.is_empty() && !selection.is_active() {
+        usage_err(program, opts);
+    }
+
+    let read_env = |pid| read_nul_strings(&proc_path!("{}/environ", pid))
+        .map_err(|e| eprintln!("Unable to read the environment of process {}: {}", pid, e));
+    let pids = selection.pids(&matches.free);
+
+    // Like diff, exit with 1 if there are differences, and 2 if something went wrong
+    match (matches.opt_str("save"), matches.opt_str("baseline")) {
+        (Some(path), None) => {
+            if pids.len() != 1 {
+                eprintln!("--save needs exactly one process, but {} were selected", pids.len());
+                exit(2);
+            }
+            let env = read_env(pids[0]).unwrap_or_else(|()| exit(2));
+            if let Err(e) = save_env_baseline(&path, &env) {
+                eprintln!("Unable to write {}: {}", path, e);
+                exit(2);
+            }
+        }
+        (None, Some(path)) => {
+            let baseline = read_nul_strings(&path).unwrap_or_else(|e| {
+                eprintln!("Unable to read {}: {}", path, e);
+                exit(2);
+            });
+            let ignore = matches.opt_strs("ignore");
+            let mut error = false;
+            let mut changed = false;
+            for pid in pids {
+                let env = match read_env(pid) {
+                    Ok(env) => env,
+                    Err(()) => {
+                        error = true;
+                        continue;
+                    }
+                };
+                // Print nothing when nothing changed, so that cron has nothing to mail
+                let diff = env_diff(&baseline, &env, &ignore);
+                if !diff.is_empty() {
+                    changed = true;
+                    print_proc_summary(pid);
+                    for line in diff {
+                        println!("  {}", line);
+                    }
+                }
+            }
+            if error {
+                exit(2);
+            } else if changed {
+                exit(1);
+            }
+        }
+        _ => {
+            eprintln!("Exactly one of --save and --baseline must be given");
+            usage_err(program, opts);
+        }
+    }
+}
+
+// The processes pstop and prun act on: those given, and with --tree, their descendants
+fn tree_pids(matches: &Matches, pids: Vec<u64>) -> Vec<u64> {
+    if !matches.opt_present("tree") {
+        return pids;
+    }
+    match with_descendants(&pids) {
+        Ok(pids) => pids,
+        Err(e) => {
+            eprintln!("Unable to read processes: {}", e);
+            exit(1);
+        }
+    }
+}
+
+pub fn pstop_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("", "at-syscall", "Let each thread run on to its next system call and stop it when that \
+                                        returns, rather than wherever it happens to be. Threads which make none \
+                                        within a second are stopped where they are");
+        opts.optflag("", "tree", "Also stop all descendants of the processes, parents first so that none can \
+                                  fork once stopped");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    refuse_capture(program, "it stops the live process");
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    if matches.free.is_empty() && !selection.is_active() {
+        usage_err(program, opts);
+    }
+
+    let at_syscall = matches.opt_present("at-syscall");
+    let mut error = false;
+    for pid in tree_pids(&matches, selection.pids(&matches.free)) {
+        let cap = match MutateCap::acquire(&format!("stop process {}", pid)) {
+            Ok(cap) => cap,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        };
+        let result = if at_syscall {
+            stop_at_syscall(&cap, pid, SYSCALL_STOP_TIMEOUT).map(|late| {
+                for tid in late {
+                    eprintln!("{}: thread {} made no system call within {}s, so it was stopped where it was",
+                              pid, tid, SYSCALL_STOP_TIMEOUT.as_secs());
+                }
+            })
+        } else {
+            stop_process(&cap, pid)
+        };
+        if let Err(e) = result {
+            eprintln!("Unable to stop process {}: {}", pid, e);
+            error = true;
+        }
+    }
+    if error {
+        exit(1);
+    }
+}
+
+pub fn pionice_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optopt("c", "class", "set the I/O scheduling class: none, realtime, best-effort or idle (or 0-3, as \
+                                   for ionice)", "CLASS");
+        opts.optopt("n", "level", "set the level within the realtime or best-effort class, from 0 (highest) to 7",
+                    "LEVEL");
+        opts.optflag("", "tree", "Also show or set the I/O priority of all descendants of the processes");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    refuse_capture(program, "I/O priorities are only kept by the kernel");
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    if matches.free.is_empty() && !selection.is_active() {
+        usage_err(program, opts);
+    }
+
+    let setting = matches.opt_present("c") || matches.opt_present("n");
+    let ioprio = match ioprio_from_opts(matches.opt_str("c").as_deref(), matches.opt_str("n").as_deref()) {
+        Ok(ioprio) => ioprio,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    let mut error = false;
+    for pid in tree_pids(&matches, selection.pids(&matches.free)) {
+        if !setting {
+            error = !print_io_priorities(pid) || error;
+            continue;
+        }
+        let cap = match MutateCap::acquire(&format!("change the I/O priority of process {}", pid)) {
+            Ok(cap) => cap,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        };
+        error = !set_io_priority(&cap, pid, ioprio) || error;
+    }
+    if error {
+        exit(1);
+    }
+}
+
+pub fn prun_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("", "tree", "Also set all descendants of the processes running");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    refuse_capture(program, "it sets the live process running");
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    if matches.free.is_empty() && !selection.is_active() {
+        usage_err(program, opts);
+    }
+
+    let mut error = false;
+    for pid in tree_pids(&matches, selection.pids(&matches.free)) {
+        let cap = match MutateCap::acquire(&format!("set process {} running", pid)) {
+            Ok(cap) => cap,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        };
+        if let Err(e) = resume_process(&cap, pid) {
+            eprintln!("Unable to set process {} running: {}", pid, e);
+            error = true;
+        }
+    }
+    if error {
+        exit(1);
+    }
+}
+
+pub fn pwait_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("", "all", "Wait until all the processes have exited (the default)");
+        opts.optflag("", "any", "Wait until any one of the processes has exited");
+        opts.optflag("v", "verbose", "Print each process as it exits, with its exit status if we can find it out \
+                                      (which needs CAP_NET_ADMIN)");
+        opts.optflag("", "json", "Print each exit as a JSON object on a line of its own, as it happens");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    refuse_capture(program, "it waits for live processes");
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    if matches.free.is_empty() && !selection.is_active() {
+        usage_err(program, opts);
+    }
+    if matches.opt_present("all") && matches.opt_present("any") {
+        eprintln!("--all and --any can't be used together");
+        usage_err(program, opts);
+    }
+    let any = matches.opt_present("any");
+    let json = matches.opt_present("json");
+    let verbose = matches.opt_present("v") || json;
+
+    // Listen before looking at the processes, so that no exit can be missed in between
+    let events = Arc::new(Mutex::new(vec![]));
+    let listening = verbose && listen_for_proc_events(events.clone()).is_ok();
+
+    let mut error = false;
+    let mut processes = vec![];
+    for pid in selection.pids(&matches.free) {
+        match WaitedProcess::open(pid) {
+            Ok(process) => processes.push(process),
+            Err(e) => {
+                eprintln!("{}", e);
+                error = true;
+            }
+        }
+    }
+
+    // Shutdown scripts often give up on waiting with timeout(1) or ^C, so on SIGTERM or SIGINT we
+    // say which processes were still running, rather than just dying
+    catch_interrupts();
+    let mut fds = Vec::with_capacity(processes.len() + 1);
+    let mut stat = String::new();
+    let waited = processes.iter().map(|process| process.pid).collect::<std::collections::HashSet<_>>();
+    let mut statuses = HashMap::new();
+    while !processes.is_empty() && interrupt_signal().is_none() {
+        wait_for_exits(&processes, &mut fds, listening);
+        take_exit_statuses(&events, &waited, &mut statuses);
+        let time = std::time::SystemTime::now();
+        let mut i = 0;
+        let mut exited = false;
+        while i < processes.len() {
+            if !processes[i].exited(&mut stat) {
+                i += 1;
+                continue;
+            }
+            let process = processes.remove(i);
+            exited = true;
+            if verbose {
+                let status = exit_status(&events, &waited, &mut statuses, process.pid, listening);
+                match json {
+                    true => println!("{}", exit_event_json(&process, time, status, processes.len())),
+                    false => println!("{}", exit_event_str(&process, time, status)),
+                }
+            }
+        }
+        if any && exited {
+            break;
+        }
+    }
+
+    if let Some(signal) = interrupt_signal() {
+        if json {
+            println!("{}", Json::object(vec![
+                ("event", Json::Str("interrupted".to_string())),
+                ("signal", Json::Str(signal_str(signal))),
+                ("remaining", Json::UInt(processes.len() as u64)),
+                ("pids", Json::Array(processes.iter().map(|p| Json::UInt(p.pid)).collect())),
+            ]));
+        } else {
+            eprintln!("Interrupted by {}, still waiting for {} process{}:", signal_str(signal), processes.len(),
+                      if processes.len() == 1 { "" } else { "es" });
+            for process in &processes {
+                eprintln!("  {:>7}  {}", process.pid, process.summary);
+            }
+        }
+        let _ = std::io::stdout().flush();
+        exit(128 + signal);
+    }
+    if error {
+        exit(1);
+    }
+}
+
+pub fn pcaps_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("a", "all", "list the processes holding each dangerous capability, out of every process");
+        opts.optmulti("", "cap", "list the processes holding capability NAME (e.g. cap_sys_admin or net_raw) \
+                                  instead of the dangerous ones (may be given more than once)", "NAME");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    let all = matches.opt_present("a");
+    if all && !matches.free.is_empty() {
+        eprintln!("--all can't be given pids");
+        usage_err(program, opts);
+    }
+    let mut caps = vec![];
+    for name in matches.opt_strs("cap") {
+        match parse_capability(&name) {
+            Some(cap) => caps.push(cap),
+            None => {
+                eprintln!("Unknown capability '{}'", name);
+                usage_err(program, opts);
+            }
+        }
+    }
+    let audit = all || !caps.is_empty();
+    if matches.free.is_empty() && !selection.is_active() && !audit {
+        usage_err(program, opts);
+    }
+
+    let mut error = false;
+    if !audit {
+        for pid in ScanProgress::new(selection.pids(&matches.free), false) {
+            error = !print_capability_sets(pid) || error;
+        }
+        if error {
+            exit(1);
+        }
+        return;
+    }
+
+    if caps.is_empty() {
+        caps = DANGEROUS_CAPABILITIES.iter().filter_map(|name| parse_capability(name)).collect();
+    }
+    // Kernel threads have every capability, and can't be made to do anything with them. Going
+    // through every process, those we can't look at are left out quietly.
+    let mut processes = vec![];
+    let mut scan = ScanProgress::new(selection.pids(&matches.free), true);
+    while let Some(pid) = scan.next() {
+        match read_capability_sets(pid) {
+            Ok(_) if is_kernel_thread(pid) => {}
+            Ok(sets) => processes.push((pid, sets)),
+            Err(_) if matches.free.is_empty() => {}
+            Err(e) => {
+                scan.clear();
+                eprintln!("Unable to read capabilities of process {}: {}", pid, e);
+                error = true;
+            }
+        }
+    }
+    drop(scan);
+
+    let mut unheld = vec![];
+    for (cap, holders) in capability_holders(&processes, &caps) {
+        if holders.is_empty() {
+            unheld.push(CAPABILITY_NAMES[cap]);
+            continue;
+        }
+        let plural = if holders.len() == 1 { "" } else { "es" };
+        println!("{} ({} process{}):", CAPABILITY_NAMES[cap], holders.len(), plural);
+        for (pid, effective) in holders {
+            print!("  {:>7}  {}", pid, if effective { "" } else { "[permitted, not effective]  " });
+            print_cmd_summary(pid);
+        }
+    }
+    if !unheld.is_empty() {
+        println!("held by no process: {}", unheld.join(", "));
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+pub fn pboot_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("", "json", "Print it all as a JSON object");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    refuse_capture(program, "it looks at the running kernel");
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+
+    let read = |path| fs::read_to_string(path).ok().map(|contents| contents.trim().to_string());
+    let release = read("/proc/sys/kernel/osrelease");
+    let version = read("/proc/sys/kernel/version");
+    let cmdline = read("/proc/cmdline");
+    let boot_id = read("/proc/sys/kernel/random/boot_id");
+    let booted = fs::read_to_string("/proc/stat").ok().and_then(|stat| parse_boot_time(&stat));
+
+    // Without pids, only the system
+    let pids = match matches.free.is_empty() && !selection.is_active() {
+        true => vec![],
+        false => selection.pids(&matches.free),
+    };
+    let mut error = false;
+    let mut processes = vec![];
+    let mut scan = ScanProgress::new(pids, true);
+    while let Some(pid) = scan.next() {
+        match read_personality(pid) {
+            Ok(personality) => processes.push((pid, personality, read_lsm_attrs(pid))),
+            Err(e) => {
+                scan.clear();
+                eprintln!("Unable to read personality of process {}: {}", pid, e);
+                error = true;
+            }
+        }
+    }
+
+    if matches.opt_present("json") {
+        let string = |value: Option<String>| value.map_or(Json::Null, Json::Str);
+        println!("{}", Json::object(vec![
+            ("kernel", string(release)),
+            ("version", string(version)),
+            ("cmdline", string(cmdline)),
+            ("boot_id", string(boot_id)),
+            ("booted", booted.map_or(Json::Null, |time| Json::Str(utc_time_str(time)))),
+            ("processes", Json::Array(processes.into_iter().map(|(pid, personality, attrs)| Json::object(vec![
+                ("pid", Json::UInt(pid)),
+                ("cmdline", Json::Str(cmd_summary(pid).trim_end().to_string())),
+                ("personality", Json::Str(personality_str(personality))),
+                ("attr", Json::object(attrs.into_iter().map(|(name, value)| (name, Json::Str(value))).collect())),
+            ])).collect())),
+        ]));
+    } else {
+        let unknown = || "unknown".to_string();
+        println!("kernel: Linux {} {}", release.unwrap_or_else(unknown), version.unwrap_or_default());
+        println!("cmdline: {}", cmdline.unwrap_or_else(unknown));
+        println!("boot id: {}", boot_id.unwrap_or_else(unknown));
+        match booted {
+            Some(time) => {
+                let ago = std::time::SystemTime::now().duration_since(time).map_or(0, |d| d.as_secs());
+                println!("booted: {} ({})", time_str(time), ago_str(ago));
+            }
+            None => println!("booted: unknown"),
+        }
+        for (pid, personality, attrs) in processes {
+            print_proc_summary(pid);
+            println!("  personality: {}", personality_str(personality));
+            if attrs.is_empty() {
+                println!("  attr: none");
+            }
+            for (name, value) in attrs {
+                println!("  attr/{}: {}", name, value);
+            }
+        }
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+// The controlling terminal of a process, from tty_nr in /proc/[pid]/stat, which has the major
+// number in bits 8-19 and the minor in bits 0-7 and 20-31 (see new_encode_dev())
+fn tty_name(tty_nr: u64) -> Option<String> {
+    let (major, minor) = ((tty_nr >> 8) & 0xfff, (tty_nr & 0xff) | ((tty_nr >> 12) & 0xfff00));
+    match (major, minor) {
+        (0, 0) => None,
+        (136..=143, _) => Some(format!("/dev/pts/{}", (major - 136) * 256 + minor)),
+        (4, 0..=63) => Some(format!("/dev/tty{}", minor)),
+        (4, _) => Some(format!("/dev/ttyS{}", minor - 64)),
+        (5, 1) => Some("/dev/console".to_string()),
+        _ => Some(format!("tty {}:{}", major, minor)),
+    }
+}
+
+// An argument quoted for a shell when it needs to be, so a command line can be pasted back
+fn shell_word(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    match !arg.is_empty() && arg.chars().all(plain) {
+        true => arg.to_string(),
+        false => format!("'{}'", arg.replace('\'', "'\\''")),
+    }
+}
+
+// What pstartup found out about how a process was started
+struct Startup {
+    exe: Option<String>,
+    argv: Vec<String>,
+    cwd: Option<String>,
+    user: String,
+    // The audit login uid and session, naming the login it came from
+    audit_ids: (Option<u32>, Option<u32>),
+    started: Option<std::time::SystemTime>,
+    tty: Option<String>,
+    unit: Option<String>,
+    // Its parent first, up to pid 1, each with its comm
+    ancestors: Vec<(u64, String)>,
+    // How its environment differs from its parent's, as env_diff() shows it, if both can be read
+    env_delta: Option<Vec<String>>,
+}
+
+impl Startup {
+    fn read(pid: u64) -> Result<Self, Box<dyn Error>> {
+        let link = |name| read_proc_link(proc_path!("{}/{}", pid, name)).ok().map(|l| l.to_string_lossy().into_owned());
+        let stat = fs::read_to_string(proc_path!("{}/stat", pid))?;
+        let ancestors = process_ancestors(pid);
+        // Both are the environments the processes were started with, so what the parent set between
+        // its own start and the exec() of this one, e.g. a shell's exports, shows up as a difference
+        let env_delta = match (ancestors.first(), read_nul_strings(&proc_path!("{}/environ", pid))) {
+            (Some(&(ppid, _)), Ok(env)) => read_nul_strings(&proc_path!("{}/environ", ppid)).ok()
+                .map(|parent_env| env_diff(&parent_env, &env, &[])),
+            _ => None,
+        };
+        Ok(Startup {
+            exe: link("exe"),
+            argv: read_nul_strings(&proc_path!("{}/cmdline", pid))?,
+            cwd: link("cwd"),
+            user: process_user(pid),
+            audit_ids: read_audit_ids(pid),
+            started: process_age(pid).ok().and_then(|age| std::time::SystemTime::now().checked_sub(age)),
+            tty: stat_field(&stat, 7).and_then(|tty_nr| tty_nr.parse::<i64>().ok()).and_then(|nr| tty_name(nr as u64)),
+            unit: process_service(pid),
+            ancestors,
+            env_delta,
+        })
+    }
+
+    // One sentence saying who started the process. pid 1 is the parent of both the services it
+    // starts and the processes whose parents exited, which only a service's cgroup tells apart.
+    // Kernel threads are all children of kthreadd (pid 2).
+    fn summary(&self) -> String {
+        let (ppid, comm) = match self.ancestors.first() {
+            Some((ppid, comm)) => (*ppid, comm),
+            None => return "started by the kernel".to_string(),
+        };
+        let parent = format!("{} (pid {})", comm, ppid);
+        match (ppid, &self.unit, &self.tty) {
+            (1, Some(unit), _) => format!("started by the service manager for {}", unit),
+            (1, None, _) => "a child of pid 1 which isn't a service, so its parent probably exited, e.g. when \
+                             it daemonized".to_string(),
+            (2, _, _) => format!("a kernel thread, started by {}", parent),
+            (_, Some(unit), _) => format!("started by {}, in {}", parent, unit),
+            (_, None, Some(tty)) => format!("started by {} on {}", parent, tty),
+            (_, None, None) => format!("started by {}", parent),
+        }
+    }
+
+    fn json(&self, pid: u64) -> Json {
+        let string = |value: &Option<String>| value.clone().map_or(Json::Null, Json::Str);
+        let strings = |values: &[String]| Json::Array(values.iter().cloned().map(Json::Str).collect());
+        Json::object(vec![
+            ("pid", Json::UInt(pid)),
+            ("summary", Json::Str(self.summary())),
+            ("exe", string(&self.exe)),
+            ("argv", strings(&self.argv)),
+            ("cwd", string(&self.cwd)),
+            ("user", Json::Str(self.user.clone())),
+            ("loginuid", self.audit_ids.0.map_or(Json::Null, |uid| Json::UInt(u64::from(uid)))),
+            ("sessionid", self.audit_ids.1.map_or(Json::Null, |id| Json::UInt(u64::from(id)))),
+            ("started", self.started.map_or(Json::Null, |time| Json::Str(utc_time_str(time)))),
+            ("tty", string(&self.tty)),
+            ("unit", string(&self.unit)),
+            ("ancestors", Json::Array(self.ancestors.iter().map(|(ppid, comm)| Json::object(vec![
+                ("pid", Json::UInt(*ppid)),
+                ("comm", Json::Str(comm.clone())),
+            ])).collect())),
+            ("env_delta", self.env_delta.as_ref().map_or(Json::Null, |delta| strings(delta))),
+        ])
+    }
+
+    fn print(&self, pid: u64) {
+        let unknown = |value: &Option<String>| value.clone().unwrap_or_else(|| "unknown".to_string());
+        print_proc_summary(pid);
+        println!("  {}", self.summary());
+        println!("  exe: {}", unknown(&self.exe));
+        println!("  command: {}", self.argv.iter().map(|arg| shell_word(arg)).collect::<Vec<_>>().join(" "));
+        println!("  cwd: {}", unknown(&self.cwd));
+        match self.started {
+            Some(time) => {
+                let ago = std::time::SystemTime::now().duration_since(time).map_or(0, |d| d.as_secs());
+                println!("  user: {}, started {} ({})", self.user, time_str(time), ago_str(ago));
+            }
+            None => println!("  user: {}", self.user),
+        }
+        println!("  login uid: {}", audit_ids_str(self.audit_ids.0, self.audit_ids.1));
+        println!("  tty: {}", self.tty.as_deref().unwrap_or("none"));
+        println!("  unit: {}", self.unit.as_deref().unwrap_or("none"));
+        let ancestors = self.ancestors.iter().map(|(ppid, comm)| format!("{} ({})", ppid, comm)).collect::<Vec<_>>();
+        println!("  ancestors: {}", if ancestors.is_empty() { "none".to_string() } else { ancestors.join(" <- ") });
+        match self.env_delta {
+            Some(ref delta) if delta.is_empty() => println!("  environment: the same as its parent's"),
+            Some(ref delta) => {
+                println!("  environment, compared with its parent's:");
+                for line in delta {
+                    println!("    {}", line);
+                }
+            }
+            None => println!("  environment: unable to compare it with its parent's"),
+        }
+    }
+}
+
+pub fn pstartup_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("", "json", "Print a JSON object for each process");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+    if matches.free.is_empty() && !selection.is_active() {
+        usage_err(program, opts);
+    }
+
+    let json = matches.opt_present("json");
+    let mut error = false;
+    for pid in ScanProgress::new(selection.pids(&matches.free), false) {
+        match Startup::read(pid) {
+            Ok(startup) if json => println!("{}", startup.json(pid)),
+            Ok(startup) => startup.print(pid),
+            Err(e) => {
+                eprintln!("Unable to read process {}: {}", pid, e);
+                error = true;
+            }
+        }
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+//
+// plock shows the file locks a process holds and those it is waiting for. /proc/locks lists every
+// lock on the system: POSIX record locks (fcntl(F_SETLK) and lockf()), which belong to a process,
+// OFD locks (fcntl(F_OFD_SETLK)) and flock()s, which belong to an open file, and leases. Each
+// request blocked on a lock is listed after it with '->', indented by one more space for each
+// request in turn blocked behind that one. OFD locks have no owning process (their pid is -1), but the fdinfo of
+// the fd a lock was taken through lists it, which ties it to a process and fd.
+//
+
+// A lock or a blocked request for one, as /proc/locks and fdinfo list it, e.g.
+// '1: POSIX  ADVISORY  WRITE 1234 08:01:5678 0 EOF'
+#[derive(Clone, Debug, PartialEq)]
+struct FileLock {
+    id: u64,
+    // 0 for a lock held, or how many requests deep a blocked one is
+    depth: usize,
+    // POSIX, OFDLCK, FLOCK, LEASE or DELEG
+    kind: String,
+    // READ, WRITE or UNLCK
+    access: String,
+    // None for OFD locks
+    pid: Option<u64>,
+    // The major and minor numbers of the filesystem and the inode of the file
+    dev: (u64, u64),
+    inode: u64,
+    // The range of bytes locked, which ends at EOF if end is None
+    start: u64,
+    end: Option<u64>,
+}
+
+impl FileLock {
+    fn parse(line: &str) -> Option<FileLock> {
+        let (id, rest) = line.trim().split_once(':')?;
+        // '1: -> FLOCK ...' is blocked on the lock, and '1:  -> FLOCK ...' on the request above it
+        let indented = rest.trim_start_matches(' ');
+        let (depth, rest) = match indented.strip_prefix("->") {
+            Some(request) => (rest.len() - indented.len(), request),
+            None => (0, rest),
+        };
+        let mut fields = rest.split_whitespace();
+        let kind = fields.next()?.to_string();
+        // ADVISORY or MANDATORY for locks, and the state of a lease
+        fields.next()?;
+        let access = fields.next()?.to_string();
+        let pid = fields.next()?.parse::<i64>().ok()?;
+        let mut file = fields.next()?.split(':');
+        let dev = (u64::from_str_radix(file.next()?, 16).ok()?, u64::from_str_radix(file.next()?, 16).ok()?);
+        let inode = file.next()?.parse().ok()?;
+        let start = fields.next()?.parse().ok()?;
+        let end = match fields.next()? {
+            "EOF" => None,
+            end => Some(end.parse().ok()?),
+        };
+        let pid = (pid >= 0).then_some(pid as u64);
+        Some(FileLock { id: id.parse().ok()?, depth, kind, access, pid, dev, inode, start, end })
+    }
+
+    // Whether this is the same lock as another, which lists may number differently
+    fn same(&self, other: &FileLock) -> bool {
+        (&self.kind, &self.access, self.pid, self.dev, self.inode, self.start, self.end) ==
+            (&other.kind, &other.access, other.pid, other.dev, other.inode, other.start, other.end)
+    }
+
+    fn describe(&self) -> String {
+        let range = match (self.kind.as_str(), self.end) {
+            ("POSIX" | "OFDLCK", Some(end)) => format!(", bytes {}-{}", self.start, end),
+            ("POSIX" | "OFDLCK", None) => format!(", bytes {}-EOF", self.start),
+            _ => String::new(),
+        };
+        format!("{} {} lock{}", self.kind, self.access, range)
+    }
+}
+
+fn parse_file_locks(contents: &str) -> Vec<FileLock> {
+    contents.lines().filter_map(FileLock::parse).collect()
+}
+
+// What is in the way of a blocked request: the lock, or else the request, one level up from it
+fn lock_blocker<'a>(locks: &'a [FileLock], request: &FileLock) -> Option<&'a FileLock> {
+    let at = locks.iter().position(|lock| lock == request)?;
+    locks[..at].iter().rev().take_while(|lock| lock.id == request.id)
+        .find(|lock| lock.depth + 1 == request.depth)
+}
+
+// The requests blocked on a lock, or on a request, directly
+fn lock_waiters<'a>(locks: &'a [FileLock], lock: &FileLock) -> Vec<&'a FileLock> {
+    locks.iter().filter(|&other| lock_blocker(locks, other) == Some(lock)).collect()
+}
+
+// An fd of a process, with the file it refers to and the locks taken through it
+struct LockFd {
+    fd: u64,
+    dev: (u64, u64),
+    inode: u64,
+    path: String,
+    locks: Vec<FileLock>,
+}
+
+fn lock_fds(pid: u64) -> Result<Vec<LockFd>, Box<dyn Error>> {
+    let mut fds = vec![];
+    for fd in read_fds(pid)? {
+        // The fd may have been closed since we listed them
+        let (Ok(st), Ok(fdinfo)) = (fd_stat(pid, fd), fs::read_to_string(proc_path!("{}/fdinfo/{}", pid, fd))) else {
+            continue;
+        };
+        let locks = fdinfo.lines().filter_map(|line| FileLock::parse(line.strip_prefix("lock:")?)).collect();
+        let path = read_proc_link(proc_path!("{}/fd/{}", pid, fd)).map_or_else(|_| "?".to_string(),
+                                                                         |path| path.to_string_lossy().into_owned());
+        fds.push(LockFd { fd, dev: (major(st.st_dev), minor(st.st_dev)), inode: st.st_ino, path, locks });
+    }
+    Ok(fds)
+}
+
+// Where the OFD locks and requests on the system are, to tell whose they are: every fd with such
+// a lock in its fdinfo, and every fd some process is waiting to take one through. This means
+// looking through every process, so it is only done once.
+#[derive(Default)]
+struct OfdLockOwners {
+    // The pid and fd, with the locks
+    held: Vec<(u64, u64, Vec<FileLock>)>,
+    // The pid and fd, with the device and inode of the file
+    waiting: Vec<(u64, u64, (u64, u64), u64)>,
+}
+
+impl OfdLockOwners {
+    fn read() -> Self {
+        let mut owners = OfdLockOwners::default();
+        for pid in all_pids() {
+            for fd in read_fds(pid).unwrap_or_default() {
+                let locks = fs::read_to_string(proc_path!("{}/fdinfo/{}", pid, fd)).unwrap_or_default().lines()
+                    .filter_map(|line| FileLock::parse(line.strip_prefix("lock:")?))
+                    .filter(|lock| lock.pid.is_none())
+                    .collect::<Vec<_>>();
+                if !locks.is_empty() {
+                    owners.held.push((pid, fd, locks));
+                }
+            }
+            for fd in fds_waiting_for_ofd_locks(pid) {
+                if let Ok(st) = fd_stat(pid, fd) {
+                    owners.waiting.push((pid, fd, (major(st.st_dev), minor(st.st_dev)), st.st_ino));
+                }
+            }
+        }
+        owners
+    }
+
+    // Which processes an OFD lock or request belongs to, through which of their fds
+    fn owners(&self, lock: &FileLock) -> Vec<(u64, u64)> {
+        match lock.depth {
+            0 => self.held.iter().filter(|(_, _, locks)| locks.iter().any(|l| l.same(lock)))
+                .map(|&(pid, fd, _)| (pid, fd)).collect(),
+            _ => self.waiting.iter().filter(|&&(_, _, dev, inode)| (dev, inode) == (lock.dev, lock.inode))
+                .map(|&(pid, fd, _, _)| (pid, fd)).collect(),
+        }
+    }
+}
+
+// Who holds a lock, or makes a request, e.g. '1234 (postgres)' or '1234 (postgres) through its fd 5'
+fn lock_owner_str(lock: &FileLock, ofd_owners: &OfdLockOwners) -> String {
+    let process = |pid: u64| {
+        let comm = fs::read_to_string(proc_path!("{}/comm", pid)).unwrap_or_default();
+        format!("{} ({})", pid, comm.trim_end())
+    };
+    match lock.pid {
+        Some(pid) => process(pid),
+        None => {
+            let owners = ofd_owners.owners(lock).into_iter()
+                .map(|(pid, fd)| format!("{} through its fd {}", process(pid), fd)).collect::<Vec<_>>();
+            match owners.is_empty() {
+                true => "an owner which can't be found".to_string(),
+                false => owners.join(", "),
+            }
+        }
+    }
+}
+
+// The fcntl() command which waits for an OFD lock (see include/uapi/asm-generic/fcntl.h)
+const F_OFD_SETLKW: u64 = 38;
+
+// The fds which threads of a process are waiting in fcntl() to take OFD locks through. Blocked OFD
+// requests have no pid either, and aren't in any fdinfo, so this is how to tell whose they are.
+fn fds_waiting_for_ofd_locks(pid: u64) -> Vec<u64> {
+    let mut fds = vec![];
+    for tid in task_ids(pid).unwrap_or_default() {
+        if let Ok(TaskSyscall::Syscall { nr, args }) = read_task_syscall(pid, tid) {
+            // 'int' arguments are passed in 64 bit registers, but only the low 32 bits matter
+            if matches!(syscall_name(nr).as_str(), "fcntl" | "fcntl64") && args[1] & 0xffffffff == F_OFD_SETLKW {
+                fds.push(args[0] & 0xffffffff);
+            }
+        }
+    }
+    fds
+}
+
+// Locks or requests of a process, each with the fd it is through, if the process still has one
+type ProcessLocks<'a> = Vec<(&'a FileLock, Option<&'a LockFd>)>;
+
+// The locks a process holds, and the requests it has waiting
+fn process_locks<'a>(pid: u64, locks: &'a [FileLock], fds: &'a [LockFd]) -> (ProcessLocks<'a>, ProcessLocks<'a>) {
+    let on_file = |lock: &FileLock| fds.iter().find(|fd| (fd.dev, fd.inode) == (lock.dev, lock.inode));
+    let mut held = vec![];
+    for lock in locks.iter().filter(|lock| lock.depth == 0) {
+        // A lock is listed in the fdinfo of the fd it was taken through
+        let through = fds.iter().find(|fd| fd.locks.iter().any(|l| l.same(lock)));
+        if through.is_some() || lock.pid == Some(pid) {
+            held.push((lock, through.or_else(|| on_file(lock))));
+        }
+    }
+    let blocked_fds = fds_waiting_for_ofd_locks(pid);
+    let mut waiting = vec![];
+    for lock in locks.iter().filter(|lock| lock.depth > 0) {
+        let blocked = fds.iter()
+            .find(|fd| blocked_fds.contains(&fd.fd) && (fd.dev, fd.inode) == (lock.dev, lock.inode));
+        match lock.pid {
+            Some(lock_pid) if lock_pid == pid => waiting.push((lock, on_file(lock))),
+            None if blocked.is_some() => waiting.push((lock, blocked)),
+            _ => {}
+        }
+    }
+    (held, waiting)
+}
+
+fn print_process_locks(pid: u64, locks: &[FileLock], ofd_owners: &OfdLockOwners) -> bool {
+    let fds = match lock_fds(pid) {
+        Ok(fds) => fds,
+        Err(e) => {
+            eprintln!("Unable to read the fds of process {}: {}", pid, e);
+            return false;
+        }
+    };
+    let (held, waiting) = process_locks(pid, locks, &fds);
+    let file_str = |lock: &FileLock, fd: Option<&LockFd>| match fd {
+        Some(fd) => format!("fd {} ({})", fd.fd, fd.path),
+        None => format!("file {:02x}:{:02x}:{}", lock.dev.0, lock.dev.1, lock.inode),
+    };
+
+    print_proc_summary(pid);
+    if held.is_empty() && waiting.is_empty() {
+        println!("  no locks");
+    }
+    for (lock, fd) in held {
+        println!("  holds {} on {}", lock.describe(), file_str(lock, fd));
+        for waiter in lock_waiters(locks, lock) {
+            println!("    blocking {} of {}", waiter.describe(), lock_owner_str(waiter, ofd_owners));
+        }
+    }
+    for (request, fd) in waiting {
+        println!("  waiting for {} on {}", request.describe(), file_str(request, fd));
+        match lock_blocker(locks, request) {
+            Some(blocker) if blocker.depth == 0 => {
+                println!("    held as {} by {}", blocker.describe(), lock_owner_str(blocker, ofd_owners))
+            }
+            Some(blocker) => println!("    behind {} of {}, itself waiting", blocker.describe(),
+                                      lock_owner_str(blocker, ofd_owners)),
+            None => println!("    behind a lock which is gone"),
+        }
+    }
+    true
+}
+
+pub fn plock_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        add_selection_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    refuse_capture(program, "/proc/locks lists the locks on the live system");
+    let selection = apply_selection_opts(program, &opts, &args[1..], &matches);
+    if matches.free.is_empty() && !selection.is_active() {
+        usage_err(program, opts);
+    }
+
+    let locks = match fs::read_to_string("/proc/locks") {
+        Ok(contents) => parse_file_locks(&contents),
+        Err(e) => {
+            eprintln!("Unable to read /proc/locks: {}", e);
+            exit(1);
+        }
+    };
+    let ofd_owners = match locks.iter().any(|lock| lock.pid.is_none()) {
+        true => OfdLockOwners::read(),
+        false => OfdLockOwners::default(),
+    };
+    let mut error = false;
+    for pid in ScanProgress::new(selection.pids(&matches.free), false) {
+        error = !print_process_locks(pid, &locks, &ofd_owners) || error;
+    }
+
+    if error {
+        exit(1);
+    }
+}
+
+pub fn pdoctor_main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let opts = {
+        let mut opts = Options::new();
+        opts.optflag("", "json", "Print the checks as a JSON object");
+        opts.optflag("h", "help", "print this help message");
+        add_common_opts(&mut opts);
+        opts.parsing_style(ParsingStyle::StopAtFirstFree);
+        opts
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage_err(program, opts);
+        }
+    };
+
+    if matches.opt_present("h") {
+        usage(program, opts);
+    }
+
+    apply_common_opts(program, &opts, &matches);
+    refuse_capture(program, "it checks the running kernel");
+    if !matches.free.is_empty() {
+        usage_err(program, opts);
+    }
+
+    let checks = doctor_checks();
+    let kernel = fs::read_to_string("/proc/sys/kernel/osrelease").map(|release| release.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+    if matches.opt_present("json") {
+        println!("{}", Json::object(vec![
+            ("kernel", Json::Str(kernel)),
+            ("checks", Json::Array(checks.iter().map(doctor_check_json).collect())),
+        ]));
+    } else {
+        println!("kernel: Linux {}", kernel);
+        for check in &checks {
+            print_doctor_check(check);
+        }
+    }
+    // Scripts can tell whether everything will work from the exit status
+    if checks.iter().any(|check| check.problem.is_some()) {
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_ipv4_sock_addr() {
+        assert_eq!(
+            parse_ipv4_sock_addr("0100007F:1538").unwrap(),
+            "127.0.0.1:5432".parse::<SocketAddr>().unwrap()
+        );
+
+        assert!(parse_ipv4_sock_addr("0100007F 1538").is_err());
+        assert!(parse_ipv4_sock_addr("010000YY:1538").is_err());
+        assert!(parse_ipv4_sock_addr("0100007F:15YY").is_err());
+
+        assert_eq!(parse_ipv6_sock_addr("00000000000000000000000001000000:1538").unwrap(),
+                   "[::1]:5432".parse::<SocketAddr>().unwrap());
+        assert_eq!(parse_ipv6_sock_addr("0000000000000000FFFF00000100007F:0016").unwrap(),
+                   "[::ffff:127.0.0.1]:22".parse::<SocketAddr>().unwrap());
+        assert!(parse_ipv6_sock_addr("0100007F:1538").is_err());
+    }
+
+    #[test]
+    fn test_read_only() {
+        assert!(MutateCap::acquire_in_mode(false, "do something").is_ok());
+        let err = MutateCap::acquire_in_mode(true, "do something").err().unwrap();
+        assert_eq!(err.to_string(), "Refusing to do something: running in read-only mode");
+    }
+
+    #[test]
+    fn test_parse_syscall() {
+        assert_eq!(parse_syscall("running\n").unwrap(), TaskSyscall::Running);
+        assert_eq!(
+            parse_syscall("-1 0x7ffd3a1b5f28 0x7f0d1c4e1a1b\n").unwrap(),
+            TaskSyscall::NotInSyscall
+        );
+
+        let nr = SYSCALL_NAMES.iter().position(|&name| name == "nanosleep").unwrap() as u64;
+        let syscall = parse_syscall(
+            &format!("{} 0x7ffd3a1b5f30 0x0 0x0 0x0 0x0 0x0 0x7ffd3a1b5f28 0x7f0d1c4e1a1b\n", nr)).unwrap();
+        assert_eq!(syscall, TaskSyscall::Syscall { nr, args: [0x7ffd3a1b5f30, 0, 0, 0, 0, 0] });
+        assert_eq!(describe_syscall(1, &syscall), "nanosleep(0x7ffd3a1b5f30, 0x0)");
+
+        assert!(parse_syscall("0 0x1 0x2\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_fd_range() {
+        assert_eq!(parse_fd_range("5").unwrap(), (5, 5));
+        assert_eq!(parse_fd_range("100-200").unwrap(), (100, 200));
+
+        assert!(parse_fd_range("").is_err());
+        assert!(parse_fd_range("200-100").is_err());
+        assert!(parse_fd_range("1-x").is_err());
+    }
+
+    #[test]
+    fn test_parse_smaps() {
+        let smaps = "\
+561e4693a000-561e4693c000 r--p 00000000 fe:00 280542                     /usr/bin/my  cat
+Size:                  8 kB
+Rss:                   8 kB
+AnonHugePages:         0 kB
+VmFlags: rd mr mw me
+7f0000000000-7f0000400000 rw-s 00000000 00:10 1234                       /dev/hugepages/seg
+Size:               4096 kB
+Shared_Hugetlb:     4096 kB
+THPeligible:           0
+VmFlags: rd wr sh mr mw me ms de ht
+7f1000000000-7f1000600000 rw-p 00000000 00:00 0
+Anonymous:          6144 kB
+AnonHugePages:      4096 kB
+";
+        let mappings = parse_smaps(smaps.as_bytes()).unwrap();
+        assert_eq!(mappings.len(), 3);
+
+        assert_eq!(mappings[0].start, 0x561e4693a000);
+        assert_eq!(mappings[0].kbytes(), 8);
+        assert_eq!(mappings[0].perms, "r--p");
+        assert_eq!((mappings[0].dev, mappings[0].inode), ((0xfe, 0), 280542));
+        assert_eq!(mappings[0].name(), "/usr/bin/my  cat");
+        assert_eq!(mappings[0].size("Rss"), 8);
+        assert!(!mappings[0].is_hugetlb());
+
+        assert!(mappings[1].is_hugetlb());
+        assert_eq!(mappings[1].size("Shared_Hugetlb"), 4096);
+
+        assert_eq!(mappings[2].name(), "[ anon ]");
+        assert_eq!(mappings[2].size("AnonHugePages"), 4096);
+        assert!(mappings[2].vm_flags.is_empty());
+
+        assert!(parse_smaps("Rss: 8 kB\n".as_bytes()).is_err());
+        assert!(parse_smaps("561e4693a000 r--p 00000000 fe:00 280542\n".as_bytes()).is_err());
+
+        assert_eq!(split_fields("a  b   c d", 2), Some((vec!["a", "b"], "c d")));
+        assert_eq!(split_fields("a b", 2), Some((vec!["a", "b"], "")));
+        assert_eq!(split_fields("a ", 2), None);
+    }
+
+    #[test]
+    fn test_futex_wait() {
+        let tids = [100, 101, 102];
+        // FUTEX_WAIT|FUTEX_PRIVATE_FLAG on a locked pthread mutex owned by 101
+        assert_eq!(futex_wait(0x80, 0x1000, &[2, 0, 101], &tids),
+            Some(FutexWait::Lock { addr: 0x1000, owner: Some(101) }));
+        // ... and on something which isn't a mutex
+        assert_eq!(futex_wait(0x80, 0x1000, &[0, 0, 101], &tids),
+            Some(FutexWait::Lock { addr: 0x1000, owner: None }));
+        // pthread_join() of 102
+        assert_eq!(futex_wait(0, 0x2000, &[102, 0, 0], &tids),
+            Some(FutexWait::Join { addr: 0x2000, tid: 102 }));
+        // FUTEX_LOCK_PI, with the FUTEX_WAITERS bit set in the futex word
+        assert_eq!(futex_wait(0x86, 0x3000, &[0x8000_0000 | 100], &tids),
+            Some(FutexWait::Lock { addr: 0x3000, owner: Some(100) }));
+        // FUTEX_WAKE isn't a wait
+        assert_eq!(futex_wait(1, 0x3000, &[0, 0, 0], &tids), None);
+    }
+
+    #[test]
+    fn test_find_wait_cycles() {
+        let graph = |edges: &[(u64, u64)]| edges.iter().cloned().collect::<HashMap<_, _>>();
+
+        assert!(find_wait_cycles(&graph(&[(1, 2), (2, 3)])).is_empty());
+        assert_eq!(find_wait_cycles(&graph(&[(3, 2), (2, 3), (4, 3)])), vec![vec![2, 3]]);
+        assert_eq!(find_wait_cycles(&graph(&[(5, 5)])), vec![vec![5]]);
+        assert_eq!(find_wait_cycles(&graph(&[(1, 2), (2, 1), (7, 9), (9, 8), (8, 7)])),
+            vec![vec![1, 2], vec![7, 9, 8]]);
+    }
+
+    #[test]
+    fn test_json() {
+        let json = Json::object(vec![
+            ("fd", Json::UInt(3)),
+            ("size", Json::Int(-1)),
+            ("path", Json::Str("/tmp/a \"b\"\\\n".to_string())),
+            ("flags", Json::Array(vec![Json::Str("O_RDONLY".to_string())])),
+        ]);
+        assert_eq!(
+            json.to_string(),
+            r#"{"fd":3,"size":-1,"path":"/tmp/a \"b\"\\\n","flags":["O_RDONLY"]}"#
+        );
+    }
+    #[test]
+    fn test_json_parse() {
+        let json = Json::parse(r#" {"id": -1, "params": {"pid": 42}, "a": [true, false, null],
+            "s": "x\"\u00e9\ud83d\ude00\n"} "#).unwrap();
+        assert_eq!(
+            json.to_string(),
+            "{\"id\":-1,\"params\":{\"pid\":42},\"a\":[true,false,null],\"s\":\"x\\\"\u{e9}\u{1f600}\\n\"}"
+        );
+        assert_eq!(json.get("params").and_then(|p| p.get("pid")).and_then(Json::as_u64), Some(42));
+        assert_eq!(json.get("id").and_then(Json::as_u64), None);
+
+        assert_eq!(Json::parse("[]").unwrap().to_string(), "[]");
+        assert!(Json::parse("").is_err());
+        assert!(Json::parse("[1,]").is_err());
+        assert!(Json::parse("{\"a\" 1}").is_err());
+        assert!(Json::parse("1.5").is_err());
+        assert!(Json::parse("\"\\ud83d\"").is_err());
+        assert!(Json::parse("[1] x").is_err());
+        assert!(Json::parse(&"[".repeat(1000)).is_err());
+    }
+
+    #[test]
+    fn test_rpc_handle() {
+        let cache = Mutex::new(SnapshotCache { ttl: Duration::from_secs(60), entries: HashMap::new() });
+        let pid = std::process::id();
+
+        let response = rpc_handle(
+            &format!(r#"{{"jsonrpc":"2.0","id":"a","method":"args","params":{{"pid":{}}}}}"#, pid),
+            &cache,
+        ).unwrap();
+        assert!(response.starts_with(r#"{"jsonrpc":"2.0","id":"a","result":[""#), "{}", response);
+
+        assert_eq!(
+            rpc_handle(r#"{"jsonrpc":"2.0","id":1,"method":"nope"}"#, &cache).unwrap(),
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32601,"message":"unknown method 'nope'"}}"#
+        );
+        assert!(rpc_handle(r#"{"jsonrpc":"2.0","id":2,"method":"env"}"#, &cache).unwrap()
+            .contains(r#""code":-32602"#));
+        assert!(rpc_handle("{", &cache).unwrap().contains(r#""id":null,"error":{"code":-32700"#));
+        // Notifications get no response
+        assert_eq!(rpc_handle(r#"{"jsonrpc":"2.0","method":"tree"}"#, &cache), None);
+    }
+
+    #[test]
+    fn test_parse_cgroup() {
+        let contents = "9:name=systemd:/user.slice/session-2.scope\n\
+                        4:cpu,cpuacct:/user.slice\n\
+                        0::/user.slice/session-2.scope\n";
+        let controller = |name: &str| CgroupHierarchy::Controller(name.to_string());
+
+        assert_eq!(parse_cgroup(contents, &CgroupHierarchy::Unified),
+                   Some("/user.slice/session-2.scope".to_string()));
+        assert_eq!(parse_cgroup(contents, &controller("cpuacct")), Some("/user.slice".to_string()));
+        assert_eq!(parse_cgroup(contents, &controller("name=systemd")),
+                   Some("/user.slice/session-2.scope".to_string()));
+        assert_eq!(parse_cgroup(contents, &controller("memory")), None);
+        assert_eq!(parse_cgroup("4:memory:/\n", &CgroupHierarchy::Unified), None);
+    }
+
+    #[test]
+    fn test_core_file() {
+        fn note(buf: &mut Vec<u8>, n_type: u32, desc: &[u8]) {
+            buf.extend_from_slice(&5u32.to_ne_bytes());
+            buf.extend_from_slice(&(desc.len() as u32).to_ne_bytes());
+            buf.extend_from_slice(&n_type.to_ne_bytes());
+            buf.extend_from_slice(b"CORE\0\0\0\0");
+            buf.extend_from_slice(desc);
+        }
+        fn phdr(buf: &mut Vec<u8>, p_type: u32, offset: u64, vaddr: u64, filesz: u64) {
+            buf.extend_from_slice(&p_type.to_ne_bytes());
+            buf.extend_from_slice(&[0; 4]);
+            for field in &[offset, vaddr, 0, filesz, filesz, 0] {
+                buf.extend_from_slice(&field.to_ne_bytes());
+            }
+        }
+
+        let mut siginfo = vec![];
+        for field in &[11u32, 0, 2, 0] {
+            siginfo.extend_from_slice(&field.to_ne_bytes());
+        }
+        siginfo.extend_from_slice(&0xdeadu64.to_ne_bytes());
+        let mut auxv = vec![];
+        for field in &[6u64, 4096, 31, 0x1000, 0, 0] {
+            auxv.extend_from_slice(&field.to_ne_bytes());
+        }
+        let mut notes = vec![];
+        note(&mut notes, NT_SIGINFO, &siginfo);
+        note(&mut notes, NT_AUXV, &auxv);
+        let memory = b"/bin/true\0";
+
+        let mut core = vec![0; 64];
+        core[..4].copy_from_slice(ELF_MAGIC);
+        core[4] = ELFCLASS64;
+        core[5] = ELFDATA_NATIVE;
+        core[16..18].copy_from_slice(&ET_CORE.to_ne_bytes());
+        core[32..40].copy_from_slice(&64u64.to_ne_bytes());
+        core[54..56].copy_from_slice(&56u16.to_ne_bytes());
+        core[56..58].copy_from_slice(&2u16.to_ne_bytes());
+        let notes_off = 64 + 2 * 56;
+        phdr(&mut core, PT_NOTE, notes_off, 0, notes.len() as u64);
+        phdr(&mut core, PT_LOAD, notes_off + notes.len() as u64, 0x1000, memory.len() as u64);
+        core.extend_from_slice(&notes);
+        core.extend_from_slice(memory);
+
+        // Counts and sizes past the end of a truncated or corrupt core are an error
+        let mut bad = core.clone();
+        bad[64 + 32..64 + 40].copy_from_slice(&u64::MAX.to_ne_bytes());
+        assert!(CoreFile::parse(std::io::Cursor::new(bad)).is_err());
+        let mut bad = core.clone();
+        bad[56..58].copy_from_slice(&0xfffeu16.to_ne_bytes());
+        assert!(CoreFile::parse(std::io::Cursor::new(bad)).is_err());
+
+        let mut core = CoreFile::parse(std::io::Cursor::new(core)).unwrap();
+        assert_eq!(describe_core_signal(&core),
+                   Some("SIGSEGV (11) code 2 (SEGV_ACCERR) addr 0xdead".to_string()));
+        assert_eq!(parse_auxv(core.note(NT_AUXV).unwrap()), vec![(6, 4096), (31, 0x1000)]);
+        assert_eq!(core.read_string(0x1000), Some("/bin/true".to_string()));
+        assert_eq!(core.read_string(0x1005), Some("true".to_string()));
+        assert_eq!(core.read_memory(0x2000, 1), None);
+        assert!(core.note(NT_PRPSINFO).is_none());
+
+        assert!(CoreFile::parse(std::io::Cursor::new(b"#!/bin/sh\n".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_find_shared_files() {
+        use std::os::unix::io::AsRawFd;
+
+        let path = format!("/tmp/ptools-test-shared.{}", std::process::id());
+        let file = File::create(&path).unwrap();
+        let dup = file.try_clone().unwrap();
+        let reopened = File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let pid = std::process::id() as u64;
+        let fds = [&file, &dup, &reopened].iter().map(|f| f.as_raw_fd() as u64).collect::<Vec<_>>();
+        let files = fds.iter().map(|&fd| file_info(pid, fd).unwrap()).collect::<Vec<_>>();
+
+        // Without kcmp() (e.g. when ptrace is restricted) the groups are guessed, which tells these
+        // apart too, as the reopened file has different flags
+        let shared = find_shared_files(pid, &files);
+        assert_eq!(shared.exact, kcmp(pid, pid, KCMP_FILE, fds[0], fds[1]).is_ok());
+        assert_eq!(shared.groups, vec![vec![fds[0], fds[1]]]);
+    }
+
+    #[test]
+    fn test_kcmp() {
+        let pid = std::process::id() as u64;
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+
+        assert_eq!(kcmp(pid, pid, KCMP_VM, 0, 0), Ok(true));
+        assert_eq!(kcmp(pid, child.id() as u64, KCMP_VM, 0, 0), Ok(false));
+        assert_eq!(kcmp(pid, child.id() as u64, KCMP_FILES, 0, 0), Ok(false));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_translate_path() {
+        // A container whose root is a directory in an overlay filesystem, and which has a volume
+        // bind mounted from the host.
+        let container = parse_mountinfo("\
+            600 500 0:52 / / rw - overlay overlay rw\n\
+            601 600 8:1 /srv/vol\\040a /data rw - ext4 /dev/sda1 rw\n");
+        let host = parse_mountinfo("\
+            22 1 8:1 / / rw - ext4 /dev/sda1 rw\n\
+            90 22 0:52 / /var/lib/docker/overlay2/abc/merged rw - overlay overlay rw\n");
+
+        assert_eq!(container[1].root, "/srv/vol a");
+        assert_eq!(translate_path("/app/bin", &container, &host),
+                   vec!["/var/lib/docker/overlay2/abc/merged/app/bin"]);
+        assert_eq!(translate_path("/", &container, &host), vec!["/var/lib/docker/overlay2/abc/merged"]);
+        assert_eq!(translate_path("/data/x", &container, &host), vec!["/srv/vol a/x"]);
+        assert_eq!(translate_path("/datax", &container, &host),
+                   vec!["/var/lib/docker/overlay2/abc/merged/datax"]);
+        assert_eq!(translate_path("/", &host, &container), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_process_selection() {
+        assert_eq!(parse_age("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_age("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_age("2d").unwrap(), Duration::from_secs(2 * 86400));
+        assert!(parse_age("5y").is_err());
+        assert!(parse_age("m").is_err());
+        assert!(parse_age("99999999999999999999w").is_err());
+
+        let stat = "42 (a (weird) name) S 1 42 42 0 -1 4194560";
+        assert_eq!(stat_field(stat, 3), Some("S"));
+        assert_eq!(stat_field(stat, 4), Some("1"));
+        assert_eq!(stat_field(stat, 22), None);
+
+        // We were started just now, and init long ago
+        let me = std::process::id() as u64;
+        let young = ProcessSelection { younger_than: Some(Duration::from_secs(3600)), older_than: None, exes: vec![],
+                                       ..Default::default() };
+        assert!(young.matches(me));
+        let old = ProcessSelection { younger_than: None, older_than: Some(Duration::from_secs(3600)), exes: vec![],
+                                     ..Default::default() };
+        assert!(!old.matches(me));
+        assert_eq!(young.pids(&[me.to_string()]), vec![me]);
+        assert!(young.pids(&[]).contains(&me));
+
+        let uid = unsafe { libc::getuid() };
+        let user = |uid| ProcessSelection { younger_than: None, older_than: None, exes: vec![], users: vec![uid],
+                                          ..Default::default() };
+        assert!(user(uid).is_active());
+        assert!(user(uid).matches(me));
+        assert!(!user(uid + 1).matches(me));
+    }
+
+    #[test]
+    fn test_env_warnings() {
+        let env = ["PATH=/bin", "HOME=/root", "junk", "PATH=/usr/bin", "LONG=xxxxxxxx", "EMPTY="]
+            .iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(env_warnings(&env, Some(5)), vec![
+            "envp[2] has no '=', so isn't a variable",
+            "PATH is set 2 times (envp[0], envp[3])",
+            "PATH (envp[3]) is 8 bytes long",
+            "LONG (envp[4]) is 8 bytes long",
+        ]);
+        assert_eq!(env_warnings(&env[..2], None), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_epoll_fdinfo() {
+        let fdinfo = "pos:\t0\nflags:\t02\nmnt_id:\t15\nino:\t1057\n\
+                      tfd:        3 events:       19 data:                0  pos:0 ino:2a9f sdev:e\n\
+                      tfd:       10 events: 80000004 data:       7f12345678  pos:0 ino:31 sdev:9\n";
+        assert_eq!(parse_epoll_fdinfo(fdinfo), vec![
+            EpollWatch { tfd: 3, events: 0x19, data: 0, ino: 0x2a9f },
+            EpollWatch { tfd: 10, events: 0x8000_0004, data: 0x7f_1234_5678, ino: 0x31 },
+        ]);
+        assert_eq!(epoll_events_strs(0x19), vec!["EPOLLIN", "EPOLLERR", "EPOLLHUP"]);
+        assert_eq!(epoll_events_strs(0x8000_0004), vec!["EPOLLOUT", "EPOLLET"]);
+
+        assert_eq!(parse_watch_activity("pos:\t0\nflags:\t02\neventfd-count:       1f\n"),
+                   WatchActivity { pos: 0, pending: Some(31) });
+        assert_eq!(parse_watch_activity("pos:\t0\nclockid: 1\nticks: 3\n"),
+                   WatchActivity { pos: 0, pending: Some(3) });
+        assert_eq!(parse_watch_activity("pos:\t4096\nflags:\t0100000\n"),
+                   WatchActivity { pos: 4096, pending: None });
+    }
+
+    #[test]
+    fn test_read_namespaces() {
+        let me = std::process::id() as u64;
+        let namespaces = read_namespaces(me).unwrap();
+        assert_eq!(namespaces.len(), NAMESPACE_TYPES.len());
+        // Every kernel we run on has network namespaces
+        assert!(namespaces[2].is_some());
+        assert_eq!(read_namespaces(me).unwrap(), namespaces);
+        assert!(read_namespaces(u32::MAX as u64).is_err());
+
+        assert!(!is_kernel_thread(me));
+    }
+
+    #[test]
+    fn test_proc_exit_events() {
+        assert_eq!(wait_status_str(3 << 8), "exit status 3");
+        assert_eq!(wait_status_str(9), "killed by SIGKILL (9)");
+        assert_eq!(wait_status_str(0x80 | 11), "killed by SIGSEGV (11), core dumped");
+
+        // An exit of a process, then of a thread of it
+        let event = |pid: u32, tgid: u32, code: u32| {
+            let len = NLMSG_HDR_LEN + CN_MSG_LEN + 40;
+            let mut msg = vec![0u8; len];
+            msg[0..4].copy_from_slice(&(len as u32).to_ne_bytes());
+            let event = NLMSG_HDR_LEN + CN_MSG_LEN;
+            msg[event..event + 4].copy_from_slice(&PROC_EVENT_EXIT.to_ne_bytes());
+            msg[event + 16..event + 20].copy_from_slice(&pid.to_ne_bytes());
+            msg[event + 20..event + 24].copy_from_slice(&tgid.to_ne_bytes());
+            msg[event + 24..event + 28].copy_from_slice(&code.to_ne_bytes());
+            msg
+        };
+        let mut buf = event(100, 100, 1 << 8);
+        buf.extend(event(101, 100, 0));
+        assert_eq!(parse_proc_events(&buf), vec![ProcEvent::Exit { pid: 100, status: 1 << 8 }]);
+        assert_eq!(parse_proc_events(&buf[..10]), vec![]);
+    }
+
+    #[test]
+    fn test_device_info() {
+        let sysfs = PathBuf::from(format!("/tmp/ptools-test-sysfs.{}", std::process::id()));
+        let _ = fs::remove_dir_all(&sysfs);
+        let add = |path: &str, contents: &str| {
+            let path = sysfs.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+        add("dev/block/7:0/uevent", "MAJOR=7\nMINOR=0\nDEVNAME=loop0\nDEVTYPE=disk\n");
+        add("dev/block/7:0/loop/backing_file", "/var/tmp/disk.img\n");
+        add("dev/block/253:1/uevent", "DEVNAME=dm-1\n");
+        add("dev/block/253:1/dm/name", "vg-root\n");
+        add("dev/block/253:1/slaves/sda2/dev", "8:2\n");
+        add("dev/block/43:0/uevent", "DEVNAME=nbd0\n");
+        add("dev/char/226:128/uevent", "DEVNAME=dri/renderD128\n");
+        fs::create_dir_all(sysfs.join("drivers/amdgpu")).unwrap();
+        fs::create_dir_all(sysfs.join("dev/char/226:128/device")).unwrap();
+        std::os::unix::fs::symlink(sysfs.join("drivers/amdgpu"), sysfs.join("dev/char/226:128/device/driver"))
+            .unwrap();
+
+        // The old 16 bit encoding, which major() and minor() still decode for small numbers
+        let rdev = |major: u64, minor: u64| (major << 8) | minor;
+        assert_eq!(device_info(&sysfs, true, rdev(7, 0), ""), DeviceInfo {
+            name: Some("loop0".to_string()),
+            details: vec!["loop device backed by /var/tmp/disk.img".to_string()],
+        });
+        assert_eq!(device_info(&sysfs, true, rdev(253, 1), "").details, vec!["device mapper device vg-root on sda2"]);
+        assert_eq!(device_info(&sysfs, true, rdev(43, 0), "").details, vec!["nbd device, not connected"]);
+
+        let fdinfo = "pos:\t0\nflags:\t02100002\ndrm-driver:\tamdgpu\ndrm-client-id:\t42\n\
+                      drm-engine-gfx:\t2500000000 ns\ndrm-engine-capacity-gfx:\t1\ndrm-total-vram:\t1024 KiB\n";
+        assert_eq!(device_info(&sysfs, false, rdev(226, 128), fdinfo), DeviceInfo {
+            name: Some("dri/renderD128".to_string()),
+            details: vec!["DRM render node, driver amdgpu".to_string(),
+                          "client 42; busy: gfx 2500ms; memory: vram 1024 KiB".to_string()],
+        });
+
+        // Not in sysfs at all
+        assert_eq!(device_info(&sysfs, false, rdev(1, 3), ""), DeviceInfo::default());
+
+        fs::remove_dir_all(&sysfs).unwrap();
+    }
+
+    #[test]
+    fn test_capture_tar() {
+        let long_path = format!("proc/{}/fdinfo/3", "x".repeat(120));
+        let mut tar = TarWriter::new(vec![], 0);
+        tar.add_dir("proc/1").unwrap();
+        tar.add_file("proc/1/comm", b"init\n").unwrap();
+        tar.add_file(&long_path, &[b'y'; 1000]).unwrap();
+        let archive = tar.finish().unwrap();
+        assert_eq!(archive.len() % TAR_BLOCK, 0);
+
+        let dest = env::temp_dir().join(format!("ptools-test-tar.{}", std::process::id()));
+        fs::create_dir(&dest).unwrap();
+        unpack_tar(&mut &archive[..], &dest).unwrap();
+        assert!(dest.join("proc/1").is_dir());
+        assert_eq!(fs::read(dest.join("proc/1/comm")).unwrap(), b"init\n");
+        assert_eq!(fs::read(dest.join(&long_path)).unwrap(), vec![b'y'; 1000]);
+
+        // Nothing may be written outside of the destination
+        let mut tar = TarWriter::new(vec![], 0);
+        tar.add_file("proc/../escaped", b"").unwrap();
+        let archive = tar.finish().unwrap();
+        assert!(unpack_tar(&mut &archive[..], &dest).is_err());
+        assert!(!dest.join("escaped").exists());
+
+        let mut corrupt = TarWriter::new(vec![], 0);
+        corrupt.add_file("proc/2/comm", b"sh\n").unwrap();
+        let mut corrupt = corrupt.finish().unwrap();
+        corrupt[0] = b'q';
+        assert!(unpack_tar(&mut &corrupt[..], &dest).is_err());
+        fs::remove_dir_all(&dest).unwrap();
+
+        let st = parse_fd_stat("mode 8592\ndev 6\nino 3\nrdev 259\nsize 0\nnlink 1\nuid 0\ngid 5\n").unwrap();
+        assert_eq!((st.st_mode, st.st_ino, st.st_rdev, st.st_gid), (8592, 3, 259, 5));
+        assert_eq!(format_fd_stat(&st), "mode 8592\ndev 6\nino 3\nrdev 259\nsize 0\nnlink 1\nuid 0\ngid 5\n");
+        assert!(parse_fd_stat("mode\n").is_err());
+    }
+
+    #[test]
+    fn test_process_id() {
+        let stat = "42 (a (b) c) S 1 42 42 0 -1 4194560 100 0 0 0 3 4 0 0 20 0 1 0 123456 1000 10";
+        let id = ProcessId::from_stat(42, stat).unwrap();
+        assert_eq!(id, ProcessId { pid: 42, starttime: 123456 });
+        assert_eq!(id.to_string(), "42:123456");
+        assert_eq!(ProcessId::from_stat(42, "42 (sh) S 1"), None);
+
+        // Two processes had pid 42 in turn, and a third has it now
+        let exited_process = || ExitedProcess { ppid: 1, summary: String::new(), status: None,
+                                                noticed: std::time::UNIX_EPOCH, refreshes_left: 1 };
+        let mut exited = HashMap::new();
+        exited.insert(ProcessId { pid: 42, starttime: 200 }, exited_process());
+        exited.insert(ProcessId { pid: 42, starttime: 100 }, exited_process());
+        let mut statuses = ExitStatuses::new();
+        statuses.insert(42, vec![(3 << 8, 2), (9, 2), (0, 2)].into_iter().collect());
+        assign_exit_statuses(&mut exited, &mut statuses);
+        assert_eq!(exited[&ProcessId { pid: 42, starttime: 100 }].status.as_ref().unwrap(), "exit status 3");
+        assert_eq!(exited[&ProcessId { pid: 42, starttime: 200 }].status.as_ref().unwrap(), "killed by SIGKILL (9)");
+        assert_eq!(statuses[&42].len(), 1);
+    }
+
+    #[test]
+    fn test_ioprio_str() {
+        assert_eq!(ioprio_str(0, Some(0)), "none (best-effort/4 from nice 0)");
+        assert_eq!(ioprio_str(0, Some(19)), "none (best-effort/7 from nice 19)");
+        assert_eq!(ioprio_str(0, None), "none");
+        assert_eq!(ioprio_str((1 << IOPRIO_CLASS_SHIFT) | 2, None), "realtime/2");
+        assert_eq!(ioprio_str((2 << IOPRIO_CLASS_SHIFT) | 7, Some(0)), "best-effort/7");
+        assert_eq!(ioprio_str(3 << IOPRIO_CLASS_SHIFT, None), "idle");
+    }
+
+    #[test]
+    fn test_taskstats() {
+        // An attribute holding two nested ones, the second padded to 4 bytes
+        let mut buf = vec![];
+        buf.extend_from_slice(&20u16.to_ne_bytes());
+        buf.extend_from_slice(&TASKSTATS_TYPE_AGGR_TGID.to_ne_bytes());
+        buf.extend_from_slice(&8u16.to_ne_bytes());
+        buf.extend_from_slice(&2u16.to_ne_bytes());
+        buf.extend_from_slice(&42u32.to_ne_bytes());
+        buf.extend_from_slice(&5u16.to_ne_bytes());
+        buf.extend_from_slice(&TASKSTATS_TYPE_STATS.to_ne_bytes());
+        buf.extend_from_slice(&[7, 0, 0, 0]);
+        let attrs = netlink_attrs(&buf);
+        assert_eq!(attrs.len(), 1);
+        assert_eq!(attrs[0].0, TASKSTATS_TYPE_AGGR_TGID);
+        let nested = netlink_attrs(attrs[0].1);
+        assert_eq!(nested, vec![(2, &42u32.to_ne_bytes()[..]), (TASKSTATS_TYPE_STATS, &[7][..])]);
+
+        // A version 5 struct taskstats has the reclaim delay, but not the later ones
+        let mut stats = vec![0u8; 352];
+        stats[..2].copy_from_slice(&5u16.to_ne_bytes());
+        for &(offset, value) in &[(64, 1000u64), (24, 200), (40, 30), (56, 4), (320, 5)] {
+            stats[offset..offset + 8].copy_from_slice(&value.to_ne_bytes());
+        }
+        assert_eq!(parse_taskstats(&stats, true),
+                   vec![Some(1000), Some(200), Some(30), Some(4), Some(5), None, None]);
+        // Without delay accounting, only the scheduler's times mean anything
+        assert_eq!(parse_taskstats(&stats, false), vec![Some(1000), Some(200), None, None, None, None, None]);
+        assert_eq!(parse_taskstats(&stats[..10], true), vec![None; 7]);
+    }
+
+    #[test]
+    fn test_service_env() {
+        assert_eq!(systemd_service("/system.slice/nginx.service"), Some("nginx.service".to_string()));
+        assert_eq!(systemd_service("/system.slice/docker.service/payload"), Some("docker.service".to_string()));
+        assert_eq!(systemd_service("/user.slice/user-1000.slice/user@1000.service/app.slice/foo.service"), None);
+        assert_eq!(systemd_service("/user.slice/user-1000.slice/session-2.scope"), None);
+
+        assert_eq!(split_unit_words(r#"A=1 "B=two words" 'C=it''s' D=x\x20y"#),
+                   vec!["A=1", "B=two words", "C=its", "D=x y"]);
+        assert_eq!(split_unit_words("  "), Vec::<String>::new());
+
+        let file = "# comment\n; another\nA=1\n B = spaced \nC=\"quoted value\"\nD=con\\\ntinued\nnonsense\n";
+        let vars = parse_env_file(file);
+        let vars = vars.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect::<Vec<_>>();
+        assert_eq!(vars, vec![("A", "1"), ("B", "spaced"), ("C", "quoted value"), ("D", "continued")]);
+
+        let mut expected = HashMap::new();
+        let var = |value: Option<&str>, source: &str| ExpectedVar { value: value.map(str::to_string), source: source.to_string() };
+        expected.insert("PATH".to_string(), var(None, "systemd"));
+        expected.insert("MODE".to_string(), var(Some("prod"), "Environment= of app.service"));
+        expected.insert("PORT".to_string(), var(Some("80"), "/etc/default/app"));
+        expected.insert("TZ".to_string(), var(Some("UTC"), "the service manager"));
+        let env = ["PATH=/bin", "MODE=debug", "LC_ALL=C", "EXTRA=1"].iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(service_env_warnings(&env, "app.service", &expected), vec![
+            "MODE (envp[1]) is 'debug', but Environment= of app.service sets it to 'prod'",
+            "EXTRA (envp[3]) isn't set by app.service or the service manager, so was added after it was started",
+            "/etc/default/app sets PORT, but it isn't in the environment",
+        ]);
+    }
+
+    #[test]
+    fn test_overlay_origin() {
+        assert_eq!(overlay_layers("rw,lowerdir=/l/a\\:b:/l/c::/data,upperdir=/u,workdir=/w,uuid=on"), OverlayLayers {
+            upper: Some("/u".to_string()),
+            lower: vec!["/l/a:b".to_string(), "/l/c".to_string(), "/data".to_string()],
+        });
+        assert_eq!(overlay_layers("ro,lowerdir+=/a,lowerdir+=/b\\,c").lower, vec!["/a", "/b,c"]);
+
+        let mounts = parse_mountinfo("\
+            600 500 0:52 / / rw - overlay overlay rw,lowerdir=/l1:/l2,upperdir=/u,workdir=/w\n\
+            601 600 8:1 / /data rw - ext4 /dev/sda1 rw\n");
+        assert_eq!(mounts[0].fs_type, "overlay");
+        let files = [("/u/etc/conf", 10), ("/l1/etc/conf", 15), ("/l2/etc/conf", 20), ("/l1/bin/sh", 30),
+                     ("/l2/bin/sh", 40), ("/u/new", 50)].iter().cloned().collect::<HashMap<_, _>>();
+        let lookup = |path: &str| files.get(path).cloned();
+        let origin = |path, ino| overlay_origin(path, "0:52", ino, &mounts, &lookup);
+
+        // Copied up files keep the inode number of the file they were copied from
+        assert_eq!(origin("/etc/conf", 20), Some(OverlayOrigin {
+            lower: None, dir: "/u".to_string(), copied_from: Some(2),
+        }));
+        assert_eq!(origin("/etc/conf", 99).map(|o| o.copied_from), Some(Some(1)));
+        assert_eq!(origin("/bin/sh", 30), Some(OverlayOrigin {
+            lower: Some(1), dir: "/l1".to_string(), copied_from: None,
+        }));
+        assert_eq!(origin("/new", 50).map(|o| (o.lower, o.copied_from)), Some((None, None)));
+        assert_eq!(origin("/missing", 1), None);
+        assert_eq!(overlay_origin("/data/x", "8:1", 1, &mounts, &lookup), None);
+    }
+
+    #[test]
+    fn test_man_page() {
+        let mut opts = Options::new();
+        opts.optflag("h", "help", "print this help message");
+        opts.optopt("o", "output", "write the archive to FILE", "FILE");
+        opts.optflag("", "read-only", "refuse to use any feature which changes the state of a process");
+        opts.optopt("", "an-unusually-long-option", "a description long enough to be wrapped onto a second line \
+                                                     of the usage message", "ARG");
+        assert_eq!(usage_rows(&opts), vec![
+            ("-h, --help".to_string(), "print this help message".to_string()),
+            ("-o, --output FILE".to_string(), "write the archive to FILE".to_string()),
+            ("--read-only".to_string(), "refuse to use any feature which changes the state of a process".to_string()),
+            ("--an-unusually-long-option ARG".to_string(),
+             "a description long enough to be wrapped onto a second line of the usage message".to_string()),
+        ]);
+
+        let page = man_page("/usr/bin/pcapture2", &opts);
+        assert!(page.starts_with(".TH PCAPTURE2 1 "));
+        assert!(page.contains("\npcapture2 \\- archive the state of a process"));
+        assert!(page.contains("\n[\\fIOPTION\\fR]... PID\n"));
+        assert!(page.contains("\n.TP\n\\fB\\-o\\fR, \\fB\\-\\-output\\fR \\fIFILE\\fR\nwrite the archive to FILE\n"));
+        assert!(page.contains("\n.B PTOOLS_READ_ONLY\n"));
+        assert!(page.contains(".BR pfiles2 (1)"));
+        assert!(!page.contains(".BR pcapture2 (1)"));
+        assert_eq!(roff_escape(".x \\ y"), "\\&.x \\e y");
+    }
+
+    #[test]
+    fn test_stack_formats() {
+        let frame = |pc, symbol: Option<(&str, u64)>, module: Option<&str>| StackFrame {
+            pc, symbol: symbol.map(|(name, offset)| (name.to_string(), offset)), module: module.map(str::to_string),
+        };
+        let poll = || vec![frame(0x7f00, Some(("poll", 0x4d)), Some("/lib/libc.so.6")),
+                           frame(0x5500, None, Some("/usr/bin/app (deleted)")),
+                           frame(0x10, None, None)];
+        let stacks = vec![
+            ThreadStack { tid: 10, comm: Some("app (main)".to_string()),
+                          header: "--- thread 10 (app (main))  S (sleeping)  cpu 1 ---".to_string(), frames: poll() },
+            ThreadStack { tid: 11, comm: None, header: "--- thread 11 ---".to_string(), frames: poll() },
+        ];
+
+        assert_eq!(tid_ranges(&[101, 102, 103, 120, 122, 123]), "101-103, 120, 122-123");
+        let mut pool = (12..16).map(|tid| ThreadStack {
+            tid, comm: None, header: format!("--- thread {} ---", tid), frames: poll(),
+        }).collect::<Vec<_>>();
+        pool[2].frames.pop();
+        pool.push(ThreadStack { tid: 16, comm: None, header: "--- thread 16  [wait channel only] ---".to_string(),
+                                frames: vec![] });
+        pool.push(ThreadStack { tid: 17, comm: None, header: "--- thread 17  [wait channel only] ---".to_string(),
+                                frames: vec![] });
+        let lines = dedup_stack_lines(&pool, false);
+        assert_eq!(lines.iter().filter(|line| line.starts_with("---")).collect::<Vec<_>>(), vec![
+            "--- x 3 threads (tids 12-13, 15) ---",
+            "--- thread 14 ---",
+            "--- thread 16  [wait channel only] ---",
+            "--- thread 17  [wait channel only] ---",
+        ]);
+        assert_eq!(lines.len(), 4 + 3 + 2);
+        assert_eq!(dedup_stack_lines(&pool[..2], true)[0], "--- \u{d7} 2 threads (tids 12-13) ---");
+
+        let lines = stack_lines(&stacks);
+        assert_eq!(lines[1], " 0000000000007f00 poll+0x4d (/lib/libc.so.6)");
+        assert_eq!(lines[2], " 0000000000005500 ??? (/usr/bin/app (deleted))");
+        assert_eq!(lines[3], " 0000000000000010 ???");
+        assert_eq!(parse_stack_lines(&(lines.join("\n") + "\n")), stacks);
+
+        assert_eq!(format_stacks(10, &stacks[..1], StackFormat::Gdb), vec![
+            "",
+            "Thread 1 (LWP 10 \"app (main)\"):",
+            "#0  0x0000000000007f00 in poll () from /lib/libc.so.6",
+            "#1  0x0000000000005500 in ?? () from /usr/bin/app (deleted)",
+            "#2  0x0000000000000010 in ?? ()",
+        ]);
+        assert_eq!(format_stacks(10, &stacks[..1], StackFormat::EuStack), vec![
+            "PID 10 - process",
+            "TID 10:",
+            "#0  0x0000000000007f00 poll",
+            "#1  0x0000000000005500",
+            "#2  0x0000000000000010",
+        ]);
+        assert_eq!(format_stacks(10, &stacks, StackFormat::Folded), vec![
+            "app (main);[unknown];[app (deleted)];poll 1",
+            "[unknown];[app (deleted)];poll 1",
+        ]);
+        assert_eq!(StackFormat::parse("eu-stack"), Some(StackFormat::EuStack));
+        assert_eq!(StackFormat::parse("lldb"), None);
+    }
+
+    #[test]
+    fn test_follow() {
+        let mut follow = Follow::new(100);
+        let describe = |pid| format!("cmd of {}", pid);
+        let events = [
+            ProcEvent::Fork { parent: 1, child: 99 },
+            ProcEvent::Exec { pid: 100 },
+            ProcEvent::Fork { parent: 100, child: 101 },
+            ProcEvent::Fork { parent: 100, child: 102 },
+            ProcEvent::Exit { pid: 101, status: 0 },
+            // The usual double fork: the child forks the daemon and exits, then so does the parent
+            ProcEvent::Fork { parent: 102, child: 103 },
+            ProcEvent::Exit { pid: 102, status: 0 },
+            ProcEvent::Exit { pid: 100, status: 0 },
+            ProcEvent::Exec { pid: 103 },
+        ];
+        for event in &events {
+            follow.handle(event, &describe);
+        }
+        assert_eq!(follow.pid, 103);
+        assert!(!follow.exited);
+        assert!(!follow.is_tracked(99));
+        assert_eq!(follow.history, vec![
+            "100 exec'd: cmd of 100",
+            "100 exited (exit status 0), following its child 103",
+            "103 exec'd: cmd of 103",
+        ]);
+
+        follow.handle(&ProcEvent::Exit { pid: 103, status: 9 }, &describe);
+        assert!(follow.exited);
+        assert_eq!(follow.history.last().unwrap(), "103 exited (killed by SIGKILL (9))");
+    }
+
+    #[test]
+    fn test_project_quota() {
+        let mut dqblk = [0u8; IF_DQBLK_LEN];
+        for (i, value) in [10u64 << 20, 0, 9_961_472 * 1024, 1000, 800, 10].iter().enumerate() {
+            dqblk[i * 8..i * 8 + 8].copy_from_slice(&value.to_ne_bytes());
+        }
+        let usage = parse_if_dqblk(&dqblk);
+        assert_eq!(usage.bytes_hard, 10 << 30);
+        assert!(quota_nearly_full(&usage));
+        let project = ProjectQuota { id: 42, usage: Some(usage) };
+        assert_eq!(project_quota_str(&project), "project 42 quota: 9.5G of 10.0G used, 10 of 800 (soft) / 1000 inodes");
+
+        let usage = QuotaUsage { bytes: 100, bytes_soft: 0, bytes_hard: 0, inodes: 95, inodes_soft: 100, inodes_hard: 0 };
+        assert!(quota_nearly_full(&usage));
+        assert!(!quota_nearly_full(&QuotaUsage { inodes: 10, ..usage }));
+        assert_eq!(project_quota_str(&ProjectQuota { id: 7, usage: None }), "project 7");
+        assert_eq!(parse_if_dqblk(&[0xff; IF_DQBLK_LEN]).bytes_hard, u64::MAX);
+        assert_eq!(size_str(512), "512B");
+        assert_eq!(size_str(1536), "1.5K");
+    }
+
+    #[test]
+    fn test_exe_filter() {
+        assert_eq!(ExeFilter::parse("8:1:1234"), ExeFilter { path: None, file: Some((8, 1, 1234)) });
+        let missing = ExeFilter::parse("/no/such/bin");
+        assert_eq!(missing, ExeFilter { path: Some(PathBuf::from("/no/such/bin")), file: None });
+        assert!(missing.matches(Some(Path::new("/no/such/bin (deleted)")), Some((8, 1, 5))));
+        assert!(!missing.matches(Some(Path::new("/no/such/bin2")), None));
+        assert!(ExeFilter::parse("8:1:1234").matches(Some(Path::new("/renamed")), Some((8, 1, 1234))));
+        assert!(!ExeFilter::parse("8:1:1234").matches(None, None));
+
+        // Our own executable, by its path and by its inode
+        let me = std::process::id() as u64;
+        let exe = fs::read_link(format!("/proc/{}/exe", me)).unwrap();
+        let st = stat(&exe).unwrap();
+        let by_inode = format!("{}:{}:{}", major(st.st_dev), minor(st.st_dev), st.st_ino);
+        for arg in &[exe.to_str().unwrap(), by_inode.as_str()] {
+            let selection = ProcessSelection { younger_than: None, older_than: None, exes: vec![ExeFilter::parse(arg)],
+                                               ..Default::default() };
+            assert!(selection.is_active());
+            assert!(selection.matches(me), "{}", arg);
+            assert!(!selection.matches(1) || fs::read_link("/proc/1/exe").ok() == Some(exe.clone()));
+        }
+    }
+
+    #[test]
+    fn test_memory_totals() {
+        let rollup = "55d0c0e00000-7ffc4e5fe000 ---p 00000000 00:00 0                          [rollup]\n\
+                      Rss:                3000 kB\nPss:                1500 kB\nPss_Anon:            700 kB\n\
+                      Shared_Clean:       1800 kB\nPrivate_Clean:       200 kB\nPrivate_Dirty:       1000 kB\n\
+                      KSM:                 800 kB\nSwap:                 64 kB\nSwapPss:              64 kB\n";
+        let totals = parse_memory_totals(rollup);
+        assert_eq!(totals, MemoryTotals { rss: 3000, pss: 1500, uss: 1200, swap: 64, ksm: 800, zero: 0 });
+        // smaps has a set of fields for each mapping
+        assert_eq!(parse_memory_totals(&rollup.repeat(2)).pss, 3000);
+
+        let small = MemoryTotals { rss: 10, pss: 5, uss: 1, zero: 3, ..Default::default() };
+        let groups = group_memory_totals(&[("www".to_string(), small), ("root".to_string(), totals),
+                                           ("www".to_string(), small)]);
+        assert_eq!(groups, vec![("root".to_string(), 1, totals),
+                                ("www".to_string(), 2, MemoryTotals { rss: 20, pss: 10, uss: 2, zero: 6,
+                                                                      ..Default::default() })]);
+        assert_eq!(parse_ksm_zero_pages("ksm_rmap_items 120\nksm_zero_pages 42\nksm_merging_pages 7\n"), Some(42));
+        assert_eq!(parse_ksm_zero_pages("ksm_rmap_items 120\n"), None);
+        assert_eq!(MemoryGroup::parse("cgroup"), Some(MemoryGroup::Cgroup));
+        assert_eq!(MemoryGroup::parse("pid"), None);
+
+        let me = std::process::id() as u64;
+        assert!(read_memory_totals(me).unwrap().rss > 0);
+        // pid 1 needn't be root's, e.g. in a container
+        let status = fs::read_to_string("/proc/1/status").unwrap();
+        let uid = status.lines().find_map(|line| line.strip_prefix("Uid:"))
+            .and_then(|uids| uids.split_whitespace().next()?.parse::<libc::uid_t>().ok()).unwrap();
+        assert_eq!(process_user(1), user_name(uid));
+    }
+
+    #[test]
+    fn test_env_hazards() {
+        let env = ["PATH=/usr/bin:/opt/gone/bin::bin", "LD_LIBRARY_PATH=/opt/lib", "LD_PRELOAD=libfaketime.so"]
+            .iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let exists = |dir: &str| dir.starts_with("/usr");
+        let hazards = env_hazards(&env, Some(&exists));
+        assert_eq!(hazards.len(), 6, "{:?}", hazards);
+        assert!(hazards[0].starts_with("LD_PRELOAD is set to 'libfaketime.so'"));
+        assert_eq!(hazards[1], "LD_LIBRARY_PATH has '/opt/lib', which doesn't exist");
+        assert_eq!(hazards[2], "PATH has '/opt/gone/bin', which doesn't exist");
+        assert_eq!(hazards[3], "PATH has an empty entry, which is relative to the current directory");
+        assert_eq!(hazards[4], "PATH has 'bin', which is relative to the current directory");
+        assert!(hazards[5].starts_with("TZ isn't set"));
+
+        // Without a filesystem to look at, only what's in the environment itself is checked
+        let env = ["PATH=/opt/gone/bin".to_string(), "TZ=UTC".to_string()];
+        assert!(env_hazards(&env, None).is_empty());
+        // execve() takes entries without a '=' too
+        let env = ["LD_PRELOAD".to_string(), "TZ=UTC".to_string()];
+        assert_eq!(env_hazards(&env, None), vec!["LD_PRELOAD is set to '', which is loaded into every program run \
+                                                  with this environment"]);
+    }
+
+    #[test]
+    fn test_translate_ns_pid() {
+        // In our own namespace, a pid is itself
+        let me = std::process::id() as u64;
+        assert_eq!(ns_pids(me).unwrap().last(), Some(&me));
+        assert_eq!(translate_ns_pid(me, me).unwrap(), me);
+        assert!(translate_ns_pid(me, u32::MAX as u64).is_err());
+    }
+
+    #[test]
+    fn test_inotify_watches() {
+        let fdinfo = "pos:\t0\nflags:\t02000000\nmnt_id:\t15\nino:\t1057\n\
+                      inotify wd:2 ino:83 sdev:800013 mask:fc6 ignored_mask:0 fhandle-bytes:8 fhandle-type:1 \
+                      f_handle:8300000039a206fd\n\
+                      inotify wd:1 ino:1c0 sdev:2a mask:400 ignored_mask:0\n";
+        assert_eq!(parse_inotify_fdinfo(fdinfo), vec![
+            InotifyWatch { wd: 2, ino: 0x83, dev: (8, 19), mask: 0xfc6,
+                           handle: Some((1, vec![0x83, 0, 0, 0, 0x39, 0xa2, 0x06, 0xfd])), path: None },
+            InotifyWatch { wd: 1, ino: 0x1c0, dev: (0, 42), mask: 0x400, handle: None, path: None },
+        ]);
+        assert_eq!(inotify_mask_strs(0x0100_0108), vec!["IN_CLOSE_WRITE", "IN_CREATE", "IN_ONLYDIR"]);
+
+        // Look for files in a tree of our own, without going further than allowed
+        use std::os::unix::fs::MetadataExt;
+        let dir = std::env::temp_dir().join(format!("ptools-inotify-test.{}", std::process::id()));
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/file"), "").unwrap();
+        let ino = |path: &str| fs::metadata(dir.join(path)).unwrap().ino();
+        let dev = fs::metadata(&dir).unwrap().dev();
+        let found = find_inodes(&dir, dev, &[ino("a/b"), ino("a/b/file"), ino(".")], 100);
+        assert_eq!(found.get(&ino("a/b")).map(String::as_str), Some("/a/b"));
+        assert_eq!(found.get(&ino("a/b/file")).map(String::as_str), Some("/a/b/file"));
+        assert_eq!(found.get(&ino(".")).map(String::as_str), Some(""));
+        assert_eq!(find_inodes(&dir, dev, &[ino("a/b/file")], 2).len(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_core_dump_size() {
+        let mapping = |perms: &str, path: &str, anon_kb: u64, vm_flags: &str| Mapping {
+            start: 0x10000,
+            end: 0x14000,
+            perms: perms.to_string(),
+            offset: 0,
+            dev: (0, 0),
+            inode: 0,
+            path: path.to_string(),
+            sizes: vec![("Anonymous".to_string(), anon_kb)].into_iter().collect(),
+            vm_flags: vm_flags.split_whitespace().map(|f| f.to_string()).collect(),
+        };
+        let size = |m: &Mapping, filter| core_dump_size(m, filter, 4096, &mut || true);
+
+        let heap = mapping("rw-p", "[heap]", 8, "rd wr mr mw me ac");
+        assert_eq!(size(&heap, DEFAULT_COREDUMP_FILTER), 0x4000);
+        assert_eq!(size(&heap, 0), 0);
+        assert_eq!(size(&mapping("rw-p", "", 0, "rd wr mr mw me ac"), DEFAULT_COREDUMP_FILTER), 0);
+        assert_eq!(size(&mapping("rw-p", "", 8, "rd wr mr mw me dd ac"), DEFAULT_COREDUMP_FILTER), 0);
+
+        // Libraries: only the ELF header by default, unless written to (e.g. relocated data)
+        let text = mapping("r-xp", "/usr/lib/libc.so.6", 0, "rd ex mr mw me");
+        assert_eq!(size(&text, DEFAULT_COREDUMP_FILTER), 4096);
+        assert_eq!(size(&text, DEFAULT_COREDUMP_FILTER & !COREDUMP_ELF_HEADERS), 0);
+        assert_eq!(size(&text, COREDUMP_MAPPED_PRIVATE), 0x4000);
+        assert_eq!(size(&mapping("rw-p", "/usr/lib/libc.so.6", 4, "rd wr mr mw me ac"), DEFAULT_COREDUMP_FILTER),
+                   0x4000);
+
+        let shm = mapping("rw-s", "/dev/zero (deleted)", 0, "rd wr sh mr mw me ms");
+        assert_eq!(size(&shm, DEFAULT_COREDUMP_FILTER), 0x4000);
+        let shared_file = mapping("rw-s", "/var/db/data", 0, "rd wr sh mr mw me ms");
+        assert_eq!(size(&shared_file, DEFAULT_COREDUMP_FILTER), 0);
+        assert_eq!(size(&shared_file, COREDUMP_MAPPED_SHARED), 0x4000);
+        let huge = mapping("rw-s", "/anon_hugepage (deleted)", 0, "rd wr sh mr mw me ms ht");
+        assert_eq!(size(&huge, DEFAULT_COREDUMP_FILTER), 0);
+        assert_eq!(size(&huge, COREDUMP_HUGETLB_SHARED), 0x4000);
+        assert_eq!(size(&mapping("r--p", "[vvar]", 0, "rd mr pf io de dd"), !0), 0);
+        assert_eq!(size(&mapping("r-xp", "[vdso]", 0, "rd ex mr mw me de"), 0), 0x4000);
+    }
+
+    #[test]
+    fn test_write_core() {
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = child.id() as u64;
+        // Wait for sleep to have been exec'd
+        while fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap().trim() != "sleep" {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let cap = MutateCap::acquire_in_mode(false, "stop a process").unwrap();
+        let mut calls = 0;
+        let (core, summary) = write_core(&cap, pid, None, vec![], &mut |_, _| calls += 1).unwrap();
+        assert_eq!(summary.threads, 1);
+        assert!(summary.mappings > 0 && calls > 0);
+        assert_eq!(core.len() as u64, summary.bytes);
+
+        // It reads back like one the kernel wrote, and sleep carries on
+        let mut core = CoreFile::parse(std::io::Cursor::new(core)).unwrap();
+        let prpsinfo = core.note(NT_PRPSINFO).unwrap();
+        assert_eq!(read_u32(prpsinfo, 24) as u64, pid);
+        assert_eq!(c_string(&prpsinfo[56..136]), "sleep 10");
+        assert_eq!(read_u32(core.note(NT_PRSTATUS).unwrap(), 32) as u64, pid);
+        let auxv = parse_auxv(core.note(NT_AUXV).unwrap());
+        let execfn = auxv.iter().find(|&&(a_type, _)| a_type == 31).unwrap().1;
+        assert!(core.read_string(execfn).unwrap().ends_with("sleep"));
+        assert!(child.try_wait().unwrap().is_none());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_privileges() {
+        assert_eq!(capabilities_str(0x3000), "cap_net_admin,cap_net_raw");
+        assert_eq!(capabilities_str(0x1ff_ffff_ffff), "all");
+        assert_eq!(capabilities_str(1 << 50), "cap_50");
+        assert_eq!(capabilities_str(0x1ff_feff_ffff), "all except cap_sys_resource");
+
+        let root = Privileges { setuid: None, setgid: None, effective: 0x1ff_ffff_ffff, ambient: 0 };
+        let user = Privileges { setuid: None, setgid: None, effective: 0, ambient: 0 };
+        assert_eq!(privileges_annotation(&root, Some(&root)), None);
+        assert_eq!(privileges_annotation(&user, Some(&root)), None);
+        assert_eq!(privileges_annotation(&root, None), Some("caps all".to_string()));
+        let su = Privileges { setuid: Some(0), ..root };
+        assert_eq!(privileges_annotation(&su, Some(&user)), Some("setuid root, caps all".to_string()));
+        let service = Privileges { setuid: None, setgid: Some(0), effective: 0x400, ambient: 0x400 };
+        assert_eq!(privileges_annotation(&service, Some(&root)),
+                   Some("setgid root, caps cap_net_bind_service, ambient caps cap_net_bind_service".to_string()));
+
+        let me = read_privileges(std::process::id() as u64).unwrap();
+        assert_eq!(me.setuid, None);
+    }
+
+    #[test]
+    fn test_signalfd_and_pidfd() {
+        let fdinfo = "pos:\t0\nflags:\t02\nmnt_id:\t17\nino:\t26\nsigmask:\t0000000200004202\n";
+        let mask = u64::from_str_radix(&fdinfo_field(fdinfo, "sigmask").unwrap(), 16).unwrap();
+        assert_eq!(signal_mask_names(mask), vec!["SIGINT", "SIGUSR1", "SIGTERM", "SIGRTMIN+0"]);
+        assert_eq!(fdinfo_field("pos:\t0\nPid:\t-1\nNSpid:\t-1\n", "Pid"), Some("-1".to_string()));
+        assert_eq!(fdinfo_field("pos:\t0\n", "Pid"), None);
+
+        assert_eq!(pidfd_target(-1), PidfdTarget::Exited);
+        assert_eq!(pidfd_target(0), PidfdTarget::Hidden);
+        let me = std::process::id() as u64;
+        assert_eq!(pidfd_target(me as i64), PidfdTarget::Running(me));
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let zombie = child.id() as u64;
+        while pidfd_target(zombie as i64) != PidfdTarget::Zombie(zombie) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_shm_uses() {
+        let segments = parse_sysvipc_shm("\
+       key      shmid perms                  size  cpid  lpid nattch   uid   gid  cuid  cgid      atime      dtime      ctime                   rss                  swap
+  87654321          3  1600              67108864   501   620      2    70    70    70    70 1700000100 1700000000 1699999000              65536                     0
+         0          7   600                  4096   999   999      0  1000  1000  1000  1000 1700000001 1700000002 1700000000                  4096                     0
+");
+        assert_eq!(segments, vec![
+            SysvSegment { key: 87654321, id: 3, mode: 0o1600, size: 64 << 20, cpid: 501, lpid: 620, attached: 2,
+                          uid: 70, dtime: 1700000000 },
+            SysvSegment { key: 0, id: 7, mode: 0o600, size: 4096, cpid: 999, lpid: 999, attached: 0, uid: 1000,
+                          dtime: 1700000002 },
+        ]);
+
+        let maps = "\
+7f0000000000-7f0004000000 rw-s 00000000 00:01 3                          /SYSV05397fb1 (deleted)
+7f1000000000-7f1000100000 rw-s 00000000 00:1a 415                        /dev/shm/PostgreSQL.42
+7f1000100000-7f1000200000 r--s 00100000 00:1a 415                        /dev/shm/PostgreSQL.42
+7f2000000000-7f2000001000 rw-s 00000000 00:1a 77                         /dev/shm/gone (deleted)
+7f3000000000-7f3000001000 r--p 00000000 fe:00 280542                     /usr/lib/libc.so.6
+";
+        let mappings = parse_smaps(maps.as_bytes()).unwrap();
+        let fds = vec![(5, "/dev/shm/PostgreSQL.42".to_string(), (0, 0x1a, 415)),
+                       (6, "/dev/shm/sem.lock".to_string(), (0, 0x1a, 90)),
+                       (7, "/tmp/other".to_string(), (0xfe, 0, 12))];
+        assert_eq!(shm_uses(&mappings, &fds), vec![
+            ShmUse { object: ShmObject::Sysv(3), file: (0, 1, 3), mapped: 64 << 20, fds: vec![] },
+            ShmUse { object: ShmObject::Posix("/dev/shm/PostgreSQL.42".to_string()), file: (0, 0x1a, 415),
+                     mapped: 2 << 20, fds: vec![5] },
+            ShmUse { object: ShmObject::Posix("/dev/shm/gone (deleted)".to_string()), file: (0, 0x1a, 77),
+                     mapped: 4096, fds: vec![] },
+            ShmUse { object: ShmObject::Posix("/dev/shm/sem.lock".to_string()), file: (0, 0x1a, 90), mapped: 0,
+                     fds: vec![6] },
+        ]);
+
+        assert_eq!(ago_str(59), "59s ago");
+        assert_eq!(ago_str(2 * 60 * 60 + 5), "2h ago");
+        assert_eq!(ago_str(15 * 24 * 60 * 60), "2w ago");
+    }
+
+    #[test]
+    fn test_fd_age() {
+        let secs = Duration::from_secs;
+        let age = |at_least, at_most, socket| FdAge { at_least, at_most, socket };
+
+        // Nobody had listed the fds before
+        assert_eq!(fd_age(None, Some(secs(3600)), None), age(None, Some(secs(3600)), false));
+        assert_eq!(fd_age(Some(secs(60)), Some(secs(3600)), None), age(Some(secs(60)), Some(secs(3600)), false));
+        // A socket made after the process started
+        assert_eq!(fd_age(Some(secs(60)), Some(secs(3600)), Some(secs(120))),
+                   age(Some(secs(60)), Some(secs(120)), true));
+        assert_eq!(fd_age(None, Some(secs(3600)), Some(secs(7200))), age(None, Some(secs(3600)), false));
+        // The fd was listed, closed, and the number reused for a newer socket
+        assert_eq!(fd_age(Some(secs(600)), Some(secs(3600)), Some(secs(120))), age(None, Some(secs(120)), true));
+
+        assert_eq!(fd_age_str(&age(Some(secs(7300)), Some(secs(3 * 86400)), true), None).unwrap(),
+                   "open for at least 2h, at most 3d (the socket's age)");
+        assert_eq!(fd_age_str(&age(None, Some(secs(59)), false), None).unwrap(),
+                   "open for at most 59s (the process' age)");
+        assert_eq!(fd_age_str(&age(None, None, false), None), None);
+    }
+
+    #[test]
+    fn test_tree_summary() {
+        let mut tree = ProcessTree::default();
+        for &(pid, ppid) in &[(1, 0), (10, 1), (20, 1), (11, 10), (21, 20), (22, 20), (23, 21), (24, 23)] {
+            tree.insert(pid, ppid, None);
+        }
+        assert_eq!(tree_summary(&tree, vec![], 1), TreeSummary {
+            pids: vec![1, 10, 11, 20, 21, 23, 24, 22],
+            depth: 5,
+            deepest: 24,
+            largest: Some((20, 5)),
+        });
+        assert_eq!(tree_summary(&tree, vec![1, 20], 21), TreeSummary {
+            pids: vec![1, 20, 21, 23, 24],
+            depth: 5,
+            deepest: 24,
+            largest: Some((23, 2)),
+        });
+        assert_eq!(tree_summary(&tree, vec![1], 10).largest, Some((11, 1)));
+        assert_eq!(tree_summary(&tree, vec![1, 10], 11), TreeSummary {
+            pids: vec![1, 10, 11],
+            depth: 3,
+            deepest: 11,
+            largest: None,
+        });
+    }
+
+    #[test]
+    fn test_env_diff() {
+        let env = |vars: &[&str]| vars.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let baseline = env(&["PATH=/usr/bin", "HOME=/root", "LANG=C", "INVOCATION_ID=1", "TZ=UTC", "TZ=GMT"]);
+        assert_eq!(env_diff(&baseline, &baseline, &[]), Vec::<String>::new());
+        assert_eq!(env_diff(&baseline, &env(&["HOME=/root", "PATH=/usr/local/bin:/usr/bin", "TZ=UTC",
+                                              "LD_PRELOAD=/tmp/x.so", "INVOCATION_ID=2", "LANG=C"]),
+                            &["INVOCATION_ID".to_string()]),
+                   vec!["+LD_PRELOAD=/tmp/x.so", "-PATH=/usr/bin", "+PATH=/usr/local/bin:/usr/bin"]);
+        assert_eq!(env_diff(&baseline, &env(&["PATH=/usr/bin", "HOME=/root", "LANG=", "INVOCATION_ID=1"]), &[]),
+                   vec!["-LANG=C", "+LANG=", "-TZ=UTC"]);
+    }
+
+    #[test]
+    fn test_resplit_args() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let no_paths = |_: &str| false;
+        let title = args(&["postgres: checkpointer  "]);
+        assert_eq!(resplit_args(&title, Some(3), &no_paths), Some(args(&["postgres:", "checkpointer"])));
+        assert_eq!(resplit_args(&title, None, &no_paths), Some(args(&["postgres:", "checkpointer"])));
+        // Really started with one argument
+        assert_eq!(resplit_args(&title, Some(1), &no_paths), None);
+        assert_eq!(resplit_args(&args(&["/opt/My App/app"]), None, &|path: &str| path == "/opt/My App/app"), None);
+        assert_eq!(resplit_args(&args(&["/usr/bin/sleep", "a b"]), Some(2), &no_paths), None);
+        assert_eq!(resplit_args(&args(&["nginx"]), Some(3), &no_paths), None);
+    }
+
+    #[test]
+    fn test_fd_counts() {
+        let limits = "\
+Limit                     Soft Limit           Hard Limit           Units
+Max processes             63432                63432                processes
+Max open files            1024                 524288               files
+Max locked memory         8388608              8388608              bytes
+";
+        assert_eq!(parse_limit(limits, "Max open files"), Some((Some(1024), Some(524288))));
+        assert_eq!(parse_limit("Max open files            unlimited            unlimited            files",
+                               "Max open files"),
+                   Some((None, None)));
+        assert_eq!(parse_limit("Max processes             63432                63432", "Max open files"), None);
+        assert_eq!(parse_limit("Max stack size            8388608              unlimited            bytes",
+                               "Max stack size"), Some((Some(8388608), None)));
+
+        let socket = |family, sock_type| SockInfo { family, sock_type, inode: 0, local_addr: None, peer_addr: None,
+                                                   peer_process: None, protocol: None, ulp: None, netns: None,
+                                                   path: None, bound_if: None };
+        assert_eq!(fd_count_type(libc::S_IFREG | 0o644, None), "regular");
+        assert_eq!(fd_count_type(0o600, None), "anon");
+        assert_eq!(fd_count_type(libc::S_IFCHR | 0o666, None), "device");
+        assert_eq!(fd_count_type(libc::S_IFSOCK | 0o777, Some(&socket(AddressFamily::Inet6, SockType::Stream))),
+                   "socket-tcp");
+        assert_eq!(fd_count_type(libc::S_IFSOCK | 0o777, Some(&socket(AddressFamily::Unix, SockType::Datagram))),
+                   "socket-unix");
+        assert_eq!(fd_count_type(libc::S_IFSOCK | 0o777, None), "socket-other");
+    }
+
+    #[test]
+    fn test_id_maps() {
+        let map = parse_id_map("         0     100000      65536\n     65536       1000          1\n");
+        assert_eq!(map, vec![(0, 100000, 65536), (65536, 1000, 1)]);
+        assert_eq!(id_inside(&map, 100000), Some(0));
+        assert_eq!(id_inside(&map, 165535), Some(65535));
+        assert_eq!(id_inside(&map, 165536), None);
+        assert_eq!(id_inside(&map, 1000), Some(65536));
+        assert_eq!(id_inside_str(&map, 0), "-");
+        assert_eq!(parse_id_map("         0          0 4294967295\n"), vec![(0, 0, u32::MAX)]);
+
+        assert_eq!(parse_user("0").unwrap(), 0);
+        assert_eq!(parse_user("root").unwrap(), 0);
+        assert!(parse_user("no-such-user-here").is_err());
+    }
+
+    #[test]
+    fn test_select_mappings() {
+        let smaps = "\
+55d0c0000000-55d0c0001000 r--p 00000000 fe:00 12                         /usr/bin/app
+55d0c1000000-55d0c3000000 rw-p 00000000 00:00 0                          [heap]
+Rss:                4096 kB
+Anonymous:          4096 kB
+7f0000000000-7f0010000000 rw-p 00000000 00:00 0 
+Rss:               20480 kB
+Anonymous:         20480 kB
+7f1000000000-7f1000400000 rw-p 00000000 00:00 0                          [anon:arena]
+Rss:               12288 kB
+Anonymous:         12288 kB
+7ffc00000000-7ffc00021000 rw-p 00000000 00:00 0                          [stack]
+Rss:                  16 kB
+";
+        let mappings = parse_smaps(smaps.as_bytes()).unwrap();
+        let opts = |kinds, min_rss, sort| PmapOptions { extended: true, hugepages: false, kinds, min_rss, sort,
+                                                         stacks: false };
+        let starts = |opts: PmapOptions| select_mappings(&mappings, &opts).iter().map(|m| m.start).collect::<Vec<_>>();
+
+        assert_eq!(starts(opts(vec![], 0, MappingSort::Address)).len(), 5);
+        // Anonymous mappings over 10M, biggest first
+        assert_eq!(starts(opts(vec![MappingKind::Anon], parse_size("10M").unwrap() / 1024, MappingSort::Rss)),
+                   vec![0x7f0000000000, 0x7f1000000000]);
+        assert_eq!(starts(opts(vec![MappingKind::Heap, MappingKind::Stack], 0, MappingSort::Address)),
+                   vec![0x55d0c1000000, 0x7ffc00000000]);
+        assert_eq!(starts(opts(vec![MappingKind::File], 0, MappingSort::Address)), vec![0x55d0c0000000]);
+        assert_eq!(starts(opts(vec![], 0, MappingSort::Size))[0], 0x7f0000000000);
+
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64K").unwrap(), 64 << 10);
+        assert_eq!(parse_size("1.5G").unwrap(), 3 << 29);
+        assert_eq!(parse_size("2MiB").unwrap(), 2 << 20);
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn test_env_values_summary() {
+        let me = std::process::id() as u64;
+        let names = vec!["PATH".to_string(), "PTOOLS_NOT_SET_ANYWHERE".to_string()];
+        assert_eq!(env_values_summary(me, &names), format!("PATH={}  ", env::var("PATH").unwrap()));
+        assert_eq!(env_values_summary(u32::MAX as u64, &names), "PATH=?  PTOOLS_NOT_SET_ANYWHERE=?  ");
+    }
+
+    #[test]
+    fn test_stop_at_syscall() {
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = child.id() as u64;
+        while fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap().trim() != "sleep" {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let state = || ProcStat::read(pid).unwrap().get_field("State").unwrap().chars().next().unwrap();
+
+        // sleep is blocked in nanosleep, which is interrupted and stops on its way back
+        let cap = MutateCap::acquire_in_mode(false, "stop a process").unwrap();
+        assert_eq!(stop_at_syscall(&cap, pid, Duration::from_secs(1)).unwrap(), Vec::<u64>::new());
+        assert_eq!(state(), 'T');
+        assert!(child.try_wait().unwrap().is_none());
+
+        nix::sys::signal::kill(Pid::from_raw(pid as i32), Signal::SIGCONT).unwrap();
+        while state() == 'T' {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_walk_tree() {
+        struct Recorder(Vec<String>);
+        impl TreeVisitor for Recorder {
+            fn pre(&mut self, pid: u64, depth: usize) -> bool {
+                self.0.push(format!("{}{}", "  ".repeat(depth), pid));
+                pid != 20
+            }
+            fn post(&mut self, pid: u64, _: usize) {
+                self.0.push(format!("/{}", pid));
+            }
+        }
+
+        let mut tree = ProcessTree::default();
+        for &(pid, ppid, starttime) in &[(1, 0, 1), (10, 1, 5), (11, 10, 6), (20, 1, 7), (21, 20, 8), (12, 10, 9)] {
+            tree.insert(pid, ppid, Some(starttime));
+        }
+        // Children are visited in order, and pre() returning false prunes the subtree
+        let mut recorder = Recorder(vec![]);
+        walk_tree(&tree, 1, &mut recorder);
+        assert_eq!(recorder.0, ["1", "  10", "    11", "/11", "    12", "/12", "/10", "  20", "/1"]);
+
+        // 30 started before 10, so it was the child of an earlier process with 10's pid
+        tree.insert(30, 10, Some(2));
+        assert_eq!(subtree_pids(&tree, 10), [10, 11, 12]);
+
+        // Without start times to go by, each process is still only visited once
+        let mut tree = ProcessTree::default();
+        for &(pid, ppid) in &[(2, 1), (3, 2), (1, 3), (4, 3)] {
+            tree.insert(pid, ppid, None);
+        }
+        let mut pids = vec![];
+        walk_tree(&tree, 1, &mut |pid, depth| {
+            pids.push((pid, depth));
+            true
+        });
+        assert_eq!(pids, [(1, 0), (2, 1), (3, 2), (4, 3)]);
+    }
+
+    #[test]
+    fn test_fanotify() {
+        let fdinfo = "pos:\t0\nflags:\t02\nmnt_id:\t15\nino:\t1057\n\
+                      fanotify flags:14 event-flags:8000\n\
+                      fanotify mnt_id:259 mflags:0 mask:10020 ignored_mask:0\n\
+                      fanotify ino:1c0 sdev:800001 mflags:0 mask:8 ignored_mask:1 fhandle-bytes:8 fhandle-type:1 \
+                      f_handle:c00100003a20c4e1\n\
+                      fanotify sdev:2a mflags:0 mask:2 ignored_mask:0\n";
+        let mut fanotify = parse_fanotify_fdinfo(fdinfo).unwrap();
+        assert_eq!(fanotify_flags_strs(fanotify.flags), vec!["FAN_CLASS_CONTENT", "FAN_UNLIMITED_QUEUE"]);
+        assert_eq!(fanotify.event_flags, 0x8000);
+        let objects = fanotify.marks.iter().map(|mark| &mark.object).collect::<Vec<_>>();
+        assert_eq!(objects, vec![&FanotifyObject::Mount(0x259), &FanotifyObject::Inode(0x1c0, (8, 1)),
+                                 &FanotifyObject::Filesystem((0, 42))]);
+        assert!(fanotify.permission_events());
+
+        // The mount ids are in decimal in mountinfo
+        let mounts = parse_mountinfo("\
+            600 1 8:1 / / rw - ext4 /dev/sda1 rw\n\
+            601 600 8:1 /srv /srv rw - ext4 /dev/sda1 rw\n");
+        resolve_fanotify_mounts(&mut fanotify, &mounts);
+        assert_eq!(fanotify_mark_str(&fanotify.marks[0]), "mark on mount 601 (/srv): FAN_OPEN|FAN_OPEN_PERM");
+        assert_eq!(fanotify_mark_str(&fanotify.marks[1]),
+                   "mark on inode 448 on 8:1 (/): FAN_CLOSE_WRITE ignoring FAN_ACCESS");
+        assert_eq!(fanotify_mark_str(&fanotify.marks[2]), "mark on filesystem 0:42: FAN_MODIFY");
+
+        // Notification groups can't get permission events
+        let notif = parse_fanotify_fdinfo("fanotify flags:0 event-flags:0\n").unwrap();
+        assert_eq!(fanotify_flags_strs(notif.flags), vec!["FAN_CLASS_NOTIF"]);
+        assert!(!notif.permission_events());
+        assert_eq!(parse_fanotify_fdinfo("pos:\t0\n"), None);
+    }
+
+    #[test]
+    fn test_containers() {
+        let id = "4f1c2dd3e5b1d6c3f0a97b81a34e1b6d1f6f4bb1e1c3d7a8b9c0d1e2f3a4b5c6";
+        let container = |runtime: &str, pod_uid: Option<&str>| Some(Container {
+            runtime: runtime.to_string(), id: id.to_string(), pod_uid: pod_uid.map(|uid| uid.to_string()),
+        });
+        assert_eq!(parse_container_cgroup(&format!("/system.slice/docker-{}.scope", id)), container("docker", None));
+        assert_eq!(parse_container_cgroup(&format!("/docker/{}", id)), container("docker", None));
+        let uid = "0f4e3b0c-5d2a-4c1e-9b7f-2a1d3c4b5e6f";
+        assert_eq!(parse_container_cgroup(&format!(
+            "/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod{}.slice/cri-containerd-{}.scope",
+            uid.replace('-', "_"), id)), container("cri-containerd", Some(uid)));
+        assert_eq!(parse_container_cgroup(&format!("/kubepods/besteffort/pod{}/{}", uid, id)),
+                   container("kubernetes", Some(uid)));
+        // systemd in a container puts services in cgroups of their own
+        assert_eq!(parse_container_cgroup(&format!("/system.slice/libpod-{}.scope/system.slice/cron.service", id)),
+                   container("libpod", None));
+        assert_eq!(parse_container_cgroup("/user.slice/user-1000.slice/session-2.scope"), None);
+        assert_eq!(parse_container_cgroup(&format!("/system.slice/crio-conmon-{}.scope", &id[1..])), None);
+
+        let cri = Json::parse(r#"{"ociVersion": "1.0.2", "annotations": {"io.kubernetes.cri.container-name": "nginx",
+            "io.kubernetes.cri.sandbox-name": "web-7d4b9c", "io.kubernetes.cri.sandbox-namespace": "default"}}"#);
+        assert_eq!(parse_container_config(&cri.unwrap()), ContainerNames {
+            name: Some("nginx".to_string()), pod: Some("web-7d4b9c".to_string()),
+        });
+        let docker = Json::parse(r#"{"ID": "4f1c", "Name": "/db", "Config": {"Labels": {}}}"#).unwrap();
+        assert_eq!(parse_container_config(&docker), ContainerNames { name: Some("db".to_string()), pod: None });
+    }
+
+    #[test]
+    fn test_argv_diff() {
+        let argv = |args: &str| args.split(' ').map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(highlight_arg_change("--mem=400m", "--mem=512m"), "--mem=[-400m-]{+512m+}");
+        assert_eq!(highlight_arg_change("--log-level=info", "--log-level=debug"), "--log-level=[-info-]{+debug+}");
+        assert_eq!(highlight_arg_change("-v", "-vv"), "-[-v-]{+vv+}");
+        assert_eq!(highlight_arg_change("a,b", "a,b,c"), "a,b{+,c+}");
+
+        assert_eq!(argv_diff(&argv("worker --threads=4 --port 80"), &argv("worker --threads=8 --port 80 --debug")), [
+            "  argv[0]: worker",
+            "~ argv[1]: --threads=[-4-]{+8+}",
+            "  argv[2]: --port",
+            "  argv[3]: 80",
+            "+ argv[4]: --debug",
+        ]);
+        assert_eq!(argv_diff(&argv("a -x b c"), &argv("a b c")), ["  argv[0]: a", "- argv[1]: -x", "  argv[2]: b",
+                                                                  "  argv[3]: c"]);
+        assert_eq!(argv_diff(&argv("a b"), &argv("a b")), ["  argv[0]: a", "  argv[1]: b"]);
+        assert_eq!(argv_diff(&argv("a x b y c"), &argv("a b z c")), ["  argv[0]: a", "- argv[1]: x", "  argv[2]: b",
+                                                                    "~ argv[3]: [-y-]{+z+}", "  argv[4]: c"]);
+
+        // Too many differing arguments to pair up are only listed
+        let many = |prefix: &str| (0..1100).map(|i| format!("{}{}", prefix, i)).collect::<Vec<_>>();
+        let (old, new) = ([argv("cc"), many("a")].concat(), [argv("cc"), many("b")].concat());
+        let lines = argv_diff(&old, &new);
+        assert_eq!(lines.len(), 1 + 2 * 1100);
+        assert_eq!((&lines[0][..], &lines[1][..], &lines[1101][..]), ("  argv[0]: cc", "- argv[1]: a0", "+ argv[1]: b0"));
+    }
+
+    #[test]
+    fn test_pwait() {
+        let at = |secs: u64, millis: u64| std::time::UNIX_EPOCH + Duration::from_millis(secs * 1000 + millis);
+        assert_eq!(utc_time_str(at(0, 0)), "1970-01-01T00:00:00.000Z");
+        assert_eq!(utc_time_str(at(951_782_400, 5)), "2000-02-29T00:00:00.005Z");
+        assert_eq!(utc_time_str(at(1_709_312_645, 123)), "2024-03-01T17:04:05.123Z");
+
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = child.id() as u64;
+        let process = WaitedProcess::open(pid).unwrap();
+        // As on kernels without pidfds
+        let polled = WaitedProcess { pidfd: None, stat_path: process.stat_path.clone(), summary: String::new(),
+                                     ..process };
+        let mut stat = String::new();
+        assert!(!process.exited(&mut stat) && !polled.exited(&mut stat));
+        child.kill().unwrap();
+        // The pidfd is readable, or the process a zombie, as soon as it has exited
+        while !process.exited(&mut stat) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(polled.exited(&mut stat));
+        child.wait().unwrap();
+        assert!(process.exited(&mut stat) && polled.exited(&mut stat));
+        assert!(WaitedProcess::open(pid).is_err());
+
+        let json = exit_event_json(&process, at(1_709_312_645, 123), Some(9), 2);
+        assert_eq!(json.get("time").and_then(Json::as_str), Some("2024-03-01T17:04:05.123Z"));
+        assert_eq!(json.get("status").and_then(Json::as_str), Some("killed by SIGKILL (9)"));
+        assert_eq!(json.get("remaining").and_then(Json::as_u64), Some(2));
+    }
+
+    #[test]
+    fn test_ulp_info() {
+        fn attr(attr_type: u16, payload: &[u8]) -> Vec<u8> {
+            let mut attr = vec![];
+            attr.extend_from_slice(&((NLA_HDR_LEN + payload.len()) as u16).to_ne_bytes());
+            attr.extend_from_slice(&attr_type.to_ne_bytes());
+            attr.extend_from_slice(payload);
+            attr.resize((attr.len() + 3) & !3, 0);
+            attr
+        }
+        fn diag_msg(inode: u32, attrs: &[u8]) -> Vec<u8> {
+            bound_diag_msg(inode, 0, attrs)
+        }
+        fn bound_diag_msg(inode: u32, index: u32, attrs: &[u8]) -> Vec<u8> {
+            let mut msg = vec![];
+            msg.extend_from_slice(&((NLMSG_HDR_LEN + INET_DIAG_MSG_LEN + attrs.len()) as u32).to_ne_bytes());
+            msg.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+            msg.resize(NLMSG_HDR_LEN + 40, 0);
+            msg.extend_from_slice(&index.to_ne_bytes());
+            msg.resize(NLMSG_HDR_LEN + 68, 0);
+            msg.extend_from_slice(&inode.to_ne_bytes());
+            msg.extend_from_slice(attrs);
+            msg
+        }
+        let tls = [attr(TLS_INFO_VERSION, &0x0304u16.to_ne_bytes()), attr(TLS_INFO_CIPHER, &52u16.to_ne_bytes()),
+                   attr(TLS_INFO_TXCONF, &3u16.to_ne_bytes()), attr(TLS_INFO_RXCONF, &2u16.to_ne_bytes())].concat();
+        let ktls = attr(INET_DIAG_ULP_INFO, &[attr(INET_ULP_INFO_NAME, b"tls\0"), attr(INET_ULP_INFO_TLS, &tls)].concat());
+        let mptcp = [attr(1, &[0; 12]), attr(INET_DIAG_ULP_INFO, &attr(INET_ULP_INFO_NAME, b"mptcp\0"))].concat();
+
+        let mut dump = [diag_msg(100, &ktls), diag_msg(101, &[]), diag_msg(102, &mptcp), bound_diag_msg(103, 7, &[])]
+            .concat();
+        let mut diags = HashMap::new();
+        assert!(!parse_inet_diag_dump(&dump, &mut diags));
+        let ulps = diags.iter().filter_map(|(&inode, diag)| Some((inode, diag.ulp.as_ref()?))).collect::<HashMap<_, _>>();
+        let ktls = Ulp::Tls { version: Some(0x0304), cipher: Some(52), tx: 3, rx: 2 };
+        assert_eq!(ulps.get(&100), Some(&&ktls));
+        assert_eq!(diags.get(&101), None);
+        assert_eq!(ulps.get(&102), Some(&&Ulp::Other("mptcp".to_string())));
+        assert_eq!(diags.get(&103), Some(&InetDiag { ulp: None, bound_if: Some(BoundIf { index: 7, name: None }) }));
+
+        dump.extend_from_slice(&(NLMSG_HDR_LEN as u32 + 4).to_ne_bytes());
+        dump.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+        dump.resize(dump.len() + 14, 0);
+        assert!(parse_inet_diag_dump(&dump, &mut diags));
+
+        assert_eq!(ulp_str(&ktls), "kTLS: TLS 1.3 AES_GCM_256, tx: offloaded to the NIC, rx: in kernel software");
+        assert_eq!(ulp_str(&Ulp::Tls { version: None, cipher: None, tx: 2, rx: 1 }),
+                   "kTLS: tx: in kernel software, rx: not configured");
+        assert_eq!(ulp_str(&Ulp::Other("mptcp".to_string())), "ULP: mptcp");
+        assert_eq!(ulp_json(&ktls).to_string(),
+                   r#"{"name":"tls","version":"TLS 1.3","cipher":"AES_GCM_256","tx":"hw","rx":"sw"}"#);
+    }
+
+    #[test]
+    fn test_doctor_checks() {
+        let mounts = parse_mountinfo("\
+22 1 0:21 / /proc rw,nosuid - proc proc rw,hidepid=invisible,gid=998
+25 1 0:23 / /sys/fs/cgroup rw - cgroup2 cgroup2 rw
+");
+        let ptrace = 1 << CAP_SYS_PTRACE;
+        assert_eq!(proc_mount_problem(&mounts, 0).unwrap(),
+                   "/proc is mounted with hidepid=invisible, which hides other users' processes (except to members \
+                    of gid=998)");
+        assert_eq!(proc_mount_problem(&mounts, ptrace), None);
+        assert_eq!(proc_mount_problem(&parse_mountinfo("22 1 0:21 / /proc rw - proc proc rw,hidepid=0\n"), 0), None);
+        assert_eq!(proc_mount_problem(&[], 0).unwrap(), "procfs isn't mounted at /proc");
+        assert!(proc_mount_problem(&parse_mountinfo("22 1 0:21 / /proc rw - proc proc rw,subset=pid\n"), ptrace)
+                .unwrap().contains("subset=pid"));
+
+        assert_eq!(ptrace_problem(None, 0), None);
+        assert_eq!(ptrace_problem(Some(0), 0), None);
+        assert!(ptrace_problem(Some(1), 0).unwrap().contains("only descendants"));
+        assert_eq!(ptrace_problem(Some(2), ptrace), None);
+        assert!(ptrace_problem(Some(3), ptrace).unwrap().contains("nothing can be traced at all"));
+
+        assert_eq!(cgroup_problem(&mounts), None);
+        let v1 = "30 25 0:26 / /sys/fs/cgroup/memory rw - cgroup cgroup rw,memory\n";
+        assert!(cgroup_problem(&parse_mountinfo(v1)).unwrap().contains("only cgroup v1"));
+        let hybrid = format!("{}31 25 0:27 / /sys/fs/cgroup/unified rw - cgroup2 cgroup2 rw\n", v1);
+        assert!(cgroup_problem(&parse_mountinfo(&hybrid)).unwrap().contains("hybrid"));
+        assert_eq!(cgroup_problem(&[]).unwrap(), "no cgroup filesystem is mounted");
+    }
+
+    #[test]
+    fn test_stack_room() {
+        let smaps = "\
+7effffff0000-7f0000000000 r--p 00000000 fe:00 12                         /usr/lib/libc.so.6
+7f0000000000-7f0000001000 ---p 00000000 00:00 0 
+7f0000001000-7f0000801000 rw-p 00000000 00:00 0 
+VmFlags: rd wr mr mw me ac
+7f0000801000-7f0000802000 rw-p 00000000 00:00 0 
+VmFlags: rd wr mr mw me ac
+7ffc00000000-7ffc00021000 rw-p 00000000 00:00 0                          [stack]
+VmFlags: rd wr mr mw me gd ac
+";
+        let mappings = parse_smaps(smaps.as_bytes()).unwrap();
+        let gap = 256 * 4096;
+
+        // A thread's stack with a guard page below it
+        let room = stack_room(&mappings, 0x7f0000800000, None, gap).unwrap();
+        assert_eq!(room, StackRoom { start: 0x7f0000001000, end: 0x7f0000801000, used: 0x1000, left: 0x7ff000,
+                                     guard: 0x1000, grows: false, unguarded: false });
+        assert!(!room.is_nearly_full());
+        assert!(stack_room(&mappings, 0x7f0000001100, None, gap).unwrap().is_nearly_full());
+        // Overflowing this one would write to the one below
+        let room = stack_room(&mappings, 0x7f0000801800, None, gap).unwrap();
+        assert_eq!((room.guard, room.unguarded), (0, true));
+
+        // The main thread's can grow to the rlimit
+        let room = stack_room(&mappings, 0x7ffc00020000, Some(8 << 20), gap).unwrap();
+        assert_eq!((room.used, room.left, room.grows, room.guard), (0x1000, (8 << 20) - 0x1000, true, gap));
+        // Or the guard gap above the mapping below
+        let room = stack_room(&mappings, 0x7ffc00020000, None, gap).unwrap();
+        assert_eq!(room.left, 0x7ffc00020000 - 0x7f0000802000 - gap);
+
+        assert_eq!(stack_room(&mappings, 0x1000, None, gap), None);
+
+        assert_eq!(syscall_stack_pointer("7 0x7ffc1 0x1 0xffffffff 0x0 0x0 0x0 0x7ffc00020000 0x7f4a3c2e1b2d\n"),
+                   Some(0x7ffc00020000));
+        assert_eq!(syscall_stack_pointer("-1 0x7f0000800000 0x55d0c0000ab0\n"), Some(0x7f0000800000));
+        assert_eq!(syscall_stack_pointer("running\n"), None);
+    }
+
+    #[test]
+    fn test_task_paths() {
+        // No process has this pid, so no other test's paths are redirected
+        let pid = u64::from(u32::MAX) + 1;
+        TASK.set((pid, 7)).unwrap();
+        assert_eq!(proc_path!("{}/fd/3", pid), format!("/proc/{}/task/7/fd/3", pid));
+        assert_eq!(proc_path!("{}/task/8/comm", pid), format!("/proc/{}/task/8/comm", pid));
+        assert_eq!(proc_path!("{}1/fd", pid), format!("/proc/{}1/fd", pid));
+        assert_eq!(proc_path!("self/fd"), "/proc/self/fd");
+    }
+
+    #[test]
+    fn test_process_tree_fuzz() {
+        // A fixed xorshift sequence, so that any failure can be reproduced
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move |n: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % n
+        };
+
+        for _ in 0..500 {
+            // Few pids, so that parents are often missing, reused or in cycles
+            let mut stats = vec![];
+            if random(4) != 0 {
+                stats.push((1, "1 (init) S 0 1 1 0 -1 0 0 0 0 0 0 0 0 0 20 0 1 0 1 0 0".to_string()));
+            }
+            for _ in 0..random(40) {
+                let pid = 2 + random(50);
+                let comm = (0..random(16)).map(|_| b"ab) (1\n"[random(7) as usize] as char).collect::<String>();
+                let mut stat = format!("{} ({}) S {} 1 1 0 -1 0 0 0 0 0 0 0 0 0 20 0 1 0 {} 0 0", pid, comm,
+                                       random(55), random(100));
+                if random(8) == 0 {
+                    stat.truncate(random(stat.len() as u64) as usize);
+                }
+                stats.push((pid, stat));
+            }
+            let has_init = stats.iter().any(|&(pid, _)| pid == 1);
+
+            let mut tree = process_tree_from_stats(stats);
+            tree.adopt_orphans();
+            let mut pids = tree.parent_map.keys().cloned().collect::<Vec<_>>();
+            pids.sort();
+
+            for &pid in &pids {
+                // Every process is in the child list of its parent, and only that one
+                let lists = tree.child_map.iter().filter(|(_, children)| children.contains(&pid)).collect::<Vec<_>>();
+                assert_eq!(lists.len(), 1);
+                assert_eq!(*lists[0].0, tree.parent_map[&pid]);
+
+                let ancestors = tree.ancestors(pid);
+                let unique = ancestors.iter().collect::<std::collections::HashSet<_>>();
+                assert_eq!(unique.len(), ancestors.len());
+                assert!(!ancestors.contains(&pid) && !ancestors.contains(&1));
+
+                let mut visited = vec![];
+                walk_tree(&tree, pid, &mut |pid, _| {
+                    visited.push(pid);
+                    true
+                });
+                let unique = visited.iter().collect::<std::collections::HashSet<_>>();
+                assert_eq!(unique.len(), visited.len());
+            }
+
+            // Once orphans are adopted, everything can be reached from the top
+            if has_init {
+                let mut reached = std::collections::HashSet::new();
+                for &pid in pids.iter().filter(|pid| tree.parent_map[pid] == 0) {
+                    walk_tree(&tree, pid, &mut |pid, _| reached.insert(pid));
+                }
+                assert_eq!(reached.len(), pids.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_adopt_orphans() {
+        let mut tree = ProcessTree::default();
+        // 10's parent exited before we read it, 20's pid was reused by a process started after it,
+        // and 30 and 31 were each read just before and after the other got a pid reused
+        for &(pid, ppid, starttime) in &[(1, 0, None), (2, 0, None), (10, 5, Some(10)), (11, 10, Some(11)),
+                                         (20, 21, Some(9)), (21, 1, Some(50)), (30, 31, None), (31, 30, None),
+                                         (32, 31, None)] {
+            tree.insert(pid, ppid, starttime);
+        }
+        assert_eq!(tree.ancestors(32), [31, 30]);
+        assert_eq!(tree.ancestors(20), Vec::<u64>::new());
+
+        tree.adopt_orphans();
+        assert_eq!(tree.children(1), [21, 10, 20, 30]);
+        assert_eq!(tree.parent_map[&31], 30);
+        assert_eq!(tree.ancestors(11), [10]);
+        assert_eq!(subtree_pids(&tree, 30), [30, 31, 32]);
+        assert_eq!(tree.children(5), Vec::<u64>::new().as_slice());
+    }
+
+    #[test]
+    fn test_ioprio_from_opts() {
+        assert_eq!(ioprio_from_opts(Some("idle"), None), Ok(3 << IOPRIO_CLASS_SHIFT));
+        assert_eq!(ioprio_from_opts(Some("1"), Some("0")), Ok(1 << IOPRIO_CLASS_SHIFT));
+        assert_eq!(ioprio_from_opts(None, Some("7")), Ok((2 << IOPRIO_CLASS_SHIFT) | 7));
+        assert_eq!(ioprio_from_opts(Some("best-effort"), None), Ok((2 << IOPRIO_CLASS_SHIFT) | 4));
+        assert_eq!(ioprio_from_opts(Some("none"), None), Ok(0));
+        assert!(ioprio_from_opts(Some("idle"), Some("3")).is_err());
+        assert!(ioprio_from_opts(None, Some("8")).is_err());
+        assert!(ioprio_from_opts(Some("fast"), None).unwrap_err().contains("'fast'"));
+    }
 
-    // If we have some additional info to print about the remote side of this socket, print it here
+    #[test]
+    fn test_lsof_columns() {
+        assert_eq!(lsof_fd_str(0, libc::O_RDONLY as u64), "0r");
+        assert_eq!(lsof_fd_str(1, (libc::O_WRONLY | libc::O_APPEND) as u64), "1w");
+        assert_eq!(lsof_fd_str(7, (libc::O_RDWR | libc::O_CLOEXEC) as u64), "7u");
 
-    // TODO check that addr is not 0.0.0.0 or :: (Actually, should we make it such that sockaddrs
-    // are none in these cases)?
-    if let Some(addr) = sock_info.peer_addr {
-        if addr.ip() != IpAddr::from([0, 0, 0, 0])
-            && addr.ip() != IpAddr::from([0, 0, 0, 0, 0, 0, 0, 0])
-        {
-            println!(
-                "         peername: {} ",
-                inet_address_str(sock_info.family, Some(addr))
-            );
-        }
+        let addr = |s: &str| Some(s.parse::<SocketAddr>().unwrap());
+        assert_eq!(lsof_inet_name(addr("0.0.0.0:8080"), addr("0.0.0.0:0")), "*:8080");
+        assert_eq!(lsof_inet_name(addr("10.0.0.1:22"), addr("10.0.0.2:50312")), "10.0.0.1:22->10.0.0.2:50312");
+        assert_eq!(lsof_inet_name(addr("[::1]:53"), None), "[::1]:53");
+        assert_eq!(lsof_inet_name(addr("0.0.0.0:0"), None), "*:*");
+
+        let row = |cols: [&str; 9]| cols.map(String::from);
+        let rows = [row(["sshd", "812", "root", "cwd", "DIR", "254,0", "4096", "2", "/"]),
+                    row(["sshd", "812", "root", "3u", "IPv4", "21844", "0t0", "TCP", "*:22"])];
+        assert_eq!(lsof_table(&rows, true),
+                   "COMMAND PID USER  FD TYPE DEVICE SIZE/OFF NODE NAME\n\
+                    sshd    812 root cwd DIR   254,0     4096    2 /\n\
+                    sshd    812 root  3u IPv4  21844      0t0  TCP *:22\n");
+        assert_eq!(lsof_table(&rows[1..], false), "sshd 812 root 3u IPv4 21844 0t0 TCP *:22\n");
     }
-    // TODO for unix sockets, or for tcp connections connected to another process on this machine,
-    // see if we can find and print the pid/comm of the other process
-}
 
-// TODO handle case where doesn't match
-fn parse_sock_type(type_code: &str) -> SockType {
-    match type_code.parse::<u64>().unwrap() {
-        1 => SockType::Stream,
-        2 => SockType::Datagram,
-        5 => SockType::SeqPacket,
-        _ => panic!("unknown type"), // TODO
+    #[test]
+    fn test_delimited_line() {
+        let row = ["812", "my app", "4", "/srv/a,b \"c\"", "/tmp/x\ty\nz\\"].map(String::from);
+        assert_eq!(delimited_line(&row, PfilesFormat::Csv), "812,my app,4,\"/srv/a,b \"\"c\"\"\",\"/tmp/x\ty\nz\\\"");
+        assert_eq!(delimited_line(&row, PfilesFormat::Tsv), "812\tmy app\t4\t/srv/a,b \"c\"\t/tmp/x\\ty\\nz\\\\");
+
+        let rows = csv_rows(std::process::id() as u64, &PfilesOptions::default()).unwrap();
+        assert!(rows.iter().all(|row| row.len() == CSV_HEADER.len()));
+        let stdin = rows.iter().find(|row| row[2] == "0").unwrap();
+        assert_eq!(stdin[0], std::process::id().to_string());
     }
-}
 
-// Parse a socket address of the form "0100007F:1538" (i.e. 127.0.0.1:5432)
-fn parse_ipv4_sock_addr(s: &str) -> Result<SocketAddr, ParseError> {
-    let mk_err = || {
-        ParseError::new(
-            "IPv4 address",
-            &format!("expected address in form '0100007F:1538', got {}", s),
-        )
-    };
+    #[test]
+    fn test_cgroup_tree() {
+        let session = "/user.slice/user-1000.slice/session-2.scope";
+        let cgroups = [(1, "/init.scope"), (812, "/system.slice/ssh.service"), (2040, session),
+                       (2044, "/system.slice/cron.service"), (2051, session), (2100, "/system.slice-extra"), (7, "/")];
+        let cgroups = cgroups.iter().map(|&(pid, path)| (pid, path.to_string())).collect::<Vec<_>>();
+        let tree = cgroup_tree(&cgroups).into_iter()
+            .map(|(cgroup, pids)| (cgroup.join("/"), pids))
+            .collect::<Vec<_>>();
+        let expected: Vec<(&str, Vec<u64>)> = vec![
+            ("", vec![7]),
+            ("init.scope", vec![1]),
+            ("system.slice", vec![]),
+            ("system.slice/cron.service", vec![2044]),
+            ("system.slice/ssh.service", vec![812]),
+            ("system.slice-extra", vec![2100]),
+            ("user.slice", vec![]),
+            ("user.slice/user-1000.slice", vec![]),
+            ("user.slice/user-1000.slice/session-2.scope", vec![2040, 2051]),
+        ];
+        assert_eq!(tree, expected.into_iter().map(|(c, p)| (c.to_string(), p)).collect::<Vec<_>>());
+    }
 
-    let fields = s.split(':').collect::<Vec<_>>();
-    if fields.len() != 2 {
-        return Err(mk_err());
+    #[test]
+    fn test_exec_size() {
+        let strings = |strings: &[&str]| strings.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let env = strings(&["HOME=/root", "PATH=/usr/bin:/bin"]);
+        let size = exec_size(&env, &strings(&["sleep", "100"]), Some(8 << 20));
+        assert_eq!(size, ExecSize { env_bytes: 11 + 8 + 19 + 8, env_count: 2, argv_bytes: 6 + 8 + 4 + 8,
+                                    argv_count: 2, limit: 2 << 20 });
+        assert!(exec_size_warnings(&env, &size, 4096).is_empty());
+
+        // The limit is never more than 6MiB, or less than ARG_MAX
+        assert_eq!(exec_size(&env, &[], None).limit, 6 << 20);
+        assert_eq!(exec_size(&env, &[], Some(64 * 1024)).limit, ARG_MAX);
+
+        let mut big = env.clone();
+        big.push(format!("CI_LOG={}", "x".repeat(90 * 1024)));
+        let size = exec_size(&big, &[], Some(256 * 1024));
+        let warnings = exec_size_warnings(&big, &size, 4096);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("% of the 131072 exec() allows"), "{}", warnings[0]);
+
+        big.push(format!("CI_LOG2={}", "x".repeat(130 * 1024)));
+        let size = exec_size(&big, &[], Some(256 * 1024));
+        let warnings = exec_size_warnings(&big, &size, 4096);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("more than the 131072 exec() allows with this stack rlimit"), "{}", warnings[0]);
+        assert!(warnings[1].starts_with("CI_LOG2 (envp[3]) is 133128 bytes"), "{}", warnings[1]);
     }
 
-    // Port is always printed with most-significant byte first.
-    let port = u16::from_str_radix(fields[1], 16).map_err(|_| mk_err())?;
+    #[test]
+    fn test_name_mismatches() {
+        let argv = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let kinds = |argv: &[String], comm, exe, link: bool| name_mismatches(argv, comm, exe, &|_| link)
+            .into_iter().map(|(kind, _)| kind).collect::<Vec<_>>();
 
-    // Address is printed with most-significant byte first on big-endian systems and vice-versa on
-    // little-endian systems.
-    let addr_native_endian = u32::from_str_radix(fields[0], 16).map_err(|_| mk_err())?;
-    let addr = Ipv4Addr::from(addr_native_endian.to_be());
+        assert_eq!(kinds(&argv(&["/usr/sbin/sshd", "-D"]), "sshd", "/usr/sbin/sshd", false), vec![]);
+        assert_eq!(kinds(&argv(&["-bash"]), "bash", "/usr/bin/bash", false), vec![]);
+        // comm is truncated to 15 bytes
+        assert_eq!(kinds(&argv(&["systemd-resolved"]), "systemd-resolve", "/usr/lib/systemd/systemd-resolved", false),
+                   vec![]);
+        // A script, run by its interpreter
+        assert_eq!(kinds(&argv(&["/usr/bin/python3", "/opt/app/worker.py"]), "worker.py", "/usr/bin/python3.11",
+                         false), vec![]);
 
-    Ok(SocketAddr::new(IpAddr::V4(addr), port))
-}
+        assert_eq!(kinds(&argv(&["nginx: worker process"]), "nginx", "/usr/sbin/nginx", false),
+                   vec![NameMismatch::Retitled]);
+        assert_eq!(kinds(&argv(&["ls", "-l"]), "ls", "/bin/busybox", true), vec![NameMismatch::Link]);
+        assert_eq!(kinds(&argv(&["java", "-jar", "app.jar"]), "C2 CompilerThre", "/usr/lib/jvm/bin/java", false),
+                   vec![NameMismatch::Renamed]);
+        assert_eq!(kinds(&argv(&["[kworker/0:2]"]), "kworker/0:2", "/tmp/.x/miner", false),
+                   vec![NameMismatch::Masquerade, NameMismatch::Renamed]);
+        assert_eq!(kinds(&argv(&["sshd"]), "sshd", "/dev/shm/x (deleted)", false),
+                   vec![NameMismatch::Deleted, NameMismatch::Masquerade]);
+        assert_eq!(kinds(&argv(&["payload"]), "3", "/memfd:payload (deleted)", false),
+                   vec![NameMismatch::Memfd, NameMismatch::Masquerade, NameMismatch::Renamed]);
+    }
 
-fn fetch_sock_info(pid: u64) -> Result<HashMap<u64, SockInfo>, Box<dyn Error>> {
-    let file = File::open(format!("/proc/{}/net/unix", pid)).unwrap();
-    let mut sockets = BufReader::new(file)
-              .lines()
-              .skip(1) // Header
-              .map(|line| {
-                  let line = line.unwrap();
-                  let fields = line.split_whitespace().collect::<Vec<&str>>();
-                  let inode = fields[6].parse().unwrap();
-                  let sock_info = SockInfo {
-                      family: AddressFamily::Unix,
-                      sock_type: parse_sock_type(fields[4]),
-                      inode: inode,
-                      local_addr: None,
-                      peer_addr: None,
-                      peer_pid: None,
-                  };
-                  (inode, sock_info)
-              }).collect::<HashMap<_,_>>();
-
-    let file = File::open(format!("/proc/{}/net/netlink", pid)).unwrap();
-    let netlink_sockets = BufReader::new(file)
-        .lines()
-        .skip(1) // Header
-        .map(|line| {
-            let line = line.unwrap();
-            let fields = line.split_whitespace().collect::<Vec<&str>>();
-            let inode = fields[9].parse().unwrap();
-            let sock_info = SockInfo {
-                family: AddressFamily::Netlink,
-                sock_type: SockType::Datagram,
-                inode: inode,
-                local_addr: None,
-                peer_addr: None,
-                peer_pid: None,
-            };
-            (inode, sock_info)
-        }).collect::<HashMap<_,_>>();
-    sockets.extend(netlink_sockets);
-
-    // procfs entries for tcp, udp, and raw sockets all use same format
-    let parse_file = |file, s_type| {
-        BufReader::new(file)
-              .lines()
-              .skip(1) // Header
-              .map(|line| {
-                  let line = line.unwrap();
-                  let fields = line.split_whitespace().collect::<Vec<&str>>();
-                  let inode = fields[9].parse().unwrap();
-                  let sock_info = SockInfo {
-                      family: AddressFamily::Inet,
-                      sock_type: s_type,
-                      local_addr: Some(parse_ipv4_sock_addr(fields[1]).unwrap()),
-                      peer_addr: Some(parse_ipv4_sock_addr(fields[2]).unwrap()),
-                      peer_pid: None,
-                      //state: u64::from_str_radix(fields[3], 16).unwrap(),
-                      inode: inode,
-                  };
-                  (inode, sock_info)
-              }).collect::<Vec<_>>().into_iter() // TODO the collect().into_iter() is obviously bad
-    };
-
-    sockets.extend(parse_file(
-        File::open(format!("/proc/{}/net/tcp", pid)).unwrap(),
-        SockType::Stream,
-    ));
-    sockets.extend(parse_file(
-        File::open(format!("/proc/{}/net/udp", pid)).unwrap(),
-        SockType::Datagram,
-    ));
-    sockets.extend(parse_file(
-        File::open(format!("/proc/{}/net/raw", pid)).unwrap(),
-        SockType::Raw,
-    ));
+    #[test]
+    fn test_file_access() {
+        let mut xattr = 2u32.to_le_bytes().to_vec();
+        for &(tag, perm, id) in &[(ACL_USER_OBJ, 6, u32::MAX), (ACL_USER, 7, 1001), (ACL_GROUP_OBJ, 4, u32::MAX),
+                                  (ACL_GROUP, 6, 2000), (ACL_MASK, 5, u32::MAX), (ACL_OTHER, 0, u32::MAX)] {
+            xattr.extend_from_slice(&tag.to_le_bytes());
+            xattr.extend_from_slice(&(perm as u16).to_le_bytes());
+            xattr.extend_from_slice(&id.to_le_bytes());
+        }
+        let acl = parse_posix_acl(&xattr).unwrap();
+        assert_eq!(acl[1], AclEntry { tag: ACL_USER, perm: 7, id: 1001 });
+        assert_eq!(parse_posix_acl(&xattr[..10]), None);
 
-    Ok(sockets)
-}
+        let creds = |fsuid, groups: &[u32], caps| {
+            FileCredentials { fsuid, fsgid: fsuid, groups: groups.to_vec(), caps }
+        };
+        let access = |creds: &FileCredentials, mode, acl: &[AclEntry]| {
+            let (access, reason) = file_access(creds, 1000, 1000, libc::S_IFREG | mode, acl);
+            (acl_perm_str(access), reason)
+        };
 
-/*
- * Some things about Illumos pfiles output seem less than ideal. For instance, would
- * printing 'TCP' be preferrable to 'SOCK_STREAM'? Could we add somewhere in output the
- * psuedo file for the socket? That could be very useful for manually inspecting or
- * draining the output.
- *
- *    435: S_IFSOCK mode:0666 dev:556,0 ino:38252 uid:0 gid:0 rdev:0,0
- *         O_RDWR
- *           SOCK_STREAM
- *           SO_SNDBUF(16384),SO_RCVBUF(5120)
- *           sockname: AF_UNIX
- *           peer: java[1053] zone: global[0]
- *
- * Another example: we can guess by the way that there is no peer address that this socket
- * is listening. Could we make this more explicit? Even for sockets that aren't listening, it
- * might be really useful to know the state of the connection
- *
- *    436: S_IFSOCK mode:0666 dev:556,0 ino:37604 uid:0 gid:0 rdev:0,0
- *         O_RDWR|O_NONBLOCK
- *           SOCK_STREAM
- *           SO_REUSEADDR,SO_SNDBUF(16777216),SO_RCVBUF(4194304)
- *           sockname: AF_INET6 ::  port: 8341
- */
+        let owner = creds(1000, &[], 0);
+        assert_eq!(access(&owner, 0o640, &[]).0, "rw-");
+        assert_eq!(access(&creds(1002, &[1000], 0), 0o640, &[]).0, "r--");
+        assert_eq!(access(&creds(1002, &[], 0), 0o640, &[]), ("---".to_string(), "as anyone else".to_string()));
 
-fn print_files(pid: u64) -> bool {
+        assert_eq!(access(&owner, 0o640, &acl).0, "rw-");
+        // The mask takes away the named user's write permission
+        let (perm, reason) = access(&creds(1001, &[], 0), 0o640, &acl);
+        assert_eq!(perm, "r-x");
+        assert!(reason.ends_with("(limited by the mask)"), "{}", reason);
+        // Members of both groups get what either allows, less the mask
+        assert_eq!(access(&creds(1003, &[1000, 2000], 0), 0o640, &acl).0, "r--");
+        assert_eq!(access(&creds(1003, &[], 0), 0o640, &acl).0, "---");
 
-    let proc_dir = format!("/proc/{}/", pid);
-    if !Path::new(&proc_dir).exists() {
-        eprintln!("No such directory {}", &proc_dir);
-        return false;
+        let root = creds(0, &[], 1 << CAP_DAC_OVERRIDE);
+        assert_eq!(access(&root, 0o600, &[]), ("rw-".to_string(), "as anyone else, and CAP_DAC_OVERRIDE".to_string()));
+        assert_eq!(access(&root, 0o700, &[]).0, "rwx");
+        assert_eq!(access(&creds(1003, &[], 1 << CAP_DAC_READ_SEARCH), 0o600, &[]).0, "r--");
     }
 
-    print_proc_summary(pid);
+    #[test]
+    fn test_progress_str() {
+        assert_eq!(progress_str(1200, 4000, Duration::from_millis(12500)), "1200 of 4000 processes (30%), 12s");
+        assert_eq!(progress_str(0, 0, Duration::from_secs(1)), "0 of 0 processes (0%), 1s");
+    }
 
-    // TODO print current rlimit
+    #[test]
+    fn test_parse_kernel_stack() {
+        let frames = parse_kernel_stack("[<0>] ep_poll+0x2a8/0x300\n[<ffffffff8123abcd>] do_epoll_wait+0xb0/0xd0\n\
+                                         [<0>] entry_SYSCALL_64_after_hwframe+0x76/0x7e\njunk\n");
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frame_line(&frames[0]), " 0000000000000000 ep_poll+0x2a8 (kernel)");
+        assert_eq!(frames[1].pc, 0xffffffff8123abcd);
+        assert_eq!(frames[1].symbol, Some(("do_epoll_wait".to_string(), 0xb0)));
 
-    let sockets = fetch_sock_info(pid).unwrap();
+        let state = TaskState { comm: "nginx".to_string(), state: "S (sleeping)".to_string(),
+                                wchan: Some("ep_poll".to_string()), cpu: Some(3) };
+        assert_eq!(stack_header(812, Some(&state), "  [wait channel only]"),
+                   "--- thread 812 (nginx)  S (sleeping)  cpu 3  wchan: ep_poll  [wait channel only] ---");
+        assert_eq!(parse_stack_lines(&stack_header(812, Some(&state), "  [kernel stack]"))[0].comm,
+                   Some("nginx".to_string()));
+    }
 
-    let fd_dir = format!("/proc/{}/fd/", pid);
-    let readdir_res = fs::read_dir(&fd_dir).and_then(|entries| {
-        for entry in entries {
-            let entry = entry?;
-            let filename = entry.file_name();
-            let filename = filename.to_string_lossy();
-            if let Ok(fd) = (&filename).parse::<u64>() {
-                print_file(pid, fd, &sockets);
-            } else {
-                eprint!("Unexpected file /proc/[pid]/fd/{} found", &filename);
-            }
+    #[test]
+    fn test_stdin_kind() {
+        let socket = |family, local: &str, peer: &str| SockInfo {
+            family, sock_type: SockType::Stream, inode: 1, local_addr: local.parse().ok(), peer_addr: peer.parse().ok(),
+            peer_process: None, protocol: None, ulp: None, netns: None, path: None, bound_if: None,
         };
-        Ok(())
-    });
+        assert_eq!(stdin_kind("/dev/pts/3", None), "tty /dev/pts/3");
+        assert_eq!(stdin_kind("/dev/null", None), "/dev/null");
+        assert_eq!(stdin_kind("pipe:[4242]", None), "pipe");
+        assert_eq!(stdin_kind("/var/log/in.txt (deleted)", None), "file /var/log/in.txt (deleted)");
+        assert_eq!(stdin_kind("socket:[99]", None), "socket");
+        assert_eq!(stdin_kind("socket:[99]", Some(&socket(AddressFamily::Unix, "", ""))), "unix socket");
+        assert_eq!(stdin_kind("socket:[99]", Some(&socket(AddressFamily::Inet, "0.0.0.0:22", "0.0.0.0:0"))),
+                   "socket on 0.0.0.0:22");
+        assert_eq!(stdin_kind("socket:[99]", Some(&socket(AddressFamily::Inet, "10.0.0.1:23", "10.0.0.7:51234"))),
+                   "socket from 10.0.0.7:51234");
+    }
 
-    if let Err(e) = readdir_res {
-        eprintln!("Unable to read {}: {}", &fd_dir, e);
-        return false;
+    #[test]
+    fn test_resolve_socket_peers() {
+        let socket = |inode, sock_type, local: &str, peer: &str| (inode, SockInfo {
+            family: AddressFamily::Inet, sock_type, inode, local_addr: local.parse().ok(), peer_addr: peer.parse().ok(),
+            peer_process: None, protocol: None, ulp: None, netns: None, path: None, bound_if: None,
+        });
+        let mut sockets = vec![
+            socket(1, SockType::Stream, "127.0.0.1:40100", "127.0.0.1:5432"),
+            socket(2, SockType::Stream, "127.0.0.1:5432", "127.0.0.1:40100"),
+            socket(3, SockType::Stream, "0.0.0.0:5432", "0.0.0.0:0"),
+            socket(4, SockType::Stream, "10.0.0.1:22", "10.0.0.9:61000"),
+            socket(5, SockType::Stream, "10.0.0.1:50000", "10.0.0.9:50001"),
+        ].into_iter().collect::<HashMap<_, _>>();
+        // The other end of the first connection is ours, and nothing owns the others
+        let me = std::process::id() as u64;
+        let owners = SocketOwners(OnceLock::from(vec![(2, me)].into_iter().collect::<HashMap<_, _>>()));
+        resolve_socket_peers(&mut sockets, &owners);
+        assert_eq!(sockets[&1].protocol, Some("postgresql"));
+        assert_eq!(sockets[&2].protocol, Some("postgresql"));
+        assert_eq!(sockets[&3].protocol, None);
+        assert_eq!(peer_str(&sockets[&4]), Some("tcp -> 10.0.0.9:61000 (ssh)".to_string()));
+        assert_eq!(peer_str(&sockets[&5]), None);
+        assert_eq!(sockets[&1].peer_process.as_ref().map(|&(pid, _)| pid), Some(me));
+        assert_eq!(sockets[&2].peer_process, None);
+
+        sockets.get_mut(&1).unwrap().peer_process = Some((1234, "postgres".to_string()));
+        assert_eq!(peer_str(&sockets[&1]), Some("tcp -> 127.0.0.1:5432 (postgresql; postgres, pid 1234)".to_string()));
     }
 
-    return true;
-}
+    #[test]
+    fn test_format_time() {
+        let time = std::time::UNIX_EPOCH + Duration::from_millis(1_709_312_645_123);
+        let format = |style, utc| TimeFormat { style, utc };
+        assert_eq!(format_time(time, format(TimeStyle::Local, false), 3600), "2024-03-01 18:04:05 +0100");
+        assert_eq!(format_time(time, format(TimeStyle::Local, false), -4 * 3600 - 1800), "2024-03-01 12:34:05 -0430");
+        assert_eq!(format_time(time, format(TimeStyle::Local, true), 3600), "2024-03-01 17:04:05 UTC");
+        assert_eq!(format_time(time, format(TimeStyle::Iso, false), 3600), "2024-03-01T18:04:05.123+01:00");
+        assert_eq!(format_time(time, format(TimeStyle::Iso, true), 3600), "2024-03-01T17:04:05.123Z");
+        assert_eq!(format_time(time, format(TimeStyle::Epoch, false), 3600), "1709312645.123");
+        // Across midnight and a leap day
+        assert_eq!(format_time(std::time::UNIX_EPOCH + Duration::from_secs(951_868_799), format(TimeStyle::Local, false),
+                               3600), "2000-03-01 00:59:59 +0100");
+    }
 
-pub fn pargs_main() {
-    let args: Vec<String> = env::args().collect();
-    let program = &args[0];
+    #[test]
+    fn test_capability_holders() {
+        assert_eq!(parse_capability("cap_sys_admin"), Some(21));
+        assert_eq!(parse_capability("NET_RAW"), Some(13));
+        assert_eq!(parse_capability("cap_flying"), None);
+        assert!(DANGEROUS_CAPABILITIES.iter().all(|name| parse_capability(name).is_some()));
 
-    let opts = {
-        let mut opts = Options::new();
-        opts.optflag("a", "", "Print command line args to process");
-        // We have a separate penv command, but keep this option for compatibility with Solaris
-        opts.optflag("e", "", "Print environement variables of process");
-        opts.optflag("h", "help", "print this help message");
-        opts.parsing_style(ParsingStyle::StopAtFirstFree);
-        opts
-    };
+        let root = CapabilitySets { permitted: u64::MAX, effective: u64::MAX, bounding: u64::MAX, ..Default::default() };
+        // A daemon which keeps cap_net_raw around, but only raises it when it needs it
+        let ping = CapabilitySets { permitted: 1 << 13, effective: 0, bounding: u64::MAX, ..Default::default() };
+        let user = CapabilitySets { bounding: u64::MAX, ..Default::default() };
+        let processes = [(1, root), (500, ping), (1000, user)];
+        assert_eq!(capability_holders(&processes, &[21, 13]),
+                   vec![(21, vec![(1, true)]), (13, vec![(1, true), (500, false)])]);
+        assert_eq!(capability_holders(&processes[1..], &[21]), vec![(21, vec![])]);
 
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(e) => {
-            eprint!("{}\n", e.to_string());
-            usage_err(program, opts);
-        }
-    };
+        let me = read_capability_sets(std::process::id() as u64).unwrap();
+        assert_eq!(me.effective & !me.permitted, 0);
+    }
 
-    if matches.opt_present("h") {
-        usage(program, opts);
+    #[test]
+    fn test_namespace_str() {
+        assert_eq!(parse_ns_link("net:[4026531840]"), Some(("net", 4026531840)));
+        assert_eq!(parse_ns_link("mnt:[4026531841]"), Some(("mnt", 4026531841)));
+        assert_eq!(parse_ns_link("socket:[4026531840]"), None);
+        assert_eq!(parse_ns_link("net:[x]"), None);
+        assert_eq!(parse_ns_link("/var/run/netns/blue"), None);
+
+        let me = std::process::id() as u64;
+        let own = process_namespace(me, "net").unwrap();
+        assert_eq!(namespace_str(me, "net", own), format!("network namespace net:[{}], the process' own", own));
+        assert_eq!(namespace_str(me, "uts", 1), "UTS namespace uts:[1], which no process is in");
     }
 
-    if matches.free.len() == 0 {
-        usage_err(program, opts);
+    #[test]
+    fn test_common_env() {
+        let env = |vars: &str| vars.split(' ').map(str::to_string).collect::<Vec<_>>();
+        let workers = [env("PATH=/bin HOME=/srv ID=1"), env("HOME=/srv PATH=/bin ID=2"), env("PATH=/bin HOME=/srv ID=3"),
+                       env("PATH=/usr/bin HOME=/srv ID=4 DEBUG=1")];
+        let common = common_env(&workers.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        assert_eq!(common, ["HOME=/srv", "PATH=/bin"]);
+        assert_eq!(env_differences(&workers[1], &common), (vec![&workers[1][2]], vec![]));
+        assert_eq!(env_differences(&workers[3], &common),
+                   (vec![&workers[3][3], &workers[3][2], &workers[3][0]], vec![&common[1]]));
+
+        // Two processes only have what both have in common
+        assert_eq!(common_env(&[&workers[0][..], &workers[3][..]]), ["HOME=/srv"]);
+        assert_eq!(common_env(&[&workers[0][..]]), ["HOME=/srv", "ID=1", "PATH=/bin"]);
     }
+    #[test]
+    fn test_file_hash() {
+        assert_eq!(sha256_hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // Two blocks once padded
+        assert_eq!(sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+                   "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
 
-    let do_print_args = matches.opt_present("a");
-    let do_print_env = matches.opt_present("e");
+        let dir = std::env::temp_dir().join(format!("ptools-hash-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.conf");
+        fs::write(&path, "abc").unwrap();
+        let file = File::open(&path).unwrap();
+        let pid = u64::from(std::process::id());
+        let info = || {
+            use std::os::unix::io::AsRawFd;
+            file_info(pid, file.as_raw_fd() as u64).unwrap()
+        };
+        let hash = file_hash(pid, &info(), 1024).unwrap();
+        assert_eq!(hash.sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hash.on_disk, OnDisk::Same);
+        assert!(file_hash(pid, &info(), 2).is_none());
+        let version = File::open("/proc/version").unwrap();
+        assert!(file_hash(pid, &{
+            use std::os::unix::io::AsRawFd;
+            file_info(pid, version.as_raw_fd() as u64).unwrap()
+        }, 1024).is_none());
 
-    for arg in &matches.free {
-        let pid = arg.parse::<u64>().unwrap();
-        if do_print_args || !do_print_env {
-            print_args(pid);
-        }
+        // Replaced the way editors do, by renaming a new file over it
+        fs::write(dir.join("app.conf.new"), "").unwrap();
+        fs::rename(dir.join("app.conf.new"), &path).unwrap();
+        let hash = file_hash(pid, &info(), 1024).unwrap();
+        assert_eq!(hash.sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hash.on_disk,
+                   OnDisk::Replaced(Some("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string())));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(file_hash(pid, &info(), 1024).unwrap().on_disk, OnDisk::Gone);
+        fs::remove_dir(&dir).unwrap();
+    }
+    #[test]
+    fn test_pboot() {
+        assert_eq!(personality_str(0), "PER_LINUX");
+        assert_eq!(personality_str(0x0040000), "PER_LINUX|ADDR_NO_RANDOMIZE");
+        assert_eq!(personality_str(0x8000008), "PER_LINUX32|ADDR_LIMIT_3GB");
+        assert_eq!(personality_str(0x10000003), "domain 0x3|0x10000000");
 
-        if do_print_env {
-            print_env(pid);
-        }
+        let stat = "cpu  1 2 3 4\nintr 100\nctxt 200\nbtime 1709312645\nprocesses 300\n";
+        assert_eq!(parse_boot_time(stat), Some(std::time::UNIX_EPOCH + Duration::from_secs(1_709_312_645)));
+        assert_eq!(parse_boot_time("cpu  1 2 3 4\n"), None);
     }
-}
+    #[test]
+    fn test_visual_ascii() {
+        let mapping = |start: u64, end: u64, perms: &str, path: &str| Mapping {
+            start, end, perms: perms.to_string(), offset: 0, dev: (0, 0), inode: 0, path: path.to_string(),
+            sizes: HashMap::new(), vm_flags: vec![],
+        };
+        let mappings = vec![
+            mapping(0x10000, 0x14000, "r-xp", "/usr/bin/app"),
+            mapping(0x14000, 0x18000, "rw-p", "/usr/bin/app"),
+            mapping(0x20000, 0x30000, "rw-p", "[heap]"),
+            mapping(0x7f0000000000, 0x7f0000008000, "r-xp", "/usr/lib/libc.so.6"),
+            mapping(0x7f0000008000, 0x7f0000010000, "rw-p", ""),
+            mapping(0x7ffff0000000, 0x7ffff0004000, "rw-p", "[stack]"),
+        ];
+        assert_eq!(address_runs(&mappings, 0x10000).iter().map(|run| run.len()).collect::<Vec<_>>(), [3, 2, 1]);
 
-pub fn penv_main() {
-    let args: Vec<String> = env::args().collect();
-    let program = &args[0];
+        let rows = visual_ascii(&mappings, 8, 0x4000);
+        let gap = |size: &str| format!("{: <16}   ~ {} unmapped ~", "", size);
+        assert_eq!(rows, [
+            "0000000000010000 |XF..HHHH| app".to_string(),
+            gap("127.0T"),
+            "00007f0000000000 |XXAA    | libc.so.6".to_string(),
+            gap("1023.7G"),
+            "00007ffff0000000 |S       |".to_string(),
+        ]);
+    }
+    #[test]
+    fn test_port_sockets() {
+        let rows = |table: &str| table.lines().map(|line| line.split_whitespace().map(str::to_string).collect())
+            .collect::<Vec<Vec<String>>>();
+        let tcp = rows("0: 00000000:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000 0 0 1001 1\n\
+                        1: 0100007F:1F90 0100007F:A2C4 01 00000000:00000000 00:00000000 00000000 0 0 1002 1\n\
+                        2: 0100007F:A2C4 0100007F:1F90 01 00000000:00000000 00:00000000 00000000 0 0 1003 1\n\
+                        3: 00000000:1538 00000000:0000 0A 00000000:00000000 00:00000000 00000000 0 0 1004 1");
+        // Only the listening socket, not the connections accepted from it or made to it
+        assert_eq!(port_sockets(&tcp, &[8080], true), [1001]);
+        assert_eq!(port_sockets(&tcp, &[8080, 5432], true), [1001, 1004]);
+        assert_eq!(port_sockets(&tcp, &[80], true), Vec::<u64>::new());
 
-    let opts = {
-        let mut opts = Options::new();
-        opts.optflag("h", "help", "print this help message");
-        opts.parsing_style(ParsingStyle::StopAtFirstFree);
-        opts
-    };
+        let udp6 = rows("0: 00000000000000000000000000000000:0035 00000000000000000000000000000000:0000 07 \
+                         00000000:00000000 00:00000000 00000000 0 0 2001 2");
+        assert_eq!(port_sockets(&udp6, &[53], false), [2001]);
 
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(e) => {
-            eprint!("{}\n", e.to_string());
-            usage_err(program, opts);
-        }
-    };
+        let me = std::process::id() as u64;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let selection = ProcessSelection { ports: vec![port], ..Default::default() };
+        assert!(selection.is_active());
+        assert!(selection.matches(me));
+        assert!(!ProcessSelection { ports: vec![port], ..Default::default() }.matches(1));
+    }
+    #[test]
+    fn test_scm_fds_in_flight() {
+        use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+        use std::os::unix::io::AsRawFd;
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let file = File::open("/proc/self/stat").unwrap();
+        let fds = [file.as_raw_fd(), file.as_raw_fd()];
+        sendmsg(sender.as_raw_fd(), &[IoVec::from_slice(b"x")], &[ControlMessage::ScmRights(&fds)], MsgFlags::empty(),
+                None).unwrap();
 
-    if matches.opt_present("h") {
-        usage(program, opts);
+        let me = u64::from(std::process::id());
+        let fdinfo = fs::read_to_string(format!("/proc/self/fdinfo/{}", receiver.as_raw_fd())).unwrap();
+        // Kernels before 5.8 don't count them
+        if fdinfo.contains("scm_fds:") {
+            assert_eq!(file_info(me, receiver.as_raw_fd() as u64).unwrap().scm_fds, Some(2));
+            assert_eq!(file_info(me, sender.as_raw_fd() as u64).unwrap().scm_fds, Some(0));
+        }
+        assert_eq!(file_info(me, file.as_raw_fd() as u64).unwrap().scm_fds, None);
+    }
+    #[test]
+    fn test_ptree_field_lines() {
+        let fields = [PtreeField::User, PtreeField::Rss, PtreeField::Nlwp];
+        let values = |values: &[&str]| Some(values.iter().map(|v| v.to_string()).collect());
+        let lines = vec![
+            ("1  /sbin/init ".to_string(), values(&["root", "12044", "1"])),
+            ("  812  /usr/sbin/sshd -D ".to_string(), values(&["root", "7300", "1"])),
+            ("    4711  sshd: alice [priv] ".to_string(), values(&["alice", "-", "12"])),
+            ("    4712  make -j8 [exited: status 2]".to_string(), None),
+        ];
+        assert_eq!(ptree_field_lines(&lines, &fields), [
+            "                              USER     RSS  NLWP",
+            "1  /sbin/init                 root   12044     1",
+            "  812  /usr/sbin/sshd -D      root    7300     1",
+            "    4711  sshd: alice [priv]  alice      -    12",
+            "    4712  make -j8 [exited: status 2]",
+        ]);
+        assert_eq!(PtreeField::parse("cpu"), Some(PtreeField::Cpu));
+        assert_eq!(PtreeField::parse("pcpu"), None);
     }
 
-    if matches.free.len() == 0 {
-        usage_err(program, opts);
+    #[test]
+    fn test_read_args() {
+        let cmdline = b"prog\0--name=caf\xc3\xa9\0bad\xff\0".to_vec();
+        let read = |limits| read_args(&cmdline[..], limits, true).unwrap();
+
+        let all = read(ArgLimits::default());
+        assert_eq!(all.args, vec!["prog", "--name=caf\u{e9}", "bad\\xff"]);
+        assert_eq!((all.kept_bytes, all.total_args, all.total_bytes), (23, 3, 23));
+        assert!(!all.truncated());
+        assert_eq!(read_args(&cmdline[..], ArgLimits::default(), false).unwrap().args[1], "--name=caf\\xc3\\xa9");
+
+        let two = read(ArgLimits { max_args: Some(2), max_bytes: None });
+        assert_eq!(two.args.len(), 2);
+        assert_eq!((two.kept_bytes, two.total_args, two.total_bytes, two.cut), (18, 3, 23, false));
+        assert!(two.truncated());
+
+        let cut = read(ArgLimits { max_args: None, max_bytes: Some(9) });
+        assert_eq!(cut.args, vec!["prog", "--na"]);
+        assert_eq!((cut.kept_bytes, cut.cut), (9, true));
+
+        // An argument which ends right at the limit is whole
+        let exact = read(ArgLimits { max_args: None, max_bytes: Some(5) });
+        assert_eq!(exact.args, vec!["prog"]);
+        assert!(!exact.cut && exact.truncated());
     }
 
-    for arg in &matches.free {
-        let pid = arg.parse::<u64>().unwrap();
-        print_env(pid);
+    #[test]
+    fn test_addr_scope() {
+        let scope = |addr: &str| addr_scope(addr.parse().unwrap());
+        assert_eq!(scope("0.0.0.0"), "wildcard");
+        assert_eq!(scope("::"), "wildcard");
+        assert_eq!(scope("127.0.0.53"), "loopback");
+        assert_eq!(scope("::1"), "loopback");
+        assert_eq!(scope("169.254.169.254"), "link-local");
+        assert_eq!(scope("fe80::1"), "link-local");
+        assert_eq!(scope("10.1.2.3"), "private");
+        assert_eq!(scope("fd12:3456::1"), "private");
+        assert_eq!(scope("::ffff:192.168.0.1"), "private");
+        assert_eq!(scope("224.0.0.251"), "multicast");
+        assert_eq!(scope("8.8.8.8"), "global");
+        assert_eq!(scope("2001:db8::1"), "global");
+
+        assert_eq!(bound_if_str(&BoundIf { index: 3, name: Some("vrf-blue".to_string()) }), "vrf-blue (ifindex 3)");
+        assert_eq!(bound_if_str(&BoundIf { index: 3, name: None }), "ifindex 3");
     }
-}
 
-pub fn pfiles_main() {
-    let args: Vec<String> = env::args().collect();
-    let program = &args[0];
+    #[test]
+    fn test_pstartup() {
+        assert_eq!(tty_name(0), None);
+        assert_eq!(tty_name(34816 + 3), Some("/dev/pts/3".to_string()));
+        // pts 300 is minor 44 of the second major
+        assert_eq!(tty_name((137 << 8) | 44), Some("/dev/pts/300".to_string()));
+        assert_eq!(tty_name((4 << 8) | 1), Some("/dev/tty1".to_string()));
+        assert_eq!(tty_name((4 << 8) | 65), Some("/dev/ttyS1".to_string()));
+        assert_eq!(tty_name((5 << 8) | 1), Some("/dev/console".to_string()));
 
-    let opts = {
-        let mut opts = Options::new();
-        opts.optflag("h", "help", "print this help message");
-        opts.parsing_style(ParsingStyle::StopAtFirstFree);
-        opts
-    };
+        assert_eq!(shell_word("--name=x"), "--name=x");
+        assert_eq!(shell_word("import time; time.sleep(1)"), "'import time; time.sleep(1)'");
+        assert_eq!(shell_word("it's"), "'it'\\''s'");
+        assert_eq!(shell_word(""), "''");
 
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(e) => {
-            eprint!("{}\n", e.to_string());
-            usage_err(program, opts);
+        let startup = |unit: Option<&str>, tty: Option<&str>, ancestors: &[(u64, &str)]| Startup {
+            exe: None, argv: vec![], cwd: None, user: "root".to_string(), audit_ids: (None, None), started: None,
+            tty: tty.map(str::to_string), unit: unit.map(str::to_string),
+            ancestors: ancestors.iter().map(|&(pid, comm)| (pid, comm.to_string())).collect(), env_delta: None,
+        };
+        assert_eq!(startup(Some("nginx.service"), None, &[(1, "systemd")]).summary(),
+                   "started by the service manager for nginx.service");
+        assert!(startup(None, None, &[(1, "systemd")]).summary().contains("parent probably exited"));
+        assert_eq!(startup(Some("nginx.service"), None, &[(812, "nginx"), (1, "systemd")]).summary(),
+                   "started by nginx (pid 812), in nginx.service");
+        assert_eq!(startup(None, Some("/dev/pts/3"), &[(4200, "bash"), (4100, "sshd"), (1, "systemd")]).summary(),
+                   "started by bash (pid 4200) on /dev/pts/3");
+        assert_eq!(startup(None, None, &[(4200, "cron"), (1, "systemd")]).summary(), "started by cron (pid 4200)");
+        assert_eq!(startup(None, None, &[]).summary(), "started by the kernel");
+        assert_eq!(startup(None, None, &[(2, "kthreadd")]).summary(), "a kernel thread, started by kthreadd (pid 2)");
+        // The only ancestor isn't pid 1 when it was started by something which is in another pid
+        // namespace, or whose own parent couldn't be read
+        assert_eq!(startup(None, None, &[(4200, "containerd-shim")]).summary(),
+                   "started by containerd-shim (pid 4200)");
+
+        assert_eq!(audit_ids_str(None, None), "unset, it isn't part of a login");
+        assert_eq!(audit_ids_str(Some(0), Some(42)), "root (0), session 42");
+        assert_eq!(audit_ids_str(None, Some(42)), "unset, session 42");
+    }
+
+    #[test]
+    fn test_lookup_in_namespace() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = std::env::temp_dir().join(format!("ptools-join-ns-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (file, other, socket) = (dir.join("file"), dir.join("other"), dir.join("socket"));
+        fs::write(&file, "a").unwrap();
+        fs::write(&other, "b").unwrap();
+        let _listener = UnixListener::bind(&socket).unwrap();
+        let id = |path: &Path| fs::metadata(path).map(|m| (m.dev(), m.ino())).unwrap();
+        let path = |path: &Path| path.to_string_lossy().into_owned();
+        let paths = vec![
+            (path(&file), Some(id(&file))),
+            (path(&file), Some(id(&other))),
+            (path(&dir.join("missing")), Some(id(&file))),
+            (path(&socket), None),
+            (path(&file), None),
+        ];
+        // Joining a mount namespace, even our own, takes CAP_SYS_ADMIN
+        let answers = lookup_in_namespace(std::process::id() as u64, &paths);
+        fs::remove_dir_all(&dir).unwrap();
+        if !has_capability(read_capability_sets(std::process::id() as u64).unwrap().effective, 21) {
+            return;
         }
-    };
+        assert_eq!(answers, Some(vec![NsPath::Same, NsPath::Other, NsPath::Missing, NsPath::Same, NsPath::Other]));
+    }
 
-    if matches.opt_present("h") {
-        usage(program, opts);
+    #[test]
+    fn test_tree_counts() {
+        let process = |pid, children, threads, zombie, user: &str, service: Option<&str>| CountedProcess {
+            pid, children, threads, zombie, user: user.to_string(), service: service.map(str::to_string),
+        };
+        let processes = vec![
+            process(1, 3, 1, false, "root", None),
+            process(400, 4, 2, false, "root", Some("nginx.service")),
+            process(401, 0, 8, false, "www-data", Some("nginx.service")),
+            process(402, 0, 8, true, "www-data", Some("nginx.service")),
+            process(500, 4, 1, false, "postgres", Some("postgresql.service")),
+        ];
+        let counts = tree_counts(&processes);
+        assert_eq!(counts, TreeCounts {
+            processes: 5,
+            threads: 20,
+            zombies: 1,
+            users: vec![("root".to_string(), 2), ("www-data".to_string(), 2), ("postgres".to_string(), 1)],
+            services: vec![("nginx.service".to_string(), 3), ("postgresql.service".to_string(), 1)],
+            widest: Some((400, 4)),
+        });
+        assert_eq!(tree_counts(&[]).widest, None);
     }
 
-    if matches.free.len() == 0 {
-        usage_err(program, opts);
+    #[test]
+    fn test_working_set() {
+        assert_eq!(pagemap_pfn(1 << 63 | 0x1234), Some(0x1234));
+        assert_eq!(pagemap_pfn(1 << 63 | 1 << 61 | 0x1234), Some(0x1234));
+        assert_eq!(pagemap_pfn(1 << 62 | 0x1234), None);
+        assert_eq!(bitmap_words(&[1, 3, 64, 200, 255]), vec![(0, 0b1010), (1, 1), (3, 1 << 8 | 1 << 63)]);
+
+        // Frame 1 was used, 3 wasn't, 64 didn't take the mark and 70 was faulted in since
+        let marks = IdleMarks { marked: vec![1, 3, 64], tracked: HashMap::from([(0, 0b1010)]) };
+        let idle = HashMap::from([(0, 0b1000), (1, 0)]);
+        assert_eq!(WorkingSet::count(&marks, &[1, 3, 64, 70], &idle),
+                   WorkingSet { resident: 4, accessed: 2, idle: 1, untracked: 1 });
     }
 
-    let mut error = false;
-    for arg in &matches.free {
-        let pid = arg.parse::<u64>().unwrap();
-        error = error || !print_files(pid);
+    #[test]
+    fn test_skip_unresponsive_mounts() {
+        use std::os::unix::io::AsRawFd;
+        let file = File::open("/proc/self/status").unwrap();
+        let fd = file.as_raw_fd() as u64;
+        let pid = std::process::id() as u64;
+        let mounts = parse_mountinfo(&fs::read_to_string("/proc/self/mountinfo").unwrap());
+        let on = fd_mount_ids(pid, &[fd]);
+        assert_eq!(on.len(), 1);
+        assert!(mounts.iter().any(|mount| mount.id == on[0].1 && mount.fs_type == "proc"));
+        assert_eq!(probe_mounts(pid, &[(on[0].1, fd)], Duration::from_secs(5)), Vec::<u64>::new());
+        let (fds, unresponsive) = skip_unresponsive_mounts(pid, vec![fd], &mounts, Some(Duration::from_secs(5)));
+        assert_eq!(fds, vec![fd]);
+        assert!(unresponsive.is_empty());
     }
 
-    if error {
-        exit(1);
+    #[test]
+    fn test_lineage_str() {
+        let ancestors = vec![(300, "make".to_string()), (200, "bash".to_string()), (100, "sshd".to_string()),
+                             (1, "systemd".to_string())];
+        assert_eq!(lineage_str(&ancestors, "cc1", true),
+                   "systemd \u{2192} sshd \u{2192} bash \u{2192} make \u{2192} cc1");
+        assert_eq!(lineage_str(&ancestors, "cc1", false), "systemd -> sshd -> bash -> make -> cc1");
+        assert_eq!(lineage_str(&[], "systemd", true), "systemd");
     }
-}
 
-pub fn ptree_main() {
-    let args: Vec<String> = env::args().collect();
-    let program = &args[0];
+    #[test]
+    fn test_file_locks() {
+        // As the kernel lists a write lock with a writer waiting for it, and a reader behind that
+        let locks = parse_file_locks("\
+1: POSIX  ADVISORY  WRITE 1234 08:01:5678 0 EOF
+1: -> POSIX  ADVISORY  WRITE 2345 08:01:5678 0 99
+1:  -> OFDLCK ADVISORY  READ -1 08:01:5678 10 19
+2: FLOCK  ADVISORY  WRITE 30777 fe:00:16171144 0 EOF
+2: -> FLOCK  ADVISORY  WRITE 30781 fe:00:16171144 0 EOF
+3: LEASE  ACTIVE    READ  5678 fd:00:7 0 EOF
+");
+        assert_eq!(locks.len(), 6);
+        assert_eq!(locks[0], FileLock { id: 1, depth: 0, kind: "POSIX".to_string(), access: "WRITE".to_string(),
+                                        pid: Some(1234), dev: (8, 1), inode: 5678, start: 0, end: None });
+        assert_eq!((locks[2].depth, locks[2].pid, locks[2].end), (2, None, Some(19)));
+        assert_eq!(locks[5].dev, (0xfd, 0));
+        assert_eq!(locks[2].describe(), "OFDLCK READ lock, bytes 10-19");
+        assert_eq!(locks[3].describe(), "FLOCK WRITE lock");
 
-    let opts = {
+        assert_eq!(lock_blocker(&locks, &locks[1]), Some(&locks[0]));
+        assert_eq!(lock_blocker(&locks, &locks[2]), Some(&locks[1]));
+        assert_eq!(lock_blocker(&locks, &locks[4]), Some(&locks[3]));
+        assert_eq!(lock_waiters(&locks, &locks[0]), vec![&locks[1]]);
+        assert_eq!(lock_waiters(&locks, &locks[5]), Vec::<&FileLock>::new());
+        assert_eq!((locks[1].depth, locks[4].depth, locks[4].pid), (1, 1, Some(30781)));
+
+        // fdinfo numbers the locks it lists itself
+        let fdinfo = FileLock::parse(" 0: FLOCK  ADVISORY  WRITE 30777 fe:00:16171144 0 EOF").unwrap();
+        assert!(fdinfo.same(&locks[3]) && !fdinfo.same(&locks[4]));
+    }
+
+    #[test]
+    fn test_fs_used_percent() {
+        assert_eq!(fs_used_percent(1000, 500, 450), Some(53));
+        // The blocks reserved for root don't count as available
+        assert_eq!(fs_used_percent(1000, 50, 0), Some(100));
+        assert_eq!(fs_used_percent(1000, 1000, 950), Some(0));
+        assert_eq!(fs_used_percent(0, 0, 0), None);
+    }
+
+    #[test]
+    fn test_ptree_label() {
+        let label = PtreeLabel::parse("{pid} {user} [{{{comm}}}]").unwrap();
+        assert_eq!(label, PtreeLabel(vec![LabelPart::Pid, LabelPart::Text(" ".to_string()),
+                                          LabelPart::Field(PtreeField::User), LabelPart::Text(" [{".to_string()),
+                                          LabelPart::Comm, LabelPart::Text("}]".to_string())]));
+        let pid = std::process::id() as u64;
+        assert_eq!(PtreeLabel::parse("pid={pid}").unwrap().format(pid), format!("pid={}", pid));
+        assert!(PtreeLabel::parse("{pid").unwrap_err().contains("unclosed"));
+        assert!(PtreeLabel::parse("{name}").unwrap_err().contains("unknown field '{name}'"));
+        assert!(PtreeLabel::parse("pid}").unwrap_err().contains("unmatched"));
+    }
+
+    #[test]
+    fn test_selection_terms() {
+        let args = |args: &str| args.split_whitespace().map(String::from).collect::<Vec<_>>();
+        let filter = |name: &str, value: &str| SelectionTerm::Filter(name.to_string(), value.to_string());
         let mut opts = Options::new();
-        opts.optflag("h", "help", "print this help message");
+        add_selection_opts(&mut opts);
+        opts.optopt("", "fd", "", "FD");
+        opts.optopt("", "label", "", "LABEL");
+        opts.optflag("a", "", "");
+        opts.optflag("", "json", "");
         opts.parsing_style(ParsingStyle::StopAtFirstFree);
-        opts
-    };
+        let terms = |line: &str| selection_terms(&opts, &args(line));
+        assert_eq!(terms("-u foo --not --match=ssh* --fd 5 --cgroup /system.slice --or -uroot 42"),
+                   vec![filter("user", "foo"), SelectionTerm::Not, filter("match", "ssh*"),
+                        filter("cgroup", "/system.slice"), SelectionTerm::Or, filter("user", "root")]);
+        assert_eq!(terms("--json -a -- --not"), vec![]);
+        // Values are whatever getopts takes them to be, even when they look like options
+        assert_eq!(terms("--label --not --match --or -u root"),
+                   vec![filter("match", "--or"), filter("user", "root")]);
+        assert_eq!(terms("42 --not"), vec![]);
 
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(e) => {
-            eprint!("{}\n", e.to_string());
-            usage_err(program, opts);
-        }
-    };
+        let me = std::process::id() as u64;
+        let uid = unsafe { libc::getuid() };
+        let selection = |terms: Vec<SelectionTerm>| ProcessSelection::from_terms(&terms);
+        let us = || filter("user", &uid.to_string());
+        let others = || filter("user", &(uid + 1).to_string());
+        // Our own command line is the path of the test binary, with its arguments
+        let ours = || filter("match", "*ptools2-*");
+        assert!(selection(vec![us(), ours()]).unwrap().matches(me));
+        assert!(!selection(vec![us(), SelectionTerm::Not, ours()]).unwrap().matches(me));
+        assert!(selection(vec![SelectionTerm::Not, others()]).unwrap().matches(me));
+        assert!(selection(vec![SelectionTerm::Not, others()]).unwrap().is_active());
+        assert!(!selection(vec![others()]).unwrap().matches(me));
+        assert!(selection(vec![others(), SelectionTerm::Or, ours()]).unwrap().matches(me));
+        assert!(!selection(vec![others(), SelectionTerm::Or, us(), SelectionTerm::Not, ours()]).unwrap().matches(me));
+        // Giving one kind of option again adds to what it lets through, as before
+        assert!(selection(vec![others(), us()]).unwrap().matches(me));
+        assert!(selection(vec![filter("cgroup", "/")]).unwrap().matches(me));
 
-    if matches.opt_present("h") {
-        usage(program, opts);
+        assert!(selection(vec![SelectionTerm::Not]).is_err());
+        assert!(selection(vec![SelectionTerm::Not, SelectionTerm::Not, us()]).is_err());
+        assert!(selection(vec![SelectionTerm::Or, us()]).is_err());
+        assert!(selection(vec![us(), SelectionTerm::Or]).is_err());
+        assert!(selection(vec![filter("port", "http")]).is_err());
+        assert!(selection(vec![us(), SelectionTerm::Not, filter("match", "^ssh")]).unwrap().matches(me));
+        assert!(!selection(vec![us(), SelectionTerm::Not, filter("match", "^*ptools2-")]).unwrap().matches(me));
+        assert_eq!(anchored_pattern("^ssh"), "ssh*");
+        assert_eq!(anchored_pattern("sshd$"), "*sshd");
+        assert_eq!(anchored_pattern("^sshd: *$"), "sshd: *");
+        assert_eq!(anchored_pattern("ssh*"), "ssh*");
+        assert_eq!(anchored_pattern("price\\$"), "price\\$");
+        assert!(selection(vec![filter("container", "")]).is_err());
+        assert!(selection(vec![filter("pod", "")]).is_err());
     }
 
-    if matches.free.len() == 0 {
-        // Should we print all processes here, including kernel threads? Is there any way this
-        // could miss userspace processes?
-        print_tree(1).unwrap();
-    } else {
-        // This loop parses /proc/<pid>/status for each process in the system for each
-        // argument provided. Should rearrange it so it's only parsed once.
-        for arg in &matches.free {
-            let pid = arg.parse::<u64>().unwrap();
-            print_tree(pid).unwrap();
+    #[test]
+    fn test_snapshot_log() {
+        let dir = std::env::temp_dir().join(format!("ptools-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = SnapshotLog {
+            path: dir.join("pargs.jsonl"), tool: "pargs", interval: Duration::from_secs(1), rotate: 100,
+            daemon: false,
+        };
+        let me = std::process::id() as u64;
+        let line = log.line(strings_snapshot(&[me, u32::MAX as u64], "args", "cmdline"));
+        let snapshot = Json::parse(&line).unwrap();
+        assert_eq!(snapshot.get("tool").and_then(Json::as_str), Some("pargs"));
+        let processes = snapshot.get("processes").unwrap().to_string();
+        assert!(processes.starts_with(&format!("[{{\"pid\":{},", me)) && processes.contains("\"args\":["));
+
+        // Each line is 40 bytes, so two fit in a log before it is rotated
+        let line = |n| format!("{:039}", n);
+        for n in 0..12 {
+            log.append(&line(n)).unwrap();
         }
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap_or_default();
+        assert_eq!(read(log.path.clone()), format!("{}\n{}\n", line(10), line(11)));
+        assert_eq!(read(log.generation(1)), format!("{}\n{}\n", line(8), line(9)));
+        assert_eq!(read(log.generation(4)), format!("{}\n{}\n", line(2), line(3)));
+        assert!(!log.generation(5).exists());
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::net::SocketAddr;
 
     #[test]
-    fn test_parse_ipv4_sock_addr() {
-        assert_eq!(
-            parse_ipv4_sock_addr("0100007F:1538").unwrap(),
-            "127.0.0.1:5432".parse::<SocketAddr>().unwrap()
-        );
-
-        assert!(parse_ipv4_sock_addr("0100007F 1538").is_err());
-        assert!(parse_ipv4_sock_addr("010000YY:1538").is_err());
-        assert!(parse_ipv4_sock_addr("0100007F:15YY").is_err());
+    fn test_read_extent() {
+        let mut file = std::io::Cursor::new(b"0123456789".to_vec());
+        assert_eq!(read_extent(&mut file, 10, 4, 6).unwrap(), b"456789");
+        assert!(read_extent(&mut file, 10, 4, 7).is_err());
+        // Sizes from a corrupt file can be anything, and mustn't be allocated or overflow
+        assert!(read_extent(&mut file, 10, 4, u64::MAX).is_err());
+        assert!(read_extent(&mut file, 10, u64::MAX, 1).is_err());
     }
 }
@@ -14,13 +14,18 @@
 //   limitations under the License.
 //
 
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::fs;
 use std::io;
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Tests within a test binary run in parallel, so each example process gets its own file to signal
+// that it is ready.
+static NEXT_SIGNAL_FILE: AtomicUsize = AtomicUsize::new(0);
 
 // Find an executable produced by the Cargo build
-fn find_exec(name: &str) -> PathBuf {
+pub fn find_exec(name: &str) -> PathBuf {
 
     // Find the path where Cargo has placed the executables by looking at this test process's
     // executable, which was also built by Cargo.
@@ -31,9 +36,18 @@ fn find_exec(name: &str) -> PathBuf {
 }
 
 // Run a ptool against a sample process and return the stdout of the ptool
+#[allow(dead_code)]
 pub fn run_ptool(tool: &str, test_proc: &str) -> String {
+    run_ptool_with_args(tool, &[], test_proc)
+}
+
+// Same as run_ptool, but pass some additional arguments to the ptool before the pid
+#[allow(dead_code)]
+pub fn run_ptool_with_args(tool: &str, args: &[&str], test_proc: &str) -> String {
 
-    let signal_file = Path::new("/tmp/ptools-test-ready");
+    let signal_file = PathBuf::from(format!("/tmp/ptools-test-ready.{}.{}",
+        std::process::id(), NEXT_SIGNAL_FILE.fetch_add(1, Ordering::SeqCst)));
+    let signal_file = signal_file.as_path();
     if let Err(e) = fs::remove_file(signal_file) {
         if e.kind() != io::ErrorKind::NotFound {
             panic!("Failed to remove {:?}: {:?}", signal_file, e.kind())
@@ -41,6 +55,7 @@ pub fn run_ptool(tool: &str, test_proc: &str) -> String {
     }
 
     let mut examined_proc = Command::new(find_exec(test_proc))
+        .env("PTOOLS_TEST_READY_FILE", signal_file)
         .stdin(Stdio::null())
         .stderr(Stdio::inherit())
         .stdout(Stdio::inherit())
@@ -55,6 +70,7 @@ pub fn run_ptool(tool: &str, test_proc: &str) -> String {
     }
 
     let pfiles_output = Command::new(find_exec(tool))
+        .args(args)
         .arg(examined_proc.id().to_string())
         .stdin(Stdio::null())
         .output()
@@ -64,6 +80,8 @@ pub fn run_ptool(tool: &str, test_proc: &str) -> String {
     assert_eq!(stderr, "");
 
     examined_proc.kill().unwrap();
+    examined_proc.wait().unwrap();
+    fs::remove_file(signal_file).unwrap();
 
     stdout.into_owned()
 }
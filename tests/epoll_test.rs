@@ -18,10 +18,31 @@ mod common;
 
 #[test]
 fn epoll_basic() {
-    let stdout = common::run_ptool("pfiles", "epoll_example");
+    let stdout = common::run_ptool("pfiles2", "epoll_example");
 
     let pattern = "5: anon_inode(epoll)";
     if !stdout.contains(pattern) {
         panic!("String '{}' not found in command output:\n\n{}\n\n", pattern, stdout);
     }
+
+    let pattern = "watching fd 3: EPOLLIN";
+    if !stdout.contains(pattern) {
+        panic!("String '{}' not found in command output:\n\n{}\n\n", pattern, stdout);
+    }
+}
+
+#[test]
+fn epoll_fd_filter_json() {
+    let stdout = common::run_ptool_with_args("pfiles2", &["--fd", "5", "--json"], "epoll_example");
+
+    let pattern = r#"{"fd":5,"type":"anon_inode(epoll)""#;
+    if !stdout.contains(pattern) {
+        panic!("String '{}' not found in command output:\n\n{}\n\n", pattern, stdout);
+    }
+
+    // The only fd we asked for is the epoll fd
+    let pattern = r#""fd":3"#;
+    if stdout.contains(pattern) {
+        panic!("String '{}' unexpectedly found in command output:\n\n{}\n\n", pattern, stdout);
+    }
 }
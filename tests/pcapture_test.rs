@@ -0,0 +1,43 @@
+//
+//   Copyright 2026 Delphix
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+//
+
+
+mod common;
+
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn pcapture_pfiles() {
+    let archive = format!("/tmp/ptools-test-capture.{}.tar.gz", std::process::id());
+    let stdout = common::run_ptool_with_args("pcapture2", &["-o", &archive], "epoll_example");
+    assert!(stdout.contains("captured 1 process"), "unexpected output:\n\n{}\n\n", stdout);
+
+    // The example has been killed by now, so this can only come from the archive
+    let output = Command::new(common::find_exec("pfiles2"))
+        .args(["--no-pager", "--from-capture", &archive])
+        .output()
+        .unwrap();
+    fs::remove_file(&archive).unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    for pattern in &["5: anon_inode(epoll)", "watching fd 3: EPOLLIN"] {
+        if !stdout.contains(pattern) {
+            panic!("String '{}' not found in command output:\n\n{}\n\n", pattern, stdout);
+        }
+    }
+}
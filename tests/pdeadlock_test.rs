@@ -0,0 +1,33 @@
+//
+//   Copyright 2026 Delphix
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+//
+
+mod common;
+
+#[test]
+fn pdeadlock_basic() {
+    let stdout = common::run_ptool("pdeadlock2", "deadlock_example");
+
+    let pattern = "DEADLOCK: ";
+    let cycles = stdout.lines().filter(|l| l.trim().starts_with(pattern)).collect::<Vec<_>>();
+    if cycles.len() != 1 {
+        panic!("Expected one line starting with '{}' in command output:\n\n{}\n\n", pattern, stdout);
+    }
+
+    // The cycle goes from one of the threads to the other and back to the first
+    if cycles[0].matches(" -> ").count() != 2 {
+        panic!("Unexpected deadlock cycle in command output:\n\n{}\n\n", stdout);
+    }
+}
@@ -0,0 +1,29 @@
+//
+//   Copyright 2026 Delphix
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+//
+
+
+mod common;
+
+#[test]
+fn pmem_group_by_comm() {
+    let stdout = common::run_ptool_with_args("pmem2", &["--group-by", "comm"], "deadlock_example");
+
+    for pattern in &["COMMAND                  PROCESSES     USS kB", "\ndeadlock_exampl                  1 "] {
+        if !stdout.contains(pattern) {
+            panic!("String '{}' not found in command output:\n\n{}\n\n", pattern, stdout);
+        }
+    }
+}
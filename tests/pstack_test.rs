@@ -0,0 +1,37 @@
+//
+//   Copyright 2026 Delphix
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+//
+
+mod common;
+
+#[test]
+fn pstack_threads() {
+    let stdout = common::run_ptool("pstack2", "deadlock_example");
+
+    // The main thread and the two deadlocked threads
+    let headers = stdout.lines().filter(|l| l.starts_with("--- thread ")).collect::<Vec<_>>();
+    if headers.len() != 3 {
+        panic!("Expected three thread headers in command output:\n\n{}\n\n", stdout);
+    }
+    if !headers.iter().all(|h| h.contains("(deadlock_exampl)") && h.contains("wchan: ")) {
+        panic!("Expected thread name and wchan in each header:\n\n{}\n\n", stdout);
+    }
+
+    // Unwinding has to get from libc back into the example to find the lock calls
+    if stdout.matches("PthreadMutex4lock").count() != 2 {
+        panic!("Expected two threads in PthreadMutex::lock in command output:\n\n{}\n\n", stdout);
+    }
+}
+
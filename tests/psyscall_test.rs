@@ -0,0 +1,29 @@
+//
+//   Copyright 2026 Delphix
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+//
+
+mod common;
+
+#[test]
+fn psyscall_basic() {
+    let stdout = common::run_ptool("psyscall2", "epoll_example");
+
+    // After signalling that it is ready, the example process parks its only thread, which blocks
+    // in futex().
+    let pattern = "futex(";
+    if !stdout.contains(pattern) {
+        panic!("String '{}' not found in command output:\n\n{}\n\n", pattern, stdout);
+    }
+}
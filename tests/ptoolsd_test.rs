@@ -0,0 +1,78 @@
+//
+//   Copyright 2026 Delphix
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+//
+
+mod common;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+#[test]
+fn ptoolsd_basic() {
+    let socket_path = format!("/tmp/ptoolsd-test.{}.sock", std::process::id());
+    let mut daemon = Command::new(common::find_exec("ptoolsd2"))
+        .args(["--socket", &socket_path])
+        .stdin(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !Path::new(&socket_path).exists() {
+        if let Some(status) = daemon.try_wait().unwrap() {
+            panic!("ptoolsd exited too soon with status {}", status)
+        }
+        if Instant::now() > deadline {
+            daemon.kill().unwrap();
+            panic!("ptoolsd didn't create {} in time", socket_path)
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let mode = fs::metadata(&socket_path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let stream = UnixStream::connect(&socket_path).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut call = |request: &str| {
+        writeln!(writer, "{}", request).unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        response
+    };
+
+    let pid = std::process::id();
+    let response = call(&format!(
+        r#"{{"jsonrpc":"2.0","id":1,"method":"tree","params":{{"pid":{}}}}}"#, pid));
+    let pattern = format!(r#"{{"jsonrpc":"2.0","id":1,"result":[{{"pid":{},"ppid":"#, pid);
+    if !response.starts_with(&pattern) {
+        panic!("String '{}' not found in response:\n\n{}\n\n", pattern, response);
+    }
+
+    let response = call(&format!(
+        r#"{{"jsonrpc":"2.0","id":2,"method":"files","params":{{"pid":{}}}}}"#, pid));
+    let pattern = r#""fd":0,"#;
+    if !response.contains(pattern) {
+        panic!("String '{}' not found in response:\n\n{}\n\n", pattern, response);
+    }
+
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+    fs::remove_file(&socket_path).unwrap();
+}